@@ -0,0 +1,41 @@
+// Bubble sort: sorts six numbers in memory into ascending order and prints them.
+// Each data label holds one word, and the labels come first, so the array
+// takes addresses 0 to 5.
+
+array: #5
+a1: #-3
+a2: #9
+a3: #0
+a4: #7
+a5: #2
+
+_start:
+    MOV r5, #5          // pairs to compare in this pass
+outer:
+    MOV r6, #0          // whether this pass swapped anything
+    MOV r1, #0          // the index of the pair
+inner:
+    LDR r2, [r1]
+    LDR r3, [r1, #1]
+    CMP r2, r3
+    BLE next
+    STR r3, [r1]
+    STR r2, [r1, #1]
+    MOV r6, #1
+next:
+    ADD r1, r1, #1
+    CMP r1, r5
+    BLT inner
+    // The largest number has bubbled up to the end, so the next pass is shorter
+    SUB r5, r5, #1
+    CMP r6, #0
+    BNE outer
+
+    MOV r1, #0
+print:
+    LDR r0, [r1]
+    PRINT r0
+    ADD r1, r1, #1
+    CMP r1, #6
+    BLT print
+    EXIT
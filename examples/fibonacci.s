@@ -0,0 +1,14 @@
+// Fibonacci: prints the first 10 Fibonacci numbers, keeping the last two in registers.
+
+_start:
+    MOV r0, #0          // F(n)
+    MOV r1, #1          // F(n + 1)
+    MOV r2, #10         // numbers left to print
+loop:
+    PRINT r0
+    ADD r3, r0, r1
+    MOV r0, r1
+    MOV r1, r3
+    SUBS r2, r2, #1
+    BNE loop
+    EXIT
@@ -0,0 +1,21 @@
+// GCD: Euclid's algorithm by repeated subtraction, as a function called with BL.
+
+_start:
+    MOV r0, #1071
+    MOV r1, #462
+    BL gcd
+    PRINT r0            // 21
+    EXIT
+
+// r0 = gcd(r0, r1), for positive r0 and r1
+gcd:
+    CMP r0, r1
+    BEQ return
+    BLT smaller
+    SUB r0, r0, r1
+    B gcd
+smaller:
+    SUB r1, r1, r0
+    B gcd
+return:
+    BX r14
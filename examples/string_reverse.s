@@ -0,0 +1,46 @@
+// String reverse: reverses "hello" in place and prints it. The string is held
+// one character code per word and ends with 0, so PRINT shows the codes:
+// 111 for 'o', 108 for 'l', 101 for 'e' and 104 for 'h'.
+
+s0: #104
+s1: #101
+s2: #108
+s3: #108
+s4: #111
+s5: #0
+
+_start:
+    // The length is the index of the 0 at the end
+    MOV r1, #0
+length:
+    LDR r0, [r1]
+    CMP r0, #0
+    BEQ found
+    ADD r1, r1, #1
+    B length
+found:
+    // Swap the outermost characters, moving inward until the indices meet
+    SUB r2, r1, #1      // the last character
+    MOV r1, #0          // the first
+swap:
+    CMP r1, r2
+    BGE print
+    LDR r3, [r1]
+    LDR r4, [r2]
+    STR r4, [r1]
+    STR r3, [r2]
+    ADD r1, r1, #1
+    SUB r2, r2, #1
+    B swap
+
+print:
+    MOV r1, #0
+next:
+    LDR r0, [r1]
+    CMP r0, #0
+    BEQ done
+    PRINT r0
+    ADD r1, r1, #1
+    B next
+done:
+    EXIT
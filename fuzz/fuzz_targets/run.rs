@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to the loader and executor of every instruction set. The
+//! first byte picks the instruction set, and the rest is both the program and the
+//! input of an interactive session. Any panic is a bug: malformed programs must
+//! end in diagnostics.
+
+#![no_main]
+
+use std::io::{self, Cursor};
+use libfuzzer_sys::fuzz_target;
+use asm::{isa, Interpreter, RunOptions};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, source)) = data.split_first() else {
+        return;
+    };
+    let front_end = isa::FRONT_ENDS[selector as usize % isa::FRONT_ENDS.len()];
+    let options = RunOptions { max_steps: Some(10_000), ..RunOptions::default() };
+    let source = String::from_utf8_lossy(source);
+
+    let interpreter = Interpreter::new(front_end, options).with_output(io::sink());
+    let _ = interpreter.check_source(&source);
+    let _ = interpreter.run_source(&source);
+    interpreter.with_input(Cursor::new(source.into_owned())).interactive();
+});
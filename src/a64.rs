@@ -0,0 +1,702 @@
+//! AArch64 (A64) front-end, selected with `--isa a64`.
+//!
+//! Registers are 64 bits wide. `x0`-`x30` name a whole register and `w0`-`w30` its
+//! lower 32 bits; writing a `w` register clears the upper half, and `xzr`/`wzr` read
+//! as zero and ignore writes. Labels and memory work as in ARM mode: memory is
+//! word-addressed, so a 64-bit value occupies two consecutive words (low word
+//! first) and offsets count words.
+
+use std::collections::HashMap;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::instructions::{self, InstructionInfo, A64_INSTRUCTIONS};
+use crate::interpreter::{parse_immediate, Machine};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+
+const NUM_REGISTERS: usize = 31;
+// Index of xzr/wzr
+const ZERO_REGISTER: usize = 31;
+// Base of the locals named by .local
+const FRAME_POINTER: usize = 29;
+// Written by BL and read by RET
+const LINK_REGISTER: usize = 30;
+
+const REGISTER_NOTE: &str = "registers are x0 through x30, their 32-bit views w0 through w30, and xzr/wzr";
+
+/// A register operand: which register, and whether it is used as x (64-bit) or w (32-bit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Register {
+    index: usize,
+    wide: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressOperand {
+    // [Xn] or [Xn, #offset]
+    RegisterOffset(usize, i32),
+    // #address or a label, known when decoding
+    Absolute(usize),
+}
+
+/// Condition codes of B.cond and CSEL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition { Eq, Ne, Cs, Cc, Mi, Pl, Vs, Vc, Hi, Ls, Ge, Lt, Gt, Le, Al }
+
+/// An A64 instruction with its operands checked and parsed. Branch targets are instruction indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Mov { rd: Register, src: Operand },
+    // ADD, SUB and their flag-setting forms; CMP and CMN write to the zero register
+    AddSub { subtract: bool, set_flags: bool, rd: Register, rn: Register, op2: Operand },
+    Csel { rd: Register, rn: Register, rm: Register, cond: Condition },
+    Ldr { rt: Register, address: AddressOperand },
+    Str { rt: Register, address: AddressOperand },
+    B { cond: Condition, target: usize },
+    Bl { target: usize },
+    Ret,
+    // The register name is printed as written
+    Print { rt: Register, name: String },
+    Exit,
+}
+
+/// The NZCV condition flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub n: bool,
+    pub z: bool,
+    pub c: bool,
+    pub v: bool,
+}
+
+/// Registers and flags of the simulated AArch64 core, with its memory and labels.
+pub struct Cpu {
+    pub registers: [u64; NUM_REGISTERS],
+    pub flags: Flags,
+    pub machine: Machine,
+}
+
+impl AsRef<Machine> for Cpu {
+    fn as_ref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl AsMut<Machine> for Cpu {
+    fn as_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+/// The AArch64 instruction set.
+pub struct A64;
+
+impl Isa for A64 {
+    type Instruction = Instruction;
+    type Cpu = Cpu;
+
+    const NAME: &'static str = "a64";
+    const DESCRIPTION: &'static str = "AArch64: 64-bit registers x0-x30 with 32-bit w views, and branches";
+    const INSTRUCTIONS: &'static [InstructionInfo] = A64_INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &RegisterFile {
+        names: &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14", "x15",
+                 "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29", "x30"],
+        bits: 64,
+    };
+    const CORE_REGISTER: usize = 0;
+
+    fn new_cpu(&self) -> Cpu {
+        Cpu { registers: [0; NUM_REGISTERS], flags: Flags::default(), machine: Machine::new() }
+    }
+
+    fn read_register(&self, cpu: &Cpu, index: usize) -> i64 {
+        cpu.registers[index] as i64
+    }
+
+    fn flags(&self, cpu: &Cpu) -> Vec<(&'static str, bool)> {
+        vec![("N", cpu.flags.n), ("Z", cpu.flags.z), ("C", cpu.flags.c), ("V", cpu.flags.v)]
+    }
+
+    fn seed_registers(&self, cpu: &mut Cpu, registers: &[(usize, i32)]) {
+        for &(reg, val) in registers {
+            cpu.registers[reg] = val as i64 as u64;
+        }
+    }
+
+    fn prepare(&self, cpu: &mut Cpu, len: usize) {
+        // RET from the entry point ends the program
+        cpu.registers[LINK_REGISTER] = len as u64;
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        decode(tokens, labels, report)
+    }
+
+    fn execute(&self, cpu: &mut Cpu, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        cpu.execute(instruction, pc, console)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::B { .. } | Instruction::Bl { .. } | Instruction::Ret)
+    }
+
+    fn frame_offset(&self, instruction: &Instruction) -> Option<i32> {
+        match *instruction {
+            Instruction::Ldr { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. }
+            | Instruction::Str { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. } => Some(offset),
+            _ => None,
+        }
+    }
+
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Bl { .. })
+    }
+
+    fn call_target(&self, instruction: &Instruction) -> Option<usize> {
+        match instruction {
+            Instruction::Bl { target } => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn is_return(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Ret)
+    }
+
+    fn documented(&self, mnemonic: &str) -> Option<&'static InstructionInfo> {
+        documented(mnemonic)
+    }
+
+    // A W register written clears the upper half of its X register, so only X registers are left as they were
+    fn no_effect(&self, instruction: &Instruction) -> Option<&'static str> {
+        match *instruction {
+            Instruction::Mov { rd, src: Operand::Register(rm) } if rd == rm && rd.wide => Some("it copies the register to itself"),
+            Instruction::AddSub { set_flags: false, rd, rn, op2: Operand::Immediate(0), .. } if rd == rn && rd.wide =>
+                Some("an operand of #0 leaves the register as it was"),
+            _ => None,
+        }
+    }
+}
+
+impl Cpu {
+    fn read(&self, reg: Register) -> u64 {
+        if reg.index == ZERO_REGISTER {
+            0
+        } else if reg.wide {
+            self.registers[reg.index]
+        } else {
+            self.registers[reg.index] as u32 as u64
+        }
+    }
+
+    fn write(&mut self, reg: Register, value: u64) {
+        if reg.index == ZERO_REGISTER {
+            return;
+        }
+        // Writing a w register zeroes the upper 32 bits
+        self.registers[reg.index] = if reg.wide { value } else { value as u32 as u64 };
+    }
+
+    fn value(&self, operand: Operand, wide: bool) -> u64 {
+        match operand {
+            Operand::Register(reg) => self.read(reg),
+            Operand::Immediate(val) if wide => val as u64,
+            Operand::Immediate(val) => val as u32 as u64,
+        }
+    }
+
+    // Checks that a 32-bit (one word) or 64-bit (two words) access fits in memory
+    fn effective_address(&self, address: AddressOperand, wide: bool) -> Result<usize, Box<Diagnostic>> {
+        let address = match address {
+            AddressOperand::Absolute(addr) => addr as i64,
+            AddressOperand::RegisterOffset(base, offset) => (self.registers[base] as i64).wrapping_add(offset as i64),
+        };
+        let (words, size) = (if wide { 2 } else { 1 }, self.machine.memory.len());
+        if address < 0 || address > size as i64 - words {
+            return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                format!("Memory access out of bounds: address {} (MEMORY_SIZE {})", address, size))
+                .with_note("x registers use two consecutive memory words, w registers one")));
+        }
+        Ok(address as usize)
+    }
+
+    fn condition_holds(&self, cond: Condition) -> bool {
+        let Flags { n, z, c, v } = self.flags;
+        match cond {
+            Condition::Eq => z,
+            Condition::Ne => !z,
+            Condition::Cs => c,
+            Condition::Cc => !c,
+            Condition::Mi => n,
+            Condition::Pl => !n,
+            Condition::Vs => v,
+            Condition::Vc => !v,
+            Condition::Hi => c && !z,
+            Condition::Ls => !c || z,
+            Condition::Ge => n == v,
+            Condition::Lt => n != v,
+            Condition::Gt => !z && n == v,
+            Condition::Le => z || n != v,
+            Condition::Al => true,
+        }
+    }
+
+    // Executes a decoded instruction at index `pc`
+    fn execute(&mut self, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        match *instruction {
+            Instruction::Exit => return Ok(Flow::Exit),
+            Instruction::Mov { rd, src } => {
+                let value = self.value(src, rd.wide);
+                self.write(rd, value);
+            },
+            Instruction::AddSub { subtract, set_flags, rd, rn, op2 } => {
+                let x = self.read(rn);
+                let y = self.value(op2, rn.wide);
+                // Subtraction is x + !y + 1, which gives ARM's carry (no borrow) flag
+                let (result, flags) = if subtract {
+                    add_with_carry(x, !y, true, rn.wide)
+                } else {
+                    add_with_carry(x, y, false, rn.wide)
+                };
+                if set_flags {
+                    self.flags = flags;
+                }
+                self.write(rd, result);
+            },
+            Instruction::Csel { rd, rn, rm, cond } => {
+                let value = if self.condition_holds(cond) { self.read(rn) } else { self.read(rm) };
+                self.write(rd, value);
+            },
+            Instruction::Ldr { rt, address } => {
+                let address = self.effective_address(address, rt.wide)?;
+                let mut value = self.machine.load(address)? as u32 as u64;
+                if rt.wide {
+                    value |= (self.machine.load(address + 1)? as u32 as u64) << 32;
+                }
+                self.write(rt, value);
+            },
+            Instruction::Str { rt, address } => {
+                let address = self.effective_address(address, rt.wide)?;
+                let value = self.read(rt);
+                self.machine.store(address, value as u32 as i32)?;
+                if rt.wide {
+                    self.machine.store(address + 1, (value >> 32) as u32 as i32)?;
+                }
+            },
+            Instruction::B { cond, target } => {
+                if self.condition_holds(cond) {
+                    return Ok(Flow::Jump(target));
+                }
+            },
+            Instruction::Bl { target } => {
+                self.registers[LINK_REGISTER] = pc as u64 + 1;
+                return Ok(Flow::Jump(target));
+            },
+            Instruction::Ret => {
+                return Ok(Flow::Jump(self.registers[LINK_REGISTER] as usize));
+            },
+            Instruction::Print { rt, ref name } => {
+                let (value, bits) = if rt.wide { (self.read(rt) as i64, 64) } else { (self.read(rt) as u32 as i32 as i64, 32) };
+                // xzr has no entry in the register file, and a radix of its own would be pointless
+                let register = A64::REGISTERS.names.get(rt.index).copied().unwrap_or("xzr");
+                console.print(format_args!("{} = {}", name, self.machine.formats.format(register, value, bits)))?;
+            },
+        }
+        Ok(Flow::Next)
+    }
+}
+
+// Adds with a carry in, at 64 or 32 bits, returning the result and the NZCV flags it sets
+fn add_with_carry(x: u64, y: u64, carry: bool, wide: bool) -> (u64, Flags) {
+    if wide {
+        let (partial, carry1) = x.overflowing_add(y);
+        let (result, carry2) = partial.overflowing_add(carry as u64);
+        let overflow = ((x ^ result) & (y ^ result)) >> 63 == 1;
+        (result, Flags { n: result >> 63 == 1, z: result == 0, c: carry1 || carry2, v: overflow })
+    } else {
+        let (x, y) = (x as u32, y as u32);
+        let (partial, carry1) = x.overflowing_add(y);
+        let (result, carry2) = partial.overflowing_add(carry as u32);
+        let overflow = ((x ^ result) & (y ^ result)) >> 31 == 1;
+        (result as u64, Flags { n: result >> 31 == 1, z: result == 0, c: carry1 || carry2, v: overflow })
+    }
+}
+
+// Parses x0-x30, w0-w30, xzr and wzr
+fn parse_register(reg: &str) -> Option<Register> {
+    let lower = reg.to_lowercase();
+    match lower.as_str() {
+        "xzr" => return Some(Register { index: ZERO_REGISTER, wide: true }),
+        "wzr" => return Some(Register { index: ZERO_REGISTER, wide: false }),
+        _ => {},
+    }
+    let wide = match lower.chars().next()? {
+        'x' => true,
+        'w' => false,
+        _ => return None,
+    };
+    let index = lower[1..].parse::<usize>().ok().filter(|&idx| idx < NUM_REGISTERS)?;
+    Some(Register { index, wide })
+}
+
+fn parse_condition(cond: &str) -> Option<Condition> {
+    Some(match cond.to_uppercase().as_str() {
+        "EQ" => Condition::Eq,
+        "NE" => Condition::Ne,
+        "CS" | "HS" => Condition::Cs,
+        "CC" | "LO" => Condition::Cc,
+        "MI" => Condition::Mi,
+        "PL" => Condition::Pl,
+        "VS" => Condition::Vs,
+        "VC" => Condition::Vc,
+        "HI" => Condition::Hi,
+        "LS" => Condition::Ls,
+        "GE" => Condition::Ge,
+        "LT" => Condition::Lt,
+        "GT" => Condition::Gt,
+        "LE" => Condition::Le,
+        "AL" => Condition::Al,
+        _ => return None,
+    })
+}
+
+// Checks the operand count and the commas between operands of an instruction,
+// returning the operands.
+fn operands<'a>(tokens: &Tokens<'a>, count: usize, report: &Reporter) -> Option<Vec<&'a str>> {
+    tokens.expect_instruction(count, documented(tokens.mnemonic), report)
+}
+
+// ADDS and B.NE are documented under ADD and B
+fn documented(mnemonic: &str) -> Option<&'static InstructionInfo> {
+    let mnemonic = mnemonic.to_uppercase();
+    let base = mnemonic.split('.').next().unwrap_or(&mnemonic);
+    let base = match base {
+        "ADDS" | "SUBS" => &base[..3],
+        "CMN" => "CMP",
+        _ => base,
+    };
+    instructions::lookup_in(A64_INSTRUCTIONS, base)
+}
+
+fn register_operand(operand: &str, role: &str, report: &Reporter) -> Option<Register> {
+    let reg = parse_register(operand);
+    if reg.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidRegister,
+            format!("Invalid {}: {}", role, operand)).with_note(REGISTER_NOTE));
+    }
+    reg
+}
+
+fn value_operand(operand: &str, message: &str, report: &Reporter) -> Option<Operand> {
+    let val = match operand.strip_prefix('#') {
+        Some(imm_str) => parse_immediate(imm_str).map(|val| Operand::Immediate(val as i64)),
+        None => parse_register(operand).map(Operand::Register),
+    };
+    if val.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, message)
+            .with_note("use an immediate prefixed with '#' (e.g. #15 or #0x10) or a register"));
+    }
+    val
+}
+
+// Checks that every register of an instruction has the width of the first one
+fn same_width(registers: &[(Register, &str)], report: &Reporter) -> Option<()> {
+    let (first, first_name) = registers[0];
+    for &(reg, name) in &registers[1..] {
+        if reg.wide != first.wide {
+            report.report(name, Diagnostic::error(Code::InvalidOperand,
+                format!("Register {} does not have the same width as {}", name, first_name))
+                .with_note("use x registers (64-bit) or w registers (32-bit) throughout an instruction"));
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn address_operand(operand: &str, labels: &HashMap<String, usize>, report: &Reporter) -> Option<AddressOperand> {
+    if let Some(inner) = operand.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let parts: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
+        if parts.len() > 2 {
+            report.error(operand, Code::InvalidAddressOperand, format!("Invalid address operand format: {}", operand));
+            return None;
+        }
+        let base = register_operand(parts[0], "base register", report)?;
+        if !base.wide || base.index == ZERO_REGISTER {
+            report.report(parts[0], Diagnostic::error(Code::InvalidRegister,
+                format!("Invalid base register: {}", parts[0])).with_note("the base address must be in an x register"));
+            return None;
+        }
+        let offset = match parts.get(1) {
+            None => 0,
+            Some(offset_str) => {
+                let Some(offset) = offset_str.strip_prefix('#').and_then(parse_immediate) else {
+                    report.error(offset_str, Code::InvalidAddressOperand,
+                        format!("Invalid immediate offset in address operand: {}", offset_str));
+                    return None;
+                };
+                offset
+            },
+        };
+        Some(AddressOperand::RegisterOffset(base.index, offset))
+    } else if let Some(imm_str) = operand.strip_prefix('#') {
+        let Some(addr_val) = parse_immediate(imm_str) else {
+            report.error(operand, Code::InvalidAddressOperand, format!("Invalid immediate address: {}", operand));
+            return None;
+        };
+        if addr_val < 0 {
+            report.error(operand, Code::NegativeAddress, format!("Memory address cannot be negative: {}", addr_val));
+            return None;
+        }
+        Some(AddressOperand::Absolute(addr_val as usize))
+    } else if let Some(addr) = labels.get(operand) {
+        Some(AddressOperand::Absolute(*addr))
+    } else {
+        report.error(operand, Code::UndefinedLabel, format!("Undefined label: {}", operand));
+        None
+    }
+}
+
+fn branch_target(label: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    if let Some(index) = labels.code.get(label) {
+        Some(*index)
+    } else if labels.addresses.contains_key(label) {
+        report.error(label, Code::InvalidBranchTarget, format!("Cannot branch to '{}': it is a data label, not code.", label));
+        None
+    } else {
+        report.error(label, Code::UndefinedLabel, format!("Undefined label: {}", label));
+        None
+    }
+}
+
+// Decodes a single A64 instruction (labels already stripped), reporting any errors.
+fn decode(tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+    let mnemonic_token = tokens.mnemonic;
+    let mnemonic = mnemonic_token.to_uppercase();
+    debug!("Decoding {} with operands {:?}", mnemonic, tokens.operands);
+
+    let instruction = match mnemonic.as_str() {
+        "EXIT" => {
+            operands(tokens, 0, report)?;
+            Instruction::Exit
+        },
+        "RET" => {
+            operands(tokens, 0, report)?;
+            Instruction::Ret
+        },
+        "MOV" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let src = value_operand(ops[1], "Invalid operand for MOV.", report)?;
+            if let Operand::Register(rm) = src {
+                same_width(&[(rd, ops[0]), (rm, ops[1])], report)?;
+            }
+            Instruction::Mov { rd, src }
+        },
+        "ADD" | "ADDS" | "SUB" | "SUBS" => {
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let message = format!("Invalid second operand for {}. It must be an immediate (prefixed with '#') or a valid register.", mnemonic);
+            let op2 = value_operand(ops[2], &message, report)?;
+            let mut registers = vec![(rd, ops[0]), (rn, ops[1])];
+            if let Operand::Register(rm) = op2 {
+                registers.push((rm, ops[2]));
+            }
+            same_width(&registers, report)?;
+            Instruction::AddSub { subtract: mnemonic.starts_with("SUB"), set_flags: mnemonic.ends_with('S'), rd, rn, op2 }
+        },
+        "CMP" | "CMN" => {
+            let ops = operands(tokens, 2, report)?;
+            let rn = register_operand(ops[0], "first operand register", report)?;
+            let message = format!("Invalid second operand for {}. It must be an immediate (prefixed with '#') or a valid register.", mnemonic);
+            let op2 = value_operand(ops[1], &message, report)?;
+            if let Operand::Register(rm) = op2 {
+                same_width(&[(rn, ops[0]), (rm, ops[1])], report)?;
+            }
+            let rd = Register { index: ZERO_REGISTER, wide: rn.wide };
+            Instruction::AddSub { subtract: mnemonic == "CMP", set_flags: true, rd, rn, op2 }
+        },
+        "CSEL" => {
+            let ops = operands(tokens, 4, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let rm = register_operand(ops[2], "second operand register", report)?;
+            same_width(&[(rd, ops[0]), (rn, ops[1]), (rm, ops[2])], report)?;
+            let Some(cond) = parse_condition(ops[3]) else {
+                report.report(ops[3], Diagnostic::error(Code::InvalidOperand, format!("Invalid condition: {}", ops[3]))
+                    .with_note("conditions are EQ, NE, CS/HS, CC/LO, MI, PL, VS, VC, HI, LS, GE, LT, GT, LE and AL"));
+                return None;
+            };
+            Instruction::Csel { rd, rn, rm, cond }
+        },
+        "LDR" | "STR" => {
+            let ops = operands(tokens, 2, report)?;
+            let role = if mnemonic == "LDR" { "destination register" } else { "source register" };
+            let rt = register_operand(ops[0], role, report)?;
+            let address = address_operand(ops[1], labels.addresses, report)?;
+            if mnemonic == "LDR" {
+                Instruction::Ldr { rt, address }
+            } else {
+                Instruction::Str { rt, address }
+            }
+        },
+        "BL" => {
+            let ops = operands(tokens, 1, report)?;
+            Instruction::Bl { target: branch_target(ops[0], labels, report)? }
+        },
+        "PRINT" => {
+            let ops = operands(tokens, 1, report)?;
+            let rt = register_operand(ops[0], "register", report)?;
+            Instruction::Print { rt, name: ops[0].to_string() }
+        },
+        _ if mnemonic == "B" || mnemonic.starts_with("B.") => {
+            let cond = match mnemonic.strip_prefix("B.") {
+                None => Condition::Al,
+                Some(cond_str) => match parse_condition(cond_str) {
+                    Some(cond) => cond,
+                    None => {
+                        report.report(mnemonic_token, Diagnostic::error(Code::UnknownInstruction,
+                            format!("Unknown branch condition: {}", mnemonic_token))
+                            .with_note("conditions are EQ, NE, CS/HS, CC/LO, MI, PL, VS, VC, HI, LS, GE, LT, GT, LE and AL"));
+                        return None;
+                    },
+                },
+            };
+            let ops = operands(tokens, 1, report)?;
+            Instruction::B { cond, target: branch_target(ops[0], labels, report)? }
+        },
+        _ => {
+            report.report(mnemonic_token, instructions::unknown(A64_INSTRUCTIONS, mnemonic_token));
+            return None;
+        }
+    };
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::interpreter::{run_sources, RunOptions};
+
+    fn run(script: &str) -> Cpu {
+        match run_sources(&A64, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(cpu) => cpu,
+            Err(errors) => panic!("{}", errors.iter().map(|e| e.to_human()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    fn errors(script: &str) -> Vec<Diagnostic> {
+        match run_sources(&A64, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(_) => panic!("expected the script to fail"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn test_w_registers_are_views_of_x_registers() {
+        let cpu = run("\
+            MOV x0, #-1\n\
+            ADD w1, w0, #1\n\
+            MOV w2, w0\n\
+            SUB x3, xzr, #1\n\
+            MOV xzr, #5\n\
+            EXIT");
+        assert_eq!(cpu.registers[0], u64::MAX);
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.registers[2], 0xFFFF_FFFF);
+        assert_eq!(cpu.registers[3] as i64, -1);
+    }
+
+    #[test]
+    fn test_loop_with_cmp_and_conditional_branch() {
+        let cpu = run("\
+            MOV x0, #0\n\
+            MOV x1, #0\n\
+            loop: ADD x0, x0, #1\n\
+            ADD x1, x1, x0\n\
+            CMP x0, #10\n\
+            B.LT loop\n\
+            EXIT");
+        assert_eq!(cpu.registers[0], 10);
+        assert_eq!(cpu.registers[1], 55);
+    }
+
+    #[test]
+    fn test_bl_and_ret() {
+        let cpu = run("\
+            _start: MOV x0, #7\n\
+            BL double\n\
+            MOV x2, x0\n\
+            EXIT\n\
+            double: ADD x0, x0, x0\n\
+            RET");
+        assert_eq!(cpu.registers[2], 14);
+        assert_eq!(cpu.registers[LINK_REGISTER], 2);
+    }
+
+    #[test]
+    fn test_csel_and_flags() {
+        let cpu = run("\
+            MOV w0, #5\n\
+            MOV w1, #9\n\
+            CMP w0, w1\n\
+            CSEL w2, w0, w1, GT\n\
+            CSEL w3, w0, w1, LT\n\
+            EXIT");
+        assert_eq!(cpu.registers[2], 9);
+        assert_eq!(cpu.registers[3], 5);
+        assert!(cpu.flags.n);
+        assert!(!cpu.flags.c);
+    }
+
+    #[test]
+    fn test_x_registers_use_two_memory_words() {
+        let cpu = run("\
+            value: #0\n\
+            high: #0\n\
+            MOV x0, #-2\n\
+            STR x0, value\n\
+            LDR w1, high\n\
+            MOV x4, #0\n\
+            LDR x2, [x4, #0]\n\
+            EXIT");
+        assert_eq!(cpu.machine.memory[0], -2);
+        assert_eq!(cpu.machine.memory[1], -1);
+        assert_eq!(cpu.registers[1], 0xFFFF_FFFF);
+        assert_eq!(cpu.registers[2] as i64, -2);
+    }
+
+    #[test]
+    fn test_addresses_near_the_register_limits() {
+        let errors = errors("\
+            MOV x0, #1\n\
+            MOV x2, #63\n\
+            loop: ADD x0, x0, x0\n\
+            SUB x2, x2, #1\n\
+            CMP x2, #0\n\
+            B.GT loop\n\
+            SUB x1, x0, #1\n\
+            LDR x3, [x1]\n");
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 8);
+    }
+
+    #[test]
+    fn test_mixed_register_widths_are_rejected() {
+        let errors = errors("ADD x0, w1, #1\n");
+        assert_eq!(errors[0].code, Code::InvalidOperand);
+        assert_eq!(errors[0].span.as_ref().unwrap().column_start, 9);
+    }
+
+    #[test]
+    fn test_branch_to_data_label_is_rejected() {
+        let errors = errors("data: #1\nB data\n");
+        assert_eq!(errors[0].code, Code::InvalidBranchTarget);
+    }
+}
@@ -0,0 +1,164 @@
+//! Breakpoints set with `--break`, such as `loop if r2 > 100` or `14 count 50`.
+//!
+//! A breakpoint is a code label, a line number or a `file:line`, optionally with a
+//! condition (an [`Expression`] over registers, flags and memory) and a hit count.
+//! Before the instruction at its location runs, the condition is evaluated; the
+//! breakpoint counts a hit when it holds, and triggers from the `count`th hit on.
+
+use std::str::FromStr;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::expression::{Context, Expression};
+use crate::interpreter::Program;
+
+/// Where a breakpoint is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A code label
+    Label(String),
+    /// A line of the named file, or of the first file when there is none. A line
+    /// without an instruction stands for the next instruction after it.
+    Line { file: Option<String>, line: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub location: Location,
+    pub condition: Option<Expression>,
+    /// Triggers from this many hits on, or at the first hit when `None`
+    pub count: Option<u64>,
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    /// Parses `<location> [if <condition>] [count <hits>]`.
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (location, mut rest) = spec.split_once(char::is_whitespace).unwrap_or((spec, ""));
+        if location.is_empty() {
+            return Err("expected a label, line or file:line".to_string());
+        }
+
+        let mut count = None;
+        if let Some((before, hits)) = rest.rsplit_once(char::is_whitespace)
+            && let Some(before) = before.trim_end().strip_suffix("count").filter(|before| before.is_empty() || before.ends_with(char::is_whitespace)) {
+            count = match hits.parse() {
+                Ok(hits) if hits > 0 => Some(hits),
+                _ => return Err(format!("invalid hit count '{}', expected a number from 1", hits)),
+            };
+            rest = before.trim_end();
+        }
+
+        let rest = rest.trim();
+        let condition = match rest.strip_prefix("if").filter(|condition| condition.starts_with(char::is_whitespace)) {
+            Some(condition) => Some(Expression::parse(condition)?),
+            None if rest.is_empty() => None,
+            None => return Err(format!("unexpected '{}', expected 'if <condition>' or 'count <hits>'", rest)),
+        };
+
+        let location = match location.rsplit_once(':') {
+            Some((file, line)) => Location::Line { file: Some(file.to_string()), line: line_number(line)? },
+            None if location.starts_with(|c: char| c.is_ascii_digit()) => Location::Line { file: None, line: line_number(location)? },
+            None => Location::Label(location.to_string()),
+        };
+        Ok(Breakpoint { location, condition, count })
+    }
+}
+
+fn line_number(text: &str) -> Result<usize, String> {
+    text.parse().ok().filter(|&line| line > 0).ok_or_else(|| format!("invalid line number '{}'", text))
+}
+
+// A breakpoint found in the loaded program
+struct Armed<'a> {
+    breakpoint: &'a Breakpoint,
+    index: usize,
+    hits: u64,
+}
+
+/// The breakpoints of a run, by the instruction they stop before.
+pub struct Breakpoints<'a> {
+    armed: Vec<Armed<'a>>,
+}
+
+impl<'a> Breakpoints<'a> {
+    /// Finds the instruction each breakpoint stops before, reporting those that match none.
+    pub fn resolve<I>(breakpoints: &'a [Breakpoint], program: &Program<I>) -> Result<Self, Vec<Diagnostic>> {
+        let mut resolved = Breakpoints { armed: Vec::new() };
+        let errors: Vec<Diagnostic> = breakpoints.iter()
+            .filter_map(|breakpoint| resolved.add(breakpoint, program).err().map(|error| *error))
+            .collect();
+        if errors.is_empty() { Ok(resolved) } else { Err(errors) }
+    }
+
+    /// Adds a breakpoint, or returns why it matches no instruction.
+    pub fn add<I>(&mut self, breakpoint: &'a Breakpoint, program: &Program<I>) -> Result<(), Box<Diagnostic>> {
+        let index = match &breakpoint.location {
+            Location::Label(label) => program.code_labels.get(label).copied()
+                .ok_or_else(|| format!("There is no code label '{}' to break at", label)),
+            Location::Line { file, line } => {
+                let file = file.as_deref().or_else(|| program.lines.first().map(|first| first.file.as_str()));
+                program.lines.iter()
+                    .position(|source| Some(source.file.as_str()) == file && source.line_no >= *line)
+                    .ok_or_else(|| format!("There is no instruction at or after line {} of {}", line, file.unwrap_or("the program")))
+            },
+        };
+        let index = index.map_err(|message| Box::new(Diagnostic::error(Code::InvalidBreakpoint, message)))?;
+        self.armed.push(Armed { breakpoint, index, hits: 0 });
+        Ok(())
+    }
+
+    /// Counts the hits of the breakpoints before instruction `pc` and returns whether
+    /// one triggers, or the error of a condition that cannot be evaluated.
+    pub fn hit(&mut self, pc: usize, context: &dyn Context) -> Result<bool, String> {
+        let mut triggered = false;
+        for armed in self.armed.iter_mut().filter(|armed| armed.index == pc) {
+            if let Some(condition) = &armed.breakpoint.condition
+                && !condition.holds(context).map_err(|error| format!("{} in '{}'", error, condition))? {
+                continue;
+            }
+            armed.hits += 1;
+            triggered |= armed.hits >= armed.breakpoint.count.unwrap_or(1);
+        }
+        Ok(triggered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_with_condition() {
+        let breakpoint: Breakpoint = "loop if r2 > 100".parse().unwrap();
+        assert_eq!(breakpoint.location, Location::Label("loop".to_string()));
+        assert_eq!(breakpoint.condition.unwrap().to_string(), "r2 > 100");
+        assert_eq!(breakpoint.count, None);
+    }
+
+    #[test]
+    fn test_file_line_with_condition_and_count() {
+        let breakpoint: Breakpoint = "main.s:14 if r0 == 1 count 50".parse().unwrap();
+        assert_eq!(breakpoint.location, Location::Line { file: Some("main.s".to_string()), line: 14 });
+        assert_eq!(breakpoint.condition.unwrap().to_string(), "r0 == 1");
+        assert_eq!(breakpoint.count, Some(50));
+    }
+
+    #[test]
+    fn test_line_with_count() {
+        assert_eq!("14 count 50".parse(), Ok(Breakpoint { location: Location::Line { file: None, line: 14 }, condition: None, count: Some(50) }));
+    }
+
+    #[test]
+    fn test_label_called_count() {
+        assert_eq!("count".parse::<Breakpoint>().unwrap().location, Location::Label("count".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_breakpoints() {
+        assert!("loop count 0".parse::<Breakpoint>().is_err());
+        assert!("loop if".parse::<Breakpoint>().is_err());
+        assert!("loop when r0".parse::<Breakpoint>().is_err());
+        assert!("main.s:x".parse::<Breakpoint>().is_err());
+    }
+}
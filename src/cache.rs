@@ -0,0 +1,207 @@
+//! A data cache simulated over memory, for `--cache 1k,4way,16B`.
+//!
+//! Every load and store a program makes is looked up in a set-associative cache
+//! with least-recently-used replacement. Stores allocate a line like loads do, so
+//! both count as hits or misses. Only the statistics are simulated: memory always
+//! holds the current values. They are kept overall and for each instruction, so a
+//! lab can show which loop has poor locality.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use crate::interpreter::SourceLine;
+
+// Bytes per memory word, the unit of an access
+const WORD_BYTES: usize = 4;
+
+/// The geometry of the cache: `<size>,<ways>,<line>`, such as `1k,4way,16B`,
+/// `256,direct,8B` or `512,full,16B`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Total capacity in bytes
+    pub size: usize,
+    /// Lines per set, 1 for a direct-mapped cache
+    pub ways: usize,
+    /// Bytes per line
+    pub line: usize,
+}
+
+impl CacheConfig {
+    pub fn sets(&self) -> usize {
+        self.size / (self.ways * self.line)
+    }
+}
+
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split(',').map(str::trim).collect();
+        let [size, ways, line] = parts[..] else {
+            return Err("expected <size>,<ways>,<line>, such as 1k,4way,16B".to_string());
+        };
+        let size = bytes(size).ok_or_else(|| format!("invalid cache size '{}', expected bytes such as 512 or 1k", size))?;
+        let line = bytes(line).ok_or_else(|| format!("invalid line size '{}', expected bytes such as 16B", line))?;
+        if line < WORD_BYTES || !line.is_power_of_two() {
+            return Err(format!("the line size must be a power of two of at least {} bytes", WORD_BYTES));
+        }
+        if !size.is_power_of_two() || size < line {
+            return Err(format!("the cache size must be a power of two of at least one line ({} bytes)", line));
+        }
+        let ways = match ways.to_lowercase().as_str() {
+            "direct" => 1,
+            "full" => size / line,
+            lowercase => lowercase.strip_suffix("way").and_then(|count| count.parse().ok())
+                .filter(|count: &usize| count.is_power_of_two())
+                .ok_or_else(|| format!("invalid associativity '{}', expected direct, full or a power of two such as 4way", ways))?,
+        };
+        if ways * line > size {
+            return Err(format!("{} ways of {}-byte lines do not fit in {} bytes", ways, line, size));
+        }
+        Ok(CacheConfig { size, ways, line })
+    }
+}
+
+// A byte count, optionally suffixed with k and B
+fn bytes(text: &str) -> Option<usize> {
+    let text = text.strip_suffix(['B', 'b']).unwrap_or(text);
+    let (number, scale) = match text.strip_suffix(['k', 'K']) {
+        Some(number) => (number, 1024),
+        None => (text, 1),
+    };
+    number.parse::<usize>().ok().filter(|&number| number > 0).map(|number| number * scale)
+}
+
+/// Hit and miss counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Stats {
+    fn count(&mut self, hit: bool) {
+        if hit { self.hits += 1 } else { self.misses += 1 }
+    }
+
+    fn describe(&self) -> String {
+        let accesses = self.hits + self.misses;
+        let rate = if accesses == 0 { 0.0 } else { 100.0 * self.hits as f64 / accesses as f64 };
+        format!("{} hits, {} misses ({:.1}% hits)", self.hits, self.misses, rate)
+    }
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    // The tags in each set, most recently used last
+    sets: Vec<Vec<usize>>,
+    // The instruction making the accesses
+    current: usize,
+    pub total: Stats,
+    /// Statistics by instruction index
+    pub by_instruction: BTreeMap<usize, Stats>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Cache { config, sets: vec![Vec::new(); config.sets()], current: 0, total: Stats::default(), by_instruction: BTreeMap::new() }
+    }
+
+    /// Attributes the following accesses to instruction `index`.
+    pub fn at(&mut self, index: usize) {
+        self.current = index;
+    }
+
+    /// Looks up the memory word at `address`, loading its line on a miss, and returns whether it hit.
+    pub fn access(&mut self, address: usize) -> bool {
+        let block = address * WORD_BYTES / self.config.line;
+        let sets = self.sets.len();
+        let set = &mut self.sets[block % sets];
+        let tag = block / sets;
+        let hit = match set.iter().position(|&cached| cached == tag) {
+            Some(way) => {
+                set.remove(way);
+                true
+            },
+            None => {
+                if set.len() == self.config.ways {
+                    set.remove(0);
+                }
+                false
+            },
+        };
+        set.push(tag);
+        self.total.count(hit);
+        self.by_instruction.entry(self.current).or_default().count(hit);
+        hit
+    }
+
+    /// The statistics overall and for each instruction that accessed memory, in program order.
+    pub fn report(&self, lines: &[SourceLine]) -> String {
+        let CacheConfig { size, ways, line } = self.config;
+        let associativity = if ways == 1 { "direct-mapped".to_string() } else { format!("{}-way", ways) };
+        let mut report = format!("Cache: {} bytes, {}, {}-byte lines, {} sets", size, associativity, line, self.config.sets());
+        report.push_str(&format!("\n  {} accesses: {}", self.total.hits + self.total.misses, self.total.describe()));
+        for (&index, stats) in &self.by_instruction {
+            if let Some(source) = lines.get(index) {
+                report.push_str(&format!("\n  {}:{}  {}  {}", source.file, source.line_no, source.text(), stats.describe()));
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!("1k,4way,16B".parse(), Ok(CacheConfig { size: 1024, ways: 4, line: 16 }));
+        assert_eq!("256, direct, 8".parse(), Ok(CacheConfig { size: 256, ways: 1, line: 8 }));
+        assert_eq!("64,full,16B".parse::<CacheConfig>().map(|config| config.sets()), Ok(1));
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        assert!("1k,3way,16B".parse::<CacheConfig>().is_err());
+        assert!("1k,4way,2B".parse::<CacheConfig>().is_err());
+        assert!("32,4way,16B".parse::<CacheConfig>().is_err());
+        assert!("1k,4way".parse::<CacheConfig>().is_err());
+    }
+
+    // Two sets of two 8-byte (two-word) lines
+    fn small_cache() -> Cache {
+        Cache::new("32,2way,8B".parse().unwrap())
+    }
+
+    #[test]
+    fn test_hits_in_a_line() {
+        let mut cache = small_cache();
+        assert!(!cache.access(0));
+        assert!(cache.access(1));
+    }
+
+    #[test]
+    fn test_least_recently_used_eviction() {
+        let mut cache = small_cache();
+        // Words 0, 4 and 8 all map to set 0, which holds two lines
+        assert!(!cache.access(0));
+        assert!(!cache.access(4));
+        assert!(cache.access(0));
+        assert!(!cache.access(8));
+        assert!(cache.access(0));
+        assert!(!cache.access(4));
+    }
+
+    #[test]
+    fn test_stats_by_instruction() {
+        let mut cache = small_cache();
+        assert!(!cache.access(0));
+        assert!(cache.access(1));
+        // Word 2 is in the other set
+        cache.at(3);
+        assert!(!cache.access(2));
+        assert_eq!(cache.total, Stats { hits: 1, misses: 2 });
+        assert_eq!(cache.by_instruction[&3], Stats { hits: 0, misses: 1 });
+    }
+}
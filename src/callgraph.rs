@@ -0,0 +1,301 @@
+//! The call graph of a run, for `--call-graph`.
+//!
+//! Built from the calls and returns a program executes: which function called
+//! which and how often, which functions are leaves, and how deep the calls
+//! nested. A branch that is not a call but lands on the entry of another function
+//! is counted as a tail call, and the caller's frame becomes the callee's. The
+//! entries are the labels the program calls anywhere, so the first time a function
+//! is reached can be by a tail call too.
+//!
+//! Each function also counts the cycles of the instructions it ran itself and of
+//! those run while it was active, including in the functions it called, which
+//! `--call-graph-file` writes with the graph as DOT or JSON.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use serde_json::json;
+use crate::diagnostics::{Code, Diagnostic};
+
+pub struct CallGraph {
+    // Function names by entry instruction index
+    names: HashMap<usize, String>,
+    // The entry of the function each active frame is in, outermost first
+    stack: Vec<usize>,
+    // The program entry and every call target reached
+    functions: BTreeSet<usize>,
+    // The labels some instruction of the program calls
+    entries: BTreeSet<usize>,
+    // Number of calls by (caller, callee)
+    calls: BTreeMap<(usize, usize), u64>,
+    tail_calls: BTreeMap<(usize, usize), u64>,
+    // The stack when it was deepest
+    deepest: Vec<usize>,
+    // Frames on the stack by function, counting a recursive function once per frame
+    active: HashMap<usize, usize>,
+    // Cycles spent in each function itself, and while it was on the stack
+    own: BTreeMap<usize, u64>,
+    cumulative: BTreeMap<usize, u64>,
+}
+
+impl CallGraph {
+    /// Starts a graph for a program entered at `entry`, naming functions after `code_labels`.
+    /// `call_targets` are the targets of the calls in the program, whether they run or not.
+    pub fn new(code_labels: &HashMap<String, usize>, entry: usize, call_targets: impl IntoIterator<Item = usize>) -> Self {
+        let mut names: HashMap<usize, String> = HashMap::new();
+        for (label, &index) in code_labels {
+            // Of several labels on one instruction, use the first alphabetically so reports are stable
+            if names.get(&index).is_none_or(|name| label < name) {
+                names.insert(index, label.clone());
+            }
+        }
+        let entries = call_targets.into_iter().filter(|target| names.contains_key(target)).collect();
+        CallGraph {
+            names,
+            entries,
+            stack: vec![entry],
+            functions: BTreeSet::from([entry]),
+            calls: BTreeMap::new(),
+            tail_calls: BTreeMap::new(),
+            deepest: vec![entry],
+            active: HashMap::from([(entry, 1)]),
+            own: BTreeMap::new(),
+            cumulative: BTreeMap::new(),
+        }
+    }
+
+    /// Records a call to the function at `target`.
+    pub fn call(&mut self, target: usize) {
+        *self.calls.entry((self.current(), target)).or_default() += 1;
+        self.functions.insert(target);
+        self.stack.push(target);
+        *self.active.entry(target).or_default() += 1;
+        if self.stack.len() > self.deepest.len() {
+            self.deepest = self.stack.clone();
+        }
+    }
+
+    /// Records a return from the current function.
+    pub fn ret(&mut self) {
+        // A return from the entry function ends the program
+        if self.stack.len() > 1
+            && let Some(function) = self.stack.pop() {
+            self.leave(function);
+        }
+    }
+
+    /// Records a branch that is neither a call nor a return.
+    pub fn jump(&mut self, target: usize) {
+        let current = self.current();
+        if target != current && (self.functions.contains(&target) || self.entries.contains(&target)) {
+            self.functions.insert(target);
+            *self.tail_calls.entry((current, target)).or_default() += 1;
+            *self.stack.last_mut().expect("the entry frame is never popped") = target;
+            self.leave(current);
+            *self.active.entry(target).or_default() += 1;
+        }
+    }
+
+    /// Counts the `cycles` of an instruction run in the current function.
+    pub fn executed(&mut self, cycles: u64) {
+        *self.own.entry(self.current()).or_default() += cycles;
+        for &function in self.active.keys() {
+            *self.cumulative.entry(function).or_default() += cycles;
+        }
+    }
+
+    /// The most frames that were active at once, counting the entry function.
+    pub fn max_depth(&self) -> usize {
+        self.deepest.len()
+    }
+
+    /// The functions that called no other function, by name.
+    pub fn leaf_functions(&self) -> Vec<&str> {
+        let mut leaves: Vec<&str> = self.functions.iter()
+            .filter(|&&function| !self.calls.keys().chain(self.tail_calls.keys()).any(|&(caller, _)| caller == function))
+            .map(|&function| self.name(function))
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    /// Describes the graph for printing after a run.
+    pub fn report(&self) -> String {
+        if self.calls.is_empty() && self.tail_calls.is_empty() {
+            return "Call graph: no functions were called".to_string();
+        }
+        let mut text = "Call graph:".to_string();
+        for (&(caller, callee), &count) in &self.calls {
+            text.push_str(&format!("\n  {} -> {} ({})", self.name(caller), self.name(callee), plural(count, "call")));
+        }
+        for (&(caller, callee), &count) in &self.tail_calls {
+            text.push_str(&format!("\n  {} -> {} ({})", self.name(caller), self.name(callee), plural(count, "tail call")));
+        }
+        let leaves = self.leaf_functions();
+        text.push_str(&format!("\nLeaf functions: {}", if leaves.is_empty() { "none".to_string() } else { leaves.join(", ") }));
+        let path: Vec<&str> = self.deepest.iter().map(|&function| self.name(function)).collect();
+        text.push_str(&format!("\nMaximum call depth: {} ({})", self.max_depth(), path.join(" -> ")));
+        text
+    }
+
+    /// The graph in Graphviz DOT: a box per function with its cycles,
+    /// and an edge per caller and callee, dashed for tail calls.
+    pub fn dot(&self) -> String {
+        let mut dot = "digraph calls {\n  node [shape=box];\n".to_string();
+        for &function in &self.functions {
+            dot.push_str(&format!("  \"{}\" [label=\"{}\\n{}, {} with calls\"];\n", self.name(function), self.name(function),
+                plural(self.own.get(&function).copied().unwrap_or(0), "cycle"), self.cumulative.get(&function).unwrap_or(&0)));
+        }
+        for (&(caller, callee), &count) in &self.calls {
+            dot.push_str(&format!("  \"{}\" -> \"{}\" [label=\"{}\"];\n", self.name(caller), self.name(callee), plural(count, "call")));
+        }
+        for (&(caller, callee), &count) in &self.tail_calls {
+            dot.push_str(&format!("  \"{}\" -> \"{}\" [label=\"{}\", style=dashed];\n", self.name(caller), self.name(callee), plural(count, "tail call")));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as a JSON object with the functions, the calls between them and the maximum depth.
+    pub fn json(&self) -> String {
+        let functions: Vec<_> = self.functions.iter().map(|&function| json!({
+            "name": self.name(function),
+            "calls": self.calls.iter().filter(|&(&(_, callee), _)| callee == function).map(|(_, &count)| count).sum::<u64>(),
+            "cycles": self.own.get(&function).unwrap_or(&0),
+            "cumulative_cycles": self.cumulative.get(&function).unwrap_or(&0),
+        })).collect();
+        let edges = |edges: &BTreeMap<(usize, usize), u64>, tail: bool| edges.iter().map(|(&(caller, callee), &count)| json!({
+            "caller": self.name(caller),
+            "callee": self.name(callee),
+            "count": count,
+            "tail": tail,
+        })).collect::<Vec<_>>();
+        let mut calls = edges(&self.calls, false);
+        calls.extend(edges(&self.tail_calls, true));
+        let graph = json!({ "functions": functions, "calls": calls, "max_depth": self.max_depth() });
+        serde_json::to_string_pretty(&graph).expect("call graphs are plain JSON") + "\n"
+    }
+
+    /// Writes the graph to `path`: JSON for a .json file, otherwise DOT.
+    pub fn write(&self, path: &str) -> Result<(), Box<Diagnostic>> {
+        let text = if path.ends_with(".json") { self.json() } else { self.dot() };
+        fs::write(path, text).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write the call graph to {}: {}", path, error))))
+    }
+
+    // Takes a frame of `function` off the stack
+    fn leave(&mut self, function: usize) {
+        if let Some(frames) = self.active.get_mut(&function) {
+            *frames -= 1;
+            if *frames == 0 {
+                self.active.remove(&function);
+            }
+        }
+    }
+
+    fn current(&self) -> usize {
+        *self.stack.last().expect("the entry frame is never popped")
+    }
+
+    fn name(&self, function: usize) -> &str {
+        self.names.get(&function).map_or("<unnamed>", String::as_str)
+    }
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 { format!("1 {}", noun) } else { format!("{} {}s", count, noun) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(names: &[(&str, usize)]) -> HashMap<String, usize> {
+        names.iter().map(|&(name, index)| (name.to_string(), index)).collect()
+    }
+
+    #[test]
+    fn test_call_graph_report() {
+        let mut graph = CallGraph::new(&labels(&[("main", 0), ("fact", 10), ("square", 20), ("done", 30)]), 0, [10, 20]);
+        graph.call(10);
+        graph.call(10);
+        graph.call(20);
+        graph.ret();
+        graph.ret();
+        graph.ret();
+        graph.call(20);
+        graph.jump(21);
+        graph.ret();
+        assert_eq!(graph.leaf_functions(), vec!["square"]);
+        assert_eq!(graph.max_depth(), 4);
+        assert_eq!(graph.report(), "Call graph:\
+            \n  main -> fact (1 call)\
+            \n  main -> square (1 call)\
+            \n  fact -> fact (1 call)\
+            \n  fact -> square (1 call)\
+            \nLeaf functions: square\
+            \nMaximum call depth: 4 (main -> fact -> fact -> square)");
+    }
+
+    #[test]
+    fn test_tail_calls_replace_the_frame() {
+        let mut graph = CallGraph::new(&labels(&[("main", 0), ("outer", 10), ("inner", 20)]), 0, [10, 20]);
+        graph.call(20);
+        graph.ret();
+        graph.call(10);
+        // outer ends with "B inner"
+        graph.jump(20);
+        assert_eq!(graph.max_depth(), 2);
+        graph.ret();
+        graph.call(20);
+        assert_eq!(graph.report(), "Call graph:\
+            \n  main -> outer (1 call)\
+            \n  main -> inner (2 calls)\
+            \n  outer -> inner (1 tail call)\
+            \nLeaf functions: inner\
+            \nMaximum call depth: 2 (main -> inner)");
+    }
+
+    #[test]
+    fn test_tail_call_before_any_call() {
+        // main ends with "B helper", and helper is only called by a BL that has not run yet
+        let mut graph = CallGraph::new(&labels(&[("main", 0), ("loop", 5), ("helper", 20)]), 0, [20]);
+        graph.jump(5);
+        graph.jump(20);
+        assert_eq!(graph.report(), "Call graph:\
+            \n  main -> helper (1 tail call)\
+            \nLeaf functions: helper\
+            \nMaximum call depth: 1 (main)");
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        let mut graph = CallGraph::new(&labels(&[("main", 0), ("fact", 10), ("tail", 20)]), 0, [10, 20]);
+        graph.executed(1);
+        graph.call(20);
+        graph.executed(3);
+        graph.ret();
+        graph.call(10);
+        graph.executed(1);
+        graph.call(10);
+        graph.executed(1);
+        graph.executed(2);
+        graph.ret();
+        // fact ends with "B tail"
+        graph.jump(20);
+        graph.executed(1);
+        graph.ret();
+        graph.executed(3);
+        assert_eq!(graph.dot(), "digraph calls {\n  node [shape=box];\n\
+            \x20 \"main\" [label=\"main\\n4 cycles, 12 with calls\"];\n\
+            \x20 \"fact\" [label=\"fact\\n4 cycles, 4 with calls\"];\n\
+            \x20 \"tail\" [label=\"tail\\n4 cycles, 4 with calls\"];\n\
+            \x20 \"main\" -> \"fact\" [label=\"1 call\"];\n\
+            \x20 \"main\" -> \"tail\" [label=\"1 call\"];\n\
+            \x20 \"fact\" -> \"fact\" [label=\"1 call\"];\n\
+            \x20 \"fact\" -> \"tail\" [label=\"1 tail call\", style=dashed];\n}\n");
+        let json: serde_json::Value = serde_json::from_str(&graph.json()).unwrap();
+        assert_eq!(json["functions"][1], json!({"name": "fact", "calls": 2, "cycles": 4, "cumulative_cycles": 4}));
+        assert_eq!(json["calls"][3], json!({"caller": "fact", "callee": "tail", "count": 1, "tail": true}));
+        assert_eq!(json["max_depth"], 3);
+    }
+}
@@ -1,11 +1,535 @@
-use clap::Parser;
+use std::path::PathBuf;
+use std::str::FromStr;
+use clap::builder::{PossibleValue, PossibleValuesParser};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use asm::breakpoints::Breakpoint;
+use asm::cache::CacheConfig;
+use asm::config::Config;
+use asm::cores::{Interleaving, MAX_CORES};
+use asm::diagnostics;
+use asm::examples::EXAMPLES;
+use asm::exercises::EXERCISES;
+use asm::grading::ReportFormat;
+use asm::jupyter;
+use asm::history::MAX_HISTORY_DEPTH;
+use asm::interpreter::{parse_immediate, Profile};
+use asm::memory_map::{self, Region};
+use asm::target::Target;
+use asm::prompt::Prompt;
+use asm::isa::FRONT_ENDS;
+use asm::radix::Setting;
+use asm::timeline::TraceFormat;
+use asm::watch::WatchRange;
 
 #[derive(Parser)]
-#[command(name = crate::APP_NAME)]
-#[command(version = crate::APP_VERSION)]
-#[command(about = crate::APP_DESCRIPTION, long_about = None)]
+#[command(name = asm::APP_NAME)]
+#[command(version = asm::APP_VERSION)]
+#[command(about = asm::APP_DESCRIPTION, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Cli {
-    /// Optional input file to execute.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Input files to execute, linked into a single program.
+    /// Execution starts at a `_start` or `main` label if one is defined.
     /// If not provided, the interpreter runs in interactive mode.
-    pub input_file: Option<String>,
-}
\ No newline at end of file
+    pub input_files: Vec<String>,
+
+    /// Label to start execution at, instead of `_start`, `main` or the first line.
+    #[arg(long, value_name = "LABEL")]
+    pub entry: Option<String>,
+
+    /// Set a register before execution starts, e.g. `--set r1=0x100`. Can be repeated.
+    #[arg(long = "set", value_name = "REG=VALUE", value_parser = parse_register_seed)]
+    pub registers: Vec<(String, i32)>,
+
+    /// Set a memory word before execution starts, e.g. `--mem 0x20=7`. Can be repeated.
+    #[arg(long = "mem", value_name = "ADDR=VALUE", value_parser = parse_memory_seed)]
+    pub memory: Vec<(usize, i32)>,
+
+    /// The size of memory, e.g. `--memory-bytes 4G` for the base addresses of a real board. Takes a K, M or G
+    /// suffix, from 4K, the default, to 8G; addresses count words of 4 bytes. Only the pages written take room.
+    #[arg(long, value_name = "SIZE", value_parser = memory_map::parse_memory_bytes)]
+    pub memory_bytes: Option<u64>,
+
+    /// A region of memory, `NAME @START ro|rw|mmio SIZE` with START and SIZE in bytes, e.g. `--memory-map "sram @0x2000_0000 rw 20K"`. Can be
+    /// repeated; with regions, accesses outside them and stores to ro regions fail, and data labels start in the first
+    /// rw region. Replaces the memory-map of the configuration files.
+    #[arg(long, value_name = "REGION", value_parser = Region::from_str)]
+    pub memory_map: Vec<Region>,
+
+    /// Start the program as the board does out of reset: with its memory map unless --memory-map is given, sp from the
+    /// first word of its vector table and pc at Reset_Handler if the program defines it. Only for --isa arm and thumb.
+    #[arg(long, value_enum, value_name = "TARGET")]
+    pub target: Option<Target>,
+
+    /// Stop with an error on signed overflow of ADD, SUB, MUL, ADC and SBC instead of wrapping around.
+    #[arg(long)]
+    pub trap_overflow: bool,
+
+    /// Compute with words of 8 or 16 bits instead of 32, to emulate a small machine: results wrap around and
+    /// set the flags at that width, and registers and memory words hold its values.
+    #[arg(long, value_name = "BITS", value_parser = parse_word_size)]
+    pub word_size: Option<u32>,
+
+    /// Make the string helpers SVC #5 strlen, #6 strcmp and #7 memcpy an error, for labs where the program
+    /// implements them itself.
+    #[arg(long)]
+    pub no_helpers: bool,
+
+    /// After the run, print which functions called which, the leaf functions and the deepest nesting of calls.
+    #[arg(long)]
+    pub call_graph: bool,
+
+    /// After the run, write the call graph with the calls between functions and the instructions each ran,
+    /// alone and with the functions it called, to FILE: JSON for .json, otherwise Graphviz DOT.
+    #[arg(long, value_name = "FILE")]
+    pub call_graph_file: Option<String>,
+
+    /// Do not read the space, s and c hotkeys that pause, step and continue a running program.
+    #[arg(long)]
+    pub no_hotkeys: bool,
+
+    /// Pause before a label, line or file:line, like at a BKPT, e.g. `--break "loop if r2 > 100"` or
+    /// `--break "14 count 50"` to stop from the 50th time line 14 is reached. Can be repeated.
+    #[arg(long = "break", value_name = "WHERE", value_parser = Breakpoint::from_str)]
+    pub breakpoints: Vec<Breakpoint>,
+
+    /// Run the debugger commands in FILE: set breakpoints, run, step, print and check values.
+    #[arg(long, value_name = "FILE")]
+    pub debug_script: Option<String>,
+
+    /// Run the Rhai script FILE with the program: memory-mapped devices, checks of the final state and
+    /// filters of which steps go to --trace.
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<String>,
+
+    /// After the run, write how often each memory word was read and written to FILE: a CSV table for .csv,
+    /// an image for .png, otherwise a text grid. `-` prints the grid.
+    #[arg(long, value_name = "FILE")]
+    pub heatmap: Option<String>,
+
+    /// Simulate a data cache of SIZE bytes, WAYS lines per set (direct, full or e.g. 4way) and LINE bytes
+    /// per line, e.g. `1k,4way,16B`, and print its hits and misses overall and per instruction after the run.
+    #[arg(long, value_name = "SIZE,WAYS,LINE", value_parser = CacheConfig::from_str)]
+    pub cache: Option<CacheConfig>,
+
+    /// After the run, write a self-contained HTML report to FILE: the source with the lines that ran
+    /// highlighted, the trace, the final registers, flags and memory, and whether the run passed.
+    #[arg(long, value_name = "FILE")]
+    pub html: Option<String>,
+
+    /// After the run, write the registers, flags, memory and data labels to FILE as JSON, for `asm diff`,
+    /// even when the run fails.
+    #[arg(long, value_name = "FILE")]
+    pub snapshot: Option<String>,
+
+    /// Write a snapshot of the state every N instructions to --checkpoint-dir, e.g. to compare the states
+    /// around step 53000 of a long run with `asm diff`.
+    #[arg(long, value_name = "N", requires = "checkpoint_dir", value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_every: Option<u64>,
+
+    /// Directory for the snapshots of --checkpoint-every, named after their step, e.g. `step-53000.json`.
+    #[arg(long, value_name = "DIR", requires = "checkpoint_every")]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// Write what each instruction changed, registers, flags and memory words, to FILE as the program runs.
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<String>,
+
+    /// How --trace records steps: a readable line each, or one JSON object each for visualization tools.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "text")]
+    pub trace_format: TraceFormat,
+
+    /// Watch a region of memory, `START..END` without END or a single word, each an address or a data label,
+    /// e.g. `--watch-memory grid..512`, and write each change of its words to --watch-events. Can be repeated.
+    #[arg(long, value_name = "RANGE", requires = "watch_events", value_parser = WatchRange::from_str)]
+    pub watch_memory: Vec<WatchRange>,
+
+    /// Where --watch-memory writes its events, one JSON object per line with the address, the old and new
+    /// value, the instruction and the step. `-` writes them to stdout.
+    #[arg(long, value_name = "FILE", requires = "watch_memory")]
+    pub watch_events: Option<String>,
+
+    /// Mark the value of a register, memory address or data label as tainted, e.g. `--taint r0`, follow it
+    /// through arithmetic, loads and stores, and print every instruction and location it reached. Can be repeated.
+    #[arg(long, value_name = "SOURCE")]
+    pub taint: Vec<String>,
+
+    /// Reject programs that use any instruction but these, e.g. `--allow-only MOV,ADD,SUB,B,CMP`. A mnemonic also
+    /// allows its variants, such as ADDS and BEQ; PRINT, EXIT and BKPT are always allowed.
+    #[arg(long, value_name = "MNEMONICS", value_delimiter = ',')]
+    pub allow_only: Vec<String>,
+
+    /// Reject programs that use these instructions or their variants, e.g. `--forbid MUL`.
+    #[arg(long, value_name = "MNEMONICS", value_delimiter = ',')]
+    pub forbid: Vec<String>,
+
+    /// Stop with an error once the program takes more than N cycles, overriding a `.budget cycles` directive.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub cycle_budget: Option<u64>,
+
+    /// Start every register and memory word the program does not set as undefined rather than 0, and warn
+    /// when a branch, a memory address or PRINT depends on an undefined value.
+    #[arg(long)]
+    pub undefined: bool,
+
+    /// Warn about instructions that have no effect, such as MOV r1, r1, and registers written again
+    /// before anything reads them.
+    #[arg(long)]
+    pub lint: bool,
+
+    /// Run the program on N cores with their own registers and one shared memory. Each starts at the entry
+    /// point with its number, from 0, in r0 (a0 for rv32i, rdi for x86).
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..=MAX_CORES as i64))]
+    pub cores: u32,
+
+    /// How the cores take turns: `round-robin` one instruction each, `round-robin:N` N instructions each,
+    /// or `random:SEED` a core picked at random each step, the same ones for the same seed.
+    #[arg(long, value_name = "ORDER", default_value = "round-robin", value_parser = Interleaving::from_str)]
+    pub interleave: Interleaving,
+
+    /// Write which core ran each turn, and for how many instructions, to FILE, even when the run fails.
+    #[arg(long, value_name = "FILE")]
+    pub record_schedule: Option<String>,
+
+    /// Run the cores in the turns of a schedule from --record-schedule, to repeat that run exactly,
+    /// then as --interleave picks.
+    #[arg(long, value_name = "FILE")]
+    pub replay_schedule: Option<String>,
+
+    /// Map the terminal's keyboard to the memory word at ADDRESS, a number or a data label. It holds the last key
+    /// pressed, without waiting for Enter, and the program stores 0 there once it has handled the key. Turns off the
+    /// hotkeys.
+    #[arg(long, value_name = "ADDRESS")]
+    pub keyboard: Option<String>,
+
+    /// Show registers in PRINT, RECENT, HISTORY and register listings in dec, hex or bin, e.g. `--radix hex`,
+    /// or show one register in its own radix, e.g. `--radix r7=bin`. Can be repeated.
+    #[arg(long, value_name = "RADIX", value_parser = Setting::from_str)]
+    pub radix: Vec<Setting>,
+
+    /// Stop with an error at BKPT instead of printing the registers and continuing.
+    #[arg(long)]
+    pub strict_bkpt: bool,
+
+    /// Reject immediates a real ARM instruction could not encode, such as MOV r0, #257.
+    #[arg(long)]
+    pub strict_imm: bool,
+
+    /// Reject immediates that are cut down to fit, such as LSL r0, r0, #40, instead of warning.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// How closely programs must keep to real ARM: teaching accepts anything the interpreter runs, strict what an assembler would.
+    #[arg(long, value_enum, default_value = "teaching")]
+    pub profile: Profile,
+
+    /// Only show program output and errors, without banners or label messages.
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Show loading and execution details; repeat (-vv) to also show decoding and operand resolution.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Instruction set the program is written in.
+    #[arg(long, value_parser = isa_parser(), default_value = FRONT_ENDS[0].name())]
+    pub isa: String,
+
+    /// Run programs on a machine defined in a TOML file instead: its instructions, their operands
+    /// and what each does, in a small statement language.
+    #[arg(long, value_name = "FILE", conflicts_with = "isa")]
+    pub isa_file: Option<PathBuf>,
+
+    /// Load the instructions and SVC services of a plugin, a dynamic library such as `libcrypto_ops.so`
+    /// built against the plugin ABI of `asm::plugin`, for ARM and Thumb programs. May be given more than once.
+    #[arg(long, value_name = "LIBRARY")]
+    pub plugin: Vec<String>,
+
+    /// Serve a web playground on ADDRESS, e.g. `:8080` on this machine only or `0.0.0.0:8080` on every interface: a page to write,
+    /// run and step through programs in a browser, and its JSON API. Each run has a fresh interpreter.
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "input_files")]
+    pub serve: Option<String>,
+
+    /// How errors are reported: readable text, or one JSON object per line for editors and graders.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "human")]
+    pub diagnostics: diagnostics::Format,
+
+    /// When to color the severity of readable errors and warnings.
+    #[arg(long, value_enum, value_name = "WHEN", default_value = "auto")]
+    pub color: diagnostics::Color,
+
+    /// How many writes of each register HISTORY lists, 16 by default.
+    #[arg(long, value_name = "N", value_parser = parse_history_size)]
+    pub history_size: Option<usize>,
+
+    /// Append every line typed, every line of program output and every error, each with the time, to FILE,
+    /// e.g. to review a lab session afterwards.
+    #[arg(long, value_name = "FILE")]
+    pub log_session: Option<String>,
+
+    /// The prompt of interactive mode, with registers, flags, pc (the next instruction's number) and data labels
+    /// in braces filled in before each line, e.g. `[pc={pc} Z={Z} r0={r0}]> `.
+    #[arg(long, value_name = "FORMAT", value_parser = Prompt::from_str)]
+    pub prompt: Option<Prompt>,
+
+    /// Ignore the defaults in ~/.config/asm-interpreter/config.toml and ./.asmrc.
+    #[arg(long)]
+    pub no_config: bool,
+}
+
+impl Cli {
+    /// Takes the options the command line (parsed into `matches`) leaves out from `config`.
+    pub fn apply(&mut self, config: Config, matches: &ArgMatches) {
+        let given = |matches: &ArgMatches, id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let Some(isa) = &config.isa {
+            if !given(matches, "isa") {
+                self.isa = isa.clone();
+            }
+            if let Some(Command::Lsp { isa: command_isa } | Command::Jupyter { isa: command_isa, .. }) = &mut self.command
+                && let Some(("lsp" | "jupyter", command)) = matches.subcommand()
+                && !given(command, "isa") {
+                *command_isa = isa.clone();
+            }
+        }
+        // The command line adds to or overrides the configured radixes
+        self.radix.splice(0..0, config.radix);
+        if let Some(format) = config.diagnostics.filter(|_| !given(matches, "diagnostics")) {
+            self.diagnostics = format;
+        }
+        if let Some(color) = config.color.filter(|_| !given(matches, "color")) {
+            self.color = color;
+        }
+        if let Some(format) = config.trace_format.filter(|_| !given(matches, "trace_format")) {
+            self.trace_format = format;
+        }
+        if let Some(profile) = config.profile.filter(|_| !given(matches, "profile")) {
+            self.profile = profile;
+        }
+        if !given(matches, "quiet") && !given(matches, "verbose") {
+            self.quiet = config.quiet.unwrap_or(false);
+            self.verbose = config.verbose.unwrap_or(0);
+        }
+        let flags = [
+            (&mut self.trap_overflow, "trap_overflow", config.trap_overflow),
+            (&mut self.call_graph, "call_graph", config.call_graph),
+            (&mut self.no_hotkeys, "no_hotkeys", config.hotkeys.map(|hotkeys| !hotkeys)),
+            (&mut self.strict_bkpt, "strict_bkpt", config.strict_bkpt),
+            (&mut self.strict_imm, "strict_imm", config.strict_imm),
+            (&mut self.strict, "strict", config.strict),
+            (&mut self.lint, "lint", config.lint),
+            (&mut self.no_helpers, "no_helpers", config.helpers.map(|helpers| !helpers)),
+        ];
+        for (flag, id, configured) in flags {
+            if let Some(configured) = configured.filter(|_| !given(matches, id)) {
+                *flag = configured;
+            }
+        }
+        if config.log_session.is_some() && !given(matches, "log_session") {
+            self.log_session = config.log_session;
+        }
+        if config.prompt.is_some() && !given(matches, "prompt") {
+            self.prompt = config.prompt;
+        }
+        if config.cache.is_some() && !given(matches, "cache") {
+            self.cache = config.cache;
+        }
+        if config.word_size.is_some() && !given(matches, "word_size") {
+            self.word_size = config.word_size;
+        }
+        if config.memory_bytes.is_some() && !given(matches, "memory_bytes") {
+            self.memory_bytes = config.memory_bytes;
+        }
+        if config.history_size.is_some() && !given(matches, "history_size") {
+            self.history_size = config.history_size;
+        }
+        if config.trace.is_some() && !given(matches, "trace") {
+            self.trace = config.trace;
+        }
+        if let Some(map) = config.memory_map.filter(|_| !given(matches, "memory_map")) {
+            self.memory_map = map.regions().to_vec();
+        }
+        if config.target.is_some() && !given(matches, "target") {
+            self.target = config.target;
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a Language Server Protocol server on stdin/stdout for editor integration.
+    Lsp {
+        /// Instruction set the edited programs are written in.
+        #[arg(long, value_parser = isa_parser(), default_value = FRONT_ENDS[0].name())]
+        isa: String,
+    },
+    /// Run a Jupyter kernel, so notebooks can have assembly cells; Jupyter starts it from a kernel spec.
+    Jupyter {
+        /// Connection file Jupyter passes, with the ports and the signing key.
+        connection_file: PathBuf,
+        /// Instruction set the cells are written in.
+        #[arg(long, value_parser = isa_parser(), default_value = FRONT_ENDS[0].name())]
+        isa: String,
+        /// Instructions a cell may run before it is stopped.
+        #[arg(long, value_name = "N", default_value_t = jupyter::MAX_STEPS)]
+        max_steps: u64,
+    },
+    /// Create a directory with a starter program, a test script, its expected output and an `.asmrc`.
+    New {
+        /// Directory to create, which must not exist yet.
+        directory: PathBuf,
+    },
+    /// List the built-in example programs, or run one.
+    Examples {
+        #[command(subcommand)]
+        action: Option<ExamplesAction>,
+    },
+    /// List the built-in exercises, show one, or check a solution to it.
+    Exercise {
+        /// Exercise to show, e.g. `sum-memory`; without it, the exercises are listed.
+        #[arg(value_parser = exercise_parser())]
+        name: Option<String>,
+        /// Solution to check against the goal of the exercise.
+        solution: Option<String>,
+    },
+    /// Run every submission in a directory against the cases of a rubric and print the scores.
+    Grade {
+        /// TOML file with the test cases and their points.
+        #[arg(long, value_name = "FILE")]
+        rubric: PathBuf,
+        /// Directory with a `.s` file or a subdirectory of `.s` files per student.
+        submissions: PathBuf,
+        /// Format of the score report.
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ReportFormat,
+        /// Submissions graded at once, by default one per CPU core.
+        #[arg(long, short = 'j', value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: Option<u32>,
+    },
+    /// Run a program and a reference solution on the same cases and report where their results differ.
+    Compare {
+        /// Program to check.
+        program: String,
+        /// Reference solution it should match.
+        reference: String,
+        /// TOML file with the cases, in the form of a rubric; their expectations are not needed.
+        #[arg(long, value_name = "FILE")]
+        cases: PathBuf,
+    },
+    /// Print the registers, flags and memory words that differ between two snapshots from --snapshot.
+    Diff {
+        /// Snapshot from before.
+        before: String,
+        /// Snapshot from after.
+        after: String,
+    },
+
+    /// Print a completion script for a shell, e.g. `asm completions bash > /etc/bash_completion.d/asm`.
+    Completions {
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ExamplesAction {
+    /// Run an example program, e.g. `asm examples run fibonacci`.
+    Run {
+        #[arg(value_parser = example_parser())]
+        name: String,
+    },
+    /// Print the source of an example program, to copy and change it.
+    Show {
+        #[arg(value_parser = example_parser())]
+        name: String,
+    },
+}
+
+fn example_parser() -> PossibleValuesParser {
+    PossibleValuesParser::new(EXAMPLES.iter().map(|example| PossibleValue::new(example.name).help(example.description)))
+}
+
+fn exercise_parser() -> PossibleValuesParser {
+    PossibleValuesParser::new(EXERCISES.iter().map(|exercise| PossibleValue::new(exercise.name)))
+}
+
+// Accepts the name of any registered front-end, listing them all in --help
+fn isa_parser() -> PossibleValuesParser {
+    PossibleValuesParser::new(FRONT_ENDS.iter().map(|front_end| PossibleValue::new(front_end.name()).help(front_end.description())))
+}
+
+// Parses "r0=42" into a register name and value; the name is checked against the registers of --isa later
+fn parse_register_seed(s: &str) -> Result<(String, i32), String> {
+    let (reg, value) = s.split_once('=').ok_or("expected REG=VALUE, e.g. r0=42")?;
+    Ok((reg.trim().to_string(), parse_seed_value(value)?))
+}
+
+// Parses the bits of --word-size
+fn parse_word_size(s: &str) -> Result<u32, String> {
+    match s.trim() {
+        "8" => Ok(8),
+        "16" => Ok(16),
+        "32" => Ok(32),
+        _ => Err(format!("invalid word size '{}', expected 8, 16 or 32", s)),
+    }
+}
+
+// Parses "0x20=7" into a memory address and value
+fn parse_memory_seed(s: &str) -> Result<(usize, i32), String> {
+    let (addr, value) = s.split_once('=').ok_or("expected ADDR=VALUE, e.g. 0x20=7")?;
+    let address = parse_seed_value(addr)?;
+    if address < 0 {
+        return Err(format!("memory address {} is negative", address));
+    }
+    Ok((address as usize, parse_seed_value(value)?))
+}
+
+// Parses a number of register writes for HISTORY to keep
+fn parse_history_size(s: &str) -> Result<usize, String> {
+    s.trim().parse().ok().filter(|size| (1..=MAX_HISTORY_DEPTH).contains(size))
+        .ok_or(format!("invalid history size '{}', expected a number from 1 to {}", s.trim(), MAX_HISTORY_DEPTH))
+}
+
+// Parses a decimal or 0x-prefixed hexadecimal number, with an optional leading '#'
+fn parse_seed_value(s: &str) -> Result<i32, String> {
+    let s = s.trim();
+    parse_immediate(s.strip_prefix('#').unwrap_or(s)).ok_or(format!("invalid number '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    // The options of `args` with the defaults of the configuration file `config` applied
+    fn configured(args: &[&str], config: &str) -> Cli {
+        let matches = Cli::command().get_matches_from(std::iter::once("asm").chain(args.iter().copied()));
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        cli.apply(Config::parse(config).unwrap(), &matches);
+        cli
+    }
+
+    const CONFIG: &str = "isa = \"thumb\"\ndiagnostics = \"json\"\ntrap-overflow = true\nword-size = 16\n\
+        memory-bytes = \"64K\"\nhistory-size = 64\ntrace = \"run.trace\"\ntrace-format = \"json\"\ncolor = \"never\"";
+
+    #[test]
+    fn test_config_fills_in_options() {
+        let cli = configured(&[], CONFIG);
+        assert_eq!((cli.isa.as_str(), cli.diagnostics, cli.trap_overflow, cli.word_size), ("thumb", diagnostics::Format::Json, true, Some(16)));
+        assert_eq!((cli.memory_bytes, cli.history_size), (Some(64 << 10), Some(64)));
+        assert_eq!((cli.trace.as_deref(), cli.trace_format), (Some("run.trace"), TraceFormat::Json));
+        assert_eq!(cli.color, diagnostics::Color::Never);
+    }
+
+    #[test]
+    fn test_command_line_overrides_config() {
+        let cli = configured(&["--isa", "arm", "--diagnostics", "human", "--word-size", "8", "--memory-bytes", "8K",
+            "--history-size", "4", "--trace", "mine.trace", "--trace-format", "text", "--color", "always"], CONFIG);
+        assert_eq!((cli.isa.as_str(), cli.diagnostics, cli.word_size), ("arm", diagnostics::Format::Human, Some(8)));
+        assert_eq!((cli.memory_bytes, cli.history_size), (Some(8 << 10), Some(4)));
+        assert_eq!((cli.trace.as_deref(), cli.trace_format), (Some("mine.trace"), TraceFormat::Text));
+        assert_eq!(cli.color, diagnostics::Color::Always);
+    }
+}
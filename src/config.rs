@@ -0,0 +1,283 @@
+//! Defaults for command-line options, read from configuration files.
+//!
+//! `~/.config/asm-interpreter/config.toml` (under `$XDG_CONFIG_HOME` when it is
+//! set) holds a user's defaults, and an `.asmrc` in the current directory those
+//! of a project, taking precedence. Both are TOML with keys named after the
+//! options they set:
+//!
+//! ```toml
+//! isa = "thumb"
+//! radix = ["hex", "r7=bin"]
+//! verbose = 1
+//! trap-overflow = true
+//! hotkeys = false
+//! cache = "1k,4way,16B"
+//! memory-map = ["flash @0x0800_0000 ro 128K", "sram @0x2000_0000 rw 20K", "peripheral @0x4000_0000 mmio"]
+//! target = "cortex-m-like"
+//! memory-bytes = "64K"
+//! history-size = 64
+//! trace = "run.trace"
+//! trace-format = "json"
+//! color = "never"
+//! ```
+//!
+//! Options given on the command line override both files; `--no-config` ignores them.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use crate::cache::CacheConfig;
+use crate::diagnostics::{Code, Color, Diagnostic, Format};
+use crate::history::MAX_HISTORY_DEPTH;
+use crate::interpreter::Profile;
+use crate::memory_map::{self, MemoryMap};
+use crate::target::Target;
+use crate::isa;
+use crate::prompt::Prompt;
+use crate::radix::Setting;
+use crate::timeline::TraceFormat;
+
+const KEYS: &str = "isa, radix, diagnostics, verbose, quiet, trap-overflow, call-graph, hotkeys, strict-bkpt, strict-imm, strict, lint, word-size, profile, helpers, cache, memory-map, target, memory-bytes, history-size, trace, trace-format, color, log-session and prompt";
+
+/// The options a configuration file sets; `None` where it leaves the default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub isa: Option<String>,
+    /// Applied before any `--radix` of the command line
+    pub radix: Vec<Setting>,
+    pub diagnostics: Option<Format>,
+    pub verbose: Option<u8>,
+    pub quiet: Option<bool>,
+    pub trap_overflow: Option<bool>,
+    pub call_graph: Option<bool>,
+    pub hotkeys: Option<bool>,
+    pub strict_bkpt: Option<bool>,
+    pub strict_imm: Option<bool>,
+    pub strict: Option<bool>,
+    pub lint: Option<bool>,
+    pub word_size: Option<u32>,
+    pub profile: Option<Profile>,
+    pub helpers: Option<bool>,
+    pub cache: Option<CacheConfig>,
+    pub memory_map: Option<MemoryMap>,
+    pub target: Option<Target>,
+    pub memory_bytes: Option<u64>,
+    pub history_size: Option<usize>,
+    pub trace: Option<String>,
+    pub trace_format: Option<TraceFormat>,
+    pub color: Option<Color>,
+    pub log_session: Option<String>,
+    pub prompt: Option<Prompt>,
+}
+
+impl Config {
+    /// Parses the TOML `text` of a configuration file, describing the first problem on failure.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table: toml::Table = text.parse().map_err(|error: toml::de::Error| match error.span() {
+            Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, error.message()),
+            None => error.message().to_string(),
+        })?;
+        let mut config = Config::default();
+        for (key, value) in &table {
+            let expected = |kind: &str| format!("'{}' must be {}", key, kind);
+            let string = || value.as_str().ok_or_else(|| expected("a string"));
+            let boolean = || value.as_bool().ok_or_else(|| expected("true or false")).map(Some);
+            match key.as_str() {
+                "isa" => {
+                    let name = string()?;
+                    if isa::front_end(name).is_none() {
+                        let names: Vec<&str> = isa::FRONT_ENDS.iter().map(|front_end| front_end.name()).collect();
+                        return Err(format!("unknown isa '{}', expected one of {}", name, names.join(", ")));
+                    }
+                    config.isa = Some(name.to_string());
+                },
+                // One setting or a list of them
+                "radix" => {
+                    let settings = match value.as_array() {
+                        Some(settings) => settings.iter().map(|setting| setting.as_str()).collect::<Option<Vec<_>>>(),
+                        None => value.as_str().map(|setting| vec![setting]),
+                    };
+                    let settings = settings.ok_or_else(|| expected("a string or a list of strings"))?;
+                    config.radix = settings.into_iter().map(str::parse).collect::<Result<_, _>>()?;
+                },
+                "diagnostics" => config.diagnostics = Some(Format::from_str(string()?, true)
+                    .map_err(|_| expected("\"human\" or \"json\""))?),
+                "verbose" => config.verbose = Some(value.as_integer().and_then(|level| u8::try_from(level).ok())
+                    .filter(|&level| level <= 2).ok_or_else(|| expected("0, 1 or 2"))?),
+                "quiet" => config.quiet = boolean()?,
+                "trap-overflow" => config.trap_overflow = boolean()?,
+                "call-graph" => config.call_graph = boolean()?,
+                "hotkeys" => config.hotkeys = boolean()?,
+                "strict-bkpt" => config.strict_bkpt = boolean()?,
+                "strict-imm" => config.strict_imm = boolean()?,
+                "strict" => config.strict = boolean()?,
+                "lint" => config.lint = boolean()?,
+                "word-size" => config.word_size = Some(value.as_integer().and_then(|bits| u32::try_from(bits).ok())
+                    .filter(|bits| [8, 16, 32].contains(bits)).ok_or_else(|| expected("8, 16 or 32"))?),
+                "profile" => config.profile = Some(Profile::from_str(string()?, true)
+                    .map_err(|_| expected("\"teaching\" or \"strict\""))?),
+                "helpers" => config.helpers = boolean()?,
+                "cache" => config.cache = Some(string()?.parse().map_err(|error| format!("invalid cache: {}", error))?),
+                "memory-map" => {
+                    let regions = value.as_array().and_then(|regions| regions.iter().map(|region| region.as_str()).collect::<Option<Vec<_>>>())
+                        .ok_or_else(|| expected("a list of regions, e.g. [\"sram @0x2000_0000 rw 20K\"]"))?;
+                    config.memory_map = Some(MemoryMap::new(regions.into_iter().map(str::parse).collect::<Result<_, _>>()?)?);
+                },
+                "target" => config.target = Some(Target::from_str(string()?, true)
+                    .map_err(|_| expected("\"cortex-m-like\""))?),
+                // A size such as "64K", or a number of bytes
+                "memory-bytes" => config.memory_bytes = Some(match value.as_integer() {
+                    Some(bytes) => memory_map::parse_memory_bytes(&bytes.to_string())?,
+                    None => memory_map::parse_memory_bytes(string()?)?,
+                }),
+                "history-size" => config.history_size = Some(value.as_integer().and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| (1..=MAX_HISTORY_DEPTH).contains(size))
+                    .ok_or_else(|| expected(&format!("a number from 1 to {}", MAX_HISTORY_DEPTH)))?),
+                "trace" => config.trace = Some(string()?.to_string()),
+                "trace-format" => config.trace_format = Some(TraceFormat::from_str(string()?, true)
+                    .map_err(|_| expected("\"text\" or \"json\""))?),
+                "color" => config.color = Some(Color::from_str(string()?, true)
+                    .map_err(|_| expected("\"auto\", \"always\" or \"never\""))?),
+                "log-session" => config.log_session = Some(string()?.to_string()),
+                "prompt" => config.prompt = Some(string()?.parse()?),
+                _ => return Err(format!("unknown setting '{}', expected {}", key, KEYS)),
+            }
+        }
+        if config.quiet == Some(true) && config.verbose.is_some_and(|level| level > 0) {
+            return Err("'quiet' and 'verbose' cannot both be set".to_string());
+        }
+        Ok(config)
+    }
+
+    /// Adds the settings of a later file, which take precedence.
+    pub fn merge(&mut self, later: Config) {
+        self.isa = later.isa.or(self.isa.take());
+        self.radix.extend(later.radix);
+        self.diagnostics = later.diagnostics.or(self.diagnostics);
+        // Quiet and verbose exclude each other, so the later file decides both
+        if later.quiet.is_some() || later.verbose.is_some() {
+            (self.quiet, self.verbose) = (later.quiet, later.verbose);
+        }
+        self.trap_overflow = later.trap_overflow.or(self.trap_overflow);
+        self.call_graph = later.call_graph.or(self.call_graph);
+        self.hotkeys = later.hotkeys.or(self.hotkeys);
+        self.strict_bkpt = later.strict_bkpt.or(self.strict_bkpt);
+        self.strict_imm = later.strict_imm.or(self.strict_imm);
+        self.strict = later.strict.or(self.strict);
+        self.lint = later.lint.or(self.lint);
+        self.word_size = later.word_size.or(self.word_size);
+        self.profile = later.profile.or(self.profile);
+        self.helpers = later.helpers.or(self.helpers);
+        self.cache = later.cache.or(self.cache);
+        self.memory_map = later.memory_map.or(self.memory_map.take());
+        self.target = later.target.or(self.target);
+        self.memory_bytes = later.memory_bytes.or(self.memory_bytes);
+        self.history_size = later.history_size.or(self.history_size);
+        self.trace = later.trace.or(self.trace.take());
+        self.trace_format = later.trace_format.or(self.trace_format);
+        self.color = later.color.or(self.color);
+        self.log_session = later.log_session.or(self.log_session.take());
+        self.prompt = later.prompt.or(self.prompt.take());
+    }
+
+    /// Reads the configuration files that exist among `paths`, later ones taking precedence.
+    pub fn load(paths: &[PathBuf]) -> Result<Self, Box<Diagnostic>> {
+        let mut config = Config::default();
+        for path in paths {
+            let text = match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(invalid(path, error.to_string())),
+            };
+            config.merge(Config::parse(&text).map_err(|message| invalid(path, message))?);
+        }
+        Ok(config)
+    }
+}
+
+fn invalid(path: &Path, message: String) -> Box<Diagnostic> {
+    Box::new(Diagnostic::error(Code::InvalidConfig, format!("Invalid configuration file {}: {}", path.display(), message)))
+}
+
+/// The user's configuration file, then the project's.
+pub fn default_paths() -> Vec<PathBuf> {
+    user_dir().map(|dir| dir.join("config.toml")).into_iter()
+        .chain([PathBuf::from(".asmrc")])
+        .collect()
+}
+
+/// The file the snippets of interactive mode are saved to, next to the user's configuration file.
+pub fn snippets_path() -> Option<PathBuf> {
+    user_dir().map(|dir| dir.join("snippets.s"))
+}
+
+fn user_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME").filter(|home| !home.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    config_home.map(|home| home.join("asm-interpreter"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("isa = \"thumb\"\nradix = \"hex\"\nverbose = 1\nhotkeys = false\ncache = \"1k,4way,16B\"").unwrap();
+        assert_eq!(config.isa.as_deref(), Some("thumb"));
+        assert_eq!(config.radix, vec!["hex".parse().unwrap()]);
+        assert_eq!((config.verbose, config.hotkeys), (Some(1), Some(false)));
+        assert_eq!(config.cache, Some("1k,4way,16B".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut config = Config::parse("isa = \"thumb\"\nradix = \"hex\"\nverbose = 1").unwrap();
+        config.merge(Config::parse("radix = [\"r7=bin\"]\nquiet = true\ndiagnostics = \"json\"\nprofile = \"strict\"\nlog-session = \"lab.log\"\nprompt = \"{r0}> \"\nlint = true\nword-size = 8").unwrap());
+        // What the second file leaves out stays, radixes add up, and quiet replaces verbose
+        assert_eq!(config.isa.as_deref(), Some("thumb"));
+        assert_eq!(config.radix.len(), 2);
+        assert_eq!((config.quiet, config.verbose), (Some(true), None));
+        assert_eq!(config.diagnostics, Some(Format::Json));
+        assert_eq!(config.profile, Some(Profile::Strict));
+        assert_eq!(config.log_session.as_deref(), Some("lab.log"));
+        assert_eq!(config.prompt, Some("{r0}> ".parse().unwrap()));
+        assert_eq!(config.lint, Some(true));
+        assert_eq!(config.word_size, Some(8));
+    }
+
+    #[test]
+    fn test_memory_settings() {
+        let config = Config::parse("memory-map = [\"sram @0x2000_0000 rw 20K\", \"flash @0x0800_0000 ro 128K\"]\ntarget = \"cortex-m-like\"").unwrap();
+        assert_eq!(config.memory_map.as_ref().map(|map| map.regions()[0].name.as_str()), Some("flash"));
+        assert_eq!(config.target, Some(Target::CortexMLike));
+        assert_eq!(Config::parse("memory-bytes = \"64K\"").unwrap().memory_bytes, Some(64 << 10));
+        assert_eq!(Config::parse("memory-bytes = 8192").unwrap().memory_bytes, Some(8192));
+    }
+
+    #[test]
+    fn test_output_settings() {
+        let config = Config::parse("history-size = 64\ntrace = \"run.trace\"\ntrace-format = \"json\"\ncolor = \"never\"").unwrap();
+        assert_eq!(config.history_size, Some(64));
+        assert_eq!((config.trace.as_deref(), config.trace_format), (Some("run.trace"), Some(TraceFormat::Json)));
+        assert_eq!(config.color, Some(Color::Never));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert_eq!(Config::parse("verbose = 3"), Err("'verbose' must be 0, 1 or 2".to_string()));
+        assert_eq!(Config::parse("memory-bytes = \"2K\"").unwrap_err(), "invalid size '2K', expected a multiple of 4 bytes from 4K to 8G");
+        assert_eq!(Config::parse("history-size = 0"), Err("'history-size' must be a number from 1 to 65536".to_string()));
+        assert_eq!(Config::parse("color = \"rainbow\""), Err("'color' must be \"auto\", \"always\" or \"never\"".to_string()));
+        assert!(Config::parse("isa = \"mips\"").unwrap_err().starts_with("unknown isa 'mips'"));
+        assert_eq!(Config::parse("memory-map = [\"a @0 rw 8\", \"b @4 ro 4\"]"), Err("the regions 'a' and 'b' overlap".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert_eq!(Config::parse("colour = 1"), Err(format!("unknown setting 'colour', expected {}", KEYS)));
+        assert!(Config::parse("quiet = true\nverbose = 2").is_err());
+        assert!(Config::parse("quiet = true\nisa = ").unwrap_err().starts_with("line 2: "));
+    }
+}
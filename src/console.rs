@@ -0,0 +1,155 @@
+//! Where a program reads its input and writes its output.
+//!
+//! Program output (PRINT, prompts, `--call-graph` reports) and every line read in
+//! interactive mode go through a [`Console`] rather than the process's stdin and
+//! stdout, so embedders and tests can supply input and capture output, and
+//! `--log-session` can record both.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::time::Duration;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::transcript;
+
+pub struct Console<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    // A copy of the program output since `capture`
+    captured: Option<String>,
+    // Bytes of input read so far
+    read: u64,
+}
+
+impl<'a> Console<'a> {
+    pub fn new(input: &'a mut dyn BufRead, output: &'a mut dyn Write) -> Self {
+        Console { input, output, captured: None, read: 0 }
+    }
+
+    /// How many bytes of input have been read, which tells apart states of a program that reads input.
+    pub fn position(&self) -> u64 {
+        self.read
+    }
+
+    /// Keeps a copy of the program output from now on, until [`Console::take_captured`].
+    pub fn capture(&mut self) {
+        self.captured.get_or_insert_default();
+    }
+
+    /// The program output since [`Console::capture`], which stops keeping it.
+    pub fn take_captured(&mut self) -> String {
+        self.captured.take().unwrap_or_default()
+    }
+
+    /// Writes a line of program output, such as the result of PRINT.
+    pub fn print(&mut self, line: fmt::Arguments) -> Result<(), Box<Diagnostic>> {
+        transcript::output(&line.to_string());
+        if let Some(captured) = self.captured.as_mut() {
+            captured.push_str(&format!("{}\n", line));
+        }
+        writeln!(self.output, "{}", line).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write program output: {}", error))))
+    }
+
+    /// Writes program output that need not end a line, such as a character of a semihosting call.
+    pub fn write(&mut self, text: &str) -> Result<(), Box<Diagnostic>> {
+        transcript::output(text);
+        if let Some(captured) = self.captured.as_mut() {
+            captured.push_str(text);
+        }
+        write!(self.output, "{}", text).and_then(|_| self.output.flush()).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write program output: {}", error))))
+    }
+
+    /// Writes `prompt` without a line break, flushing it so it shows before input is read.
+    pub fn prompt(&mut self, prompt: &str) -> io::Result<()> {
+        write!(self.output, "{}", prompt)?;
+        self.output.flush()
+    }
+
+    /// Sounds a beep lasting `duration`: a tone of `frequency` Hz when built with
+    /// the `sound` feature, otherwise, or for a frequency of 0, the terminal bell,
+    /// waiting out the duration so that beeps one after another are heard apart.
+    pub fn beep(&mut self, frequency: u32, duration: Duration) -> Result<(), Box<Diagnostic>> {
+        if frequency > 0 && play_tone(frequency, duration) {
+            return Ok(());
+        }
+        write!(self.output, "\x07").and_then(|_| self.output.flush()).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write program output: {}", error))))?;
+        thread::sleep(duration);
+        Ok(())
+    }
+
+    /// Reads a line of input without its line ending, or `None` at the end of the input.
+    pub fn read_line(&mut self) -> Result<Option<String>, Box<Diagnostic>> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(read) => {
+                self.read += read as u64;
+                let len = line.trim_end_matches(['\r', '\n']).len();
+                line.truncate(len);
+                transcript::input(&line);
+                Ok(Some(line))
+            },
+            Err(error) => Err(Box::new(Diagnostic::error(Code::InputFailed,
+                format!("Could not read input: {}", error)))),
+        }
+    }
+
+    /// Reads a byte of input, or `None` at the end of the input.
+    pub fn read_byte(&mut self) -> Result<Option<u8>, Box<Diagnostic>> {
+        let byte = match self.input.fill_buf() {
+            Ok(buffer) => buffer.first().copied(),
+            Err(error) => return Err(Box::new(Diagnostic::error(Code::InputFailed,
+                format!("Could not read input: {}", error)))),
+        };
+        if let Some(byte) = byte {
+            self.input.consume(1);
+            self.read += 1;
+            transcript::input(&char::from(byte).to_string());
+        }
+        Ok(byte)
+    }
+}
+
+// Plays a sine tone on the default audio output, returning false if there is none
+#[cfg(feature = "sound")]
+fn play_tone(frequency: u32, duration: Duration) -> bool {
+    use rodio::{OutputStream, Sink, Source};
+    let Ok((_stream, handle)) = OutputStream::try_default() else {
+        return false;
+    };
+    let Ok(sink) = Sink::try_new(&handle) else {
+        return false;
+    };
+    sink.append(rodio::source::SineWave::new(frequency as f32).take_duration(duration).amplify(0.2));
+    sink.sleep_until_end();
+    true
+}
+
+#[cfg(not(feature = "sound"))]
+fn play_tone(_frequency: u32, _duration: Duration) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_lines() {
+        let mut input = "first\r\nsecond\n\nlast".as_bytes();
+        let mut output = Vec::new();
+        let mut console = Console::new(&mut input, &mut output);
+        let mut lines = Vec::new();
+        while let Some(line) = console.read_line().unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines, vec!["first", "second", "", "last"]);
+
+        let mut input = &[0xff, b'\n'][..];
+        let error = Console::new(&mut input, &mut output).read_line().unwrap_err();
+        assert_eq!(error.code, Code::InputFailed);
+    }
+}
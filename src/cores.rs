@@ -0,0 +1,370 @@
+//! Several cores sharing one memory, for `--cores N`.
+//!
+//! Every core has its own registers and flags and starts at the entry point
+//! with its number in [`Isa::CORE_REGISTER`](crate::isa::Isa::CORE_REGISTER).
+//! Only one core runs at a time: the caller holds the running core, the others
+//! wait here, and switching cores hands the memory, and everything that watches
+//! it, to the core that runs next. The interleaving decides which core runs
+//! each step, in turns of a few instructions or at random from a seed, so a
+//! data race shows up the same way every time the program runs.
+//!
+//! The cores that ran are recorded as a [`Schedule`], which a later run can
+//! follow instead of the interleaving to repeat one racy execution exactly.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::str::FromStr;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::interpreter::{self, Machine};
+
+/// The most cores `--cores` accepts.
+pub const MAX_CORES: usize = 16;
+
+/// How the cores take turns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interleaving {
+    /// Each core in order runs `quantum` instructions
+    RoundRobin { quantum: u64 },
+    /// Each step runs a core picked at random, the same ones for the same seed
+    Random { seed: u64 },
+}
+
+impl Default for Interleaving {
+    fn default() -> Self {
+        Interleaving::RoundRobin { quantum: 1 }
+    }
+}
+
+impl FromStr for Interleaving {
+    type Err = String;
+
+    /// Parses `round-robin`, `round-robin:N` for turns of N instructions, `random` or `random:SEED`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, value) = match s.split_once(':') {
+            Some((kind, value)) => (kind, Some(value.trim())),
+            None => (s, None),
+        };
+        let number = |default: u64| value.map_or(Ok(default), |value| value.parse::<u64>()
+            .map_err(|_| format!("invalid number '{}'", value)));
+        match kind.trim() {
+            "round-robin" => match number(1)? {
+                0 => Err("a turn must be at least 1 instruction".to_string()),
+                quantum => Ok(Interleaving::RoundRobin { quantum }),
+            },
+            "random" => Ok(Interleaving::Random { seed: number(1)? }),
+            _ => Err(format!("unknown interleaving '{}', use round-robin[:N] or random[:SEED]", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Interleaving {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Interleaving::RoundRobin { quantum } => write!(f, "round-robin:{}", quantum),
+            Interleaving::Random { seed } => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+/// Picks the core that runs each step.
+#[derive(Debug)]
+struct Scheduler {
+    interleaving: Interleaving,
+    // xorshift64, so a seed always gives the same interleaving
+    state: u64,
+    // Instructions the running core has run in its turn
+    ran: u64,
+}
+
+impl Scheduler {
+    fn new(interleaving: Interleaving) -> Self {
+        let seed = match interleaving {
+            Interleaving::Random { seed } => seed,
+            Interleaving::RoundRobin { .. } => 0,
+        };
+        // Spread the seed, which must not leave the state 0
+        Scheduler { interleaving, state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1, ran: 0 }
+    }
+
+    // The core to run next of those that have not stopped, after `running`
+    fn pick(&mut self, running: usize, pcs: &[Option<usize>]) -> Option<usize> {
+        let mut ready = (0..pcs.len()).filter(|&core| pcs[core].is_some());
+        match self.interleaving {
+            Interleaving::RoundRobin { quantum } if pcs[running].is_some() && self.ran < quantum => {
+                self.ran += 1;
+                Some(running)
+            },
+            Interleaving::RoundRobin { .. } => {
+                self.ran = 1;
+                let first = ready.next()?;
+                // In order, the lowest coming after the highest
+                Some(if first > running { first } else { ready.find(|&core| core > running).unwrap_or(first) })
+            },
+            Interleaving::Random { .. } => {
+                let count = ready.clone().count() as u64;
+                if count == 0 {
+                    return None;
+                }
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+                ready.nth((self.state % count) as usize)
+            },
+        }
+    }
+}
+
+/// The order the cores ran in: which core ran each turn and for how many
+/// instructions. It is written with `--record-schedule` as a line per turn,
+/// `<core> <instructions>`, and followed with `--replay-schedule`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub turns: Vec<(usize, u64)>,
+}
+
+impl Schedule {
+    /// Parses a schedule, where `//` starts a comment as in a program.
+    pub fn parse(file: &str, text: &str) -> Result<Self, Vec<Diagnostic>> {
+        let errors = RefCell::new(Vec::new());
+        let mut turns = Vec::new();
+        for (index, source) in text.lines().enumerate() {
+            let turn = interpreter::strip_comment(source);
+            if turn.is_empty() {
+                continue;
+            }
+            let report = Reporter::new(&errors, file, index + 1, source);
+            let numbers: Vec<Option<u64>> = turn.split_whitespace().map(|word| word.parse().ok()).collect();
+            match numbers[..] {
+                [Some(core), Some(steps)] if (core as usize) < MAX_CORES && steps > 0 => turns.push((core as usize, steps)),
+                _ => report.error(turn, Code::InvalidSchedule,
+                    format!("Invalid turn '{}', expected a core below {} and how many instructions it runs, at least 1", turn, MAX_CORES)),
+            }
+        }
+        let errors = errors.into_inner();
+        if errors.is_empty() { Ok(Schedule { turns }) } else { Err(errors) }
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Box<Diagnostic>> {
+        fs::write(path, self.to_string()).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write the schedule to {}: {}", path, error))))
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "// core, instructions")?;
+        for (core, steps) in &self.turns {
+            writeln!(f, "{} {}", core, steps)?;
+        }
+        Ok(())
+    }
+}
+
+/// The cores waiting for their turn, and where each one is.
+pub struct Cores<C> {
+    // Every core by number; the slot of the running core holds a spare
+    parked: Vec<C>,
+    // The instruction each core runs next, or None once it has stopped
+    pcs: Vec<Option<usize>>,
+    running: usize,
+    scheduler: Scheduler,
+    // The turns still to follow from --replay-schedule, before the scheduler picks
+    replay: VecDeque<(usize, u64)>,
+    ran: Schedule,
+    steps: u64,
+}
+
+impl<C: AsMut<Machine>> Cores<C> {
+    /// Core 0 is the one the caller holds and `others` are cores 1 and up, all
+    /// starting at `entry`. `spare` fills the slot of whichever core is running.
+    pub fn new(spare: C, others: Vec<C>, entry: usize, interleaving: Interleaving) -> Self {
+        let mut parked = vec![spare];
+        parked.extend(others);
+        Cores {
+            pcs: vec![Some(entry); parked.len()],
+            parked,
+            running: 0,
+            scheduler: Scheduler::new(interleaving),
+            replay: VecDeque::new(),
+            ran: Schedule::default(),
+            steps: 0,
+        }
+    }
+
+    /// Runs the cores in the turns of `schedule`, then as the interleaving picks.
+    pub fn replay(&mut self, schedule: &Schedule) {
+        self.replay = schedule.turns.iter().copied().collect();
+    }
+
+    /// How many cores there are.
+    pub fn count(&self) -> usize {
+        self.pcs.len()
+    }
+
+    /// The number of the running core.
+    pub fn running(&self) -> usize {
+        self.running
+    }
+
+    /// The next instruction of the running core, before it has run any.
+    pub fn pc(&self) -> Option<usize> {
+        self.pcs[self.running]
+    }
+
+    /// The turns the cores have run so far.
+    pub fn schedule(&self) -> &Schedule {
+        &self.ran
+    }
+
+    /// Records the next instruction of the running core, `None` once it has
+    /// stopped, and switches `cpu` to the core that runs next. Returns that
+    /// core's next instruction, or `None` once every core has stopped. Fails
+    /// if the schedule being replayed runs a core that has stopped.
+    pub fn next(&mut self, cpu: &mut C, pc: Option<usize>) -> Result<Option<usize>, Box<Diagnostic>> {
+        self.pcs[self.running] = pc;
+        if self.pcs.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        let core = match self.replay.front_mut() {
+            Some((core, steps)) => {
+                let core = *core;
+                *steps -= 1;
+                if *steps == 0 {
+                    self.replay.pop_front();
+                }
+                if self.pcs.get(core).is_none_or(Option::is_none) {
+                    let state = if core < self.count() { "it has stopped" } else { "there is no such core" };
+                    return Err(Box::new(Diagnostic::error(Code::InvalidSchedule,
+                        format!("The schedule runs core {} after {} instructions, but {}", core, self.steps, state))
+                        .with_note("a schedule replays the run of the same program, with the same --cores and input")));
+                }
+                core
+            },
+            None => self.scheduler.pick(self.running, &self.pcs).expect("a core has not stopped"),
+        };
+        match self.ran.turns.last_mut() {
+            Some((last, steps)) if *last == core => *steps += 1,
+            _ => self.ran.turns.push((core, 1)),
+        }
+        self.steps += 1;
+        self.switch(cpu, core);
+        Ok(self.pcs[core])
+    }
+
+    /// Switches `cpu` to `core`, which takes over the memory.
+    pub fn switch(&mut self, cpu: &mut C, core: usize) {
+        if core == self.running {
+            return;
+        }
+        mem::swap(cpu, &mut self.parked[self.running]);
+        mem::swap(cpu, &mut self.parked[core]);
+        hand_over(self.parked[self.running].as_mut(), cpu.as_mut());
+        self.running = core;
+    }
+}
+
+// Moves what the cores share from the core that ran to the one that runs next
+fn hand_over(from: &mut Machine, to: &mut Machine) {
+    mem::swap(&mut from.memory, &mut to.memory);
+    mem::swap(&mut from.accesses, &mut to.accesses);
+    mem::swap(&mut from.cache, &mut to.cache);
+    mem::swap(&mut from.trace, &mut to.trace);
+    mem::swap(&mut from.timeline, &mut to.timeline);
+    mem::swap(&mut from.taint, &mut to.taint);
+    mem::swap(&mut from.undefined, &mut to.undefined);
+    mem::swap(&mut from.warnings, &mut to.warnings);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, Machine, RunOptions};
+    use super::*;
+
+    // Each core adds 1 to the counter, at address 0, 50 times
+    const COUNTER: &str = "counter: #0\nmain: MOV r1, #50\n\
+        loop: LDR r2, counter\nADD r2, r2, #1\nSTR r2, counter\nSUBS r1, r1, #1\nBNE loop\nEXIT";
+
+    fn run_with(program: &str, options: &RunOptions) -> Result<Machine, Vec<Diagnostic>> {
+        let sources = [("main.s".to_string(), program.to_string())];
+        run_sources(&Arm, &sources, options, &mut Console::new(&mut io::empty(), &mut io::sink()))
+    }
+
+    fn run(program: &str, cores: usize, interleaving: &str) -> Result<Machine, Vec<Diagnostic>> {
+        run_with(program, &RunOptions { cores, interleaving: interleaving.parse().unwrap(), ..RunOptions::default() })
+    }
+
+    // The counter two cores leave
+    fn counter(program: &str, interleaving: &str) -> i32 {
+        run(program, 2, interleaving).unwrap().memory[0]
+    }
+
+    // COUNTER with an update that LDREX and STREX retry when another core stored in between
+    fn atomic() -> String {
+        COUNTER.replace("loop: LDR r2, counter\nADD r2, r2, #1\nSTR r2, counter",
+            "loop: MOV r3, #0\nretry: LDREX r2, [r3]\nADD r2, r2, #1\nSTREX r4, r2, [r3]\nCMP r4, #0\nBNE retry")
+    }
+
+    #[test]
+    fn test_round_robin() {
+        // Whole loops in one turn never overlap, so no update is lost
+        assert_eq!(counter(COUNTER, "round-robin:1000"), 100);
+        // Alternating single instructions lose every other update
+        assert_eq!(counter(COUNTER, "round-robin"), 50);
+    }
+
+    #[test]
+    fn test_random_interleaving_repeats_for_a_seed() {
+        let random = counter(COUNTER, "random:7");
+        assert!((50..100).contains(&random), "{}", random);
+        assert_eq!(counter(COUNTER, "random:7"), random);
+    }
+
+    #[test]
+    fn test_exclusive_updates() {
+        assert_eq!(counter(&atomic(), "round-robin"), 100);
+        assert_eq!(counter(&atomic(), "random:7"), 100);
+    }
+
+    #[test]
+    fn test_errors_name_the_core() {
+        // Each core finds its number in r0
+        let errors = run("CMP r0, #1\nBNE done\nLDR r1, [r0, #5000]\ndone: EXIT", 3, "round-robin").err().unwrap();
+        assert_eq!(errors[0].message, "Memory access out of bounds: address 5001 >= MEMORY_SIZE 1024");
+        assert_eq!(errors[0].notes, ["this ran on core 1"]);
+    }
+
+    #[test]
+    fn test_recorded_schedule_repeats_the_race() {
+        let path = std::env::temp_dir().join(format!("asm-schedule-{}.txt", std::process::id()));
+        let record = RunOptions { cores: 2, interleaving: "random:7".parse().unwrap(), record_schedule: Some(path.display().to_string()), ..RunOptions::default() };
+        run_with(COUNTER, &record).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let schedule = Schedule::parse("schedule.txt", &text).unwrap();
+        assert_eq!(schedule.turns.iter().map(|&(_, steps)| steps).sum::<u64>(), 2 * 252);
+        // Whatever the interleaving
+        let replay = RunOptions { cores: 2, replay_schedule: Some(schedule), ..RunOptions::default() };
+        assert_eq!(run_with(COUNTER, &replay).unwrap().memory[0], counter(COUNTER, "random:7"));
+    }
+
+    #[test]
+    fn test_schedule_for_a_stopped_core() {
+        // Core 1 has long stopped when the schedule wants it again
+        let replay = RunOptions { cores: 2, replay_schedule: Some(Schedule { turns: vec![(1, 300)] }), ..RunOptions::default() };
+        let errors = run_with(COUNTER, &replay).err().unwrap();
+        assert_eq!(errors[0].message, "The schedule runs core 1 after 252 instructions, but it has stopped");
+    }
+
+    #[test]
+    fn test_invalid_schedules_and_interleavings() {
+        assert!(Schedule::parse("schedule.txt", "0 5\n1 0").is_err());
+        assert_eq!("random:3".parse(), Ok(Interleaving::Random { seed: 3 }));
+        assert!("round-robin:0".parse::<Interleaving>().is_err());
+        assert!("fair".parse::<Interleaving>().is_err());
+    }
+}
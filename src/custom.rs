@@ -0,0 +1,459 @@
+//! Instruction sets defined in a TOML file, for `--isa-file`, so an instructor can
+//! invent a simplified machine without writing Rust:
+//!
+//! ```toml
+//! # A toy machine with a decrement-and-branch loop
+//! [[instruction]]
+//! mnemonic = "set"
+//! operands = ["rd: reg", "n: imm"]
+//! semantics = "rd = n"
+//!
+//! [[instruction]]
+//! mnemonic = "djnz"
+//! operands = ["rd: reg", "target: label"]
+//! semantics = "rd = rd - 1; Z = rd == 0; if !Z then jump target"
+//! ```
+//!
+//! A defined machine has eight registers `r0`-`r7` and the flags `Z` and `N`, with
+//! the memory and labels of the other modes. Operands are a register (`reg`), an
+//! immediate with or without `#` (`imm`) or a label (`label`), which stands for its
+//! instruction or, for a data label, its address. The semantics are statements
+//! separated by `;` that run in order:
+//!
+//! - `target = expression` sets a register, a register operand, a flag or a memory word (`[address]`)
+//! - `jump expression` continues at that instruction, usually a label operand
+//! - `if condition then statement` runs the statement when the condition is not 0
+//! - `print expression` prints the value on a line of its own
+//! - `halt` ends the program
+//!
+//! Expressions are those of the debugger (see [`Expression`]) over the operands,
+//! registers, flags, `pc` (the index of the instruction) and memory words.
+
+use std::fs;
+use std::path::Path;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::expression::{Context, Expression};
+use crate::instructions::InstructionInfo;
+use crate::interpreter::{parse_immediate, Machine};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+
+const REGISTER_NAMES: [&str; 8] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+const FLAG_NAMES: [&str; 2] = ["Z", "N"];
+
+/// What an operand of a defined instruction is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Register,
+    Immediate,
+    Label,
+}
+
+/// What an assignment sets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Register(usize),
+    // The register an operand names
+    Operand(usize),
+    Flag(usize),
+    Word(Expression),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Statement {
+    Assign(Target, Expression),
+    Jump(Expression),
+    If(Expression, Box<Statement>),
+    Print(Expression),
+    Halt,
+}
+
+impl Statement {
+    fn jumps(&self) -> bool {
+        match self {
+            Statement::Jump(_) => true,
+            Statement::If(_, statement) => statement.jumps(),
+            _ => false,
+        }
+    }
+}
+
+/// An instruction of the file: its operands, by name, and what it does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Definition {
+    mnemonic: String,
+    operands: Vec<(String, Kind)>,
+    semantics: Vec<Statement>,
+    // e.g. "djnz rd, target"
+    syntax: String,
+}
+
+/// A decoded instruction: the definition it follows and the value of each operand,
+/// which is the register index for a register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    definition: usize,
+    operands: Vec<i64>,
+}
+
+/// The registers and flags of a defined machine, with its memory and labels.
+pub struct Cpu {
+    pub registers: [i32; REGISTER_NAMES.len()],
+    pub flags: [bool; FLAG_NAMES.len()],
+    pub machine: Machine,
+}
+
+impl AsRef<Machine> for Cpu {
+    fn as_ref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl AsMut<Machine> for Cpu {
+    fn as_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+/// A machine defined in a TOML file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Custom {
+    definitions: Vec<Definition>,
+}
+
+impl Custom {
+    /// Reads the definition of a machine from the file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Box<Diagnostic>> {
+        let text = fs::read_to_string(path).map_err(|error| Box::new(Diagnostic::error(Code::InputFailed,
+            format!("Cannot read {}: {}", path.display(), error))))?;
+        Custom::parse(&text).map_err(|message| Box::new(Diagnostic::error(Code::InvalidIsaFile,
+            format!("Invalid ISA file {}: {}", path.display(), message))))
+    }
+
+    /// Parses the TOML `text` of a definition, describing the first problem on failure.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table: toml::Table = text.parse().map_err(|error: toml::de::Error| match error.span() {
+            Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, error.message()),
+            None => error.message().to_string(),
+        })?;
+        let mut definitions = Vec::new();
+        for (key, value) in &table {
+            match key.as_str() {
+                "instruction" => {
+                    let instructions = value.as_array().ok_or("'instruction' must be a list of tables, written [[instruction]]")?;
+                    for instruction in instructions {
+                        let instruction = instruction.as_table().ok_or("'instruction' must be a list of tables, written [[instruction]]")?;
+                        let definition = definition(instruction)?;
+                        if definitions.iter().any(|defined: &Definition| defined.mnemonic == definition.mnemonic) {
+                            return Err(format!("{} is defined twice", definition.mnemonic));
+                        }
+                        definitions.push(definition);
+                    }
+                },
+                _ => return Err(format!("unknown setting '{}', expected [[instruction]] tables", key)),
+            }
+        }
+        if definitions.is_empty() {
+            return Err("no instructions are defined; add one with [[instruction]]".to_string());
+        }
+        Ok(Custom { definitions })
+    }
+
+    fn mnemonics(&self) -> String {
+        self.definitions.iter().map(|definition| definition.mnemonic.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+// Parses one [[instruction]] table
+fn definition(table: &toml::Table) -> Result<Definition, String> {
+    if let Some(key) = table.keys().find(|key| !matches!(key.as_str(), "mnemonic" | "operands" | "semantics")) {
+        return Err(format!("unknown instruction setting '{}', expected mnemonic, operands and semantics", key));
+    }
+    let mnemonic = table.get("mnemonic").and_then(|value| value.as_str()).ok_or("each instruction needs a 'mnemonic' string")?;
+    if mnemonic.is_empty() || !mnemonic.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+        return Err(format!("invalid mnemonic '{}': use letters, digits, '.' and '_'", mnemonic));
+    }
+    let mnemonic = mnemonic.to_lowercase();
+    let in_mnemonic = |message: String| format!("{}: {}", mnemonic, message);
+    let mut operands: Vec<(String, Kind)> = Vec::new();
+    for operand in table.get("operands").map_or(Ok(&Vec::new()), |value| value.as_array().ok_or("'operands' must be a list of strings"))? {
+        let operand = operand.as_str().ok_or_else(|| in_mnemonic("'operands' must be a list of strings".to_string()))?;
+        let Some((name, kind)) = operand.split_once(':').map(|(name, kind)| (name.trim(), kind.trim())) else {
+            return Err(in_mnemonic(format!("expected 'name: kind' in operand '{}'", operand)));
+        };
+        let kind = match kind {
+            "reg" => Kind::Register,
+            "imm" => Kind::Immediate,
+            "label" => Kind::Label,
+            _ => return Err(in_mnemonic(format!("unknown kind '{}' of operand {}, expected reg, imm or label", kind, name))),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || reserved(name) {
+            return Err(in_mnemonic(format!("invalid operand name '{}': use letters, digits and '_', and not a register, flag or pc", name)));
+        }
+        if operands.iter().any(|(defined, _)| defined == name) {
+            return Err(in_mnemonic(format!("operand {} is named twice", name)));
+        }
+        operands.push((name.to_string(), kind));
+    }
+    let semantics = table.get("semantics").and_then(|value| value.as_str()).ok_or_else(|| in_mnemonic("needs a 'semantics' string".to_string()))?;
+    let semantics = semantics.split([';', '\n']).map(str::trim).filter(|text| !text.is_empty())
+        .map(|text| statement(text, &operands).map_err(in_mnemonic))
+        .collect::<Result<Vec<_>, _>>()?;
+    let names: Vec<&str> = operands.iter().map(|(name, _)| name.as_str()).collect();
+    let syntax = format!("{} {}", mnemonic, names.join(", ")).trim_end().to_string();
+    Ok(Definition { mnemonic, operands, semantics, syntax })
+}
+
+// Whether `name` is a register, a flag or pc, which operands cannot be called
+fn reserved(name: &str) -> bool {
+    REGISTER_NAMES.contains(&name) || FLAG_NAMES.contains(&name) || name == "pc"
+}
+
+// Parses one statement of the semantics
+fn statement(text: &str, operands: &[(String, Kind)]) -> Result<Statement, String> {
+    let expression = |part: &str| {
+        let expression = Expression::parse(part)?;
+        match expression.names().into_iter().find(|name| !reserved(name) && !operands.iter().any(|(operand, _)| operand == name)) {
+            Some(name) => Err(format!("unknown name '{}' in '{}'; names are the operands, r0-r7, Z, N and pc", name, text)),
+            None => Ok(expression),
+        }
+    };
+    if text == "halt" {
+        return Ok(Statement::Halt);
+    }
+    if let Some(rest) = text.strip_prefix("if ") {
+        let (condition, then) = rest.split_once(" then ").ok_or_else(|| format!("expected 'if condition then statement' in '{}'", text))?;
+        return Ok(Statement::If(expression(condition)?, Box::new(statement(then.trim(), operands)?)));
+    }
+    if let Some(target) = text.strip_prefix("jump ") {
+        return Ok(Statement::Jump(expression(target)?));
+    }
+    if let Some(value) = text.strip_prefix("print ") {
+        return Ok(Statement::Print(expression(value)?));
+    }
+    // The first '=' that is not part of ==, !=, <= or >=
+    let bytes = text.as_bytes();
+    let assignment = (0..bytes.len()).find(|&i| bytes[i] == b'='
+        && bytes.get(i + 1) != Some(&b'=')
+        && (i == 0 || !b"=!<>".contains(&bytes[i - 1])));
+    let Some(equals) = assignment else {
+        return Err(format!("expected 'target = expression', jump, if, print or halt in '{}'", text));
+    };
+    let (target, value) = (text[..equals].trim(), expression(&text[equals + 1..])?);
+    let target = if let Some(address) = target.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        Target::Word(expression(address)?)
+    } else if let Some(index) = REGISTER_NAMES.iter().position(|&name| name == target) {
+        Target::Register(index)
+    } else if let Some(index) = FLAG_NAMES.iter().position(|&name| name == target) {
+        Target::Flag(index)
+    } else {
+        match operands.iter().position(|(name, _)| name == target) {
+            Some(index) if operands[index].1 == Kind::Register => Target::Operand(index),
+            _ => return Err(format!("cannot assign to '{}'; assign to a register, a reg operand, Z, N or [address]", target)),
+        }
+    };
+    Ok(Statement::Assign(target, value))
+}
+
+impl Isa for Custom {
+    type Instruction = Instruction;
+    type Cpu = Cpu;
+
+    const NAME: &'static str = "custom";
+    const DESCRIPTION: &'static str = "A machine defined in the TOML file given with --isa-file";
+    const INSTRUCTIONS: &'static [InstructionInfo] = &[];
+    const REGISTERS: &'static RegisterFile = &RegisterFile { names: &REGISTER_NAMES, bits: 32 };
+    const CORE_REGISTER: usize = 0;
+
+    fn new_cpu(&self) -> Cpu {
+        Cpu { registers: [0; REGISTER_NAMES.len()], flags: [false; FLAG_NAMES.len()], machine: Machine::new() }
+    }
+
+    fn read_register(&self, cpu: &Cpu, index: usize) -> i64 {
+        cpu.registers[index] as i64
+    }
+
+    fn flags(&self, cpu: &Cpu) -> Vec<(&'static str, bool)> {
+        FLAG_NAMES.iter().copied().zip(cpu.flags).collect()
+    }
+
+    fn seed_registers(&self, cpu: &mut Cpu, registers: &[(usize, i32)]) {
+        for &(reg, value) in registers.iter().filter(|&&(reg, _)| reg < REGISTER_NAMES.len()) {
+            cpu.registers[reg] = value;
+        }
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        let Some(index) = self.definitions.iter().position(|definition| definition.mnemonic.eq_ignore_ascii_case(tokens.mnemonic)) else {
+            report.report(tokens.mnemonic, Diagnostic::error(Code::UnknownInstruction, format!("Unknown instruction: {}", tokens.mnemonic))
+                .with_note(format!("the instructions of this machine are {}", self.mnemonics())));
+            return None;
+        };
+        let definition = &self.definitions[index];
+        let ops = tokens.expect(definition.operands.len(), Some(&definition.syntax), report)?;
+        let mut operands = Vec::new();
+        for (&operand, (name, kind)) in ops.iter().zip(&definition.operands) {
+            let value = match kind {
+                Kind::Register => REGISTER_NAMES.iter().position(|register| register.eq_ignore_ascii_case(operand)).map(|index| index as i64),
+                Kind::Immediate => parse_immediate(operand.strip_prefix('#').unwrap_or(operand)).map(i64::from),
+                Kind::Label => labels.code.get(operand).or_else(|| labels.addresses.get(operand)).map(|&index| index as i64),
+            };
+            let Some(value) = value else {
+                let (code, message) = match kind {
+                    Kind::Register => (Code::InvalidRegister, format!("Invalid register for {}: {}", name, operand)),
+                    Kind::Immediate => (Code::InvalidOperand, format!("Invalid immediate for {}: {}", name, operand)),
+                    Kind::Label => (Code::UndefinedLabel, format!("Undefined label: {}", operand)),
+                };
+                report.report(operand, Diagnostic::error(code, message).with_note(format!("usage: {}", definition.syntax)));
+                return None;
+            };
+            operands.push(value);
+        }
+        Some(Instruction { definition: index, operands })
+    }
+
+    fn execute(&self, cpu: &mut Cpu, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        let definition = &self.definitions[instruction.definition];
+        for statement in &definition.semantics {
+            if let Some(flow) = run(cpu, definition, statement, &instruction.operands, pc, console)? {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        self.definitions[instruction.definition].semantics.iter().any(Statement::jumps)
+    }
+}
+
+// The names an expression of an instruction's semantics can use
+struct Scope<'a> {
+    cpu: &'a Cpu,
+    definition: &'a Definition,
+    operands: &'a [i64],
+    pc: usize,
+}
+
+impl Context for Scope<'_> {
+    fn value(&self, name: &str) -> Option<i64> {
+        if let Some(index) = self.definition.operands.iter().position(|(operand, _)| operand == name) {
+            let value = self.operands[index];
+            return Some(match self.definition.operands[index].1 {
+                Kind::Register => self.cpu.registers[value as usize] as i64,
+                Kind::Immediate | Kind::Label => value,
+            });
+        }
+        if let Some(index) = REGISTER_NAMES.iter().position(|&register| register == name) {
+            return Some(self.cpu.registers[index] as i64);
+        }
+        if let Some(index) = FLAG_NAMES.iter().position(|&flag| flag == name) {
+            return Some(self.cpu.flags[index] as i64);
+        }
+        (name == "pc").then_some(self.pc as i64)
+    }
+
+    fn word(&self, address: i64) -> Option<i64> {
+        usize::try_from(address).ok().and_then(|address| self.cpu.machine.memory.get(address)).map(|&word| word as i64)
+    }
+}
+
+// Runs a statement, returning where the program goes if it jumps or halts
+fn run(cpu: &mut Cpu, definition: &Definition, statement: &Statement, operands: &[i64], pc: usize, console: &mut Console) -> Result<Option<Flow>, Box<Diagnostic>> {
+    let failed = |message: String| Box::new(Diagnostic::error(Code::InvalidOperand, format!("{} failed: {}", definition.mnemonic.to_uppercase(), message)));
+    let evaluate = |cpu: &Cpu, expression: &Expression| expression.evaluate(&Scope { cpu, definition, operands, pc }).map_err(failed);
+    match statement {
+        Statement::Assign(target, value) => {
+            let value = evaluate(cpu, value)?;
+            match target {
+                Target::Register(index) => cpu.registers[*index] = value as i32,
+                Target::Operand(index) => cpu.registers[operands[*index] as usize] = value as i32,
+                Target::Flag(index) => cpu.flags[*index] = value != 0,
+                Target::Word(address) => {
+                    let address = evaluate(cpu, address)?;
+                    let size = cpu.machine.memory.len();
+                    let Some(address) = usize::try_from(address).ok().filter(|&address| address < size) else {
+                        return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                            format!("Memory access out of bounds: address {} (MEMORY_SIZE {})", address, size))));
+                    };
+                    cpu.machine.store(address, value as i32)?;
+                },
+            }
+        },
+        Statement::Jump(target) => {
+            let target = evaluate(cpu, target)?;
+            let target = usize::try_from(target).map_err(|_| failed(format!("cannot jump to {}", target)))?;
+            return Ok(Some(Flow::Jump(target)));
+        },
+        Statement::If(condition, then) => if evaluate(cpu, condition)? != 0 {
+            return run(cpu, definition, then, operands, pc, console);
+        },
+        Statement::Print(value) => {
+            let value = evaluate(cpu, value)?;
+            console.print(format_args!("{}", value))?;
+        },
+        Statement::Halt => return Ok(Some(Flow::Exit)),
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{check_sources, run_sources, RunOptions};
+
+    const MACHINE: &str = r#"
+        [[instruction]]
+        mnemonic = "set"
+        operands = ["rd: reg", "n: imm"]
+        semantics = "rd = n"
+
+        [[instruction]]
+        mnemonic = "add"
+        operands = ["rd: reg", "rs: reg"]
+        semantics = "rd = rd + rs"
+
+        [[instruction]]
+        mnemonic = "djnz"
+        operands = ["rd: reg", "target: label"]
+        semantics = """
+            rd = rd - 1
+            Z = rd == 0
+            if !Z then jump target
+        """
+
+        [[instruction]]
+        mnemonic = "out"
+        operands = ["rs: reg"]
+        semantics = "print rs; [100] = rs"
+    "#;
+
+    #[test]
+    fn test_run_on_defined_machine() {
+        let custom = Custom::parse(MACHINE).unwrap();
+        let mut out = Vec::new();
+        // Sums 5 + 4 + 3 + 2 + 1
+        let program = "set r1, #5\nset r0, 0\nloop: add r0, r1\ndjnz r1, loop\nout r0";
+        let sources = [("main.s".to_string(), program.to_string())];
+        let cpu = run_sources(&custom, &sources, &RunOptions::default(), &mut Console::new(&mut std::io::empty(), &mut out)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "15\n");
+        assert_eq!((cpu.registers[0], cpu.registers[1], cpu.flags[0]), (15, 0, true));
+        assert_eq!(cpu.machine.memory[100], 15);
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let custom = Custom::parse(MACHINE).unwrap();
+        let errors = check_sources(&custom, &[("main.s".to_string(), "set r9, 1\nmul r0, r1".to_string())]);
+        assert_eq!(errors.iter().map(|error| error.code).collect::<Vec<_>>(), [Code::InvalidRegister, Code::UnknownInstruction]);
+        assert_eq!(errors[1].notes, ["the instructions of this machine are set, add, djnz, out"]);
+    }
+
+    #[test]
+    fn test_invalid_semantics() {
+        let invalid = |semantics: &str| Custom::parse(&format!("[[instruction]]\nmnemonic = \"x\"\noperands = [\"n: imm\"]\nsemantics = \"{}\"", semantics)).unwrap_err();
+        assert_eq!(invalid("r0 = m"), "x: unknown name 'm' in 'r0 = m'; names are the operands, r0-r7, Z, N and pc");
+        assert_eq!(invalid("n = 1"), "x: cannot assign to 'n'; assign to a register, a reg operand, Z, N or [address]");
+        assert_eq!(invalid("r0 == 1"), "x: expected 'target = expression', jump, if, print or halt in 'r0 == 1'");
+    }
+}
@@ -0,0 +1,303 @@
+//! Debugger commands read from a file with `--debug-script`.
+//!
+//! Commands before the first `run`, `continue` or `step` run before the program
+//! starts. Each time the program pauses, at a breakpoint or after `step`, the
+//! following commands run until one resumes it. Whatever is left once the program
+//! ends runs against its final state, where resuming does nothing:
+//!
+//! ```text
+//! break loop if r2 > 100    // the --break syntax
+//! run
+//! regs
+//! recent 10                 // the last 10 instructions and the registers they wrote
+//! print r2 * 4
+//! encode ADD r1, r2, #4     // the machine code, as ENCODE shows it
+//! step 3
+//! assert r0 == 12           // stops with an error if the expression is 0
+//! snapshot before           // keeps the registers, flags and memory as "before"
+//! continue
+//! diff before               // what changed since, as `asm diff` shows it
+//! print [result]
+//! ```
+//!
+//! `quit` ends the program where it is paused. Lines are commented with `//`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::breakpoints::{Breakpoint, Breakpoints};
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::expression::Expression;
+use crate::history::RECENT_DEPTH;
+use crate::interpreter::{self, CpuContext, FinalState, Program};
+use crate::isa::{Isa, Labels};
+use crate::snapshot::{self, Snapshot};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Break(Breakpoint),
+    Continue,
+    Step(u64),
+    Regs,
+    Recent(usize),
+    Print(Expression),
+    Assert(Expression),
+    Encode,
+    Snapshot(String),
+    // A snapshot and the one to compare it with, or None for the current state
+    Diff(String, Option<String>),
+    Quit,
+}
+
+const COMMANDS: &str = "break, run, continue, step, regs, recent, print, assert, encode, snapshot, diff and quit";
+
+// A command with the script line it came from
+#[derive(Clone, Debug)]
+struct Line {
+    command: Command,
+    line_no: usize,
+    source: String,
+    // Byte range of the command's argument in `source`, for diagnostics
+    start: usize,
+    end: usize,
+}
+
+/// A parsed debugger script.
+#[derive(Clone, Debug, Default)]
+pub struct DebugScript {
+    file: String,
+    lines: Vec<Line>,
+}
+
+impl DebugScript {
+    /// Parses the script `text` read from `file`, reporting every invalid command.
+    pub fn parse(file: &str, text: &str) -> Result<Self, Vec<Diagnostic>> {
+        let errors = RefCell::new(Vec::new());
+        let mut lines = Vec::new();
+        let mut snapshots = BTreeSet::new();
+        for (index, source) in text.lines().enumerate() {
+            let command_text = interpreter::strip_comment(source);
+            if command_text.is_empty() {
+                continue;
+            }
+            let report = Reporter::new(&errors, file, index + 1, source);
+            // Slices of the line, so diagnostics can point at them
+            let name_len = command_text.find(char::is_whitespace).unwrap_or(command_text.len());
+            let (name, argument) = (&command_text[..name_len], command_text[name_len..].trim());
+            if let Some(command) = parse_command(name, argument, &report) {
+                match &command {
+                    Command::Snapshot(taken) => {
+                        snapshots.insert(taken.clone());
+                    },
+                    // Commands run in order, so a snapshot must be taken on an earlier line
+                    Command::Diff(before, after) => for name in std::iter::once(before).chain(after).filter(|name| !snapshots.contains(*name)) {
+                        report.report(name, Diagnostic::error(Code::InvalidDebugCommand, format!("No snapshot called {}", name))
+                            .with_note(format!("take one on an earlier line with snapshot {}", name)));
+                    },
+                    _ => {},
+                }
+                let start = argument.as_ptr() as usize - source.as_ptr() as usize;
+                lines.push(Line { command, line_no: index + 1, source: source.to_string(), start, end: start + argument.len() });
+            }
+        }
+        let errors = errors.into_inner();
+        if errors.is_empty() { Ok(DebugScript { file: file.to_string(), lines }) } else { Err(errors) }
+    }
+}
+
+fn parse_command(name: &str, argument: &str, report: &Reporter) -> Option<Command> {
+    let invalid = |message: String| {
+        report.error(if argument.is_empty() { name } else { argument }, Code::InvalidDebugCommand, message);
+        None
+    };
+    let command = match name.to_lowercase().as_str() {
+        "break" => match argument.parse() {
+            Ok(breakpoint) => Command::Break(breakpoint),
+            Err(error) => return invalid(format!("Invalid breakpoint: {}", error)),
+        },
+        "print" | "assert" => match Expression::parse(argument) {
+            Ok(expression) if name.eq_ignore_ascii_case("print") => Command::Print(expression),
+            Ok(expression) => Command::Assert(expression),
+            Err(error) => return invalid(format!("Invalid expression: {}", error)),
+        },
+        "step" if argument.is_empty() => Command::Step(1),
+        "step" => match argument.parse() {
+            Ok(count) if count > 0 => Command::Step(count),
+            _ => return invalid(format!("Invalid step count '{}', expected a number from 1", argument)),
+        },
+        "recent" if argument.is_empty() => Command::Recent(RECENT_DEPTH),
+        "recent" => match argument.parse() {
+            Ok(count) if count > 0 => Command::Recent(count),
+            _ => return invalid(format!("Invalid instruction count '{}', expected a number from 1", argument)),
+        },
+        // Decoded when it runs, with the labels of the loaded program
+        "encode" if argument.is_empty() => return invalid("encode needs an instruction".to_string()),
+        "encode" => Command::Encode,
+        "snapshot" => match argument.split_whitespace().collect::<Vec<_>>()[..] {
+            [name] => Command::Snapshot(name.to_string()),
+            _ => return invalid("snapshot needs a name, e.g. snapshot before".to_string()),
+        },
+        "diff" => match argument.split_whitespace().collect::<Vec<_>>()[..] {
+            [before] => Command::Diff(before.to_string(), None),
+            [before, after] => Command::Diff(before.to_string(), Some(after.to_string())),
+            _ => return invalid("diff needs one snapshot to compare with the current state, or two to compare, e.g. diff before after".to_string()),
+        },
+        "run" | "continue" | "regs" | "quit" if !argument.is_empty() => {
+            return invalid(format!("{} takes no argument", name.to_lowercase()));
+        },
+        "run" | "continue" => Command::Continue,
+        "regs" => Command::Regs,
+        "quit" => Command::Quit,
+        _ => {
+            report.report(name, Diagnostic::error(Code::InvalidDebugCommand, format!("Unknown debugger command: {}", name))
+                .with_note(format!("the commands are {}", COMMANDS)));
+            return None;
+        },
+    };
+    Some(command)
+}
+
+/// Whether the program goes on after a pause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resume {
+    Continue,
+    Quit,
+}
+
+/// A script being run alongside a program.
+pub(crate) struct Session<'a> {
+    script: &'a DebugScript,
+    next: usize,
+    // Instructions left to run before pausing after `step`
+    steps_left: Option<u64>,
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(script: &'a DebugScript) -> Self {
+        Session { script, next: 0, steps_left: None, snapshots: BTreeMap::new() }
+    }
+
+    /// Pauses before the next instruction, as after a BKPT.
+    pub(crate) fn pause_next(&mut self) {
+        self.steps_left = Some(0);
+    }
+
+    /// Counts an instruction that has run.
+    pub(crate) fn ran(&mut self) {
+        if let Some(left) = self.steps_left.as_mut() {
+            *left = left.saturating_sub(1);
+        }
+    }
+
+    /// Whether a `step` ends before the next instruction.
+    pub(crate) fn step_done(&mut self) -> bool {
+        let done = self.steps_left == Some(0);
+        if done {
+            self.steps_left = None;
+        }
+        done
+    }
+
+    /// Runs commands until one resumes the program, or to the end of the script.
+    /// Once `ended`, resuming does nothing and every remaining command runs.
+    pub(crate) fn pause<I: Isa>(&mut self, isa: &I, cpu: &I::Cpu, ended: bool, breakpoints: &mut Breakpoints<'a>, program: &Program<I::Instruction>, console: &mut Console) -> Result<Resume, Vec<Diagnostic>> {
+        let script = self.script;
+        while let Some(line) = script.lines.get(self.next) {
+            self.next += 1;
+            let errors = RefCell::new(Vec::new());
+            let report = Reporter::new(&errors, &script.file, line.line_no, &line.source);
+            let argument = &line.source[line.start..line.end];
+            let context = CpuContext { isa, cpu };
+            let evaluate = |expression: &Expression| expression.evaluate(&context).map_err(|error| {
+                report.error(argument, Code::InvalidDebugCommand, format!("Cannot evaluate '{}': {}", expression, error));
+            });
+            let printed = match &line.command {
+                Command::Break(breakpoint) => {
+                    if let Err(error) = breakpoints.add(breakpoint, program) {
+                        report.report(argument, *error);
+                    }
+                    Ok(())
+                },
+                Command::Continue if !ended => return Ok(Resume::Continue),
+                Command::Step(count) if !ended => {
+                    self.steps_left = Some(*count);
+                    return Ok(Resume::Continue);
+                },
+                Command::Continue | Command::Step(_) => Ok(()),
+                Command::Regs => interpreter::show_state(isa, cpu, console),
+                Command::Recent(count) => interpreter::show_recent::<I>(cpu, &program.lines, *count, console),
+                Command::Print(expression) => match evaluate(expression) {
+                    Ok(value) => console.print(format_args!("{} = {}", expression, value)),
+                    Err(()) => Ok(()),
+                },
+                Command::Assert(expression) => {
+                    if evaluate(expression) == Ok(0) {
+                        report.report(argument, Diagnostic::error(Code::AssertionFailed, format!("Assertion failed: {}", expression))
+                            .with_note("the expression is 0"));
+                    }
+                    Ok(())
+                },
+                Command::Encode => {
+                    let labels = Labels { addresses: &cpu.as_ref().labels, code: &program.code_labels, pool: &cpu.as_ref().literal_pool() };
+                    interpreter::show_encoding(isa, argument, argument, &labels, &report, console);
+                    Ok(())
+                },
+                Command::Snapshot(name) => {
+                    self.snapshots.insert(name.clone(), Snapshot::of(I::NAME, &FinalState::of(isa, cpu)));
+                    Ok(())
+                },
+                Command::Diff(before, after) => {
+                    // The script was checked for snapshots taken before they are compared
+                    let now = Snapshot::of(I::NAME, &FinalState::of(isa, cpu));
+                    let after = after.as_ref().map_or(&now, |after| &self.snapshots[after]);
+                    let differences = self.snapshots[before].diff(after).expect("a script runs one instruction set");
+                    console.print(format_args!("{}", snapshot::report(&differences)))
+                },
+                Command::Quit => return Ok(Resume::Quit),
+            };
+            if let Err(error) = printed {
+                report.report(argument, *error);
+            }
+            let errors = errors.into_inner();
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+        }
+        Ok(Resume::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALKTHROUGH: &str = "// Walkthrough\nbreak loop count 2\nrun\nstep\nstep 3 // three more\nprint [total] + 1\nREGS\nrecent 5\nencode LDR r0, [r1, #4]";
+
+    #[test]
+    fn test_parse_commands() {
+        let script = DebugScript::parse("walk.dbg", WALKTHROUGH).unwrap();
+        let commands: Vec<&Command> = script.lines.iter().map(|line| &line.command).collect();
+        assert!(matches!(commands[0], Command::Break(_)));
+        assert_eq!(commands[1..4], [&Command::Continue, &Command::Step(1), &Command::Step(3)]);
+        assert!(matches!(commands[4], Command::Print(_)));
+        assert_eq!(commands[5..], [&Command::Regs, &Command::Recent(5), &Command::Encode]);
+    }
+
+    #[test]
+    fn test_lines_keep_their_place() {
+        let script = DebugScript::parse("walk.dbg", WALKTHROUGH).unwrap();
+        // Comments take up lines too
+        assert_eq!(script.lines[4].line_no, 6);
+        assert_eq!(&script.lines[7].source[script.lines[7].start..script.lines[7].end], "LDR r0, [r1, #4]");
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let errors = DebugScript::parse("walk.dbg", "brake loop\nstep 0\nprint r0 +\nrun now\nencode").unwrap_err();
+        assert_eq!(errors.len(), 5);
+        assert!(errors.iter().all(|error| error.code == Code::InvalidDebugCommand));
+        assert_eq!(errors[0].notes, vec![format!("the commands are {}", COMMANDS)]);
+        assert_eq!(errors[2].span.as_ref().unwrap().line, 3);
+    }
+}
@@ -0,0 +1,139 @@
+//! What each instruction changed, for front-ends that follow a program step by
+//! step, so they can update their view without comparing whole states.
+//!
+//! With [`RunOptions::deltas`](crate::interpreter::RunOptions::deltas) set, a run
+//! records a [`StateDelta`] for each instruction after the given step, which the
+//! final state then holds:
+//!
+//! ```text
+//! {"flags":[],"line":4,"memory":[[0,12]],"output":"","registers":[["r0",12]],"step":3}
+//! ```
+
+use serde_json::{json, Value};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::FinalState;
+
+/// The registers, flags and memory words an instruction changed, with their new
+/// values, and what it printed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateDelta {
+    /// How many instructions had run, counting this one
+    pub step: u64,
+    /// The source line of the instruction
+    pub line: usize,
+    pub registers: Vec<(&'static str, i64)>,
+    pub flags: Vec<(&'static str, bool)>,
+    /// Addresses and new values
+    pub memory: Vec<(usize, i32)>,
+    pub output: String,
+}
+
+impl StateDelta {
+    /// What differs in `after` from `before`, which must be states of the same instruction set.
+    pub fn between(before: &FinalState, after: &FinalState) -> Self {
+        StateDelta {
+            registers: after.registers.iter().zip(&before.registers).filter(|(now, then)| now != then).map(|(&now, _)| now).collect(),
+            flags: after.flags.iter().zip(&before.flags).filter(|(now, then)| now != then).map(|(&now, _)| now).collect(),
+            memory: after.memory.changes(&before.memory).collect(),
+            ..StateDelta::default()
+        }
+    }
+
+    /// The delta as a JSON object.
+    pub fn json(&self) -> Value {
+        json!({
+            "step": self.step,
+            "line": self.line,
+            "registers": self.registers.iter().map(|(name, value)| json!([name, value])).collect::<Vec<_>>(),
+            "flags": self.flags.iter().map(|(name, set)| json!([name, set])).collect::<Vec<_>>(),
+            "memory": self.memory.iter().map(|(address, word)| json!([address, word])).collect::<Vec<_>>(),
+            "output": self.output,
+        })
+    }
+
+    /// Applies the delta to `state`, giving the state after the instruction, or
+    /// fails leaving `state` as it was if the delta names what the state lacks.
+    pub fn apply(&self, state: &mut FinalState) -> Result<(), Box<Diagnostic>> {
+        let missing = |what: &str, name: &str| Box::new(Diagnostic::error(Code::InvalidRegister,
+            format!("The delta of step {} changes {} {}, which the state does not have", self.step, what, name))
+            .with_note("a delta applies to a state of the instruction set that recorded it"));
+        let registers = self.registers.iter().map(|&(name, value)| state.registers.iter().position(|(register, _)| *register == name)
+            .map(|index| (index, value)).ok_or_else(|| missing("register", name))).collect::<Result<Vec<_>, _>>()?;
+        let flags = self.flags.iter().map(|&(name, set)| state.flags.iter().position(|(flag, _)| *flag == name)
+            .map(|index| (index, set)).ok_or_else(|| missing("flag", name))).collect::<Result<Vec<_>, _>>()?;
+        if let Some(&(address, _)) = self.memory.iter().find(|&&(address, _)| address >= state.memory.len()) {
+            return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                format!("The delta of step {} writes address {}, outside the {} words of the state", self.step, address, state.memory.len()))));
+        }
+        for (index, value) in registers {
+            state.registers[index].1 = value;
+        }
+        for (index, set) in flags {
+            state.flags[index].1 = set;
+        }
+        for &(address, word) in &self.memory {
+            state.memory[address] = word;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources_keeping_state, Arm, RunOptions};
+
+    const PROGRAM: &str = "result: #0\nMOV r0, #5\nPRINT r0\nADDS r0, r0, #7\nSTR r0, result";
+
+    // The final state of PROGRAM, recording deltas after step `deltas`
+    fn run(deltas: Option<u64>) -> FinalState {
+        let options = RunOptions { deltas, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), PROGRAM.to_string())];
+        let (cpu, result) = run_sources_keeping_state(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink())).unwrap();
+        result.unwrap();
+        FinalState::of(&Arm, &cpu)
+    }
+
+    #[test]
+    fn test_each_step_is_a_delta() {
+        let deltas: Vec<Value> = run(Some(0)).deltas.iter().map(StateDelta::json).collect();
+        assert_eq!(deltas, [
+            json!({ "step": 1, "line": 2, "registers": [["r0", 5]], "flags": [], "memory": [], "output": "" }),
+            json!({ "step": 2, "line": 3, "registers": [], "flags": [], "memory": [], "output": "r0 = 5\n" }),
+            json!({ "step": 3, "line": 4, "registers": [["r0", 12]], "flags": [], "memory": [], "output": "" }),
+            json!({ "step": 4, "line": 5, "registers": [], "flags": [], "memory": [[0, 12]], "output": "" }),
+        ]);
+    }
+
+    #[test]
+    fn test_deltas_start_after_the_step() {
+        assert_eq!(run(Some(3)).deltas, run(Some(0)).deltas[3..]);
+        assert!(run(None).deltas.is_empty());
+    }
+
+    #[test]
+    fn test_deltas_lead_to_the_last_state() {
+        let state = run(Some(0));
+        let mut replayed = run(Some(0));
+        replayed.registers.iter_mut().for_each(|register| register.1 = 0);
+        replayed.memory[0] = 0;
+        for delta in &state.deltas {
+            delta.apply(&mut replayed).unwrap();
+        }
+        assert_eq!((replayed.registers[0], replayed.memory[0]), (("r0", 12), 12));
+    }
+
+    #[test]
+    fn test_malformed_deltas_leave_the_state() {
+        let mut state = run(None);
+        let unknown = StateDelta { step: 7, registers: vec![("r0", 1), ("rax", 1)], ..StateDelta::default() };
+        let error = unknown.apply(&mut state).unwrap_err();
+        assert_eq!((error.code, error.message.as_str()), (Code::InvalidRegister, "The delta of step 7 changes register rax, which the state does not have"));
+        let outside = StateDelta { step: 7, registers: vec![("r0", 1)], memory: vec![(1 << 20, 1)], ..StateDelta::default() };
+        let error = outside.apply(&mut state).unwrap_err();
+        assert_eq!((error.code, error.message.as_str()), (Code::MemoryOutOfBounds, "The delta of step 7 writes address 1048576, outside the 1024 words of the state"));
+        assert_eq!(state.registers[0], ("r0", 12));
+    }
+}
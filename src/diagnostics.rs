@@ -0,0 +1,377 @@
+//! Structured errors about a program.
+//!
+//! Every problem the interpreter finds is a [`Diagnostic`] with a severity, a stable
+//! code, an optional source span and notes. Diagnostics are printed either in a
+//! human-readable form or as JSON lines (`--diagnostics json`) for editors and graders.
+//! The readable form colors its severity on a terminal, which `--color` changes.
+
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use serde_json::json;
+use crate::transcript;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Stable identifiers for every kind of diagnostic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    WrongOperandCount,
+    MissingComma,
+    InvalidRegister,
+    RegisterExpected,
+    InvalidOperand,
+    InvalidAddressOperand,
+    NegativeAddress,
+    UndefinedLabel,
+    MemoryOutOfBounds,
+    DuplicateLabel,
+    InvalidDataValue,
+    UnknownInstruction,
+    OutOfLabelMemory,
+    UndefinedEntryPoint,
+    EntryPointIsData,
+    ThumbRestriction,
+    InvalidBranchTarget,
+    ArithmeticOverflow,
+    OutputFailed,
+    InputFailed,
+    StepLimit,
+    Interrupted,
+    Breakpoint,
+    InvalidBreakpoint,
+    InvalidDebugCommand,
+    AssertionFailed,
+    NoEncoding,
+    InvalidConfig,
+    InvalidRubric,
+    InvalidTaint,
+    UnsupportedOption,
+    InfiniteLoop,
+    UnknownService,
+    TaskLimit,
+    InvalidSchedule,
+    CycleBudget,
+    ForbiddenInstruction,
+    InvalidSnapshot,
+    InvalidIsaFile,
+    PluginFailed,
+    InvalidScript,
+    ReadOnlyMemory,
+    FailedExit,
+    NoDevice,
+    UndefinedValue,
+    Truncated,
+    UnsetFlags,
+    NoEffect,
+    DeadStore,
+}
+
+impl Code {
+    /// The code as shown to users, e.g. "E0012"
+    pub fn id(self) -> &'static str {
+        match self {
+            Code::WrongOperandCount => "E0001",
+            Code::MissingComma => "E0002",
+            Code::InvalidRegister => "E0003",
+            Code::RegisterExpected => "E0004",
+            Code::InvalidOperand => "E0005",
+            Code::InvalidAddressOperand => "E0006",
+            Code::NegativeAddress => "E0007",
+            Code::UndefinedLabel => "E0008",
+            Code::MemoryOutOfBounds => "E0009",
+            Code::DuplicateLabel => "E0010",
+            Code::InvalidDataValue => "E0011",
+            Code::UnknownInstruction => "E0012",
+            Code::OutOfLabelMemory => "E0013",
+            Code::UndefinedEntryPoint => "E0014",
+            Code::EntryPointIsData => "E0015",
+            Code::ThumbRestriction => "E0016",
+            Code::InvalidBranchTarget => "E0017",
+            Code::ArithmeticOverflow => "E0018",
+            Code::OutputFailed => "E0019",
+            Code::InputFailed => "E0020",
+            Code::StepLimit => "E0021",
+            Code::Interrupted => "E0022",
+            Code::Breakpoint => "E0023",
+            Code::InvalidBreakpoint => "E0024",
+            Code::InvalidDebugCommand => "E0025",
+            Code::AssertionFailed => "E0026",
+            Code::NoEncoding => "E0027",
+            Code::InvalidConfig => "E0028",
+            Code::InvalidRubric => "E0029",
+            Code::InvalidTaint => "E0030",
+            Code::UnsupportedOption => "E0031",
+            Code::InfiniteLoop => "E0032",
+            Code::UnknownService => "E0033",
+            Code::TaskLimit => "E0034",
+            Code::InvalidSchedule => "E0035",
+            Code::CycleBudget => "E0036",
+            Code::ForbiddenInstruction => "E0037",
+            Code::InvalidSnapshot => "E0038",
+            Code::InvalidIsaFile => "E0039",
+            Code::PluginFailed => "E0040",
+            Code::InvalidScript => "E0041",
+            Code::ReadOnlyMemory => "E0042",
+            Code::FailedExit => "E0043",
+            Code::NoDevice => "E0044",
+            // Warnings, which do not stop the program
+            Code::UndefinedValue => "W0001",
+            Code::Truncated => "W0002",
+            Code::UnsetFlags => "W0003",
+            Code::NoEffect => "W0004",
+            Code::DeadStore => "W0005",
+        }
+    }
+
+    /// The name of the code, e.g. "UnknownInstruction"
+    pub fn name(self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// A location in a source file. Lines and columns start at 1; `column_end` is exclusive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    // The full source line, for showing the offending text
+    pub source_line: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: Code, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Error, code, message: message.into(), span: None, notes: Vec::new() }
+    }
+
+    pub fn warning(code: Code, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Warning, code, message: message.into(), span: None, notes: Vec::new() }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic for a terminal, with the offending text underlined.
+    pub fn to_human(&self) -> String {
+        self.render(false)
+    }
+
+    // The readable form, with the severity in bold red or yellow if `color`
+    fn render(&self, color: bool) -> String {
+        let severity = match (color, self.severity) {
+            (false, severity) => severity.as_str().to_string(),
+            (true, Severity::Error) => format!("\x1b[1;31m{}\x1b[0m", self.severity.as_str()),
+            (true, Severity::Warning) => format!("\x1b[1;33m{}\x1b[0m", self.severity.as_str()),
+        };
+        let mut out = format!("{}[{}]: {}", severity, self.code.id(), self.message);
+        if let Some(span) = &self.span {
+            let line_no = span.line.to_string();
+            let gutter = " ".repeat(line_no.len());
+            out += &format!("\n{}--> {}:{}:{}", gutter, span.file, span.line, span.column_start);
+            out += &format!("\n{} |", gutter);
+            out += &format!("\n{} | {}", line_no, span.source_line);
+            out += &format!("\n{} | {}{}", gutter,
+                            " ".repeat(span.column_start - 1),
+                            "^".repeat((span.column_end - span.column_start).max(1)));
+            for note in &self.notes {
+                out += &format!("\n{} = note: {}", gutter, note);
+            }
+        } else {
+            for note in &self.notes {
+                out += &format!("\n = note: {}", note);
+            }
+        }
+        out
+    }
+
+    /// Renders the diagnostic as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let span = self.span.as_ref().map(|span| json!({
+            "file": span.file,
+            "line": span.line,
+            "column_start": span.column_start,
+            "column_end": span.column_end,
+        }));
+        json!({
+            "severity": self.severity.as_str(),
+            "code": self.code.id(),
+            "name": self.code.name(),
+            "message": self.message,
+            "span": span,
+            "notes": self.notes,
+        }).to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Readable messages with the offending source text underlined
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+/// When readable diagnostics are colored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Color {
+    /// When stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Human as u8);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn set_color(color: Color) {
+    let enabled = match color {
+        Color::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+        Color::Always => true,
+        Color::Never => false,
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints diagnostics to stderr in the selected format, recording them in any `--log-session`.
+pub fn emit(diagnostics: &[Diagnostic]) {
+    let json = FORMAT.load(Ordering::Relaxed) == Format::Json as u8;
+    let color = COLOR.load(Ordering::Relaxed);
+    for diagnostic in diagnostics {
+        transcript::diagnostic(diagnostic);
+        if json {
+            eprintln!("{}", diagnostic.to_json());
+        } else {
+            eprintln!("{}", diagnostic.render(color));
+        }
+    }
+}
+
+/// Reports diagnostics about one line of source into a shared list.
+pub struct Reporter<'a> {
+    diagnostics: &'a RefCell<Vec<Diagnostic>>,
+    file: &'a str,
+    line_no: usize,
+    source_line: &'a str,
+    // Added to every diagnostic reported
+    note: Option<String>,
+    // The operands of the instruction being decoded, for errors about one of them
+    usage: RefCell<Option<Usage>>,
+}
+
+struct Usage {
+    // The columns of each operand and what it is
+    operands: Vec<(usize, usize, String)>,
+    example: &'static str,
+}
+
+// The errors about an operand, which say which operand it is
+const OPERAND_CODES: [Code; 6] = [Code::InvalidRegister, Code::RegisterExpected, Code::InvalidOperand, Code::InvalidAddressOperand,
+    Code::NegativeAddress, Code::UndefinedLabel];
+
+impl<'a> Reporter<'a> {
+    pub fn new(diagnostics: &'a RefCell<Vec<Diagnostic>>, file: &'a str, line_no: usize, source_line: &'a str) -> Self {
+        Reporter { diagnostics, file, line_no, source_line, note: None, usage: RefCell::new(None) }
+    }
+
+    /// Adds `note` to every diagnostic reported, if given.
+    pub fn with_note(mut self, note: Option<String>) -> Self {
+        self.note = note;
+        self
+    }
+
+    /// Whether nothing has been reported yet.
+    pub fn is_clean(&self) -> bool {
+        self.diagnostics.borrow().is_empty()
+    }
+
+    /// Reports an error about `token`, a slice of the source line.
+    pub fn error(&self, token: &str, code: Code, message: impl Into<String>) {
+        self.report(token, Diagnostic::error(code, message));
+    }
+
+    /// Describes the operands of the instruction being decoded, each a slice of the source
+    /// line with what it is, e.g. "the third operand, <operand> in ADD <dest_register>,
+    /// <reg_operand>, <operand>". An error reported afterwards about one of them says
+    /// which it is and shows `example`, a correct instruction.
+    pub fn describe_operands(&self, operands: &[(&str, String)], example: &'static str) {
+        let operands = operands.iter().map(|(operand, what)| {
+            let span = self.span(operand);
+            (span.column_start, span.column_end, what.clone())
+        }).collect();
+        *self.usage.borrow_mut() = Some(Usage { operands, example });
+    }
+
+    /// Reports a diagnostic, pointing it at `token`, a slice of the source line.
+    pub fn report(&self, token: &str, mut diagnostic: Diagnostic) {
+        if let Some(usage) = self.usage.borrow().as_ref().filter(|_| OPERAND_CODES.contains(&diagnostic.code)) {
+            let span = self.span(token);
+            let operand = usage.operands.iter().find(|(start, end, _)| span.column_start >= *start && span.column_end <= *end);
+            if let Some((start, end, what)) = operand {
+                let relation = if (span.column_start, span.column_end) == (*start, *end) { "is" } else { "is in" };
+                diagnostic = diagnostic.with_note(format!("{} {} {}", token, relation, what))
+                    .with_note(format!("for example: {}", usage.example));
+            }
+        }
+        if let Some(note) = &self.note {
+            diagnostic = diagnostic.with_note(note.clone());
+        }
+        self.diagnostics.borrow_mut().push(diagnostic.with_span(self.span(token)));
+    }
+
+    /// The span of `token` within the source line. Tokens that are not slices of
+    /// the line span the whole line.
+    pub fn span(&self, token: &str) -> Span {
+        let line_start = self.source_line.as_ptr() as usize;
+        let token_start = token.as_ptr() as usize;
+        let (start, end) = if token_start >= line_start && token_start + token.len() <= line_start + self.source_line.len() {
+            let offset = token_start - line_start;
+            (offset, offset + token.len())
+        } else {
+            (0, self.source_line.len())
+        };
+        let column_start = self.source_line[..start].chars().count() + 1;
+        Span {
+            file: self.file.to_string(),
+            line: self.line_no,
+            column_start,
+            column_end: column_start + self.source_line[start..end].chars().count(),
+            source_line: self.source_line.to_string(),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Differential testing of the ARM front-end against unicorn-engine, enabled with
+//! `cargo test --features unicorn`.
+//!
+//! Random sequences of data-processing instructions run in the interpreter and,
+//! encoded as machine code, in unicorn. Both start from the same registers and
+//! flags, and must end with the same r0-r7 and NZCV. Each sequence is generated
+//! with its assembly text and its encoding side by side, so a mismatch can be
+//! reproduced by pasting the printed program into the interpreter.
+
+use unicorn_engine::unicorn_const::{Arch, Mode, Permission};
+use unicorn_engine::{RegisterARM, Unicorn};
+use crate::{Interpreter, RunOptions};
+
+const PROGRAMS: usize = 2000;
+const CODE_BASE: u64 = 0x1000;
+// Condition field AL, "always"
+const ALWAYS: u32 = 0xE << 28;
+const NZCV: [&str; 4] = ["N", "Z", "C", "V"];
+
+// xorshift64, so failures reproduce without a random-number dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u32) as usize]
+    }
+
+    // Register values that exercise carries, overflows and shift amounts
+    fn value(&mut self) -> u32 {
+        match self.below(3) {
+            0 => self.pick(&[0, 1, 2, 31, 32, 33, 0xFF, 0x7FFF_FFFF, 0x8000_0000, 0x8000_0001, 0xFFFF_FFFF, 0xFFFF_FFFE]),
+            1 => self.below(64),
+            _ => self.next(),
+        }
+    }
+}
+
+// One instruction as the interpreter reads it and as the processor decodes it
+struct Generated {
+    text: String,
+    word: u32,
+}
+
+fn register(rng: &mut Rng) -> u32 {
+    rng.below(8)
+}
+
+// A flexible second operand: a register, or an 8-bit value rotated right by twice
+// `rotation`. Returns the text and the low 12 bits and I bit of the encoding.
+fn operand2(rng: &mut Rng, rotated: bool) -> (String, u32) {
+    if rng.below(2) == 0 {
+        let rm = register(rng);
+        return (format!("r{}", rm), rm);
+    }
+    let imm8 = rng.below(256);
+    let rotation = if rotated { rng.below(16) } else { 0 };
+    let value = imm8.rotate_right(2 * rotation) as i32;
+    (format!("#{}", value), 1 << 25 | rotation << 8 | imm8)
+}
+
+fn instruction(rng: &mut Rng) -> Generated {
+    match rng.below(6) {
+        // ADD, SUB, ADC, SBC, AND, ORR, BIC and EOR, with or without S
+        0 | 1 => {
+            let (mnemonic, opcode) = rng.pick(&[("ADD", 4), ("SUB", 2), ("ADC", 5), ("SBC", 6), ("AND", 0), ("ORR", 12), ("BIC", 14), ("EOR", 1)]);
+            let set_flags = rng.below(2);
+            let (rd, rn) = (register(rng), register(rng));
+            let (op2, bits) = operand2(rng, true);
+            Generated {
+                text: format!("{}{} r{}, r{}, {}", mnemonic, if set_flags == 1 { "S" } else { "" }, rd, rn, op2),
+                word: ALWAYS | opcode << 21 | set_flags << 20 | rn << 16 | rd << 12 | bits,
+            }
+        },
+        2 => {
+            let (mnemonic, opcode) = rng.pick(&[("CMP", 10), ("CMN", 11)]);
+            let rn = register(rng);
+            let (op2, bits) = operand2(rng, true);
+            Generated { text: format!("{} r{}, {}", mnemonic, rn, op2), word: ALWAYS | opcode << 21 | 1 << 20 | rn << 16 | bits }
+        },
+        3 => {
+            let rd = register(rng);
+            let (op2, bits) = operand2(rng, true);
+            Generated { text: format!("MOV r{}, {}", rd, op2), word: ALWAYS | 13 << 21 | rd << 12 | bits }
+        },
+        // MUL rd, rn, rm is encoded with rn in the Rm field and rm in the Rs field
+        4 => {
+            let set_flags = rng.below(2);
+            let (rd, rn, rm) = (register(rng), register(rng), register(rng));
+            Generated {
+                text: format!("MUL{} r{}, r{}, r{}", if set_flags == 1 { "S" } else { "" }, rd, rn, rm),
+                word: ALWAYS | set_flags << 20 | rd << 16 | rm << 8 | 0b1001 << 4 | rn,
+            }
+        },
+        // Shifts and RRX are MOV with a shifted register
+        _ => {
+            let (rd, rm) = (register(rng), register(rng));
+            let shift = rng.below(5);
+            if shift == 4 {
+                return Generated { text: format!("RRX r{}, r{}", rd, rm), word: ALWAYS | 13 << 21 | rd << 12 | 3 << 5 | rm };
+            }
+            let mnemonic = ["LSL", "LSR", "ASR", "ROR"][shift as usize];
+            let set_flags = rng.below(2);
+            let suffix = if set_flags == 1 { "S" } else { "" };
+            let base = ALWAYS | 13 << 21 | set_flags << 20 | rd << 12 | shift << 5 | rm;
+            if rng.below(2) == 0 {
+                let rs = register(rng);
+                return Generated { text: format!("{}{} r{}, r{}, r{}", mnemonic, suffix, rd, rm, rs), word: base | rs << 8 | 1 << 4 };
+            }
+            // LSR and ASR by 32 are encoded as 0, and ROR by 0 would be RRX
+            let amount = match shift {
+                0 => rng.below(32),
+                3 => 1 + rng.below(31),
+                _ => 1 + rng.below(32),
+            };
+            Generated { text: format!("{}{} r{}, r{}, #{}", mnemonic, suffix, rd, rm, amount), word: base | (amount % 32) << 7 }
+        },
+    }
+}
+
+// The final r0-r7 and NZCV of a program in unicorn
+fn run_unicorn(program: &[Generated], registers: &[u32; 8], flags: u32) -> ([u32; 8], [bool; 4]) {
+    let mut emu = Unicorn::new(Arch::ARM, Mode::ARM).expect("unicorn supports ARM");
+    let code: Vec<u8> = program.iter().flat_map(|instruction| instruction.word.to_le_bytes()).collect();
+    emu.mem_map(CODE_BASE, 0x1000, Permission::ALL).unwrap();
+    emu.mem_write(CODE_BASE, &code).unwrap();
+    for (index, &value) in registers.iter().enumerate() {
+        emu.reg_write(RegisterARM::R0 as i32 + index as i32, value as u64).unwrap();
+    }
+    let cpsr = emu.reg_read(RegisterARM::CPSR).unwrap() as u32;
+    emu.reg_write(RegisterARM::CPSR, (cpsr & 0x0FFF_FFFF | flags << 28) as u64).unwrap();
+    emu.emu_start(CODE_BASE, CODE_BASE + code.len() as u64, 0, program.len()).unwrap();
+
+    let final_registers = std::array::from_fn(|index| emu.reg_read(RegisterARM::R0 as i32 + index as i32).unwrap() as u32);
+    let cpsr = emu.reg_read(RegisterARM::CPSR).unwrap() as u32;
+    (final_registers, std::array::from_fn(|bit| cpsr & 1 << (31 - bit) != 0))
+}
+
+// The final r0-r7 and NZCV of a program in the interpreter
+fn run_interpreter(source: &str, registers: &[u32; 8]) -> ([u32; 8], [bool; 4]) {
+    let options = RunOptions {
+        registers: registers.iter().enumerate().map(|(index, &value)| (index, value as i32)).collect(),
+        ..RunOptions::default()
+    };
+    let state = Interpreter::new(crate::isa::FRONT_ENDS[0], options).run_source(source)
+        .unwrap_or_else(|errors| panic!("{}\n{}", source, errors.iter().map(|error| error.to_human()).collect::<Vec<_>>().join("\n")));
+    let final_registers = std::array::from_fn(|index| state.register(&format!("r{}", index)).unwrap() as u32);
+    (final_registers, NZCV.map(|flag| state.flag(flag).unwrap()))
+}
+
+#[test]
+fn test_data_processing_matches_unicorn() {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    for _ in 0..PROGRAMS {
+        let registers: [u32; 8] = std::array::from_fn(|_| rng.value());
+        let flags = rng.below(16);
+        let program: Vec<Generated> = (0..1 + rng.below(6)).map(|_| instruction(&mut rng)).collect();
+        // The interpreter starts with the flags clear, so they are set with MSR
+        let mut source = format!("MSR CPSR_f, #{}\n", (flags << 28) as i32);
+        for instruction in &program {
+            source.push_str(&instruction.text);
+            source.push('\n');
+        }
+
+        let expected = run_unicorn(&program, &registers, flags);
+        let actual = run_interpreter(&source, &registers);
+        assert_eq!(actual, expected, "\nstarting registers {:x?}, NZCV {:04b}\n{}(left: interpreter, right: unicorn)",
+            registers, flags, source);
+    }
+}
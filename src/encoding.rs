@@ -0,0 +1,316 @@
+//! The 32-bit ARM encodings shown by `ENCODE`.
+//!
+//! An instruction is encoded field by field, and each field keeps its bit range
+//! and what its value means, so the encoding can be drawn as a diagram:
+//!
+//! ```text
+//! +-------+-------+----+--------+----+-------+-------+--------------+
+//! | 31-28 | 27-26 | 25 | 24-21  | 20 | 19-16 | 15-12 | 11-0         |
+//! | 1110  | 00    | 1  | 0100   | 0  | 0010  | 0001  | 000000000100 |
+//! | cond  | op    | I  | opcode | S  | Rn    | Rd    | operand2     |
+//! +-------+-------+----+--------+----+-------+-------+--------------+
+//! ```
+//!
+//! Only what a real assembler would produce for the same text is encoded: the
+//! interpreter accepts any immediate, but an operand2 immediate must be an 8-bit
+//! value rotated right by an even amount, and branches and labels are relative
+//! to the PC of a loaded program.
+
+use std::fmt;
+use crate::interpreter::{AddressOperand, AluOp, Instruction, Operand, ShiftOp, ARM_REGISTERS};
+
+/// A field of an encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    /// The highest and lowest bit of the field
+    pub high: u32,
+    pub low: u32,
+    pub value: u32,
+    /// What the value stands for, e.g. `r2` for a register field
+    pub meaning: String,
+}
+
+impl Field {
+    fn bits(&self) -> String {
+        format!("{:0width$b}", self.value, width = (self.high - self.low + 1) as usize)
+    }
+
+    fn range(&self) -> String {
+        if self.high == self.low { self.high.to_string() } else { format!("{}-{}", self.high, self.low) }
+    }
+}
+
+/// An instruction word and its fields, from bit 31 down.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub word: u32,
+    pub fields: Vec<Field>,
+}
+
+impl Encoding {
+    fn field(mut self, name: &'static str, high: u32, low: u32, value: u32, meaning: impl Into<String>) -> Self {
+        let mask = (1u64 << (high - low + 1)) as u32 - 1;
+        debug_assert!(value & !mask == 0, "{} does not fit in bits {}-{}", name, high, low);
+        self.word |= (value & mask) << low;
+        self.fields.push(Field { name, high, low, value, meaning: meaning.into() });
+        self
+    }
+
+    // Every instruction here is unconditional
+    fn always() -> Self {
+        Encoding::default().field("cond", 31, 28, 0b1110, "AL, always")
+    }
+}
+
+impl fmt::Display for Encoding {
+    /// Draws the fields as a table of bit ranges, bits and names, then lists their meanings.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows: Vec<[String; 3]> = self.fields.iter().map(|field| [field.range(), field.bits(), field.name.to_string()]).collect();
+        let widths: Vec<usize> = rows.iter().map(|row| row.iter().map(String::len).max().unwrap_or(0)).collect();
+        let border: String = widths.iter().map(|&width| format!("+{}", "-".repeat(width + 2))).collect::<String>() + "+";
+        writeln!(f, "{}", border)?;
+        for line in 0..3 {
+            let cells: String = rows.iter().zip(&widths).map(|(row, &width)| format!("| {:<width$} ", row[line], width = width)).collect();
+            writeln!(f, "{}|", cells)?;
+        }
+        write!(f, "{}", border)?;
+        for field in &self.fields {
+            write!(f, "\n  {:<8} {}", field.name, field.meaning)?;
+        }
+        Ok(())
+    }
+}
+
+fn register(index: usize) -> String {
+    ARM_REGISTERS.names[index].to_string()
+}
+
+// An operand2 immediate: an 8-bit value and half the amount it is rotated right by
+pub(crate) fn rotated_immediate(value: i32) -> Result<(u32, u32), String> {
+    let value = value as u32;
+    (0..16).map(|rotate| (value.rotate_left(2 * rotate), rotate))
+        .find(|&(imm8, _)| imm8 <= 0xFF)
+        .map(|(imm8, rotate)| (rotate, imm8))
+        .ok_or_else(|| format!("#{} is not an 8-bit value rotated right by an even amount, so it has no operand2 encoding", value as i32))
+}
+
+fn shift_type(op: ShiftOp) -> (u32, &'static str) {
+    match op {
+        ShiftOp::Lsl => (0b00, "LSL"),
+        ShiftOp::Lsr => (0b01, "LSR"),
+        ShiftOp::Asr => (0b10, "ASR"),
+        ShiftOp::Ror => (0b11, "ROR"),
+    }
+}
+
+// Bits 11-0 of operand2 and their meaning: `rm` shifted by `shift`, or an immediate
+fn operand2(src: Operand, shift: Option<(ShiftOp, Operand)>) -> Result<(bool, u32, String), String> {
+    match (src, shift) {
+        (Operand::Immediate(value), _) => {
+            let (rotate, imm8) = rotated_immediate(value)?;
+            Ok((true, rotate << 8 | imm8, format!("#{}: {} rotated right by {} (rotate {})", value, imm8, 2 * rotate, rotate)))
+        },
+        (Operand::Register(rm), None) => Ok((false, rm as u32, format!("{}, not shifted", register(rm)))),
+        (Operand::Register(rm), Some((op, Operand::Register(rs)))) => {
+            let (kind, name) = shift_type(op);
+            Ok((false, (rs as u32) << 8 | kind << 5 | 1 << 4 | rm as u32, format!("{}, {} {}", register(rm), name, register(rs))))
+        },
+        (Operand::Register(rm), Some((op, Operand::Immediate(amount)))) => {
+            let (kind, name) = shift_type(op);
+            // LSR #32 and ASR #32 are encoded as 0, and ROR #0 would be RRX
+            let encoded = match (op, amount) {
+                (ShiftOp::Lsl, 0..=31) | (ShiftOp::Ror, 1..=31) | (ShiftOp::Lsr | ShiftOp::Asr, 1..=31) => amount as u32,
+                (ShiftOp::Lsr | ShiftOp::Asr, 32) => 0,
+                _ => return Err(format!("{} #{} has no encoding", name, amount)),
+            };
+            Ok((false, encoded << 7 | kind << 5 | rm as u32, format!("{}, {} #{}", register(rm), name, amount)))
+        },
+    }
+}
+
+fn data_processing(opcode: (u32, &str), set_flags: bool, rn: Option<usize>, rd: Option<usize>, op2: (bool, u32, String)) -> Encoding {
+    let (immediate, bits, meaning) = op2;
+    let register_field = |reg: Option<usize>| reg.map_or((0, "unused".to_string()), |reg| (reg as u32, register(reg)));
+    let ((rn, rn_meaning), (rd, rd_meaning)) = (register_field(rn), register_field(rd));
+    Encoding::always()
+        .field("op", 27, 26, 0b00, "data processing")
+        .field("I", 25, 25, immediate as u32, if immediate { "operand2 is an immediate" } else { "operand2 is a register" })
+        .field("opcode", 24, 21, opcode.0, opcode.1)
+        .field("S", 20, 20, set_flags as u32, if set_flags { "sets the flags" } else { "leaves the flags" })
+        .field("Rn", 19, 16, rn, rn_meaning)
+        .field("Rd", 15, 12, rd, rd_meaning)
+        .field("operand2", 11, 0, bits, meaning)
+}
+
+// Why MUL rd, rn, #value has no encoding, and what to write instead
+fn mul_immediate(rd: usize, rn: usize, value: i32) -> String {
+    // r12 is the scratch register of the calling convention
+    let scratch = (0..=12).rev().find(|&reg| reg != rd && reg != rn).unwrap();
+    format!("MUL has no immediate form; put the constant in a spare register first: MOV {2}, #{3} then MUL {0}, {1}, {2}",
+        register(rd), register(rn), register(scratch), value)
+}
+
+// MUL Rd, Rm, Rs computes Rm * Rs, and MLA and MLS add it to or subtract it from Rn
+fn multiply(op: u32, set_flags: bool, rd: usize, rn: (u32, String), rm: usize, rs: usize) -> Encoding {
+    Encoding::always()
+        .field("op", 27, 21, op, "multiply")
+        .field("S", 20, 20, set_flags as u32, if set_flags { "sets the flags" } else { "leaves the flags" })
+        .field("Rd", 19, 16, rd as u32, register(rd))
+        .field("Rn", 15, 12, rn.0, rn.1)
+        .field("Rs", 11, 8, rs as u32, register(rs))
+        .field("op", 7, 4, 0b1001, "multiply")
+        .field("Rm", 3, 0, rm as u32, register(rm))
+}
+
+/// Checks that the immediates of an instruction fit their fields, as a real
+/// assembler requires. Like one, a MOV, AND, ADC or their counterparts may use the
+/// complement of an immediate that does not fit (MOV r0, #-1 is MVN r0, #0), and
+/// an ADD, SUB, CMP or CMN its negation.
+pub fn check_immediates(instruction: &Instruction) -> Result<(), String> {
+    let (value, counterpart) = match *instruction {
+        Instruction::Mov { src: Operand::Immediate(value), .. } => (value, !value),
+        Instruction::Alu { op: AluOp::Mul, rd, rn, op2: Operand::Immediate(value), .. } => return Err(mul_immediate(rd, rn, value)),
+        Instruction::Alu { op: AluOp::Mul, .. } => return Ok(()),
+        Instruction::Alu { op, op2: Operand::Immediate(value), .. } => match op {
+            AluOp::Add | AluOp::Sub => (value, value.wrapping_neg()),
+            AluOp::And | AluOp::Bic | AluOp::Adc | AluOp::Sbc => (value, !value),
+            _ => (value, value),
+        },
+        Instruction::Cmp { op2: Operand::Immediate(value), .. } | Instruction::Cmn { op2: Operand::Immediate(value), .. } => {
+            (value, value.wrapping_neg())
+        },
+        Instruction::Shift { op, rm, amount: amount @ Operand::Immediate(_), .. } => {
+            return operand2(Operand::Register(rm), Some((op, amount))).map(|_| ());
+        },
+        Instruction::Ldr { address: AddressOperand::RegisterOffset(_, offset), .. }
+        | Instruction::Str { address: AddressOperand::RegisterOffset(_, offset), .. } if offset.unsigned_abs() > 0xFFF => {
+            return Err(format!("the offset #{} does not fit in 12 bits", offset));
+        },
+        _ => return Ok(()),
+    };
+    rotated_immediate(value).or_else(|error| rotated_immediate(counterpart).map_err(|_| error)).map(|_| ())
+}
+
+/// Encodes a data-processing, multiply, load/store or BX instruction, or explains why it cannot be.
+pub fn encode(instruction: &Instruction) -> Result<Encoding, String> {
+    Ok(match *instruction {
+        Instruction::Mov { rd, src } => data_processing((0b1101, "MOV"), false, None, Some(rd), operand2(src, None)?),
+        Instruction::Alu { op: AluOp::Mul, set_flags, rd, rn, op2 } => {
+            let rm = match op2 {
+                Operand::Register(rm) => rm,
+                Operand::Immediate(value) => return Err(mul_immediate(rd, rn, value)),
+            };
+            multiply(0b0000000, set_flags, rd, (0, "unused, the addend of MLA".to_string()), rn, rm)
+        },
+        Instruction::MultiplyAccumulate { subtract: false, set_flags, rd, rn, rm, ra } => {
+            multiply(0b0000001, set_flags, rd, (ra as u32, format!("{}, the addend", register(ra))), rn, rm)
+        },
+        // MLS is MLA with the S bit always clear and bit 22 set
+        Instruction::MultiplyAccumulate { subtract: true, rd, rn, rm, ra, .. } => {
+            multiply(0b0000011, false, rd, (ra as u32, format!("{}, subtracted from", register(ra))), rn, rm)
+        },
+        Instruction::Alu { op, set_flags, rd, rn, op2 } => {
+            let opcode = match op {
+                AluOp::And => (0b0000, "AND"),
+                AluOp::Eor => (0b0001, "EOR"),
+                AluOp::Sub => (0b0010, "SUB"),
+                AluOp::Add => (0b0100, "ADD"),
+                AluOp::Adc => (0b0101, "ADC"),
+                AluOp::Sbc => (0b0110, "SBC"),
+                AluOp::Orr => (0b1100, "ORR"),
+                AluOp::Bic => (0b1110, "BIC"),
+                AluOp::Mul => unreachable!("encoded above"),
+            };
+            data_processing(opcode, set_flags, Some(rn), Some(rd), operand2(op2, None)?)
+        },
+        // Comparisons always set the flags and write no register
+        Instruction::Cmp { rn, op2 } => data_processing((0b1010, "CMP"), true, Some(rn), None, operand2(op2, None)?),
+        Instruction::Cmn { rn, op2 } => data_processing((0b1011, "CMN"), true, Some(rn), None, operand2(op2, None)?),
+        // Shifts are MOVs of a shifted register
+        Instruction::Shift { op, set_flags, rd, rm, amount } => {
+            data_processing((0b1101, "MOV"), set_flags, None, Some(rd), operand2(Operand::Register(rm), Some((op, amount)))?)
+        },
+        Instruction::Rrx { rd, rm } => {
+            data_processing((0b1101, "MOV"), false, None, Some(rd), (false, 0b11 << 5 | rm as u32, format!("{}, RRX", register(rm))))
+        },
+        Instruction::Ldr { rd: rt, address } | Instruction::Str { rs: rt, address } => {
+            let load = matches!(instruction, Instruction::Ldr { .. });
+            let AddressOperand::RegisterOffset(rn, offset) = address else {
+                return Err("a label is addressed relative to the PC of a loaded program; use [Rn, #offset]".to_string());
+            };
+            if offset.unsigned_abs() > 0xFFF {
+                return Err(format!("the offset #{} does not fit in 12 bits", offset));
+            }
+            Encoding::always()
+                .field("op", 27, 26, 0b01, "load/store")
+                .field("I", 25, 25, 0, "the offset is an immediate")
+                .field("P", 24, 24, 1, "offset before the access")
+                .field("U", 23, 23, (offset >= 0) as u32, if offset >= 0 { "add the offset" } else { "subtract the offset" })
+                .field("B", 22, 22, 0, "a word")
+                .field("W", 21, 21, 0, "no write-back")
+                .field("L", 20, 20, load as u32, if load { "load" } else { "store" })
+                .field("Rn", 19, 16, rn as u32, register(rn))
+                .field("Rt", 15, 12, rt as u32, register(rt))
+                .field("offset", 11, 0, offset.unsigned_abs(), format!("#{}", offset.unsigned_abs()))
+        },
+        Instruction::Bx { rm } => Encoding::always()
+            .field("op", 27, 4, 0x12FFF1, "BX")
+            .field("Rm", 3, 0, rm as u32, register(rm)),
+        Instruction::Svc { number } => Encoding::always()
+            .field("op", 27, 24, 0b1111, "SVC")
+            .field("imm24", 23, 0, number, "the service"),
+        _ => return Err("ENCODE covers data processing, shifts, MUL, MLA, MLS, LDR/STR with [Rn, #offset], BX and SVC".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(instruction: Instruction) -> Result<u32, String> {
+        encode(&instruction).map(|encoding| encoding.word)
+    }
+
+    #[test]
+    fn test_fields_table() {
+        let add = Instruction::Alu { op: AluOp::Add, set_flags: false, rd: 1, rn: 2, op2: Operand::Immediate(4) };
+        let encoding = encode(&add).unwrap();
+        assert_eq!(encoding.word, 0xE2821004);
+        assert_eq!(encoding.to_string().lines().take(5).collect::<Vec<_>>(), [
+            "+-------+-------+----+--------+----+-------+-------+--------------+",
+            "| 31-28 | 27-26 | 25 | 24-21  | 20 | 19-16 | 15-12 | 11-0         |",
+            "| 1110  | 00    | 1  | 0100   | 0  | 0010  | 0001  | 000000000100 |",
+            "| cond  | op    | I  | opcode | S  | Rn    | Rd    | operand2     |",
+            "+-------+-------+----+--------+----+-------+-------+--------------+",
+        ]);
+        assert!(encoding.to_string().ends_with("\n  operand2 #4: 4 rotated right by 0 (rotate 0)"));
+    }
+
+    #[test]
+    fn test_data_processing() {
+        assert_eq!(word(Instruction::Mov { rd: 0, src: Operand::Immediate(0xFF00) }), Ok(0xE3A00CFF));
+        assert_eq!(word(Instruction::Cmp { rn: 3, op2: Operand::Register(4) }), Ok(0xE1530004));
+        assert_eq!(word(Instruction::Shift { op: ShiftOp::Lsl, set_flags: true, rd: 1, rm: 0, amount: Operand::Immediate(2) }), Ok(0xE1B01100));
+    }
+
+    #[test]
+    fn test_multiplies() {
+        assert_eq!(word(Instruction::Alu { op: AluOp::Mul, set_flags: false, rd: 0, rn: 1, op2: Operand::Register(2) }), Ok(0xE0000291));
+        assert_eq!(word(Instruction::MultiplyAccumulate { subtract: false, set_flags: false, rd: 0, rn: 1, rm: 2, ra: 3 }), Ok(0xE0203291));
+        assert_eq!(word(Instruction::MultiplyAccumulate { subtract: true, set_flags: false, rd: 0, rn: 1, rm: 2, ra: 3 }), Ok(0xE0603291));
+    }
+
+    #[test]
+    fn test_loads_and_branches() {
+        assert_eq!(word(Instruction::Ldr { rd: 2, address: AddressOperand::RegisterOffset(0, -4) }), Ok(0xE5102004));
+        assert_eq!(word(Instruction::Bx { rm: 14 }), Ok(0xE12FFF1E));
+    }
+
+    #[test]
+    fn test_unencodable() {
+        // 0x101 is no 8-bit value rotated by an even amount, and ARM has no absolute addresses
+        assert!(word(Instruction::Mov { rd: 0, src: Operand::Immediate(0x101) }).is_err());
+        assert!(word(Instruction::Str { rs: 0, address: AddressOperand::Absolute(4) }).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Demonstration programs built into the binary, for `asm examples`.
+//!
+//! The sources live in `examples/` and are compiled in with `include_str!`, so
+//! `asm examples run gcd` works wherever the binary is installed. They are ARM
+//! programs and are run with the ARM front-end whatever `--isa` says.
+
+/// A built-in program.
+pub struct Example {
+    /// The name `asm examples run` takes
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+}
+
+impl Example {
+    /// The file name diagnostics show for the program.
+    pub fn file(&self) -> String {
+        format!("{}.s", self.name)
+    }
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example { name: "fibonacci", description: "The first 10 Fibonacci numbers", source: include_str!("../examples/fibonacci.s") },
+    Example { name: "bubble-sort", description: "Sorting an array in memory", source: include_str!("../examples/bubble_sort.s") },
+    Example { name: "string-reverse", description: "Reversing a string in place", source: include_str!("../examples/string_reverse.s") },
+    Example { name: "gcd", description: "Euclid's algorithm as a function", source: include_str!("../examples/gcd.s") },
+];
+
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
+
+/// The names and descriptions of the examples, one per line.
+pub fn list() -> String {
+    let width = EXAMPLES.iter().map(|example| example.name.len()).max().unwrap_or(0);
+    EXAMPLES.iter().map(|example| format!("{:width$}  {}\n", example.name, example.description)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    #[test]
+    fn test_examples() {
+        let run = |name: &str| {
+            let example = find(name).unwrap();
+            let mut out = Vec::new();
+            let sources = [(example.file(), example.source.to_string())];
+            run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines().map(|line| line.trim_start_matches("r0 = ").to_string()).collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(run("fibonacci"), "0 1 1 2 3 5 8 13 21 34");
+        assert_eq!(run("bubble-sort"), "-3 0 2 5 7 9");
+        assert_eq!(run("string-reverse"), "111 108 108 101 104");
+        assert_eq!(run("gcd"), "21");
+        assert_eq!(list().lines().count(), EXAMPLES.len());
+        assert!(list().starts_with("fibonacci       The first 10"));
+    }
+}
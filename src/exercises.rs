@@ -0,0 +1,188 @@
+//! Built-in exercises, for `asm exercise`.
+//!
+//! An exercise asks for a small ARM program, such as one summing an array. A
+//! solution is run once for each of several cases, with registers and memory
+//! preloaded, and the goal expression is evaluated on the state it leaves
+//! behind, so a program that only works for one input does not pass.
+
+use std::io;
+use crate::console::Console;
+use crate::diagnostics::Diagnostic;
+use crate::expression::Expression;
+use crate::interpreter::{run_sources, Arm, FinalState, RunOptions};
+
+/// Where the data of a case is placed, clear of the labels of a solution.
+pub const DATA: usize = 512;
+// Steps a case may take before the solution is taken to loop forever
+const MAX_STEPS: u64 = 100_000;
+
+/// One run of a solution.
+pub struct Case {
+    /// The input, as the feedback mentions it
+    pub description: &'static str,
+    /// Initial registers as (register index, value)
+    pub registers: &'static [(usize, i32)],
+    /// Words placed at [`DATA`] onwards
+    pub memory: &'static [i32],
+    /// The value the goal must have
+    pub expected: i64,
+}
+
+pub struct Exercise {
+    pub name: &'static str,
+    pub prompt: &'static str,
+    /// An expression over the final state, e.g. `r0` or `[512]`
+    pub goal: &'static str,
+    pub cases: &'static [Case],
+    /// Shown when a case fails, from the gentlest
+    pub hints: &'static [&'static str],
+}
+
+pub const EXERCISES: &[Exercise] = &[
+    Exercise {
+        name: "sum-memory",
+        prompt: "Words 512 to 521 of memory hold ten numbers, and r1 holds 512, the address of the first. \
+            Make r0 contain the sum of the ten numbers.",
+        goal: "r0",
+        cases: &[
+            Case { description: "the numbers 1 to 10", registers: &[(1, 512)], memory: &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], expected: 55 },
+            Case { description: "negative numbers", registers: &[(1, 512)], memory: &[-4, 7, -100, 0, 3, 3, 3, -8, 20, 1], expected: -75 },
+            Case { description: "ten zeros", registers: &[(1, 512)], memory: &[0; 10], expected: 0 },
+        ],
+        hints: &[
+            "Keep a running total in r0, starting at 0, and add one word at a time.",
+            "LDR r2, [r1] loads the word r1 points to, and ADD r1, r1, #1 moves on to the next word.",
+            "Count the words in another register and stop with CMP and BNE after the tenth.",
+        ],
+    },
+    Exercise {
+        name: "max",
+        prompt: "Words 512 to 519 of memory hold eight signed numbers, and r1 holds 512. \
+            Make r0 contain the largest of them.",
+        goal: "r0",
+        cases: &[
+            Case { description: "increasing numbers", registers: &[(1, 512)], memory: &[1, 2, 3, 4, 5, 6, 7, 8], expected: 8 },
+            Case { description: "the largest first", registers: &[(1, 512)], memory: &[90, 2, 30, 4, 5, 6, 7, 8], expected: 90 },
+            Case { description: "only negative numbers", registers: &[(1, 512)], memory: &[-9, -3, -70, -4, -5, -6, -7, -8], expected: -3 },
+        ],
+        hints: &[
+            "Start with the first word as the largest so far, not with 0: all the numbers may be negative.",
+            "Compare each word with CMP and replace the largest so far only when the word is greater.",
+            "BLE skips the replacement with a signed comparison; BLS would compare unsigned.",
+        ],
+    },
+    Exercise {
+        name: "count-bits",
+        prompt: "Make r0 contain the number of bits that are set (1) in r1.",
+        goal: "r0",
+        cases: &[
+            Case { description: "r1 = 0b1011", registers: &[(1, 0b1011)], memory: &[], expected: 3 },
+            Case { description: "r1 = 0", registers: &[(1, 0)], memory: &[], expected: 0 },
+            Case { description: "r1 = -1, all 32 bits set", registers: &[(1, -1)], memory: &[], expected: 32 },
+        ],
+        hints: &[
+            "AND r2, r1, #1 gives the lowest bit of r1.",
+            "Shift r1 right one bit at a time with LSR, so the sign bit does not come back as ASR would bring it.",
+            "Stop when r1 is 0 rather than after 32 steps; CMP r1, #0 and BNE loop back.",
+        ],
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Exercise> {
+    EXERCISES.iter().find(|exercise| exercise.name == name)
+}
+
+/// How a solution did in one case.
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    /// The goal had another value
+    Wrong(i64),
+    /// The solution stopped with errors
+    Stopped(Vec<Diagnostic>),
+}
+
+impl Exercise {
+    /// Runs the solution in `sources` for every case.
+    pub fn check(&self, sources: &[(String, String)]) -> Vec<Outcome> {
+        let goal = Expression::parse(self.goal).expect("the goals of exercises are valid");
+        self.cases.iter().map(|case| {
+            let options = RunOptions {
+                registers: case.registers.to_vec(),
+                memory: case.memory.iter().enumerate().map(|(index, &word)| (DATA + index, word)).collect(),
+                max_steps: Some(MAX_STEPS),
+                ..RunOptions::default()
+            };
+            // What the solution prints is not part of the goal
+            match run_sources(&Arm, sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink())) {
+                Ok(cpu) => match goal.evaluate(&FinalState::of(&Arm, &cpu)) {
+                    Ok(value) if value == case.expected => Outcome::Passed,
+                    Ok(value) => Outcome::Wrong(value),
+                    Err(message) => panic!("the goal of {} cannot be evaluated: {}", self.name, message),
+                },
+                Err(errors) => Outcome::Stopped(errors),
+            }
+        }).collect()
+    }
+
+    /// A line per case, then the hints if any case failed.
+    pub fn summary(&self, outcomes: &[Outcome]) -> String {
+        let mut summary = String::new();
+        for (number, (case, outcome)) in self.cases.iter().zip(outcomes).enumerate() {
+            let result = match outcome {
+                Outcome::Passed => "passed".to_string(),
+                Outcome::Wrong(value) => format!("failed: expected {} = {}, got {}", self.goal, case.expected, value),
+                Outcome::Stopped(_) => "failed: the program stopped with an error".to_string(),
+            };
+            summary.push_str(&format!("Case {} ({}): {}\n", number + 1, case.description, result));
+        }
+        let passed = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Passed)).count();
+        if passed == outcomes.len() {
+            summary.push_str(&format!("All {} cases passed.\n", passed));
+        } else {
+            summary.push_str(&format!("{} of {} cases passed. Hints:\n", passed, outcomes.len()));
+            for hint in self.hints {
+                summary.push_str(&format!("  - {}\n", hint));
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(exercise: &Exercise, solution: &str) -> Vec<Outcome> {
+        exercise.check(&[("solution.s".to_string(), solution.to_string())])
+    }
+
+    #[test]
+    fn test_passing_solution() {
+        let sum = find("sum-memory").unwrap();
+        let outcomes = check(sum, "MOV r0, #0\nMOV r3, #10\nloop: LDR r2, [r1]\nADD r0, r0, r2\nADD r1, r1, #1\nSUBS r3, r3, #1\nBNE loop");
+        assert!(outcomes.iter().all(|outcome| matches!(outcome, Outcome::Passed)));
+        assert!(sum.summary(&outcomes).ends_with("All 3 cases passed.\n"));
+        let bits = find("count-bits").unwrap();
+        let solution = "MOV r0, #0\nloop: CMP r1, #0\nBEQ done\nAND r2, r1, #1\nADD r0, r0, r2\nLSR r1, r1, #1\nB loop\ndone: EXIT";
+        assert!(check(bits, solution).iter().all(|outcome| matches!(outcome, Outcome::Passed)));
+    }
+
+    #[test]
+    fn test_hard_coded_answer() {
+        // Hard-coding the first answer fails the other cases
+        let sum = find("sum-memory").unwrap();
+        let outcomes = check(sum, "MOV r0, #55");
+        assert!(matches!(outcomes[1], Outcome::Wrong(55)));
+        let summary = sum.summary(&outcomes);
+        assert!(summary.starts_with("Case 1 (the numbers 1 to 10): passed\n\
+            Case 2 (negative numbers): failed: expected r0 = -75, got 55\n"));
+        assert!(summary.contains("1 of 3 cases passed. Hints:\n  - Keep a running total"));
+    }
+
+    #[test]
+    fn test_solution_that_never_ends() {
+        let outcomes = check(find("count-bits").unwrap(), "loop: ADD r0, r0, #1\nB loop");
+        assert!(matches!(&outcomes[0], Outcome::Stopped(errors) if errors[0].code == crate::diagnostics::Code::StepLimit));
+    }
+}
@@ -0,0 +1,301 @@
+//! Expressions over the machine state, such as `r2 > 100 && Z` or `[count] * 4`.
+//!
+//! Names are registers, flags (1 when set) and data labels (their address), looked
+//! up through a [`Context`] when the expression is evaluated; `[address]` reads a
+//! memory word. Operators follow C precedence, and comparisons and `&&`, `||` and
+//! `!` give 1 or 0. Numbers are decimal or `0x` hex, optionally prefixed with `#`.
+
+use std::fmt;
+
+/// Where the values of names and memory words come from.
+pub trait Context {
+    /// The value of a register, flag or label, or `None` if there is no such name.
+    fn value(&self, name: &str) -> Option<i64>;
+    /// The memory word at `address`, or `None` if it is outside memory.
+    fn word(&self, address: i64) -> Option<i64>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOp { Mul, Div, Rem, Add, Sub, Shl, Shr, Lt, Le, Gt, Ge, Eq, Ne, And, Xor, Or, LogicalAnd, LogicalOr }
+
+impl BinaryOp {
+    // Binding strength, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::And => 5,
+            BinaryOp::Xor => 4,
+            BinaryOp::Or => 3,
+            BinaryOp::LogicalAnd => 2,
+            BinaryOp::LogicalOr => 1,
+        }
+    }
+}
+
+// Longest operators first, so "<=" is not read as "<"
+const OPERATORS: &[(&str, BinaryOp)] = &[
+    ("<<", BinaryOp::Shl), (">>", BinaryOp::Shr), ("<=", BinaryOp::Le), (">=", BinaryOp::Ge),
+    ("==", BinaryOp::Eq), ("!=", BinaryOp::Ne), ("&&", BinaryOp::LogicalAnd), ("||", BinaryOp::LogicalOr),
+    ("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem), ("+", BinaryOp::Add), ("-", BinaryOp::Sub),
+    ("<", BinaryOp::Lt), (">", BinaryOp::Gt), ("&", BinaryOp::And), ("^", BinaryOp::Xor), ("|", BinaryOp::Or),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Name(String),
+    // A memory word
+    Word(Box<Node>),
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Complement(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, kept with its text for messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+}
+
+impl Expression {
+    /// Parses `text`, describing the first problem on failure.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { text: text.trim(), position: 0 };
+        let root = parser.expression(0)?;
+        parser.skip_whitespace();
+        if !parser.rest().is_empty() {
+            return Err(format!("unexpected '{}' in '{}'", parser.rest(), parser.text));
+        }
+        Ok(Expression { text: parser.text.to_string(), root })
+    }
+
+    /// Evaluates the expression, or describes why it cannot be.
+    pub fn evaluate(&self, context: &dyn Context) -> Result<i64, String> {
+        evaluate(&self.root, context)
+    }
+
+    /// The names the expression refers to, in order and with repeats.
+    pub fn names(&self) -> Vec<&str> {
+        fn collect<'a>(node: &'a Node, names: &mut Vec<&'a str>) {
+            match node {
+                Node::Number(_) => {},
+                Node::Name(name) => names.push(name),
+                Node::Word(operand) | Node::Negate(operand) | Node::Not(operand) | Node::Complement(operand) => collect(operand, names),
+                Node::Binary(_, left, right) => {
+                    collect(left, names);
+                    collect(right, names);
+                },
+            }
+        }
+        let mut names = Vec::new();
+        collect(&self.root, &mut names);
+        names
+    }
+
+    /// Whether the expression is non-zero, as a condition.
+    pub fn holds(&self, context: &dyn Context) -> Result<bool, String> {
+        self.evaluate(context).map(|value| value != 0)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn evaluate(node: &Node, context: &dyn Context) -> Result<i64, String> {
+    Ok(match node {
+        Node::Number(value) => *value,
+        Node::Name(name) => context.value(name).ok_or_else(|| format!("unknown name '{}'", name))?,
+        Node::Word(address) => {
+            let address = evaluate(address, context)?;
+            context.word(address).ok_or_else(|| format!("address {} is outside memory", address))?
+        },
+        Node::Negate(operand) => evaluate(operand, context)?.wrapping_neg(),
+        Node::Not(operand) => (evaluate(operand, context)? == 0) as i64,
+        Node::Complement(operand) => !evaluate(operand, context)?,
+        // Short-circuits, so `r1 != 0 && [r1] > 3` does not read address 0 needlessly
+        Node::Binary(BinaryOp::LogicalAnd, left, right) => (evaluate(left, context)? != 0 && evaluate(right, context)? != 0) as i64,
+        Node::Binary(BinaryOp::LogicalOr, left, right) => (evaluate(left, context)? != 0 || evaluate(right, context)? != 0) as i64,
+        Node::Binary(op, left, right) => {
+            let (a, b) = (evaluate(left, context)?, evaluate(right, context)?);
+            match op {
+                BinaryOp::Mul => a.wrapping_mul(b),
+                BinaryOp::Div | BinaryOp::Rem if b == 0 => return Err("division by zero".to_string()),
+                BinaryOp::Div => a.wrapping_div(b),
+                BinaryOp::Rem => a.wrapping_rem(b),
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Shl => a.wrapping_shl(b as u32),
+                BinaryOp::Shr => a.wrapping_shr(b as u32),
+                BinaryOp::Lt => (a < b) as i64,
+                BinaryOp::Le => (a <= b) as i64,
+                BinaryOp::Gt => (a > b) as i64,
+                BinaryOp::Ge => (a >= b) as i64,
+                BinaryOp::Eq => (a == b) as i64,
+                BinaryOp::Ne => (a != b) as i64,
+                BinaryOp::And => a & b,
+                BinaryOp::Xor => a ^ b,
+                BinaryOp::Or => a | b,
+                BinaryOp::LogicalAnd | BinaryOp::LogicalOr => unreachable!("handled above"),
+            }
+        },
+    })
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.position = self.text.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.rest().starts_with(token);
+        if found {
+            self.position += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}' in '{}'", token, self.text))
+        }
+    }
+
+    // Binary operators binding tighter than `min_precedence`, by precedence climbing
+    fn expression(&mut self, min_precedence: u8) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let Some(&(token, op)) = OPERATORS.iter().find(|(token, _)| self.rest().starts_with(token)) else {
+                return Ok(left);
+            };
+            if op.precedence() <= min_precedence {
+                return Ok(left);
+            }
+            self.position += token.len();
+            let right = self.expression(op.precedence())?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Ok(Node::Complement(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let inner = self.expression(0)?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        if self.eat("[") {
+            let address = self.expression(0)?;
+            self.expect("]")?;
+            return Ok(Node::Word(Box::new(address)));
+        }
+        self.eat("#");
+        let word_len = self.rest().find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(self.rest().len());
+        let word = &self.rest()[..word_len];
+        if word.is_empty() {
+            return Err(match self.rest().chars().next() {
+                Some(c) => format!("unexpected '{}' in '{}'", c, self.text),
+                None => format!("'{}' ends too early", self.text),
+            });
+        }
+        self.position += word_len;
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse(),
+            };
+            return value.map(Node::Number).map_err(|_| format!("invalid number '{}'", word));
+        }
+        Ok(Node::Name(word.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Values;
+
+    impl Context for Values {
+        fn value(&self, name: &str) -> Option<i64> {
+            match name {
+                "r2" => Some(150),
+                "Z" => Some(1),
+                "count" => Some(4),
+                _ => None,
+            }
+        }
+
+        fn word(&self, address: i64) -> Option<i64> {
+            (address == 4).then_some(-7)
+        }
+    }
+
+    fn eval(text: &str) -> Result<i64, String> {
+        Expression::parse(text)?.evaluate(&Values)
+    }
+
+    #[test]
+    fn test_names_and_memory() {
+        assert_eq!(eval("r2 > 100"), Ok(1));
+        assert_eq!(eval("r2 > 100 && !Z"), Ok(0));
+        assert_eq!(eval("[count] + #0x10"), Ok(9));
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3 == 7 || 0"), Ok(1));
+        assert_eq!(eval("(1 + 2) * -3"), Ok(-9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 4 | 1"), Ok(17));
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // The right side is not evaluated once the left decides
+        assert_eq!(eval("Z || [0]"), Ok(1));
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        assert_eq!(eval("r9 > 1"), Err("unknown name 'r9'".to_string()));
+        assert_eq!(eval("[0]"), Err("address 0 is outside memory".to_string()));
+        assert_eq!(eval("r2 / (Z - 1)"), Err("division by zero".to_string()));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(Expression::parse("r2 >"), Err("'r2 >' ends too early".to_string()));
+        assert_eq!(Expression::parse("(r2"), Err("expected ')' in '(r2'".to_string()));
+        assert_eq!(Expression::parse("r2 r3"), Err("unexpected 'r3' in 'r2 r3'".to_string()));
+    }
+}
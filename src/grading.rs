@@ -0,0 +1,841 @@
+//! Grading submissions against a rubric, for `asm grade --rubric rubric.toml submissions/`.
+//!
+//! A rubric is a TOML file of test cases. Each sets up registers, memory and
+//! program input, and lists the registers, memory words and output expected
+//! when the program ends:
+//!
+//! ```toml
+//! isa = "arm"           # the default
+//! max-steps = 100000    # for every case, unless one sets its own
+//! max-output = 4096     # bytes, likewise
+//!
+//! [[case]]
+//! name = "sum of 1 to 4"
+//! points = 2
+//! registers = { r1 = 512 }
+//! memory = { 512 = [1, 2, 3, 4] }   # consecutive words from address 512
+//! input = "4\n"
+//! expect = { registers = { r0 = 10 }, memory = { 600 = 10 }, output = "r0 = 10\n" }
+//! ```
+//!
+//! Every run gets a fresh interpreter and is stopped after `max-steps`
+//! instructions or `max-output` bytes of output, and the interpreter panicking
+//! fails only the case it panicked in, so one pathological submission cannot
+//! hold up or end the batch. Memory needs no limit: it is a fixed 1024 words.
+//!
+//! A case earns its points when everything expected matches, and none otherwise.
+//! Values are compared as bit patterns of the register or word, so `-1` and
+//! `0xFFFFFFFF` both match a 32-bit -1.
+//!
+//! So that answers cannot be copied or hard-coded, a case can draw inputs at
+//! random and compare the results with those of a reference solution run on the
+//! same inputs. The inputs follow from the student's name and the rubric's `seed`,
+//! so a student gets the same ones on every run:
+//!
+//! ```toml
+//! reference = "reference.s"   # relative to the rubric
+//! seed = 2024
+//!
+//! [[case]]
+//! registers = { r1 = 512 }
+//! random = { registers = { r2 = [1, 100] }, memory = { 512 = { words = 10, min = -100, max = 100 } } }
+//! compare = { registers = ["r0"], memory = { 600 = 1 }, output = true }
+//! ```
+
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use serde_json::json;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::html;
+use crate::interpreter::{FinalState, RunOptions, MEMORY_SIZE};
+use crate::isa::{self, FrontEnd};
+
+/// Steps a case may take when the rubric does not say.
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+/// Bytes a case may print when the rubric does not say.
+pub const DEFAULT_MAX_OUTPUT: usize = 64 * 1024;
+
+const RUBRIC_KEYS: &str = "isa, max-steps, max-output, reference, seed and case";
+const CASE_KEYS: &str = "name, points, registers, memory, input, max-steps, max-output, expect, random and compare";
+const EXPECT_KEYS: &str = "registers, memory and output";
+
+/// Register and memory values, set before a case or expected after it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    /// (register index, value)
+    pub registers: Vec<(usize, i64)>,
+    /// (address, value)
+    pub memory: Vec<(usize, i64)>,
+}
+
+/// Inputs drawn for each student from inclusive ranges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Random {
+    /// (register index, min, max)
+    pub registers: Vec<(usize, i64, i64)>,
+    /// (first address, words, min, max)
+    pub memory: Vec<(usize, usize, i64, i64)>,
+}
+
+/// What must match the reference solution run on the same inputs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Compare {
+    /// Register indexes
+    pub registers: Vec<usize>,
+    /// (first address, words)
+    pub memory: Vec<(usize, usize)>,
+    pub output: bool,
+}
+
+/// How far a run may go before it is stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub steps: u64,
+    /// Bytes of output
+    pub output: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { steps: DEFAULT_MAX_STEPS, output: DEFAULT_MAX_OUTPUT }
+    }
+}
+
+pub struct TestCase {
+    pub name: String,
+    pub points: u32,
+    pub initial: State,
+    /// What the program reads as input
+    pub input: String,
+    pub limits: Limits,
+    pub expected: State,
+    pub output: Option<String>,
+    pub random: Random,
+    pub compare: Compare,
+}
+
+pub struct Rubric {
+    pub front_end: &'static dyn FrontEnd,
+    pub cases: Vec<TestCase>,
+    /// The `(file name, contents)` of the reference solution, if there is one
+    pub reference: Vec<(String, String)>,
+    /// Mixed with each student's name to draw their random inputs
+    pub seed: u64,
+}
+
+/// How a submission did in one case.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaseResult {
+    pub name: String,
+    pub points: u32,
+    pub earned: u32,
+    /// What did not match, or the error that stopped the program
+    pub failures: Vec<String>,
+}
+
+/// The results of one student.
+pub struct Report {
+    pub student: String,
+    pub cases: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn score(&self) -> u32 {
+        self.cases.iter().map(|case| case.earned).sum()
+    }
+
+    pub fn max(&self) -> u32 {
+        self.cases.iter().map(|case| case.points).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// A row per student with the points of each case, the score and the maximum
+    Csv,
+    /// Every case of every student, with what failed
+    Json,
+    /// A page with a badge per case for each student and what failed, to share with students
+    Html,
+}
+
+impl Rubric {
+    /// Parses the TOML `text` of a rubric in `directory`, describing the first problem on failure.
+    pub fn parse(text: &str, directory: &Path) -> Result<Self, String> {
+        let rubric = Rubric::parse_cases(text, directory)?;
+        if rubric.reference.is_empty()
+            && let Some(case) = rubric.cases.iter().find(|case| case.compare != Compare::default()) {
+            return Err(format!("{} compares with a reference solution, but there is no 'reference'", case.name));
+        }
+        Ok(rubric)
+    }
+
+    /// Parses a rubric like [`Rubric::parse`], but for `asm compare`, which is given
+    /// the reference solution separately.
+    pub fn parse_cases(text: &str, directory: &Path) -> Result<Self, String> {
+        let table: toml::Table = text.parse().map_err(|error: toml::de::Error| match error.span() {
+            Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, error.message()),
+            None => error.message().to_string(),
+        })?;
+        let mut front_end = isa::FRONT_ENDS[0];
+        let mut limits = Limits::default();
+        let (mut reference, mut seed) = (Vec::new(), 0);
+        for (key, value) in &table {
+            match key.as_str() {
+                "isa" => {
+                    let name = value.as_str().ok_or("'isa' must be a string")?;
+                    front_end = isa::front_end(name).ok_or_else(|| format!("unknown isa '{}'", name))?;
+                },
+                "max-steps" | "max-output" => limit(&mut limits, key, value)?,
+                "reference" => {
+                    let file = value.as_str().ok_or("'reference' must be a file name")?;
+                    let contents = fs::read_to_string(directory.join(file))
+                        .map_err(|error| format!("cannot read the reference solution {}: {}", file, error))?;
+                    reference = vec![(file.to_string(), contents)];
+                },
+                "seed" => seed = value.as_integer().ok_or("'seed' must be a number")? as u64,
+                "case" => {},
+                _ => return Err(format!("unknown setting '{}', expected {}", key, RUBRIC_KEYS)),
+            }
+        }
+        let cases = match table.get("case") {
+            Some(cases) => cases.as_array().ok_or("'case' must be a list of tables, written [[case]]")?.iter().enumerate()
+                .map(|(index, case)| {
+                    let name = format!("case {}", index + 1);
+                    let case = case.as_table().ok_or_else(|| format!("{} must be a table", name))?;
+                    parse_case(case, name.clone(), front_end, limits).map_err(|message| format!("{}: {}", name, message))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        if cases.is_empty() {
+            return Err("the rubric has no [[case]]".to_string());
+        }
+        Ok(Rubric { front_end, cases, reference, seed })
+    }
+
+    /// Reads the rubric at `path`.
+    pub fn load(path: &Path) -> Result<Self, Box<Diagnostic>> {
+        Rubric::read(path, Rubric::parse)
+    }
+
+    /// Reads the cases at `path` for `asm compare`.
+    pub fn load_cases(path: &Path) -> Result<Self, Box<Diagnostic>> {
+        Rubric::read(path, Rubric::parse_cases)
+    }
+
+    fn read(path: &Path, parse: fn(&str, &Path) -> Result<Self, String>) -> Result<Self, Box<Diagnostic>> {
+        let invalid = |message: String| Box::new(Diagnostic::error(Code::InvalidRubric,
+            format!("Invalid rubric {}: {}", path.display(), message)));
+        let text = fs::read_to_string(path).map_err(|error| invalid(error.to_string()))?;
+        parse(&text, path.parent().unwrap_or(Path::new(""))).map_err(invalid)
+    }
+
+    /// Runs the program of `student` in `sources` for every case. A submission
+    /// without files fails them all. Fails if the reference solution does.
+    pub fn grade(&self, student: &str, sources: &[(String, String)]) -> Result<Vec<CaseResult>, Box<Diagnostic>> {
+        let names = self.front_end.registers().names;
+        self.cases.iter().enumerate().map(|(index, case)| {
+            let (initial, drawn) = self.inputs(student, index, case);
+            let (mut expected, mut output) = (case.expected.clone(), case.output.clone());
+            if case.compare != Compare::default() {
+                let (state, printed) = self.execute(case, &initial, &self.reference).map_err(|failures| Box::new(Diagnostic::error(Code::InvalidRubric,
+                    format!("The reference solution {} in {}", failures[0], case.name))))?;
+                expected.registers.extend(case.compare.registers.iter().map(|&register| (register, state.register(names[register]).unwrap_or(0))));
+                for &(start, words) in &case.compare.memory {
+                    expected.memory.extend((start..start + words).map(|address| (address, state.memory[address] as i64)));
+                }
+                if case.compare.output {
+                    output = Some(printed);
+                }
+            }
+
+            let mut failures = if sources.is_empty() {
+                vec!["no .s files were submitted".to_string()]
+            } else {
+                match self.execute(case, &initial, sources) {
+                    Ok((state, printed)) => self.mismatches(&state, &printed, &expected, output.as_deref()),
+                    Err(failures) => failures,
+                }
+            };
+            if !failures.is_empty() && !drawn.is_empty() {
+                failures.push(format!("with the random inputs {}", drawn.join(", ")));
+            }
+            let earned = if failures.is_empty() { case.points } else { 0 };
+            Ok(CaseResult { name: case.name.clone(), points: case.points, earned, failures })
+        }).collect()
+    }
+
+    /// Runs `program` and `reference` on the same inputs in every case, for `asm compare`,
+    /// listing where their final registers, memory and output differ. The random inputs
+    /// follow from the name of the program's first file.
+    pub fn compare(&self, program: &[(String, String)], reference: &[(String, String)]) -> Vec<CaseResult> {
+        let name = program.first().map_or("", |(file, _)| file.as_str());
+        self.cases.iter().enumerate().map(|(index, case)| {
+            let (initial, drawn) = self.inputs(name, index, case);
+            let mut failures = match (self.execute(case, &initial, program), self.execute(case, &initial, reference)) {
+                (Ok(ran), Ok(expected)) => self.divergences(case, &ran, &expected),
+                (Err(stopped), Err(expected)) if stopped == expected => Vec::new(),
+                (Err(stopped), Err(expected)) => vec![format!("{}, the reference {}", stopped[0], expected[0])],
+                (Err(stopped), Ok(_)) => vec![format!("{}, the reference did not", stopped[0])],
+                (Ok(_), Err(expected)) => vec![format!("the reference {}, the program did not", expected[0])],
+            };
+            if !failures.is_empty() && !drawn.is_empty() {
+                failures.push(format!("with the random inputs {}", drawn.join(", ")));
+            }
+            let earned = if failures.is_empty() { case.points } else { 0 };
+            CaseResult { name: case.name.clone(), points: case.points, earned, failures }
+        }).collect()
+    }
+
+    // The registers and memory a case sets, with the random inputs drawn for `student`,
+    // and those inputs described for a failure
+    fn inputs(&self, student: &str, index: usize, case: &TestCase) -> (State, Vec<String>) {
+        let names = self.front_end.registers().names;
+        let mut initial = case.initial.clone();
+        let mut drawn = Vec::new();
+        let mut rng = Rng::new(self.seed, student, index);
+        for &(register, min, max) in &case.random.registers {
+            let value = rng.between(min, max);
+            initial.registers.push((register, value));
+            drawn.push(format!("{} = {}", names[register], value));
+        }
+        for &(start, words, min, max) in &case.random.memory {
+            for address in start..start + words {
+                let value = rng.between(min, max);
+                initial.memory.push((address, value));
+                drawn.push(format!("[{}] = {}", address, value));
+            }
+        }
+        (initial, drawn)
+    }
+
+    // Where a run and the reference's differ: what the case's `compare` names, or else
+    // every register but those holding addresses in the program, the words at labels
+    // both define, every word past both programs' data and the output
+    fn divergences(&self, case: &TestCase, (state, output): &(FinalState, String), (reference, expected_output): &(FinalState, String)) -> Vec<String> {
+        let names = self.front_end.registers().names;
+        let mask = u64::MAX >> (64 - self.front_end.registers().bits);
+        let everything = case.compare == Compare::default();
+        let registers: Vec<usize> = if everything {
+            let layout = self.front_end.layout_registers();
+            (0..names.len()).filter(|reg| !layout.contains(reg)).collect()
+        } else {
+            case.compare.registers.clone()
+        };
+        let mut words: Vec<(String, usize, usize)> = Vec::new();
+        if everything {
+            let mut labels: Vec<&String> = state.labels.keys().filter(|label| reference.labels.contains_key(*label)).collect();
+            labels.sort();
+            words.extend(labels.into_iter().map(|label| (label.clone(), state.labels[label], reference.labels[label])));
+            words.extend((state.data_end.max(reference.data_end)..MEMORY_SIZE).map(|address| (format!("[{}]", address), address, address)));
+        } else {
+            for &(start, count) in &case.compare.memory {
+                words.extend((start..start + count).map(|address| (format!("[{}]", address), address, address)));
+            }
+        }
+
+        let mut failures = Vec::new();
+        for index in registers {
+            let (actual, expected) = (state.register(names[index]).unwrap_or(0), reference.register(names[index]).unwrap_or(0));
+            if (actual ^ expected) as u64 & mask != 0 {
+                failures.push(format!("{} = {}, the reference has {}", names[index], actual, expected));
+            }
+        }
+        for (name, address, expected_address) in words {
+            let (actual, expected) = (state.memory[address], reference.memory[expected_address]);
+            if actual != expected {
+                failures.push(format!("{} = {}, the reference has {}", name, actual, expected));
+            }
+        }
+        if (everything || case.compare.output) && output != expected_output {
+            failures.push(format!("the output was {:?}, the reference printed {:?}", output, expected_output));
+        }
+        failures
+    }
+
+    // Runs `sources` from `initial` in a fresh interpreter, returning the final
+    // state and the output, or why the run stopped
+    fn execute(&self, case: &TestCase, initial: &State, sources: &[(String, String)]) -> Result<(FinalState, String), Vec<String>> {
+        let options = RunOptions {
+            registers: initial.registers.iter().map(|&(index, value)| (index, value as i32)).collect(),
+            memory: initial.memory.iter().map(|&(address, value)| (address, value as i32)).collect(),
+            max_steps: Some(case.limits.steps),
+            ..RunOptions::default()
+        };
+        let mut output = Limited { output: Vec::new(), limit: case.limits.output };
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.front_end.run_sources(sources, &options, &mut Console::new(&mut io::Cursor::new(case.input.as_bytes()), &mut output))
+        }));
+        match run {
+            Ok(Ok(state)) => Ok((state, String::from_utf8_lossy(&output.output).into_owned())),
+            Ok(Err(errors)) => Err(errors.iter().map(|error| format!("stopped with {}: {}", error.code.id(), error.message)).collect()),
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str));
+                Err(vec![format!("crashed the interpreter: {}", message.unwrap_or("unknown panic"))])
+            },
+        }
+    }
+
+    // What in the final state and output differs from what is expected
+    fn mismatches(&self, state: &FinalState, output: &str, expected: &State, expected_output: Option<&str>) -> Vec<String> {
+        let names = self.front_end.registers().names;
+        let mask = u64::MAX >> (64 - self.front_end.registers().bits);
+        let mut failures = Vec::new();
+        for &(index, expected) in &expected.registers {
+            let actual = state.register(names[index]).unwrap_or(0);
+            if (actual ^ expected) as u64 & mask != 0 {
+                failures.push(format!("{} = {}, expected {}", names[index], actual, expected));
+            }
+        }
+        for &(address, expected) in &expected.memory {
+            let actual = state.memory[address];
+            if actual != expected as i32 {
+                failures.push(format!("[{}] = {}, expected {}", address, actual, expected));
+            }
+        }
+        if let Some(expected) = expected_output
+            && output != expected {
+            failures.push(format!("the output was {:?}, expected {:?}", output, expected));
+        }
+        failures
+    }
+}
+
+// Collects program output, failing once it would exceed `limit` bytes
+pub(crate) struct Limited {
+    pub(crate) output: Vec<u8>,
+    pub(crate) limit: usize,
+}
+
+impl Write for Limited {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.output.len() + bytes.len() > self.limit {
+            return Err(io::Error::other(format!("the output exceeds the limit of {} bytes", self.limit)));
+        }
+        self.output.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// xorshift64, seeded from the rubric, the student and the case, so inputs reproduce
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64, student: &str, case: usize) -> Self {
+        // FNV-1a over the name
+        let name = student.bytes().fold(0xCBF2_9CE4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3));
+        let mut rng = Rng((name ^ seed.rotate_left(32) ^ (case as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1);
+        // Spread the seed before the first value
+        for _ in 0..4 {
+            rng.next();
+        }
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn between(&mut self, min: i64, max: i64) -> i64 {
+        min + (self.next() % ((max - min) as u64 + 1)) as i64
+    }
+}
+
+fn parse_case(case: &toml::Table, default_name: String, front_end: &dyn FrontEnd, limits: Limits) -> Result<TestCase, String> {
+    let mut test = TestCase {
+        name: default_name, points: 1, initial: State::default(), input: String::new(),
+        limits, expected: State::default(), output: None, random: Random::default(), compare: Compare::default(),
+    };
+    for (key, value) in case {
+        let string = || value.as_str().map(str::to_string).ok_or_else(|| format!("'{}' must be a string", key));
+        match key.as_str() {
+            "name" => test.name = string()?,
+            "points" => test.points = value.as_integer().and_then(|points| u32::try_from(points).ok())
+                .ok_or("'points' must be a number of at least 0")?,
+            "registers" => test.initial.registers = registers(value, front_end)?,
+            "memory" => test.initial.memory = memory(value)?,
+            "input" => test.input = string()?,
+            "max-steps" | "max-output" => limit(&mut test.limits, key, value)?,
+            "expect" => {
+                let expect = value.as_table().ok_or("'expect' must be a table")?;
+                for (key, value) in expect {
+                    match key.as_str() {
+                        "registers" => test.expected.registers = registers(value, front_end)?,
+                        "memory" => test.expected.memory = memory(value)?,
+                        "output" => test.output = Some(value.as_str().ok_or("'output' must be a string")?.to_string()),
+                        _ => return Err(format!("unknown expectation '{}', expected {}", key, EXPECT_KEYS)),
+                    }
+                }
+            },
+            "random" => test.random = random(value, front_end)?,
+            "compare" => test.compare = compare(value, front_end)?,
+            _ => return Err(format!("unknown setting '{}', expected {}", key, CASE_KEYS)),
+        }
+    }
+    Ok(test)
+}
+
+// { registers = { r2 = [1, 100] }, memory = { 512 = { words = 10, min = -100, max = 100 } } }
+fn random(value: &toml::Value, front_end: &dyn FrontEnd) -> Result<Random, String> {
+    let table = value.as_table().ok_or("'random' must be a table")?;
+    let range = |value: &toml::Value, what: &str| match value.as_array().map(Vec::as_slice) {
+        Some([min, max]) => match (word(min), word(max)) {
+            (Some(min), Some(max)) if min <= max => Ok((min, max)),
+            _ => Err(format!("the range of {} must be two 32-bit numbers, the smaller first", what)),
+        },
+        _ => Err(format!("the range of {} must be [min, max]", what)),
+    };
+    let mut random = Random::default();
+    for (key, value) in table {
+        match key.as_str() {
+            "registers" => {
+                let ranges = value.as_table().ok_or("'random.registers' must be a table, such as { r2 = [1, 100] }")?;
+                for (name, value) in ranges {
+                    let (min, max) = range(value, name)?;
+                    random.registers.push((register_index(name, front_end)?, min, max));
+                }
+            },
+            "memory" => {
+                let blocks = value.as_table().ok_or("'random.memory' must be a table, such as { 512 = { words = 10, min = 0, max = 9 } }")?;
+                for (address, block) in blocks {
+                    let start = address_of(address)?;
+                    let block = block.as_table().ok_or_else(|| format!("the random words at {} must be {{ words = N, min = A, max = B }}", address))?;
+                    let words = block.get("words").and_then(toml::Value::as_integer).and_then(|words| usize::try_from(words).ok())
+                        .unwrap_or(1);
+                    let bound = |key: &str| block.get(key).and_then(word)
+                        .ok_or_else(|| format!("the random words at {} need a 32-bit '{}'", address, key));
+                    let (min, max) = (bound("min")?, bound("max")?);
+                    if min > max {
+                        return Err(format!("the random words at {} have a 'min' above their 'max'", address));
+                    }
+                    if start + words > MEMORY_SIZE {
+                        return Err(format!("address {} is outside memory (0 to {})", start + words - 1, MEMORY_SIZE - 1));
+                    }
+                    random.memory.push((start, words, min, max));
+                }
+            },
+            _ => return Err(format!("unknown random input '{}', expected registers and memory", key)),
+        }
+    }
+    Ok(random)
+}
+
+// { registers = ["r0"], memory = { 600 = 2 }, output = true }
+fn compare(value: &toml::Value, front_end: &dyn FrontEnd) -> Result<Compare, String> {
+    let table = value.as_table().ok_or("'compare' must be a table")?;
+    let mut compare = Compare::default();
+    for (key, value) in table {
+        match key.as_str() {
+            "registers" => compare.registers = value.as_array().and_then(|names| names.iter().map(toml::Value::as_str).collect::<Option<Vec<_>>>())
+                .ok_or("'compare.registers' must be a list of register names")?
+                .into_iter().map(|name| register_index(name, front_end)).collect::<Result<_, _>>()?,
+            "memory" => {
+                let blocks = value.as_table().ok_or("'compare.memory' must be a table of addresses and word counts, such as { 600 = 2 }")?;
+                for (address, words) in blocks {
+                    let start = address_of(address)?;
+                    let words = words.as_integer().and_then(|words| usize::try_from(words).ok())
+                        .ok_or_else(|| format!("the number of words at {} must be a number", address))?;
+                    if start + words > MEMORY_SIZE {
+                        return Err(format!("address {} is outside memory (0 to {})", start + words - 1, MEMORY_SIZE - 1));
+                    }
+                    compare.memory.push((start, words));
+                }
+            },
+            "output" => compare.output = value.as_bool().ok_or("'compare.output' must be true or false")?,
+            _ => return Err(format!("unknown comparison '{}', expected registers, memory and output", key)),
+        }
+    }
+    Ok(compare)
+}
+
+fn register_index(name: &str, front_end: &dyn FrontEnd) -> Result<usize, String> {
+    front_end.registers().names.iter().position(|register| register.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown register '{}' for --isa {}", name, front_end.name()))
+}
+
+fn address_of(address: &str) -> Result<usize, String> {
+    address.parse().ok().filter(|&address| address < MEMORY_SIZE).ok_or_else(|| format!("invalid memory address '{}'", address))
+}
+
+fn limit(limits: &mut Limits, key: &str, value: &toml::Value) -> Result<(), String> {
+    let limit = value.as_integer().filter(|&limit| limit > 0).ok_or_else(|| format!("'{}' must be a positive number", key))?;
+    match key {
+        "max-steps" => limits.steps = limit as u64,
+        _ => limits.output = usize::try_from(limit).unwrap_or(usize::MAX),
+    }
+    Ok(())
+}
+
+// A value a 32-bit word holds, read as signed or unsigned
+fn word(value: &toml::Value) -> Option<i64> {
+    value.as_integer().filter(|&value| value >= i32::MIN as i64 && value <= u32::MAX as i64)
+}
+
+// A table of register names and values, e.g. { r0 = 5, r1 = -1 }
+fn registers(value: &toml::Value, front_end: &dyn FrontEnd) -> Result<Vec<(usize, i64)>, String> {
+    let table = value.as_table().ok_or("'registers' must be a table, such as { r0 = 5 }")?;
+    table.iter().map(|(name, value)| {
+        let index = register_index(name, front_end)?;
+        let value = word(value).ok_or_else(|| format!("the value of {} must be a 32-bit number", name))?;
+        Ok((index, value))
+    }).collect()
+}
+
+// A table of addresses and a word or the list of words starting there, e.g. { 512 = [1, 2] }
+fn memory(value: &toml::Value) -> Result<Vec<(usize, i64)>, String> {
+    let table = value.as_table().ok_or("'memory' must be a table, such as { 512 = [1, 2] }")?;
+    let mut words = Vec::new();
+    for (address, value) in table {
+        let start = address_of(address)?;
+        let values = match value.as_array() {
+            Some(values) => values.iter().collect(),
+            None => vec![value],
+        };
+        for (offset, value) in values.into_iter().enumerate() {
+            if start + offset >= MEMORY_SIZE {
+                return Err(format!("address {} is outside memory (0 to {})", start + offset, MEMORY_SIZE - 1));
+            }
+            let value = word(value).ok_or_else(|| format!("the value at {} must be a 32-bit number", start + offset))?;
+            words.push((start + offset, value));
+        }
+    }
+    Ok(words)
+}
+
+/// Grades `submissions` of (student, sources) on up to `jobs` threads, each run
+/// in its own interpreter, and returns the reports in the order of `submissions`.
+pub fn grade_all(rubric: &Rubric, submissions: &[(String, Vec<(String, String)>)], jobs: usize) -> Result<Vec<Report>, Box<Diagnostic>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..submissions.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, submissions.len().max(1)) {
+            scope.spawn(|| {
+                // Take the next submission until none are left
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((student, sources)) = submissions.get(index) else { break };
+                    let result = rubric.grade(student, sources);
+                    results.lock().expect("no thread panics while holding the results")[index] = Some(result);
+                }
+            });
+        }
+    });
+    results.into_inner().expect("no thread panics while holding the results").into_iter().zip(submissions)
+        .map(|(result, (student, _))| Ok(Report { student: student.clone(), cases: result.expect("every submission is graded")? }))
+        .collect()
+}
+
+/// The submissions in `directory`, by student: each `.s` file is one, and so
+/// is each subdirectory with the `.s` files in it. Sorted by student.
+pub fn submissions(directory: &Path) -> io::Result<Vec<(String, Vec<PathBuf>)>> {
+    let is_source = |path: &Path| path.extension().is_some_and(|extension| extension == "s");
+    let mut submissions = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(&path)?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<_>>()?;
+            files.retain(|file| is_source(file));
+            files.sort();
+            submissions.push((path.file_name().unwrap_or_default().to_string_lossy().into_owned(), files));
+        } else if is_source(&path) {
+            submissions.push((path.file_stem().unwrap_or_default().to_string_lossy().into_owned(), vec![path]));
+        }
+    }
+    submissions.sort();
+    Ok(submissions)
+}
+
+// Quotes a CSV field that needs it
+fn field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The result of `asm compare`: whether each case matched the reference, and how not.
+pub fn comparison(results: &[CaseResult]) -> String {
+    let mut summary = String::new();
+    for (number, result) in results.iter().enumerate() {
+        if result.failures.is_empty() {
+            summary.push_str(&format!("Case {} ({}): same as the reference\n", number + 1, result.name));
+        } else {
+            summary.push_str(&format!("Case {} ({}): differs from the reference\n", number + 1, result.name));
+            for failure in &result.failures {
+                summary.push_str(&format!("  {}\n", failure));
+            }
+        }
+    }
+    let same = results.iter().filter(|result| result.failures.is_empty()).count();
+    summary.push_str(&format!("{} of {} cases match the reference.\n", same, results.len()));
+    summary
+}
+
+/// A header with the case names, then a row per student with the points earned in each case.
+pub fn csv(rubric: &Rubric, reports: &[Report]) -> String {
+    let names: Vec<String> = rubric.cases.iter().map(|case| field(&case.name)).collect();
+    let mut csv = format!("student,{},score,max\n", names.join(","));
+    for report in reports {
+        let earned: Vec<String> = report.cases.iter().map(|case| case.earned.to_string()).collect();
+        csv.push_str(&format!("{},{},{},{}\n", field(&report.student), earned.join(","), report.score(), report.max()));
+    }
+    csv
+}
+
+/// A list with an object per student, holding the score and every case.
+pub fn json(reports: &[Report]) -> String {
+    let reports: Vec<_> = reports.iter().map(|report| json!({
+        "student": report.student,
+        "score": report.score(),
+        "max": report.max(),
+        "cases": report.cases.iter().map(|case| json!({
+            "name": case.name,
+            "points": case.points,
+            "earned": case.earned,
+            "failures": case.failures,
+        })).collect::<Vec<_>>(),
+    })).collect();
+    serde_json::to_string_pretty(&reports).expect("reports are plain JSON") + "\n"
+}
+
+/// A page with a row per student and a badge per case, then the failures of each student.
+pub fn html(rubric: &Rubric, reports: &[Report]) -> String {
+    let names: Vec<String> = rubric.cases.iter().map(|case| format!("<th>{}</th>", html::escape(&case.name))).collect();
+    let mut body = format!("<h1>Grades</h1>\n<table>\n<tr><th>Student</th>{}<th>Score</th></tr>\n", names.concat());
+    for report in reports {
+        let cases: Vec<String> = report.cases.iter()
+            .map(|case| format!("<td>{} {}/{}</td>", html::badge(case.failures.is_empty()), case.earned, case.points)).collect();
+        body.push_str(&format!("<tr><td>{}</td>{}<td>{}/{}</td></tr>\n", html::escape(&report.student), cases.concat(), report.score(), report.max()));
+    }
+    body.push_str("</table>\n");
+    for report in reports.iter().filter(|report| report.cases.iter().any(|case| !case.failures.is_empty())) {
+        body.push_str(&format!("<details>\n<summary>{}</summary>\n<ul>\n", html::escape(&report.student)));
+        for case in report.cases.iter().filter(|case| !case.failures.is_empty()) {
+            let failures: Vec<String> = case.failures.iter().map(|failure| html::escape(failure)).collect();
+            body.push_str(&format!("<li>{}: {}</li>\n", html::escape(&case.name), failures.join("; ")));
+        }
+        body.push_str("</ul>\n</details>\n");
+    }
+    html::page("Grades", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grade() {
+        let rubric = Rubric::parse("max-steps = 1000\n\
+            [[case]]\nname = \"sum, small\"\npoints = 2\nregisters = { r1 = 512 }\nmemory = { 512 = [1, 2, 3, 4] }\n\
+            expect = { registers = { r0 = 10 }, output = \"r0 = 10\\n\" }\n\
+            [[case]]\nregisters = { r1 = 512 }\nmemory = { 512 = [-1, -1, -1, 0xFFFFFFFF] }\n\
+            expect = { registers = { R0 = 0xFFFFFFFC }, memory = { 600 = -4 } }", Path::new("")).unwrap();
+        assert_eq!(rubric.cases[1].name, "case 2");
+        assert_eq!(rubric.cases[1].limits, Limits { steps: 1000, output: DEFAULT_MAX_OUTPUT });
+
+        let source = |text: &str| vec![("main.s".to_string(), text.to_string())];
+        let sum = "MOV r0, #0\nMOV r3, #4\nloop: LDR r2, [r1]\nADD r0, r0, r2\nADD r1, r1, #1\nSUBS r3, r3, #1\nBNE loop\n\
+            MOV r4, #600\nSTR r0, [r4]\nPRINT r0";
+        let results = rubric.grade("ada", &source(sum)).unwrap();
+        assert!(results.iter().all(|result| result.earned == result.points), "{:?}", results);
+
+        let reports = vec![
+            Report { student: "ada".to_string(), cases: results },
+            Report { student: "bob".to_string(), cases: rubric.grade("bob", &source("MOV r0, #10\nPRINT r0")).unwrap() },
+            Report { student: "cy".to_string(), cases: rubric.grade("cy", &source("loop: B loop")).unwrap() },
+        ];
+        assert_eq!(reports[1].cases[1].failures, ["r0 = 10, expected 4294967292", "[600] = 0, expected -4"]);
+        assert_eq!(reports[2].cases[0].failures, ["stopped with E0021: Stopped after 1000 instructions"]);
+        let quiet = Rubric::parse("max-output = 16\n[[case]]\nmax-steps = 100", Path::new("")).unwrap();
+        assert_eq!(quiet.grade("dee", &source("MOV r0, #1\nloop: PRINT r0\nB loop")).unwrap()[0].failures,
+            ["stopped with E0019: Could not write program output: the output exceeds the limit of 16 bytes"]);
+        assert_eq!(csv(&rubric, &reports), "student,\"sum, small\",case 2,score,max\nada,2,1,3,3\nbob,2,0,2,3\ncy,0,0,0,3\n");
+        let json: serde_json::Value = serde_json::from_str(&json(&reports)).unwrap();
+        // On several threads, in the order given
+        let submissions: Vec<_> = [("ada", sum), ("bob", "MOV r0, #10\nPRINT r0"), ("cy", "loop: B loop")].iter()
+            .map(|&(student, text)| (student.to_string(), source(text))).collect();
+        let parallel = grade_all(&rubric, &submissions, 3).unwrap();
+        assert_eq!(csv(&rubric, &parallel), csv(&rubric, &reports));
+        assert_eq!(json[1]["cases"][1]["failures"][1], "[600] = 0, expected -4");
+        let html = html(&rubric, &reports);
+        assert!(html.contains("<tr><td>bob</td><td><span class=\"badge pass\">passed</span> 2/2</td>\
+            <td><span class=\"badge fail\">failed</span> 0/1</td><td>2/3</td></tr>"));
+        assert!(html.contains("<li>case 2: r0 = 10, expected 4294967292; [600] = 0, expected -4</li>"));
+        assert!(!html.contains("<summary>ada</summary>"));
+
+        assert_eq!(Rubric::parse("[[case]]\nregisters = { r99 = 1 }", Path::new("")).err(), Some("case 1: unknown register 'r99' for --isa arm".to_string()));
+        assert_eq!(Rubric::parse("[[case]]\nmemory = { 1023 = [1, 2] }", Path::new("")).err(), Some("case 1: address 1024 is outside memory (0 to 1023)".to_string()));
+        assert!(Rubric::parse("isa = \"arm\"", Path::new("")).err().is_some_and(|error| error.contains("no [[case]]")));
+    }
+
+    #[test]
+    fn test_random_inputs() {
+        let directory = std::env::temp_dir().join(format!("asm-rubric-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        // Sums four words and doubles r2
+        let sum = "MOV r0, #0\nMOV r3, #4\nloop: LDR r4, [r1]\nADD r0, r0, r4\nADD r1, r1, #1\nSUBS r3, r3, #1\nBNE loop\n\
+            ADD r2, r2, r2\nMOV r5, #600\nSTR r2, [r5]\nPRINT r0";
+        fs::write(directory.join("reference.s"), sum).unwrap();
+        let rubric = Rubric::parse("reference = \"reference.s\"\nseed = 7\n\
+            [[case]]\nregisters = { r1 = 512 }\n\
+            random = { registers = { r2 = [1, 1000] }, memory = { 512 = { words = 4, min = -50, max = 50 } } }\n\
+            compare = { registers = [\"r0\"], memory = { 600 = 1 }, output = true }", &directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let source = |text: &str| vec![("main.s".to_string(), text.to_string())];
+        assert_eq!(rubric.grade("ada", &source(sum)).unwrap()[0].earned, 1);
+        assert_eq!(rubric.grade("bob", &source(sum)).unwrap()[0].earned, 1);
+        // Another student's answer, hard-coded, fails
+        let ada = rubric.grade("ada", &source("MOV r0, #1")).unwrap();
+        let bob = rubric.grade("bob", &source("MOV r0, #1")).unwrap();
+        let inputs = |results: &[CaseResult]| results[0].failures.last().unwrap().clone();
+        assert!(inputs(&ada).starts_with("with the random inputs r2 = "));
+        assert_ne!(inputs(&ada), inputs(&bob));
+        assert_eq!(inputs(&ada), inputs(&rubric.grade("ada", &source("MOV r0, #1")).unwrap()));
+
+        let broken = Rubric { reference: source("FOO"), ..rubric };
+        assert_eq!(broken.grade("ada", &source(sum)).unwrap_err().code, Code::InvalidRubric);
+        assert!(Rubric::parse("[[case]]\ncompare = { output = true }", Path::new("")).err()
+            .is_some_and(|error| error.contains("no 'reference'")));
+    }
+
+    #[test]
+    fn test_compare() {
+        let cases = Rubric::parse_cases("[[case]]\nname = \"small\"\nregisters = { r1 = 2 }\n\
+            [[case]]\nname = \"large\"\nregisters = { r1 = 200 }\ncompare = { registers = [\"r0\"] }\n\
+            [[case]]\nrandom = { registers = { r1 = [1, 3] } }", Path::new("")).unwrap();
+        let source = |text: &str| vec![("main.s".to_string(), text.to_string())];
+        // Doubles r1 into r0 and a labelled word, with its data and code laid out differently from the other
+        let reference = source("result: #0\nADD r0, r1, r1\nSTR r0, result\nMOV r2, #700\nSTR r0, [r2]\nPRINT r0");
+        let program = source("pad: #0\nresult: #0\nMOV r2, #700\nMOV r3, #1\nLSL r0, r1, #1\nSTR r0, result\nSTR r0, [r2]\nPRINT r0\nEXIT");
+        let results = cases.compare(&program, &reference);
+        assert_eq!(results[0].failures, ["r3 = 1, the reference has 0"]);
+        // The second case only compares r0
+        assert!(results[1].failures.is_empty());
+        assert_eq!(results[2].failures[0], "r3 = 1, the reference has 0");
+        assert!(results[2].failures[1].starts_with("with the random inputs r1 = "));
+
+        let wrong = cases.compare(&source("result: #0\nMOV r0, r1\nMOV r2, #700\nSTR r0, [r2]\nPRINT r0\nMOV r2, #0"), &reference);
+        assert_eq!(wrong[0].failures, ["r0 = 2, the reference has 4", "r2 = 0, the reference has 700", "result = 0, the reference has 4", "[700] = 2, the reference has 4",
+            "the output was \"r0 = 2\\n\", the reference printed \"r0 = 4\\n\""]);
+        let stuck = cases.compare(&source("loop: B loop"), &reference);
+        assert!(stuck[1].failures[0].starts_with("stopped with E0032") && stuck[1].failures[0].ends_with(", the reference did not"));
+        assert_eq!(comparison(&results[..2]), "Case 1 (small): differs from the reference\n  r3 = 1, the reference has 0\n\
+            Case 2 (large): same as the reference\n1 of 2 cases match the reference.\n");
+    }
+}
@@ -0,0 +1,294 @@
+//! Memory access counts, and the heatmap `--heatmap` draws from them.
+//!
+//! Every load and store a program makes is counted per memory word. After the
+//! run, the counts are written as a text grid, a CSV table or a PNG image,
+//! chosen by the file extension, so locality can be discussed with the data of
+//! a real program. Brightness follows the logarithm of the number of accesses,
+//! so a hot loop counter does not wash out everything else. With a memory map
+//! only the words of its regions are drawn, each region starting a new row.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops::Range;
+use std::path::Path;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic};
+
+// Words per row of the text grid and the image
+const ROW_WORDS: usize = 32;
+// Pixels per side of a word in the image
+const CELL_PIXELS: usize = 8;
+// Characters for untouched words, then for increasing access counts
+const SHADES: &[u8] = b" .:-=+*#%@";
+// Words listed under the text grid
+const HOTTEST_WORDS: usize = 5;
+
+/// The number of reads and writes of each memory word, kept only for the words
+/// accessed, so a large memory costs nothing until used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accesses {
+    // The addresses drawn, in order
+    ranges: Vec<Range<usize>>,
+    // Reads and writes by address
+    counts: HashMap<usize, [u64; 2]>,
+}
+
+impl Accesses {
+    pub fn new(words: usize) -> Self {
+        Accesses::of_ranges(iter::once(0..words).collect())
+    }
+
+    /// Counts accesses to memory of which only `ranges`, such as the regions of a
+    /// memory map, are drawn.
+    pub fn of_ranges(ranges: Vec<Range<usize>>) -> Self {
+        Accesses { ranges, counts: HashMap::new() }
+    }
+
+    /// The words drawn.
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(ExactSizeIterator::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The rows of the grid, as the address of their first word and the addresses
+    // of their words, a range starting a new row
+    fn rows(&self) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+        self.ranges.iter().flat_map(|range| range.clone().step_by(ROW_WORDS)
+            .map(|row| (row, row..(row + ROW_WORDS).min(range.end))))
+    }
+
+    pub fn read(&mut self, address: usize) {
+        self.counts.entry(address).or_default()[0] += 1;
+    }
+
+    pub fn write(&mut self, address: usize) {
+        self.counts.entry(address).or_default()[1] += 1;
+    }
+
+    pub fn reads(&self, address: usize) -> u64 {
+        self.counts.get(&address).map_or(0, |counts| counts[0])
+    }
+
+    pub fn writes(&self, address: usize) -> u64 {
+        self.counts.get(&address).map_or(0, |counts| counts[1])
+    }
+
+    fn total(&self, address: usize) -> u64 {
+        self.reads(address) + self.writes(address)
+    }
+
+    fn max_total(&self) -> u64 {
+        self.counts.values().map(|counts| counts[0] + counts[1]).max().unwrap_or(0)
+    }
+
+    // How hot a word is, from 0 for untouched to 1 for the most accessed (`max` accesses)
+    fn heat(&self, address: usize, max: u64) -> f64 {
+        match self.total(address) {
+            0 => 0.0,
+            _ if max <= 1 => 1.0,
+            // The least accessed words still show
+            count => 0.1 + 0.9 * (count as f64).ln() / (max as f64).ln(),
+        }
+    }
+}
+
+/// Writes the heatmap to `path`: CSV for `.csv`, PNG for `.png`, otherwise text.
+/// `-` prints the text through `console` instead.
+pub fn write(path: &str, accesses: &Accesses, labels: &HashMap<String, usize>, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    if path == "-" {
+        return console.print(format_args!("{}", text(accesses, labels)));
+    }
+    let contents = match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some(extension) if extension.eq_ignore_ascii_case("csv") => csv(accesses, labels).into_bytes(),
+        Some(extension) if extension.eq_ignore_ascii_case("png") => png(accesses),
+        _ => (text(accesses, labels) + "\n").into_bytes(),
+    };
+    fs::write(path, contents).map_err(|error: io::Error| Box::new(Diagnostic::error(Code::OutputFailed,
+        format!("Could not write the heatmap to {}: {}", path, error))))
+}
+
+// Label names by address, several joined with '/'
+fn names(labels: &HashMap<String, usize>) -> HashMap<usize, String> {
+    let mut sorted: Vec<(&String, &usize)> = labels.iter().collect();
+    sorted.sort();
+    let mut names: HashMap<usize, String> = HashMap::new();
+    for (label, &address) in sorted {
+        names.entry(address).and_modify(|name| { name.push('/'); name.push_str(label) }).or_insert_with(|| label.clone());
+    }
+    names
+}
+
+/// A grid of one character per word, followed by the most accessed words.
+pub fn text(accesses: &Accesses, labels: &HashMap<String, usize>) -> String {
+    let reads: u64 = accesses.counts.values().map(|counts| counts[0]).sum();
+    let writes: u64 = accesses.counts.values().map(|counts| counts[1]).sum();
+    let touched = accesses.counts.len();
+    let max = accesses.max_total();
+    let mut text = format!("Memory heatmap: {} words accessed, {} reads, {} writes ({} words per row, from '{}' to '{}')",
+        touched, reads, writes, ROW_WORDS, SHADES[1] as char, SHADES[SHADES.len() - 1] as char);
+    for (row, words) in accesses.rows() {
+        let cells: String = words.map(|address| match accesses.total(address) {
+            0 => SHADES[0] as char,
+            // Equal bands of heat, the hottest band ending at 1
+            _ => SHADES[1 + ((accesses.heat(address, max) * (SHADES.len() - 1) as f64) as usize).min(SHADES.len() - 2)] as char,
+        }).collect();
+        text.push_str(&format!("\n{:>5} |{}|", row, cells));
+    }
+
+    let mut hottest: Vec<usize> = accesses.counts.keys().copied().collect();
+    hottest.sort_by_key(|&address| (std::cmp::Reverse(accesses.total(address)), address));
+    if !hottest.is_empty() {
+        let names = names(labels);
+        text.push_str("\nMost accessed words:");
+        for &address in hottest.iter().take(HOTTEST_WORDS) {
+            let name = names.get(&address).map_or(String::new(), |name| format!(" ({})", name));
+            text.push_str(&format!("\n  [{}]{}: {} reads, {} writes", address, name, accesses.reads(address), accesses.writes(address)));
+        }
+    }
+    text
+}
+
+/// One row per word: its address, labels and counts.
+pub fn csv(accesses: &Accesses, labels: &HashMap<String, usize>) -> String {
+    let names = names(labels);
+    let mut csv = String::from("address,label,reads,writes\n");
+    for address in accesses.ranges.iter().flat_map(Range::clone) {
+        let name = names.get(&address).map_or("", String::as_str);
+        csv.push_str(&format!("{},{},{},{}\n", address, name, accesses.reads(address), accesses.writes(address)));
+    }
+    csv
+}
+
+/// An image with a square per word, from dark gray for untouched words through
+/// red to yellow for the most accessed.
+pub fn png(accesses: &Accesses) -> Vec<u8> {
+    let rows: Vec<Range<usize>> = accesses.rows().map(|(_, words)| words).collect();
+    let (width, height) = (ROW_WORDS * CELL_PIXELS, rows.len() * CELL_PIXELS);
+    let max = accesses.max_total();
+    // Each row starts with filter type 0, no filtering
+    let mut pixels = Vec::with_capacity(height * (1 + width * 3));
+    for y in 0..height {
+        pixels.push(0);
+        let words = &rows[y / CELL_PIXELS];
+        for x in 0..width {
+            let address = words.start + x / CELL_PIXELS;
+            let color = if words.contains(&address) && accesses.total(address) > 0 {
+                let heat = accesses.heat(address, max);
+                [(510.0 * heat).min(255.0) as u8, (510.0 * heat - 255.0).max(0.0) as u8, 0]
+            } else {
+                [32, 32, 32]
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc32(&[&kind[..], data].concat()).to_be_bytes());
+}
+
+// A zlib stream of uncompressed deflate blocks, which every PNG reader accepts
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(u16::MAX as usize).collect();
+    for (index, block) in blocks.iter().enumerate() {
+        stream.push((index == blocks.len() - 1) as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nine reads and a write of count, at 3, and a write of word 40, in 64 words
+    fn counted() -> (Accesses, HashMap<String, usize>) {
+        let mut accesses = Accesses::new(64);
+        for _ in 0..9 {
+            accesses.read(3);
+        }
+        accesses.write(3);
+        accesses.write(40);
+        (accesses, HashMap::from([("count".to_string(), 3)]))
+    }
+
+    #[test]
+    fn test_text() {
+        let (accesses, labels) = counted();
+        let text = text(&accesses, &labels);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Memory heatmap: 2 words accessed, 9 reads, 2 writes (32 words per row, from '.' to '@')");
+        assert_eq!(lines[1], format!("    0 |   @{}|", " ".repeat(28)));
+        assert_eq!(lines[2], format!("   32 |{}.{}|", " ".repeat(8), " ".repeat(23)));
+        assert_eq!(lines[4], "  [3] (count): 9 reads, 1 writes");
+    }
+
+    #[test]
+    fn test_csv() {
+        let (accesses, labels) = counted();
+        let csv = csv(&accesses, &labels);
+        assert_eq!(csv.lines().nth(4), Some("3,count,9,1"));
+        assert_eq!(csv.lines().count(), 65);
+    }
+
+    #[test]
+    fn test_png() {
+        // 256 pixels wide, 16 high
+        let png = png(&counted().0);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\x01\0\0\0\0\x10"));
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+
+    #[test]
+    fn test_ranges() {
+        // Two regions far apart, the second shorter than a row
+        let mut accesses = Accesses::of_ranges(vec![0x100..0x140, 0x8000_0000..0x8000_0004]);
+        accesses.write(0x8000_0001);
+        assert_eq!(accesses.len(), 68);
+        let text = text(&accesses, &HashMap::new());
+        let rows: Vec<&str> = text.lines().skip(1).take(3).collect();
+        assert_eq!(rows, [format!("  256 |{}|", " ".repeat(32)), format!("  288 |{}|", " ".repeat(32)), "2147483648 | @  |".to_string()]);
+        assert_eq!(csv(&accesses, &HashMap::new()).lines().count(), 69);
+        assert!(png(&accesses).starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\x01\0\0\0\0\x18"));
+    }
+}
@@ -0,0 +1,195 @@
+//! A bounded history of register writes.
+//!
+//! Every instruction that changes a register is recorded with the new value, its
+//! source line and the step number, keeping the most recent [`HISTORY_DEPTH`]
+//! writes per register unless `--history-size` says otherwise. `HISTORY r5` in interactive mode lists them, answering
+//! "when did r5 become 0?" without re-running the program under `-v`. The last
+//! [`RECENT_DEPTH`] instructions executed are kept too, with the registers each
+//! wrote, for `RECENT` and the post-mortem shown when a program stops with an error.
+
+use std::collections::VecDeque;
+
+/// How many writes are kept for each register by default.
+pub const HISTORY_DEPTH: usize = 16;
+/// The most writes `--history-size` may keep for each register.
+pub const MAX_HISTORY_DEPTH: usize = 65536;
+/// How many of the last executed instructions are kept.
+pub const RECENT_DEPTH: usize = 64;
+
+/// A value written to a register, and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub value: i64,
+    /// The number of the instruction execution that wrote the value, starting at 1
+    pub step: u64,
+    // Index into `History::files`
+    file: usize,
+    pub line: usize,
+}
+
+/// An executed instruction and its effects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Executed {
+    pub step: u64,
+    // Index into `History::files`
+    file: usize,
+    pub line: usize,
+    /// The registers written, as (register index, new value)
+    pub writes: Vec<(usize, i64)>,
+}
+
+pub struct History {
+    // How many writes to keep for each register
+    depth: usize,
+    // The most recent writes of each register, oldest first
+    writes: Vec<VecDeque<RegisterWrite>>,
+    // The most recently executed instructions, oldest first
+    recent: VecDeque<Executed>,
+    // File names, stored once
+    files: Vec<String>,
+    // Where the instruction being executed comes from
+    file: usize,
+    line: usize,
+    step: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::with_depth(HISTORY_DEPTH)
+    }
+}
+
+impl History {
+    /// A history keeping the last `depth` writes of each register.
+    pub fn with_depth(depth: usize) -> Self {
+        History { depth, writes: Vec::new(), recent: VecDeque::new(), files: Vec::new(), file: 0, line: 0, step: 0 }
+    }
+
+    /// Marks the start of an instruction from `file`:`line`, to which the following writes belong.
+    pub fn begin_step(&mut self, file: &str, line: usize) {
+        self.step += 1;
+        self.line = line;
+        if self.files.get(self.file).is_none_or(|current| current != file) {
+            self.file = match self.files.iter().position(|known| known == file) {
+                Some(index) => index,
+                None => {
+                    self.files.push(file.to_string());
+                    self.files.len() - 1
+                },
+            };
+        }
+        if self.recent.len() == RECENT_DEPTH {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Executed { step: self.step, file: self.file, line: self.line, writes: Vec::new() });
+    }
+
+    pub fn record(&mut self, reg: usize, value: i64) {
+        if reg >= self.writes.len() {
+            self.writes.resize(reg + 1, VecDeque::new());
+        }
+        let writes = &mut self.writes[reg];
+        if writes.len() == self.depth {
+            writes.pop_front();
+        }
+        writes.push_back(RegisterWrite { value, step: self.step, file: self.file, line: self.line });
+        if let Some(executed) = self.recent.back_mut() {
+            executed.writes.push((reg, value));
+        }
+    }
+
+    /// The recorded writes of a register, oldest first.
+    pub fn writes(&self, reg: usize) -> impl Iterator<Item = &RegisterWrite> {
+        self.writes.get(reg).into_iter().flatten()
+    }
+
+    /// The file a write was made from.
+    pub fn file(&self, write: &RegisterWrite) -> &str {
+        &self.files[write.file]
+    }
+
+    /// The last `count` executed instructions (at most [`RECENT_DEPTH`]), oldest first.
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = &Executed> {
+        self.recent.iter().skip(self.recent.len().saturating_sub(count))
+    }
+
+    /// The file an executed instruction comes from.
+    pub fn executed_file(&self, executed: &Executed) -> &str {
+        &self.files[executed.file]
+    }
+
+    /// Lists the recorded writes of a register, one per line and shown with `format`, for `HISTORY`.
+    pub fn describe(&self, name: &str, reg: usize, format: &dyn Fn(i64) -> String) -> String {
+        if self.writes(reg).next().is_none() {
+            return format!("{} has not been written", name);
+        }
+        let mut text = format!("Last writes to {} (oldest first):", name);
+        for write in self.writes(reg) {
+            text.push_str(&format!("\n  step {:>5}  {}:{}  {} = {}", write.step, self.file(write), write.line, name, format(write.value)));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut history = History::default();
+        for value in 0..HISTORY_DEPTH as i64 + 3 {
+            history.begin_step("main.s", value as usize + 1);
+            history.record(1, value);
+        }
+        let writes: Vec<&RegisterWrite> = history.writes(1).collect();
+        assert_eq!(writes.len(), HISTORY_DEPTH);
+        assert_eq!(writes[0].value, 3);
+        assert_eq!(writes[0].step, 4);
+        assert_eq!(writes[0].line, 4);
+        assert_eq!(history.writes(0).count(), 0);
+    }
+
+    #[test]
+    fn test_history_depth() {
+        let mut history = History::with_depth(2);
+        for value in 0..5 {
+            history.begin_step("main.s", 1);
+            history.record(1, value);
+        }
+        let values: Vec<i64> = history.writes(1).map(|write| write.value).collect();
+        assert_eq!(values, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_recent_instructions() {
+        let mut history = History::default();
+        for step in 1..=RECENT_DEPTH + 2 {
+            history.begin_step("main.s", step);
+            if step % 2 == 0 {
+                history.record(0, step as i64);
+                history.record(3, -1);
+            }
+        }
+        let recent: Vec<&Executed> = history.recent(usize::MAX).collect();
+        assert_eq!(recent.len(), RECENT_DEPTH);
+        assert_eq!((recent[0].step, recent[0].line), (3, 3));
+        assert_eq!(recent[0].writes, vec![]);
+        assert_eq!(recent[1].writes, vec![(0, 4), (3, -1)]);
+        let lines: Vec<usize> = history.recent(2).map(|executed| executed.line).collect();
+        assert_eq!(lines, vec![RECENT_DEPTH + 1, RECENT_DEPTH + 2]);
+        assert_eq!(history.executed_file(recent[0]), "main.s");
+    }
+
+    #[test]
+    fn test_history_remembers_files() {
+        let mut history = History::default();
+        history.begin_step("main.s", 3);
+        history.record(0, 7);
+        history.begin_step("lib.s", 1);
+        history.begin_step("main.s", 4);
+        history.record(0, 0);
+        assert_eq!(history.describe("r0", 0, &|value| value.to_string()),
+            "Last writes to r0 (oldest first):\n  step     1  main.s:3  r0 = 7\n  step     3  main.s:4  r0 = 0");
+    }
+}
@@ -0,0 +1,205 @@
+//! Pausing and single-stepping a running program from the keyboard.
+//!
+//! While a file runs in a terminal, space pauses it before the next instruction.
+//! When paused, `s` (or space) runs one instruction and pauses again, and `c`
+//! continues. Keys are read without waiting for Enter, and the terminal is put
+//! back as it was when the run ends.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+use crate::interpreter::{Interrupt, SourceLine};
+
+// Instructions between checks for a key press while running
+const POLL_INTERVAL: u64 = 1024;
+// How long to sleep between checks for a key press while paused
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A source of key presses that does not block.
+pub trait Keyboard {
+    /// The next key pressed, if there is one.
+    fn poll(&mut self) -> Option<u8>;
+}
+
+impl Keyboard for VecDeque<u8> {
+    fn poll(&mut self) -> Option<u8> {
+        self.pop_front()
+    }
+}
+
+pub struct Hotkeys<K: Keyboard> {
+    keyboard: K,
+    paused: bool,
+    steps: u64,
+}
+
+impl Hotkeys<Terminal> {
+    /// Hotkeys on the terminal, or `None` if stdin is not one.
+    pub fn on_terminal() -> Option<Self> {
+        Terminal::new().map(Hotkeys::new)
+    }
+}
+
+impl<K: Keyboard> Hotkeys<K> {
+    pub fn new(keyboard: K) -> Self {
+        Hotkeys { keyboard, paused: false, steps: 0 }
+    }
+
+    /// Pauses before the next instruction, as at a BKPT.
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.paused = true;
+            eprintln!("Breakpoint. Press s or space to step, c to continue.");
+        }
+    }
+
+    /// Called before each instruction. Returns once the instruction at `line` may
+    /// run, waiting while paused unless `interrupt` is requested.
+    pub fn before(&mut self, line: &SourceLine, interrupt: &Interrupt) {
+        self.steps += 1;
+        if !self.paused {
+            if !self.steps.is_multiple_of(POLL_INTERVAL) || self.keyboard.poll() != Some(b' ') {
+                return;
+            }
+            self.paused = true;
+            eprintln!("Paused. Press s or space to step, c to continue.");
+        }
+        eprintln!("{}:{}: {}", line.file, line.line_no, line.text());
+        while !interrupt.is_requested() {
+            match self.keyboard.poll() {
+                Some(b's' | b' ') => return,
+                Some(b'c') => {
+                    self.paused = false;
+                    return;
+                },
+                _ => thread::sleep(WAIT_INTERVAL),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::Terminal;
+
+#[cfg(unix)]
+mod unix {
+    use std::io::{self, IsTerminal};
+    use super::Keyboard;
+
+    /// The terminal on stdin, switched to reading single keys without echoing them.
+    pub struct Terminal {
+        original: libc::termios,
+    }
+
+    impl Terminal {
+        pub fn new() -> Option<Self> {
+            if !io::stdin().is_terminal() {
+                return None;
+            }
+            // SAFETY: termios is plain data, and tcgetattr fills it in before it is used
+            unsafe {
+                let mut original: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                    return None;
+                }
+                let mut keys = original;
+                keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+                // Reads return at once, with or without a key
+                keys.c_cc[libc::VMIN] = 0;
+                keys.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys) != 0 {
+                    return None;
+                }
+                Some(Terminal { original })
+            }
+        }
+    }
+
+    impl Keyboard for Terminal {
+        fn poll(&mut self) -> Option<u8> {
+            let mut key = 0u8;
+            // SAFETY: reads at most one byte into `key`
+            let read = unsafe { libc::read(libc::STDIN_FILENO, (&mut key as *mut u8).cast(), 1) };
+            (read == 1).then_some(key)
+        }
+    }
+
+    impl Drop for Terminal {
+        fn drop(&mut self) {
+            // SAFETY: restores the settings read in `new`
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+            }
+        }
+    }
+}
+
+/// Without termios there is no way to read single keys, so hotkeys are unavailable.
+#[cfg(not(unix))]
+pub struct Terminal;
+
+#[cfg(not(unix))]
+impl Terminal {
+    pub fn new() -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+impl Keyboard for Terminal {
+    fn poll(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hotkeys that get `keys`, run up to the first check for a key press
+    fn at_first_poll(keys: &[u8]) -> Hotkeys<VecDeque<u8>> {
+        let mut hotkeys = Hotkeys::new(VecDeque::from(keys.to_vec()));
+        let line = SourceLine::new("main.s", 1, "ADD r0, r0, #1");
+        for _ in 0..POLL_INTERVAL {
+            hotkeys.before(&line, &Interrupt::default());
+        }
+        hotkeys
+    }
+
+    #[test]
+    fn test_keys_wait_for_the_poll() {
+        let mut hotkeys = Hotkeys::new(VecDeque::from(*b" "));
+        let line = SourceLine::new("main.s", 1, "ADD r0, r0, #1");
+        for _ in 1..POLL_INTERVAL {
+            hotkeys.before(&line, &Interrupt::default());
+        }
+        assert!(!hotkeys.paused);
+        assert_eq!(hotkeys.keyboard.len(), 1);
+    }
+
+    #[test]
+    fn test_space_pauses_and_s_steps() {
+        // The s runs the instruction, and the program stays paused
+        let mut hotkeys = at_first_poll(b" ss");
+        assert!(hotkeys.paused);
+        hotkeys.before(&SourceLine::new("main.s", 2, "SUB r0, r0, #1"), &Interrupt::default());
+        assert!(hotkeys.paused);
+        assert!(hotkeys.keyboard.is_empty());
+    }
+
+    #[test]
+    fn test_c_continues() {
+        let hotkeys = at_first_poll(b" c");
+        assert!(!hotkeys.paused);
+        assert!(hotkeys.keyboard.is_empty());
+    }
+
+    #[test]
+    fn test_breakpoint_pauses() {
+        let mut hotkeys = Hotkeys::new(VecDeque::from(*b"s"));
+        hotkeys.pause();
+        hotkeys.before(&SourceLine::new("main.s", 1, "ADD r0, r0, #1"), &Interrupt::default());
+        assert!(hotkeys.paused);
+        assert!(hotkeys.keyboard.is_empty());
+    }
+}
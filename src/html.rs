@@ -0,0 +1,204 @@
+//! Self-contained HTML reports, for `--html FILE` and `grade --format html`.
+//!
+//! A report is a single file with its styles inline, so it can be mailed to a
+//! student or attached to an assignment. The report of a run shows the source
+//! with the lines that ran highlighted, the trace in a collapsible section and
+//! the final registers, flags and memory; that of grading a table of students
+//! with a badge per case and what failed.
+
+use std::collections::HashMap;
+use std::fs;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::{FinalState, Machine, SourceLine};
+use crate::isa::RegisterFile;
+
+/// Steps kept in the trace; the run goes on past them, only the report stops listing.
+pub const TRACE_LIMIT: usize = 10_000;
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.4em; } h2 { font-size: 1.15em; margin-top: 1.6em; }
+table { border-collapse: collapse; margin: 0.5em 0; }
+td, th { padding: 0.15em 0.6em; text-align: left; border-bottom: 1px solid #eee; }
+code, pre, .source td { font-family: ui-monospace, monospace; }
+.source td { border: none; white-space: pre; }
+.source .number, .source .count { color: #888; text-align: right; }
+.hit { background: #e3f6e3; } .miss { background: #fbe3e3; }
+.badge { display: inline-block; padding: 0.1em 0.6em; border-radius: 0.8em; font-size: 0.85em; color: white; }
+.pass { background: #2e8b57; } .fail { background: #c0392b; }
+pre.error { background: #fdf2f2; padding: 0.6em; }
+summary { cursor: pointer; }
+";
+
+/// The instructions a run executed, recorded for its report.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// Executions of each instruction, by index
+    pub counts: HashMap<usize, u64>,
+    /// The first [`TRACE_LIMIT`] steps, as (instruction index, registers written)
+    pub steps: Vec<(usize, Vec<(usize, i64)>)>,
+    /// Steps run after the trace was full
+    pub dropped: u64,
+}
+
+impl Trace {
+    pub fn executed(&mut self, index: usize, writes: Vec<(usize, i64)>) {
+        *self.counts.entry(index).or_default() += 1;
+        if self.steps.len() < TRACE_LIMIT {
+            self.steps.push((index, writes));
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A pass or fail badge.
+pub fn badge(passed: bool) -> &'static str {
+    if passed { "<span class=\"badge pass\">passed</span>" } else { "<span class=\"badge fail\">failed</span>" }
+}
+
+/// A complete page around `body`.
+pub fn page(title: &str, body: &str) -> String {
+    format!("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title), STYLE, body)
+}
+
+/// Writes a report to `path`.
+pub fn write(path: &str, html: &str) -> Result<(), Box<Diagnostic>> {
+    fs::write(path, html).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+        format!("Could not write the HTML report to {}: {}", path, error))))
+}
+
+/// The report of a run of `sources`, loaded as `lines`, that ended in `machine`
+/// and `state`, with the `errors` that stopped it.
+pub fn run_report(sources: &[(String, String)], lines: &[SourceLine], machine: &Machine, state: &FinalState, registers: &RegisterFile, errors: &[Diagnostic]) -> String {
+    let empty = Trace::default();
+    let trace = machine.trace.as_ref().unwrap_or(&empty);
+    let format = |name: &str, value: i64| escape(&machine.formats.format(name, value, registers.bits));
+    let steps = trace.counts.values().sum::<u64>();
+    let executed = (0..lines.len()).filter(|index| trace.counts.contains_key(index)).count();
+    let percent = if lines.is_empty() { 100.0 } else { 100.0 * executed as f64 / lines.len() as f64 };
+    let files: Vec<&str> = sources.iter().map(|(file, _)| file.as_str()).collect();
+
+    let mut body = format!("<h1>{} {}</h1>\n<p>{} instructions run; {} of {} instructions executed at least once ({:.0}%).</p>\n",
+        escape(&files.join(", ")), badge(errors.is_empty()), steps, executed, lines.len(), percent);
+    for error in errors {
+        body.push_str(&format!("<pre class=\"error\">{}</pre>\n", escape(&error.to_human())));
+    }
+
+    // Executions of each line, None for lines without an instruction
+    let mut counts: HashMap<(&str, usize), u64> = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        *counts.entry((line.file.as_str(), line.line_no)).or_default() += trace.counts.get(&index).copied().unwrap_or(0);
+    }
+    body.push_str("<h2>Source</h2>\n");
+    for (file, contents) in sources {
+        body.push_str(&format!("<p><code>{}</code></p>\n<table class=\"source\">\n", escape(file)));
+        for (number, text) in contents.lines().enumerate() {
+            let (class, count) = match counts.get(&(file.as_str(), number + 1)) {
+                Some(0) => (" class=\"miss\"", "0".to_string()),
+                Some(count) => (" class=\"hit\"", count.to_string()),
+                None => ("", String::new()),
+            };
+            body.push_str(&format!("<tr{}><td class=\"number\">{}</td><td class=\"count\">{}</td><td>{}</td></tr>\n",
+                class, number + 1, count, escape(text)));
+        }
+        body.push_str("</table>\n");
+    }
+
+    body.push_str(&format!("<h2>Trace</h2>\n<details>\n<summary>{} steps</summary>\n<table>\n<tr><th>Step</th><th>Line</th><th>Instruction</th><th>Writes</th></tr>\n",
+        trace.steps.len()));
+    for (step, (index, writes)) in trace.steps.iter().enumerate() {
+        let line = &lines[*index];
+        let writes: Vec<String> = writes.iter().map(|&(reg, value)| format!("{} = {}", registers.names[reg], format(registers.names[reg], value))).collect();
+        body.push_str(&format!("<tr><td>{}</td><td>{}:{}</td><td><code>{}</code></td><td>{}</td></tr>\n",
+            step + 1, escape(&line.file), line.line_no, escape(line.text()), writes.join(", ")));
+    }
+    body.push_str("</table>\n");
+    if trace.dropped > 0 {
+        body.push_str(&format!("<p>{} more steps are not listed.</p>\n", trace.dropped));
+    }
+    body.push_str("</details>\n");
+
+    body.push_str("<h2>Registers</h2>\n<table>\n");
+    for (name, value) in &state.registers {
+        body.push_str(&format!("<tr><td>{}</td><td><code>{}</code></td></tr>\n", name, format(name, *value)));
+    }
+    let flags: Vec<String> = state.flags.iter().map(|&(name, set)| format!("{}={}", name, set as u8)).collect();
+    body.push_str(&format!("</table>\n<p>Flags: <code>{}</code></p>\n", flags.join(" ")));
+
+    // Labeled words and any other word that is not 0
+    let mut names: HashMap<usize, Vec<&str>> = HashMap::new();
+    for (label, &address) in &state.labels {
+        names.entry(address).or_default().push(label);
+    }
+    body.push_str("<h2>Memory</h2>\n<table>\n<tr><th>Address</th><th>Labels</th><th>Value</th></tr>\n");
+    let mut addresses: Vec<usize> = state.memory.words().filter(|&(_, word)| word != 0).map(|(address, _)| address).collect();
+    addresses.extend(names.keys().filter(|&&address| address < state.memory.len()));
+    addresses.sort_unstable();
+    addresses.dedup();
+    for address in addresses {
+        let mut labels = names.get(&address).cloned().unwrap_or_default();
+        labels.sort();
+        body.push_str(&format!("<tr><td>{}</td><td>{}</td><td><code>{}</code></td></tr>\n", address, escape(&labels.join(", ")), state.memory[address]));
+    }
+    body.push_str("</table>\n");
+    page(&format!("Run of {}", files.join(", ")), &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    const PROGRAM: &str = "count: #7\nMOV r0, #2\nCMP r0, #3\nBEQ skip\nADD r0, r0, r0 // <doubled>\nskip: PRINT r0";
+
+    // The report of running `program`, written to a file of its own for `test`, and whether the run passed
+    fn report(test: &str, program: &str) -> (bool, String) {
+        let path = std::env::temp_dir().join(format!("asm-report-{}-{}.html", test, std::process::id()));
+        let options = RunOptions { html: Some(path.display().to_string()), ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        let passed = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink())).is_ok();
+        let html = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (passed, html)
+    }
+
+    #[test]
+    fn test_passed_run() {
+        let (passed, html) = report("passed", PROGRAM);
+        assert!(passed);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h1>main.s <span class=\"badge pass\">passed</span></h1>"));
+    }
+
+    #[test]
+    fn test_coverage() {
+        let (_, html) = report("coverage", PROGRAM);
+        assert!(html.contains("5 instructions run; 5 of 5 instructions executed at least once (100%)"));
+        assert!(html.contains("<tr class=\"hit\"><td class=\"number\">5</td><td class=\"count\">1</td><td>ADD r0, r0, r0 // &lt;doubled&gt;</td></tr>"));
+        assert!(html.contains("<tr><td class=\"number\">1</td><td class=\"count\"></td><td>count: #7</td></tr>"));
+    }
+
+    #[test]
+    fn test_trace_and_memory() {
+        let (_, html) = report("trace", PROGRAM);
+        assert!(html.contains("<tr><td>4</td><td>main.s:5</td><td><code>ADD r0, r0, r0</code></td><td>r0 = 4</td></tr>"));
+        assert!(html.contains("<tr><td>0</td><td>count</td><td><code>7</code></td></tr>"));
+    }
+
+    #[test]
+    fn test_failed_run() {
+        let (passed, html) = report("failed", "MOV r0, #1\nBEQ done\nMOV r1, #5000\nLDR r0, [r1]\ndone: EXIT");
+        assert!(!passed);
+        assert!(html.contains("<span class=\"badge fail\">failed</span>"));
+        assert!(html.contains("<pre class=\"error\">error[E0"));
+        assert!(html.contains("<tr class=\"miss\"><td class=\"number\">5</td><td class=\"count\">0</td><td>done: EXIT</td></tr>"));
+    }
+}
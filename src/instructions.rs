@@ -0,0 +1,719 @@
+//! Reference information about every supported instruction, used for usage
+//! messages, suggestions for mistyped mnemonics and editor help.
+
+use crate::diagnostics::{Code, Diagnostic};
+
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    /// Operand syntax, e.g. `ADD <dest_register>, <reg_operand>, <operand>`
+    pub syntax: &'static str,
+    pub summary: &'static str,
+    pub example: &'static str,
+}
+
+pub const INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        mnemonic: "MOV",
+        syntax: "MOV <register>, <value>",
+        summary: "Sets the register to an immediate (#15, #0x0F, #0b1111, #'A') or to the value of another register.",
+        example: "MOV r0, #15",
+    },
+    InstructionInfo {
+        mnemonic: "ADD",
+        syntax: "ADD <dest_register>, <reg_operand>, <operand>",
+        summary: "Adds the register operand and the second operand (immediate or register). ADDS also sets the N, Z, C and V flags.",
+        example: "ADD r0, r1, #5",
+    },
+    InstructionInfo {
+        mnemonic: "SUB",
+        syntax: "SUB <dest_register>, <reg_operand>, <operand>",
+        summary: "Subtracts the second operand (immediate or register) from the register operand. SUBS also sets the N, Z, C and V flags.",
+        example: "SUB r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "ADC",
+        syntax: "ADC <dest_register>, <reg_operand>, <operand>",
+        summary: "Adds the operands and the carry flag. ADCS also sets the N, Z, C and V flags.",
+        example: "ADC r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "SBC",
+        syntax: "SBC <dest_register>, <reg_operand>, <operand>",
+        summary: "Subtracts the second operand and the inverted carry flag. SBCS also sets the N, Z, C and V flags.",
+        example: "SBC r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "MUL",
+        syntax: "MUL <dest_register>, <reg_operand>, <operand>",
+        summary: "Multiplies the register operand by the second operand.",
+        example: "MUL r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "MLA",
+        syntax: "MLA <dest_register>, <register>, <register>, <accumulate_register>",
+        summary: "Multiplies the registers and adds the accumulate register. MLAS also sets the N and Z flags.",
+        example: "MLA r0, r1, r2, r0",
+    },
+    InstructionInfo {
+        mnemonic: "MLS",
+        syntax: "MLS <dest_register>, <register>, <register>, <accumulate_register>",
+        summary: "Multiplies the registers and subtracts the product from the accumulate register.",
+        example: "MLS r0, r1, r2, r0",
+    },
+    InstructionInfo {
+        mnemonic: "AND",
+        syntax: "AND <dest_register>, <reg_operand>, <operand>",
+        summary: "Bitwise AND of the register operand and the second operand.",
+        example: "AND r0, r1, #0xFF",
+    },
+    InstructionInfo {
+        mnemonic: "ORR",
+        syntax: "ORR <dest_register>, <reg_operand>, <operand>",
+        summary: "Bitwise OR of the register operand and the second operand.",
+        example: "ORR r0, r1, #1",
+    },
+    InstructionInfo {
+        mnemonic: "BIC",
+        syntax: "BIC <dest_register>, <reg_operand>, <operand>",
+        summary: "Clears the bits of the register operand that are set in the second operand.",
+        example: "BIC r0, r1, #0x0F",
+    },
+    InstructionInfo {
+        mnemonic: "EOR",
+        syntax: "EOR <dest_register>, <reg_operand>, <operand>",
+        summary: "Bitwise exclusive OR of the register operand and the second operand.",
+        example: "EOR r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "CMP",
+        syntax: "CMP <reg_operand>, <operand>",
+        summary: "Subtracts the operand from the register and sets the N, Z, C and V flags without keeping the result.",
+        example: "CMP r0, #10",
+    },
+    InstructionInfo {
+        mnemonic: "CMN",
+        syntax: "CMN <reg_operand>, <operand>",
+        summary: "Adds the operand to the register and sets the N, Z, C and V flags without keeping the result.",
+        example: "CMN r0, #1",
+    },
+    InstructionInfo {
+        mnemonic: "B",
+        syntax: "B{cond} <label>",
+        summary: "Branches to a code label, if the condition holds: EQ, NE, CS/HS, CC/LO, MI, PL, VS, VC, HI, LS, GE, LT, GT or LE.",
+        example: "BGT loop",
+    },
+    InstructionInfo {
+        mnemonic: "BL",
+        syntax: "BL <label>",
+        summary: "Calls a function: branches to the label, saving the return address in r14.",
+        example: "BL square",
+    },
+    InstructionInfo {
+        mnemonic: "BX",
+        syntax: "BX <register>",
+        summary: "Branches to the address in the register. BX r14 returns from a function.",
+        example: "BX r14",
+    },
+    InstructionInfo {
+        mnemonic: "LSL",
+        syntax: "LSL <dest_register>, <source_register>, <shift_amount>",
+        summary: "Logical shift left of the source register. LSLS also sets N, Z and the carry flag.",
+        example: "LSL r0, r1, #2",
+    },
+    InstructionInfo {
+        mnemonic: "LSR",
+        syntax: "LSR <dest_register>, <source_register>, <shift_amount>",
+        summary: "Logical shift right of the source register, filling with zeros. LSRS also sets N, Z and the carry flag.",
+        example: "LSR r0, r1, #3",
+    },
+    InstructionInfo {
+        mnemonic: "ASR",
+        syntax: "ASR <dest_register>, <source_register>, <shift_amount>",
+        summary: "Arithmetic shift right of the source register, keeping the sign. ASRS also sets N, Z and the carry flag.",
+        example: "ASR r0, r1, #1",
+    },
+    InstructionInfo {
+        mnemonic: "ROR",
+        syntax: "ROR <dest_register>, <source_register>, <rotate_amount>",
+        summary: "Rotates the bits of the source register to the right. RORS also sets N, Z and the carry flag.",
+        example: "ROR r0, r1, #4",
+    },
+    InstructionInfo {
+        mnemonic: "RRX",
+        syntax: "RRX <dest_register>, <source_register>",
+        summary: "Rotates the source register right by one bit, shifting the carry flag in at the top.",
+        example: "RRX r0, r1",
+    },
+    InstructionInfo {
+        mnemonic: "LDR",
+        syntax: "LDR <register>, <address_operand>",
+        summary: "Loads a memory word. The address is a label, #address, [Rx] or [Rx, #offset]; =value loads a constant or a label's address from the literal pool.",
+        example: "LDR r0, [r1, #4]",
+    },
+    InstructionInfo {
+        mnemonic: "STR",
+        syntax: "STR <source_register>, <address_operand>",
+        summary: "Stores a register to a memory word. The address is a label, #address, [Rx] or [Rx, #offset].",
+        example: "STR r0, data",
+    },
+    InstructionInfo {
+        mnemonic: "ADR",
+        syntax: "ADR <register>, <label>",
+        summary: "Sets the register to the address of a label: the instruction of a code label, for BX or a task, or the memory word of a data label.",
+        example: "ADR r0, worker",
+    },
+    InstructionInfo {
+        mnemonic: "LDREX",
+        syntax: "LDREX <dest_register>, [<register>]",
+        summary: "Loads a memory word and reserves its address for a following STREX.",
+        example: "LDREX r0, [r1]",
+    },
+    InstructionInfo {
+        mnemonic: "STREX",
+        syntax: "STREX <status_register>, <source_register>, [<register>]",
+        summary: "Stores a register if no store reached the address since the LDREX, setting the status to 0 if it did and 1 if not.",
+        example: "STREX r2, r0, [r1]",
+    },
+    InstructionInfo {
+        mnemonic: "CLREX",
+        syntax: "CLREX",
+        summary: "Drops the reservation of LDREX, so the next STREX fails.",
+        example: "CLREX",
+    },
+    InstructionInfo {
+        mnemonic: "DMB",
+        syntax: "DMB [<option>]",
+        summary: "Memory barrier. Accesses here always complete in program order, so it has no effect; the trace notes it.",
+        example: "DMB ISH",
+    },
+    InstructionInfo {
+        mnemonic: "DSB",
+        syntax: "DSB [<option>]",
+        summary: "Synchronization barrier. Accesses here always complete before the next instruction, so it has no effect; the trace notes it.",
+        example: "DSB SY",
+    },
+    InstructionInfo {
+        mnemonic: "ISB",
+        syntax: "ISB [SY]",
+        summary: "Instruction barrier. No instruction is fetched ahead here, so it has no effect; the trace notes it.",
+        example: "ISB",
+    },
+    InstructionInfo {
+        mnemonic: "VADD.F32",
+        syntax: "VADD.F32 <dest_fp_register>, <fp_register>, <fp_register>",
+        summary: "Adds two single-precision floating-point registers.",
+        example: "VADD.F32 s0, s1, s2",
+    },
+    InstructionInfo {
+        mnemonic: "VSUB.F32",
+        syntax: "VSUB.F32 <dest_fp_register>, <fp_register>, <fp_register>",
+        summary: "Subtracts the second floating-point register from the first.",
+        example: "VSUB.F32 s0, s1, s2",
+    },
+    InstructionInfo {
+        mnemonic: "VMUL.F32",
+        syntax: "VMUL.F32 <dest_fp_register>, <fp_register>, <fp_register>",
+        summary: "Multiplies two single-precision floating-point registers.",
+        example: "VMUL.F32 s0, s1, s2",
+    },
+    InstructionInfo {
+        mnemonic: "VDIV.F32",
+        syntax: "VDIV.F32 <dest_fp_register>, <fp_register>, <fp_register>",
+        summary: "Divides the first floating-point register by the second.",
+        example: "VDIV.F32 s0, s1, s2",
+    },
+    InstructionInfo {
+        mnemonic: "VMOV",
+        syntax: "VMOV <fp_register>, <value> | VMOV <register>, <fp_register>",
+        summary: "Sets an s register to a float immediate (#1.5), another s register or the raw bits of an r register, or copies the raw bits of an s register to an r register.",
+        example: "VMOV s0, #1.5",
+    },
+    InstructionInfo {
+        mnemonic: "VLDR",
+        syntax: "VLDR <fp_register>, <address_operand>",
+        summary: "Loads a memory word into an s register. The address is a label, #address, [Rx] or [Rx, #offset].",
+        example: "VLDR s0, [r1, #1]",
+    },
+    InstructionInfo {
+        mnemonic: "VSTR",
+        syntax: "VSTR <fp_register>, <address_operand>",
+        summary: "Stores an s register to a memory word. The address is a label, #address, [Rx] or [Rx, #offset].",
+        example: "VSTR s0, result",
+    },
+    InstructionInfo {
+        mnemonic: "VCVT.F32.S32",
+        syntax: "VCVT.F32.S32 <dest_fp_register>, <fp_register>",
+        summary: "Converts the signed integer held in an s register to a float.",
+        example: "VCVT.F32.S32 s0, s0",
+    },
+    InstructionInfo {
+        mnemonic: "VCVT.S32.F32",
+        syntax: "VCVT.S32.F32 <dest_fp_register>, <fp_register>",
+        summary: "Converts a float to a signed integer held in an s register, rounding toward zero.",
+        example: "VCVT.S32.F32 s0, s1",
+    },
+    InstructionInfo {
+        mnemonic: "SMULBB",
+        syntax: "SMUL<x><y> <dest_register>, <register>, <register>",
+        summary: "Multiplies the signed bottom (B) or top (T) halfwords of two registers, e.g. two Q15 values into a Q30 result.",
+        example: "SMULBB r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "SMLABB",
+        syntax: "SMLA<x><y> <dest_register>, <register>, <register>, <accumulate_register>",
+        summary: "Multiplies the signed bottom (B) or top (T) halfwords of two registers and adds the accumulate register.",
+        example: "SMLABB r0, r1, r2, r0",
+    },
+    InstructionInfo {
+        mnemonic: "SADD8",
+        syntax: "<S|U|Q|UQ><ADD|SUB><8|16> <dest_register>, <register>, <register>",
+        summary: "Adds or subtracts each packed byte (8) or halfword (16) separately: signed (S) or unsigned (U), setting the GE flags, or saturating (Q, UQ).",
+        example: "UADD8 r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "SEL",
+        syntax: "SEL <dest_register>, <register>, <register>",
+        summary: "Takes each byte from the first register where its GE flag is set, otherwise from the second.",
+        example: "SEL r0, r1, r2",
+    },
+    InstructionInfo {
+        mnemonic: "MRS",
+        syntax: "MRS <dest_register>, <CPSR|APSR>",
+        summary: "Reads the status register: flags N Z C V Q in bits 31-27, GE in bits 19-16 and the mode in bits 4-0.",
+        example: "MRS r0, CPSR",
+    },
+    InstructionInfo {
+        mnemonic: "MSR",
+        syntax: "MSR <CPSR_fields|APSR_nzcvq|APSR_g>, <operand>",
+        summary: "Writes the selected fields of the status register: f for the flags, s for GE. The mode cannot be changed in User mode.",
+        example: "MSR CPSR_f, r0",
+    },
+    InstructionInfo {
+        mnemonic: "PRINT",
+        syntax: "PRINT <register>[, q<N>]",
+        summary: "Displays the current value of the register. s registers are shown as floats, and q15 or q31 shows a fixed-point value with that many fraction bits.",
+        example: "PRINT r0, q15",
+    },
+    InstructionInfo {
+        mnemonic: "SVC",
+        syntax: "SVC #<service>",
+        summary: "Calls a service: #1 creates a task at r0 with stack r1 and argument r2, #2 yields to the next task, #3 ends the running task, #4 beeps at r0 Hz for r1 ms, #5 strlen, #6 strcmp and #7 memcpy r2 words from r1 to r0.",
+        example: "SVC #2",
+    },
+    InstructionInfo {
+        mnemonic: "BKPT",
+        syntax: "BKPT [#<immediate>]",
+        summary: "Breakpoint: pauses when running in a terminal, otherwise prints the registers and flags and continues.",
+        example: "BKPT #1",
+    },
+    InstructionInfo {
+        mnemonic: "EXIT",
+        syntax: "EXIT",
+        summary: "Terminates the program.",
+        example: "EXIT",
+    },
+];
+
+/// Instructions of the AArch64 mode (`--isa a64`).
+pub const A64_INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        mnemonic: "MOV",
+        syntax: "MOV <Xd|Wd>, <Xm|Wm|#imm>",
+        summary: "Sets the register to an immediate or to the value of another register of the same width.",
+        example: "MOV x0, #15",
+    },
+    InstructionInfo {
+        mnemonic: "ADD",
+        syntax: "ADD <Xd|Wd>, <Xn|Wn>, <Xm|Wm|#imm>",
+        summary: "Adds the second operand to the first. ADDS also sets the NZCV flags.",
+        example: "ADD x0, x1, #8",
+    },
+    InstructionInfo {
+        mnemonic: "SUB",
+        syntax: "SUB <Xd|Wd>, <Xn|Wn>, <Xm|Wm|#imm>",
+        summary: "Subtracts the second operand from the first. SUBS also sets the NZCV flags.",
+        example: "SUB w0, w1, w2",
+    },
+    InstructionInfo {
+        mnemonic: "CMP",
+        syntax: "CMP <Xn|Wn>, <Xm|Wm|#imm>",
+        summary: "Compares two values by subtracting them, setting the NZCV flags.",
+        example: "CMP x0, #10",
+    },
+    InstructionInfo {
+        mnemonic: "CSEL",
+        syntax: "CSEL <Xd|Wd>, <Xn|Wn>, <Xm|Wm>, <cond>",
+        summary: "Sets the destination to the first register if the condition holds, otherwise to the second.",
+        example: "CSEL x0, x1, x2, GT",
+    },
+    InstructionInfo {
+        mnemonic: "LDR",
+        syntax: "LDR <Xt|Wt>, <address_operand>",
+        summary: "Loads a word (W) or two words (X). The address is a label, #address, [Xn] or [Xn, #offset].",
+        example: "LDR x0, [x1, #2]",
+    },
+    InstructionInfo {
+        mnemonic: "STR",
+        syntax: "STR <Xt|Wt>, <address_operand>",
+        summary: "Stores a word (W) or two words (X). The address is a label, #address, [Xn] or [Xn, #offset].",
+        example: "STR w0, data",
+    },
+    InstructionInfo {
+        mnemonic: "B",
+        syntax: "B <label> or B.<cond> <label>",
+        summary: "Branches to the label, if the condition holds.",
+        example: "B.NE loop",
+    },
+    InstructionInfo {
+        mnemonic: "BL",
+        syntax: "BL <label>",
+        summary: "Branches to the label, saving the return address in x30.",
+        example: "BL square",
+    },
+    InstructionInfo {
+        mnemonic: "RET",
+        syntax: "RET",
+        summary: "Returns to the address in x30.",
+        example: "RET",
+    },
+    InstructionInfo {
+        mnemonic: "PRINT",
+        syntax: "PRINT <register>",
+        summary: "Displays the current value of the register.",
+        example: "PRINT x0",
+    },
+    InstructionInfo {
+        mnemonic: "EXIT",
+        syntax: "EXIT",
+        summary: "Terminates the program.",
+        example: "EXIT",
+    },
+];
+
+/// Instructions of the RISC-V mode (`--isa rv32i`).
+pub const RV32I_INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        mnemonic: "add",
+        syntax: "add rd, rs1, rs2",
+        summary: "Adds two registers. sub, and, or, xor, sll, srl, sra, slt and sltu take the same operands.",
+        example: "add a0, a1, a2",
+    },
+    InstructionInfo {
+        mnemonic: "addi",
+        syntax: "addi rd, rs1, imm",
+        summary: "Adds a 12-bit signed immediate. andi, ori, xori, slti and sltiu take the same operands; slli, srli and srai shift by 0 to 31.",
+        example: "addi t0, t0, 1",
+    },
+    InstructionInfo {
+        mnemonic: "li",
+        syntax: "li rd, imm",
+        summary: "Loads any 32-bit immediate (pseudo-instruction).",
+        example: "li a0, 100",
+    },
+    InstructionInfo {
+        mnemonic: "mv",
+        syntax: "mv rd, rs",
+        summary: "Copies a register (pseudo-instruction for addi rd, rs, 0).",
+        example: "mv a0, t0",
+    },
+    InstructionInfo {
+        mnemonic: "lui",
+        syntax: "lui rd, imm",
+        summary: "Loads a 20-bit immediate into the upper bits of the register.",
+        example: "lui t0, 0x12345",
+    },
+    InstructionInfo {
+        mnemonic: "lw",
+        syntax: "lw rd, offset(rs1)",
+        summary: "Loads a memory word. The address is offset(rs1), with the offset in words, or a label.",
+        example: "lw t0, 0(sp)",
+    },
+    InstructionInfo {
+        mnemonic: "sw",
+        syntax: "sw rs2, offset(rs1)",
+        summary: "Stores a register to a memory word. The address is offset(rs1), with the offset in words, or a label.",
+        example: "sw t0, 1(sp)",
+    },
+    InstructionInfo {
+        mnemonic: "beq",
+        syntax: "beq rs1, rs2, label",
+        summary: "Branches if the registers are equal. bne, blt, bge, bltu, bgeu, bgt and ble take the same operands.",
+        example: "blt t0, t1, loop",
+    },
+    InstructionInfo {
+        mnemonic: "beqz",
+        syntax: "beqz rs, label",
+        summary: "Branches if the register is zero. bnez branches if it is not.",
+        example: "beqz a0, done",
+    },
+    InstructionInfo {
+        mnemonic: "j",
+        syntax: "j label",
+        summary: "Jumps to the label (pseudo-instruction for jal x0, label).",
+        example: "j loop",
+    },
+    InstructionInfo {
+        mnemonic: "jal",
+        syntax: "jal [rd,] label",
+        summary: "Jumps to the label, saving the return address in rd (ra if omitted).",
+        example: "jal ra, func",
+    },
+    InstructionInfo {
+        mnemonic: "jalr",
+        syntax: "jalr rd, rs1, offset",
+        summary: "Jumps to the address in rs1 plus offset, saving the return address in rd.",
+        example: "jalr x0, ra, 0",
+    },
+    InstructionInfo {
+        mnemonic: "call",
+        syntax: "call label",
+        summary: "Calls a function, saving the return address in ra.",
+        example: "call func",
+    },
+    InstructionInfo {
+        mnemonic: "ret",
+        syntax: "ret",
+        summary: "Returns to the address in ra.",
+        example: "ret",
+    },
+    InstructionInfo {
+        mnemonic: "nop",
+        syntax: "nop",
+        summary: "Does nothing.",
+        example: "nop",
+    },
+    InstructionInfo {
+        mnemonic: "print",
+        syntax: "print rs",
+        summary: "Displays the current value of the register.",
+        example: "print a0",
+    },
+    InstructionInfo {
+        mnemonic: "exit",
+        syntax: "exit",
+        summary: "Terminates the program.",
+        example: "exit",
+    },
+];
+
+/// Instructions of the x86-64 mode (`--isa x86`).
+pub const X86_INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        mnemonic: "mov",
+        syntax: "mov dst, src",
+        summary: "Copies the source (register, immediate or memory) to the destination (register or memory).",
+        example: "mov rax, [rbx + 2]",
+    },
+    InstructionInfo {
+        mnemonic: "add",
+        syntax: "add dst, src",
+        summary: "Adds the source to the destination, setting ZF, SF, CF and OF.",
+        example: "add rax, 5",
+    },
+    InstructionInfo {
+        mnemonic: "sub",
+        syntax: "sub dst, src",
+        summary: "Subtracts the source from the destination, setting ZF, SF, CF (borrow) and OF.",
+        example: "sub rcx, 1",
+    },
+    InstructionInfo {
+        mnemonic: "cmp",
+        syntax: "cmp a, b",
+        summary: "Compares two values by subtracting them, setting the flags without storing the result.",
+        example: "cmp rax, rbx",
+    },
+    InstructionInfo {
+        mnemonic: "jmp",
+        syntax: "jmp label",
+        summary: "Jumps to the label.",
+        example: "jmp loop",
+    },
+    InstructionInfo {
+        mnemonic: "jcc",
+        syntax: "jcc label",
+        summary: "Jumps if the condition holds: je/jz, jne/jnz, jl, jle, jg, jge (signed), jb, jbe, ja, jae (unsigned), js, jns, jo, jno.",
+        example: "jne loop",
+    },
+    InstructionInfo {
+        mnemonic: "push",
+        syntax: "push src",
+        summary: "Decrements rsp by two words and stores the value on the stack.",
+        example: "push rax",
+    },
+    InstructionInfo {
+        mnemonic: "pop",
+        syntax: "pop dst",
+        summary: "Loads the value on top of the stack and increments rsp by two words.",
+        example: "pop rbx",
+    },
+    InstructionInfo {
+        mnemonic: "call",
+        syntax: "call label",
+        summary: "Pushes the return address and jumps to the label.",
+        example: "call func",
+    },
+    InstructionInfo {
+        mnemonic: "ret",
+        syntax: "ret",
+        summary: "Pops the return address and jumps to it. With an empty stack, ends the program.",
+        example: "ret",
+    },
+    InstructionInfo {
+        mnemonic: "print",
+        syntax: "print reg",
+        summary: "Displays the current value of the register.",
+        example: "print rax",
+    },
+    InstructionInfo {
+        mnemonic: "exit",
+        syntax: "exit",
+        summary: "Terminates the program.",
+        example: "exit",
+    },
+];
+
+/// Instructions of the Little Man Computer (`--isa lmc`).
+pub const LMC_INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        mnemonic: "INP",
+        syntax: "INP",
+        summary: "Reads a number from 0 to 999 into the accumulator.",
+        example: "INP",
+    },
+    InstructionInfo {
+        mnemonic: "OUT",
+        syntax: "OUT",
+        summary: "Prints the accumulator.",
+        example: "OUT",
+    },
+    InstructionInfo {
+        mnemonic: "ADD",
+        syntax: "ADD mailbox",
+        summary: "Adds the number in a mailbox to the accumulator, wrapping past 999.",
+        example: "ADD total",
+    },
+    InstructionInfo {
+        mnemonic: "SUB",
+        syntax: "SUB mailbox",
+        summary: "Subtracts the number in a mailbox from the accumulator, setting the negative flag when the result is below 0.",
+        example: "SUB one",
+    },
+    InstructionInfo {
+        mnemonic: "STA",
+        syntax: "STA mailbox",
+        summary: "Stores the accumulator in a mailbox.",
+        example: "STA total",
+    },
+    InstructionInfo {
+        mnemonic: "LDA",
+        syntax: "LDA mailbox",
+        summary: "Loads the number in a mailbox into the accumulator.",
+        example: "LDA count",
+    },
+    InstructionInfo {
+        mnemonic: "BRA",
+        syntax: "BRA mailbox",
+        summary: "Branches to a mailbox.",
+        example: "BRA loop",
+    },
+    InstructionInfo {
+        mnemonic: "BRZ",
+        syntax: "BRZ mailbox",
+        summary: "Branches to a mailbox if the accumulator is 0.",
+        example: "BRZ done",
+    },
+    InstructionInfo {
+        mnemonic: "BRP",
+        syntax: "BRP mailbox",
+        summary: "Branches to a mailbox unless the negative flag is set.",
+        example: "BRP loop",
+    },
+    InstructionInfo {
+        mnemonic: "HLT",
+        syntax: "HLT",
+        summary: "Stops the program. COB is another name for it.",
+        example: "HLT",
+    },
+    InstructionInfo {
+        mnemonic: "DAT",
+        syntax: "DAT [value]",
+        summary: "Fills the mailbox of the line with a number from 0 to 999, or 0 without one.",
+        example: "count DAT 10",
+    },
+];
+
+/// Finds an instruction by mnemonic, ignoring case.
+pub fn lookup(mnemonic: &str) -> Option<&'static InstructionInfo> {
+    lookup_in(INSTRUCTIONS, mnemonic)
+}
+
+/// Finds an instruction of the given instruction set by mnemonic, ignoring case.
+pub fn lookup_in(table: &'static [InstructionInfo], mnemonic: &str) -> Option<&'static InstructionInfo> {
+    table.iter().find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+}
+
+/// The error for a mnemonic the instruction set does not have, suggesting the
+/// closest ones of `table` that it does.
+pub fn unknown(table: &'static [InstructionInfo], mnemonic: &str) -> Diagnostic {
+    let error = Diagnostic::error(Code::UnknownInstruction, format!("Unknown instruction: {}", mnemonic));
+    match &suggestions(table, mnemonic)[..] {
+        [] => error,
+        [only] => error.with_note(format!("did you mean {}?", only)),
+        [rest @ .., last] => error.with_note(format!("did you mean {} or {}?", rest.join(", "), last)),
+    }
+}
+
+/// The mnemonics of `table` fewest edits (Levenshtein distance, ignoring case) away
+/// from `mnemonic`: one edit for a mnemonic of up to 4 letters, two for a longer one.
+pub fn suggestions(table: &'static [InstructionInfo], mnemonic: &str) -> Vec<&'static str> {
+    let typed: Vec<char> = mnemonic.to_uppercase().chars().collect();
+    let mut nearest = Vec::new();
+    // The fewest edits seen so far, starting from the most allowed
+    let mut best = if typed.len() <= 4 { 1 } else { 2 };
+    for info in table {
+        let distance = edit_distance(&typed, &info.mnemonic.to_uppercase().chars().collect::<Vec<_>>());
+        // A single letter is one edit away from every other one, which says nothing
+        if distance >= typed.len() || distance > best || nearest.contains(&info.mnemonic) {
+            continue;
+        }
+        if distance < best {
+            (best, nearest) = (distance, Vec::new());
+        }
+        nearest.push(info.mnemonic);
+    }
+    nearest
+}
+
+// The number of insertions, deletions and substitutions that turn `a` into `b`
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            current.push((previous[j] + usize::from(ca != cb)).min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions() {
+        assert_eq!(suggestions(INSTRUCTIONS, "ADDD"), ["ADD"]);
+        assert_eq!(suggestions(INSTRUCTIONS, "move"), ["MOV"]);
+        assert_eq!(suggestions(INSTRUCTIONS, "LDRXE"), ["LDR", "LDREX"]);
+        assert_eq!(suggestions(INSTRUCTIONS, "LSX"), ["LSL", "LSR"]);
+        assert!(suggestions(INSTRUCTIONS, "FOO").is_empty());
+        assert!(suggestions(INSTRUCTIONS, "X").is_empty());
+        assert_eq!(suggestions(X86_INSTRUCTIONS, "MOVV"), ["mov"]);
+
+        let error = unknown(INSTRUCTIONS, "SUBB");
+        assert_eq!(error.message, "Unknown instruction: SUBB");
+        assert_eq!(error.notes, ["did you mean SUB?"]);
+        assert_eq!(unknown(INSTRUCTIONS, "LSX").notes, ["did you mean LSL or LSR?"]);
+    }
+}
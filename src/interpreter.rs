@@ -1,930 +1,5699 @@
-use std::io::{self, BufRead, Write};
-use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::ops::{Range, RangeInclusive};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::breakpoints::{Breakpoint, Breakpoints};
+use crate::cache::{Cache, CacheConfig};
+use crate::callgraph::CallGraph;
+use crate::debugger::{DebugScript, Resume, Session};
+use crate::console::Console;
+use crate::cores::{Cores, Interleaving, Schedule};
+use crate::diagnostics::{self, Code, Diagnostic, Reporter, Severity};
+use crate::encoding::{self, Encoding};
+use crate::expression;
+use crate::heatmap::{self, Accesses};
+use crate::html::{self, Trace};
+use crate::history::{History, RECENT_DEPTH};
+use crate::hotkeys::Hotkeys;
+use crate::keyboard::MappedKeyboard;
+use crate::instructions::{self, InstructionInfo};
+use crate::isa::{FrontEnd, Flow, Isa, Labels, LiteralPool, RegisterFile, Tokens};
+use crate::log;
+use crate::plugin;
+use crate::prompt::Prompt;
+use crate::radix::{self, Conversion, Formats, Setting};
+use crate::script::{self, Script};
+use crate::semihosting;
+use crate::snapshot::Snapshot;
+use crate::snippets::Snippets;
+use crate::taint::{DataFlow, Taint};
+use crate::tasks::{self, Tasks, MAX_TASKS, TCB_CPSR, TCB_PC, TCB_WORDS};
+use crate::timeline::{Event, Timeline, TraceFormat};
+use crate::watch::{MemoryWatch, WatchRange};
+use crate::delta::StateDelta;
+use crate::memory::Memory;
+use crate::memory_map::{Devices, MemoryMap};
+use crate::target::Target;
+use crate::undefined::Undefined;
+use crate::lint::DeadStores;
+use crate::watchdog::{Loop, State, Watchdog};
 
 const NUM_REGISTERS: usize = 16;
-const MEMORY_SIZE: usize = 1024; // memory size (1024 words)
-
-pub fn interactive(){
-    let stdin = io::stdin();
-    run_with_reader(stdin.lock(), true);
-}
-
-pub fn run_with_reader<R: BufRead>(mut reader: R, interactive: bool) {
-    // Initialize all registers to 0
-    let mut registers = [0i32; NUM_REGISTERS];
-    // Initialize the CPSR carry flag (0 or 1)
-    let mut cpsr: u32 = 0;
-    // Initialize memory
-    let mut memory: Vec<i32> = vec![0; MEMORY_SIZE];
-    // Store labels and their memory addresses
-    let mut labels: HashMap<String, usize> = HashMap::new();
-    // Keep track of the next available memory address for new labels
-    let mut next_label_mem_addr: usize = 0;
-
-    fn report_error(interactive: bool, msg: &str) {
-        if interactive {
-            println!("{}", msg);
-        } else {
-            panic!("{}", msg);
+const NUM_FP_REGISTERS: usize = 32;
+// Registers with a conventional role; r14 holds the return address of BL
+const FRAME_POINTER: usize = 11;
+const STACK_POINTER: usize = 13;
+const LINK_REGISTER: usize = 14;
+pub const MEMORY_SIZE: usize = 1024; // memory size (1024 words)
+const WORD_BYTES: usize = 4;
+// The most words of memory map regions --undefined and --heatmap keep something for, 1M bytes
+const MAX_MAPPED_WORDS: usize = 1 << 18;
+// The largest power of two bytes .align accepts, all of memory
+const MAX_ALIGN: i32 = 12;
+// Stack words and instructions shown after a runtime error
+const STACK_WORDS: usize = 8;
+const POST_MORTEM_INSTRUCTIONS: usize = 16;
+
+// Where a return with no caller goes in interactive mode: past every instruction entered, ending the line
+const REPL_END: usize = i32::MAX as usize;
+
+/// Labels that mark the start of a program, in order of preference.
+const DEFAULT_ENTRY_LABELS: [&str; 2] = ["_start", "main"];
+
+const REGISTER_NOTE: &str = "registers are r0 through r15, with fp, sp and lr standing for r11, r13 and r14";
+const PSR_NOTE: &str = "use CPSR or APSR, e.g. MRS r0, CPSR or MSR CPSR_f, r0";
+
+// CPSR bits
+const CPSR_N: u32 = 1 << 31;
+const CPSR_Z: u32 = 1 << 30;
+const CPSR_C: u32 = 1 << 29;
+const CPSR_V: u32 = 1 << 28;
+const CPSR_Q: u32 = 1 << 27;
+const CPSR_GE_SHIFT: u32 = 16;
+// Programs run in User mode
+const USER_MODE: u32 = 0x10;
+// The bits MSR can change in User mode: the N, Z, C, V and Q flags and GE[3:0]
+const CPSR_FLAGS_FIELD: u32 = 0xF800_0000;
+const CPSR_STATUS_FIELD: u32 = 0x000F_0000;
+// The services of SVC
+const SVC_CREATE_TASK: u32 = 1;
+const SVC_YIELD: u32 = 2;
+const SVC_END_TASK: u32 = 3;
+const SVC_BEEP: u32 = 4;
+const SVC_STRLEN: u32 = 5;
+const SVC_STRCMP: u32 = 6;
+const SVC_MEMCPY: u32 = 7;
+/// The numbers of the services of the interpreter, which plugins cannot take
+pub const SERVICES: RangeInclusive<u32> = SVC_CREATE_TASK..=SVC_MEMCPY;
+const SERVICES_NOTE: &str = "the services are SVC #1 to create a task, #2 to yield to the next task, #3 to end the running task, #4 to beep, \
+    and the helpers #5 strlen, #6 strcmp and #7 memcpy";
+// The longest beep, so a wrong duration does not hang the program
+const MAX_BEEP_MS: i32 = 10_000;
+// Lines of a loop body shown when reporting an infinite loop
+const LOOP_BODY_LINES: usize = 12;
+const FP_REGISTER_NOTE: &str = "floating-point registers are s0 through s31";
+
+/// Registers, flags, memory and labels of the simulated machine.
+pub struct Machine {
+    pub registers: [i32; NUM_REGISTERS],
+    // The single-precision VFP registers s0-s31
+    pub fp_registers: [f32; NUM_FP_REGISTERS],
+    // The current program status register: condition flags, GE[3:0] and the mode
+    cpsr: u32,
+    // Report signed overflow of ADD, SUB, MUL, ADC and SBC instead of wrapping around
+    pub trap_overflow: bool,
+    // The bits in a word, which --word-size narrows from 32
+    pub word_bits: u32,
+    // Whether the string helpers of SVC are available, which --no-helpers turns off
+    pub helpers: bool,
+    // Recent register changes, for HISTORY
+    pub history: History,
+    pub memory: Memory,
+    // The regions a program may read and write, from the memory map
+    pub memory_map: Option<MemoryMap>,
+    // Loads and stores of each memory word, for --heatmap
+    pub accesses: Accesses,
+    // The simulated data cache, for --cache
+    pub cache: Option<Cache>,
+    // What answers the words of mmio regions
+    pub devices: Devices,
+    // The instructions run, for --html
+    pub trace: Option<Trace>,
+    // Where each step is recorded, for --trace
+    pub timeline: Option<Timeline>,
+    // Where the changes of watched memory words go, for --watch-memory
+    pub memory_watch: Option<MemoryWatch>,
+    // What each instruction changed, for RunOptions::deltas
+    pub deltas: Vec<StateDelta>,
+    // The tainted registers and memory, for --taint
+    pub taint: Option<Taint>,
+    // The registers and memory not set yet, for --undefined
+    pub undefined: Option<Undefined>,
+    // The registers written and not read since, for --lint
+    pub dead_stores: Option<DeadStores>,
+    // Warnings the run gave, such as for using undefined values
+    pub warnings: Vec<Diagnostic>,
+    // The instructions warned about reading the flags before any instruction set them; None once one has
+    pub unset_flags: Option<BTreeSet<usize>>,
+    // The tasks of SVC and which one is running
+    pub tasks: Tasks,
+    // The address LDREX reserved and how often it had been written then; STREX stores only if no store came since
+    pub exclusive: Option<(usize, u64)>,
+    // The radix PRINT, RECENT, HISTORY and register listings use, set with --radix and SET
+    pub formats: Formats,
+    // Labels and their memory addresses
+    pub labels: HashMap<String, usize>,
+    // The next available memory address for new labels
+    next_label_mem_addr: usize,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        Machine {
+            registers: [0i32; NUM_REGISTERS],
+            fp_registers: [0.0; NUM_FP_REGISTERS],
+            cpsr: USER_MODE,
+            trap_overflow: false,
+            word_bits: 32,
+            helpers: true,
+            history: History::default(),
+            memory: Memory::new(MEMORY_SIZE),
+            memory_map: None,
+            accesses: Accesses::new(MEMORY_SIZE),
+            cache: None,
+            devices: Devices::default(),
+            trace: None,
+            timeline: None,
+            memory_watch: None,
+            deltas: Vec::new(),
+            taint: None,
+            undefined: None,
+            dead_stores: None,
+            warnings: Vec::new(),
+            unset_flags: Some(BTreeSet::new()),
+            tasks: Tasks::default(),
+            exclusive: None,
+            formats: Formats::default(),
+            labels: HashMap::new(),
+            next_label_mem_addr: 0,
         }
     }
 
-    // Helper function to parse a register name
-    fn parse_register(reg: &str) -> Option<usize> {
-        if reg.len() < 2 || !reg.to_lowercase().starts_with('r') {
-            return None;
+    /// Computes with words of `bits` bits from now on, cutting the registers below the
+    /// stack pointer and every memory word down to them.
+    pub fn narrow(&mut self, bits: u32) {
+        self.word_bits = bits;
+        self.narrow_registers();
+        self.memory.update(|word| narrow(word, bits));
+    }
+
+    // The stack pointer, link register and pc hold addresses, which keep 32 bits
+    fn narrow_registers(&mut self) {
+        for register in &mut self.registers[..STACK_POINTER] {
+            *register = narrow(*register, self.word_bits);
         }
-        reg[1..].parse::<usize>()
-            .ok()
-            .and_then(|idx| if idx < NUM_REGISTERS { Some(idx) } else { None })
     }
 
-    // Helper function to parse a value (immediate or register content)
-    fn parse_value(s: &str, registers: &[i32]) -> Option<i32> {
-        if s.starts_with('#') {
-            let imm_str = &s[1..];
-            // Support hexadecimal if prefixed with "0x" (or "0X")s
-            if imm_str.starts_with("0x") || imm_str.starts_with("0X") {
-                i32::from_str_radix(&imm_str[2..], 16).ok()
-            } else {
-                imm_str.parse::<i32>().ok()
-            }
-        } else {
-            // Otherwise, assume it's a register and return its current value
-            parse_register(s).map(|idx| registers[idx])
+    /// Reads a memory word for the program, counting the access, or fails outside memory or its map.
+    /// In an mmio region, the device there gives the word.
+    pub fn load(&mut self, address: usize) -> Result<i32, Box<Diagnostic>> {
+        check_bounds(address, self.memory.len())?;
+        count_access(&mut self.accesses, &mut self.cache, &self.memory_map, address, false)?;
+        if self.is_mmio(address) && let Some(word) = self.devices.read(address)? {
+            return Ok(word);
         }
+        Ok(self.memory[address])
     }
 
-    // Helper function to parse memory addressing modes for LDR/STR
-    fn parse_address_operand(
-        operand_str: &str,
-        registers: &[i32],
-        labels: &HashMap<String, usize>,
-        report_fn: &dyn Fn(&str), // For reporting errors
-    ) -> Option<usize> {
-        let trimmed_operand = operand_str.trim();
+    /// Writes a memory word for the program, counting the access, or fails outside memory or its map
+    /// and in read-only regions. In an mmio region, the device there takes the word.
+    pub fn store(&mut self, address: usize, value: i32) -> Result<(), Box<Diagnostic>> {
+        check_bounds(address, self.memory.len())?;
+        count_access(&mut self.accesses, &mut self.cache, &self.memory_map, address, true)?;
+        if self.is_mmio(address) && self.devices.write(address, value)? {
+            return Ok(());
+        }
+        self.memory[address] = value;
+        Ok(())
+    }
 
-        if trimmed_operand.starts_with('[') && trimmed_operand.ends_with(']') {
-            // Register indirect or register indirect with offset
-            let inner = &trimmed_operand[1..trimmed_operand.len()-1];
-            let parts: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
+    fn is_mmio(&self, address: usize) -> bool {
+        self.memory_map.as_ref().is_some_and(|map| map.is_mmio(address))
+    }
 
-            if parts.len() == 1 { // [Rx]
-                let reg_name = parts[0];
-                if let Some(reg_idx) = parse_register(reg_name) {
-                    return Some(registers[reg_idx] as usize);
-                } else {
-                    report_fn(&format!("Invalid register in address operand: {}", reg_name));
-                    return None;
-                }
-            } else if parts.len() == 2 { // [Rx, #offset] or [Rx, label_as_offset] - simplified to #offset
-                let reg_name = parts[0];
-                let offset_str = parts[1];
-
-                if let Some(reg_idx) = parse_register(reg_name) {
-                    let base_address = registers[reg_idx] as usize;
-                    if offset_str.starts_with('#') {
-                        // Using parse_value to handle #hex and #dec for offset
-                        if let Some(offset_val) = parse_value(offset_str, registers) { // Pass empty registers, not used for #
-                             // Ensure offset is treated as usize for address calculation
-                            if offset_val < 0 {
-                                // Handle negative offsets by subtracting magnitude
-                                return Some(base_address.saturating_sub(offset_val.abs() as usize));
-                            } else {
-                                return Some(base_address.saturating_add(offset_val as usize));
-                            }
-                        } else {
-                            report_fn(&format!("Invalid immediate offset in address operand: {}", offset_str));
-                            return None;
-                        }
-                    } else {
-                        report_fn("Offset in [Reg, Offset] must be an immediate value starting with #.");
-                        return None;
-                    }
-                } else {
-                    report_fn(&format!("Invalid register in address operand: {}", reg_name));
-                    return None;
-                }
-            } else {
-                report_fn(&format!("Invalid address operand format: {}", trimmed_operand));
-                return None;
-            }
-        } else if trimmed_operand.starts_with('#') {
-            // Immediate address #0x... or #...
-            // Using parse_value, but its return is i32, address should be usize
-            if let Some(addr_val) = parse_value(trimmed_operand, registers) { // Pass empty registers
-                if addr_val < 0 {
-                    report_fn(&format!("Memory address cannot be negative: {}", addr_val));
-                    return None;
-                }
-                return Some(addr_val as usize);
-            } else {
-                report_fn(&format!("Invalid immediate address: {}", trimmed_operand));
-                return None;
-            }
-        } else {
-            // Label
-            if let Some(addr) = labels.get(trimmed_operand) {
-                return Some(*addr);
-            } else {
-                report_fn(&format!("Undefined label: {}", trimmed_operand));
-                return None;
-            }
+    /// Lays out memory by `map`: as large as its regions, with the data labels in its first `rw` region.
+    pub fn map_memory(&mut self, map: MemoryMap) {
+        self.resize_memory(map.words().max(self.memory.len()));
+        self.accesses = Accesses::of_ranges(map.regions().iter().map(|region| region.start..region.end()).collect());
+        if self.labels.is_empty() {
+            self.next_label_mem_addr = map.data_start();
         }
+        self.memory_map = Some(map);
     }
 
-    loop {
-        if interactive { print!("> "); }
-        io::stdout().flush().unwrap();
+    /// Makes memory `words` words long, for addresses past MEMORY_SIZE, counting accesses afresh.
+    pub fn resize_memory(&mut self, words: usize) {
+        if words != self.memory.len() {
+            self.memory.resize(words);
+            self.accesses = Accesses::new(words);
+        }
+    }
+
+    /// An empty literal pool after the labels defined so far.
+    pub fn literal_pool(&self) -> LiteralPool {
+        LiteralPool::new(self.next_label_mem_addr, self.memory.len())
+    }
+
+    /// Writes the words of `pool` to memory, after which new labels come.
+    pub fn fill_literal_pool(&mut self, pool: &LiteralPool) {
+        for (address, word) in pool.words() {
+            self.memory[address] = word;
+            self.next_label_mem_addr = address + 1;
+        }
+    }
 
-        let mut input_line = String::new();
-        if reader.read_line(&mut input_line).unwrap() == 0 {
-            break; // EOF
+    // Applies the initial memory values requested in `options`
+    fn seed_memory(&mut self, options: &RunOptions) {
+        for &(addr, val) in &options.memory {
+            self.memory[addr] = val;
         }
+    }
+}
+
+impl AsRef<Machine> for Machine {
+    fn as_ref(&self) -> &Machine {
+        self
+    }
+}
+
+impl AsMut<Machine> for Machine {
+    fn as_mut(&mut self) -> &mut Machine {
+        self
+    }
+}
+
+/// How closely a program must keep to what a real ARM assembler accepts, for `--profile`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+    /// Accept anything the interpreter can run, such as any 32-bit immediate
+    #[default]
+    Teaching,
+    /// Reject what a real assembler would, like --strict-imm and --strict together with the operand rules of ARMv4
+    Strict,
+}
+
+/// Settings for a run, given on the command line.
+#[derive(Default)]
+pub struct RunOptions {
+    /// Label to start execution at, instead of the default entry point
+    pub entry: Option<String>,
+    /// Initial register values as (register index, value)
+    pub registers: Vec<(usize, i32)>,
+    /// Initial memory words as (address, value)
+    pub memory: Vec<(usize, i32)>,
+    /// The words of memory, for addresses past MEMORY_SIZE; None for MEMORY_SIZE. Pages are only allocated once written.
+    pub memory_words: Option<usize>,
+    /// The regions of memory and what a program may do in each, which also size memory unless `memory_words` is set
+    pub memory_map: Option<MemoryMap>,
+    /// The board whose startup to follow: its memory map unless `memory_map` is set, the stack pointer from its vector table and its reset handler as the entry point
+    pub target: Option<Target>,
+    /// Stop with an error on signed arithmetic overflow instead of wrapping around
+    pub trap_overflow: bool,
+    /// The bits in a word, 8 or 16 to emulate a small machine, or None for the instruction set's own
+    pub word_size: Option<u32>,
+    /// Make the string helpers of SVC, strlen, strcmp and memcpy, an error, for labs where the program writes them
+    pub no_helpers: bool,
+    /// Print the call graph after the run
+    pub call_graph: bool,
+    /// Where to write the call graph with instruction counts after the run, as JSON for .json and otherwise DOT
+    pub call_graph_file: Option<String>,
+    /// Stop with an error after this many instructions, for programs that may never end
+    pub max_steps: Option<u64>,
+    /// The only mnemonics a program may use, if not empty, for exercises; each also allows its variants, such as ADDS and BEQ
+    pub allow_only: Vec<String>,
+    /// Mnemonics a program may not use, with their variants
+    pub forbid: Vec<String>,
+    /// Stop with an error once the run takes more cycles than this, instead of any `.budget cycles` of the program
+    pub cycle_budget: Option<u64>,
+    /// Stops the running program when requested, e.g. on Ctrl-C
+    pub interrupt: Interrupt,
+    /// Pause and single-step with the keyboard while running in a terminal
+    pub hotkeys: bool,
+    /// Stop with an error at BKPT instead of printing the registers and continuing
+    pub strict_bkpt: bool,
+    /// Reject immediates that no real encoding of their instruction has
+    pub strict_imm: bool,
+    /// Stop with an error instead of warning about immediates cut down to fit their fields
+    pub strict: bool,
+    /// Which operands and immediates are accepted
+    pub profile: Profile,
+    /// Where to pause like at a BKPT, e.g. `loop if r2 > 100`
+    pub breakpoints: Vec<Breakpoint>,
+    /// Debugger commands to run at the start, at each pause and at the end, instead of the hotkeys
+    pub debug_script: Option<DebugScript>,
+    /// Print the registers, the top of the stack and the last instructions run when the program stops with an error
+    pub post_mortem: bool,
+    /// Where to write the memory heatmap after the run: a .csv, .png or text file, or `-` for the console
+    pub heatmap: Option<String>,
+    /// The data cache to simulate, reporting its hits and misses after the run
+    pub cache: Option<CacheConfig>,
+    /// Where to write an HTML report of the run, with the source, the trace and the final state
+    pub html: Option<String>,
+    /// Where to write a snapshot of the final state, for `asm diff`
+    pub snapshot: Option<String>,
+    /// Write a snapshot every this many instructions to the directory, as `step-N.json`
+    pub checkpoints: Option<(u64, PathBuf)>,
+    /// How many writes of each register HISTORY keeps, or None for [`crate::history::HISTORY_DEPTH`]
+    pub history_size: Option<usize>,
+    /// Where to write what each step changed, as text or JSON
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    /// Memory regions whose changed words to write as JSON events, and where: a file, or `-` for stdout
+    pub watch_memory: Option<(Vec<WatchRange>, String)>,
+    /// Record what each instruction after this many steps changed, in the deltas of the final state; Some(0) records every one
+    pub deltas: Option<u64>,
+    /// Registers, memory addresses and data labels whose values to follow through the run, reporting what they reached
+    pub taint: Vec<String>,
+    /// Start registers and memory the program does not set as undefined, warning when a branch, an address or PRINT uses one
+    pub undefined: bool,
+    /// Warn about instructions with no effect and registers written again before they are read
+    pub lint: bool,
+    /// How many cores run the program, sharing its memory; 0 and 1 both run one
+    pub cores: usize,
+    /// How the cores take turns
+    pub interleaving: Interleaving,
+    /// Where to write the turns the cores ran, to replay them later
+    pub record_schedule: Option<String>,
+    /// Turns for the cores to run before the interleaving picks, from an earlier run
+    pub replay_schedule: Option<Schedule>,
+    /// The memory address or data label whose word holds the last key pressed in the terminal
+    pub keyboard: Option<String>,
+    /// The radix registers are shown in
+    pub formats: Formats,
+    /// The file interactive mode saves its snippets to, None to keep them for the session only
+    pub snippets: Option<PathBuf>,
+    /// The prompt of interactive mode, with fields filled in from the session
+    pub prompt: Prompt,
+    /// Devices, checks and trace filters to run with the program, for --script
+    pub script: Option<Script>,
+}
 
-        // First, trim whitespace from the raw line
-        let effective_line = input_line.trim();
+/// A request to stop a running program from another thread, such as a Ctrl-C
+/// handler. Clones share the request.
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    requested: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+}
 
-        // Strip any comment part (from "//" to the end of the line)
-        let mut comment_stripped_line = effective_line;
-        if let Some(comment_start_index) = comment_stripped_line.find("//") {
-            comment_stripped_line = &comment_stripped_line[..comment_start_index].trim_end();
+impl Interrupt {
+    /// Asks the running program to stop before its next instruction. Returns false
+    /// if no program is running, e.g. while interactive mode waits for a line.
+    pub fn request(&self) -> bool {
+        if !self.running.load(Ordering::SeqCst) {
+            return false;
         }
+        self.requested.store(true, Ordering::SeqCst);
+        true
+    }
 
-        // Skip if the line is now empty (was blank or only a comment)
-        if comment_stripped_line.is_empty() {
-            continue;
+    // Marks a program as running until the guard is dropped, dropping any request left over
+    fn start(&self) -> Running<'_> {
+        self.requested.store(false, Ordering::SeqCst);
+        self.running.store(true, Ordering::SeqCst);
+        Running(self)
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+}
+
+struct Running<'a>(&'a Interrupt);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+        self.0.requested.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The registers, flags and memory a program left behind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FinalState {
+    /// Register names and values, in the order of the instruction set's register file
+    pub registers: Vec<(&'static str, i64)>,
+    /// Condition flags and whether each is set, e.g. ("N", true)
+    pub flags: Vec<(&'static str, bool)>,
+    pub memory: Memory,
+    /// Labels and their memory addresses
+    pub labels: HashMap<String, usize>,
+    /// The first memory word after the data labels and the literal pool
+    pub data_end: usize,
+    /// What each instruction changed, if the run recorded it with [`RunOptions::deltas`]
+    pub deltas: Vec<StateDelta>,
+}
+
+impl FinalState {
+    /// Captures the state of a core.
+    pub fn of<I: Isa>(isa: &I, cpu: &I::Cpu) -> Self {
+        let machine = cpu.as_ref();
+        FinalState { labels: machine.labels.clone(), deltas: machine.deltas.clone(), ..FinalState::registers_flags_memory(isa, cpu) }
+    }
+
+    // The registers, flags and memory of a core without its labels and deltas, to compare before and after an instruction
+    fn registers_flags_memory<I: Isa>(isa: &I, cpu: &I::Cpu) -> Self {
+        let machine = cpu.as_ref();
+        FinalState {
+            registers: I::REGISTERS.names.iter().enumerate().map(|(index, &name)| (name, isa.read_register(cpu, index))).collect(),
+            flags: isa.flags(cpu),
+            memory: machine.memory.clone(),
+            labels: HashMap::new(),
+            data_end: machine.next_label_mem_addr,
+            deltas: Vec::new(),
         }
+    }
 
-        // Now, use 'comment_stripped_line' for all further processing
-        if comment_stripped_line.eq_ignore_ascii_case("EXIT") {
-            break;
+    /// The value of a register, by name in any case.
+    pub fn register(&self, name: &str) -> Option<i64> {
+        self.registers.iter().find(|(reg, _)| reg.eq_ignore_ascii_case(name)).map(|&(_, value)| value)
+    }
+
+    /// Whether a flag is set, by name in any case.
+    pub fn flag(&self, name: &str) -> Option<bool> {
+        self.flags.iter().find(|(flag, _)| flag.eq_ignore_ascii_case(name)).map(|&(_, set)| set)
+    }
+
+    /// The memory word at a label.
+    pub fn word(&self, label: &str) -> Option<i32> {
+        self.labels.get(label).map(|&address| self.memory[address])
+    }
+}
+
+// Expressions over the final state, such as the goals of exercises
+impl expression::Context for FinalState {
+    fn value(&self, name: &str) -> Option<i64> {
+        self.register(name)
+            .or_else(|| self.flags.iter().find(|(flag, _)| *flag == name).map(|&(_, set)| set as i64))
+            .or_else(|| self.labels.get(name).map(|&address| address as i64))
+    }
+
+    fn word(&self, address: i64) -> Option<i64> {
+        usize::try_from(address).ok().and_then(|address| self.memory.get(address)).map(|&word| word as i64)
+    }
+}
+
+/// An instruction line of a loaded program, together with where it came from.
+pub struct SourceLine {
+    pub file: String,
+    pub line_no: usize,
+    // The full line as written, for diagnostics
+    pub source: String,
+    // Byte range of the instruction within `source` (labels and comments removed)
+    start: usize,
+    end: usize,
+    /// The `.local` variable the instruction accesses, shown in the trace
+    pub local: Option<String>,
+    /// The line of C (or other) source the instruction was generated from, if annotated
+    pub origin: Option<Origin>,
+}
+
+impl SourceLine {
+    #[cfg(test)]
+    pub(crate) fn new(file: &str, line_no: usize, source: &str) -> Self {
+        SourceLine { file: file.to_string(), line_no, source: source.to_string(), start: 0, end: source.len(), local: None, origin: None }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.source[self.start..self.end]
+    }
+
+    // Attributes errors to the generated-from line as well
+    fn origin_note(&self) -> Option<String> {
+        self.origin.as_ref().map(|origin| format!("generated from {}", origin))
+    }
+}
+
+/// A line of the source a program was compiled from, set by `.loc` or `#line`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// One or more source files linked into a single instruction stream.
+pub struct Program<I = Instruction> {
+    pub lines: Vec<SourceLine>,
+    // The decoded instruction of each line
+    pub instructions: Vec<I>,
+    // Code labels and the index of the instruction they point to
+    pub code_labels: HashMap<String, usize>,
+    // The cycles `.budget cycles` allows the run, and where it was set
+    pub cycle_budget: Option<(u64, String)>,
+}
+
+/// A source operand: an immediate, or a register read when the instruction executes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Immediate(i32),
+    Register(usize),
+}
+
+impl Operand {
+    fn value(self, registers: &[i32]) -> i32 {
+        match self {
+            Operand::Immediate(val) => val,
+            Operand::Register(idx) => registers[idx],
         }
+    }
+}
+
+/// The memory address operand of LDR/STR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressOperand {
+    // [Rx] or [Rx, #offset]
+    RegisterOffset(usize, i32),
+    // #address or a label, known when decoding
+    Absolute(usize),
+}
 
-        let mut line_to_parse = comment_stripped_line;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AluOp { Add, Sub, Adc, Sbc, Mul, And, Orr, Bic, Eor }
 
-        // Label detection and processing
-        if let Some(colon_index) = line_to_parse.find(':') {
-            let label_candidate = line_to_parse[..colon_index].trim();
-            let rest_of_line_after_colon = line_to_parse[colon_index + 1..].trim();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShiftOp { Lsl, Lsr, Asr, Ror }
 
-            if !label_candidate.is_empty() && !label_candidate.contains(char::is_whitespace) {
-                // Valid label format
-                if labels.contains_key(label_candidate) {
-                    report_error(interactive, &format!("Duplicate label definition: {}", label_candidate));
-                    continue; // Skip this line
-                }
-                if next_label_mem_addr >= MEMORY_SIZE {
-                    report_error(interactive, "Out of memory for new labels/data.");
-                    continue; // Skip this line
-                }
+/// The condition of a conditional branch, tested against the NZCV flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition { Eq, Ne, Cs, Cc, Mi, Pl, Vs, Vc, Hi, Ls, Ge, Lt, Gt, Le, Al }
 
-                let current_label_address = next_label_mem_addr;
-                labels.insert(label_candidate.to_string(), current_label_address);
-                
-                // Check if there's a data initializer like #value
-                if !rest_of_line_after_colon.is_empty() && rest_of_line_after_colon.starts_with('#') {
-                    let value_str = rest_of_line_after_colon;
-                    let parsed_val: Option<i32>;
-                    if value_str.starts_with("#0x") || value_str.starts_with("#0X") {
-                        // Ensure there are characters after #0x for parsing
-                        if value_str.len() > 3 {
-                            parsed_val = i32::from_str_radix(&value_str[3..], 16).ok();
-                        } else {
-                            parsed_val = None;
-                        }
-                    } else {
-                        // Ensure there are characters after # for parsing
-                        if value_str.len() > 1 {
-                            parsed_val = value_str[1..].parse::<i32>().ok();
-                        } else {
-                            parsed_val = None;
-                        }
-                    }
+impl Condition {
+    fn holds(self, cpsr: u32) -> bool {
+        let (n, z, c, v) = (cpsr & CPSR_N != 0, cpsr & CPSR_Z != 0, cpsr & CPSR_C != 0, cpsr & CPSR_V != 0);
+        match self {
+            Condition::Eq => z,
+            Condition::Ne => !z,
+            Condition::Cs => c,
+            Condition::Cc => !c,
+            Condition::Mi => n,
+            Condition::Pl => !n,
+            Condition::Vs => v,
+            Condition::Vc => !v,
+            Condition::Hi => c && !z,
+            Condition::Ls => !c || z,
+            Condition::Ge => n == v,
+            Condition::Lt => n != v,
+            Condition::Gt => !z && n == v,
+            Condition::Le => z || n != v,
+            Condition::Al => true,
+        }
+    }
+}
 
-                    if let Some(val) = parsed_val {
-                        memory[current_label_address] = val;
-                        if interactive {
-                            println!("Label '{}' defined at memory address {}, initialized with value {}", 
-                                     label_candidate, current_label_address, val);
-                        }
-                        next_label_mem_addr += 1; // Consume memory slot for data
-                        continue; // This line was a label with data definition, fully processed.
-                    } else {
-                        report_error(interactive, &format!("Invalid value for label data initialization: {}. Expected format like #123 or #0xFF.", value_str));
-                        labels.remove(label_candidate); // Rollback label definition
-                        continue; // Skip this erroneous line
-                    }
-                } else {
-                    // This is "label:" (rest_of_line_after_colon is empty)
-                    // or "label: instruction" (rest_of_line_after_colon has an instruction)
-                    if interactive {
-                         println!("Label '{}' defined at memory address {}", label_candidate, current_label_address);
-                    }
-                    next_label_mem_addr += 1; // Consume memory slot for the label definition itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VfpOp { Add, Sub, Mul, Div }
 
-                    line_to_parse = rest_of_line_after_colon; // Continue parsing the rest of the line (if any)
-                    // If line_to_parse is empty (was just "label:"), the check below will handle it.
-                }
-            }
+/// A parallel add or subtract on packed bytes or halfwords: signed or unsigned
+/// (setting the GE flags), or signed or unsigned saturating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParallelOp { Sadd, Uadd, Ssub, Usub, Qadd, Qsub, Uqadd, Uqsub }
+
+/// The source operand of VMOV into a floating-point register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FpSource {
+    // The bits of a float immediate such as #1.5
+    Immediate(u32),
+    // Another floating-point register
+    Register(usize),
+    // The raw bits of a core register
+    Core(usize),
+}
+
+/// An instruction with its operands checked and parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Mov { rd: usize, src: Operand },
+    // ADDS and friends also set the NZCV flags
+    Alu { op: AluOp, set_flags: bool, rd: usize, rn: usize, op2: Operand },
+    Cmp { rn: usize, op2: Operand },
+    Cmn { rn: usize, op2: Operand },
+    // Branch targets are instruction indices
+    Branch { cond: Condition, target: usize },
+    // Saves the return address (the next instruction index) in r14
+    Bl { target: usize },
+    Bx { rm: usize },
+    // LSLS and friends also set N, Z and C
+    Shift { op: ShiftOp, set_flags: bool, rd: usize, rm: usize, amount: Operand },
+    Rrx { rd: usize, rm: usize },
+    Ldr { rd: usize, address: AddressOperand },
+    Str { rs: usize, address: AddressOperand },
+    // LDREX reserves the address [rn]; STREX stores rs there only if no store came since, setting rd to 0 if it did and 1 if not
+    Ldrex { rd: usize, rn: usize },
+    Strex { rd: usize, rs: usize, rn: usize },
+    // Drops the reservation of LDREX
+    Clrex,
+    // The address of a label: the instruction index of code, the memory address of data
+    Adr { rd: usize, address: i32 },
+    // Calls a service of the interpreter, such as creating a task
+    Svc { number: u32 },
+    // An instruction a plugin added, by its index among those of all plugins
+    Plugin { index: usize, operands: Vec<plugin::Operand> },
+    // DMB, DSB and ISB, which have nothing to wait for since every access completes in order
+    Barrier { kind: Barrier },
+    Vfp { op: VfpOp, sd: usize, sn: usize, sm: usize },
+    Vmov { sd: usize, src: FpSource },
+    // VMOV rd, sn copies the raw bits of a floating-point register
+    VmovToCore { rd: usize, sn: usize },
+    Vldr { sd: usize, address: AddressOperand },
+    Vstr { sd: usize, address: AddressOperand },
+    // VCVT.S32.F32 (to_int) or VCVT.F32.S32, where the integer is held in an s register
+    Vcvt { to_int: bool, sd: usize, sm: usize },
+    // MLA rd, rn, rm, ra gives ra + rn * rm and MLS ra - rn * rm
+    MultiplyAccumulate { subtract: bool, set_flags: bool, rd: usize, rn: usize, rm: usize, ra: usize },
+    // SMUL<x><y> and SMLA<x><y>: signed multiply of the bottom or top halfwords, plus ra for SMLA
+    HalfwordMultiply { rd: usize, rn: usize, rm: usize, rn_top: bool, rm_top: bool, ra: Option<usize> },
+    // Lanes are 8 or 16 bits wide
+    Parallel { op: ParallelOp, lane_bits: u32, rd: usize, rn: usize, rm: usize },
+    // Picks each byte from rn where its GE flag is set, else from rm
+    Sel { rd: usize, rn: usize, rm: usize },
+    Mrs { rd: usize },
+    // Writes the bits of `mask` allowed by the selected fields
+    Msr { mask: u32, src: Operand },
+    // The register name is printed as written, and as a Qn fixed-point number with `fraction_bits`
+    Print { reg: usize, name: String, fraction_bits: Option<u32> },
+    PrintFloat { reg: usize, name: String },
+    // BKPT #0xAB calls the host; other numbers are for the debugger
+    Bkpt { number: u32 },
+    Exit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Barrier {
+    Dmb,
+    Dsb,
+    Isb,
+}
+
+// The options a barrier may name the shareability domain and the accesses it orders with
+const BARRIER_OPTIONS: &[&str] = &["SY", "ST", "LD", "ISH", "ISHST", "ISHLD", "NSH", "NSHST", "NSHLD", "OSH", "OSHST", "OSHLD"];
+
+/// The 32-bit ARM instruction set, the default front-end.
+pub struct Arm;
+
+pub const ARM_REGISTERS: RegisterFile = RegisterFile {
+    names: &["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"],
+    bits: 32,
+};
+
+impl Isa for Arm {
+    type Instruction = Instruction;
+    type Cpu = Machine;
+
+    const NAME: &'static str = "arm";
+    const DESCRIPTION: &'static str = "32-bit ARM: any register and immediate in every operand";
+    const INSTRUCTIONS: &'static [InstructionInfo] = instructions::INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &ARM_REGISTERS;
+    const STACK_POINTER: Option<usize> = Some(STACK_POINTER);
+    const CORE_REGISTER: usize = 0;
+
+    fn new_cpu(&self) -> Machine {
+        Machine::new()
+    }
+
+    fn read_register(&self, machine: &Machine, index: usize) -> i64 {
+        machine.registers[index] as i64
+    }
+
+    fn flags(&self, machine: &Machine) -> Vec<(&'static str, bool)> {
+        [("N", CPSR_N), ("Z", CPSR_Z), ("C", CPSR_C), ("V", CPSR_V), ("Q", CPSR_Q)].into_iter()
+            .map(|(name, bit)| (name, machine.cpsr & bit != 0))
+            .collect()
+    }
+
+    fn seed_registers(&self, machine: &mut Machine, registers: &[(usize, i32)]) {
+        for &(reg, val) in registers {
+            machine.registers[reg] = val;
         }
+    }
 
-        // If line_to_parse is empty at this point (e.g., after processing "label:" or "label: #data"), skip instruction parsing.
-        if line_to_parse.is_empty() {
-            continue;
+    // sp, lr and fp as well as r0 to r15
+    fn register_index(&self, name: &str) -> Option<usize> {
+        parse_register(name)
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        decode(tokens, labels, report)
+    }
+
+    fn prepare(&self, machine: &mut Machine, len: usize) {
+        // BX r14 from the entry point ends the program, as it does from a task's
+        machine.registers[LINK_REGISTER] = len as i32;
+        machine.tasks.end = len;
+    }
+
+    fn execute(&self, machine: &mut Machine, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        // The flags are the top bits of the CPSR, which a narrower register would lose
+        if machine.word_bits < 32 && matches!(instruction, Instruction::Mrs { .. }) {
+            return Err(Box::new(Diagnostic::error(Code::UnsupportedOption,
+                format!("MRS cannot copy the CPSR into words of {} bits", machine.word_bits))
+                .with_note("the flags are bits 27 to 31 of the CPSR; run without --word-size to read them with MRS, or test them with conditional instructions")));
+        }
+        let flow = execute(machine, instruction, pc, console);
+        // Results wrap around at the word size, whichever instruction wrote them
+        if machine.word_bits < 32 {
+            machine.narrow_registers();
         }
+        flow
+    }
 
-        // Instruction parsing starts here, using line_to_parse
-        let parts: Vec<&str> = line_to_parse.split_whitespace().collect();
-        // parts.is_empty() should not happen here due to the effective_line.is_empty() check above,
-        // but an extra check or assertion wouldn't hurt if you want to be extremely defensive.
-        if parts.is_empty() { // Should be redundant due to check above, but safe.
-            continue;
+    fn narrow(&self, machine: &mut Machine, bits: u32) -> bool {
+        machine.narrow(bits);
+        true
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Branch { .. } | Instruction::Bl { .. } | Instruction::Bx { .. })
+    }
+
+    fn frame_offset(&self, instruction: &Instruction) -> Option<i32> {
+        match *instruction {
+            Instruction::Ldr { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. }
+            | Instruction::Str { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. }
+            | Instruction::Vldr { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. }
+            | Instruction::Vstr { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. } => Some(offset),
+            _ => None,
         }
-        
-        let report_fn_closure = |msg: &str| report_error(interactive, msg);
+    }
 
-        match parts[0].to_uppercase().as_str() {
-            "MOV" => {
-                if parts.len() != 3 {
-                    report_error(interactive, "Usage: MOV <register>, <value>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register in MOV");
-                    continue;
-                }
-                let reg_name = parts[1].trim_end_matches(',');
-                if let Some(idx) = parse_register(reg_name) {
-                    if let Some(val) = parse_value(parts[2], &registers) {
-                        registers[idx] = val;
-                    } else {
-                        report_error(interactive, "Invalid operand for MOV. Use immediate with '#' (e.g. \"#0x10\" or \"#15\") or a valid register.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Bl { .. })
+    }
+
+    fn call_target(&self, instruction: &Instruction) -> Option<usize> {
+        match instruction {
+            Instruction::Bl { target } => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn data_flow(&self, machine: &Machine, instruction: &Instruction) -> Option<DataFlow> {
+        Some(data_flow(machine, instruction))
+    }
+
+    fn is_return(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Bx { rm: LINK_REGISTER })
+    }
+
+    fn documented(&self, mnemonic: &str) -> Option<&'static InstructionInfo> {
+        documented(mnemonic)
+    }
+
+    fn remark(&self, instruction: &Instruction) -> Option<&'static str> {
+        match instruction {
+            Instruction::Barrier { kind: Barrier::Dmb } => Some("memory barrier: no effect, every access completes in program order"),
+            Instruction::Barrier { kind: Barrier::Dsb } => Some("synchronization barrier: no effect, every access completes before the next instruction"),
+            Instruction::Barrier { kind: Barrier::Isb } => Some("instruction barrier: no effect, no instruction is fetched ahead"),
+            _ => None,
+        }
+    }
+
+    fn check_immediates(&self, instruction: &Instruction) -> Result<(), String> {
+        encoding::check_immediates(instruction)
+    }
+
+    fn check_operands(&self, instruction: &Instruction) -> Result<(), String> {
+        match *instruction {
+            // MUL Rd, Rm, Rs is unpredictable on ARMv4 and ARMv5 when Rd is Rm
+            Instruction::Alu { op: AluOp::Mul, rd, rn, op2, .. } if rd == rn => Err(match op2 {
+                Operand::Register(rm) if rm != rd => format!("MUL cannot write the register it multiplies before ARMv6; swap the operands: MUL {0}, {1}, {0}",
+                    ARM_REGISTERS.names[rd], ARM_REGISTERS.names[rm]),
+                _ => "MUL cannot write the register it multiplies before ARMv6; multiply into another register".to_string(),
+            }),
+            Instruction::MultiplyAccumulate { subtract: false, rd, rn, rm, ra, .. } if rd == rn => Err(if rm != rd {
+                format!("MLA cannot write the register it multiplies before ARMv6; swap the operands: MLA {0}, {1}, {0}, {2}",
+                    ARM_REGISTERS.names[rd], ARM_REGISTERS.names[rm], ARM_REGISTERS.names[ra])
+            } else {
+                "MLA cannot write the register it multiplies before ARMv6; multiply into another register".to_string()
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn truncation(&self, instruction: &Instruction, word_bits: u32) -> Option<String> {
+        match *instruction {
+            Instruction::Mov { src: Operand::Immediate(value), .. }
+            | Instruction::Alu { op2: Operand::Immediate(value), .. }
+            | Instruction::Cmp { op2: Operand::Immediate(value), .. }
+            | Instruction::Cmn { op2: Operand::Immediate(value), .. } if word_bits < 32 => {
+                // A word holds the value as a signed or an unsigned number
+                let (least, most) = (-(1i64 << (word_bits - 1)), (1i64 << word_bits) - 1);
+                if (least..=most).contains(&i64::from(value)) {
+                    return None;
                 }
+                Some(format!("words of {} bits hold {} to {}, so #{} becomes {}", word_bits, least, most, value, narrow(value, word_bits)))
             },
-            "ADD" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: ADD <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in ADD");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    // The first operand must be a register
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        // The second operand may be an immediate or a register
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val + op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for ADD. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for ADD must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register. Use r0 through r15.");
+            Instruction::Shift { op, amount: Operand::Immediate(amount), .. } => {
+                // An immediate shift has 5 bits, with LSR #32 and ASR #32 encoded as 0
+                let most = if matches!(op, ShiftOp::Lsr | ShiftOp::Asr) { 32 } else { 31 };
+                if (0..=most).contains(&amount) {
+                    return None;
                 }
+                let effect = match op {
+                    ShiftOp::Ror => format!("rotates by {}", amount.rem_euclid(32)),
+                    ShiftOp::Asr => "fills every bit with the sign bit".to_string(),
+                    ShiftOp::Lsl | ShiftOp::Lsr => "shifts every bit out".to_string(),
+                };
+                Some(format!("a shift amount is 0 to 31 (LSR and ASR also take 32), and #{} {}", amount, effect))
             },
-            "SUB" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: SUB <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in SUB");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val - op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for SUB. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for SUB must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register. Use r0 through r15.");
-                }
+            _ => None,
+        }
+    }
+
+    // Roughly a Cortex-M3: a memory access or a multiply-accumulate takes two cycles
+    fn cycles(&self, instruction: &Instruction, taken: bool) -> u64 {
+        let base = match instruction {
+            Instruction::Ldr { .. } | Instruction::Str { .. } | Instruction::Ldrex { .. } | Instruction::Strex { .. }
+                | Instruction::Vldr { .. } | Instruction::Vstr { .. } | Instruction::MultiplyAccumulate { .. } => 2,
+            _ => 1,
+        };
+        if taken { base + 2 } else { base }
+    }
+
+    fn no_effect(&self, instruction: &Instruction) -> Option<&'static str> {
+        match *instruction {
+            Instruction::Mov { rd, src: Operand::Register(rm) } if rd == rm => Some("it copies the register to itself"),
+            Instruction::Alu { op: AluOp::Add | AluOp::Sub | AluOp::Orr | AluOp::Eor | AluOp::Bic, set_flags: false, rd, rn, op2: Operand::Immediate(0) }
+                if rd == rn => Some("an operand of #0 leaves the register as it was"),
+            Instruction::Shift { set_flags: false, rd, rm, amount: Operand::Immediate(0), .. } if rd == rm => Some("a shift by #0 leaves the register as it was"),
+            _ => None,
+        }
+    }
+
+    fn encode(&self, instruction: &Instruction) -> Result<Encoding, String> {
+        encoding::encode(instruction)
+    }
+}
+
+/// Runs interactive mode on the process's stdin and stdout.
+pub fn interactive(front_end: &dyn FrontEnd, options: &RunOptions){
+    let (mut stdin, mut stdout) = (io::stdin().lock(), io::stdout());
+    front_end.interactive(&mut Console::new(&mut stdin, &mut stdout), options);
+}
+
+/// Runs lines read from `console` one at a time until the end of input or EXIT,
+/// printing the diagnostics of each line.
+pub fn repl<I: Isa>(isa: &I, console: &mut Console, options: &RunOptions) {
+    if let Err(error) = check_word_size(isa, options).and_then(|()| check_memory(options)).and_then(|()| check_target::<I>(options)) {
+        diagnostics::emit(&[*error]);
+        return;
+    }
+    let mut cpu = isa.new_cpu();
+    seed(isa, &mut cpu, options);
+    isa.prepare(&mut cpu, REPL_END);
+    // Snippets that cannot be read are not saved either, so the file is left for the user to fix
+    let snippets = Snippets::load(options.snippets.clone()).unwrap_or_else(|error| {
+        diagnostics::emit(&[error.with_note("snippets defined now are kept for this session only")]);
+        Snippets::default()
+    });
+    let mut repl = Repl { cpu, entered: Program { lines: Vec::new(), instructions: Vec::new(), code_labels: HashMap::new(), cycle_budget: None }, snippets, echo: false, options };
+    let mut line_no = 0;
+
+    loop {
+        // Output that cannot be written ends the session like the end of input
+        if console.prompt(&prompt_text(isa, &repl)).is_err() {
+            break;
+        }
+
+        let input_line = match console.read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                break;
             },
-            "ADC" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: ADC <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in ADC");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            // ADC: result = op1 + op2 + CPSR. Using overflowing add to update CPSR.
-                            let (sum, carry1) = (op1_val as u32).overflowing_add(op2_val as u32);
-                            let (result, carry2) = sum.overflowing_add(cpsr);
-                            registers[idx_dest] = result as i32;
-                            cpsr = if carry1 || carry2 { 1 } else { 0 };
-                        } else {
-                            report_error(interactive, "Invalid second operand for ADC. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for ADC must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register in ADC. Use r0 through r15.");
-                }
+        };
+        line_no += 1;
+
+        let source_line = input_line.trim_end();
+        if strip_comment(source_line).eq_ignore_ascii_case("BEGIN") {
+            let block = read_block(console, &mut line_no);
+            if !execute_repl_block(isa, &mut repl, &block, console) {
+                break;
+            }
+            continue;
+        }
+        if let [def, name] = strip_comment(source_line).split_whitespace().collect::<Vec<_>>()[..]
+            && def.eq_ignore_ascii_case("DEF") {
+            let block = read_block(console, &mut line_no);
+            define_snippet(isa, &mut repl, name, &block, console);
+            continue;
+        }
+        let errors = RefCell::new(Vec::new());
+        let report = Reporter::new(&errors, "<stdin>", line_no, source_line);
+
+        let keep_running = execute_repl_line(isa, &mut repl, source_line, line_no, &report, console);
+        diagnostics::emit(&errors.borrow());
+        if !keep_running {
+            break;
+        }
+    }
+}
+
+// The prompt of `--prompt` with its fields filled in: registers, flags, pc (the
+// number of the next instruction typed) and data labels
+fn prompt_text<I: Isa>(isa: &I, repl: &Repl<I>) -> String {
+    let machine = repl.cpu.as_ref();
+    repl.options.prompt.render(|name| {
+        if name.eq_ignore_ascii_case("pc") {
+            return Some(repl.entered.instructions.len().to_string());
+        }
+        if let Some(index) = I::REGISTERS.names.iter().position(|register| register.eq_ignore_ascii_case(name)) {
+            let register = I::REGISTERS.names[index];
+            return Some(machine.formats.format(register, isa.read_register(&repl.cpu, index), I::REGISTERS.bits));
+        }
+        if let Some(&(_, set)) = isa.flags(&repl.cpu).iter().find(|(flag, _)| *flag == name) {
+            return Some((set as u8).to_string());
+        }
+        machine.labels.get(name).map(|&address| machine.memory[address].to_string())
+    })
+}
+
+// Reads the lines of a block after BEGIN, up to END, a blank line or the end of input,
+// each with its line number
+fn read_block(console: &mut Console, line_no: &mut usize) -> Vec<(usize, String)> {
+    let mut block = Vec::new();
+    loop {
+        if console.prompt("... ").is_err() {
+            break;
+        }
+        let input_line = match console.read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                break;
             },
-            "SBC" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: SBC <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in SBC");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            // SBC: result = op1 - op2 - (1 - CPSR)
-                            // Note: In ARM, carry means no borrow, so (1 - carry) is subtracted.
-                            let (diff1, borrow1) = (op1_val as u32).overflowing_sub(op2_val as u32);
-                            let subtrahend = 1 - cpsr;
-                            let (result, borrow2) = diff1.overflowing_sub(subtrahend);
-                            registers[idx_dest] = result as i32;
-                            cpsr = if borrow1 || borrow2 { 0 } else { 1 };
-                        } else {
-                            report_error(interactive, "Invalid second operand for SBC. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for SBC must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register in SBC. Use r0 through r15.");
-                }
+        };
+        *line_no += 1;
+        let line = strip_comment(&input_line);
+        if line.is_empty() && input_line.trim().is_empty() || line.eq_ignore_ascii_case("END") {
+            break;
+        }
+        block.push((*line_no, input_line.trim_end().to_string()));
+    }
+    block
+}
+
+// What interactive mode keeps between lines
+struct Repl<'a, I: Isa> {
+    cpu: I::Cpu,
+    // The instructions entered so far, which code labels point into, so a branch can run them again
+    entered: Program<I::Instruction>,
+    snippets: Snippets,
+    // Whether to print what each line changed, turned on with SET echo on
+    echo: bool,
+    options: &'a RunOptions,
+}
+
+// Handles one line typed in interactive mode. Returns false on EXIT.
+fn execute_repl_line<I: Isa>(isa: &I, repl: &mut Repl<I>, source_line: &str, line_no: usize, report: &Reporter, console: &mut Console) -> bool {
+    let Repl { cpu, entered, snippets, echo, options } = repl;
+    // Skip if the line is empty (was blank or only a comment)
+    let mut line_to_parse = strip_comment(source_line);
+    if line_to_parse.is_empty() {
+        return true;
+    }
+
+    let tokens = Tokens::split(line_to_parse);
+    if tokens.mnemonic.eq_ignore_ascii_case("RECENT") {
+        let count = match tokens.operands.as_slice() {
+            [] => Some(RECENT_DEPTH),
+            _ => tokens.expect(1, Some("RECENT [<count>]"), report).and_then(|ops| recent_count(ops[0], report)),
+        };
+        if let Some(count) = count
+            && let Err(error) = show_recent::<I>(cpu, &entered.lines, count, console) {
+            report.report(line_to_parse, *error);
+        }
+        return true;
+    }
+    if tokens.mnemonic.eq_ignore_ascii_case("SET") {
+        // Settings are separated by spaces, not commas
+        match line_to_parse[tokens.mnemonic.len()..].split_whitespace().collect::<Vec<_>>()[..] {
+            [setting, value] if setting.eq_ignore_ascii_case("echo") => match value.to_lowercase().as_str() {
+                "on" => *echo = true,
+                "off" => *echo = false,
+                _ => report.report(value, Diagnostic::error(Code::InvalidOperand, format!("Invalid echo setting: {}", value))
+                    .with_note("use on or off")),
             },
-            "LSL" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: LSL <dest_register>, <source_register>, <shift_amount>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma in LSL instruction");
+            [target, radix] => set_radix::<I>(cpu, target, radix, report),
+            _ => report.report(line_to_parse, Diagnostic::error(Code::InvalidOperand, "Wrong number of operands for SET")
+                .with_note("usage: SET radix <dec|hex|bin>, SET <register> <dec|hex|bin|default> or SET echo <on|off>")),
+        }
+        return true;
+    }
+    if let Some(conversion) = Conversion::from_command(tokens.mnemonic) {
+        let usage = format!("{} <number>", tokens.mnemonic.to_uppercase());
+        if let Some(ops) = tokens.expect(1, Some(&usage), report) {
+            let converted = match radix::convert(conversion, ops[0], I::REGISTERS.bits) {
+                Ok(converted) => console.print(format_args!("{}", converted)),
+                Err(message) => {
+                    report.report(ops[0], Diagnostic::error(Code::InvalidOperand, message)
+                        .with_note("numbers are decimal, 0x hex, 0b binary or 0o octal"));
+                    Ok(())
+                },
+            };
+            if let Err(error) = converted {
+                report.report(line_to_parse, *error);
+            }
+        }
+        return true;
+    }
+    if tokens.mnemonic.eq_ignore_ascii_case("ENCODE") {
+        let labels = Labels { addresses: &cpu.as_ref().labels, code: &HashMap::new(), pool: &cpu.as_ref().literal_pool() };
+        show_encoding(isa, line_to_parse, line_to_parse[tokens.mnemonic.len()..].trim(), &labels, report, console);
+        return true;
+    }
+    if tokens.mnemonic.eq_ignore_ascii_case("DEF") {
+        report.report(line_to_parse, Diagnostic::error(Code::WrongOperandCount, "DEF takes the name of the snippet")
+            .with_note("usage: DEF <name>, then the lines of the snippet and END"));
+        return true;
+    }
+    // x86 has a CALL instruction, which a code label typed earlier is left to
+    if tokens.mnemonic.eq_ignore_ascii_case("CALL")
+        && let [name] = tokens.operands[..]
+        && !entered.code_labels.contains_key(name) {
+        let Some(lines) = snippets.get(name) else {
+            let defined = snippets.names().collect::<Vec<_>>();
+            report.report(name, Diagnostic::error(Code::UndefinedLabel, format!("No snippet named {}", name))
+                .with_note(match defined.is_empty() {
+                    true => "define one with DEF <name>, then its lines and END".to_string(),
+                    false => format!("the snippets are {}", defined.join(", ")),
+                }));
+            return true;
+        };
+        // A snippet's code labels are its own, so it can be called again and again
+        let block: Vec<_> = lines.iter().enumerate().map(|(index, line)| (index + 1, line.clone())).collect();
+        let mut program = Program { lines: Vec::new(), instructions: Vec::new(), code_labels: HashMap::new(), cycle_budget: None };
+        if let Err(errors) = decode_block(isa, cpu, &mut program, &format!("<{}>", name), &block, None) {
+            diagnostics::emit(&errors);
+            return true;
+        }
+        return run_reported(isa, cpu, &program, 0, options, *echo, console);
+    }
+    if tokens.mnemonic.eq_ignore_ascii_case("HISTORY") {
+        if let Some(ops) = tokens.expect(1, Some("HISTORY <register>"), report) {
+            show_history::<I>(cpu, ops[0], report, console);
+        }
+        return true;
+    }
+
+    // A code label names the next instruction entered, and only a data label takes a memory word
+    if let Some((label, rest)) = isa.split_label(line_to_parse).filter(|(_, rest)| !rest.starts_with('#')) {
+        if !define_code_label(cpu.as_ref(), &mut entered.code_labels, label, entered.instructions.len(), report, Some(console)) {
+            return true;
+        }
+        line_to_parse = rest;
+    } else if let Some((label, rest)) = isa.split_label(line_to_parse) {
+        if entered.code_labels.contains_key(label) {
+            report.error(label, Code::DuplicateLabel, format!("Duplicate label definition: {}", label));
+            return true;
+        }
+        let rest = define_label(cpu.as_mut(), label, rest, report);
+        let machine = cpu.as_ref();
+        if let Some(&address) = machine.labels.get(label).filter(|_| log::enabled(log::Level::Normal) && report.is_clean()) {
+            let message = match rest {
+                Some(_) => format!("Label '{}' defined at memory address {}", label, address),
+                None => format!("Label '{}' defined at memory address {}, initialized with value {}", label, address, machine.memory[address]),
+            };
+            if let Err(error) = console.print(format_args!("{}", message)) {
+                report.report(label, *error);
+            }
+        }
+        match rest {
+            Some(rest) => line_to_parse = rest,
+            None => return true, // Data definition or rejected label, fully processed
+        }
+    }
+
+    // If line_to_parse is empty at this point (e.g., after processing "label:"), skip instruction parsing.
+    if line_to_parse.is_empty() {
+        return true;
+    }
+
+    // Branches can only go back, to the code labels of lines already entered
+    let pool = cpu.as_ref().literal_pool();
+    let labels = Labels { addresses: &cpu.as_ref().labels, code: &entered.code_labels, pool: &pool };
+    let Some(instruction) = isa.decode(&isa.tokenize(line_to_parse), &labels, report) else {
+        return true;
+    };
+    cpu.as_mut().fill_literal_pool(&pool);
+    let start = line_to_parse.as_ptr() as usize - source_line.as_ptr() as usize;
+    entered.lines.push(SourceLine {
+        file: "<stdin>".to_string(),
+        line_no,
+        source: source_line.to_string(),
+        start,
+        end: start + line_to_parse.len(),
+        local: None,
+        origin: None,
+    });
+    entered.instructions.push(instruction);
+    run_reported(isa, cpu, entered, entered.instructions.len() - 1, options, *echo, console)
+}
+
+// Handles a block typed between BEGIN and END in interactive mode. Its lines are decoded
+// together, so a branch can also go forward within the block, and run as a unit once all
+// of them decode. Returns false on EXIT.
+fn execute_repl_block<I: Isa>(isa: &I, repl: &mut Repl<I>, block: &[(usize, String)], console: &mut Console) -> bool {
+    let Repl { cpu, entered, echo, options, .. } = repl;
+    let first = entered.instructions.len();
+    if let Err(errors) = decode_block(isa, cpu, entered, "<stdin>", block, Some(console)) {
+        diagnostics::emit(&errors);
+        return true;
+    }
+    run_reported(isa, cpu, entered, first, options, *echo, console)
+}
+
+// Stores the block typed after DEF as the snippet `name` once all its lines decode,
+// without running it
+fn define_snippet<I: Isa>(isa: &I, repl: &mut Repl<I>, name: &str, block: &[(usize, String)], console: &mut Console) {
+    let errors = RefCell::new(Vec::new());
+    for (line_no, source_line) in block {
+        // A data label would be defined again on every CALL
+        if let Some((label, rest)) = isa.split_label(strip_comment(source_line)).filter(|(_, rest)| rest.starts_with('#')) {
+            Reporter::new(&errors, "<stdin>", *line_no, source_line).report(label, Diagnostic::error(Code::InvalidDataValue, "A snippet cannot define data")
+                .with_note(format!("define {}: {} before DEF and use it in the snippet", label, rest)));
+        }
+    }
+    let mut errors = errors.into_inner();
+    if errors.is_empty() {
+        let mut program = Program { lines: Vec::new(), instructions: Vec::new(), code_labels: HashMap::new(), cycle_budget: None };
+        if let Err(decode_errors) = decode_block(isa, &mut repl.cpu, &mut program, "<stdin>", block, None) {
+            errors = decode_errors;
+        }
+    }
+    if !errors.is_empty() {
+        diagnostics::emit(&errors);
+        return;
+    }
+    let saved = repl.snippets.define(name, block.iter().map(|(_, line)| line.trim().to_string()).collect());
+    if let Err(error) = saved {
+        diagnostics::emit(&[*error]);
+    } else if log::enabled(log::Level::Normal)
+        && let Err(error) = console.print(format_args!("Snippet '{}' defined", name)) {
+        diagnostics::emit(&[*error]);
+    }
+}
+
+// Decodes the lines of a block onto the end of `program`, numbering its code labels on
+// from the instructions already there and printing them if a console is given. Nothing
+// is added if any line has an error.
+fn decode_block<I: Isa>(isa: &I, cpu: &mut I::Cpu, program: &mut Program<I::Instruction>, file: &str, block: &[(usize, String)], mut console: Option<&mut Console>) -> Result<(), Vec<Diagnostic>> {
+    let errors = RefCell::new(Vec::new());
+    let code_labels = program.code_labels.clone();
+    let first = program.instructions.len();
+    let mut lines = Vec::new();
+    for (line_no, source_line) in block {
+        let report = Reporter::new(&errors, file, *line_no, source_line);
+        let mut line_to_parse = strip_comment(source_line);
+        if let Some((label, rest)) = isa.split_label(line_to_parse) {
+            let rest = if rest.starts_with('#') {
+                if program.code_labels.contains_key(label) {
+                    report.error(label, Code::DuplicateLabel, format!("Duplicate label definition: {}", label));
                     continue;
                 }
-                let dest = parts[1].trim_end_matches(',');
-                let src = parts[2].trim_end_matches(',');
-                if let (Some(idx_dest), Some(idx_src)) = (parse_register(dest), parse_register(src)) {
-                    if let Some(shift_val) = parse_value(parts[3], &registers) {
-                        registers[idx_dest] = registers[idx_src] << (shift_val as u32);
-                    } else {
-                        report_error(interactive, "Invalid shift amount for LSL instruction.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
-                }
+                define_label(cpu.as_mut(), label, rest, &report)
+            } else {
+                define_code_label(cpu.as_ref(), &mut program.code_labels, label, first + lines.len(), &report, console.as_deref_mut()).then_some(rest)
+            };
+            match rest {
+                Some(rest) => line_to_parse = rest,
+                None => continue,
+            }
+        }
+        if line_to_parse.is_empty() {
+            continue;
+        }
+        let start = line_to_parse.as_ptr() as usize - source_line.as_ptr() as usize;
+        lines.push(SourceLine {
+            file: file.to_string(),
+            line_no: *line_no,
+            source: source_line.clone(),
+            start,
+            end: start + line_to_parse.len(),
+            local: None,
+            origin: None,
+        });
+    }
+
+    let pool = cpu.as_ref().literal_pool();
+    let labels = Labels { addresses: &cpu.as_ref().labels, code: &program.code_labels, pool: &pool };
+    let instructions: Vec<_> = lines.iter()
+        .filter_map(|line| isa.decode(&isa.tokenize(line.text()), &labels, &Reporter::new(&errors, &line.file, line.line_no, &line.source)))
+        .collect();
+    let errors = errors.into_inner();
+    if !errors.is_empty() {
+        // The code labels of a block with an error are forgotten
+        program.code_labels = code_labels;
+        return Err(errors);
+    }
+    cpu.as_mut().fill_literal_pool(&pool);
+    program.lines.extend(lines);
+    program.instructions.extend(instructions);
+    Ok(())
+}
+
+// Names instruction `index` of those entered with a code label, unless the name is taken.
+// Returns whether it did.
+fn define_code_label(machine: &Machine, code_labels: &mut HashMap<String, usize>, label: &str, index: usize, report: &Reporter, console: Option<&mut Console>) -> bool {
+    if machine.labels.contains_key(label) || code_labels.contains_key(label) {
+        report.error(label, Code::DuplicateLabel, format!("Duplicate label definition: {}", label));
+        return false;
+    }
+    code_labels.insert(label.to_string(), index);
+    if let Some(console) = console.filter(|_| log::enabled(log::Level::Normal))
+        && let Err(error) = console.print(format_args!("Label '{}' defined at instruction {}", label, index)) {
+        report.report(label, *error);
+    }
+    true
+}
+
+// Runs the instructions entered from `pc` like `run_entered`, printing an error against
+// the line that gave it and, with `echo`, the registers, flags and memory words the
+// run changed. Returns false on EXIT.
+fn run_reported<I: Isa>(isa: &I, cpu: &mut I::Cpu, entered: &Program<I::Instruction>, pc: usize, options: &RunOptions, echo: bool, console: &mut Console) -> bool {
+    let before = echo.then(|| FinalState::of(isa, cpu));
+    let result = run_entered(isa, cpu, entered, pc, options, console);
+    let mut errors = Vec::new();
+    if let Some(before) = before
+        && let Err(error) = show_changes(isa, cpu, &before, console) {
+        errors.push(*error);
+    }
+    let keep_running = match result {
+        Ok(keep_running) => keep_running,
+        Err((pc, error)) => {
+            let line = &entered.lines[pc];
+            let reported = RefCell::new(Vec::new());
+            Reporter::new(&reported, &line.file, line.line_no, &line.source).report(line.text(), *error);
+            errors.extend(reported.into_inner());
+            true
+        },
+    };
+    diagnostics::emit(&errors);
+    keep_running
+}
+
+// Prints what changed since `before` on one line, e.g. "-> r1 = 6, Z = 1, count = 7",
+// naming a memory word by its data label if it has one; nothing if nothing changed
+fn show_changes<I: Isa>(isa: &I, cpu: &I::Cpu, before: &FinalState, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    let after = FinalState::of(isa, cpu);
+    let machine = cpu.as_ref();
+    let mut changes: Vec<String> = after.registers.iter().zip(&before.registers)
+        .filter(|((_, value), (_, old))| value != old)
+        .map(|(&(name, value), _)| format!("{} = {}", name, machine.formats.format(name, value, I::REGISTERS.bits)))
+        .collect();
+    changes.extend(after.flags.iter().zip(&before.flags)
+        .filter(|((_, set), (_, old))| set != old)
+        .map(|(&(name, set), _)| format!("{} = {}", name, set as u8)));
+    for (address, word) in after.memory.changes(&before.memory) {
+        let label = machine.labels.iter().find(|&(_, &labelled)| labelled == address).map(|(label, _)| label.clone());
+        changes.push(format!("{} = {}", label.unwrap_or_else(|| format!("[{}]", address)), word));
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    console.print(format_args!("-> {}", changes.join(", ")))
+}
+
+// Runs the instructions entered in interactive mode from `pc` until one runs past the
+// last, which only a branch back to a code label goes beyond the newest. Returns false
+// on EXIT, and on an error the instruction that gave it.
+fn run_entered<I: Isa>(isa: &I, cpu: &mut I::Cpu, entered: &Program<I::Instruction>, mut pc: usize, options: &RunOptions, console: &mut Console) -> Result<bool, (usize, Box<Diagnostic>)> {
+    let _running = options.interrupt.start();
+    let mut steps = 0;
+    while pc < entered.instructions.len() {
+        if options.interrupt.is_requested() {
+            return Err((pc, Box::new(Diagnostic::error(Code::Interrupted, format!("Interrupted after {} instructions", steps))
+                .with_note("this line was about to run"))));
+        }
+        if options.max_steps == Some(steps) {
+            return Err((pc, Box::new(Diagnostic::error(Code::StepLimit, format!("Stopped after {} instructions", steps))
+                .with_note("the line may be stuck in a loop"))));
+        }
+        steps += 1;
+        let line = &entered.lines[pc];
+        if let Some(warning) = check_flags_set(isa, cpu, &entered.instructions[pc], pc, line) {
+            diagnostics::emit(std::slice::from_ref(&warning));
+            cpu.as_mut().warnings.push(warning);
+        }
+        pc = match step(isa, cpu, &entered.instructions[pc], pc, &line.file, line.line_no, console).map_err(|error| (pc, error))? {
+            Flow::Next => pc + 1,
+            Flow::Jump(target) => target,
+            Flow::Breakpoint => {
+                show_breakpoint(isa, cpu, &line.file, line.line_no, console).map_err(|error| (pc, error))?;
+                pc + 1
             },
-            "LSR" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: LSR <dest_register>, <source_register>, <shift_amount>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma in LSR instruction");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                let src = parts[2].trim_end_matches(',');
-                if let (Some(idx_dest), Some(idx_src)) = (parse_register(dest), parse_register(src)) {
-                    if let Some(shift_val) = parse_value(parts[3], &registers) {
-                        registers[idx_dest] = ((registers[idx_src] as u32) >> (shift_val as u32)) as i32;
-                    } else {
-                        report_error(interactive, "Invalid shift amount for LSR instruction.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
-                }
+            Flow::Exit => return Ok(false),
+        };
+    }
+    Ok(true)
+}
+
+// Sets the radix of every register (`target` is "radix") or of one, for SET
+fn set_radix<I: Isa>(cpu: &mut I::Cpu, target: &str, radix: &str, report: &Reporter) {
+    let register = match I::REGISTERS.names.iter().find(|name| name.eq_ignore_ascii_case(target)) {
+        Some(name) => Some(name.to_string()),
+        None if target.eq_ignore_ascii_case("radix") => None,
+        None => {
+            report.report(target, Diagnostic::error(Code::InvalidOperand, format!("Unknown setting: {}", target))
+                .with_note(format!("SET radix sets the radix of every register, SET {} that of one", I::REGISTERS.names[0])));
+            return;
+        },
+    };
+    let radix = match radix.to_lowercase().as_str() {
+        "default" if register.is_some() => None,
+        _ => match radix.parse() {
+            Ok(radix) => Some(radix),
+            Err(_) => {
+                report.report(radix, Diagnostic::error(Code::InvalidOperand, format!("Invalid radix: {}", radix))
+                    .with_note(if register.is_some() { "use dec, hex, bin or default" } else { "use dec, hex or bin" }));
+                return;
             },
-            "ASR" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: ASR <dest_register>, <source_register>, <shift_amount>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma in ASR instruction");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                let src = parts[2].trim_end_matches(',');
-                if let (Some(idx_dest), Some(idx_src)) = (parse_register(dest), parse_register(src)) {
-                    if let Some(shift_val) = parse_value(parts[3], &registers) {
-                        registers[idx_dest] = registers[idx_src] >> (shift_val as u32);
-                    } else {
-                        report_error(interactive, "Invalid shift amount for ASR instruction.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
+        },
+    };
+    cpu.as_mut().formats.set(&Setting { register, radix });
+}
+
+/// Prints the machine code of `instruction`, decoded with `labels`, for ENCODE. `command`
+/// is the whole command, to report a missing instruction.
+pub(crate) fn show_encoding<I: Isa>(isa: &I, command: &str, instruction: &str, labels: &Labels, report: &Reporter, console: &mut Console) {
+    if instruction.is_empty() {
+        report.report(command, Diagnostic::error(Code::InvalidOperand, "ENCODE needs an instruction")
+            .with_note("usage: ENCODE <instruction>, e.g. ENCODE ADD r1, r2, #4"));
+        return;
+    }
+    let Some(decoded) = isa.decode(&isa.tokenize(instruction), labels, report) else {
+        return;
+    };
+    let printed = match isa.encode(&decoded) {
+        Ok(encoding) => console.print(format_args!("{} = 0x{:08X}\n{}", instruction, encoding.word, encoding)),
+        Err(message) => {
+            report.report(instruction, Diagnostic::error(Code::NoEncoding, format!("Cannot encode {}", instruction)).with_note(message));
+            Ok(())
+        },
+    };
+    if let Err(error) = printed {
+        report.report(command, *error);
+    }
+}
+
+// Parses the number of instructions RECENT shows
+fn recent_count(count: &str, report: &Reporter) -> Option<usize> {
+    let parsed = count.parse().ok().filter(|&count| count > 0);
+    if parsed.is_none() {
+        report.report(count, Diagnostic::error(Code::InvalidOperand, format!("Invalid instruction count: {}", count))
+            .with_note(format!("RECENT shows up to the last {} instructions", RECENT_DEPTH)));
+    }
+    parsed
+}
+
+// Prints the recent changes of a register for the HISTORY command
+fn show_history<I: Isa>(cpu: &I::Cpu, name: &str, report: &Reporter, console: &mut Console) {
+    match I::REGISTERS.names.iter().position(|reg| reg.eq_ignore_ascii_case(name)) {
+        Some(reg) => {
+            let machine = cpu.as_ref();
+            let format = |value| machine.formats.format(I::REGISTERS.names[reg], value, I::REGISTERS.bits);
+            if let Err(error) = console.print(format_args!("{}", machine.history.describe(name, reg, &format))) {
+                report.report(name, *error);
+            }
+        },
+        None => {
+            report.report(name, Diagnostic::error(Code::InvalidRegister, format!("Invalid register: {}", name))
+                .with_note(format!("registers are {} through {}", I::REGISTERS.names[0], I::REGISTERS.names[I::REGISTERS.names.len() - 1])));
+        },
+    }
+}
+
+// Executes one instruction from `file`:`line`, recording the registers it changes in the history
+fn step<I: Isa>(isa: &I, cpu: &mut I::Cpu, instruction: &I::Instruction, pc: usize, file: &str, line: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+    let count = I::REGISTERS.names.len();
+    // At most 32 general-purpose registers
+    let mut before = [0i64; 32];
+    for (reg, value) in before.iter_mut().enumerate().take(count) {
+        *value = isa.read_register(cpu, reg);
+    }
+    cpu.as_mut().history.begin_step(file, line);
+    let flow = isa.execute(cpu, instruction, pc, console)?;
+    for (reg, &old) in before.iter().enumerate().take(count) {
+        let value = isa.read_register(cpu, reg);
+        if value != old {
+            cpu.as_mut().history.record(reg, value);
+        }
+    }
+    Ok(flow)
+}
+
+// Warns, once per instruction, when the instruction at `pc` reads the flags before any
+// instruction has set them. Front-ends without data flow are not checked.
+fn check_flags_set<I: Isa>(isa: &I, cpu: &mut I::Cpu, instruction: &I::Instruction, pc: usize, line: &SourceLine) -> Option<Diagnostic> {
+    cpu.as_ref().unset_flags.as_ref()?;
+    let flow = isa.data_flow(cpu, instruction)?;
+    let machine = cpu.as_mut();
+    // --undefined already warns about branches on undefined flags
+    let reads = flow.reads_flags && !(flow.branches && machine.undefined.is_some());
+    let first = machine.unset_flags.as_mut().is_some_and(|warned| reads && warned.insert(pc));
+    if flow.writes_flags {
+        machine.unset_flags = None;
+    }
+    first.then(|| Diagnostic::warning(Code::UnsetFlags, format!("{} reads the flags before any instruction has set them", line.text()))
+        .with_note("the flags start out clear here, but a real core holds whatever was left in the CPSR; set them first with CMP, CMN or an instruction ending in S, such as SUBS")
+        .with_span(Reporter::new(&RefCell::new(Vec::new()), &line.file, line.line_no, &line.source).span(line.text())))
+}
+
+// The whole state of a core about to run the instruction at `pc`, for the watchdog, with
+// how far the program has read its input, since reading more can change what it does next.
+// The tasks and the exclusive monitor are part of it too: a loop that switches tasks or
+// holds a reservation only repeats if they come back as well.
+fn snapshot<I: Isa>(isa: &I, cpu: &I::Cpu, pc: usize, input: u64) -> State {
+    let machine = cpu.as_ref();
+    let mut core = vec![pc as i64, machine.cpsr as i64, input as i64];
+    core.extend((0..I::REGISTERS.names.len()).map(|reg| isa.read_register(cpu, reg)));
+    core.extend(isa.flags(cpu).iter().map(|&(_, set)| set as i64));
+    core.extend(machine.fp_registers.iter().map(|value| value.to_bits() as i64));
+    core.extend([machine.tasks.running() as i64, machine.tasks.end as i64]);
+    core.extend(machine.tasks.alive().map(|alive| alive as i64));
+    // What matters of a reservation is its address and whether a store has broken it since,
+    // not how many stores there have been
+    core.extend(match machine.exclusive {
+        Some((address, writes)) => [address as i64, (machine.accesses.writes(address) == writes) as i64],
+        None => [-1, 0],
+    });
+    State { core, memory: machine.memory.clone() }
+}
+
+// Describes a loop the program can never leave, with the lines of its body
+fn infinite_loop<I>(program: &Program<I>, found: &Loop) -> Box<Diagnostic> {
+    let lines: BTreeSet<(&str, usize, &str)> = found.body.iter()
+        .map(|&pc| (program.lines[pc].file.as_str(), program.lines[pc].line_no, program.lines[pc].source.trim()))
+        .collect();
+    let files: BTreeSet<&str> = lines.iter().map(|&(file, _, _)| file).collect();
+    let (first, last) = (lines.iter().map(|&(_, line, _)| line).min(), lines.iter().map(|&(_, line, _)| line).max());
+    let place = match (first, last) {
+        (Some(first), Some(last)) if files.len() == 1 && first == last => format!("on line {}", first),
+        (Some(first), Some(last)) if files.len() == 1 => format!("between lines {} and {}", first, last),
+        _ => format!("across {} files", files.len()),
+    };
+    let mut body = "the loop runs:".to_string();
+    for &(file, line, text) in lines.iter().take(LOOP_BODY_LINES) {
+        let location = if files.len() == 1 { format!("{:>7}", line) } else { format!("{}:{}", file, line) };
+        body.push_str(&format!("\n{} | {}", location, text));
+    }
+    if lines.len() > LOOP_BODY_LINES {
+        body.push_str(&format!("\n    ... and {} more lines", lines.len() - LOOP_BODY_LINES));
+    }
+    Box::new(Diagnostic::error(Code::InfiniteLoop, format!("Infinite loop detected {}", place))
+        .with_note(format!("the program came back to exactly the same registers, flags and memory after {} instruction{}, so it can never stop",
+                           found.period, if found.period == 1 { "" } else { "s" }))
+        .with_note(body))
+}
+
+// The name of a register in data flow, where floating-point registers follow the core registers
+fn register_name<I: Isa>(reg: usize) -> String {
+    I::REGISTERS.names.get(reg).map_or_else(|| format!("s{}", reg - I::REGISTERS.names.len()), |name| name.to_string())
+}
+
+// Resolves the --taint sources: register names, memory addresses and data labels
+fn taint_sources<I: Isa>(machine: &Machine, sources: &[String]) -> Result<Taint, Box<Diagnostic>> {
+    let (mut registers, mut memory) = (BTreeSet::new(), BTreeSet::new());
+    for source in sources {
+        if let Some(reg) = I::REGISTERS.names.iter().position(|name| name.eq_ignore_ascii_case(source)) {
+            registers.insert(reg);
+        } else if let Some(address) = machine.labels.get(source).copied()
+            .or_else(|| parse_immediate(source).and_then(|value| usize::try_from(value).ok())).filter(|&address| address < machine.memory.len()) {
+            memory.insert(address);
+        } else {
+            return Err(Box::new(Diagnostic::error(Code::InvalidTaint, format!("Cannot taint '{}'", source))
+                .with_note(format!("expected a register, a memory address below {} or a data label", MEMORY_SIZE))));
+        }
+    }
+    Ok(Taint::new(sources.to_vec(), registers, memory))
+}
+
+// Writes what the step just run from `line` changed to the --trace timeline,
+// given the memory and flags from `before` it
+fn record_event<I: Isa>(isa: &I, cpu: &mut I::Cpu, instruction: &I::Instruction, pc: usize, line: &SourceLine, before: (Memory, Vec<(&'static str, bool)>), error: Option<&Diagnostic>) -> Result<(), Box<Diagnostic>> {
+    let (memory, flags) = before;
+    let changed_flags = isa.flags(cpu).into_iter().filter(|flag| !flags.contains(flag)).collect();
+    let Machine { timeline, history, memory: now, formats, .. } = cpu.as_mut();
+    let (Some(timeline), Some(executed)) = (timeline.as_mut(), history.recent(1).next()) else {
+        return Ok(());
+    };
+    let event = Event {
+        step: executed.step,
+        pc,
+        line,
+        registers: executed.writes.iter().map(|&(reg, value)| (I::REGISTERS.names[reg], value)).collect(),
+        flags: changed_flags,
+        memory: now.changes(&memory).collect(),
+        remark: isa.remark(instruction),
+        error,
+    };
+    timeline.record(&event, formats, I::REGISTERS.bits)
+}
+
+// Prints the registers and flags at a BKPT that is not paused at
+fn show_breakpoint<I: Isa>(isa: &I, cpu: &I::Cpu, file: &str, line: usize, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    console.print(format_args!("Breakpoint at {}:{}", file, line))?;
+    show_state(isa, cpu, console)
+}
+
+// Prints the registers four to a line, then the flags
+pub(crate) fn show_state<I: Isa>(isa: &I, cpu: &I::Cpu, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    let state = FinalState::of(isa, cpu);
+    let formats = &cpu.as_ref().formats;
+    for row in state.registers.chunks(4) {
+        let row: Vec<String> = row.iter().map(|(name, value)| format!("{:>3} = {:<11}", name, formats.format(name, *value, I::REGISTERS.bits))).collect();
+        console.print(format_args!("  {}", row.join(" ").trim_end()))?;
+    }
+    let flags: Vec<String> = state.flags.iter().map(|&(name, set)| format!("{}={}", name, set as u8)).collect();
+    console.print(format_args!("  {}", flags.join(" ")))
+}
+
+// The registers, flags, labels and memory of a core, for breakpoint conditions
+pub(crate) struct CpuContext<'a, I: Isa> {
+    pub(crate) isa: &'a I,
+    pub(crate) cpu: &'a I::Cpu,
+}
+
+impl<I: Isa> expression::Context for CpuContext<'_, I> {
+    fn value(&self, name: &str) -> Option<i64> {
+        if let Some(index) = I::REGISTERS.names.iter().position(|reg| reg.eq_ignore_ascii_case(name)) {
+            return Some(self.isa.read_register(self.cpu, index));
+        }
+        if let Some(&(_, set)) = self.isa.flags(self.cpu).iter().find(|(flag, _)| *flag == name) {
+            return Some(set as i64);
+        }
+        self.cpu.as_ref().labels.get(name).map(|&address| address as i64)
+    }
+
+    fn word(&self, address: i64) -> Option<i64> {
+        let memory = &self.cpu.as_ref().memory;
+        usize::try_from(address).ok().and_then(|address| memory.get(address)).map(|&word| word as i64)
+    }
+}
+
+// What the functions of a --script see of the core at `steps` instructions, on source line `line`
+fn script_core<I: Isa>(isa: &I, cpu: &I::Cpu, steps: u64, line: usize) -> script::Core {
+    let machine = cpu.as_ref();
+    let registers = I::REGISTERS.names.iter().enumerate().map(|(index, name)| (name.to_string(), isa.read_register(cpu, index)));
+    let flags = isa.flags(cpu).into_iter().map(|(flag, set)| (flag.to_string(), set as i64));
+    let labels = machine.labels.iter().map(|(label, &address)| (label.clone(), address as i64));
+    script::Core { names: registers.chain(flags).chain(labels).collect(), memory: machine.memory.clone(), steps, line, value: None }
+}
+
+// Prints the registers, flags, top of the stack and last instructions of a failed run
+fn post_mortem<I: Isa>(isa: &I, cpu: &I::Cpu, program: &Program<I::Instruction>, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    console.print(format_args!("Post-mortem:"))?;
+    show_state(isa, cpu, console)?;
+    if let Some(sp) = I::STACK_POINTER {
+        let name = I::REGISTERS.names[sp];
+        let top = isa.read_register(cpu, sp);
+        let memory = &cpu.as_ref().memory;
+        match usize::try_from(top).ok().filter(|&top| top <= memory.len()) {
+            Some(top) if top == memory.len() => console.print(format_args!("  The stack is empty ({} = {})", name, top))?,
+            Some(top) => {
+                console.print(format_args!("  Top of the stack ({} = {}):", name, top))?;
+                for address in (top..memory.len()).take(STACK_WORDS) {
+                    console.print(format_args!("    [{}] = {}", address, memory[address]))?;
                 }
             },
-            "ROR" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: ROR <dest_register>, <source_register>, <rotate_amount>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma in ROR instruction");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                let src = parts[2].trim_end_matches(',');
-                if let (Some(idx_dest), Some(idx_src)) = (parse_register(dest), parse_register(src)) {
-                    if let Some(rotate_val) = parse_value(parts[3], &registers) {
-                        registers[idx_dest] = (registers[idx_src] as u32).rotate_right(rotate_val as u32) as i32;
-                    } else {
-                        report_error(interactive, "Invalid rotate amount for ROR instruction.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
-                }
+            None => console.print(format_args!("  {} = {} is outside memory", name, top))?,
+        }
+    }
+    console.print(format_args!("  Last instructions run, oldest first:"))?;
+    show_recent::<I>(cpu, &program.lines, POST_MORTEM_INSTRUCTIONS, console)
+}
+
+// Prints the last `count` instructions run and the registers they wrote, for RECENT
+// and the post-mortem. `lines` gives the text of the instructions, if known.
+pub(crate) fn show_recent<I: Isa>(cpu: &I::Cpu, lines: &[SourceLine], count: usize, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+    let history = &cpu.as_ref().history;
+    for executed in history.recent(count) {
+        let file = history.executed_file(executed);
+        let text = lines.iter().find(|line| line.file == file && line.line_no == executed.line).map_or("", SourceLine::text);
+        let mut entry = format!("step {:>5}  {}:{}", executed.step, file, executed.line);
+        if !text.is_empty() {
+            entry.push_str(&format!("  {}", text));
+        }
+        let writes: Vec<String> = executed.writes.iter().map(|&(reg, value)| {
+            let name = I::REGISTERS.names[reg];
+            format!("{} = {}", name, cpu.as_ref().formats.format(name, value, I::REGISTERS.bits))
+        }).collect();
+        if !writes.is_empty() {
+            entry.push_str(&format!("  -> {}", writes.join(", ")));
+        }
+        console.print(format_args!("    {}", entry))?;
+    }
+    Ok(())
+}
+
+/// Loads every file in `paths`, links them into one program and runs it.
+/// Returns whether the program ran without errors.
+pub fn run_files(front_end: &dyn FrontEnd, paths: &[String], options: &RunOptions, console: &mut Console) -> io::Result<bool> {
+    let mut sources = Vec::new();
+    for path in paths {
+        sources.push((path.clone(), fs::read_to_string(path)?));
+    }
+    match front_end.run_sources(&sources, options, console) {
+        Ok(_) => Ok(true),
+        Err(errors) => {
+            diagnostics::emit(&errors);
+            Ok(false)
+        },
+    }
+}
+
+// Applies the initial register and memory values requested in `options`
+fn seed<I: Isa>(isa: &I, cpu: &mut I::Cpu, options: &RunOptions) {
+    lay_out_memory(cpu.as_mut(), options);
+    cpu.as_mut().seed_memory(options);
+    // The stack pointer comes from the vector table, which --mem may fill in, unless --set gives it
+    if let (Some(target), Some(sp)) = (options.target, I::STACK_POINTER) {
+        let table = target.vector_table();
+        let memory = &mut cpu.as_mut().memory;
+        if memory[table] == 0 {
+            memory[table] = target.initial_stack() as i32;
+        }
+        let stack = memory[table];
+        isa.seed_registers(cpu, &[(sp, stack)]);
+    }
+    isa.seed_registers(cpu, &options.registers);
+    cpu.as_mut().trap_overflow = options.trap_overflow;
+    cpu.as_mut().helpers = !options.no_helpers;
+    if let Some(size) = options.history_size {
+        cpu.as_mut().history = History::with_depth(size);
+    }
+    cpu.as_mut().formats = options.formats.clone();
+    if let Some(bits) = options.word_size {
+        isa.narrow(cpu, bits);
+    }
+}
+
+// Rejects --word-size for front-ends that only compute with their own width
+fn check_word_size<I: Isa>(isa: &I, options: &RunOptions) -> Result<(), Box<Diagnostic>> {
+    match options.word_size {
+        Some(bits) if !isa.narrow(&mut isa.new_cpu(), bits) => Err(Box::new(Diagnostic::error(Code::UnsupportedOption,
+            format!("--word-size is not supported with --isa {}", I::NAME))
+            .with_note("narrower words are available with --isa arm and --isa thumb"))),
+        _ => Ok(()),
+    }
+}
+
+// Sizes memory for `memory_words` or the memory map, which also places the data labels
+fn lay_out_memory(machine: &mut Machine, options: &RunOptions) {
+    let map = memory_map(options);
+    let map_words = map.as_ref().map_or(0, MemoryMap::words);
+    machine.resize_memory(options.memory_words.unwrap_or(MEMORY_SIZE).max(map_words));
+    if let Some(map) = map {
+        machine.map_memory(map);
+    }
+}
+
+// The memory map of the options, or else that of the target
+fn memory_map(options: &RunOptions) -> Option<MemoryMap> {
+    options.memory_map.clone().or_else(|| options.target.map(Target::memory_map))
+}
+
+// Rejects --target for instruction sets other than those of the boards
+fn check_target<I: Isa>(options: &RunOptions) -> Result<(), Box<Diagnostic>> {
+    match options.target {
+        Some(_) if !matches!(I::NAME, "arm" | "thumb") => Err(Box::new(Diagnostic::error(Code::UnsupportedOption,
+            format!("--target is not supported with --isa {}", I::NAME))
+            .with_note("the targets are ARM boards, for --isa arm and --isa thumb"))),
+        _ => Ok(()),
+    }
+}
+
+// Rejects initial memory values past the end of memory, and the options that keep something for every word
+// with a memory larger than MEMORY_SIZE
+fn check_memory(options: &RunOptions) -> Result<(), Box<Diagnostic>> {
+    let map_words = memory_map(options).as_ref().map_or(0, MemoryMap::words);
+    if options.memory_words.is_some_and(|words| words < map_words) {
+        return Err(Box::new(Diagnostic::error(Code::UnsupportedOption, format!("--memory-bytes is too small for the memory map, which needs {} bytes", map_words as u64 * 4))
+            .with_note("leave out --memory-bytes to size memory by the map")));
+    }
+    let words = options.memory_words.unwrap_or(MEMORY_SIZE).max(map_words);
+    if let Some(&(address, _)) = options.memory.iter().find(|&&(address, _)| address >= words) {
+        return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds, format!("Cannot set memory address {}: memory has {} words", address, words))
+            .with_note("a larger memory is available with --memory-bytes")));
+    }
+    let dense = [("--undefined", options.undefined), ("--heatmap", options.heatmap.is_some())];
+    let mapped_words = memory_map(options).map(|map| map.regions().iter().map(|region| region.words).sum::<usize>());
+    match (dense.iter().find(|&&(_, used)| used), mapped_words) {
+        // With a map only the words of its regions are kept
+        (Some((option, _)), Some(mapped)) if mapped > MAX_MAPPED_WORDS => Err(Box::new(Diagnostic::error(Code::UnsupportedOption,
+            format!("{} is not supported with memory map regions of more than {} bytes", option, MAX_MAPPED_WORDS * 4))
+            .with_note("it keeps something for every word of the regions; make them smaller"))),
+        (Some((option, _)), None) if words > MEMORY_SIZE => Err(Box::new(Diagnostic::error(Code::UnsupportedOption,
+            format!("{} is not supported with a memory of more than {} words", option, MEMORY_SIZE))
+            .with_note("it keeps something for every word; leave out --memory-bytes"))),
+        _ => Ok(()),
+    }
+}
+
+/// Links `(file name, contents)` pairs into one program and runs it from its entry point.
+/// The program reads and prints through `console`. Returns the final state of the core, or the errors that stopped the program.
+pub fn run_sources<I: Isa>(isa: &I, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<I::Cpu, Vec<Diagnostic>> {
+    let (cpu, result) = run_sources_keeping_state(isa, sources, options, console)?;
+    result?;
+    Ok(cpu)
+}
+
+/// The state a program left and the errors it stopped with, if any.
+pub type Ended<S> = (S, Result<(), Vec<Diagnostic>>);
+
+/// Like [`run_sources`], but also returns the state of the core when the program stops with an error,
+/// such as after `max_steps` instructions. Only errors before the program starts leave no state.
+pub fn run_sources_keeping_state<I: Isa>(isa: &I, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<Ended<I::Cpu>, Vec<Diagnostic>> {
+    check_word_size(isa, options).and_then(|()| check_memory(options)).and_then(|()| check_target::<I>(options)).map_err(|error| vec![*error])?;
+    let mut cpu = isa.new_cpu();
+    // Laid out before loading, so data and addresses may be anywhere in it
+    lay_out_memory(cpu.as_mut(), options);
+    plugin::attach_devices(cpu.as_mut()).map_err(|error| vec![*error])?;
+    let program = load_program(isa, cpu.as_mut(), sources)?;
+    check_allowed(isa, &program, options)?;
+    check_encodings(isa, &program, options)?;
+    cpu.as_mut().warnings = check_truncations(isa, &program, options)?;
+    isa.prepare(&mut cpu, program.instructions.len());
+    isa.place(&mut cpu, &program.instructions, &program.code_labels).map_err(|error| vec![*error])?;
+    // Seed after loading so requested values take precedence over data labels
+    seed(isa, &mut cpu, options);
+    cpu.as_mut().cache = options.cache.map(Cache::new);
+    cpu.as_mut().trace = options.html.as_ref().map(|_| Trace::default());
+    // Front-ends that follow data flow do so for every instruction
+    let flows = program.instructions.first().is_none_or(|instruction| isa.data_flow(&cpu, instruction).is_some());
+    if !options.taint.is_empty() {
+        if !flows {
+            return Err(vec![Diagnostic::error(Code::UnsupportedOption, format!("--taint is not supported with --isa {}", I::NAME))
+                .with_note("taint is tracked with --isa arm and --isa thumb")]);
+        }
+        let taint = taint_sources::<I>(cpu.as_ref(), &options.taint).map_err(|error| vec![*error])?;
+        cpu.as_mut().taint = Some(taint);
+    }
+    if options.undefined && flows {
+        // Registers the front-end prepares, such as the return address, are defined
+        let fresh = isa.new_cpu();
+        let mut registers: BTreeSet<usize> = options.registers.iter().map(|&(reg, _)| reg).collect();
+        registers.extend((0..I::REGISTERS.names.len()).filter(|&reg| isa.read_register(&cpu, reg) != isa.read_register(&fresh, reg)));
+        let mut memory: BTreeSet<usize> = options.memory.iter().map(|&(address, _)| address).collect();
+        // Every word from the first label up to the next free one is a label or in the literal pool
+        let machine = cpu.as_ref();
+        let data_start = machine.memory_map.as_ref().map_or(0, MemoryMap::data_start);
+        memory.extend(data_start..machine.next_label_mem_addr);
+        // So is the vector table of a target: the stack pointer and the reset vector
+        memory.extend(options.target.map(|target| target.vector_table()..target.vector_table() + 2).into_iter().flatten());
+        // Only the words of a memory map's regions can be read
+        let words: Vec<Range<usize>> = match &machine.memory_map {
+            Some(map) => map.regions().iter().map(|region| region.start..region.end()).collect(),
+            None => iter::once(0..machine.memory.len()).collect(),
+        };
+        // Floating-point registers follow the core registers
+        let undefined = Undefined::new(I::REGISTERS.names.len() + NUM_FP_REGISTERS, &registers, words, &memory);
+        cpu.as_mut().undefined = Some(undefined);
+    } else if options.undefined {
+        return Err(vec![Diagnostic::error(Code::UnsupportedOption, format!("--undefined is not supported with --isa {}", I::NAME))
+            .with_note("undefined values are tracked with --isa arm and --isa thumb")]);
+    }
+    if options.lint {
+        cpu.as_mut().warnings.extend(check_no_effect(isa, &program));
+        cpu.as_mut().dead_stores = flows.then(DeadStores::default);
+    }
+    if let Some(path) = &options.trace {
+        cpu.as_mut().timeline = Some(Timeline::create(path, options.trace_format).map_err(|error| vec![*error])?);
+    }
+    if let Some((regions, path)) = &options.watch_memory {
+        cpu.as_mut().memory_watch = Some(MemoryWatch::create(regions, &cpu.as_ref().labels, cpu.as_ref().memory.len(), path).map_err(|error| vec![*error])?);
+    }
+
+    let entry = entry_point(&program, cpu.as_ref(), options)?;
+    if let Some(target) = options.target {
+        // The reset vector points at the entry, counting instructions as code addresses do here
+        cpu.as_mut().memory[target.vector_table() + 1] = entry as i32;
+    }
+    let mut call_graph = (options.call_graph || options.call_graph_file.is_some()).then(|| {
+        let call_targets = program.instructions.iter().filter_map(|instruction| isa.call_target(instruction));
+        CallGraph::new(&program.code_labels, entry, call_targets)
+    });
+    let mut cores = start_cores(isa, &mut cpu, &program, entry, options);
+    let mut result = run_program(isa, &mut cpu, &program, &mut cores, options, call_graph.as_mut(), console);
+    if let Some(script) = options.script.as_ref().filter(|_| result.is_ok()) {
+        let (steps, line) = cpu.as_ref().history.recent(1).next().map_or((0, 0), |executed| (executed.step, executed.line));
+        let failed = script.check(&script_core(isa, &cpu, steps, line));
+        if !failed.is_empty() {
+            result = Err(failed);
+        }
+    }
+    if let Some(path) = &options.record_schedule
+        && let Err(error) = cores.schedule().write(path) {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some(timeline) = cpu.as_mut().timeline.as_mut()
+        && let Err(error) = timeline.flush() {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some(watch) = cpu.as_mut().memory_watch.as_mut()
+        && let Err(error) = watch.flush() {
+        result = result.and(Err(vec![*error]));
+    }
+    // Only once the program has started, not for e.g. a breakpoint that matches no instruction
+    if let Some(path) = &options.heatmap
+        && let Err(error) = heatmap::write(path, &cpu.as_ref().accesses, &cpu.as_ref().labels, console) {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some(path) = &options.html {
+        let errors = result.as_ref().err().map_or(&[][..], Vec::as_slice);
+        let report = html::run_report(sources, &program.lines, cpu.as_ref(), &FinalState::of(isa, &cpu), I::REGISTERS, errors);
+        if let Err(error) = html::write(path, &report) {
+            result = result.and(Err(vec![*error]));
+        }
+    }
+    if let Some(path) = &options.snapshot
+        && let Err(error) = Snapshot::of(I::NAME, &FinalState::of(isa, &cpu)).write(path) {
+        result = result.and(Err(vec![*error]));
+    }
+    // Attached to the last error, so it follows the diagnostic on stderr instead of
+    // appearing on stdout before it
+    if let Err(errors) = &mut result && options.post_mortem && cpu.as_ref().history.recent(1).next().is_some() {
+        let mut out = Vec::new();
+        post_mortem(isa, &cpu, &program, &mut Console::new(&mut io::empty(), &mut out)).expect("writing to a buffer");
+        if let Some(last) = errors.last_mut() {
+            last.notes.push(String::from_utf8_lossy(&out).trim_end().to_string());
+        }
+    }
+    if let Some(cache) = &cpu.as_ref().cache
+        && let Err(error) = console.print(format_args!("{}", cache.report(&program.lines))) {
+        result = result.and(Err(vec![*error]));
+    }
+    // Also shown when the program fails, e.g. to see how deep a runaway recursion went
+    if let Some(call_graph) = call_graph.as_ref().filter(|_| options.call_graph)
+        && let Err(error) = console.print(format_args!("{}", call_graph.report())) {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some((call_graph, path)) = call_graph.as_ref().zip(options.call_graph_file.as_ref())
+        && let Err(error) = call_graph.write(path) {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some(taint) = &cpu.as_ref().taint
+        && let Err(error) = console.print(format_args!("{}", taint.report(&program.lines, &register_name::<I>))) {
+        result = result.and(Err(vec![*error]));
+    }
+    if let Some(dead_stores) = &cpu.as_ref().dead_stores {
+        let warnings = dead_stores.report(&program.lines, &register_name::<I>);
+        diagnostics::emit(&warnings);
+        cpu.as_mut().warnings.extend(warnings);
+    }
+    Ok((cpu, result))
+}
+
+// Prepares the cores after the first for --cores, each like `cpu` but with its own number
+fn start_cores<I: Isa>(isa: &I, cpu: &mut I::Cpu, program: &Program<I::Instruction>, entry: usize, options: &RunOptions) -> Cores<I::Cpu> {
+    if options.cores > 1 {
+        isa.seed_registers(cpu, &[(I::CORE_REGISTER, 0)]);
+    }
+    let others = (1..options.cores).map(|number| {
+        let mut core = isa.new_cpu();
+        core.as_mut().labels = cpu.as_ref().labels.clone();
+        isa.prepare(&mut core, program.instructions.len());
+        seed(isa, &mut core, options);
+        isa.seed_registers(&mut core, &[(I::CORE_REGISTER, number as i32)]);
+        core
+    }).collect();
+    let mut cores = Cores::new(isa.new_cpu(), others, entry, options.interleaving);
+    if let Some(schedule) = &options.replay_schedule {
+        cores.replay(schedule);
+    }
+    cores
+}
+
+/// Finds the instruction execution starts at: the `--entry` label if given, else
+/// the first default entry label the program defines, else the first instruction.
+pub fn entry_point<I>(program: &Program<I>, machine: &Machine, options: &RunOptions) -> Result<usize, Vec<Diagnostic>> {
+    let entry = match options.entry.as_deref() {
+        Some(label) => match program.code_labels.get(label) {
+            Some(index) => *index,
+            None if machine.labels.contains_key(label) => {
+                return Err(vec![Diagnostic::error(Code::EntryPointIsData,
+                    format!("Entry point '{}' is a data label, not code.", label))]);
             },
-            "RRX" => {
-                if parts.len() != 3 {
-                    report_error(interactive, "Usage: RRX <dest_register>, <source_register>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after destination register in RRX");
+            None => {
+                return Err(vec![Diagnostic::error(Code::UndefinedEntryPoint,
+                    format!("Entry point label '{}' is not defined.", label))]);
+            },
+        },
+        // Start at the reset handler of the target, _start or main if the program defines one, otherwise at the first instruction
+        None => options.target.map(Target::reset_handler).iter().chain(&DEFAULT_ENTRY_LABELS)
+            .find_map(|label| program.code_labels.get(*label))
+            .copied()
+            .unwrap_or(0),
+    };
+
+    match program.lines.get(entry) {
+        Some(line) => verbose!("Starting execution at {}:{}", line.file, line.line_no),
+        None => verbose!("Program has no instructions to execute"),
+    }
+    Ok(entry)
+}
+
+/// Checks `(file name, contents)` pairs without running them, returning every error
+/// that would stop them from loading.
+pub fn check_sources<I: Isa>(isa: &I, sources: &[(String, String)]) -> Vec<Diagnostic> {
+    match load_program(isa, &mut Machine::new(), sources) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors,
+    }
+}
+
+/// Collects labels and instructions from all sources, defining every label
+/// (and its data) in `machine` before any instruction runs. Instructions are
+/// decoded once all labels are known, so they may refer to labels defined later.
+fn load_program<I: Isa>(isa: &I, machine: &mut Machine, sources: &[(String, String)]) -> Result<Program<I::Instruction>, Vec<Diagnostic>> {
+    let mut program = Program { lines: Vec::new(), instructions: Vec::new(), code_labels: HashMap::new(), cycle_budget: None };
+    let errors = RefCell::new(Vec::new());
+    // Where each label was defined, for duplicate-symbol errors
+    let mut label_sites: HashMap<String, String> = HashMap::new();
+    // Frame-pointer offsets named by `.local`, per function, and the function of each line
+    let mut local_scopes: Vec<HashMap<i32, String>> = Vec::new();
+    let mut line_scopes: Vec<Option<usize>> = Vec::new();
+
+    for (file, contents) in sources {
+        let mut scope = None;
+        // A `.local` after a new label starts the locals of another function
+        let mut label_since_local = false;
+        // The generated-from line of the following instructions, and the `.file` numbers of `.loc`
+        let mut origin: Option<Origin> = None;
+        let mut origin_files: HashMap<u32, String> = HashMap::new();
+        for (index, raw_line) in contents.lines().enumerate() {
+            let line_no = index + 1;
+            let source_line = raw_line.trim_end();
+            let report = Reporter::new(&errors, file, line_no, source_line);
+
+            let mut line_to_parse = strip_comment(source_line);
+            if line_to_parse.is_empty() {
+                continue;
+            }
+
+            if let Some((label, rest)) = isa.split_label(line_to_parse) {
+                if let Some(first_site) = label_sites.get(label) {
+                    report.report(label, Diagnostic::error(Code::DuplicateLabel, format!("Duplicate label definition: {}", label))
+                        .with_note(format!("first defined at {}", first_site)));
                     continue;
                 }
-                let dest = parts[1].trim_end_matches(',');
-                let src = parts[2];
-                if let (Some(idx_dest), Some(idx_src)) = (parse_register(dest), parse_register(src)) {
-                    registers[idx_dest] = ((registers[idx_src] as u32) >> 1) as i32;
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
+                label_sites.insert(label.to_string(), format!("{}:{}", file, line_no));
+
+                match define_label(machine, label, rest, &report) {
+                    Some(rest) => {
+                        program.code_labels.insert(label.to_string(), program.lines.len());
+                        label_since_local = true;
+                        line_to_parse = rest;
+                    },
+                    None => continue,
                 }
-            },
-            "MUL" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: MUL <dest_register>, <reg_operand>, <operand>");
-                    continue;
+            }
+
+            let tokens = Tokens::split(line_to_parse);
+            let directive = tokens.mnemonic.to_lowercase();
+            if matches!(directive.as_str(), ".file" | ".loc" | "#line") {
+                let words: Vec<&str> = line_to_parse.split_whitespace().skip(1).collect();
+                if let Some(new_origin) = origin_directive(&directive, &words, &mut origin_files, origin.as_ref(), &report) {
+                    origin = new_origin;
                 }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in MUL");
-                    continue;
+                continue;
+            }
+            if directive == ".align" {
+                if let Some(words) = align_directive(&tokens, &report) {
+                    machine.next_label_mem_addr = machine.next_label_mem_addr.next_multiple_of(words);
                 }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val * op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for MUL. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for MUL must be a register, not an immediate constant.");
+                continue;
+            }
+            if directive == ".local" {
+                if let Some((name, offset)) = local_directive(&tokens, &report) {
+                    if label_since_local || scope.is_none() {
+                        local_scopes.push(HashMap::new());
+                        scope = Some(local_scopes.len() - 1);
+                        label_since_local = false;
                     }
-                } else {
-                    report_error(interactive, "Invalid destination register in MUL. Use r0 through r15.");
+                    local_scopes[scope.expect("a scope was just opened")].insert(offset, name.to_string());
                 }
-            },
-            "AND" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: AND <dest_register>, <reg_operand>, <operand>");
-                    continue;
+                continue;
+            }
+
+            if directive == ".budget" {
+                let words: Vec<&str> = line_to_parse.split_whitespace().skip(1).collect();
+                if let Some(cycles) = budget_directive(&words, &report) {
+                    match &program.cycle_budget {
+                        Some((_, site)) => report.report(".budget", Diagnostic::error(Code::InvalidOperand, "A program has one cycle budget".to_string())
+                            .with_note(format!("it was set with {}", site))),
+                        None => program.cycle_budget = Some((cycles, format!(".budget cycles {} at {}:{}", cycles, file, line_no))),
+                    }
                 }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in AND");
-                    continue;
+                continue;
+            }
+
+            if !line_to_parse.is_empty() {
+                let start = line_to_parse.as_ptr() as usize - source_line.as_ptr() as usize;
+                program.lines.push(SourceLine {
+                    file: file.clone(),
+                    line_no,
+                    source: source_line.to_string(),
+                    start,
+                    end: start + line_to_parse.len(),
+                    local: None,
+                    origin: origin.clone(),
+                });
+                line_scopes.push(scope);
+            }
+        }
+    }
+
+    // The literal pool goes after the data
+    let pool = machine.literal_pool();
+    for (line, scope) in program.lines.iter_mut().zip(line_scopes) {
+        let report = Reporter::new(&errors, &line.file, line.line_no, &line.source).with_note(line.origin_note());
+        let labels = Labels { addresses: &machine.labels, code: &program.code_labels, pool: &pool };
+        if let Some(instruction) = isa.decode(&isa.tokenize(line.text()), &labels, &report) {
+            line.local = scope.zip(isa.frame_offset(&instruction))
+                .and_then(|(scope, offset)| local_scopes[scope].get(&offset).cloned());
+            program.instructions.push(instruction);
+        }
+    }
+    machine.fill_literal_pool(&pool);
+
+    let errors = errors.into_inner();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    verbose!("Loaded {} instructions and {} labels from {} file(s)",
+             program.lines.len(), label_sites.len(), sources.len());
+    Ok(program)
+}
+
+// Rejects the immediates a real assembler could not encode, for --strict-imm, and
+// also the operands it would not accept for --profile strict
+fn check_encodings<I: Isa>(isa: &I, program: &Program<I::Instruction>, options: &RunOptions) -> Result<(), Vec<Diagnostic>> {
+    let profile = options.profile == Profile::Strict;
+    if !options.strict_imm && !profile {
+        return Ok(());
+    }
+    let option = if profile { "--profile strict" } else { "--strict-imm" };
+    let errors = RefCell::new(Vec::new());
+    for (instruction, line) in program.instructions.iter().zip(&program.lines) {
+        let report = Reporter::new(&errors, &line.file, line.line_no, &line.source).with_note(line.origin_note());
+        if let Err(message) = isa.check_immediates(instruction) {
+            report.report(line.text(), Diagnostic::error(Code::NoEncoding, format!("No {} encoding has this immediate", I::NAME.to_uppercase()))
+                .with_note(message)
+                .with_note(format!("load other constants with LDR rd, =value, or leave out {} to accept any 32-bit immediate", option)));
+        } else if profile && let Err(message) = isa.check_operands(instruction) {
+            report.report(line.text(), Diagnostic::error(Code::NoEncoding, format!("No {} encoding has these operands", I::NAME.to_uppercase()))
+                .with_note(message)
+                .with_note("--profile teaching accepts them"));
+        }
+    }
+    let errors = errors.into_inner();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(())
+}
+
+// Rejects each line whose mnemonic --allow-only leaves out or --forbid names. PRINT,
+// EXIT and BKPT are always allowed, as they show and end the result rather than compute it.
+fn check_allowed<I: Isa>(isa: &I, program: &Program<I::Instruction>, options: &RunOptions) -> Result<(), Vec<Diagnostic>> {
+    for listed in options.allow_only.iter().chain(&options.forbid) {
+        if isa.documented(listed).is_none() {
+            return Err(vec![instructions::unknown(I::INSTRUCTIONS, listed)
+                .with_note(format!("--allow-only and --forbid take mnemonics of --isa {}", I::NAME))]);
+        }
+    }
+    // A listed mnemonic stands for itself and, when it is the documented one, its variants
+    let names = |listed: &[String], mnemonic: &str| listed.iter().any(|name| name.eq_ignore_ascii_case(mnemonic)
+        || isa.documented(mnemonic).is_some_and(|info| info.mnemonic.eq_ignore_ascii_case(name)));
+    let errors = RefCell::new(Vec::new());
+    for line in &program.lines {
+        let mnemonic = Tokens::split(line.text()).mnemonic;
+        if isa.documented(mnemonic).is_some_and(|info| ["PRINT", "EXIT", "BKPT"].iter().any(|always| info.mnemonic.eq_ignore_ascii_case(always))) {
+            continue;
+        }
+        let report = Reporter::new(&errors, &line.file, line.line_no, &line.source).with_note(line.origin_note());
+        if names(&options.forbid, mnemonic) {
+            report.report(mnemonic, Diagnostic::error(Code::ForbiddenInstruction, format!("{} is not allowed here", mnemonic))
+                .with_note(format!("--forbid {} rules it out", options.forbid.join(","))));
+        } else if !options.allow_only.is_empty() && !names(&options.allow_only, mnemonic) {
+            report.report(mnemonic, Diagnostic::error(Code::ForbiddenInstruction, format!("{} is not allowed here", mnemonic))
+                .with_note(format!("--allow-only {} leaves it out", options.allow_only.join(","))));
+        }
+    }
+    let errors = errors.into_inner();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(())
+}
+
+// Warns about immediates cut down to fit their fields or --word-size, or rejects them with --strict
+fn check_truncations<I: Isa>(isa: &I, program: &Program<I::Instruction>, options: &RunOptions) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    let strict = options.strict || options.profile == Profile::Strict;
+    let word_bits = options.word_size.unwrap_or(32);
+    let diagnostics = RefCell::new(Vec::new());
+    for (instruction, line) in program.instructions.iter().zip(&program.lines) {
+        if let Some(message) = isa.truncation(instruction, word_bits) {
+            let mut diagnostic = Diagnostic::warning(Code::Truncated, format!("Immediate out of range: {}", line.text())).with_note(message);
+            if strict {
+                diagnostic.severity = Severity::Error;
+                diagnostic = diagnostic.with_note("--strict makes this warning an error");
+            }
+            Reporter::new(&diagnostics, &line.file, line.line_no, &line.source).with_note(line.origin_note())
+                .report(line.text(), diagnostic);
+        }
+    }
+    let diagnostics = diagnostics.into_inner();
+    if strict && !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    diagnostics::emit(&diagnostics);
+    Ok(diagnostics)
+}
+
+// Warns about each instruction that leaves everything as it was, for --lint
+fn check_no_effect<I: Isa>(isa: &I, program: &Program<I::Instruction>) -> Vec<Diagnostic> {
+    let diagnostics = RefCell::new(Vec::new());
+    for (instruction, line) in program.instructions.iter().zip(&program.lines) {
+        if let Some(reason) = isa.no_effect(instruction) {
+            Reporter::new(&diagnostics, &line.file, line.line_no, &line.source).with_note(line.origin_note())
+                .report(line.text(), Diagnostic::warning(Code::NoEffect, format!("{} has no effect", line.text())).with_note(reason));
+        }
+    }
+    let diagnostics = diagnostics.into_inner();
+    diagnostics::emit(&diagnostics);
+    diagnostics
+}
+
+/// Executes the program's instructions on `cores`, starting from their entry point.
+/// Stops at EXIT, at the end of the program, at the first error, after `max_steps`
+/// instructions or when interrupted. Calls, returns and other taken branches are
+/// recorded in `call_graph`.
+fn run_program<I: Isa>(isa: &I, cpu: &mut I::Cpu, program: &Program<I::Instruction>, cores: &mut Cores<I::Cpu>, options: &RunOptions, mut call_graph: Option<&mut CallGraph>, console: &mut Console) -> Result<(), Vec<Diagnostic>> {
+    let _running = options.interrupt.start();
+    let mut keyboard = match &options.keyboard {
+        Some(location) => Some(MappedKeyboard::on_terminal(location, &cpu.as_ref().labels, cpu.as_ref().memory.len()).map_err(|error| vec![*error])?),
+        None => None,
+    };
+    // The keys go to the program instead
+    let mut hotkeys = if options.hotkeys && options.debug_script.is_none() && keyboard.is_none() { Hotkeys::on_terminal() } else { None };
+    let mut breakpoints = Breakpoints::resolve(&options.breakpoints, program)?;
+    let mut session = options.debug_script.as_ref().map(Session::new);
+    let script = match &options.script {
+        Some(script) => Some(script.start(&cpu.as_ref().labels, cpu.as_ref().memory.len()).map_err(|error| vec![*error])?),
+        None => None,
+    };
+    // Their words stay in memory, where the program reaches them even in an mmio region
+    let polled = keyboard.iter().map(MappedKeyboard::address).chain(script.iter().flat_map(|script| script.addresses().iter().copied()));
+    for address in polled {
+        cpu.as_mut().devices.poll(address);
+    }
+    // A debug script, the keyboard and script devices can change registers and memory between steps, and a waiting core repeats itself while another works
+    let devices = options.script.as_ref().is_some_and(Script::has_devices);
+    let mut watchdog = (session.is_none() && keyboard.is_none() && !devices && cores.count() == 1).then(Watchdog::default);
+    if let Some(session) = session.as_mut()
+        && session.pause(isa, cpu, false, &mut breakpoints, program, console)? == Resume::Quit {
+        return Ok(());
+    }
+    let mut next = cores.pc();
+    let mut steps = 0;
+    let mut cycles = 0;
+    let budget = options.cycle_budget.map(|budget| (budget, format!("--cycle-budget {}", budget))).or_else(|| program.cycle_budget.clone());
+    if let Some((_, dir)) = &options.checkpoints {
+        fs::create_dir_all(dir).map_err(|error| vec![Diagnostic::error(Code::OutputFailed,
+            format!("Could not create the checkpoint directory {}: {}", dir.display(), error))])?;
+    }
+    // A core stops at EXIT or past the last instruction, and the program once every core has
+    while let Some(pc) = cores.next(cpu, next.filter(|&pc| pc < program.instructions.len())).map_err(|error| vec![*error])? {
+        let instruction = &program.instructions[pc];
+        let line = &program.lines[pc];
+        let core = (cores.count() > 1).then(|| cores.running());
+        let fail = |error: Box<Diagnostic>| {
+            let errors = RefCell::new(Vec::new());
+            let error = match core {
+                Some(core) => (*error).with_note(format!("this ran on core {}", core)),
+                None => *error,
+            };
+            Reporter::new(&errors, &line.file, line.line_no, &line.source).with_note(line.origin_note())
+                .report(line.text(), error);
+            errors.into_inner()
+        };
+        if options.max_steps == Some(steps) {
+            return Err(fail(Box::new(Diagnostic::error(Code::StepLimit, format!("Stopped after {} instructions", steps))
+                .with_note("the program may be stuck in a loop"))));
+        }
+        let triggered = breakpoints.hit(pc, &CpuContext { isa, cpu }).map_err(|error| fail(Box::new(Diagnostic::error(Code::InvalidBreakpoint,
+            format!("Cannot evaluate the breakpoint condition: {}", error)))))?;
+        if let Some(session) = session.as_mut() {
+            let stepped = session.step_done();
+            if triggered || stepped {
+                let pause = if triggered { "Breakpoint" } else { "Paused" };
+                console.print(format_args!("{} at {}:{}: {}", pause, line.file, line.line_no, line.text())).map_err(fail)?;
+                if session.pause(isa, cpu, false, &mut breakpoints, program, console)? == Resume::Quit {
+                    return Ok(());
                 }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val & op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for AND. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
+            }
+        } else if triggered {
+            match hotkeys.as_mut() {
+                Some(hotkeys) => hotkeys.pause(),
+                None => show_breakpoint(isa, cpu, &line.file, line.line_no, console).map_err(fail)?,
+            }
+        }
+        if let Some(hotkeys) = hotkeys.as_mut() {
+            hotkeys.before(line, &options.interrupt);
+        }
+        if let Some(keyboard) = keyboard.as_mut() {
+            keyboard.before(&mut cpu.as_mut().memory);
+        }
+        if let Some(script) = script.as_ref() {
+            let words = script.devices(&script_core(isa, cpu, steps, line.line_no)).map_err(fail)?;
+            for (address, word) in words {
+                cpu.as_mut().memory[address] = word;
+            }
+        }
+        if options.interrupt.is_requested() {
+            return Err(fail(Box::new(Diagnostic::error(Code::Interrupted, format!("Interrupted after {} instructions", steps))
+                .with_note("this line was about to run"))));
+        }
+        if let Some(found) = watchdog.as_mut().and_then(|watchdog| watchdog.check(steps, pc, || snapshot(isa, cpu, pc, console.position()))) {
+            return Err(fail(infinite_loop(program, &found)));
+        }
+        steps += 1;
+        if let Some(cache) = cpu.as_mut().cache.as_mut() {
+            cache.at(pc);
+        }
+        if log::enabled(log::Level::Verbose) {
+            let origin = line.origin.as_ref().map_or(String::new(), |origin| format!(" ({})", origin));
+            let local = line.local.as_deref().or(isa.remark(instruction)).map_or(String::new(), |note| format!("  // {}", note));
+            verbose!("{}:{}{}: {}{}", line.file, line.line_no, origin, line.text(), local);
+        }
+        let machine = cpu.as_ref();
+        let data_flow = (machine.taint.is_some() || machine.undefined.is_some() || machine.dead_stores.is_some()).then(|| isa.data_flow(cpu, instruction)).flatten();
+        if let Some(warning) = check_flags_set(isa, cpu, instruction, pc, line) {
+            diagnostics::emit(std::slice::from_ref(&warning));
+            cpu.as_mut().warnings.push(warning);
+        }
+        let before = cpu.as_ref().timeline.is_some().then(|| (cpu.as_ref().memory.clone(), isa.flags(cpu)));
+        let watched = script.as_ref().map(|script| script.watched(&cpu.as_ref().memory));
+        let watched_memory = cpu.as_ref().memory_watch.as_ref().map(|watch| watch.before(&cpu.as_ref().memory));
+        let delta_before = options.deltas.filter(|&since| steps > since).map(|_| {
+            console.capture();
+            FinalState::registers_flags_memory(isa, cpu)
+        });
+        let flow = step(isa, cpu, instruction, pc, &line.file, line.line_no, console);
+        if let Some(before) = delta_before {
+            let changed = StateDelta::between(&before, &FinalState::registers_flags_memory(isa, cpu));
+            cpu.as_mut().deltas.push(StateDelta { step: steps, line: line.line_no, output: console.take_captured(), ..changed });
+        }
+        let Machine { trace, history, taint, undefined, dead_stores, warnings, .. } = cpu.as_mut();
+        if let Some((taint, data_flow)) = taint.as_mut().zip(data_flow.as_ref()).filter(|_| flow.is_ok()) {
+            taint.step(pc, data_flow);
+        }
+        if let Some((undefined, data_flow)) = undefined.as_mut().zip(data_flow.as_ref())
+            && let Some(warning) = undefined.step(pc, data_flow, &register_name::<I>) {
+            let warning = warning.with_span(Reporter::new(&RefCell::new(Vec::new()), &line.file, line.line_no, &line.source).span(line.text()));
+            diagnostics::emit(std::slice::from_ref(&warning));
+            warnings.push(warning);
+        }
+        if let Some((dead_stores, data_flow)) = dead_stores.as_mut().zip(data_flow.as_ref()).filter(|_| flow.is_ok()) {
+            dead_stores.step(pc, data_flow);
+        }
+        if let Some(trace) = trace.as_mut() {
+            trace.executed(pc, history.recent(1).next().map_or(Vec::new(), |executed| executed.writes.clone()));
+        }
+        if let Some(before) = before {
+            let traced = match script.as_ref() {
+                Some(script) => script.traced(&script_core(isa, cpu, steps, line.line_no)).map_err(fail)?,
+                None => true,
+            };
+            if traced {
+                record_event(isa, cpu, instruction, pc, line, before, flow.as_ref().err().map(|error| &**error)).map_err(fail)?;
+            }
+        }
+        if let Machine { memory_watch: Some(watch), memory, .. } = cpu.as_mut()
+            && let Some(before) = watched_memory {
+            watch.after(&before, memory, pc, steps).map_err(fail)?;
+        }
+        if let Some((script, watched)) = script.as_ref().zip(watched) {
+            script.written(&watched, &script_core(isa, cpu, steps, line.line_no), console).map_err(fail)?;
+        }
+        if let Ok(done) = &flow {
+            let spent = isa.cycles(instruction, matches!(done, Flow::Jump(_)));
+            cycles += spent;
+            if let Some(call_graph) = call_graph.as_deref_mut() {
+                call_graph.executed(spent);
+            }
+        }
+        if let Some((_, dir)) = options.checkpoints.as_ref().filter(|&&(every, _)| flow.is_ok() && steps % every == 0) {
+            let path = dir.join(format!("step-{}.json", steps));
+            Snapshot::of(I::NAME, &FinalState::of(isa, cpu)).write(&path.display().to_string()).map_err(fail)?;
+        }
+        if let Some((budget, site)) = budget.as_ref().filter(|&&(budget, _)| cycles > budget) {
+            return Err(fail(Box::new(Diagnostic::error(Code::CycleBudget, format!("Over the budget of {} cycles: {} cycles after {} instructions", budget, cycles, steps))
+                .with_note(format!("the budget was set with {}; make the program faster, e.g. with fewer instructions in its loops", site)))));
+        }
+        next = match flow {
+            Ok(Flow::Next) => Some(pc + 1),
+            // Jumping to just past the last instruction ends the program
+            Ok(Flow::Jump(target)) if target <= program.instructions.len() => {
+                if let Some(call_graph) = call_graph.as_deref_mut() {
+                    if isa.is_call(instruction) {
+                        call_graph.call(target);
+                    } else if isa.is_return(instruction) {
+                        call_graph.ret();
                     } else {
-                        report_error(interactive, "The first operand for AND must be a register, not an immediate constant.");
+                        call_graph.jump(target);
                     }
-                } else {
-                    report_error(interactive, "Invalid destination register in AND. Use r0 through r15.");
                 }
+                Some(target)
             },
-            "ORR" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: ORR <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in ORR");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val | op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for ORR. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for ORR must be a register, not an immediate constant.");
-                    }
+            Ok(Flow::Jump(target)) => return Err(fail(Box::new(Diagnostic::error(Code::InvalidBranchTarget,
+                format!("Branch target {} is outside the program", target))
+                .with_note("return addresses must come from a call made by the program")))),
+            Ok(Flow::Breakpoint) => {
+                if let Some(session) = session.as_mut() {
+                    session.pause_next();
+                } else if let Some(hotkeys) = hotkeys.as_mut() {
+                    hotkeys.pause();
+                } else if options.strict_bkpt {
+                    return Err(fail(Box::new(Diagnostic::error(Code::Breakpoint, format!("Stopped at breakpoint after {} instructions", steps))
+                        .with_note("without --strict-bkpt, a breakpoint prints the registers and continues"))));
                 } else {
-                    report_error(interactive, "Invalid destination register in ORR. Use r0 through r15.");
+                    show_breakpoint(isa, cpu, &line.file, line.line_no, console).map_err(fail)?;
                 }
+                Some(pc + 1)
             },
-            "BIC" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: BIC <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in BIC");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val & !op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for BIC. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for BIC must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register in BIC. Use r0 through r15.");
-                }
+            Ok(Flow::Exit) => None,
+            Err(error) => return Err(fail(error)),
+        };
+        if let Some(session) = session.as_mut() {
+            session.ran();
+        }
+    }
+    // The shared memory ends up with core 0, which the run reports on
+    cores.switch(cpu, 0);
+    if let Some((budget, _)) = &budget {
+        console.print(format_args!("Used {} of {} cycles", cycles, budget)).map_err(|error| vec![*error])?;
+    }
+    // The rest of the script inspects the final state
+    if let Some(session) = session.as_mut() {
+        session.pause(isa, cpu, true, &mut breakpoints, program, console)?;
+    }
+    Ok(())
+}
+
+// Parses the source-map directives `.file <number> "<name>"`, `.loc <file> <line> [<column>]`
+// and `#line <line> ["<name>"]`, where the `.loc` file is a `.file` number or a name.
+// Returns the origin of the following instructions, if the directive changes it.
+fn origin_directive(directive: &str, words: &[&str], files: &mut HashMap<u32, String>, current: Option<&Origin>, report: &Reporter) -> Option<Option<Origin>> {
+    let usage = match directive {
+        ".file" => ".file <number> \"<name>\"",
+        ".loc" => ".loc <file> <line> [<column>]",
+        _ => "#line <line> [\"<name>\"]",
+    };
+    let malformed = |token: &str, message: String| {
+        report.report(token, Diagnostic::error(Code::InvalidOperand, message).with_note(format!("usage: {}", usage)));
+    };
+    let unquote = |name: &str| name.trim_matches('"').to_string();
+    let line_number = |token: &str| match token.parse::<usize>() {
+        Ok(line) => Some(line),
+        Err(_) => {
+            malformed(token, format!("Invalid line number: {}", token));
+            None
+        },
+    };
+    let Some(&first) = words.first() else {
+        malformed(directive, format!("Missing operands for {}", directive));
+        return None;
+    };
+
+    match directive {
+        ".file" => {
+            // `.file "name"` without a number only names the assembly file
+            if let ([_, name], Ok(number)) = (words, first.parse::<u32>()) {
+                files.insert(number, unquote(name));
+            } else if words.len() != 1 {
+                malformed(first, "Invalid .file directive".to_string());
+            }
+            None
+        },
+        ".loc" => {
+            if words.len() < 2 || words.len() > 3 {
+                malformed(first, "Wrong number of operands for .loc".to_string());
+                return None;
+            }
+            let file = match first.parse::<u32>() {
+                Ok(number) => match files.get(&number) {
+                    Some(file) => file.clone(),
+                    None => {
+                        report.report(first, Diagnostic::error(Code::InvalidOperand, format!("Unknown file number in .loc: {}", number))
+                            .with_note(format!("declare it first, e.g. .file {} \"main.c\"", number)));
+                        return None;
+                    },
+                },
+                Err(_) => unquote(first),
+            };
+            Some(Some(Origin { file, line: line_number(words[1])? }))
+        },
+        _ => {
+            let line = line_number(first)?;
+            let file = match (words.get(1), current) {
+                (Some(name), _) => unquote(name),
+                (None, Some(current)) => current.file.clone(),
+                (None, None) => {
+                    malformed(first, "#line needs a file name the first time".to_string());
+                    return None;
+                },
+            };
+            if words.len() > 2 {
+                malformed(first, "Wrong number of operands for #line".to_string());
+                return None;
+            }
+            Some(Some(Origin { file, line }))
+        },
+    }
+}
+
+// Parses ".local <name>, <offset>", naming the word at the frame pointer plus offset
+fn local_directive<'a>(tokens: &Tokens<'a>, report: &Reporter) -> Option<(&'a str, i32)> {
+    let ops = tokens.expect(2, Some(".local <name>, <offset>"), report)?;
+    let name = ops[0];
+    if name.is_empty() || !name.chars().all(|ch| ch.is_alphanumeric() || ch == '_') {
+        report.error(name, Code::InvalidOperand, format!("Invalid local variable name: {}", name));
+        return None;
+    }
+    match parse_immediate(ops[1].strip_prefix('#').unwrap_or(ops[1])) {
+        Some(offset) => Some((name, offset)),
+        None => {
+            report.report(ops[1], Diagnostic::error(Code::InvalidOperand, format!("Invalid frame offset: {}", ops[1]))
+                .with_note("the offset is in words from the frame pointer, e.g. .local count, -2"));
+            None
+        },
+    }
+}
+
+// Parses `.budget cycles <N>`, the most cycles the run may take
+fn budget_directive(words: &[&str], report: &Reporter) -> Option<u64> {
+    match words {
+        [kind, cycles] if kind.eq_ignore_ascii_case("cycles") => match cycles.parse::<u64>() {
+            Ok(cycles) if cycles > 0 => Some(cycles),
+            _ => {
+                report.report(cycles, Diagnostic::error(Code::InvalidOperand, format!("Invalid cycle budget: {}", cycles))
+                    .with_note("the budget is a number of cycles above 0, e.g. .budget cycles 500"));
+                None
             },
-            "EOR" => {
-                if parts.len() != 4 {
-                    report_error(interactive, "Usage: EOR <dest_register>, <reg_operand>, <operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') || !parts[2].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register operands in EOR");
-                    continue;
-                }
-                let dest = parts[1].trim_end_matches(',');
-                if let Some(idx_dest) = parse_register(dest) {
-                    if let Some(idx_op1) = parse_register(parts[2].trim_end_matches(',')) {
-                        let op1_val = registers[idx_op1];
-                        if let Some(op2_val) = parse_value(parts[3], &registers) {
-                            registers[idx_dest] = op1_val ^ op2_val;
-                        } else {
-                            report_error(interactive, "Invalid second operand for EOR. It must be an immediate (prefixed with '#') or a valid register.");
-                        }
-                    } else {
-                        report_error(interactive, "The first operand for EOR must be a register, not an immediate constant.");
-                    }
-                } else {
-                    report_error(interactive, "Invalid destination register in EOR. Use r0 through r15.");
-                }
+        },
+        _ => {
+            report.report(".budget", Diagnostic::error(Code::InvalidOperand, "Invalid .budget directive".to_string())
+                .with_note("usage: .budget cycles <N>"));
+            None
+        },
+    }
+}
+
+// Parses `.align <N>`, which puts the next label at a multiple of 2^N bytes as on ARM,
+// returning that multiple in words; alignments below a word change nothing
+fn align_directive(tokens: &Tokens, report: &Reporter) -> Option<usize> {
+    let ops = tokens.expect(1, Some(".align <N>"), report)?;
+    match parse_immediate(ops[0].strip_prefix('#').unwrap_or(ops[0])) {
+        Some(power @ 0..=MAX_ALIGN) => Some(((1 << power) / WORD_BYTES).max(1)),
+        _ => {
+            report.report(ops[0], Diagnostic::error(Code::InvalidOperand, format!("Invalid alignment: {}", ops[0]))
+                .with_note(format!("the next label goes at a multiple of 2^N bytes, for N from 0 to {}, e.g. .align 4 for 16 bytes", MAX_ALIGN)));
+            None
+        },
+    }
+}
+
+// Trims a line and strips any comment part (from "//" to the end of the line)
+pub fn strip_comment(line: &str) -> &str {
+    let effective_line = line.trim();
+    match effective_line.find("//") {
+        Some(comment_start_index) => effective_line[..comment_start_index].trim_end(),
+        None => effective_line,
+    }
+}
+
+// Splits "label: rest" into the label and the trimmed rest of the line
+pub fn split_label(line: &str) -> Option<(&str, &str)> {
+    let colon_index = line.find(':')?;
+    let label_candidate = line[..colon_index].trim();
+    let rest_of_line_after_colon = line[colon_index + 1..].trim();
+
+    if label_candidate.is_empty() || label_candidate.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((label_candidate, rest_of_line_after_colon))
+}
+
+// Defines a label at the next free memory address. Returns the rest of the line
+// still to be executed, or None if the line was a data definition or the label was rejected.
+pub fn define_label<'a>(
+    machine: &mut Machine,
+    label: &str,
+    rest_of_line: &'a str,
+    report: &Reporter,
+) -> Option<&'a str> {
+    if machine.labels.contains_key(label) {
+        report.error(label, Code::DuplicateLabel, format!("Duplicate label definition: {}", label));
+        return None;
+    }
+    if machine.next_label_mem_addr >= machine.memory.len() {
+        report.error(label, Code::OutOfLabelMemory, "Out of memory for new labels/data.");
+        return None;
+    }
+
+    let current_label_address = machine.next_label_mem_addr;
+
+    // Check if there's a data initializer like #value
+    if rest_of_line.starts_with('#') {
+        let value_str = rest_of_line;
+        if let Some(val) = parse_immediate(&value_str[1..]) {
+            machine.labels.insert(label.to_string(), current_label_address);
+            machine.memory[current_label_address] = narrow(val, machine.word_bits);
+            verbose!("Data label '{}' at memory address {} = {}", label, current_label_address, val);
+            machine.next_label_mem_addr += 1; // Consume memory slot for data
+        } else {
+            report.report(value_str, Diagnostic::error(Code::InvalidDataValue,
+                format!("Invalid value for label data initialization: {}", value_str))
+                .with_note("expected a format like #123, #-5, #0xFF, #0b1010_1010, #0o17 or #'A'"));
+        }
+        return None;
+    }
+
+    // This is "label:" or "label: instruction"
+    machine.labels.insert(label.to_string(), current_label_address);
+    verbose!("Label '{}' at memory address {}", label, current_label_address);
+    machine.next_label_mem_addr += 1; // Consume memory slot for the label definition itself
+    Some(rest_of_line)
+}
+
+// Helper function to parse a register name
+pub fn parse_register(reg: &str) -> Option<usize> {
+    match reg.to_lowercase().as_str() {
+        "fp" => return Some(FRAME_POINTER),
+        "sp" => return Some(STACK_POINTER),
+        "lr" => return Some(LINK_REGISTER),
+        _ => {},
+    }
+    if reg.len() < 2 || !reg.to_lowercase().starts_with('r') {
+        return None;
+    }
+    reg[1..].parse::<usize>()
+        .ok()
+        .and_then(|idx| if idx < NUM_REGISTERS { Some(idx) } else { None })
+}
+
+// Parses a single-precision floating-point register name, s0 through s31
+pub fn parse_fp_register(reg: &str) -> Option<usize> {
+    if reg.len() < 2 || !reg.to_lowercase().starts_with('s') {
+        return None;
+    }
+    reg[1..].parse::<usize>().ok().filter(|&idx| idx < NUM_FP_REGISTERS)
+}
+
+// Helper function to parse the number of an immediate (the part after '#')
+pub fn parse_immediate(imm_str: &str) -> Option<i32> {
+    // A character in single quotes stands for its code, e.g. 'A' or '\n'
+    if let Some(quoted) = imm_str.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        return parse_char(quoted);
+    }
+    let (negative, digits) = match imm_str.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, imm_str.strip_prefix('+').unwrap_or(imm_str)),
+    };
+    // Decimal, 0x hex, 0b binary or 0o octal, with any _ separators between digits
+    let (radix, magnitude) = radix::parse_unsigned(digits)?;
+    let magnitude = i64::try_from(magnitude).ok()?;
+    match i32::try_from(if negative { -magnitude } else { magnitude }) {
+        Ok(value) => Some(value),
+        // A bit pattern in another radix may set the sign bit, e.g. 0xFF000000
+        Err(_) if radix != 10 && !negative => u32::try_from(magnitude).ok().map(|pattern| pattern as i32),
+        Err(_) => None,
+    }
+}
+
+// The code of the character between the quotes of a character immediate
+fn parse_char(quoted: &str) -> Option<i32> {
+    let mut chars = quoted.chars();
+    let ch = match (chars.next()?, chars.next()) {
+        ('\\', Some(escaped)) => match escaped {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' | '\'' => escaped,
+            _ => return None,
+        },
+        (ch, None) => ch,
+        _ => return None,
+    };
+    chars.next().is_none().then_some(ch as i32)
+}
+
+
+// Helper function to parse a value operand (immediate or register)
+fn parse_value(s: &str) -> Option<Operand> {
+    if let Some(imm_str) = s.strip_prefix('#') {
+        parse_immediate(imm_str).map(Operand::Immediate)
+    } else {
+        // Otherwise, assume it's a register
+        parse_register(s).map(Operand::Register)
+    }
+}
+
+// Labels standing for their addresses in label arithmetic; memory is not read while loading
+struct LabelAddresses<'a>(&'a HashMap<String, usize>);
+
+impl expression::Context for LabelAddresses<'_> {
+    fn value(&self, name: &str) -> Option<i64> {
+        self.0.get(name).map(|&address| address as i64)
+    }
+
+    fn word(&self, _address: i64) -> Option<i64> {
+        None
+    }
+}
+
+// Whether an operand that is not a number does arithmetic, as in `array+8` or `(end-start)`
+fn is_label_arithmetic(operand: &str) -> bool {
+    operand.contains(['+', '-', '*', '/', '('])
+}
+
+// Works out label arithmetic such as `array+8` or `(end-start)`, where each label is its address
+fn label_arithmetic(operand: &str, labels: &HashMap<String, usize>, report: &Reporter) -> Option<i32> {
+    let value = expression::Expression::parse(operand)
+        .and_then(|expression| expression.evaluate(&LabelAddresses(labels)))
+        .and_then(|value| i32::try_from(value).map_err(|_| format!("{} does not fit in 32 bits", value)));
+    match value {
+        Ok(value) => Some(value),
+        Err(error) => {
+            report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("Cannot work out {}: {}", operand, error))
+                .with_note("labels stand for their addresses, e.g. array+8 or #(end-start)"));
+            None
+        },
+    }
+}
+
+// The value of a label as ADR gives it: for a code label the instruction it stands
+// for, otherwise its memory address, or label arithmetic over addresses
+fn label_value(label: &str, labels: &Labels, report: &Reporter) -> Option<i32> {
+    match (labels.code.get(label), labels.addresses.get(label)) {
+        (Some(&index), _) => Some(index as i32),
+        (None, Some(&address)) => Some(address as i32),
+        (None, None) if is_label_arithmetic(label) => label_arithmetic(label, labels.addresses, report),
+        (None, None) => {
+            report.error(label, Code::UndefinedLabel, format!("Undefined label: {}", label));
+            None
+        },
+    }
+}
+
+// Places the value of `LDR rd, =value`, a number or a label, in the literal pool
+fn literal_address(value: &str, labels: &Labels, report: &Reporter) -> Option<AddressOperand> {
+    let word = match parse_immediate(value) {
+        Some(word) => word,
+        None => label_value(value, labels, report)?,
+    };
+    let Some(address) = labels.pool.place(word) else {
+        report.error(value, Code::OutOfLabelMemory, "Out of memory for the literal pool.");
+        return None;
+    };
+    Some(AddressOperand::Absolute(address))
+}
+
+// The base register of LDREX and STREX, which take no offset
+fn exclusive_address(operand: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    match parse_address_operand(operand, labels, report)? {
+        AddressOperand::RegisterOffset(rn, 0) => Some(rn),
+        _ => {
+            report.report(operand, Diagnostic::error(Code::InvalidAddressOperand, "Exclusive loads and stores take the address in a register")
+                .with_note("use [Rn] without an offset, e.g. LDREX r0, [r1]"));
+            None
+        },
+    }
+}
+
+// Helper function to parse memory addressing modes for LDR/STR
+fn parse_address_operand(
+    operand_str: &str,
+    labels: &Labels,
+    report: &Reporter,
+) -> Option<AddressOperand> {
+    let address = resolve_address_operand(operand_str, labels.addresses, labels.pool.memory_words(), report);
+    if let Some(address) = address {
+        debug!("Address operand {} decoded as {:?}", operand_str, address);
+    }
+    address
+}
+
+fn resolve_address_operand(
+    operand_str: &str,
+    labels: &HashMap<String, usize>,
+    memory_words: usize,
+    report: &Reporter,
+) -> Option<AddressOperand> {
+    let trimmed_operand = operand_str.trim();
+
+    if trimmed_operand.starts_with('[') && trimmed_operand.ends_with(']') {
+        // Register indirect or register indirect with offset
+        let inner = &trimmed_operand[1..trimmed_operand.len()-1];
+        let parts: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
+
+        if parts.len() == 1 { // [Rx]
+            let reg_name = parts[0];
+            if let Some(reg_idx) = parse_register(reg_name) {
+                Some(AddressOperand::RegisterOffset(reg_idx, 0))
+            } else {
+                report.report(reg_name, Diagnostic::error(Code::InvalidRegister,
+                    format!("Invalid register in address operand: {}", reg_name)).with_note(REGISTER_NOTE));
+                None
+            }
+        } else if parts.len() == 2 { // [Rx, #offset]
+            let reg_name = parts[0];
+            let offset_str = parts[1];
+
+            let Some(reg_idx) = parse_register(reg_name) else {
+                report.report(reg_name, Diagnostic::error(Code::InvalidRegister,
+                    format!("Invalid register in address operand: {}", reg_name)).with_note(REGISTER_NOTE));
+                return None;
+            };
+            let Some(offset_imm) = offset_str.strip_prefix('#') else {
+                report.error(offset_str, Code::InvalidAddressOperand,
+                    "Offset in [Reg, Offset] must be an immediate value starting with #.");
+                return None;
+            };
+            if parse_immediate(offset_imm).is_none() && is_label_arithmetic(offset_imm) {
+                label_arithmetic(offset_imm, labels, report).map(|offset_val| AddressOperand::RegisterOffset(reg_idx, offset_val))
+            } else if let Some(offset_val) = parse_immediate(offset_imm) {
+                Some(AddressOperand::RegisterOffset(reg_idx, offset_val))
+            } else {
+                report.error(offset_str, Code::InvalidAddressOperand,
+                    format!("Invalid immediate offset in address operand: {}", offset_str));
+                None
+            }
+        } else {
+            report.error(trimmed_operand, Code::InvalidAddressOperand,
+                format!("Invalid address operand format: {}", trimmed_operand));
+            None
+        }
+    } else if let Some(imm_str) = trimmed_operand.strip_prefix('#') {
+        // Immediate address #0x... or #..., or label arithmetic such as #(base+2)
+        let addr_val = match parse_immediate(imm_str) {
+            Some(addr_val) => addr_val,
+            None if is_label_arithmetic(imm_str) => label_arithmetic(imm_str, labels, report)?,
+            None => {
+                report.error(trimmed_operand, Code::InvalidAddressOperand,
+                    format!("Invalid immediate address: {}", trimmed_operand));
+                return None;
             },
-            "LDR" => {
-                if parts.len() != 3 {
-                    report_error(interactive, "Usage: LDR <register>, <address_operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after register in LDR");
-                    continue;
-                }
-                let reg_name = parts[1].trim_end_matches(',');
-                let address_operand_str = parts[2];
-
-                if let Some(reg_idx) = parse_register(reg_name) {
-                    if let Some(address) = parse_address_operand(address_operand_str, &registers, &labels, &report_fn_closure) {
-                        if address < MEMORY_SIZE {
-                            registers[reg_idx] = memory[address];
-                        } else {
-                            report_error(interactive, &format!("Memory access out of bounds: address {} from operand {}", address, address_operand_str));
-                        }
-                    } // parse_address_operand already reported the error if it returned None
-                } else {
-                    report_error(interactive, "Invalid register name for LDR.");
-                }
+        };
+        checked_address(trimmed_operand, addr_val, memory_words, report)
+    } else if let Some(addr) = labels.get(trimmed_operand) {
+        Some(AddressOperand::Absolute(*addr))
+    } else if is_label_arithmetic(trimmed_operand) {
+        checked_address(trimmed_operand, label_arithmetic(trimmed_operand, labels, report)?, memory_words, report)
+    } else {
+        report.error(trimmed_operand, Code::UndefinedLabel, format!("Undefined label: {}", trimmed_operand));
+        None
+    }
+}
+
+// Checks that an address worked out while loading is in memory
+fn checked_address(operand: &str, addr_val: i32, memory_words: usize, report: &Reporter) -> Option<AddressOperand> {
+    if addr_val < 0 {
+        report.error(operand, Code::NegativeAddress,
+            format!("Memory address cannot be negative: {}", addr_val));
+        return None;
+    }
+    if addr_val as usize >= memory_words {
+        report.error(operand, Code::MemoryOutOfBounds,
+            format!("Memory access out of bounds: address {} >= MEMORY_SIZE {}", addr_val, memory_words));
+        return None;
+    }
+    Some(AddressOperand::Absolute(addr_val as usize))
+}
+
+// Checks the operand count and the commas between operands of an instruction,
+// returning the operands.
+fn operands<'a>(tokens: &Tokens<'a>, count: usize, report: &Reporter) -> Option<Vec<&'a str>> {
+    tokens.expect_instruction(count, documented(tokens.mnemonic), report)
+}
+
+// Variants share the usage of the instruction they are documented under
+fn documented(mnemonic: &str) -> Option<&'static InstructionInfo> {
+    let mnemonic = mnemonic.to_uppercase();
+    let documented = match mnemonic.as_str() {
+        "VMOV.F32" => "VMOV",
+        "LSLS" | "LSRS" | "ASRS" | "RORS" | "ADDS" | "SUBS" | "ADCS" | "SBCS" | "MULS" | "MLAS" | "ANDS" | "ORRS" | "BICS" | "EORS" => &mnemonic[..3],
+        m if m.starts_with('B') && parse_condition(&m[1..]).is_some() => "B",
+        m if m.starts_with("SMUL") => "SMULBB",
+        m if m.starts_with("SMLA") => "SMLABB",
+        m if parse_parallel_op(m).is_some() => "SADD8",
+        m => m,
+    };
+    instructions::lookup(documented)
+}
+
+// Parses a condition code suffix such as EQ or GT; no suffix means always
+fn parse_condition(suffix: &str) -> Option<Condition> {
+    Some(match suffix {
+        "EQ" => Condition::Eq,
+        "NE" => Condition::Ne,
+        "CS" | "HS" => Condition::Cs,
+        "CC" | "LO" => Condition::Cc,
+        "MI" => Condition::Mi,
+        "PL" => Condition::Pl,
+        "VS" => Condition::Vs,
+        "VC" => Condition::Vc,
+        "HI" => Condition::Hi,
+        "LS" => Condition::Ls,
+        "GE" => Condition::Ge,
+        "LT" => Condition::Lt,
+        "GT" => Condition::Gt,
+        "LE" => Condition::Le,
+        "AL" | "" => Condition::Al,
+        _ => return None,
+    })
+}
+
+// Resolves the label a branch jumps to into an instruction index
+fn branch_target(label: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    if let Some(index) = labels.code.get(label) {
+        Some(*index)
+    } else if labels.addresses.contains_key(label) {
+        report.error(label, Code::InvalidBranchTarget, format!("Cannot branch to '{}': it is a data label, not code.", label));
+        None
+    } else {
+        report.error(label, Code::UndefinedLabel, format!("Undefined label: {}", label));
+        None
+    }
+}
+
+// Parses the status register operand of MRS
+fn psr_operand(operand: &str, report: &Reporter) -> Option<()> {
+    match operand.to_uppercase().as_str() {
+        "CPSR" | "APSR" => Some(()),
+        name => {
+            psr_error(operand, name, report);
+            None
+        },
+    }
+}
+
+// Parses the status register and fields written by MSR, e.g. CPSR_f or APSR_nzcvq,
+// returning the mask of CPSR bits that may change
+fn psr_fields_operand(operand: &str, report: &Reporter) -> Option<u32> {
+    let name = operand.to_uppercase();
+    let fields = match name.split_once('_') {
+        Some(("CPSR", fields)) => fields,
+        // MSR CPSR, r0 writes the flags and control fields
+        None if name == "CPSR" => "FC",
+        Some(("APSR", "NZCVQ")) => "F",
+        Some(("APSR", "G")) => "S",
+        Some(("APSR", "NZCVQG")) => "FS",
+        _ => {
+            psr_error(operand, &name, report);
+            return None;
+        },
+    };
+    let mut mask = 0;
+    for field in fields.chars() {
+        mask |= match field {
+            'F' => CPSR_FLAGS_FIELD,
+            'S' => CPSR_STATUS_FIELD,
+            // The extension and control fields cannot be changed in User mode
+            'X' | 'C' => 0,
+            _ => {
+                report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("Invalid status register field in {}", operand))
+                    .with_note("CPSR fields are f (flags), s (status), x (extension) and c (control), e.g. CPSR_f"));
+                return None;
             },
-            "STR" => {
-                if parts.len() != 3 { 
-                    report_error(interactive, "Usage: STR <source_register>, <address_operand>");
-                    continue;
-                }
-                if !parts[1].ends_with(',') {
-                    report_error(interactive, "Syntax error: Missing comma after source register in STR");
-                    continue;
+        };
+    }
+    Some(mask)
+}
+
+fn psr_error(operand: &str, name: &str, report: &Reporter) {
+    let message = if name.starts_with("SPSR") {
+        "SPSR is not available: programs run in User mode, which has no saved status register".to_string()
+    } else {
+        format!("Invalid status register: {}", operand)
+    };
+    report.report(operand, Diagnostic::error(Code::InvalidOperand, message).with_note(PSR_NOTE));
+}
+
+// Splits a parallel add/subtract mnemonic such as UQSUB16 into its operation and lane width
+fn parse_parallel_op(mnemonic: &str) -> Option<(ParallelOp, u32)> {
+    let (name, lane_bits) = if let Some(name) = mnemonic.strip_suffix("16") {
+        (name, 16)
+    } else {
+        (mnemonic.strip_suffix('8')?, 8)
+    };
+    let op = match name {
+        "SADD" => ParallelOp::Sadd,
+        "UADD" => ParallelOp::Uadd,
+        "SSUB" => ParallelOp::Ssub,
+        "USUB" => ParallelOp::Usub,
+        "QADD" => ParallelOp::Qadd,
+        "QSUB" => ParallelOp::Qsub,
+        "UQADD" => ParallelOp::Uqadd,
+        "UQSUB" => ParallelOp::Uqsub,
+        _ => return None,
+    };
+    Some((op, lane_bits))
+}
+
+// Parses the Qn format of PRINT, e.g. q15 or q31
+fn q_format_operand(operand: &str, report: &Reporter) -> Option<u32> {
+    let bits = operand.strip_prefix(['q', 'Q'])
+        .and_then(|bits| bits.parse::<u32>().ok())
+        .filter(|bits| (1..=31).contains(bits));
+    if bits.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("Invalid fixed-point format: {}", operand))
+            .with_note("use q15 or q31, or any qN with N fraction bits from 1 to 31"));
+    }
+    bits
+}
+
+// Parses an operand that must be a register
+fn register_operand(operand: &str, role: &str, report: &Reporter) -> Option<usize> {
+    let idx = parse_register(operand);
+    if idx.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidRegister,
+            format!("Invalid {}: {}", role, operand)).with_note(REGISTER_NOTE));
+    }
+    idx
+}
+
+// Parses an operand that must be a floating-point register
+fn fp_register_operand(operand: &str, role: &str, report: &Reporter) -> Option<usize> {
+    let idx = parse_fp_register(operand);
+    if idx.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidRegister,
+            format!("Invalid {}: {}", role, operand)).with_note(FP_REGISTER_NOTE));
+    }
+    idx
+}
+
+// Parses the second operand of VMOV: a float immediate, an s register or a core register
+fn fp_source_operand(operand: &str, report: &Reporter) -> Option<FpSource> {
+    let source = if let Some(imm_str) = operand.strip_prefix('#') {
+        imm_str.parse::<f32>().ok().map(|val| FpSource::Immediate(val.to_bits()))
+    } else if let Some(sm) = parse_fp_register(operand) {
+        Some(FpSource::Register(sm))
+    } else {
+        parse_register(operand).map(FpSource::Core)
+    };
+    if source.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, "Invalid operand for VMOV.")
+            .with_note("use a float immediate (e.g. #1.5), an s register or an r register"));
+    }
+    source
+}
+
+// Parses the first source operand of a data-processing instruction, which must be a register
+fn first_operand_register(operand: &str, mnemonic: &str, report: &Reporter) -> Option<usize> {
+    if operand.starts_with('#') {
+        report.error(operand, Code::RegisterExpected,
+            format!("The first operand for {} must be a register, not an immediate constant.", mnemonic));
+        return None;
+    }
+    register_operand(operand, "first operand register", report)
+}
+
+// Parses an operand that may be an immediate, label arithmetic such as `#(end-start)` or a register
+fn value_operand(operand: &str, labels: &HashMap<String, usize>, message: &str, report: &Reporter) -> Option<Operand> {
+    if let Some(arithmetic) = operand.strip_prefix('#').filter(|text| parse_immediate(text).is_none() && is_label_arithmetic(text)) {
+        return label_arithmetic(arithmetic, labels, report).map(Operand::Immediate);
+    }
+    let val = parse_value(operand);
+    if val.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, message)
+            .with_note("use an immediate prefixed with '#' (e.g. #15 or #0x10) or a register"));
+    }
+    val
+}
+
+// Decodes a single instruction (labels already stripped), reporting any errors.
+pub fn decode(tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+    let mnemonic = tokens.mnemonic.to_uppercase();
+    debug!("Decoding {} with operands {:?}", mnemonic, tokens.operands);
+
+    let instruction = match mnemonic.as_str() {
+        "EXIT" => {
+            operands(tokens, 0, report)?;
+            Instruction::Exit
+        },
+        "BKPT" => {
+            // The optional #imm is for the debugger, except for the semihosting call
+            let mut number = 0;
+            if !tokens.operands.is_empty() {
+                let ops = operands(tokens, 1, report)?;
+                let Some(Operand::Immediate(immediate @ 0..=0xFFFF)) = parse_value(ops[0]) else {
+                    report.report(ops[0], Diagnostic::error(Code::InvalidOperand, "Invalid operand for BKPT.")
+                        .with_note("the operand is an immediate from #0 to #65535"));
+                    return None;
+                };
+                number = immediate as u32;
+            }
+            Instruction::Bkpt { number }
+        },
+        "MOV" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let src = value_operand(ops[1], labels.addresses, "Invalid operand for MOV.", report)?;
+            Instruction::Mov { rd, src }
+        },
+        "ADD" | "SUB" | "ADC" | "SBC" | "MUL" | "AND" | "ORR" | "BIC" | "EOR"
+            | "ADDS" | "SUBS" | "ADCS" | "SBCS" | "MULS" | "ANDS" | "ORRS" | "BICS" | "EORS" => {
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            // The first operand must be a register
+            let rn = first_operand_register(ops[1], &mnemonic, report)?;
+            // The second operand may be an immediate or a register
+            let message = format!("Invalid second operand for {}. It must be an immediate (prefixed with '#') or a valid register.", mnemonic);
+            let op2 = value_operand(ops[2], labels.addresses, &message, report)?;
+            let op = match &mnemonic[..3] {
+                "ADD" => AluOp::Add,
+                "SUB" => AluOp::Sub,
+                "ADC" => AluOp::Adc,
+                "SBC" => AluOp::Sbc,
+                "MUL" => AluOp::Mul,
+                "AND" => AluOp::And,
+                "ORR" => AluOp::Orr,
+                "BIC" => AluOp::Bic,
+                _ => AluOp::Eor,
+            };
+            Instruction::Alu { op, set_flags: mnemonic.len() == 4, rd, rn, op2 }
+        },
+        "CMP" | "CMN" => {
+            let ops = operands(tokens, 2, report)?;
+            let rn = first_operand_register(ops[0], &mnemonic, report)?;
+            let message = format!("Invalid second operand for {}. It must be an immediate (prefixed with '#') or a valid register.", mnemonic);
+            let op2 = value_operand(ops[1], labels.addresses, &message, report)?;
+            if mnemonic == "CMP" {
+                Instruction::Cmp { rn, op2 }
+            } else {
+                Instruction::Cmn { rn, op2 }
+            }
+        },
+        "BL" => {
+            let ops = operands(tokens, 1, report)?;
+            Instruction::Bl { target: branch_target(ops[0], labels, report)? }
+        },
+        "BX" => {
+            let ops = operands(tokens, 1, report)?;
+            Instruction::Bx { rm: register_operand(ops[0], "register", report)? }
+        },
+        m if m.starts_with('B') && parse_condition(&m[1..]).is_some() => {
+            let cond = parse_condition(&m[1..])?;
+            let ops = operands(tokens, 1, report)?;
+            Instruction::Branch { cond, target: branch_target(ops[0], labels, report)? }
+        },
+        "LSL" | "LSR" | "ASR" | "ROR" | "LSLS" | "LSRS" | "ASRS" | "RORS" => {
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rm = register_operand(ops[1], "source register", report)?;
+            let amount_name = if mnemonic.starts_with("ROR") { "rotate" } else { "shift" };
+            let message = format!("Invalid {} amount for {} instruction.", amount_name, mnemonic);
+            let amount = value_operand(ops[2], labels.addresses, &message, report)?;
+            let op = match &mnemonic[..3] {
+                "LSL" => ShiftOp::Lsl,
+                "LSR" => ShiftOp::Lsr,
+                "ASR" => ShiftOp::Asr,
+                _ => ShiftOp::Ror,
+            };
+            Instruction::Shift { op, set_flags: mnemonic.len() == 4, rd, rm, amount }
+        },
+        "RRX" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rm = register_operand(ops[1], "source register", report)?;
+            Instruction::Rrx { rd, rm }
+        },
+        "LDR" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let address = match ops[1].strip_prefix('=') {
+                Some(value) => literal_address(value.trim(), labels, report)?,
+                None => parse_address_operand(ops[1], labels, report)?,
+            };
+            Instruction::Ldr { rd, address }
+        },
+        "STR" => {
+            let ops = operands(tokens, 2, report)?;
+            let rs = register_operand(ops[0], "source register", report)?;
+            let address = parse_address_operand(ops[1], labels, report)?;
+            Instruction::Str { rs, address }
+        },
+        "LDREX" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            Instruction::Ldrex { rd, rn: exclusive_address(ops[1], labels, report)? }
+        },
+        "STREX" => {
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "status register", report)?;
+            let rs = register_operand(ops[1], "source register", report)?;
+            Instruction::Strex { rd, rs, rn: exclusive_address(ops[2], labels, report)? }
+        },
+        "ADR" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            Instruction::Adr { rd, address: label_value(ops[1], labels, report)? }
+        },
+        "SVC" => {
+            let ops = operands(tokens, 1, report)?;
+            let Some(Operand::Immediate(number @ 0..=0xFF_FFFF)) = parse_value(ops[0]) else {
+                report.report(ops[0], Diagnostic::error(Code::InvalidOperand, "Invalid operand for SVC.")
+                    .with_note("the operand is the number of the service, an immediate from #0 to #16777215"));
+                return None;
+            };
+            Instruction::Svc { number: number as u32 }
+        },
+        "CLREX" => {
+            operands(tokens, 0, report)?;
+            Instruction::Clrex
+        },
+        "DMB" | "DSB" | "ISB" => {
+            if !tokens.operands.is_empty() {
+                let ops = operands(tokens, 1, report)?;
+                // ISB only has the full-system form
+                let option = ops[0].to_ascii_uppercase();
+                if !(option == "SY" || mnemonic != "ISB" && BARRIER_OPTIONS.contains(&option.as_str())) {
+                    let options = if mnemonic == "ISB" { "SY".to_string() } else { BARRIER_OPTIONS.join(", ") };
+                    report.report(ops[0], Diagnostic::error(Code::InvalidOperand, format!("Invalid option for {}: {}", mnemonic, ops[0]))
+                        .with_note(format!("the options are {}", options)));
+                    return None;
                 }
-                let src_reg_name = parts[1].trim_end_matches(',');
-                let address_operand_str = parts[2];
-
-                if let Some(idx_src) = parse_register(src_reg_name) {
-                    if let Some(address) = parse_address_operand(address_operand_str, &registers, &labels, &report_fn_closure) {
-                        if address < MEMORY_SIZE {
-                            memory[address] = registers[idx_src];
-                        } else {
-                            report_error(interactive, &format!("Memory access out of bounds: address {} >= MEMORY_SIZE {}", address, MEMORY_SIZE));
-                        }
-                    } // parse_address_operand already reports errors
-                } else {
-                    report_error(interactive, &format!("Invalid source register in STR: {}", src_reg_name));
+            }
+            let kind = match mnemonic.as_str() {
+                "DMB" => Barrier::Dmb,
+                "DSB" => Barrier::Dsb,
+                _ => Barrier::Isb,
+            };
+            Instruction::Barrier { kind }
+        },
+        "VADD.F32" | "VSUB.F32" | "VMUL.F32" | "VDIV.F32" => {
+            let ops = operands(tokens, 3, report)?;
+            let sd = fp_register_operand(ops[0], "destination register", report)?;
+            let sn = fp_register_operand(ops[1], "first operand register", report)?;
+            let sm = fp_register_operand(ops[2], "second operand register", report)?;
+            let op = match &mnemonic[..4] {
+                "VADD" => VfpOp::Add,
+                "VSUB" => VfpOp::Sub,
+                "VMUL" => VfpOp::Mul,
+                _ => VfpOp::Div,
+            };
+            Instruction::Vfp { op, sd, sn, sm }
+        },
+        "VMOV" | "VMOV.F32" => {
+            let ops = operands(tokens, 2, report)?;
+            if let Some(rd) = parse_register(ops[0]) {
+                let sn = fp_register_operand(ops[1], "source register", report)?;
+                Instruction::VmovToCore { rd, sn }
+            } else {
+                let sd = fp_register_operand(ops[0], "destination register", report)?;
+                let src = fp_source_operand(ops[1], report)?;
+                Instruction::Vmov { sd, src }
+            }
+        },
+        "VLDR" | "VSTR" => {
+            let ops = operands(tokens, 2, report)?;
+            let role = if mnemonic == "VLDR" { "destination register" } else { "source register" };
+            let sd = fp_register_operand(ops[0], role, report)?;
+            let address = parse_address_operand(ops[1], labels, report)?;
+            if mnemonic == "VLDR" {
+                Instruction::Vldr { sd, address }
+            } else {
+                Instruction::Vstr { sd, address }
+            }
+        },
+        "VCVT.S32.F32" | "VCVT.F32.S32" => {
+            let ops = operands(tokens, 2, report)?;
+            let sd = fp_register_operand(ops[0], "destination register", report)?;
+            let sm = fp_register_operand(ops[1], "source register", report)?;
+            Instruction::Vcvt { to_int: mnemonic == "VCVT.S32.F32", sd, sm }
+        },
+        "MLA" | "MLAS" | "MLS" => {
+            let ops = operands(tokens, 4, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let rm = register_operand(ops[2], "second operand register", report)?;
+            let ra = register_operand(ops[3], "accumulate register", report)?;
+            Instruction::MultiplyAccumulate { subtract: mnemonic == "MLS", set_flags: mnemonic == "MLAS", rd, rn, rm, ra }
+        },
+        "SMULBB" | "SMULBT" | "SMULTB" | "SMULTT" | "SMLABB" | "SMLABT" | "SMLATB" | "SMLATT" => {
+            let accumulate = mnemonic.starts_with("SMLA");
+            let ops = operands(tokens, if accumulate { 4 } else { 3 }, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let rm = register_operand(ops[2], "second operand register", report)?;
+            let ra = if accumulate { Some(register_operand(ops[3], "accumulate register", report)?) } else { None };
+            let halves = mnemonic.as_bytes();
+            Instruction::HalfwordMultiply { rd, rn, rm, rn_top: halves[4] == b'T', rm_top: halves[5] == b'T', ra }
+        },
+        "SEL" => {
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let rm = register_operand(ops[2], "second operand register", report)?;
+            Instruction::Sel { rd, rn, rm }
+        },
+        "MRS" => {
+            let ops = operands(tokens, 2, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            psr_operand(ops[1], report)?;
+            Instruction::Mrs { rd }
+        },
+        "MSR" => {
+            let ops = operands(tokens, 2, report)?;
+            let mask = psr_fields_operand(ops[0], report)?;
+            let src = value_operand(ops[1], labels.addresses, "Invalid source operand for MSR.", report)?;
+            Instruction::Msr { mask, src }
+        },
+        m if parse_parallel_op(m).is_some() => {
+            let (op, lane_bits) = parse_parallel_op(m)?;
+            let ops = operands(tokens, 3, report)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rn = register_operand(ops[1], "first operand register", report)?;
+            let rm = register_operand(ops[2], "second operand register", report)?;
+            Instruction::Parallel { op, lane_bits, rd, rn, rm }
+        },
+        "PRINT" => {
+            // PRINT r0 or PRINT r0, q15
+            let count = if tokens.operands.len() > 1 { 2 } else { 1 };
+            let ops = operands(tokens, count, report)?;
+            if let Some(reg) = parse_fp_register(ops[0]) {
+                // Floats have no fixed-point format
+                if count == 2 {
+                    operands(tokens, 1, report)?;
                 }
+                return Some(Instruction::PrintFloat { reg, name: ops[0].to_string() });
+            }
+            let reg = register_operand(ops[0], "register", report)?;
+            let fraction_bits = if count == 2 { Some(q_format_operand(ops[1], report)?) } else { None };
+            Instruction::Print { reg, name: ops[0].to_string(), fraction_bits }
+        },
+        m => {
+            let Some((index, count)) = plugin::instruction(m) else {
+                report.report(tokens.mnemonic, instructions::unknown(instructions::INSTRUCTIONS, tokens.mnemonic));
+                return None;
+            };
+            let ops = tokens.expect(count as usize, None, report)?;
+            let message = format!("Invalid operand for {}.", m);
+            let operands = ops.iter().map(|&operand| value_operand(operand, labels.addresses, &message, report).map(|operand| match operand {
+                Operand::Immediate(value) => plugin::Operand { kind: plugin::Operand::IMMEDIATE, value },
+                Operand::Register(reg) => plugin::Operand { kind: plugin::Operand::REGISTER, value: reg as i32 },
+            })).collect::<Option<Vec<_>>>()?;
+            Instruction::Plugin { index, operands }
+        }
+    };
+    Some(instruction)
+}
+
+// What an ARM instruction reads and writes for --taint. Floating-point registers
+// follow the core registers, s0 at NUM_REGISTERS.
+fn data_flow(machine: &Machine, instruction: &Instruction) -> DataFlow {
+    let operand = |operand: Operand| match operand {
+        Operand::Register(reg) => vec![reg],
+        Operand::Immediate(_) => Vec::new(),
+    };
+    let address = |address: AddressOperand| effective_address(address, &machine.registers, machine.memory.len()).ok();
+    let base = |address: AddressOperand| match address {
+        AddressOperand::RegisterOffset(reg, _) => vec![reg],
+        AddressOperand::Absolute(_) => Vec::new(),
+    };
+    let fp = |reg: usize| NUM_REGISTERS + reg;
+    match *instruction {
+        Instruction::Mov { rd, src } => DataFlow { reads: operand(src), writes: vec![rd], ..DataFlow::default() },
+        Instruction::Alu { op, set_flags, rd, rn, op2 } => DataFlow {
+            reads: [vec![rn], operand(op2)].concat(),
+            writes: vec![rd],
+            reads_flags: matches!(op, AluOp::Adc | AluOp::Sbc),
+            writes_flags: set_flags,
+            ..DataFlow::default()
+        },
+        Instruction::Cmp { rn, op2 } | Instruction::Cmn { rn, op2 } =>
+            DataFlow { reads: [vec![rn], operand(op2)].concat(), writes_flags: true, ..DataFlow::default() },
+        Instruction::Branch { cond, .. } => DataFlow { reads_flags: cond != Condition::Al, branches: cond != Condition::Al, ..DataFlow::default() },
+        // The return address is never tainted
+        Instruction::Bl { .. } => DataFlow { writes: vec![LINK_REGISTER], ..DataFlow::default() },
+        Instruction::Bx { rm } => DataFlow { reads: vec![rm], branches: true, ..DataFlow::default() },
+        Instruction::Shift { set_flags, rd, rm, amount, .. } =>
+            DataFlow { reads: [vec![rm], operand(amount)].concat(), writes: vec![rd], writes_flags: set_flags, ..DataFlow::default() },
+        Instruction::Rrx { rd, rm } => DataFlow { reads: vec![rm], writes: vec![rd], reads_flags: true, ..DataFlow::default() },
+        Instruction::Ldr { rd, address: operand } => DataFlow { writes: vec![rd], load: address(operand), address: base(operand), ..DataFlow::default() },
+        Instruction::Str { rs, address: operand } => DataFlow { reads: vec![rs], store: address(operand), address: base(operand), ..DataFlow::default() },
+        Instruction::Ldrex { rd, rn } => DataFlow { writes: vec![rd], load: address(AddressOperand::RegisterOffset(rn, 0)), address: vec![rn], ..DataFlow::default() },
+        Instruction::Strex { rd, rs, rn } =>
+            DataFlow { reads: vec![rs], writes: vec![rd], store: address(AddressOperand::RegisterOffset(rn, 0)), address: vec![rn], ..DataFlow::default() },
+        Instruction::Vfp { sd, sn, sm, .. } => DataFlow { reads: vec![fp(sn), fp(sm)], writes: vec![fp(sd)], ..DataFlow::default() },
+        Instruction::Vmov { sd, src } => DataFlow {
+            reads: match src {
+                FpSource::Immediate(_) => Vec::new(),
+                FpSource::Register(sm) => vec![fp(sm)],
+                FpSource::Core(rm) => vec![rm],
+            },
+            writes: vec![fp(sd)],
+            ..DataFlow::default()
+        },
+        Instruction::VmovToCore { rd, sn } => DataFlow { reads: vec![fp(sn)], writes: vec![rd], ..DataFlow::default() },
+        Instruction::Vldr { sd, address: operand } => DataFlow { writes: vec![fp(sd)], load: address(operand), address: base(operand), ..DataFlow::default() },
+        Instruction::Vstr { sd, address: operand } => DataFlow { reads: vec![fp(sd)], store: address(operand), address: base(operand), ..DataFlow::default() },
+        Instruction::Vcvt { sd, sm, .. } => DataFlow { reads: vec![fp(sm)], writes: vec![fp(sd)], ..DataFlow::default() },
+        Instruction::MultiplyAccumulate { set_flags, rd, rn, rm, ra, .. } =>
+            DataFlow { reads: vec![rn, rm, ra], writes: vec![rd], writes_flags: set_flags, ..DataFlow::default() },
+        Instruction::HalfwordMultiply { rd, rn, rm, ra, .. } =>
+            DataFlow { reads: [vec![rn, rm], ra.into_iter().collect()].concat(), writes: vec![rd], ..DataFlow::default() },
+        Instruction::Parallel { rd, rn, rm, .. } => DataFlow { reads: vec![rn, rm], writes: vec![rd], writes_flags: true, ..DataFlow::default() },
+        Instruction::Sel { rd, rn, rm } => DataFlow { reads: vec![rn, rm], writes: vec![rd], reads_flags: true, ..DataFlow::default() },
+        Instruction::Mrs { rd } => DataFlow { writes: vec![rd], reads_flags: true, ..DataFlow::default() },
+        Instruction::Msr { src, .. } => DataFlow { reads: operand(src), writes_flags: true, ..DataFlow::default() },
+        Instruction::Print { reg, .. } => DataFlow { reads: vec![reg], prints: true, ..DataFlow::default() },
+        Instruction::PrintFloat { reg, .. } => DataFlow { reads: vec![fp(reg)], prints: true, ..DataFlow::default() },
+        Instruction::Adr { rd, .. } => DataFlow { writes: vec![rd], ..DataFlow::default() },
+        // Nor are the words a call to the host prints
+        Instruction::Svc { .. } | Instruction::Bkpt { .. } if is_semihosting(instruction) =>
+            DataFlow { reads: vec![0], writes: vec![0], address: vec![1], prints: true, ..DataFlow::default() },
+        Instruction::Svc { number: SVC_BEEP } => DataFlow { reads: vec![0, 1], prints: true, ..DataFlow::default() },
+        // The registers a context switch saves and loads are not followed
+        Instruction::Svc { number: SVC_CREATE_TASK } => DataFlow { reads: vec![0, 1, 2], writes: vec![0], ..DataFlow::default() },
+        // Nor are the words of the strings the helpers read and write
+        Instruction::Svc { number: SVC_STRLEN } => DataFlow { writes: vec![0], address: vec![0], ..DataFlow::default() },
+        Instruction::Svc { number: SVC_STRCMP } => DataFlow { writes: vec![0], address: vec![0, 1], ..DataFlow::default() },
+        Instruction::Svc { number: SVC_MEMCPY } => DataFlow { reads: vec![2], address: vec![0, 1], ..DataFlow::default() },
+        // Nor are the registers a plugin uses
+        Instruction::Clrex | Instruction::Barrier { .. } | Instruction::Svc { .. } | Instruction::Plugin { .. } | Instruction::Bkpt { .. } | Instruction::Exit => DataFlow::default(),
+    }
+}
+
+// Checks a program's access to memory against the memory map, then counts it for --heatmap
+// and --cache, which never keeps device registers
+fn count_access(accesses: &mut Accesses, cache: &mut Option<Cache>, map: &Option<MemoryMap>, address: usize, write: bool) -> Result<(), Box<Diagnostic>> {
+    if let Some(map) = map {
+        map.check(address, write)?;
+    }
+    if write {
+        accesses.write(address);
+    } else {
+        accesses.read(address);
+    }
+    if let Some(cache) = cache.as_mut().filter(|_| !map.as_ref().is_some_and(|map| map.is_mmio(address))) {
+        cache.access(address);
+    }
+    Ok(())
+}
+
+fn check_bounds(address: usize, memory_words: usize) -> Result<(), Box<Diagnostic>> {
+    if address >= memory_words {
+        return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+            format!("Memory access out of bounds: address {} >= MEMORY_SIZE {}", address, memory_words))));
+    }
+    Ok(())
+}
+
+// Computes the memory address of an address operand, checking it is within memory
+fn effective_address(address: AddressOperand, registers: &[i32], memory_words: usize) -> Result<usize, Box<Diagnostic>> {
+    let address = match address {
+        AddressOperand::Absolute(addr) => addr,
+        AddressOperand::RegisterOffset(reg_idx, offset_val) => {
+            let base_address = registers[reg_idx] as usize;
+            if offset_val < 0 {
+                // Handle negative offsets by subtracting magnitude
+                base_address.saturating_sub(offset_val.unsigned_abs() as usize)
+            } else {
+                base_address.saturating_add(offset_val as usize)
+            }
+        },
+    };
+    check_bounds(address, memory_words)?;
+    Ok(address)
+}
+
+// Adds or subtracts each byte or halfword lane of `a` and `b` separately. Returns the
+// packed result and, for the non-saturating operations, the new GE flags.
+fn parallel(op: ParallelOp, lane_bits: u32, a: i32, b: i32) -> (i32, Option<u32>) {
+    let mask = (1i64 << lane_bits) - 1;
+    let (min_signed, max_signed) = (-(1i64 << (lane_bits - 1)), (1i64 << (lane_bits - 1)) - 1);
+    let mut result = 0u32;
+    let mut ge = 0u32;
+    for lane in 0..32 / lane_bits {
+        let shift = lane * lane_bits;
+        let unsigned = |value: i32| ((value as u32 >> shift) as i64) & mask;
+        let signed = |value: i32| {
+            let lane_value = unsigned(value);
+            if lane_value > max_signed { lane_value - (mask + 1) } else { lane_value }
+        };
+        let (value, lane_ge) = match op {
+            ParallelOp::Sadd => { let sum = signed(a) + signed(b); (sum, sum >= 0) },
+            ParallelOp::Uadd => { let sum = unsigned(a) + unsigned(b); (sum, sum > mask) },
+            ParallelOp::Ssub => { let diff = signed(a) - signed(b); (diff, diff >= 0) },
+            ParallelOp::Usub => { let diff = unsigned(a) - unsigned(b); (diff, diff >= 0) },
+            ParallelOp::Qadd => ((signed(a) + signed(b)).clamp(min_signed, max_signed), false),
+            ParallelOp::Qsub => ((signed(a) - signed(b)).clamp(min_signed, max_signed), false),
+            ParallelOp::Uqadd => ((unsigned(a) + unsigned(b)).min(mask), false),
+            ParallelOp::Uqsub => ((unsigned(a) - unsigned(b)).max(0), false),
+        };
+        result |= ((value & mask) as u32) << shift;
+        if lane_ge {
+            // A halfword lane sets the GE flags of both its bytes
+            let lane_flags = if lane_bits == 16 { 0b11 } else { 0b1 };
+            ge |= lane_flags << (shift / 8);
+        }
+    }
+    let sets_ge = matches!(op, ParallelOp::Sadd | ParallelOp::Uadd | ParallelOp::Ssub | ParallelOp::Usub);
+    (result as i32, sets_ge.then_some(ge))
+}
+
+// Shifts `value` by `amount` like the barrel shifter, returning the result and the
+// carry out: the last bit shifted out, or `carry_in` when the amount is 0
+fn shift_with_carry(op: ShiftOp, value: i32, amount: u32, carry_in: bool) -> (i32, bool) {
+    let bits = value as u32;
+    let bit = |n: u32| (bits >> n) & 1 != 0;
+    if amount == 0 {
+        return (value, carry_in);
+    }
+    match op {
+        ShiftOp::Lsl => match amount {
+            1..=31 => ((bits << amount) as i32, bit(32 - amount)),
+            32 => (0, bit(0)),
+            _ => (0, false),
+        },
+        ShiftOp::Lsr => match amount {
+            1..=31 => ((bits >> amount) as i32, bit(amount - 1)),
+            32 => (0, bit(31)),
+            _ => (0, false),
+        },
+        // Shifting by 32 or more fills every bit with the sign
+        ShiftOp::Asr => match amount {
+            1..=31 => (value >> amount, bit(amount - 1)),
+            _ => (value >> 31, bit(31)),
+        },
+        ShiftOp::Ror => {
+            let result = bits.rotate_right(amount % 32);
+            // Bit 31 of the result is the last bit rotated out, also for multiples of 32
+            (result as i32, result >> 31 != 0)
+        },
+    }
+}
+
+// shift_with_carry for words of `bits` bits, with the value and the result sign-extended
+fn shift_with_carry_in(bits: u32, op: ShiftOp, value: i32, amount: u32, carry_in: bool) -> (i32, bool) {
+    if bits == 32 || amount == 0 {
+        return shift_with_carry(op, value, amount, carry_in);
+    }
+    let word = value as u32 & ((1 << bits) - 1);
+    let bit = |n: u32| n < bits && (word >> n) & 1 != 0;
+    let (result, carry_out) = match op {
+        ShiftOp::Lsl if amount <= bits => (word << amount, bit(bits - amount)),
+        ShiftOp::Lsr if amount <= bits => (word >> amount, bit(amount - 1)),
+        ShiftOp::Lsl | ShiftOp::Lsr => (0, false),
+        ShiftOp::Asr => ((value >> amount.min(31)) as u32, bit(amount.min(bits) - 1)),
+        ShiftOp::Ror => {
+            let rotated = match amount % bits {
+                0 => word,
+                by => (word >> by) | (word << (bits - by)),
+            };
+            (rotated, rotated >> (bits - 1) & 1 != 0)
+        },
+    };
+    (narrow(result as i32, bits), carry_out)
+}
+
+// The low `bits` bits of `value`, sign-extended
+// The carry out of the immediate of a logical S instruction: bit 31 of the immediate when
+// its encoding rotates the 8-bit value, and none when it does not. An assembler swaps AND
+// and BIC for an immediate only the other encodes, which then decides the rotation.
+fn immediate_carry(op: AluOp, value: i32) -> Option<bool> {
+    let value = match op {
+        AluOp::And | AluOp::Bic if encoding::rotated_immediate(value).is_err() => !value,
+        _ => value,
+    };
+    match encoding::rotated_immediate(value) {
+        Ok((rotate, _)) if rotate != 0 => Some(value < 0),
+        _ => None,
+    }
+}
+
+fn narrow(value: i32, bits: u32) -> i32 {
+    let unused = 32 - bits;
+    (value << unused) >> unused
+}
+
+// Whether the instruction calls the host through semihosting
+fn is_semihosting(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Svc { number } => semihosting::SVC.contains(&number),
+        Instruction::Bkpt { number } => number == semihosting::BKPT,
+        _ => false,
+    }
+}
+
+// Runs the service `number` of SVC at `pc`
+fn service(machine: &mut Machine, number: u32, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+    match number {
+        SVC_CREATE_TASK => {
+            let Some(task) = machine.tasks.create() else {
+                return Err(Box::new(Diagnostic::error(Code::TaskLimit, format!("Cannot create more than {} tasks", MAX_TASKS))
+                    .with_note("end a task with SVC #3 to make room for another")));
+            };
+            // The task starts at r0 with r2 in its r0, r1 as its stack pointer and a return address that ends the program
+            let block = tasks::control_block(task);
+            let (entry, stack, argument) = (machine.registers[0], machine.registers[1], machine.registers[2]);
+            for reg in 0..TCB_WORDS {
+                let value = match reg {
+                    0 => argument,
+                    STACK_POINTER => stack,
+                    LINK_REGISTER => machine.tasks.end as i32,
+                    TCB_PC => entry,
+                    TCB_CPSR => USER_MODE as i32,
+                    _ => 0,
+                };
+                machine.store(block + reg, value)?;
+            }
+            machine.registers[0] = task as i32;
+            Ok(Flow::Next)
+        },
+        SVC_YIELD | SVC_END_TASK => {
+            let running = machine.tasks.running();
+            if number == SVC_END_TASK {
+                machine.tasks.end();
+            }
+            let Some(next) = machine.tasks.switch() else {
+                return Ok(Flow::Exit);
+            };
+            if next == running {
+                return Ok(Flow::Next);
+            }
+            // A context switch ends an exclusive access
+            machine.exclusive = None;
+            if number == SVC_YIELD {
+                let block = tasks::control_block(running);
+                for reg in 0..=LINK_REGISTER {
+                    machine.store(block + reg, machine.registers[reg])?;
+                }
+                machine.store(block + TCB_PC, pc as i32 + 1)?;
+                machine.store(block + TCB_CPSR, machine.cpsr as i32)?;
+            }
+            let block = tasks::control_block(next);
+            for reg in 0..=LINK_REGISTER {
+                machine.registers[reg] = machine.load(block + reg)?;
+            }
+            // Only the flags and GE bits come back; the mode stays User
+            let mask = CPSR_FLAGS_FIELD | CPSR_STATUS_FIELD;
+            machine.cpsr = (machine.cpsr & !mask) | (machine.load(block + TCB_CPSR)? as u32 & mask);
+            // A resume point outside the program, e.g. from a block the program overwrote, stops it
+            Ok(Flow::Jump(usize::try_from(machine.load(block + TCB_PC)?).unwrap_or(usize::MAX)))
+        },
+        // A tone of r0 Hz, or the bell for 0, lasting r1 milliseconds
+        SVC_BEEP => {
+            let duration = machine.registers[1].clamp(0, MAX_BEEP_MS) as u64;
+            console.beep(machine.registers[0].max(0) as u32, Duration::from_millis(duration))?;
+            Ok(Flow::Next)
+        },
+        SVC_STRLEN..=SVC_MEMCPY if !machine.helpers => Err(Box::new(Diagnostic::error(Code::UnknownService,
+            format!("The helper SVC #{} is turned off with --no-helpers", number))
+            .with_note("this program is meant to do it with its own routine"))),
+        // The length of the string at r0, whose characters are words ending with 0
+        SVC_STRLEN => {
+            let mut length = 0;
+            while machine.load(effective_address(AddressOperand::RegisterOffset(0, length), &machine.registers, machine.memory.len())?)? != 0 {
+                length += 1;
+            }
+            machine.registers[0] = length;
+            Ok(Flow::Next)
+        },
+        // Compares the strings at r0 and r1, leaving the difference of the first characters that differ in r0
+        SVC_STRCMP => {
+            let mut index = 0;
+            machine.registers[0] = loop {
+                let a = machine.load(effective_address(AddressOperand::RegisterOffset(0, index), &machine.registers, machine.memory.len())?)?;
+                let b = machine.load(effective_address(AddressOperand::RegisterOffset(1, index), &machine.registers, machine.memory.len())?)?;
+                if a != b || a == 0 {
+                    break a.wrapping_sub(b);
+                }
+                index += 1;
+            };
+            Ok(Flow::Next)
+        },
+        // Copies r2 words from r1 to r0, first to last
+        SVC_MEMCPY => {
+            for index in 0..machine.registers[2].max(0) {
+                let value = machine.load(effective_address(AddressOperand::RegisterOffset(1, index), &machine.registers, machine.memory.len())?)?;
+                machine.store(effective_address(AddressOperand::RegisterOffset(0, index), &machine.registers, machine.memory.len())?, value)?;
+            }
+            Ok(Flow::Next)
+        },
+        // A plugin may add services with other numbers
+        _ => match plugin::call(number, machine) {
+            Some(called) => called.map(|()| Flow::Next),
+            None => Err(Box::new(Diagnostic::error(Code::UnknownService, format!("Unknown service SVC #{}", number))
+                .with_note(SERVICES_NOTE))),
+        },
+    }
+}
+
+// Sets the N and Z flags from a result
+fn set_nz(cpsr: &mut u32, result: i32) {
+    set_flag(cpsr, CPSR_N, result < 0);
+    set_flag(cpsr, CPSR_Z, result == 0);
+}
+
+fn set_flag(cpsr: &mut u32, flag: u32, set: bool) {
+    if set {
+        *cpsr |= flag;
+    } else {
+        *cpsr &= !flag;
+    }
+}
+
+// Adds `a`, `b` and the carry in as the ALU does, returning the result, the carry
+// out (unsigned overflow) and the V flag (signed overflow)
+fn add_with_carry(a: i32, b: i32, carry_in: bool) -> (i32, bool, bool) {
+    let unsigned_sum = a as u32 as u64 + b as u32 as u64 + carry_in as u64;
+    let signed_sum = a as i64 + b as i64 + carry_in as i64;
+    let result = unsigned_sum as u32 as i32;
+    (result, result as u32 as u64 != unsigned_sum, result as i64 != signed_sum)
+}
+
+// add_with_carry for words of `bits` bits, with the operands and the result sign-extended
+fn add_with_carry_in(bits: u32, a: i32, b: i32, carry_in: bool) -> (i32, bool, bool) {
+    if bits == 32 {
+        return add_with_carry(a, b, carry_in);
+    }
+    let mask = (1u64 << bits) - 1;
+    let unsigned_sum = (a as u32 as u64 & mask) + (b as u32 as u64 & mask) + carry_in as u64;
+    let signed_sum = narrow(a, bits) as i64 + narrow(b, bits) as i64 + carry_in as i64;
+    let result = narrow(unsigned_sum as i32, bits);
+    (result, unsigned_sum > mask, result as i64 != signed_sum)
+}
+
+// Sets N, Z, C and V after an addition or comparison
+fn set_nzcv(cpsr: &mut u32, (result, carry, overflow): (i32, bool, bool)) {
+    set_nz(cpsr, result);
+    set_flag(cpsr, CPSR_C, carry);
+    set_flag(cpsr, CPSR_V, overflow);
+}
+
+// Runs a load or store, which goes through Machine::load and store to reach the map's
+// devices, giving whether the instruction was one
+fn transfer(machine: &mut Machine, instruction: &Instruction) -> Result<bool, Box<Diagnostic>> {
+    let memory_words = machine.memory.len();
+    match *instruction {
+        Instruction::Ldr { rd, address } => {
+            let address = effective_address(address, &machine.registers, memory_words)?;
+            machine.registers[rd] = machine.load(address)?;
+        },
+        Instruction::Str { rs, address } => {
+            let address = effective_address(address, &machine.registers, memory_words)?;
+            machine.store(address, machine.registers[rs])?;
+        },
+        Instruction::Ldrex { rd, rn } => {
+            let address = effective_address(AddressOperand::RegisterOffset(rn, 0), &machine.registers, memory_words)?;
+            machine.registers[rd] = machine.load(address)?;
+            machine.exclusive = Some((address, machine.accesses.writes(address)));
+        },
+        Instruction::Strex { rd, rs, rn } => {
+            let address = effective_address(AddressOperand::RegisterOffset(rn, 0), &machine.registers, memory_words)?;
+            // Any store to the address, from any core, since the LDREX makes this one fail
+            let reserved = machine.exclusive.take() == Some((address, machine.accesses.writes(address)));
+            if reserved {
+                machine.store(address, machine.registers[rs])?;
+            }
+            machine.registers[rd] = !reserved as i32;
+        },
+        Instruction::Vldr { sd, address } => {
+            let address = effective_address(address, &machine.registers, memory_words)?;
+            machine.fp_registers[sd] = f32::from_bits(machine.load(address)? as u32);
+        },
+        Instruction::Vstr { sd, address } => {
+            let address = effective_address(address, &machine.registers, memory_words)?;
+            machine.store(address, machine.fp_registers[sd].to_bits() as i32)?;
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Executes a decoded instruction, returning where execution continues.
+fn execute(machine: &mut Machine, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+    if is_semihosting(instruction) {
+        return semihosting::call(machine, console);
+    }
+    if let Instruction::Svc { number } = *instruction {
+        return service(machine, number, pc, console);
+    }
+    if let Instruction::Plugin { index, ref operands } = *instruction {
+        plugin::execute(index, operands, machine)?;
+        return Ok(Flow::Next);
+    }
+    if transfer(machine, instruction)? {
+        return Ok(Flow::Next);
+    }
+    let Machine { registers, fp_registers, cpsr, exclusive, trap_overflow, word_bits, formats, .. } = machine;
+
+    match *instruction {
+        Instruction::Exit => return Ok(Flow::Exit),
+        // Stopping in the debugger is a context change, which ends an exclusive access
+        Instruction::Bkpt { .. } => {
+            *exclusive = None;
+            return Ok(Flow::Breakpoint);
+        },
+        Instruction::Svc { .. } | Instruction::Plugin { .. } => unreachable!("services and plugin instructions are run above"),
+        Instruction::Ldr { .. } | Instruction::Str { .. } | Instruction::Ldrex { .. } | Instruction::Strex { .. } | Instruction::Vldr { .. } | Instruction::Vstr { .. } =>
+            unreachable!("loads and stores are run above"),
+        Instruction::Clrex => *exclusive = None,
+        Instruction::Adr { rd, address } => registers[rd] = address,
+        Instruction::Barrier { .. } => {},
+        Instruction::Mov { rd, src } => {
+            registers[rd] = src.value(registers);
+        },
+        Instruction::Alu { op, set_flags, rd, rn, op2 } => {
+            let op1_val = registers[rn];
+            let op2_val = op2.value(registers);
+            let carry_in = *cpsr & CPSR_C != 0;
+            // Results wrap around; the S forms report signed overflow in V instead
+            let (result, overflowed) = match op {
+                AluOp::Add | AluOp::Sub | AluOp::Adc | AluOp::Sbc => {
+                    // Subtraction adds the inverted operand: a - b = a + !b + 1.
+                    // Carry means no borrow, so SBC computes a - b - !C = a + !b + C.
+                    let (op2_val, carry_in) = match op {
+                        AluOp::Add => (op2_val, false),
+                        AluOp::Sub => (!op2_val, true),
+                        AluOp::Adc => (op2_val, carry_in),
+                        _ => (!op2_val, carry_in),
+                    };
+                    let (result, carry_out, overflow) = add_with_carry_in(*word_bits, op1_val, op2_val, carry_in);
+                    if set_flags {
+                        set_flag(cpsr, CPSR_C, carry_out);
+                        set_flag(cpsr, CPSR_V, overflow);
+                    }
+                    (result, overflow)
+                },
+                AluOp::Mul => {
+                    let product = op1_val as i64 * op2_val as i64;
+                    let result = narrow(product as i32, *word_bits);
+                    (result, result as i64 != product)
+                },
+                AluOp::And => (op1_val & op2_val, false),
+                AluOp::Orr => (op1_val | op2_val, false),
+                AluOp::Bic => (op1_val & !op2_val, false),
+                AluOp::Eor => (op1_val ^ op2_val, false),
+            };
+            // An immediate may have bits above the word size
+            let result = narrow(result, *word_bits);
+            if overflowed && *trap_overflow && !set_flags {
+                let mnemonic = format!("{:?}", op).to_uppercase();
+                return Err(Box::new(Diagnostic::error(Code::ArithmeticOverflow,
+                    format!("Signed overflow in {}: the result of {} and {} does not fit in {} bits", mnemonic, op1_val, op2_val, word_bits))
+                    .with_note(format!("without --trap-overflow the result wraps around to {}", result))));
+            }
+            registers[rd] = result;
+            if set_flags {
+                set_nz(cpsr, result);
+                if let (AluOp::And | AluOp::Orr | AluOp::Bic | AluOp::Eor, Operand::Immediate(value)) = (op, op2)
+                    && let Some(carry) = immediate_carry(op, value) {
+                    set_flag(cpsr, CPSR_C, carry);
+                }
+            }
+        },
+        Instruction::Cmp { rn, op2 } => {
+            set_nzcv(cpsr, add_with_carry_in(*word_bits, registers[rn], !op2.value(registers), true));
+        },
+        Instruction::Cmn { rn, op2 } => {
+            set_nzcv(cpsr, add_with_carry_in(*word_bits, registers[rn], op2.value(registers), false));
+        },
+        Instruction::Branch { cond, target } => {
+            if cond.holds(*cpsr) {
+                return Ok(Flow::Jump(target));
+            }
+        },
+        Instruction::Bl { target } => {
+            registers[LINK_REGISTER] = pc as i32 + 1;
+            return Ok(Flow::Jump(target));
+        },
+        Instruction::Bx { rm } => {
+            return Ok(Flow::Jump(registers[rm] as u32 as usize));
+        },
+        Instruction::Shift { op, set_flags, rd, rm, amount } => {
+            let amount = match amount {
+                Operand::Immediate(val) => val as u32,
+                // A shift by register uses the bottom byte of the register
+                Operand::Register(rs) => registers[rs] as u32 & 0xFF,
+            };
+            let (result, carry_out) = shift_with_carry_in(*word_bits, op, registers[rm], amount, *cpsr & CPSR_C != 0);
+            registers[rd] = result;
+            if set_flags {
+                set_nz(cpsr, result);
+                set_flag(cpsr, CPSR_C, carry_out);
+            }
+        },
+        Instruction::Rrx { rd, rm } => {
+            // The carry flag is rotated in at the top
+            let carry_in = if *cpsr & CPSR_C != 0 { 1 << (*word_bits - 1) } else { 0 };
+            let word = registers[rm] as u32 & (u32::MAX >> (32 - *word_bits));
+            registers[rd] = ((word >> 1) | carry_in) as i32;
+        },
+        Instruction::Vfp { op, sd, sn, sm } => {
+            let (a, b) = (fp_registers[sn], fp_registers[sm]);
+            fp_registers[sd] = match op {
+                VfpOp::Add => a + b,
+                VfpOp::Sub => a - b,
+                VfpOp::Mul => a * b,
+                VfpOp::Div => a / b,
+            };
+        },
+        Instruction::Vmov { sd, src } => {
+            fp_registers[sd] = match src {
+                FpSource::Immediate(bits) => f32::from_bits(bits),
+                FpSource::Register(sm) => fp_registers[sm],
+                FpSource::Core(rn) => f32::from_bits(registers[rn] as u32),
+            };
+        },
+        Instruction::VmovToCore { rd, sn } => {
+            registers[rd] = fp_registers[sn].to_bits() as i32;
+        },
+        Instruction::Vcvt { to_int, sd, sm } => {
+            fp_registers[sd] = if to_int {
+                // Rounds toward zero, saturating out-of-range values and turning NaN into 0
+                f32::from_bits(fp_registers[sm] as i32 as u32)
+            } else {
+                fp_registers[sm].to_bits() as i32 as f32
+            };
+        },
+        Instruction::MultiplyAccumulate { subtract, set_flags, rd, rn, rm, ra } => {
+            // Like MUL, the result keeps the low bits of a word, so the overflow check uses the exact value
+            let product = registers[rn] as i64 * registers[rm] as i64;
+            let exact = if subtract { registers[ra] as i64 - product } else { registers[ra] as i64 + product };
+            let result = narrow(exact as i32, *word_bits);
+            if exact != result as i64 && *trap_overflow && !set_flags {
+                let (mnemonic, sign) = if subtract { ("MLS", '-') } else { ("MLA", '+') };
+                return Err(Box::new(Diagnostic::error(Code::ArithmeticOverflow,
+                    format!("Signed overflow in {}: {} {} {} * {} does not fit in {} bits", mnemonic, registers[ra], sign, registers[rn], registers[rm], word_bits))
+                    .with_note(format!("without --trap-overflow the result wraps around to {}", result))));
+            }
+            registers[rd] = result;
+            if set_flags {
+                set_nz(cpsr, result);
+            }
+        },
+        Instruction::HalfwordMultiply { rd, rn, rm, rn_top, rm_top, ra } => {
+            let half = |value: i32, top: bool| if top { value >> 16 } else { value as i16 as i32 };
+            // The product of two halfwords always fits in 32 bits
+            let product = half(registers[rn], rn_top) * half(registers[rm], rm_top);
+            registers[rd] = match ra {
+                Some(ra) => {
+                    // Overflow of the accumulation sets the sticky Q flag
+                    let (sum, overflow) = product.overflowing_add(registers[ra]);
+                    if overflow {
+                        *cpsr |= CPSR_Q;
+                    }
+                    sum
+                },
+                None => product,
+            };
+        },
+        Instruction::Mrs { rd } => {
+            registers[rd] = *cpsr as i32;
+        },
+        Instruction::Msr { mask, src } => {
+            *cpsr = (*cpsr & !mask) | (src.value(registers) as u32 & mask);
+        },
+        Instruction::Parallel { op, lane_bits, rd, rn, rm } => {
+            let (result, flags) = parallel(op, lane_bits, registers[rn], registers[rm]);
+            registers[rd] = result;
+            if let Some(flags) = flags {
+                *cpsr = (*cpsr & !CPSR_STATUS_FIELD) | (flags << CPSR_GE_SHIFT);
+            }
+        },
+        Instruction::Sel { rd, rn, rm } => {
+            let mut result = 0u32;
+            for byte in 0..4 {
+                let source = if *cpsr & (1 << (CPSR_GE_SHIFT + byte)) != 0 { registers[rn] } else { registers[rm] };
+                result |= source as u32 & (0xFF << (byte * 8));
+            }
+            registers[rd] = result as i32;
+        },
+        Instruction::Print { reg, ref name, fraction_bits: None } => {
+            console.print(format_args!("{} = {}", name, formats.format(ARM_REGISTERS.names[reg], registers[reg] as i64, ARM_REGISTERS.bits)))?;
+        },
+        Instruction::Print { reg, ref name, fraction_bits: Some(bits) } => {
+            let value = registers[reg] as f64 / (1u64 << bits) as f64;
+            console.print(format_args!("{} = {} (q{})", name, value, bits))?;
+        },
+        Instruction::PrintFloat { reg, ref name } => {
+            console.print(format_args!("{} = {:?}", name, fp_registers[reg]))?;
+        },
+    }
+    Ok(Flow::Next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::PAGE_WORDS;
+
+    /// Runs a script and returns the registers, flags and memory it left behind
+    fn run_test_script(script: &str) -> FinalState {
+        run_test_sources(&[("<input>", script)])
+    }
+
+    /// Links several named scripts into one program, runs it and returns its final state
+    fn run_test_sources(sources: &[(&str, &str)]) -> FinalState {
+        FinalState::of(&Arm, &run_test_sources_with(sources, &RunOptions::default()))
+    }
+
+    /// Links several named scripts, runs them with `options` and returns the final machine state.
+    /// Panics with the rendered diagnostics if the program fails.
+    fn run_test_sources_with(sources: &[(&str, &str)], options: &RunOptions) -> Machine {
+        match try_run_test_sources(sources, options) {
+            Ok(machine) => machine,
+            Err(errors) => {
+                let rendered: Vec<String> = errors.iter().map(Diagnostic::to_human).collect();
+                panic!("{}", rendered.join("\n"));
             },
+        }
+    }
+
+    /// Links several named scripts and runs them with `options`
+    fn try_run_test_sources(sources: &[(&str, &str)], options: &RunOptions) -> Result<Machine, Vec<Diagnostic>> {
+        let sources: Vec<(String, String)> = sources.iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect();
+        run_sources(&Arm, &sources, options, &mut Console::new(&mut io::empty(), &mut io::sink()))
+    }
+
+    /// Runs a script that is expected to fail and returns its errors
+    fn run_failing_script(script: &str) -> Vec<Diagnostic> {
+        match try_run_test_sources(&[("main.s", script)], &RunOptions::default()) {
+            Ok(_) => panic!("expected the script to fail"),
+            Err(errors) => errors,
+        }
+    }
+
+    /// The N, Z, C and V flags of a final state
+    fn nzcv(state: &FinalState) -> [bool; 4] {
+        ["N", "Z", "C", "V"].map(|flag| state.flag(flag).unwrap())
+    }
+
+    /// Runs `r3 = r1 <op> r2` and returns the final state
+    fn run_alu(op: &str, r1: i32, r2: i32) -> FinalState {
+        run_test_script(&format!("MOV r1, #{}\nMOV r2, #{}\n{} r3, r1, r2\nEXIT", r1, r2, op))
+    }
+
+    #[test]
+    fn test_add_instruction() {
+        let state = run_alu("ADD", 5, 10);
+        assert_eq!(state.register("r3"), Some(15));
+        // The sources are unchanged and plain ADD leaves the flags alone
+        assert_eq!((state.register("r1"), state.register("r2")), (Some(5), Some(10)));
+        assert_eq!(nzcv(&state), [false; 4]);
+    }
+
+    #[test]
+    fn test_sub_instruction() {
+        let state = run_alu("SUB", 20, 7);
+        assert_eq!(state.register("r3"), Some(13));
+        assert_eq!(run_alu("SUB", 7, 20).register("r3"), Some(-13));
+        assert_eq!(nzcv(&state), [false; 4]);
+    }
+
+    #[test]
+    fn test_adc_instruction() {
+        // C starts clear, so nothing is added; without the S the flags are left alone
+        let state = run_alu("ADC", 5, 10);
+        assert_eq!(state.register("r3"), Some(15));
+        assert_eq!(nzcv(&state), [false; 4]);
+
+        // ADDS carries out of -1 + 1, and ADC adds that carry without setting any flag itself
+        let state = run_test_script("MOV r1, #-1\nADDS r0, r1, #1\nADC r3, r1, #5\nEXIT");
+        assert_eq!(state.register("r3"), Some(5));
+        assert_eq!(state.flag("C"), Some(true));
+        let state = run_test_script("MOV r1, #-1\nADDS r0, r1, #1\nADC r3, r0, #5\nEXIT");
+        assert_eq!(state.register("r3"), Some(6));
+        // 0 + 5 + 1 carries nothing out, but only ADCS would clear C
+        assert_eq!(state.flag("C"), Some(true));
+
+        // ADCS sets all four: 0x7FFFFFFF + 0 + 1 overflows into the sign bit without a carry out
+        let state = run_test_script("MOV r1, #-1\nADDS r0, r1, #1\nMOV r2, #0x7FFFFFFF\nADCS r3, r2, #0\nEXIT");
+        assert_eq!(state.register("r3"), Some(i32::MIN as i64));
+        assert_eq!(nzcv(&state), [true, false, false, true]);
+        // -1 + 0 + 1 is 0 with a carry out and no overflow
+        let state = run_test_script("MOV r1, #-1\nADDS r0, r1, #1\nADCS r3, r1, #0\nEXIT");
+        assert_eq!(state.register("r3"), Some(0));
+        assert_eq!(nzcv(&state), [false, true, true, false]);
+    }
+
+    #[test]
+    fn test_sbc_instruction() {
+        // With C clear SBC subtracts one more (the borrow); only SBCS sets C when nothing is borrowed
+        let state = run_alu("SBC", 20, 7);
+        assert_eq!(state.register("r3"), Some(12));
+        assert_eq!(state.flag("C"), Some(false));
+        assert_eq!(run_alu("SBCS", 20, 7).flag("C"), Some(true));
+        assert_eq!(run_alu("SBCS", 7, 20).flag("C"), Some(false));
+    }
+
+    #[test]
+    fn test_mul_instruction() {
+        assert_eq!(run_alu("MUL", 3, 4).register("r3"), Some(12));
+        assert_eq!(run_alu("MUL", -3, 4).register("r3"), Some(-12));
+        let state = run_alu("MULS", 0, 4);
+        assert_eq!(state.register("r3"), Some(0));
+        assert_eq!(nzcv(&state), [false, true, false, false]);
+    }
+
+    #[test]
+    fn test_multiply_accumulate() {
+        let state = run_test_script("MOV r1, #3\nMOV r2, #4\nMOV r3, #100\nMLA r4, r1, r2, r3\nMLS r5, r1, r2, r3\nMLAS r6, r1, r2, r3\nEXIT");
+        assert_eq!(["r4", "r5", "r6"].map(|reg| state.register(reg).unwrap()), [112, 88, 112]);
+        let errors = run_failing_script("MLA r0, r1, r2\nMLS r0, r1, r2, #3");
+        assert_eq!(errors[0].notes, ["usage: MLA <dest_register>, <register>, <register>, <accumulate_register>", "for example: MLA r0, r1, r2, r0"]);
+        assert_eq!(errors[1].code, Code::InvalidRegister);
+    }
+
+    #[test]
+    fn test_and_instruction() {
+        assert_eq!(run_alu("AND", 12, 10).register("r3"), Some(8));
+        assert_eq!(nzcv(&run_alu("ANDS", 12, 3)), [false, true, false, false]);
+    }
+
+    #[test]
+    fn test_logical_immediates_set_carry_from_the_rotation() {
+        // 0x80000000 is 2 rotated right by 2, so C is its bit 31
+        let state = run_test_script("MOV r1, #-1\nANDS r0, r1, #0x80000000\nEXIT");
+        assert_eq!(state.register("r0"), Some(i32::MIN as i64));
+        assert_eq!(nzcv(&state), [true, false, true, false]);
+        let state = run_test_script("MOV r1, #-1\nCMP r1, #0\nORRS r0, r1, #0x3F0\nEXIT");
+        assert!(!nzcv(&state)[2]);
+        // An immediate that is not rotated leaves C as it was
+        let state = run_test_script("MOV r1, #0\nCMP r1, #0\nEORS r0, r1, #0xFF\nEXIT");
+        assert!(nzcv(&state)[2]);
+    }
+
+    #[test]
+    fn test_orr_instruction() {
+        assert_eq!(run_alu("ORR", 4, 2).register("r3"), Some(6));
+        assert_eq!(nzcv(&run_alu("ORRS", -4, 2)), [true, false, false, false]);
+    }
+
+    #[test]
+    fn test_bic_instruction() {
+        assert_eq!(run_alu("BIC", 7, 6).register("r3"), Some(1));
+        assert_eq!(run_alu("BIC", -1, 1).register("r3"), Some(-2));
+    }
+
+    #[test]
+    fn test_eor_instruction() {
+        assert_eq!(run_alu("EOR", 5, 3).register("r3"), Some(6));
+        assert_eq!(nzcv(&run_alu("EORS", 5, 5)), [false, true, false, false]);
+    }
+
+    #[test]
+    fn test_lsl_instruction() {
+        let state = run_test_script("MOV r1, #1\nLSL r2, r1, #3\nMOV r3, #4\nLSL r4, r1, r3\nEXIT");
+        assert_eq!(state.register("r2"), Some(8));
+        assert_eq!(state.register("r4"), Some(16));
+        assert_eq!(nzcv(&state), [false; 4]);
+    }
+
+    #[test]
+    fn test_lsr_instruction() {
+        let state = run_test_script("MOV r1, #16\nLSR r2, r1, #2\nMOV r3, #-16\nLSR r4, r3, #28\nEXIT");
+        assert_eq!(state.register("r2"), Some(4));
+        // Zeros are shifted in, even for negative values
+        assert_eq!(state.register("r4"), Some(15));
+    }
+
+    #[test]
+    fn test_asr_instruction() {
+        let state = run_test_script("MOV r1, #-32\nASR r2, r1, #2\nMOV r3, #32\nASR r4, r3, #2\nEXIT");
+        assert_eq!(state.register("r2"), Some(-8));
+        assert_eq!(state.register("r4"), Some(8));
+    }
+
+    #[test]
+    fn test_ror_instruction() {
+        let state = run_test_script("MOV r1, #4\nROR r2, r1, #1\nMOV r3, #1\nROR r4, r3, #1\nEXIT");
+        assert_eq!(state.register("r2"), Some(2));
+        assert_eq!(state.register("r4"), Some(i32::MIN as i64));
+    }
+
+    #[test]
+    fn test_rrx_instruction() {
+        // C is rotated in at the top, and RRX leaves it unchanged
+        let state = run_test_script("MOV r0, #0\nCMP r0, #0\nMOV r1, #9\nRRX r2, r1\nEXIT");
+        assert_eq!(state.register("r2"), Some(i32::MIN as i64 + 4));
+        assert_eq!(state.flag("C"), Some(true));
+        assert_eq!(run_test_script("MOV r1, #9\nRRX r2, r1\nEXIT").register("r2"), Some(4));
+    }
+
+    #[test]
+    fn test_ldr_str_label() {
+        let script = "\
+            data_val:\n\
+            MOV r0, #123\n\
+            STR r0, data_val\n\
+            MOV r1, #0\n\
+            LDR r1, data_val\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r1"), Some(123));
+        assert_eq!(state.word("data_val"), Some(123));
+    }
+
+    #[test]
+    fn test_label_arithmetic() {
+        let script = "\
+            array: #10\n\
+            second: #20\n\
+            third: #30\n\
+            end: #0\n\
+            LDR r0, array+2\n\
+            MOV r1, #(end-array)\n\
+            LDR r2, [r1, #array-2]\n\
+            MOV r3, #(end - array) * 2\n\
+            LDR r4, #(array+1)\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(["r0", "r1", "r2", "r3", "r4"].map(|reg| state.register(reg).unwrap()), [30, 3, 20, 6, 20]);
+
+        let errors = run_failing_script("MOV r0, #(nope+1)\nLDR r0, 2000+1\nADD r0 r1, #1");
+        assert_eq!(errors[0].message, "Cannot work out (nope+1): unknown name 'nope'");
+        assert_eq!(errors[1].code, Code::MemoryOutOfBounds);
+        assert_eq!(errors[2].code, Code::MissingComma);
+    }
+
+    #[test]
+    fn test_immediate_notations() {
+        let script = "\
+            letter: #'A'\n\
+            MOV r0, #'z'\n\
+            MOV r1, #0b1010\n\
+            ADD r2, r1, #-1\n\
+            ADD r6, r0, #','\n\
+            MOV r3, #'\\n'\n\
+            LDR r4, letter\n\
+            STR r1, [r0, #-0b10]\n\
+            LDR r5, =-0x10\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(["r0", "r1", "r2", "r3", "r4", "r5", "r6"].map(|reg| state.register(reg).unwrap()), [122, 10, 9, 10, 65, -16, 166]);
+        assert_eq!(state.memory[120], 10);
+        assert_eq!(run_failing_script("MOV r0, #'ab'")[0].code, Code::InvalidOperand);
+
+        let state = run_test_script("mask: #0o17\nMOV r0, #0b1010_1010\nLDR r1, mask\nADD r2, r0, #1_000\nEXIT\n");
+        assert_eq!(["r0", "r1", "r2"].map(|reg| state.register(reg).unwrap()), [0xAA, 15, 1170]);
+        assert_eq!(run_failing_script("MOV r0, #0b1010_")[0].code, Code::InvalidOperand);
+
+        // MOV r0, #-1 is MVN r0, #0 and SUB r1, r1, #256 has an encoding, unlike #257 and LSL #40
+        let options = RunOptions { strict_imm: true, ..RunOptions::default() };
+        let script = "MOV r0, #-1\nADD r1, r1, #-256\nMOV r2, #257\nLSL r3, r3, #40\nMOV r4, #0xFF000000";
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors.iter().map(|error| error.span.as_ref().unwrap().line).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(errors[0].code, Code::NoEncoding);
+        assert!(try_run_test_sources(&[("main.s", script)], &RunOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_truncation_warnings() {
+        let script = "MOV r0, #1\nLSR r1, r0, #32\nLSL r2, r0, #40\nROR r3, r0, #33\nEXIT";
+        let machine = try_run_test_sources(&[("main.s", script)], &RunOptions::default()).unwrap();
+        assert_eq!(machine.registers[3], i32::MIN);
+        assert_eq!(machine.warnings.iter().map(|warning| warning.span.as_ref().unwrap().line).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(machine.warnings[1].notes[0], "a shift amount is 0 to 31 (LSR and ASR also take 32), and #33 rotates by 1");
+
+        let options = RunOptions { strict: true, ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].to_human().starts_with("error[W0002]: Immediate out of range: LSL r2, r0, #40"));
+    }
+
+    #[test]
+    fn test_unset_flags_warnings() {
+        let script = "MOV r0, #1\nBEQ end\nADC r1, r0, r0\nCMP r0, #1\nBEQ end\nend: EXIT";
+        let machine = try_run_test_sources(&[("main.s", script)], &RunOptions::default()).unwrap();
+        assert_eq!(machine.warnings.iter().map(|warning| warning.span.as_ref().unwrap().line).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(machine.warnings[0].code, Code::UnsetFlags);
+        assert_eq!(machine.warnings[1].message, "ADC r1, r0, r0 reads the flags before any instruction has set them");
+        assert!(machine.unset_flags.is_none());
+    }
+
+    #[test]
+    fn test_strict_profile() {
+        let script = "MOV r1, #3\nMUL r0, r1, #5\nMUL r1, r1, r0\nMUL r2, r1, r0\nLSL r3, r0, #40\nMOV r4, #257\nEXIT";
+        assert!(try_run_test_sources(&[("main.s", script)], &RunOptions::default()).is_ok());
+        let options = RunOptions { profile: Profile::Strict, ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors.iter().map(|error| error.span.as_ref().unwrap().line).collect::<Vec<_>>(), [2, 3, 5, 6]);
+        assert_eq!(errors[0].notes[0], "MUL has no immediate form; put the constant in a spare register first: MOV r12, #5 then MUL r0, r1, r12");
+        assert_eq!(errors[1].notes[0], "MUL cannot write the register it multiplies before ARMv6; swap the operands: MUL r1, r0, r1");
+        assert!(errors[3].notes[1].ends_with("leave out --profile strict to accept any 32-bit immediate"));
+    }
+
+    #[test]
+    fn test_ldr_str_immediate_address() {
+        let script = "\
+            MOV r0, #456\n\
+            STR r0, #10\n\
+            MOV r1, #0\n\
+            LDR r1, #10\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r1"), Some(456));
+        assert_eq!(state.memory[10], 456);
+    }
+
+    #[test]
+    fn test_ldr_str_register_indirect() {
+        let script = "\
+            MOV r0, #20\n\
+            MOV r1, #789\n\
+            STR r1, [r0]\n\
+            MOV r2, #0\n\
+            LDR r2, [r0]\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r2"), Some(789));
+        assert_eq!(state.memory[20], 789);
+    }
+
+    #[test]
+    fn test_ldr_str_register_indirect_offset() {
+        let script = "\
+            MOV r0, #30\n\
+            MOV r1, #101\n\
+            STR r1, [r0,#4]\n\
+            MOV r2, #0\n\
+            LDR r2, [r0,#4]\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r2"), Some(101));
+        // Offsets are in words
+        assert_eq!(state.memory[34], 101);
+        assert_eq!(state.register("r0"), Some(30));
+    }
+
+    #[test]
+    fn test_ldr_str_register_indirect_negative_offset() {
+        let script = "\
+            MOV r0, #40\n\
+            MOV r1, #202\n\
+            STR r1, [r0,#-2]\n\
+            MOV r2, #0\n\
+            LDR r2, [r0,#-2]\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r2"), Some(202));
+        assert_eq!(state.memory[38], 202);
+    }
+
+    #[test]
+    fn test_comments_and_labels() {
+        let script = "\
+            // This is a full line comment\n\
+            data_start: // This is a label\n\
+            MOV r0, #10 // Move 10 to r0\n\
+            // Another comment\n\
+            STR r0, data_start // Store r0 to data_start\n\
+            LDR r1, data_start // Load from data_start to r1\n\
+            EXIT\n";
+        let state = run_test_script(script);
+        assert_eq!(state.register("r1"), Some(10));
+        assert_eq!(state.word("data_start"), Some(10));
+    }
+
+    #[test]
+    fn test_empty_lines_and_whitespace_with_comments() {
+        let script = "\
+            \n\
+            // Comment after empty line\n\
+            MOV r5, #55\n\
+            \n\
+            // Comment at end of file\n\
+            EXIT\n";
+        assert_eq!(run_test_script(script).register("r5"), Some(55));
+    }
+
+    #[test]
+    fn test_exit_stops_the_program() {
+        let state = run_test_script("MOV r0, #1\nEXIT\nMOV r0, #2");
+        assert_eq!(state.register("r0"), Some(1));
+    }
+
+    #[test]
+    fn test_operand_errors_of_every_instruction() {
+        let cases = [
+            ("MOV r0, r1, r2", Code::WrongOperandCount),
+            ("MOV r16, #1", Code::InvalidRegister),
+            ("MOV r0, 5", Code::InvalidOperand),
+            ("ADD r0, r1", Code::WrongOperandCount),
+            ("SUB r0, #1, r1", Code::RegisterExpected),
+            ("ADC r0, r1, q2", Code::InvalidOperand),
+            ("SBC q0, r1, r2", Code::InvalidRegister),
+            ("MUL r0, r1", Code::WrongOperandCount),
+            ("AND r0 r1, r2", Code::MissingComma),
+            ("ORR r0, r1, #x", Code::InvalidOperand),
+            ("BIC r0, r1", Code::WrongOperandCount),
+            ("EOR r0, r1, r2, r3", Code::WrongOperandCount),
+            ("CMP r0", Code::WrongOperandCount),
+            ("CMN #1, r0", Code::RegisterExpected),
+            ("B nowhere", Code::UndefinedLabel),
+            ("BL nowhere", Code::UndefinedLabel),
+            ("BX #4", Code::InvalidRegister),
+            ("LSL r0, r1", Code::WrongOperandCount),
+            ("LSR r0, r1, #x", Code::InvalidOperand),
+            ("ASR r0, #1, #1", Code::InvalidRegister),
+            ("ROR r0, r1, q1", Code::InvalidOperand),
+            ("RRX r0", Code::WrongOperandCount),
+            ("LDR r0, nowhere", Code::UndefinedLabel),
+            ("STR r0, #-1", Code::NegativeAddress),
+            ("LDR r0, [r1, 2]", Code::InvalidAddressOperand),
+            ("VADD.F32 s0, s1", Code::WrongOperandCount),
+            ("VMOV s32, #1.0", Code::InvalidRegister),
+            ("VLDR s0, [q1]", Code::InvalidRegister),
+            ("VCVT.S32.F32 r0, s1", Code::InvalidRegister),
+            ("SMULBB r0, r1", Code::WrongOperandCount),
+            ("SADD8 r0, r1, #1", Code::InvalidRegister),
+            ("SEL r0, r1", Code::WrongOperandCount),
+            ("MRS r0, SPSR", Code::InvalidOperand),
+            ("MSR SPSR_f, r0", Code::InvalidOperand),
+            ("PRINT r0, q40", Code::InvalidOperand),
+            ("EXIT r0", Code::WrongOperandCount),
+            ("NOP", Code::UnknownInstruction),
+        ];
+        for (script, code) in cases {
+            let errors = run_failing_script(script);
+            assert_eq!(errors.iter().map(|error| error.code).collect::<Vec<_>>(), vec![code], "{}", script);
+        }
+    }
+
+    #[test]
+    fn test_labels_shared_across_files() {
+        let lib = "\
+            value: #42\n";
+        let main = "\
+            LDR r0, value\n\
+            EXIT\n";
+        let state = run_test_sources(&[("lib.s", lib), ("main.s", main)]);
+        assert_eq!(state.register("r0"), Some(42));
+        assert_eq!(state.word("value"), Some(42));
+    }
+
+    #[test]
+    fn test_duplicate_label_across_files() {
+        let lib = "\
+            value: #42\n";
+        let main = "\
+            MOV r0, #1\n\
+            value: #7\n\
+            EXIT\n";
+        let errors = try_run_test_sources(&[("lib.s", lib), ("main.s", main)], &RunOptions::default()).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::DuplicateLabel);
+        assert_eq!(errors[0].notes, vec!["first defined at lib.s:1"]);
+        let span = errors[0].span.as_ref().unwrap();
+        assert_eq!((span.file.as_str(), span.line), ("main.s", 2));
+    }
+
+    #[test]
+    fn test_execution_starts_at_main() {
+        let lib = "\
+            helper:\n\
+            MOV r1, #99\n"; // Skipped
+        let main = "\
+            main:\n\
+            MOV r0, #1\n\
+            PRINT r0\n\
+            EXIT\n";
+        let machine = run_test_sources_with(&[("lib.s", lib), ("main.s", main)], &RunOptions::default());
+        assert_eq!(machine.registers[0], 1);
+        assert_eq!(machine.registers[1], 0);
+    }
+
+    #[test]
+    fn test_execution_prefers_start_over_main() {
+        let script = "\
+            main:\n\
+            MOV r1, #99\n\
+            _start:\n\
+            MOV r0, #1\n\
+            EXIT\n";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[1], 0);
+    }
+
+    #[test]
+    fn test_execution_without_entry_label_starts_at_first_line() {
+        let script = "\
+            MOV r1, #99\n\
+            helper:\n\
+            EXIT\n";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[1], 99);
+    }
+
+    #[test]
+    fn test_entry_label_overrides_default() {
+        let script = "\
+            main:\n\
+            MOV r1, #99\n\
+            routine:\n\
+            MOV r0, #1\n\
+            EXIT\n";
+        let options = RunOptions { entry: Some("routine".to_string()), ..RunOptions::default() };
+        let machine = run_test_sources_with(&[("main.s", script)], &options);
+        assert_eq!(machine.registers[1], 0);
+    }
+
+    #[test]
+    fn test_entry_label_must_exist() {
+        let script = "\
+            main:\n\
+            EXIT\n";
+        let options = RunOptions { entry: Some("missing".to_string()), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors[0].code, Code::UndefinedEntryPoint);
+    }
+
+    #[test]
+    fn test_entry_label_must_be_code() {
+        let script = "\
+            value: #5\n\
+            EXIT\n";
+        let options = RunOptions { entry: Some("value".to_string()), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors[0].code, Code::EntryPointIsData);
+    }
+
+    #[test]
+    fn test_seeded_registers_and_memory() {
+        let script = "\
+            LDR r2, #0x20\n\
+            ADD r3, r0, r1\n\
+            EXIT\n";
+        let options = RunOptions {
+            registers: vec![(0, 42), (1, 0x100)],
+            memory: vec![(0x20, 7)],
+            ..RunOptions::default()
+        };
+        let machine = run_test_sources_with(&[("main.s", script)], &options);
+        assert_eq!(machine.registers[2], 7);
+        assert_eq!(machine.registers[3], 42 + 0x100);
+    }
+
+    #[test]
+    fn test_seeded_memory_overrides_data_label() {
+        let script = "\
+            value: #5\n\
+            LDR r0, value\n\
+            EXIT\n";
+        let options = RunOptions { memory: vec![(0, 9)], ..RunOptions::default() };
+        let machine = run_test_sources_with(&[("main.s", script)], &options);
+        assert_eq!(machine.registers[0], 9);
+    }
+
+    #[test]
+    fn test_memory_words() {
+        let script = "\
+            MOV r1, #0x20000000\n\
+            STR r1, [r1, #4]\n\
+            LDR r0, #0x20000004\n\
+            EXIT\n";
+        let options = RunOptions { memory_words: Some(1 << 30), memory: vec![(0x3000_0000, 9)], ..RunOptions::default() };
+        let machine = run_test_sources_with(&[("main.s", script)], &options);
+        assert_eq!((machine.registers[0], machine.memory[0x3000_0000], machine.memory.len()), (0x2000_0000, 9, 1 << 30));
+        assert_eq!(machine.memory.words().count(), 2 * PAGE_WORDS);
+
+        let errors = run_failing_script("LDR r0, #0x20000004\n");
+        assert_eq!(errors[0].message, "Memory access out of bounds: address 536870916 >= MEMORY_SIZE 1024");
+        let options = RunOptions { memory_words: Some(1 << 20), undefined: true, ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", "EXIT\n")], &options).err().unwrap();
+        assert_eq!(errors[0].code, Code::UnsupportedOption);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let regions = ["flash @0x0800_0000 ro 1K", "sram @0x2000_0000 rw 1K"].iter().map(|region| region.parse().unwrap()).collect();
+        let options = RunOptions { memory_map: Some(MemoryMap::new(regions).unwrap()), ..RunOptions::default() };
+        let script = "\
+            count: #5\n\
+            LDR r0, count\n\
+            LDR r1, #0x02000000\n\
+            STR r0, #0x02000000\n";
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!((errors[0].code, errors[0].span.as_ref().map(|span| span.line)), (Code::ReadOnlyMemory, Some(4)));
+        // Programs address words, so the region at byte 0x2000_0000 starts at word 0x0800_0000
+        let machine = run_test_sources_with(&[("main.s", "count: #5\nLDR r0, count\nSTR r0, #0x08000001\nEXIT\n")], &options);
+        assert_eq!((machine.labels["count"], machine.registers[0], machine.memory[0x0800_0001]), (0x0800_0000, 5, 5));
+        let errors = try_run_test_sources(&[("main.s", "LDR r0, #4\n")], &options).err().unwrap();
+        assert_eq!(errors[0].message, "Memory access outside the memory map: address 0x4 (byte 0x10)");
+    }
+
+    // A map with RAM and a page of device registers at word 0x1000_0000
+    fn mmio_options() -> RunOptions {
+        let regions = ["sram @0x2000_0000 rw 1K", "peripheral @0x4000_0000 mmio"].iter().map(|region| region.parse().unwrap()).collect();
+        RunOptions { memory_map: Some(MemoryMap::new(regions).unwrap()), ..RunOptions::default() }
+    }
+
+    #[test]
+    fn test_mmio_goes_to_script_devices() {
+        let script = Script::parse("board.rhai", "device(0x10000000, |core| 42);\non_write(0x10000001, |core| core.value);").unwrap();
+        let options = RunOptions { script: Some(script), ..mmio_options() };
+        let sources = [("main.s".to_string(), "LDR r0, #0x10000000\nADD r0, r0, #1\nSTR r0, #0x10000001\nEXIT\n".to_string())];
+        let mut out = Vec::new();
+        let machine = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!((machine.registers[0], String::from_utf8(out).unwrap()), (43, "43\n".to_string()));
+    }
 
-            "PRINT" => {
-                if parts.len() != 2 {
-                    report_error(interactive, "Usage: PRINT <register>");
-                    continue;
-                }
-                let reg = parts[1];
-                if let Some(idx) = parse_register(reg) {
-                    println!("{} = {}", reg, registers[idx]);
-                } else {
-                    report_error(interactive, "Invalid register name. Use r0 through r15.");
-                }
-            },
-            _ => {
-                report_error(interactive, &format!("Unknown instruction: {}", parts[0]));
-                if !interactive {
-                    println!("Exiting due to unknown instruction.");
-                    break;
-                }
-            }
-        }
+    #[test]
+    fn test_mmio_without_a_device() {
+        let errors = try_run_test_sources(&[("main.s", "MOV r0, #1\nSTR r0, #0x10000004\n")], &mmio_options()).err().unwrap();
+        assert_eq!((errors[0].code, errors[0].span.as_ref().map(|span| span.line)), (Code::NoDevice, Some(2)));
+        assert_eq!(errors[0].message, "No device at address 0x10000004 (byte 0x40000010)");
+        // A device elsewhere in the region does not answer for the word
+        let options = RunOptions { script: Some(Script::parse("board.rhai", "device(0x10000000, |core| 42);").unwrap()), ..mmio_options() };
+        let errors = try_run_test_sources(&[("main.s", "LDR r0, #0x10000001\n")], &options).err().unwrap();
+        assert_eq!(errors[0].notes, ["the devices are at 0x10000000"]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
+    #[test]
+    fn test_target() {
+        let options = RunOptions { target: Some(Target::CortexMLike), ..RunOptions::default() };
+        let program = "count: #5\nhang: B hang\nReset_Handler: LDR r0, count\nSUB sp, sp, #1\nSTR r0, [sp]\nEXIT\n";
+        let machine = run_test_sources_with(&[("main.s", program)], &options);
+        // sp starts at the top of SRAM, from the vector table, and pc at the reset handler, which the reset vector holds
+        assert_eq!((machine.registers[0], machine.registers[STACK_POINTER], machine.memory[0x0800_13FF]), (5, 0x0800_13FF, 5));
+        assert_eq!((machine.labels["count"], machine.memory[0x0200_0000], machine.memory[0x0200_0001]), (0x0800_0000, 0x0800_1400, 1));
+        let options = RunOptions { registers: vec![(STACK_POINTER, 0x0800_1000)], ..options };
+        assert_eq!(run_test_sources_with(&[("main.s", "EXIT\n")], &options).registers[STACK_POINTER], 0x0800_1000);
+    }
 
-    /// A helper that runs the interpreter with a given script
-    fn run_test_script(script: &str) {
-        let input = script.as_bytes();
-        let cursor = Cursor::new(input);
-        run_with_reader(cursor, false);
+    #[test]
+    fn test_target_with_undefined_and_heatmap() {
+        let options = RunOptions { target: Some(Target::CortexMLike), undefined: true, heatmap: Some("-".to_string()), ..RunOptions::default() };
+        let program = "count: #5\nReset_Handler: LDR r0, count\nSUB sp, sp, #1\nSTR r0, [sp]\nLDR r2, #0x08000100\nCMP r2, #0\nBEQ done\ndone: EXIT\n";
+        let sources = [("main.s".to_string(), program.to_string())];
+        let mut out = Vec::new();
+        let machine = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        // The label, the stack and the vector table are defined, the word read from SRAM is not
+        let warnings: Vec<&str> = machine.warnings.iter().map(|warning| warning.message.as_str()).collect();
+        assert_eq!(warnings, ["The branch depends on flags that are undefined"]);
+        // The heatmap draws the regions of the map
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Memory heatmap: 3 words accessed, 2 reads, 1 writes"), "{}", out);
+        assert!(out.contains("\n134217728 |@") && out.contains("\n268435456 |"), "{}", out);
     }
 
     #[test]
-    fn test_add_instruction() {
+    fn test_operand_errors_name_the_operand() {
+        let errors = run_failing_script("LDR r0, [r99, #4]\nPRINT r99, q4\nCMP r1, r2, r3\n");
+        assert_eq!(errors[0].notes[1..], ["r99 is in the second operand, <address_operand> in LDR <register>, <address_operand>", "for example: LDR r0, [r1, #4]"]);
+        // The optional Q format of PRINT leaves the operands unmatched to its syntax
+        assert_eq!(errors[1].notes[1], "r99 is the first operand of PRINT");
+        assert_eq!(errors[2].notes, ["usage: CMP <reg_operand>, <operand>", "for example: CMP r0, #10"]);
+    }
+
+    #[test]
+    fn test_error_span_points_at_token() {
+        let errors = run_failing_script("MOV r0, #1\nADD r1, r99, #2  // bad register\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::InvalidRegister);
+        let span = errors[0].span.as_ref().unwrap();
+        assert_eq!((span.line, span.column_start, span.column_end), (2, 9, 12));
+        assert_eq!(errors[0].notes, vec![REGISTER_NOTE, "r99 is the second operand, <reg_operand> in ADD <dest_register>, <reg_operand>, <operand>",
+            "for example: ADD r0, r1, #5"]);
+    }
+
+    #[test]
+    fn test_execution_stops_at_first_runtime_error() {
+        let errors = run_failing_script("MOV r1, #5000\nLDR r0, [r1]\nLDR r0, [r1]\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_unreachable_invalid_code_is_rejected_when_loading() {
         let script = "\
-            MOV r1, #5\n\
-            MOV r2, #10\n\
-            ADD r3, r1, r2\n\
-            PRINT r3\n\
+            helper:\n\
+            BOGUS r0\n\
+            main:\n\
             EXIT\n";
-        run_test_script(script);
+        let errors = run_failing_script(script);
+        assert_eq!(errors[0].code, Code::UnknownInstruction);
     }
 
     #[test]
-    fn test_sub_instruction() {
+    fn test_forward_label_reference() {
         let script = "\
-            MOV r1, #20\n\
-            MOV r2, #7\n\
-            SUB r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            LDR r0, later\n\
+            EXIT\n\
+            later: #5\n";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[0], 5);
     }
 
     #[test]
-    fn test_adc_instruction() {
+    fn test_align() {
         let script = "\
-            MOV r1, #5\n\
-            MOV r2, #10\n\
-            ADC r3, r1, r2\n\
-            PRINT r3\n\
+            a: #1\n\
+            .align 4\n\
+            b: #2\n\
+            .align 3\n\
+            .align 2\n\
+            c: #3\n\
+            .align 3\n\
+            d: #4\n\
             EXIT\n";
-        run_test_script(script);
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // 16 bytes are 4 words and 8 bytes 2; a word is always aligned
+        assert_eq!(["a", "b", "c", "d"].map(|label| machine.labels[label]), [0, 4, 6, 8]);
+        assert_eq!(machine.memory[4], 2);
+
+        let errors = run_failing_script(".align 13\n.align x");
+        assert_eq!(errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>(), ["Invalid alignment: 13", "Invalid alignment: x"]);
     }
 
     #[test]
-    fn test_sbc_instruction() {
+    fn test_literal_pool() {
         let script = "\
-            MOV r1, #20\n\
-            MOV r2, #7\n\
-            SBC r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            value: #7\n\
+            main: LDR r0, =0x12345678\n\
+            LDR r1, =0x12345678\n\
+            LDR r2, =value\n\
+            LDR r3, =main\n\
+            LDR r4, =later+1\n\
+            ADR r5, value+1\n\
+            EXIT\n\
+            later: #9\n";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[..6], [0x12345678, 0x12345678, 0, 0, 3, 1]);
+        // After the labels, one word per distinct value: main is instruction 0, like value's address
+        assert_eq!(machine.memory.to_vec()[3..6], [0x12345678, 0, 3]);
+        assert_eq!(machine.labels.len(), 3);
+
+        let errors = run_failing_script("LDR r0, =nowhere");
+        assert_eq!(errors[0].message, "Undefined label: nowhere");
     }
 
     #[test]
-    fn test_mul_instruction() {
+    fn test_immediate_address_out_of_bounds_is_a_load_error() {
+        let errors = run_failing_script("EXIT\nLDR r0, #5000\n");
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+    }
+
+    #[test]
+    fn test_load_errors_are_all_reported() {
+        let errors = run_failing_script("a: #zz\nb: #1\nb: #2\n");
+        let codes: Vec<Code> = errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![Code::InvalidDataValue, Code::DuplicateLabel]);
+    }
+
+    #[test]
+    fn test_usage_error_has_note() {
+        let errors = run_failing_script("MOV r0\n");
+        assert_eq!(errors[0].code, Code::WrongOperandCount);
+        assert_eq!(errors[0].notes, vec!["usage: MOV <register>, <value>", "for example: MOV r0, #15"]);
+    }
+
+    #[test]
+    fn test_missing_comma_is_reported() {
+        let errors = run_failing_script("ADD r0 r1, #1\n");
+        assert_eq!(errors[0].code, Code::MissingComma);
+    }
+
+    #[test]
+    fn test_diagnostic_json_format() {
+        let errors = run_failing_script("FOO r1\n");
+        let json: serde_json::Value = serde_json::from_str(&errors[0].to_json()).unwrap();
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "E0012");
+        assert_eq!(json["name"], "UnknownInstruction");
+        assert_eq!(json["span"]["line"], 1);
+        assert_eq!(json["span"]["column_start"], 1);
+        assert_eq!(json["span"]["column_end"], 4);
+    }
+
+    #[test]
+    fn test_vfp_arithmetic() {
         let script = "\
-            MOV r1, #3\n\
-            MOV r2, #4\n\
-            MUL r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            VMOV s1, #1.5\n\
+            VMOV.F32 s2, #-0.25\n\
+            VADD.F32 s3, s1, s2\n\
+            VSUB.F32 s4, s1, s2\n\
+            VMUL.F32 s5, s1, s2\n\
+            VDIV.F32 s6, s1, s2\n\
+            PRINT s3\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.fp_registers[3], 1.25);
+        assert_eq!(machine.fp_registers[4], 1.75);
+        assert_eq!(machine.fp_registers[5], -0.375);
+        assert_eq!(machine.fp_registers[6], -6.0);
     }
 
     #[test]
-    fn test_and_instruction() {
+    fn test_vcvt_and_core_register_transfer() {
         let script = "\
-            MOV r1, #12\n\
-            MOV r2, #10\n\
-            AND r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r0, #7\n\
+            VMOV s0, r0\n\
+            VCVT.F32.S32 s0, s0\n\
+            VMOV s1, #2.0\n\
+            VDIV.F32 s2, s0, s1\n\
+            VCVT.S32.F32 s3, s2\n\
+            VMOV r1, s3\n\
+            VMOV s4, #-3.9\n\
+            VCVT.S32.F32 s4, s4\n\
+            VMOV r2, s4\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.fp_registers[2], 3.5);
+        // Conversion to an integer rounds toward zero
+        assert_eq!(machine.registers[1], 3);
+        assert_eq!(machine.registers[2], -3);
     }
 
     #[test]
-    fn test_orr_instruction() {
+    fn test_vldr_and_vstr() {
         let script = "\
-            MOV r1, #4\n\
-            MOV r2, #2\n\
-            ORR r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            result: #0\n\
+            VMOV s0, #0.5\n\
+            VSTR s0, result\n\
+            MOV r1, #0\n\
+            VLDR s1, [r1]\n\
+            LDR r2, result\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.fp_registers[1], 0.5);
+        assert_eq!(machine.registers[2], 0.5f32.to_bits() as i32);
     }
 
     #[test]
-    fn test_bic_instruction() {
+    fn test_invalid_fp_register_is_reported() {
+        let errors = run_failing_script("VADD.F32 s0, s1, s32\nVMOV s0, #abc\n");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].code, Code::InvalidRegister);
+        assert_eq!(errors[0].notes, vec![FP_REGISTER_NOTE, "s32 is the third operand, <fp_register> in VADD.F32 <dest_fp_register>, <fp_register>, <fp_register>",
+            "for example: VADD.F32 s0, s1, s2"]);
+        assert_eq!(errors[1].code, Code::InvalidOperand);
+    }
+
+    #[test]
+    fn test_halfword_multiplies() {
         let script = "\
-            MOV r1, #7\n\
-            MOV r2, #6\n\
-            BIC r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r1, #0x4000\n\
+            ORR r1, r1, #0x20000\n\
+            MOV r2, #-8192\n\
+            SMULBB r3, r1, r2\n\
+            SMULTB r4, r1, r2\n\
+            SMLABB r5, r1, r2, r4\n\
+            ASR r6, r3, #15\n\
+            PRINT r6, q15\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // 0.5 * -0.25 in Q15 is -0.125 in Q30
+        assert_eq!(machine.registers[3], 0x4000 * -0x2000);
+        assert_eq!(machine.registers[4], 2 * -0x2000);
+        assert_eq!(machine.registers[5], 0x4000 * -0x2000 + 2 * -0x2000);
+        assert_eq!(machine.registers[6], -0x1000);
     }
 
     #[test]
-    fn test_eor_instruction() {
+    fn test_print_q_format_is_checked() {
+        let errors = run_failing_script("PRINT r0, q32\nPRINT s0, q15\nSMLABB r0, r1, r2\n");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].code, Code::InvalidOperand);
+        assert_eq!(errors[1].code, Code::WrongOperandCount);
+        assert_eq!(errors[2].code, Code::WrongOperandCount);
+        assert_eq!(errors[2].notes, vec!["usage: SMLA<x><y> <dest_register>, <register>, <register>, <accumulate_register>", "for example: SMLABB r0, r1, r2, r0"]);
+    }
+
+    #[test]
+    fn test_parallel_add_and_subtract() {
+        let a = 0x7F80_01FFu32 as i32;
+        let b = 0x0180_FF01u32 as i32;
+        // Bytes wrap independently; GE marks unsigned carries
+        assert_eq!(parallel(ParallelOp::Uadd, 8, a, b), (0x8000_0000u32 as i32, Some(0b0111)));
+        // Signed bytes: 127+1 and -128+-128 wrap, 1+-1 = 0, -1+1 = 0
+        assert_eq!(parallel(ParallelOp::Sadd, 8, a, b), (0x8000_0000u32 as i32, Some(0b1011)));
+        assert_eq!(parallel(ParallelOp::Qadd, 8, a, b).0, 0x7F80_0000u32 as i32);
+        assert_eq!(parallel(ParallelOp::Uqadd, 8, a, b).0, 0x80FF_FFFFu32 as i32);
+        assert_eq!(parallel(ParallelOp::Uqsub, 8, a, b).0, 0x7E00_00FEu32 as i32);
+        assert_eq!(parallel(ParallelOp::Qsub, 16, 0x8000_0001u32 as i32, 0x0001_0002).0, 0x8000_FFFFu32 as i32);
+        // A halfword lane sets two GE flags
+        assert_eq!(parallel(ParallelOp::Sadd, 16, 0x0001_FFFF, 0x0001_0000).1, Some(0b1100));
+    }
+
+    #[test]
+    fn test_sel_uses_ge_flags() {
         let script = "\
-            MOV r1, #5\n\
-            MOV r2, #3\n\
-            EOR r3, r1, r2\n\
-            PRINT r3\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r1, #0x0A00\n\
+            ORR r1, r1, #0x05\n\
+            MOV r2, #0x0500\n\
+            ORR r2, r2, #0x0A\n\
+            USUB8 r0, r1, r2\n\
+            SEL r3, r1, r2\n\
+            SEL r4, r2, r1\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // Byte-wise maximum and minimum
+        assert_eq!(machine.registers[3], 0x0A0A);
+        assert_eq!(machine.registers[4], 0x0505);
+        assert_eq!((machine.cpsr >> CPSR_GE_SHIFT) & 0xF, 0b1110);
     }
 
     #[test]
-    fn test_lsl_instruction() {
+    fn test_mrs_and_msr() {
         let script = "\
-            MOV r1, #1\n\
-            LSL r2, r1, #3\n\
-            PRINT r2\n\
-            EXIT\n";
-        run_test_script(script);
+            MRS r0, CPSR\n\
+            MOV r1, #0xF\n\
+            LSL r1, r1, #28\n\
+            ORR r1, r1, #0x1F\n\
+            MSR CPSR_f, r1\n\
+            MRS r2, APSR\n\
+            MSR CPSR_c, #0\n\
+            MSR APSR_nzcvq, #0x20000000\n\
+            MRS r4, CPSR\n\
+            ADC r3, r3, #1\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[0], 0x10);
+        // Only the flags change; the mode stays User
+        assert_eq!(machine.registers[2] as u32, 0xF000_0010);
+        assert_eq!(machine.registers[4], 0x2000_0010);
+        // The carry set by MSR is added by ADC
+        assert_eq!(machine.registers[3], 2);
     }
 
     #[test]
-    fn test_lsr_instruction() {
+    fn test_exclusive_monitor() {
         let script = "\
-            MOV r1, #16\n\
-            LSR r2, r1, #2\n\
-            PRINT r2\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r1, #100\n\
+            MOV r0, #7\n\
+            STREX r2, r0, [r1]\n\
+            LDREX r3, [r1]\n\
+            DMB ISH\n\
+            STREX r4, r0, [r1]\n\
+            STREX r5, r0, [r1]\n\
+            LDREX r3, [r1]\n\
+            STR r3, [r1]\n\
+            STREX r6, r0, [r1]\n\
+            LDREX r3, [r1]\n\
+            CLREX\n\
+            STREX r7, r0, [r1]\n\
+            LDREX r3, [r1]\n\
+            ISB\n\
+            STREX r8, r0, [r1, #0]\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // Without a reservation, after one was used, after a store and after CLREX, STREX fails
+        assert_eq!(machine.registers[2..=8], [1, 7, 0, 1, 1, 1, 0]);
+
+        let errors = run_failing_script("LDREX r0, [r1, #1]\nDMB XYZ\nISB ISH\n");
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), [Code::InvalidAddressOperand, Code::InvalidOperand, Code::InvalidOperand]);
     }
 
     #[test]
-    fn test_asr_instruction() {
+    fn test_psr_operands_are_checked() {
+        let errors = run_failing_script("MRS r0, SPSR\nMSR CPSR_q, r0\nMRS r0, r1\n");
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].message.contains("User mode"));
+        assert!(errors.iter().all(|e| e.code == Code::InvalidOperand));
+    }
+
+    #[test]
+    fn test_shift_carry_out_boundaries() {
+        let value = 0x8000_0001u32 as i32;
+        // An amount of 0 leaves the value and the carry unchanged
+        for op in [ShiftOp::Lsl, ShiftOp::Lsr, ShiftOp::Asr, ShiftOp::Ror] {
+            assert_eq!(shift_with_carry(op, value, 0, true), (value, true));
+            assert_eq!(shift_with_carry(op, value, 0, false), (value, false));
+        }
+
+        assert_eq!(shift_with_carry(ShiftOp::Lsl, value, 1, false), (2, true));
+        assert_eq!(shift_with_carry(ShiftOp::Lsl, value, 31, false), (0x8000_0000u32 as i32, false));
+        assert_eq!(shift_with_carry(ShiftOp::Lsl, value, 32, false), (0, true));
+        assert_eq!(shift_with_carry(ShiftOp::Lsl, value, 33, true), (0, false));
+
+        assert_eq!(shift_with_carry(ShiftOp::Lsr, value, 1, false), (0x4000_0000, true));
+        assert_eq!(shift_with_carry(ShiftOp::Lsr, value, 31, false), (1, false));
+        assert_eq!(shift_with_carry(ShiftOp::Lsr, value, 32, false), (0, true));
+        assert_eq!(shift_with_carry(ShiftOp::Lsr, value, 33, true), (0, false));
+
+        assert_eq!(shift_with_carry(ShiftOp::Asr, value, 1, false), (0xC000_0000u32 as i32, true));
+        assert_eq!(shift_with_carry(ShiftOp::Asr, value, 31, false), (-1, false));
+        assert_eq!(shift_with_carry(ShiftOp::Asr, value, 32, false), (-1, true));
+        assert_eq!(shift_with_carry(ShiftOp::Asr, value, 200, false), (-1, true));
+        assert_eq!(shift_with_carry(ShiftOp::Asr, 0x4000_0000, 32, true), (0, false));
+
+        assert_eq!(shift_with_carry(ShiftOp::Ror, value, 1, false), (0xC000_0000u32 as i32, true));
+        assert_eq!(shift_with_carry(ShiftOp::Ror, value, 4, false), (0x1800_0000, false));
+        assert_eq!(shift_with_carry(ShiftOp::Ror, value, 32, false), (value, true));
+        assert_eq!(shift_with_carry(ShiftOp::Ror, value, 33, false), (0xC000_0000u32 as i32, true));
+    }
+
+    #[test]
+    fn test_flag_setting_shifts() {
         let script = "\
-            MOV r1, #-32\n\
-            ASR r2, r1, #2\n\
-            PRINT r2\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r1, #3\n\
+            LSRS r2, r1, #1\n\
+            MRS r3, CPSR\n\
+            MOV r4, #33\n\
+            LSLS r5, r1, r4\n\
+            MRS r6, CPSR\n\
+            MOV r4, #0x100\n\
+            LSLS r7, r1, r4\n\
+            MRS r8, CPSR\n\
+            LSL r9, r1, #31\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[2], 1);
+        assert_eq!(machine.registers[3] as u32, CPSR_C | USER_MODE);
+        assert_eq!(machine.registers[5], 0);
+        assert_eq!(machine.registers[6] as u32, CPSR_Z | USER_MODE);
+        // Only the bottom byte of a shift register counts, so this shifts by 0 and keeps C clear
+        assert_eq!(machine.registers[7], 3);
+        assert_eq!(machine.registers[8] as u32, USER_MODE);
+        // LSL without S leaves the flags alone
+        assert_eq!(machine.cpsr, USER_MODE);
+    }
+
+    // Runs CMP a, b followed by the conditional branch and returns whether it was taken
+    fn branch_taken(a: i32, b: i32, branch: &str) -> bool {
+        let script = format!("\
+            CMP r0, r1\n\
+            {} taken\n\
+            EXIT\n\
+            taken: MOV r2, #1\n", branch);
+        let options = RunOptions { registers: vec![(0, a), (1, b)], ..RunOptions::default() };
+        run_test_sources_with(&[("main.s", &script)], &options).registers[2] == 1
     }
 
     #[test]
-    fn test_ror_instruction() {
+    fn test_signed_branches_near_limits() {
+        let cases = [
+            (i32::MIN, 1),
+            (i32::MAX, -1),
+            (i32::MIN, i32::MAX),
+            (i32::MAX, i32::MIN),
+            (i32::MIN, i32::MIN),
+            (-1, 0),
+            (0, -1),
+            (5, 5),
+        ];
+        for (a, b) in cases {
+            assert_eq!(branch_taken(a, b, "BGT"), a > b, "BGT {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BLT"), a < b, "BLT {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BGE"), a >= b, "BGE {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BLE"), a <= b, "BLE {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BEQ"), a == b, "BEQ {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BHI"), (a as u32) > (b as u32), "BHI {} {}", a, b);
+            assert_eq!(branch_taken(a, b, "BLO"), (a as u32) < (b as u32), "BLO {} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_overflow_flag() {
+        let v = |a: i32, b: i32| add_with_carry(a, b, false).2;
+        let sub_v = |a: i32, b: i32| add_with_carry(a, !b, true).2;
+        assert!(v(i32::MAX, 1));
+        assert!(v(i32::MIN, -1));
+        assert!(!v(i32::MAX, -1));
+        assert!(!v(-1, -1));
+        assert!(sub_v(i32::MIN, 1));
+        assert!(sub_v(i32::MAX, -1));
+        assert!(sub_v(0, i32::MIN));
+        assert!(!sub_v(-1, i32::MIN));
+        // Carry means no unsigned borrow
+        assert_eq!(add_with_carry(5, !5, true), (0, true, false));
+        assert_eq!(add_with_carry(-1, 1, false), (0, true, false));
+    }
+
+    #[test]
+    fn test_adds_subs_and_cmn_set_flags() {
         let script = "\
-            MOV r1, #4\n\
-            ROR r2, r1, #1\n\
-            PRINT r2\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r0, #2147483647\n\
+            ADDS r1, r0, #1\n\
+            MRS r2, CPSR\n\
+            SUBS r3, r1, #1\n\
+            MRS r4, CPSR\n\
+            CMN r0, #1\n\
+            BVS overflowed\n\
+            EXIT\n\
+            overflowed: MOV r5, #1\n\
+            EOR r6, r5, r5\n\
+            ANDS r6, r6, #1\n\
+            BEQ zero\n\
+            EXIT\n\
+            zero: MOV r7, #1\n";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[1], i32::MIN);
+        assert_eq!(machine.registers[2] as u32, CPSR_N | CPSR_V | USER_MODE);
+        assert_eq!(machine.registers[3], i32::MAX);
+        assert_eq!(machine.registers[4] as u32, CPSR_C | CPSR_V | USER_MODE);
+        assert_eq!(machine.registers[5], 1);
+        assert_eq!(machine.registers[7], 1);
     }
 
     #[test]
-    fn test_rrx_instruction() {
+    fn test_branch_errors() {
+        let errors = run_failing_script("data: #1\nBNE data\nB nowhere\n");
+        assert_eq!(errors[0].code, Code::InvalidBranchTarget);
+        assert_eq!(errors[1].code, Code::UndefinedLabel);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_by_default() {
         let script = "\
-            MOV r1, #8\n\
-            RRX r2, r1\n\
-            PRINT r2\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r0, #2147483647\n\
+            ADD r1, r0, #1\n\
+            MOV r2, #65536\n\
+            MUL r3, r2, r2\n\
+            SUB r4, r1, #1\n\
+            MRS r5, CPSR\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[1], i32::MIN);
+        assert_eq!(machine.registers[3], 0);
+        assert_eq!(machine.registers[4], i32::MAX);
+        // ADD and SUB without S leave the flags alone
+        assert_eq!(machine.registers[5] as u32, USER_MODE);
     }
 
     #[test]
-    fn test_ldr_str_label() {
+    fn test_trap_overflow() {
+        let options = RunOptions { trap_overflow: true, ..RunOptions::default() };
         let script = "\
-            data_val:\n\
-            MOV r0, #123\n\
-            STR r0, data_val\n\
-            MOV r1, #0\n\
-            LDR r1, data_val\n\
-            PRINT r1\n\
-            EXIT\n"; // Expect r1 to be 123
-        run_test_script(script); // This test will panic if PRINT r1 is not 123 due to how PRINT works or if errors occur.
-                                 // A more robust test would capture output or check register values directly if possible.
+            MOV r0, #2147483647\n\
+            ADDS r1, r0, #1\n\
+            MOV r2, #65536\n\
+            MUL r3, r2, r2\n\
+            EXIT";
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::ArithmeticOverflow);
+        // ADDS reports overflow in V instead, so MUL on line 4 is the one that traps
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 4);
+        assert_eq!(errors[0].notes, vec!["without --trap-overflow the result wraps around to 0"]);
     }
 
     #[test]
-    fn test_ldr_str_immediate_address() {
+    fn test_bl_and_bx() {
         let script = "\
-            MOV r0, #456\n\
-            STR r0, #10\n\
-            MOV r1, #0\n\
-            LDR r1, #10\n\
-            PRINT r1\n\
-            EXIT\n"; // Expect r1 to be 456
-        run_test_script(script);
+            main: MOV r0, #5\n\
+            BL factorial\n\
+            EXIT\n\
+            factorial: CMP r0, #1\n\
+            BGT recurse\n\
+            MOV r1, #1\n\
+            BX r14\n\
+            recurse: STR r14, [r13]\n\
+            STR r0, [r13, #1]\n\
+            ADD r13, r13, #2\n\
+            SUB r0, r0, #1\n\
+            BL factorial\n\
+            SUB r13, r13, #2\n\
+            LDR r0, [r13, #1]\n\
+            LDR r14, [r13]\n\
+            MUL r1, r1, r0\n\
+            BX r14";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        assert_eq!(machine.registers[1], 120);
+        assert_eq!(machine.registers[14], 2);
+
+        // Returning from the entry point ends the program
+        let machine = run_test_sources_with(&[("main.s", "MOV r0, #1\nBX r14\nMOV r0, #2")], &RunOptions::default());
+        assert_eq!(machine.registers[0], 1);
     }
 
     #[test]
-    fn test_ldr_str_register_indirect() {
+    fn test_local_variable_names() {
         let script = "\
-            MOV r0, #20\n\
-            MOV r1, #789\n\
-            STR r1, [r0]\n\
-            MOV r2, #0\n\
-            LDR r2, [r0]\n\
-            PRINT r2\n\
-            EXIT\n"; // Expect r2 to be 789
-        run_test_script(script);
+            main: MOV fp, #100\n\
+            .local count, -1\n\
+            .local total, -2\n\
+            STR r0, [fp, #-1]\n\
+            loop: LDR r1, [r11, #-2]\n\
+            BL helper\n\
+            EXIT\n\
+            helper: .local count, -3\n\
+            LDR r2, [fp, #-3]\n\
+            LDR r2, [fp, #-2]\n\
+            BX lr";
+        let sources = [("main.s".to_string(), script.to_string())];
+        let program = load_program(&Arm, &mut Machine::new(), &sources).unwrap_or_else(|_| panic!("the program should load"));
+        let locals: Vec<Option<&str>> = program.lines.iter().map(|line| line.local.as_deref()).collect();
+        // A label inside a function keeps its locals; a .local after a label starts a new function
+        assert_eq!(locals, vec![None, Some("count"), Some("total"), None, None, Some("count"), None, None]);
+
+        let errors = run_failing_script(".local count, two\nEXIT");
+        assert_eq!(errors[0].code, Code::InvalidOperand);
+        assert_eq!(errors[0].message, "Invalid frame offset: two");
     }
-    
+
     #[test]
-    fn test_ldr_str_register_indirect_offset() {
+    fn test_source_map_directives() {
         let script = "\
-            MOV r0, #30\n\
-            MOV r1, #101\n\
-            STR r1, [r0,#4]\n\
-            MOV r2, #0\n\
-            LDR r2, [r0,#4]\n\
-            PRINT r2\n\
-            EXIT\n"; // Expect r2 to be 101 (stored at memory[34])
-        run_test_script(script);
+            .file 1 \"fact.c\"\n\
+            MOV r0, #1\n\
+            .loc 1 4 7\n\
+            MOV r1, #2\n\
+            MOV r2, #3\n\
+            #line 10 \"main.c\"\n\
+            MOV r3, #4\n\
+            #line 12\n\
+            MOV r4, #5\n\
+            .loc util.c 3\n\
+            EXIT";
+        let sources = [("fact.s".to_string(), script.to_string())];
+        let program = load_program(&Arm, &mut Machine::new(), &sources).unwrap_or_else(|_| panic!("the program should load"));
+        let origins: Vec<Option<String>> = program.lines.iter().map(|line| line.origin.as_ref().map(Origin::to_string)).collect();
+        assert_eq!(origins, vec![None, Some("fact.c:4".to_string()), Some("fact.c:4".to_string()),
+            Some("main.c:10".to_string()), Some("main.c:12".to_string()), Some("util.c:3".to_string())]);
+
+        // Errors point at the assembly line and name the C line
+        let errors = run_failing_script(".loc \"loop.c\" 8\nLDR r0, [r1, #2000]");
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 2);
+        assert_eq!(errors[0].notes.last().unwrap(), "generated from loop.c:8");
+
+        let errors = run_failing_script(".loc 2 8\nEXIT");
+        assert_eq!(errors[0].message, "Unknown file number in .loc: 2");
     }
 
     #[test]
-    fn test_ldr_str_register_indirect_negative_offset() {
+    fn test_step_limit() {
+        let options = RunOptions { max_steps: Some(10), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", "MOV r0, #0\nloop: ADD r0, r0, #1\nB loop")], &options).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::StepLimit);
+        assert_eq!(errors[0].message, "Stopped after 10 instructions");
+        // A program that ends within the limit is not affected
+        let options = RunOptions { max_steps: Some(3), ..RunOptions::default() };
+        assert_eq!(run_test_sources_with(&[("main.s", "MOV r0, #1\nMOV r1, #2\nEXIT")], &options).registers[1], 2);
+    }
+
+    #[test]
+    fn test_cycle_budget() {
+        // 1 for MOV, 4 for each SUBS and taken BNE, 2 for the last and 1 for EXIT
+        let script = ".budget cycles 12\nMOV r0, #3\nloop: SUBS r0, r0, #1\nBNE loop\nEXIT";
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), script.to_string())];
+        run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Used 12 of 12 cycles\n");
+
+        // --cycle-budget takes precedence
+        let options = RunOptions { cycle_budget: Some(10), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors[0].code, Code::CycleBudget);
+        assert_eq!(errors[0].message, "Over the budget of 10 cycles: 11 cycles after 7 instructions");
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 4);
+        assert!(errors[0].notes[0].starts_with("the budget was set with --cycle-budget 10"));
+
+        let errors = run_failing_script(".budget cycles 10\n.budget cycles 20\n.budget steps 5\n.budget cycles 0");
+        assert_eq!(errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>(),
+            ["A program has one cycle budget", "Invalid .budget directive", "Invalid cycle budget: 0"]);
+        assert_eq!(errors[0].notes[0], "it was set with .budget cycles 10 at main.s:1");
+    }
+
+    #[test]
+    fn test_word_size() {
+        let run = |bits: u32, script: &str| {
+            let options = RunOptions { word_size: Some(bits), ..RunOptions::default() };
+            let machine = run_test_sources_with(&[("main.s", script)], &options);
+            let flags = Arm.flags(&machine).iter().filter(|&&(_, set)| set).map(|&(name, _)| name).collect::<String>();
+            (machine.registers, flags, machine.memory)
+        };
+        // 127 + 1 overflows a signed byte, and 255 is -1, to which 1 adds with a carry
+        let (registers, flags, memory) = run(8, "value: #200\nMOV r0, #127\nADDS r0, r0, #1\nMOV r2, #100\nSTR r0, [r2]\nMOV r1, #255\nADDS r1, r1, #1\nLDR r3, value\nMOV sp, #1000");
+        assert_eq!((registers[0], registers[1], registers[3], memory[100]), (-128, 0, -56, -128));
+        // The stack pointer keeps its address
+        assert_eq!(registers[13], 1000);
+        assert_eq!(flags, "ZC");
+        let (registers, flags, _) = run(8, "MOV r0, #127\nADDS r0, r0, #1");
+        assert_eq!((registers[0], flags.as_str()), (-128, "NV"));
+        // Shifts and rotations stay within 16 bits
+        let (registers, flags, _) = run(16, "MOV r0, #1\nLSL r0, r0, #15\nMOV r1, #-1\nLSRS r1, r1, #1\nMOV r2, #0xF\nROR r2, r2, #4\nMUL r3, r0, r0");
+        assert_eq!(registers[..4], [-32768, 32767, -4096, 0]);
+        assert_eq!(flags, "C");
+
+        let errors = run_sources(&crate::x86::X86, &[("main.s".to_string(), "mov rax, 1".to_string())],
+            &RunOptions { word_size: Some(8), ..RunOptions::default() }, &mut Console::new(&mut io::empty(), &mut io::sink())).err().unwrap();
+        assert_eq!(errors[0].message, "--word-size is not supported with --isa x86");
+    }
+
+    #[test]
+    fn test_word_size_immediates() {
+        let options = RunOptions { word_size: Some(8), ..RunOptions::default() };
+        // -128 and 255 are a byte as signed and unsigned numbers, but 300 is not
+        let machine = run_test_sources_with(&[("main.s", "MOV r0, #-128\nMOV r1, #255\nMOV r2, #300\nADD r3, r2, #0x100")], &options);
+        assert_eq!(machine.registers[..4], [-128, -1, 44, 44]);
+        assert_eq!(machine.warnings.iter().map(|warning| warning.span.as_ref().unwrap().line).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(machine.warnings[0].notes[0], "words of 8 bits hold -128 to 255, so #300 becomes 44");
+
+        let strict = RunOptions { strict: true, ..options };
+        let errors = try_run_test_sources(&[("main.s", "MOV r0, #300")], &strict).err().unwrap();
+        assert_eq!(errors[0].message, "Immediate out of range: MOV r0, #300");
+    }
+
+    #[test]
+    fn test_word_size_mrs() {
+        let options = RunOptions { word_size: Some(8), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", "MOV r0, #0\nCMP r0, #0\nMRS r1, CPSR")], &options).err().unwrap();
+        assert_eq!((errors[0].code, errors[0].span.as_ref().unwrap().line), (Code::UnsupportedOption, 3));
+        assert_eq!(errors[0].message, "MRS cannot copy the CPSR into words of 8 bits");
+        // With 32-bit words MRS keeps the Z and C flags of CMP
+        let machine = run_test_sources_with(&[("main.s", "MOV r0, #0\nCMP r0, #0\nMRS r1, CPSR")], &RunOptions::default());
+        assert_eq!(machine.registers[1] as u32 >> 28, 0b0110);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let dir = std::env::temp_dir().join(format!("asm-checkpoints-{}", std::process::id()));
+        let options = RunOptions { checkpoints: Some((2, dir.clone())), ..RunOptions::default() };
+        run_test_sources_with(&[("main.s", "MOV r0, #3\nloop: SUBS r0, r0, #1\nBNE loop\nEXIT")], &options);
+        let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, ["step-2.json", "step-4.json", "step-6.json", "step-8.json"]);
+        let load = |file: &str| Snapshot::load(&dir.join(file).display().to_string()).unwrap();
+        let (second, fourth) = (load("step-2.json"), load("step-4.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        // Another time round the loop
+        assert_eq!(second.diff(&fourth).unwrap(), ["r0: 2 -> 1"]);
+    }
+
+    #[test]
+    fn test_allow_only_and_forbid() {
+        let script = "MOV r0, #3\nMOV r1, #0\nloop: ADDS r1, r1, #4\nSUBS r0, r0, #1\nBNE loop\nMUL r2, r1, r1\nPRINT r1\nEXIT";
+        let options = RunOptions { forbid: vec!["mul".to_string()], ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::ForbiddenInstruction);
+        assert_eq!(errors[0].message, "MUL is not allowed here");
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 6);
+
+        // ADD allows ADDS and B allows BNE, but BNE alone does not allow B
+        let allow = |names: &[&str]| RunOptions { allow_only: names.iter().map(|name| name.to_string()).collect(), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &allow(&["MOV", "ADD", "SUBS", "B"])).err().unwrap();
+        assert_eq!(errors.iter().map(|error| error.span.as_ref().unwrap().line).collect::<Vec<_>>(), [6]);
+        assert_eq!(errors[0].notes[0], "--allow-only MOV,ADD,SUBS,B leaves it out");
+        assert!(try_run_test_sources(&[("main.s", "SUB r0, r0, #1\nB end\nend: EXIT")], &allow(&["SUBS", "BNE"])).is_err());
+        assert!(try_run_test_sources(&[("main.s", script)], &allow(&["MOV", "ADD", "SUB", "B", "MUL"])).is_ok());
+
+        let errors = try_run_test_sources(&[("main.s", script)], &allow(&["MOV", "ADDD"])).err().unwrap();
+        assert_eq!(errors[0].message, "Unknown instruction: ADDD");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let interrupt = Interrupt::default();
+        // Nothing is running, so Ctrl-C would end the process instead
+        assert!(!interrupt.request());
+        let requester = interrupt.clone();
+        let thread = std::thread::spawn(move || {
+            while !requester.request() {
+                std::thread::yield_now();
+            }
+        });
+        let options = RunOptions { interrupt: interrupt.clone(), ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", "MOV r0, #0\nloop: ADD r0, r0, #1\nB loop")], &options).err().unwrap();
+        thread.join().unwrap();
+        assert_eq!(errors[0].code, Code::Interrupted);
+        assert!(errors[0].span.as_ref().unwrap().line >= 2);
+        // The request ends with the run
+        assert!(!interrupt.request());
+        assert_eq!(run_test_sources_with(&[("main.s", "MOV r0, #1")], &options).registers[0], 1);
+    }
+
+    #[test]
+    fn test_print_output() {
         let script = "\
-            MOV r0, #40\n\
-            MOV r1, #202\n\
-            STR r1, [r0,#-2]\n\
-            MOV r2, #0\n\
-            LDR r2, [r0,#-2]\n\
-            PRINT r2\n\
-            EXIT\n"; // Expect r2 to be 202 (stored at memory[38])
-        run_test_script(script);
+            MOV r0, #-3\n\
+            PRINT r0\n\
+            MOV r1, #0x4000\n\
+            PRINT r1, q15\n\
+            VMOV s2, #1.5\n\
+            PRINT s2";
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), script.to_string())];
+        run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "r0 = -3\nr1 = 0.5 (q15)\ns2 = 1.5\n");
     }
 
     #[test]
-    fn test_comments_and_labels() {
+    fn test_beep() {
+        // A frequency of 0 rings the bell, and a negative duration is none
+        let script = "MOV r0, #0\nMOV r1, #20\nSVC #4\nMOV r1, #-5\nSVC #4\nPRINT r0";
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), script.to_string())];
+        run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\x07\x07r0 = 0\n");
+    }
+
+    #[test]
+    fn test_string_helpers() {
         let script = "\
-            // This is a full line comment\n\
-            data_start: // This is a label\n\
-            MOV r0, #10 // Move 10 to r0\n\
-            // Another comment\n\
-            STR r0, data_start // Store r0 to data_start\n\
-            LDR r1, data_start // Load from data_start to r1\n\
-            PRINT r1 // Should print 10\n\
-            EXIT\n";
-        run_test_script(script);
+            s: #104\ns1: #105\ns2: #0\n\
+            t: #104\nt1: #97\nt2: #0\n\
+            MOV r0, #0\nSVC #5\nMOV r4, r0\n\
+            MOV r0, #0\nMOV r1, #3\nSVC #6\nMOV r5, r0\n\
+            MOV r0, #10\nMOV r1, #0\nMOV r2, #3\nSVC #7\n\
+            MOV r1, #0\nSVC #6\nEXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // "hi" has 2 characters, is after "ha" by 'i' - 'a', and equals its copy
+        assert_eq!([machine.registers[4], machine.registers[5], machine.registers[0]], [2, 8, 0]);
+        assert_eq!(machine.memory.to_vec()[10..13], [104, 105, 0]);
+
+        let options = RunOptions { no_helpers: true, ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", "SVC #5")], &options).err().unwrap();
+        assert_eq!(errors[0].message, "The helper SVC #5 is turned off with --no-helpers");
+        // A string without its 0 runs off the end of memory
+        let script = "MOV r0, #1021\nMOV r1, #1\nSTR r1, [r0]\nSTR r1, [r0, #1]\nSTR r1, [r0, #2]\nSVC #5";
+        let errors = try_run_test_sources(&[("main.s", script)], &RunOptions::default()).err().unwrap();
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
     }
 
     #[test]
-    fn test_empty_lines_and_whitespace_with_comments() {
+    fn test_bkpt_instruction() {
+        let script = "MOV r0, #5\nMSR CPSR_f, #0x40000000\nBKPT #1\nMOV r1, #7";
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), script.to_string())];
+        let machine = run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(machine.registers[1], 7);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Breakpoint at main.s:3\n   r0 = 5            r1 = 0"), "{}", out);
+        assert!(out.ends_with("\n  N=0 Z=1 C=0 V=0 Q=0\n"), "{}", out);
+
+        let options = RunOptions { strict_bkpt: true, ..RunOptions::default() };
+        let errors = try_run_test_sources(&[("main.s", script)], &options).err().unwrap();
+        assert_eq!(errors[0].code, Code::Breakpoint);
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 3);
+        assert_eq!(run_failing_script("BKPT #70000")[0].code, Code::InvalidOperand);
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let script = "MOV r0, #0\nloop: ADD r0, r0, #1\nCMP r0, #5\nBNE loop";
+        let run = |breakpoints: &[&str]| {
+            let options = RunOptions { breakpoints: breakpoints.iter().map(|spec| spec.parse().unwrap()).collect(), ..RunOptions::default() };
+            let mut out = Vec::new();
+            let sources = [("main.s".to_string(), script.to_string())];
+            run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out))
+                .map(|_| String::from_utf8(out).unwrap().lines().filter(|line| line.starts_with("Breakpoint")).count())
+        };
+        assert_eq!(run(&["loop"]), Ok(5));
+        assert_eq!(run(&["loop if r0 >= 3"]), Ok(2));
+        // From the fourth hit on, and line 3 without a file is in main.s
+        assert_eq!(run(&["3 count 4"]), Ok(2));
+        assert_eq!(run(&["main.s:4 if Z"]), Ok(1));
+
+        let errors = run(&["done", "main.s:9"]).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error.code == Code::InvalidBreakpoint));
+        let errors = run(&["loop if r0 > missing"]).unwrap_err();
+        assert_eq!(errors[0].message, "Cannot evaluate the breakpoint condition: unknown name 'missing' in 'r0 > missing'");
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_debug_script() {
+        let program = "MOV r0, #0\nloop: ADD r0, r0, #1\nCMP r0, #4\nBNE loop\nBKPT\nLSL r1, r0, #1";
+        let run = |script: &str| {
+            let options = RunOptions { debug_script: Some(DebugScript::parse("walk.dbg", script).unwrap()), ..RunOptions::default() };
+            let mut out = Vec::new();
+            let sources = [("main.s".to_string(), program.to_string())];
+            run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out))
+                .map(|machine| (machine.registers[1], String::from_utf8(out).unwrap()))
+        };
+        let (r1, out) = run("print r0\nbreak loop if r0 == 2\nrun\nprint r0\nstep 2\nassert r0 == 3\ncontinue\nprint r0 + r1\ncontinue\nprint r1").unwrap();
+        assert_eq!(r1, 8);
+        assert_eq!(out, "r0 = 0\n\
+            Breakpoint at main.s:2: ADD r0, r0, #1\nr0 = 2\n\
+            Paused at main.s:4: BNE loop\n\
+            Paused at main.s:6: LSL r1, r0, #1\nr0 + r1 = 4\n\
+            r1 = 8\n");
+
+        // quit leaves the rest of the program unrun
+        assert_eq!(run("break 6\nrun\nquit\nprint r1").unwrap().0, 0);
+        let errors = run("run\nassert r1 == 7").unwrap_err();
+        assert_eq!(errors[0].code, Code::AssertionFailed);
+        assert_eq!(errors[0].span.as_ref().unwrap().file, "walk.dbg");
+        assert_eq!(run("break done").unwrap_err()[0].code, Code::InvalidBreakpoint);
+    }
+
+    #[test]
+    fn test_watchdog_state_includes_tasks_and_reservation() {
+        let mut machine = Machine::new();
+        let free = snapshot(&Arm, &machine, 0, 0);
+        machine.exclusive = Some((100, machine.accesses.writes(100)));
+        let reserved = snapshot(&Arm, &machine, 0, 0);
+        assert_ne!(reserved, free);
+        // A store to the address breaks the reservation
+        machine.accesses.write(100);
+        let broken = snapshot(&Arm, &machine, 0, 0);
+        assert_ne!(broken, reserved);
+        // Only whether it still holds matters, not how many stores there were
+        machine.accesses.write(100);
+        assert_eq!(snapshot(&Arm, &machine, 0, 0), broken);
+        machine.exclusive = None;
+        machine.tasks.create();
+        assert_ne!(snapshot(&Arm, &machine, 0, 0), free);
+    }
+
+    #[test]
+    fn test_post_mortem() {
+        let script = "MOV r13, #1020\nMOV r2, #9\nSTR r2, [r13]\nMOV r1, #5000\nLDR r0, [r1]";
+        let sources = [("main.s".to_string(), script.to_string())];
+        let run = |options: &RunOptions| {
+            let mut out = Vec::new();
+            let errors = run_sources(&Arm, &sources, options, &mut Console::new(&mut io::empty(), &mut out)).err().unwrap();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+            assert_eq!(String::from_utf8(out).unwrap(), "", "nothing goes to stdout");
+            errors[0].notes.last().map(|note| format!("{}\n", note)).unwrap_or_default()
+        };
+        let out = run(&RunOptions { post_mortem: true, ..RunOptions::default() });
+        let human = run_sources(&Arm, &sources, &RunOptions { post_mortem: true, ..RunOptions::default() },
+                                &mut Console::new(&mut io::empty(), &mut Vec::new())).err().unwrap()[0].to_human();
+        assert!(human.starts_with("error[E"), "the diagnostic comes first: {}", human);
+        assert!(human.contains("= note: Post-mortem:\n"), "{}", human);
+        assert!(out.starts_with("Post-mortem:\n   r0 = 0            r1 = 5000         r2 = 9"), "{}", out);
+        assert!(out.contains("  Top of the stack (r13 = 1020):\n    [1020] = 9\n    [1021] = 0\n"), "{}", out);
+        assert!(out.ends_with("  Last instructions run, oldest first:\n\
+            \x20   step     1  main.s:1  MOV r13, #1020  -> r13 = 1020\n\
+            \x20   step     2  main.s:2  MOV r2, #9  -> r2 = 9\n\
+            \x20   step     3  main.s:3  STR r2, [r13]\n\
+            \x20   step     4  main.s:4  MOV r1, #5000  -> r1 = 5000\n\
+            \x20   step     5  main.s:5  LDR r0, [r1]\n"), "{}", out);
+        assert!(!run(&RunOptions::default()).contains("Post-mortem"));
+    }
+
+    #[test]
+    fn test_repl_recent() {
+        let mut input = "MOV r0, #2\nMUL r1, r0, r0\nPRINT r1\nRECENT 2\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> > > r1 = 4\n\
+            > \x20   step     2  <stdin>:2  MUL r1, r0, r0  -> r1 = 4\n\
+            \x20   step     3  <stdin>:3  PRINT r1\n\
+            > ");
+        let errors = RefCell::new(Vec::new());
+        assert_eq!(recent_count("0", &Reporter::new(&errors, "<stdin>", 1, "RECENT 0")), None);
+        assert_eq!(errors.borrow()[0].code, Code::InvalidOperand);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let script = "counter: #0\nMOV r1, #3\nloop: LDR r0, counter\nADD r0, r0, #1\nSTR r0, counter\nSUB r1, r1, #1\nCMP r1, #0\nBNE loop";
+        let sources = [("main.s".to_string(), script.to_string())];
+        let options = RunOptions { heatmap: Some("-".to_string()), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let machine = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!((machine.accesses.reads(0), machine.accesses.writes(0)), (3, 3));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Memory heatmap: 1 words accessed, 3 reads, 3 writes"), "{}", out);
+        assert!(out.ends_with("Most accessed words:\n  [0] (counter): 3 reads, 3 writes\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_cache() {
+        // Sums an array of 8 words twice; with 16-byte lines the first pass misses every 4th word
+        let script = "array: #1\na2: #2\na3: #3\na4: #4\na5: #5\na6: #6\na7: #7\na8: #8\n\
+            MOV r2, #2\nouter: MOV r1, #0\ninner: LDR r0, [r1]\nADD r3, r3, r0\nADD r1, r1, #1\nCMP r1, #8\nBNE inner\n\
+            SUB r2, r2, #1\nCMP r2, #0\nBNE outer\nSTR r3, array";
+        let sources = [("sum.s".to_string(), script.to_string())];
+        let options = RunOptions { cache: Some("1k,direct,16B".parse().unwrap()), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let machine = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(machine.registers[3], 72);
+        assert_eq!(String::from_utf8(out).unwrap(), "Cache: 1024 bytes, direct-mapped, 16-byte lines, 64 sets\n\
+            \x20 17 accesses: 15 hits, 2 misses (88.2% hits)\n\
+            \x20 sum.s:11  LDR r0, [r1]  14 hits, 2 misses (87.5% hits)\n\
+            \x20 sum.s:19  STR r3, array  1 hits, 0 misses (100.0% hits)\n");
+    }
+
+    #[test]
+    fn test_repl_radix() {
+        let mut input = "MOV r0, #-2\nMOV r7, #5\nSET radix hex\nSET r7 bin\nPRINT r0\nPRINT r7\nRECENT 3\n\
+            SET r7 default\nHISTORY r7\nSET r7 oct\nSET pc hex\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("> r0 = 0xFFFFFFFE\n> r7 = 0b101\n"), "{}", out);
+        assert!(out.contains("<stdin>:2  MOV r7, #5  -> r7 = 0b101\n"), "{}", out);
+        assert!(out.ends_with("<stdin>:2  r7 = 0x5\n> > > "), "{}", out);
+
+        let mut formats = Formats::default();
+        formats.set(&"r1=hex".parse().unwrap());
+        let options = RunOptions { formats, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), "MOV r1, #255\nPRINT r1\nPRINT r2".to_string())];
+        let mut out = Vec::new();
+        run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "r1 = 0xFF\nr2 = 0\n");
+    }
+
+    #[test]
+    fn test_repl_conversions() {
+        let mut input = "HEX 255\nbin 0xFF\nDEC 0b1010\nTWOS -5\nHEX 1z\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> 255 = 0xFF\n> 0xFF = 0b11111111\n> 0b1010 = 10\n\
+            > -5 in 32 bits: 1111 1111 1111 1111 1111 1111 1111 1011 (0xFFFFFFFB)\n\
+            \x20 = ~5 + 1: invert 0000 0000 0000 0000 0000 0000 0000 0101 and add 1\n> > ");
+    }
+
+    #[test]
+    fn test_repl_encode() {
+        let mut input = "ENCODE ADDS r1, r2, r3\nENCODE MOV r0, #257\nENCODE\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("ADDS r1, r2, r3 = 0xE0921003\n+-------+"), "{}", out);
+        assert!(out.contains("| 0010  | 0001  | 000000000011 |\n"), "{}", out);
+        assert!(out.contains("  S        sets the flags\n"), "{}", out);
+        assert!(out.contains("  operand2 r3, not shifted\n"), "{}", out);
+        // Neither the unencodable immediate nor the missing instruction prints anything
+        assert!(out.ends_with("r3, not shifted\n> > > "), "{}", out);
+    }
+
+    #[test]
+    fn test_repl_loops() {
+        let mut input = "MOV r0, #3\nloop: ADD r1, r1, r0\nSUBS r0, r0, #1\nBNE loop\nPRINT r1\ncount: #0\nLDR r2, count\n\
+            loop: MOV r0, #1\nB nowhere\nBL twice\nMOV r3, r1\nEXIT\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        // The code label takes no memory word, so the data label gets the first
+        assert_eq!(String::from_utf8(out).unwrap(), "> > Label 'loop' defined at instruction 1\n> > > r1 = 6\n\
+            > Label 'count' defined at memory address 0, initialized with value 0\n> > > > > > ");
+
+        let (mut input, mut out) = ("MOV r1, #0\nloop: ADD r1, r1, #1\nCMP r1, #5\nBLT loop\nHISTORY r1\n".as_bytes(), Vec::new());
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert!(String::from_utf8(out).unwrap().ends_with("step    14  <stdin>:2  r1 = 5\n> "));
+    }
+
+    #[test]
+    fn test_repl_return_and_step_limit() {
+        // A return with no caller ends the line instead of running the first instruction again
+        let (mut input, mut out) = ("BX lr\nMOV r0, #4\nPRINT r0\n".as_bytes(), Vec::new());
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> > > r0 = 4\n> ");
+
+        let options = RunOptions { max_steps: Some(100), ..RunOptions::default() };
+        let (mut input, mut out) = ("loop: ADD r0, r0, #1\nB loop\nPRINT r0\n".as_bytes(), Vec::new());
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &options);
+        // The ADD ran once as it was entered, then 50 times in the 100 steps of the loop
+        assert_eq!(String::from_utf8(out).unwrap(), "> Label 'loop' defined at instruction 0\n> > r0 = 51\n> ");
+    }
+
+    #[test]
+    fn test_repl_blocks() {
+        // A branch in a block can go forward, and the block runs once END is typed
+        let mut input = "MOV r0, #4\nBEGIN\nloop: SUBS r0, r0, #1\nBEQ done\nADD r1, r1, #2\nB loop\ndone: PRINT r1\nEND\n\
+            BEGIN\nMOV r2, #1\nB nowhere\n\nPRINT r2\nBEGIN\nMOV r3, #9\n\nBNE loop\nPRINT r3\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        // Nothing of the block with an undefined label runs, and a blank line also ends a block
+        assert_eq!(String::from_utf8(out).unwrap(), "> > ... ... ... ... ... ... \
+            Label 'loop' defined at instruction 1\nLabel 'done' defined at instruction 5\nr1 = 6\n> ... ... ... > r2 = 0\n> ... ... > > r3 = 9\n> ");
+    }
+
+    #[test]
+    fn test_repl_snippets() {
+        // A snippet's labels are its own, so it runs again without a duplicate label
+        let mut input = "DEF double\nloop: ADD r1, r1, r1\nSUBS r0, r0, #1\nBNE loop\nEND\nMOV r0, #2\nMOV r1, #1\nCALL double\n\
+            MOV r0, #1\nCALL double\nPRINT r1\nn: #1\nDEF bad\nm: #2\nEND\nCALL bad\nCALL nothing\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> ... ... ... ... Snippet 'double' defined\n> > > > > > r1 = 8\n\
+            > Label 'n' defined at memory address 0, initialized with value 1\n> ... ... > > > ");
+
+        let options = RunOptions { snippets: Some(std::env::temp_dir().join(format!("asm-repl-snippets-{}.s", std::process::id()))), ..RunOptions::default() };
+        let (mut input, mut out) = ("DEF seven\nMOV r2, #7\nEND\n".as_bytes(), Vec::new());
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &options);
+        let (mut input, mut out) = ("CALL seven\nPRINT r2\n".as_bytes(), Vec::new());
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &options);
+        fs::remove_file(options.snippets.as_ref().unwrap()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "> > r2 = 7\n> ");
+    }
+
+    #[test]
+    fn test_repl_prompt() {
+        let options = RunOptions { prompt: "[pc={pc} Z={Z} r0={r0} n={n}]> ".parse().unwrap(), ..RunOptions::default() };
+        let mut input = "MOV r0, #5\nn: #3\nSET r0 hex\nSUBS r0, r0, #5\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &options);
+        assert_eq!(String::from_utf8(out).unwrap(), "[pc=0 Z=0 r0=0 n=?]> [pc=1 Z=0 r0=5 n=?]> \
+            Label 'n' defined at memory address 0, initialized with value 3\n\
+            [pc=1 Z=0 r0=5 n=3]> [pc=1 Z=0 r0=0x5 n=3]> [pc=2 Z=1 r0=0x0 n=3]> ");
+    }
+
+    #[test]
+    fn test_repl_echo() {
+        let mut input = "count: #0\nSET echo on\nMOV r1, #6\nSUBS r0, r1, #6\nSTR r1, count\nCMP r1, #6\nSET echo off\nMOV r1, #7\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> Label 'count' defined at memory address 0, initialized with value 0\n\
+            > > -> r1 = 6\n> -> Z = 1, C = 1\n> -> count = 6\n> > > > ");
+    }
+
+    #[test]
+    fn test_repl_output() {
+        let mut input = "MOV r0, #2\nvalue: #7\nPRINT r0\nHISTORY r0\nEXIT\n".as_bytes();
+        let mut out = Vec::new();
+        repl(&Arm, &mut Console::new(&mut input, &mut out), &RunOptions::default());
+        assert_eq!(String::from_utf8(out).unwrap(), "> > Label 'value' defined at memory address 0, initialized with value 7\n\
+            > r0 = 2\n\
+            > Last writes to r0 (oldest first):\n  step     1  <stdin>:1  r0 = 2\n\
+            > ");
+    }
+
+    #[test]
+    fn test_register_history() {
         let script = "\
-            \n\
-            // Comment after empty line\n\
-            MOV r5, #55\n\
-            \n\
-            PRINT r5 // Expect 55\n\
-            // Comment at end of file\n\
-            EXIT\n";
-        run_test_script(script);
+            MOV r5, #3\n\
+            loop: SUB r5, r5, #1\n\
+            CMP r5, #0\n\
+            BNE loop\n\
+            MOV r5, #0\n\
+            EXIT";
+        let machine = run_test_sources_with(&[("main.s", script)], &RunOptions::default());
+        // Only changes are recorded, so the final MOV of the same value is not
+        let writes: Vec<(i64, u64, usize)> = machine.history.writes(5).map(|write| (write.value, write.step, write.line)).collect();
+        assert_eq!(writes, vec![(3, 1, 1), (2, 2, 2), (1, 5, 2), (0, 8, 2)]);
+        assert_eq!(machine.history.writes(0).count(), 0);
     }
-}
\ No newline at end of file
+}
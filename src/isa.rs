@@ -0,0 +1,432 @@
+//! Instruction set front-ends.
+//!
+//! Every instruction set is a self-contained module implementing [`Isa`]: how an
+//! instruction line splits into tokens, how the tokens decode into an instruction,
+//! how an instruction executes and what the register file looks like. Loading and
+//! linking source files, labels, entry points, the run loop and interactive mode
+//! are shared (see `interpreter`), and `--isa` selects one of the front-ends
+//! registered in [`FRONT_ENDS`].
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::a64::A64;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::encoding::Encoding;
+use crate::instructions::{self, InstructionInfo};
+use crate::interpreter::{self, Arm, Ended, FinalState, Machine, RunOptions};
+use crate::lmc::Lmc;
+use crate::rv32i::Rv32i;
+use crate::taint::DataFlow;
+use crate::thumb::Thumb;
+use crate::x86::X86;
+
+/// Every available front-end, selectable by name with `--isa`. The first is the default.
+pub static FRONT_ENDS: &[&dyn FrontEnd] = &[&Arm, &Thumb, &A64, &Rv32i, &X86, &Lmc];
+
+/// Finds a registered front-end by name.
+pub fn front_end(name: &str) -> Option<&'static dyn FrontEnd> {
+    FRONT_ENDS.iter().copied().find(|front_end| front_end.name() == name)
+}
+
+/// What runs after an instruction.
+pub enum Flow {
+    Next,
+    // Continue at this instruction index
+    Jump(usize),
+    // A breakpoint was hit; continue with the next instruction once it is handled
+    Breakpoint,
+    Exit,
+}
+
+/// The general-purpose registers of an instruction set.
+pub struct RegisterFile {
+    /// Register names, in index order
+    pub names: &'static [&'static str],
+    /// Width of each register in bits
+    pub bits: u32,
+}
+
+// The operators of label arithmetic, such as `array + 8`
+const ARITHMETIC: [char; 4] = ['+', '-', '*', '/'];
+
+/// An instruction line split into its mnemonic and operands. All tokens are
+/// slices of the line, so diagnostics can point at them.
+pub struct Tokens<'a> {
+    pub mnemonic: &'a str,
+    pub operands: Vec<&'a str>,
+}
+
+impl<'a> Tokens<'a> {
+    /// Splits a line at the first whitespace into the mnemonic and its operands, and
+    /// the operands at commas outside of brackets and character immediates such as `#','`.
+    pub fn split(line: &'a str) -> Self {
+        let (mnemonic, rest) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        let mut operands = Vec::new();
+        if !rest.is_empty() {
+            let mut depth = 0;
+            let mut start = 0;
+            let mut quoted = false;
+            let mut escaped = false;
+            for (i, ch) in rest.char_indices() {
+                match ch {
+                    _ if escaped => escaped = false,
+                    '\\' if quoted => escaped = true,
+                    '\'' => quoted = !quoted,
+                    _ if quoted => {},
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    ',' if depth == 0 => {
+                        operands.push(rest[start..i].trim());
+                        start = i + 1;
+                    },
+                    _ => {},
+                }
+            }
+            operands.push(rest[start..].trim());
+        }
+        Tokens { mnemonic, operands }
+    }
+
+    /// Checks that the operands are separated by commas and that there are `count`
+    /// of them, returning them. `syntax` is shown as a usage note on a wrong count.
+    pub fn expect(&self, count: usize, syntax: Option<&str>, report: &Reporter) -> Option<Vec<&'a str>> {
+        self.check(count, syntax).map_err(|(token, error)| report.report(token, *error)).ok()
+    }
+
+    /// Like [`Tokens::expect`] for an instruction documented by `info`: a wrong count
+    /// also shows its example, and an error reported later about one of the operands
+    /// says which operand it is and what goes there.
+    pub fn expect_instruction(&self, count: usize, info: Option<&InstructionInfo>, report: &Reporter) -> Option<Vec<&'a str>> {
+        let Some(info) = info else {
+            return self.expect(count, None, report);
+        };
+        let operands = match self.check(count, Some(info.syntax)) {
+            Ok(operands) => operands,
+            Err((token, error)) => {
+                let error = match error.code {
+                    Code::WrongOperandCount => error.with_note(format!("for example: {}", info.example)),
+                    _ => *error,
+                };
+                report.report(token, error);
+                return None;
+            },
+        };
+        // The first form of the syntax, if its operands can be matched up one for one
+        let signature = info.syntax.split(" | ").next().and_then(|form| form.split(" or ").next()).unwrap_or(info.syntax);
+        let placeholders = Tokens::split(signature).operands;
+        let optional = placeholders.iter().any(|placeholder| placeholder.contains(',') || placeholder.chars().skip(1).any(|c| c == '['));
+        let described: Vec<(&str, String)> = operands.iter().enumerate().map(|(index, &operand)| {
+            let ordinal = ORDINALS.get(index).map_or_else(|| format!("{}th", index + 1), |ordinal| ordinal.to_string());
+            let what = match placeholders.get(index) {
+                Some(placeholder) if !optional && placeholders.len() == operands.len() => format!("the {} operand, {} in {}", ordinal, placeholder, signature),
+                _ => format!("the {} operand of {}", ordinal, self.mnemonic.to_uppercase()),
+            };
+            (operand, what)
+        }).collect();
+        report.describe_operands(&described, info.example);
+        Some(operands)
+    }
+
+    // The operands if there are `count` of them, separated by commas, or the error and the token it is about
+    fn check(&self, count: usize, syntax: Option<&str>) -> Result<Vec<&'a str>, (&'a str, Box<Diagnostic>)> {
+        // "ADD r0 r1, #1" has operands separated by spaces only, unlike label arithmetic such as "#(end - start)" or "#' '"
+        for operand in &self.operands {
+            let words: Vec<&str> = operand.split_whitespace().collect();
+            let arithmetic = words.windows(2).all(|pair| pair[0].ends_with(ARITHMETIC) || pair[1].starts_with(ARITHMETIC));
+            if operand.contains(['[', '(', '\'']) || arithmetic {
+                continue;
+            }
+            if let Some(first) = operand.split_whitespace().next().filter(|first| first != operand) {
+                return Err((first, Box::new(Diagnostic::error(Code::MissingComma,
+                    format!("Syntax error: Missing comma after {} in {}", first, self.mnemonic.to_uppercase())))));
+            }
+        }
+        if self.operands.len() != count {
+            let mut error = Diagnostic::error(Code::WrongOperandCount,
+                format!("Wrong number of operands for {}", self.mnemonic.to_uppercase()));
+            if let Some(syntax) = syntax {
+                error = error.with_note(format!("usage: {}", syntax));
+            }
+            return Err((self.mnemonic, Box::new(error)));
+        }
+        Ok(self.operands.clone())
+    }
+}
+
+const ORDINALS: [&str; 6] = ["first", "second", "third", "fourth", "fifth", "sixth"];
+
+/// The labels an instruction may refer to.
+pub struct Labels<'a> {
+    /// The memory address of every label
+    pub addresses: &'a HashMap<String, usize>,
+    /// The instruction index of every label in front of code. Empty in interactive mode.
+    pub code: &'a HashMap<String, usize>,
+    /// Where constants loaded with `LDR rd, =value` are placed
+    pub pool: &'a LiteralPool,
+}
+
+/// The literal pool of `LDR rd, =value`: words in memory after the data, one for
+/// each distinct value, which the loader fills in once every instruction is decoded.
+pub struct LiteralPool {
+    start: usize,
+    // The words of memory, which the pool may not go past
+    end: usize,
+    words: RefCell<Vec<i32>>,
+}
+
+impl LiteralPool {
+    pub fn new(start: usize, memory_words: usize) -> Self {
+        LiteralPool { start, end: memory_words, words: RefCell::new(Vec::new()) }
+    }
+
+    /// The words of the memory the pool is in, past which no address is.
+    pub fn memory_words(&self) -> usize {
+        self.end
+    }
+
+    /// The address of the word holding `value`, placing it if it is new, or `None` once memory is full.
+    pub fn place(&self, value: i32) -> Option<usize> {
+        let mut words = self.words.borrow_mut();
+        let index = match words.iter().position(|&word| word == value) {
+            Some(index) => index,
+            None if self.start + words.len() < self.end => {
+                words.push(value);
+                words.len() - 1
+            },
+            None => return None,
+        };
+        Some(self.start + index)
+    }
+
+    /// The words placed and their addresses.
+    pub fn words(&self) -> Vec<(usize, i32)> {
+        self.words.borrow().iter().enumerate().map(|(index, &word)| (self.start + index, word)).collect()
+    }
+}
+
+/// An instruction set: its syntax, semantics and registers.
+pub trait Isa {
+    /// An instruction with its operands checked and parsed
+    type Instruction;
+    /// The registers and flags of a core, together with its memory and labels
+    type Cpu: AsRef<Machine> + AsMut<Machine>;
+
+    /// The name selected with `--isa`
+    const NAME: &'static str;
+    /// A one-line description for `--help`
+    const DESCRIPTION: &'static str;
+    /// Reference information for usage notes and editor help
+    const INSTRUCTIONS: &'static [InstructionInfo];
+    const REGISTERS: &'static RegisterFile;
+    /// The index of the stack pointer in [`Isa::REGISTERS`], if there is one
+    const STACK_POINTER: Option<usize> = None;
+    /// The register each core finds its number in with `--cores`, such as the first argument register
+    const CORE_REGISTER: usize;
+
+    /// A core with every register in its reset state.
+    fn new_cpu(&self) -> Self::Cpu;
+
+    /// The value of the register at `index` in [`Isa::REGISTERS`].
+    fn read_register(&self, cpu: &Self::Cpu, index: usize) -> i64;
+
+    /// The condition flags of a core and whether each is set, if the instruction set has any.
+    fn flags(&self, _cpu: &Self::Cpu) -> Vec<(&'static str, bool)> {
+        Vec::new()
+    }
+
+    /// Applies `--set` values. Register indices follow [`Isa::REGISTERS`].
+    fn seed_registers(&self, cpu: &mut Self::Cpu, registers: &[(usize, i32)]);
+
+    /// The index in [`Isa::REGISTERS`] of a register named on the command line, as with `--set`.
+    /// The default takes the names of the register file; front-ends add their aliases.
+    fn register_index(&self, name: &str) -> Option<usize> {
+        Self::REGISTERS.names.iter().position(|register| register.eq_ignore_ascii_case(name))
+    }
+
+    /// Prepares a core to run a loaded program of `len` instructions, e.g. by pointing
+    /// the return address past its end.
+    fn prepare(&self, _cpu: &mut Self::Cpu, _len: usize) {}
+
+    /// Splits a line (comment removed) into the label it defines and the rest, if it
+    /// defines one. The default is a label followed by a colon, as in `loop: ADD r0, r0, #1`.
+    fn split_label<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        interpreter::split_label(line)
+    }
+
+    /// Splits an instruction line (labels and comments removed) into tokens.
+    fn tokenize<'a>(&self, line: &'a str) -> Tokens<'a> {
+        Tokens::split(line)
+    }
+
+    /// Decodes the tokens of one instruction, reporting any errors.
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Self::Instruction>;
+
+    /// Places a loaded program in the memory of a core, for instruction sets whose
+    /// programs live there, given the instruction index of each code label.
+    fn place(&self, _cpu: &mut Self::Cpu, _instructions: &[Self::Instruction], _code_labels: &HashMap<String, usize>) -> Result<(), Box<Diagnostic>> {
+        Ok(())
+    }
+
+    /// Executes an instruction found at index `pc` of the program, printing and reading through `console`.
+    fn execute(&self, cpu: &mut Self::Cpu, instruction: &Self::Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>>;
+
+    /// Whether the instruction transfers control, which needs a loaded program.
+    fn is_branch(&self, _instruction: &Self::Instruction) -> bool {
+        false
+    }
+
+    /// The offset of the memory access the instruction makes relative to the frame
+    /// pointer, if it makes one, so `.local` can name it.
+    fn frame_offset(&self, _instruction: &Self::Instruction) -> Option<i32> {
+        None
+    }
+
+    /// Whether the instruction calls a function, saving a return address.
+    fn is_call(&self, _instruction: &Self::Instruction) -> bool {
+        false
+    }
+
+    /// The function the instruction calls, if it calls one named by a label.
+    fn call_target(&self, _instruction: &Self::Instruction) -> Option<usize> {
+        None
+    }
+
+    /// Whether the instruction returns from a function.
+    fn is_return(&self, _instruction: &Self::Instruction) -> bool {
+        false
+    }
+
+    /// The reference entry a mnemonic is documented under, such as ADD for ADDS.
+    fn documented(&self, mnemonic: &str) -> Option<&'static InstructionInfo> {
+        instructions::lookup_in(Self::INSTRUCTIONS, mnemonic)
+    }
+
+    /// How many cycles the instruction takes, for `.budget cycles`; `taken` if it
+    /// branched. The default is one, and two more to refill the pipeline after a branch.
+    fn cycles(&self, _instruction: &Self::Instruction, taken: bool) -> u64 {
+        if taken { 3 } else { 1 }
+    }
+
+    /// What the instruction reads and writes in the state of `cpu`, for `--taint`,
+    /// if the front-end tracks taint.
+    fn data_flow(&self, _cpu: &Self::Cpu, _instruction: &Self::Instruction) -> Option<DataFlow> {
+        None
+    }
+
+    /// What the trace should say about an instruction whose effect the values it
+    /// changes do not show, such as a barrier.
+    fn remark(&self, _instruction: &Self::Instruction) -> Option<&'static str> {
+        None
+    }
+
+    /// Why an immediate of the instruction does not fit its field in a real encoding,
+    /// if one does not, for `--strict-imm`.
+    fn check_immediates(&self, _instruction: &Self::Instruction) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Why a real assembler would reject the operands of the instruction other than
+    /// its immediates, if it would, for `--profile strict`.
+    fn check_operands(&self, _instruction: &Self::Instruction) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Why an immediate of the instruction does not fit its field, or a word of
+    /// `word_bits` bits under `--word-size`, and is cut down when it runs, if one
+    /// does not, for the warnings of `--strict`.
+    fn truncation(&self, _instruction: &Self::Instruction, _word_bits: u32) -> Option<String> {
+        None
+    }
+
+    /// Why the instruction leaves every register, flag and memory word as it was,
+    /// if it does, such as a move of a register to itself, for `--lint`.
+    fn no_effect(&self, _instruction: &Self::Instruction) -> Option<&'static str> {
+        None
+    }
+
+    /// Makes the core compute with words of `bits` bits, cutting its registers and memory
+    /// down to them, for `--word-size`. Front-ends that only have their own width return false.
+    fn narrow(&self, _cpu: &mut Self::Cpu, _bits: u32) -> bool {
+        false
+    }
+
+    /// The machine code of the instruction for `ENCODE`, or why it has none here.
+    fn encode(&self, _instruction: &Self::Instruction) -> Result<Encoding, String> {
+        Err(format!("ENCODE shows 32-bit ARM encodings, which --isa {} does not use", Self::NAME))
+    }
+}
+
+/// An [`Isa`] with its types erased, so front-ends can be registered and selected by name.
+pub trait FrontEnd: Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn instructions(&self) -> &'static [InstructionInfo];
+    fn registers(&self) -> &'static RegisterFile;
+    /// The index of a register named on the command line, or None if the front-end has no such register.
+    fn register_index(&self, name: &str) -> Option<usize>;
+    /// Links and runs `(file name, contents)` pairs on `console`. Returns the final
+    /// state or the errors that stopped them.
+    fn run_sources(&self, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<FinalState, Vec<Diagnostic>>;
+    /// Like `run_sources`, but also returns the final state when the program stops with an
+    /// error, such as at the step limit. Only errors before the program starts give no state.
+    fn run_sources_keeping_state(&self, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<Ended<FinalState>, Vec<Diagnostic>>;
+    /// Checks `(file name, contents)` pairs without running them.
+    fn check_sources(&self, sources: &[(String, String)]) -> Vec<Diagnostic>;
+    /// Runs lines read from `console` one at a time, printing a prompt before each.
+    fn interactive(&self, console: &mut Console, options: &RunOptions);
+    /// Registers that hold where something is in a program rather than a result:
+    /// the stack pointer and those the front-end prepares, such as the return address.
+    fn layout_registers(&self) -> Vec<usize>;
+}
+
+impl<I: Isa + Sync> FrontEnd for I {
+    fn name(&self) -> &'static str {
+        I::NAME
+    }
+
+    fn description(&self) -> &'static str {
+        I::DESCRIPTION
+    }
+
+    fn instructions(&self) -> &'static [InstructionInfo] {
+        I::INSTRUCTIONS
+    }
+
+    fn registers(&self) -> &'static RegisterFile {
+        I::REGISTERS
+    }
+
+    fn register_index(&self, name: &str) -> Option<usize> {
+        Isa::register_index(self, name)
+    }
+
+    fn run_sources(&self, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<FinalState, Vec<Diagnostic>> {
+        interpreter::run_sources(self, sources, options, console).map(|cpu| FinalState::of(self, &cpu))
+    }
+
+    fn run_sources_keeping_state(&self, sources: &[(String, String)], options: &RunOptions, console: &mut Console) -> Result<Ended<FinalState>, Vec<Diagnostic>> {
+        interpreter::run_sources_keeping_state(self, sources, options, console).map(|(cpu, result)| (FinalState::of(self, &cpu), result))
+    }
+
+    fn check_sources(&self, sources: &[(String, String)]) -> Vec<Diagnostic> {
+        interpreter::check_sources(self, sources)
+    }
+
+    fn interactive(&self, console: &mut Console, options: &RunOptions) {
+        interpreter::repl(self, console, options);
+    }
+
+    fn layout_registers(&self) -> Vec<usize> {
+        // What the front-end prepares depends on the length of the program
+        let (mut empty, mut longer) = (self.new_cpu(), self.new_cpu());
+        self.prepare(&mut empty, 0);
+        self.prepare(&mut longer, 1);
+        (0..I::REGISTERS.names.len())
+            .filter(|&reg| I::STACK_POINTER == Some(reg) || self.read_register(&empty, reg) != self.read_register(&longer, reg))
+            .collect()
+    }
+}
@@ -0,0 +1,639 @@
+//! A Jupyter kernel (`asm jupyter CONNECTION_FILE`), so notebooks can have
+//! assembly cells, e.g. lecture notes whose programs run.
+//!
+//! Each cell is a program of its own, run like a file. Its output is streamed to
+//! the notebook as it is printed, and once it ends the cell shows the registers
+//! and flags as a table, followed by the memory words that are not 0 with their
+//! labels. A cell that stops with an error shows the error as the command line
+//! does, and then the state it stopped in. A cell runs for at most [`MAX_STEPS`]
+//! instructions unless `--max-steps` says otherwise.
+//!
+//! Jupyter talks to a kernel over ZeroMQ sockets, whose wire protocol (ZMTP 3.0
+//! with the NULL mechanism) is spoken here directly on TCP, with messages signed
+//! with HMAC-SHA256 as the connection file asks. Signatures are checked in
+//! constant time, so a client cannot learn a valid one byte by byte.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::{FinalState, Interrupt, RunOptions};
+use crate::isa::FrontEnd;
+use crate::transcript;
+
+/// Instructions a cell may run unless `--max-steps` says otherwise, so a cell that
+/// never stops gives the notebook back.
+pub const MAX_STEPS: u64 = 10_000_000;
+
+// The version of the Jupyter messaging protocol spoken
+const PROTOCOL_VERSION: &str = "5.3";
+// Separates the routing identities of a message from its parts
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// Runs the kernel on the ports of the connection file until Jupyter shuts it
+/// down. Cells run on `front_end` for at most `max_steps` instructions each, and
+/// `interrupt` stops the running one.
+pub fn run(front_end: &'static dyn FrontEnd, connection_file: &Path, max_steps: u64, interrupt: Interrupt) -> Result<(), Box<Diagnostic>> {
+    let invalid = |message: String| Box::new(Diagnostic::error(Code::InputFailed,
+        format!("Invalid connection file {}: {}", connection_file.display(), message)));
+    let text = fs::read_to_string(connection_file).map_err(|error| invalid(error.to_string()))?;
+    let connection: Value = serde_json::from_str(&text).map_err(|error| invalid(error.to_string()))?;
+    if !matches!(connection["signature_scheme"].as_str(), Some("hmac-sha256") | Some("") | None) {
+        return Err(invalid(format!("the signature scheme {} is not supported, only hmac-sha256", connection["signature_scheme"])));
+    }
+    if connection["transport"].as_str().is_some_and(|transport| transport != "tcp") {
+        return Err(invalid(format!("the transport {} is not supported, only tcp", connection["transport"])));
+    }
+    let ip = connection["ip"].as_str().unwrap_or("127.0.0.1");
+    let listen = |name: &str| {
+        let port = connection[format!("{}_port", name)].as_u64().ok_or_else(|| invalid(format!("expected a port in '{}_port'", name)))?;
+        TcpListener::bind((ip, port as u16)).map_err(|error| Box::new(Diagnostic::error(Code::UnsupportedOption,
+            format!("Cannot listen on {}:{} for the {} socket: {}", ip, port, name, error))))
+    };
+    let (shell, control, iopub, stdin, heartbeat) = (listen("shell")?, listen("control")?, listen("iopub")?, listen("stdin")?, listen("hb")?);
+    let kernel = Arc::new(Kernel::new(front_end, connection["key"].as_str().unwrap_or("").as_bytes(), max_steps, interrupt));
+    let (shut_down, shutdown) = mpsc::channel();
+
+    for listener in [shell, control] {
+        let (kernel, shut_down) = (kernel.clone(), shut_down.clone());
+        accept(listener, "ROUTER", move |stream| {
+            kernel.serve(&mut BufReader::new(stream.try_clone()?), &mut &stream, &shut_down)
+        });
+    }
+    let subscribers = kernel.clone();
+    accept(iopub, "PUB", move |stream| {
+        // Subscriptions are not needed: notebooks subscribe to everything
+        let mut reader = stream.try_clone()?;
+        subscribers.subscribers.lock().expect("no thread panics holding the subscribers").push(Box::new(stream));
+        io::copy(&mut reader, &mut io::sink()).map(|_| ())
+    });
+    // The kernel never asks for input, since programs read from an empty stdin
+    accept(stdin, "ROUTER", |stream| io::copy(&mut BufReader::new(stream), &mut io::sink()).map(|_| ()));
+    accept(heartbeat, "REP", |stream| {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        while let Some(frames) = read_message(&mut reader)? {
+            write_message(&mut writer, &frames)?;
+        }
+        Ok(())
+    });
+    let _ = shutdown.recv();
+    Ok(())
+}
+
+// Accepts connections to `listener` as a ZeroMQ socket of `socket_type`, serving each on its own thread
+fn accept(listener: TcpListener, socket_type: &'static str, serve: impl Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static) {
+    let serve = Arc::new(serve);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let serve = serve.clone();
+            thread::spawn(move || {
+                // A client that goes away is no concern of the kernel
+                let _ = handshake(&mut stream, socket_type).and_then(|_| serve(stream));
+            });
+        }
+    });
+}
+
+// A message from the notebook
+struct Message {
+    identities: Vec<Vec<u8>>,
+    header: Value,
+    content: Value,
+}
+
+impl Message {
+    fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or("")
+    }
+}
+
+struct Kernel {
+    front_end: &'static dyn FrontEnd,
+    key: Vec<u8>,
+    session: String,
+    max_steps: u64,
+    interrupt: Interrupt,
+    executions: AtomicU64,
+    messages: AtomicU64,
+    // The connections to the iopub socket
+    subscribers: Mutex<Vec<Box<dyn Write + Send>>>,
+}
+
+impl Kernel {
+    fn new(front_end: &'static dyn FrontEnd, key: &[u8], max_steps: u64, interrupt: Interrupt) -> Self {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+        Kernel {
+            front_end,
+            key: key.to_vec(),
+            session: format!("{:x}-{:x}", std::process::id(), started),
+            max_steps,
+            interrupt,
+            executions: AtomicU64::new(0),
+            messages: AtomicU64::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Answers the requests of a shell or control connection until it closes,
+    // dropping those that are not messages or are not signed with the key
+    fn serve(&self, reader: &mut dyn Read, writer: &mut dyn Write, shut_down: &mpsc::Sender<()>) -> io::Result<()> {
+        while let Some(frames) = read_message(reader)? {
+            let Some(request) = self.parse(frames) else {
+                continue;
+            };
+            if let Some(reply) = self.handle(&request) {
+                write_message(writer, &reply)?;
+            }
+            if request.msg_type() == "shutdown_request" {
+                // The kernel ends once the reply is on its way
+                let _ = shut_down.send(());
+            }
+        }
+        Ok(())
+    }
+
+    // The message in `frames`, or None if it is not one or its signature does not match
+    fn parse(&self, mut frames: Vec<Vec<u8>>) -> Option<Message> {
+        let delimiter = frames.iter().position(|frame| frame == DELIMITER)?;
+        let parts: Vec<Vec<u8>> = frames.drain(delimiter..).skip(1).collect();
+        let [signature, header, parent, metadata, content, ..] = &parts[..] else {
+            return None;
+        };
+        if !self.key.is_empty() && !self.verify(signature, &[header, parent, metadata, content]) {
+            return None;
+        }
+        Some(Message { identities: frames, header: serde_json::from_slice(header).ok()?, content: serde_json::from_slice(content).ok()? })
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> [u8; 32] {
+        hmac_sha256(&self.key, parts).finalize().into_bytes().into()
+    }
+
+    // Whether `signature`, in hex, is that of `parts`, compared in constant time
+    fn verify(&self, signature: &[u8], parts: &[&[u8]]) -> bool {
+        unhex(signature).is_some_and(|signature| hmac_sha256(&self.key, parts).verify_slice(&signature).is_ok())
+    }
+
+    // The frames of a message of `msg_type` in answer to `parent`, routed with `identities`
+    fn message(&self, identities: &[Vec<u8>], parent: &Message, msg_type: &str, content: Value) -> Vec<Vec<u8>> {
+        let header = json!({
+            "msg_id": format!("{}-{}", self.session, self.messages.fetch_add(1, Ordering::Relaxed)),
+            "session": self.session,
+            "username": "kernel",
+            "date": transcript::timestamp(SystemTime::now()),
+            "msg_type": msg_type,
+            "version": PROTOCOL_VERSION,
+        });
+        let parts = [header.to_string(), parent.header.to_string(), "{}".to_string(), content.to_string()].map(String::into_bytes);
+        let signature = if self.key.is_empty() { String::new() } else { hex(&self.sign(&parts.each_ref().map(Vec::as_slice))) };
+        let mut frames = identities.to_vec();
+        frames.push(DELIMITER.to_vec());
+        frames.push(signature.into_bytes());
+        frames.extend(parts);
+        frames
+    }
+
+    // Sends a message to every notebook, dropping the connections that are gone
+    fn publish(&self, parent: &Message, msg_type: &str, content: Value) {
+        let frames = self.message(&[msg_type.as_bytes().to_vec()], parent, msg_type, content);
+        self.subscribers.lock().expect("no thread panics holding the subscribers").retain_mut(|subscriber| write_message(subscriber, &frames).is_ok());
+    }
+
+    // Answers a request on the shell or control socket, telling the notebook the kernel is busy meanwhile
+    fn handle(&self, request: &Message) -> Option<Vec<Vec<u8>>> {
+        self.publish(request, "status", json!({ "execution_state": "busy" }));
+        let reply = match request.msg_type() {
+            "kernel_info_request" => Some(("kernel_info_reply", json!({
+                "status": "ok",
+                "protocol_version": PROTOCOL_VERSION,
+                "implementation": crate::APP_NAME,
+                "implementation_version": env!("CARGO_PKG_VERSION"),
+                "language_info": {
+                    "name": "asm",
+                    "version": self.front_end.name(),
+                    "mimetype": "text/x-asm",
+                    "file_extension": ".s",
+                    "pygments_lexer": "gas",
+                    "codemirror_mode": "gas",
+                },
+                "banner": format!("{} for --isa {}: {}", crate::APP_NAME, self.front_end.name(), self.front_end.description()),
+            }))),
+            "execute_request" => Some(("execute_reply", self.execute(request))),
+            "is_complete_request" => Some(("is_complete_reply", json!({ "status": "complete" }))),
+            "complete_request" => Some(("complete_reply", self.complete(&request.content))),
+            "comm_info_request" => Some(("comm_info_reply", json!({ "status": "ok", "comms": {} }))),
+            "history_request" => Some(("history_reply", json!({ "status": "ok", "history": [] }))),
+            "interrupt_request" => {
+                self.interrupt.request();
+                Some(("interrupt_reply", json!({ "status": "ok" })))
+            },
+            "shutdown_request" => Some(("shutdown_reply", json!({ "status": "ok", "restart": request.content["restart"].as_bool().unwrap_or(false) }))),
+            _ => None,
+        };
+        self.publish(request, "status", json!({ "execution_state": "idle" }));
+        reply.map(|(msg_type, content)| self.message(&request.identities, request, msg_type, content))
+    }
+
+    // Runs a cell, publishing its output and result, and gives the content of the reply
+    fn execute(&self, request: &Message) -> Value {
+        let code = request.content["code"].as_str().unwrap_or("");
+        let silent = request.content["silent"].as_bool().unwrap_or(false);
+        let count = if silent { self.executions.load(Ordering::Relaxed) } else { self.executions.fetch_add(1, Ordering::Relaxed) + 1 };
+        if !silent {
+            self.publish(request, "execute_input", json!({ "code": code, "execution_count": count }));
+        }
+        if code.trim().is_empty() {
+            return json!({ "status": "ok", "execution_count": count, "user_expressions": {}, "payload": [] });
+        }
+        let options = RunOptions { max_steps: Some(self.max_steps), interrupt: self.interrupt.clone(), ..RunOptions::default() };
+        let sources = [(format!("In[{}]", count), code.to_string())];
+        let mut output = Stream { kernel: self, parent: request, pending: Vec::new() };
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.front_end.run_sources_keeping_state(&sources, &options, &mut Console::new(&mut io::empty(), &mut output))
+        }));
+        output.finish();
+        let (state, errors) = match run {
+            Ok(Ok((state, result))) => (Some(state), result.err().unwrap_or_default()),
+            Ok(Err(errors)) => (None, errors),
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str));
+                (None, vec![Diagnostic::error(Code::Interrupted, format!("The program crashed the interpreter: {}", message.unwrap_or("unknown panic")))])
+            },
+        };
+        if let Some(state) = &state {
+            let data = json!({ "text/plain": state_text(state), "text/html": state_html(state) });
+            if errors.is_empty() {
+                self.publish(request, "execute_result", json!({ "execution_count": count, "data": data, "metadata": {} }));
+            } else {
+                self.publish(request, "display_data", json!({ "data": data, "metadata": {} }));
+            }
+        }
+        let Some(first) = errors.first() else {
+            return json!({ "status": "ok", "execution_count": count, "user_expressions": {}, "payload": [] });
+        };
+        let error = json!({
+            "ename": first.code.id(),
+            "evalue": first.message,
+            "traceback": errors.iter().map(Diagnostic::to_human).collect::<Vec<_>>(),
+        });
+        self.publish(request, "error", error.clone());
+        let mut reply = error;
+        reply["status"] = json!("error");
+        reply["execution_count"] = json!(count);
+        reply
+    }
+
+    // The mnemonics that start with the word before the cursor
+    fn complete(&self, content: &Value) -> Value {
+        let code = content["code"].as_str().unwrap_or("");
+        let cursor = code.char_indices().nth(content["cursor_pos"].as_u64().unwrap_or(0) as usize).map_or(code.len(), |(index, _)| index);
+        let start = code[..cursor].rfind(|c: char| !c.is_alphanumeric()).map_or(0, |index| index + 1);
+        let word = code[start..cursor].to_uppercase();
+        let matches: Vec<&str> = self.front_end.instructions().iter().map(|info| info.mnemonic).filter(|mnemonic| !word.is_empty() && mnemonic.starts_with(&word)).collect();
+        json!({
+            "status": "ok",
+            "matches": matches,
+            "cursor_start": code[..start].chars().count(),
+            "cursor_end": code[..cursor].chars().count(),
+            "metadata": {},
+        })
+    }
+}
+
+// Program output, published a line at a time as it is printed
+struct Stream<'a> {
+    kernel: &'a Kernel,
+    parent: &'a Message,
+    // Output after the last complete line
+    pending: Vec<u8>,
+}
+
+impl Stream<'_> {
+    fn send(&mut self, text: &[u8]) {
+        self.kernel.publish(self.parent, "stream", json!({ "name": "stdout", "text": String::from_utf8_lossy(text) }));
+    }
+
+    // Sends what is left of the output
+    fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            let text = std::mem::take(&mut self.pending);
+            self.send(&text);
+        }
+    }
+}
+
+impl Write for Stream<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(bytes);
+        if let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') {
+            let lines: Vec<u8> = self.pending.drain(..=end).collect();
+            self.send(&lines);
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The state as text: the registers four to a line, the flags and the memory words that are not 0
+fn state_text(state: &FinalState) -> String {
+    let mut text: Vec<String> = state.registers.chunks(4)
+        .map(|row| row.iter().map(|(name, value)| format!("{:>3} = {:<11}", name, value)).collect::<Vec<_>>().join(" ").trim_end().to_string()).collect();
+    text.push(state.flags.iter().map(|&(name, set)| format!("{}={}", name, set as u8)).collect::<Vec<_>>().join(" "));
+    text.extend(memory_words(state).into_iter().map(|(address, label, word)| format!("[{}]{} = {}", address, label.map_or(String::new(), |label| format!(" {}", label)), word)));
+    text.join("\n")
+}
+
+// The state as HTML tables of the registers and flags, and of the memory words that are not 0
+fn state_html(state: &FinalState) -> String {
+    let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let mut html = String::from("<table><tr><th>Register</th><th>Value</th><th>Hex</th></tr>");
+    for (name, value) in &state.registers {
+        html += &format!("<tr><td>{}</td><td>{}</td><td><code>0x{:08X}</code></td></tr>", name, value, *value as u32);
+    }
+    html += "</table><p>";
+    html += &state.flags.iter().map(|&(name, set)| format!("<code>{}={}</code>", name, set as u8)).collect::<Vec<_>>().join(" ");
+    html += "</p>";
+    let words = memory_words(state);
+    if !words.is_empty() {
+        html += "<table><tr><th>Address</th><th>Label</th><th>Value</th><th>Hex</th></tr>";
+        for (address, label, word) in words {
+            html += &format!("<tr><td>{}</td><td>{}</td><td>{}</td><td><code>0x{:08X}</code></td></tr>", address, escape(label.unwrap_or("")), word, word as u32);
+        }
+        html += "</table>";
+    }
+    html
+}
+
+// The memory words that are not 0, with the first label at each
+fn memory_words(state: &FinalState) -> Vec<(usize, Option<&str>, i32)> {
+    let mut labels: HashMap<usize, &str> = HashMap::new();
+    let mut named: Vec<(&String, &usize)> = state.labels.iter().collect();
+    named.sort();
+    for (name, &address) in named {
+        labels.entry(address).or_insert(name);
+    }
+    state.memory.words().filter(|&(_, word)| word != 0).map(|(address, word)| (address, labels.get(&address).copied(), word)).collect()
+}
+
+// ZMTP 3.0: a greeting, a READY command each way, then frames of a flags byte, a
+// length and the bytes, with MORE set on all but the last frame of a message
+
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+// Greets the peer and exchanges READY commands, as a socket of `socket_type` with the NULL mechanism
+fn handshake(stream: &mut TcpStream, socket_type: &str) -> io::Result<()> {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xFF;
+    greeting[9] = 0x7F;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xFF || peer[9] != 0x7F || peer[10] < 3 || &peer[12..16] != b"NULL" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ZeroMQ 3 peer with the NULL mechanism"));
+    }
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    write_frame(stream, COMMAND, &ready)?;
+    // The peer's READY, whose properties are not needed
+    read_frame(stream)?;
+    Ok(())
+}
+
+fn write_frame(writer: &mut dyn Write, flags: u8, body: &[u8]) -> io::Result<()> {
+    if body.len() > 255 {
+        writer.write_all(&[flags | LONG])?;
+        writer.write_all(&(body.len() as u64).to_be_bytes())?;
+    } else {
+        writer.write_all(&[flags, body.len() as u8])?;
+    }
+    writer.write_all(body)
+}
+
+// A frame's flags and bytes, or None at the end of the stream
+fn read_frame(reader: &mut dyn Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut flags = [0u8];
+    if reader.read(&mut flags)? == 0 {
+        return Ok(None);
+    }
+    let length = if flags[0] & LONG != 0 {
+        let mut length = [0u8; 8];
+        reader.read_exact(&mut length)?;
+        u64::from_be_bytes(length)
+    } else {
+        let mut length = [0u8];
+        reader.read_exact(&mut length)?;
+        length[0] as u64
+    };
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some((flags[0], body)))
+}
+
+// The frames of the next message, skipping commands, or None at the end of the stream
+fn read_message(reader: &mut dyn Read) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut frames = Vec::new();
+    while let Some((flags, body)) = read_frame(reader)? {
+        if flags & COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & MORE == 0 {
+            return Ok(Some(frames));
+        }
+    }
+    Ok(None)
+}
+
+fn write_message(writer: &mut dyn Write, frames: &[Vec<u8>]) -> io::Result<()> {
+    for (index, frame) in frames.iter().enumerate() {
+        write_frame(writer, if index + 1 < frames.len() { MORE } else { 0 }, frame)?;
+    }
+    writer.flush()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The bytes of a hex string, or None if it is not one
+fn unhex(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2).map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok())).collect()
+}
+
+// HMAC (RFC 2104) with SHA-256 of the concatenated `parts`, to finalize or verify
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Collects what the kernel publishes
+    #[derive(Clone, Default)]
+    struct Published(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Published {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A shell message asking to run `code`, signed with `key`
+    fn execute_request(key: &[u8], code: &str) -> Vec<Vec<u8>> {
+        let parts = [json!({ "msg_id": "1", "msg_type": "execute_request" }).to_string(), "{}".to_string(), "{}".to_string(),
+                     json!({ "code": code }).to_string()];
+        let signature = hex(&hmac_sha256(key, &parts.each_ref().map(|part| part.as_bytes())).finalize().into_bytes());
+        let mut frames = vec![b"client".to_vec(), DELIMITER.to_vec(), signature.into_bytes()];
+        frames.extend(parts.map(String::into_bytes));
+        frames
+    }
+
+    #[test]
+    fn test_bad_signatures_are_dropped() {
+        let kernel = Kernel::new(crate::isa::front_end("arm").unwrap(), b"secret", MAX_STEPS, Interrupt::default());
+        let published = Published::default();
+        kernel.subscribers.lock().unwrap().push(Box::new(published.clone()));
+        let mut tampered = execute_request(b"secret", "MOV r0, #1");
+        *tampered.last_mut().unwrap() = json!({ "code": "MOV r0, #2" }).to_string().into_bytes();
+        let mut requests = Vec::new();
+        for frames in [execute_request(b"guess", "MOV r0, #1"), tampered] {
+            write_message(&mut requests, &frames).unwrap();
+        }
+        let mut replies = Vec::new();
+        kernel.serve(&mut &requests[..], &mut replies, &mpsc::channel().0).unwrap();
+        // Neither is answered nor run
+        assert!(replies.is_empty());
+        assert!(published.0.lock().unwrap().is_empty());
+        assert_eq!(kernel.executions.load(Ordering::Relaxed), 0);
+
+        // The same request signed with the key is
+        let mut requests = Vec::new();
+        write_message(&mut requests, &execute_request(b"secret", "MOV r0, #1")).unwrap();
+        kernel.serve(&mut &requests[..], &mut replies, &mpsc::channel().0).unwrap();
+        let reply = kernel.parse(read_message(&mut &replies[..]).unwrap().unwrap()).unwrap();
+        assert_eq!(reply.content["status"], "ok");
+    }
+
+    #[test]
+    fn test_cells_stop_after_max_steps() {
+        let kernel = Kernel::new(crate::isa::front_end("arm").unwrap(), b"secret", 100, Interrupt::default());
+        let mut requests = Vec::new();
+        write_message(&mut requests, &execute_request(b"secret", "loop: ADD r0, r0, #1\nB loop")).unwrap();
+        let mut replies = Vec::new();
+        kernel.serve(&mut &requests[..], &mut replies, &mpsc::channel().0).unwrap();
+        let reply = kernel.parse(read_message(&mut &replies[..]).unwrap().unwrap()).unwrap();
+        assert_eq!(reply.content["status"], "error");
+        assert_eq!(reply.content["ename"], Code::StepLimit.id());
+        assert_eq!(reply.content["evalue"], "Stopped after 100 instructions");
+    }
+
+    #[test]
+    fn test_signatures() {
+        let kernel = Kernel::new(crate::isa::front_end("arm").unwrap(), b"secret", MAX_STEPS, Interrupt::default());
+        let parts: [&[u8]; 2] = [b"{}", b"{\"code\": \"\"}"];
+        let signature = hex(&kernel.sign(&parts));
+        assert!(kernel.verify(signature.as_bytes(), &parts));
+        assert!(kernel.verify(signature.to_uppercase().as_bytes(), &parts));
+        // A signature off in its last digit, cut short or not hex at all is refused
+        let mut wrong = signature.clone().into_bytes();
+        wrong[63] = if wrong[63] == b'0' { b'1' } else { b'0' };
+        assert!(!kernel.verify(&wrong, &parts));
+        assert!(!kernel.verify(&signature.as_bytes()[..62], &parts));
+        assert!(!kernel.verify(&[b'z'; 64], &parts));
+    }
+
+    #[test]
+    fn test_kernel() {
+        let kernel = Kernel::new(crate::isa::front_end("arm").unwrap(), b"secret", MAX_STEPS, Interrupt::default());
+        let published = Published::default();
+        kernel.subscribers.lock().unwrap().push(Box::new(published.clone()));
+        let request = |msg_type: &str, content: Value, signed: bool| {
+            let parts = [json!({ "msg_id": "1", "msg_type": msg_type }).to_string(), "{}".to_string(), "{}".to_string(), content.to_string()];
+            let signature = if signed { hex(&kernel.sign(&parts.each_ref().map(|part| part.as_bytes()))) } else { "0".repeat(64) };
+            let mut frames = vec![b"client".to_vec(), DELIMITER.to_vec(), signature.into_bytes()];
+            frames.extend(parts.map(String::into_bytes));
+            kernel.parse(frames)
+        };
+        // The content of the reply, checking that it is routed back and signed
+        let reply = |request: &Message| {
+            let frames = kernel.handle(request).unwrap();
+            assert_eq!(frames[0], b"client");
+            kernel.parse(frames).unwrap().content
+        };
+        // The messages published since the last call, as (msg_type, content)
+        let messages = || {
+            let bytes = std::mem::take(&mut *published.0.lock().unwrap());
+            let mut reader = &bytes[..];
+            let mut messages = Vec::new();
+            while let Some(frames) = read_message(&mut reader).unwrap() {
+                let message = kernel.parse(frames).unwrap();
+                messages.push((message.msg_type().to_string(), message.content));
+            }
+            messages
+        };
+
+        let info = reply(&request("kernel_info_request", json!({}), true).unwrap());
+        assert_eq!(info["language_info"]["file_extension"], ".s");
+        messages();
+
+        let cell = request("execute_request", json!({ "code": "MOV r0, #6\nPRINT r0\nvalue: #42" }), true).unwrap();
+        assert_eq!(reply(&cell)["status"], "ok");
+        let published = messages();
+        let types: Vec<&str> = published.iter().map(|(msg_type, _)| msg_type.as_str()).collect();
+        assert_eq!(types, ["status", "execute_input", "stream", "execute_result", "status"]);
+        assert_eq!(published[2].1["text"], "r0 = 6\n");
+        let html = published[3].1["data"]["text/html"].as_str().unwrap();
+        assert!(html.contains("<tr><td>r0</td><td>6</td>") && html.contains("<td>value</td><td>42</td>"), "{}", html);
+        assert_eq!(published[3].1["execution_count"], 1);
+
+        let error = reply(&request("execute_request", json!({ "code": "MOV r0, #1\nFOO r0" }), true).unwrap());
+        assert_eq!((error["status"].as_str(), error["execution_count"].as_u64()), (Some("error"), Some(2)));
+        assert!(error["evalue"].as_str().unwrap().contains("FOO"));
+        assert!(messages().iter().any(|(msg_type, _)| msg_type == "error"));
+
+        let completion = reply(&request("complete_request", json!({ "code": "MOV r0, #1\nSU", "cursor_pos": 13 }), true).unwrap());
+        assert!(completion["matches"].as_array().unwrap().contains(&json!("SUB")));
+        assert_eq!(completion["cursor_start"], 11);
+
+        assert!(request("kernel_info_request", json!({}), false).is_none());
+    }
+}
@@ -0,0 +1,100 @@
+//! A memory-mapped keyboard, for `--keyboard`.
+//!
+//! While the program runs in a terminal, the word at the keyboard's address
+//! holds the character code of the last key pressed, without waiting for Enter,
+//! so a game loop can poll it with `LDR` and never block. The program stores 0
+//! there once it has handled a key, to tell the next press of the same key from
+//! the one it already saw.
+
+use std::collections::HashMap;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::hotkeys::{Keyboard, Terminal};
+use crate::interpreter::parse_immediate;
+use crate::memory::Memory;
+
+// Instructions between checks for a key press
+const POLL_INTERVAL: u64 = 64;
+
+pub struct MappedKeyboard<K: Keyboard> {
+    keys: K,
+    address: usize,
+    steps: u64,
+}
+
+impl MappedKeyboard<Terminal> {
+    /// The keyboard of the terminal on stdin, mapped at `location`: an address
+    /// in a memory of `memory_words` words or a data label in `labels`.
+    pub fn on_terminal(location: &str, labels: &HashMap<String, usize>, memory_words: usize) -> Result<Self, Box<Diagnostic>> {
+        let address = labels.get(location).copied()
+            .or_else(|| parse_immediate(location).and_then(|value| usize::try_from(value).ok()))
+            .filter(|&address| address < memory_words)
+            .ok_or_else(|| Box::new(Diagnostic::error(Code::UnsupportedOption, format!("Cannot map the keyboard at '{}'", location))
+                .with_note(format!("expected a memory address below {} or a data label", memory_words))))?;
+        let terminal = Terminal::new().ok_or_else(|| Box::new(Diagnostic::error(Code::UnsupportedOption,
+            "--keyboard needs a terminal on stdin".to_string())
+            .with_note("keys are read as they are pressed, which only a terminal can do")))?;
+        Ok(MappedKeyboard::new(terminal, address))
+    }
+}
+
+impl<K: Keyboard> MappedKeyboard<K> {
+    pub fn new(keys: K, address: usize) -> Self {
+        MappedKeyboard { keys, address, steps: 0 }
+    }
+
+    /// The word the last key goes to.
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Called before each instruction. Every few instructions, puts the last key
+    /// pressed since the previous check, if any, in its word of `memory`.
+    pub fn before(&mut self, memory: &mut Memory) {
+        self.steps += 1;
+        if !self.steps.is_multiple_of(POLL_INTERVAL) {
+            return;
+        }
+        if let Some(key) = std::iter::from_fn(|| self.keys.poll()).last() {
+            memory[self.address] = key as i32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use crate::interpreter::MEMORY_SIZE;
+    use super::*;
+
+    #[test]
+    fn test_last_key() {
+        let mut memory = Memory::new(MEMORY_SIZE);
+        let mut keyboard = MappedKeyboard::new(VecDeque::from(*b"wd"), 900);
+        for _ in 1..POLL_INTERVAL {
+            keyboard.before(&mut memory);
+        }
+        assert_eq!(memory[900], 0);
+        // Of the keys pressed since the last check, the last one counts
+        keyboard.before(&mut memory);
+        assert_eq!(memory[900], b'd' as i32);
+    }
+
+    #[test]
+    fn test_word_kept_without_a_key() {
+        // The word keeps what the program left there
+        let mut memory = Memory::new(MEMORY_SIZE);
+        memory[900] = 7;
+        let mut keyboard = MappedKeyboard::new(VecDeque::new(), 900);
+        for _ in 0..POLL_INTERVAL {
+            keyboard.before(&mut memory);
+        }
+        assert_eq!(memory[900], 7);
+    }
+
+    #[test]
+    fn test_location_outside_memory() {
+        let labels = HashMap::from([("key".to_string(), 5)]);
+        let error = MappedKeyboard::on_terminal("2000", &labels, MEMORY_SIZE).err().unwrap();
+        assert_eq!(error.message, "Cannot map the keyboard at '2000'");
+    }
+}
@@ -0,0 +1,276 @@
+//! An assembly interpreter for teaching: ARM by default, with Thumb, AArch64,
+//! RV32I and x86 front-ends (see [`isa`]).
+//!
+//! [`Interpreter`] runs programs from strings or files and returns their final
+//! state, for embedding the interpreter and for testing programs:
+//!
+//! ```
+//! let state = asm::Interpreter::default().run_source("MOV r0, #6\nMUL r0, r0, r0").unwrap();
+//! assert_eq!(state.register("r0"), Some(36));
+//! ```
+//!
+//! Program output such as PRINT goes to stdout unless another writer is given
+//! with [`Interpreter::with_output`], and input is read from stdin unless another
+//! reader is given with [`Interpreter::with_input`]. A [`Capture`] collects output
+//! into a string:
+//!
+//! ```
+//! let output = asm::Capture::default();
+//! let interpreter = asm::Interpreter::default().with_output(output.clone());
+//! interpreter.run_source("MOV r0, #6\nPRINT r0").unwrap();
+//! assert_eq!(output.contents(), "r0 = 6\n");
+//! ```
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+#[macro_use]
+pub mod log;
+pub mod diagnostics;
+pub mod console;
+pub mod config;
+pub mod instructions;
+pub mod encoding;
+pub mod interpreter;
+pub mod history;
+pub mod radix;
+pub mod cache;
+pub mod callgraph;
+pub mod heatmap;
+pub mod expression;
+pub mod breakpoints;
+pub mod debugger;
+pub mod hotkeys;
+pub mod isa;
+pub mod thumb;
+pub mod a64;
+pub mod rv32i;
+pub mod x86;
+pub mod lmc;
+pub mod custom;
+pub mod plugin;
+pub mod script;
+pub mod watch;
+pub mod serve;
+pub mod jupyter;
+pub mod msgpack;
+pub mod delta;
+pub mod memory;
+pub mod memory_map;
+pub mod target;
+pub mod lsp;
+pub mod scaffold;
+pub mod examples;
+pub mod exercises;
+pub mod grading;
+pub mod html;
+pub mod timeline;
+pub mod taint;
+pub mod undefined;
+pub mod lint;
+pub mod snapshot;
+pub mod watchdog;
+pub mod cores;
+pub mod tasks;
+pub mod semihosting;
+pub mod keyboard;
+pub mod snippets;
+pub mod prompt;
+pub mod transcript;
+#[cfg(all(test, feature = "unicorn"))]
+mod differential;
+
+pub use delta::StateDelta;
+pub use diagnostics::Diagnostic;
+pub use interpreter::{FinalState, RunOptions};
+
+pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const APP_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+
+/// Runs programs for one instruction set with fixed [`RunOptions`].
+pub struct Interpreter {
+    front_end: &'static dyn isa::FrontEnd,
+    options: RunOptions,
+    output: RefCell<Box<dyn Write>>,
+    // None reads the process's stdin
+    input: RefCell<Option<Box<dyn BufRead>>>,
+}
+
+impl Default for Interpreter {
+    /// An interpreter for the default instruction set (ARM) with default options.
+    fn default() -> Self {
+        Interpreter::new(isa::FRONT_ENDS[0], RunOptions::default())
+    }
+}
+
+impl Interpreter {
+    pub fn new(front_end: &'static dyn isa::FrontEnd, options: RunOptions) -> Self {
+        Interpreter { front_end, options, output: RefCell::new(Box::new(io::stdout())), input: RefCell::new(None) }
+    }
+
+    /// Reads input from `input` instead of stdin, e.g. the lines of an [`Interpreter::interactive`] session.
+    pub fn with_input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = RefCell::new(Some(Box::new(input)));
+        self
+    }
+
+    /// Sends program output to `output` instead of stdout, e.g. a file, a
+    /// [`Capture`] or `io::sink()` to suppress it.
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = RefCell::new(Box::new(output));
+        self
+    }
+
+    /// An interpreter for the instruction set registered as `name`, e.g. "rv32i".
+    pub fn for_isa(name: &str, options: RunOptions) -> Option<Self> {
+        isa::front_end(name).map(|front_end| Interpreter::new(front_end, options))
+    }
+
+    /// Runs a program given as a string, named `<input>` in diagnostics.
+    pub fn run_source(&self, source: &str) -> Result<FinalState, Vec<Diagnostic>> {
+        self.run_sources(&[("<input>".to_string(), source.to_string())])
+    }
+
+    /// Links `(file name, contents)` pairs into one program and runs it.
+    pub fn run_sources(&self, sources: &[(String, String)]) -> Result<FinalState, Vec<Diagnostic>> {
+        self.with_console(|console| self.front_end.run_sources(sources, &self.options, console))
+    }
+
+    /// Runs an interactive session on the input until it ends or EXIT, as the
+    /// interpreter does when started without files.
+    pub fn interactive(&self) {
+        self.with_console(|console| self.front_end.interactive(console, &self.options));
+    }
+
+    /// Runs the program in the file at `path`. Fails with an I/O error if it cannot be read.
+    pub fn run_file(&self, path: impl AsRef<Path>) -> io::Result<Result<FinalState, Vec<Diagnostic>>> {
+        self.run_files(&[path])
+    }
+
+    /// Links the files at `paths` into one program and runs it.
+    pub fn run_files(&self, paths: &[impl AsRef<Path>]) -> io::Result<Result<FinalState, Vec<Diagnostic>>> {
+        let mut sources = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            sources.push((path.display().to_string(), fs::read_to_string(path)?));
+        }
+        Ok(self.run_sources(&sources))
+    }
+
+    /// Checks a program for errors without running it.
+    pub fn check_source(&self, source: &str) -> Vec<Diagnostic> {
+        self.front_end.check_sources(&[("<input>".to_string(), source.to_string())])
+    }
+
+    fn with_console<T>(&self, run: impl FnOnce(&mut console::Console) -> T) -> T {
+        let mut output = self.output.borrow_mut();
+        match self.input.borrow_mut().as_mut() {
+            Some(input) => run(&mut console::Console::new(input, &mut *output)),
+            None => run(&mut console::Console::new(&mut io::stdin().lock(), &mut *output)),
+        }
+    }
+}
+
+/// A writer that keeps everything written to it. Clones share the same buffer, so
+/// one can be given to [`Interpreter::with_output`] and another read afterwards.
+#[derive(Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_source() {
+        let state = Interpreter::default().run_source("MOV r0, #-7\nSUBS r1, r0, #1\nSTR r1, result\nresult: #0").unwrap();
+        assert_eq!(state.register("r0"), Some(-7));
+        assert_eq!(state.register("R1"), Some(-8));
+        assert_eq!(state.register("r16"), None);
+        assert_eq!(state.flag("N"), Some(true));
+        assert_eq!(state.flag("Z"), Some(false));
+        assert_eq!(state.word("result"), Some(-8));
+    }
+
+    #[test]
+    fn test_run_source_for_other_instruction_sets() {
+        let state = Interpreter::for_isa("rv32i", RunOptions::default()).unwrap()
+            .run_source("li a0, 5\naddi a0, a0, 1").unwrap();
+        assert_eq!(state.register("a0"), Some(6));
+        assert!(state.flags.is_empty());
+    }
+
+    #[test]
+    fn test_run_source_errors() {
+        let errors = Interpreter::default().run_source("MOV r0, #1\nFOO r0").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, diagnostics::Code::UnknownInstruction);
+        assert_eq!(errors[0].span.as_ref().unwrap().file, "<input>");
+    }
+
+    #[test]
+    fn test_capture_output() {
+        let output = Capture::default();
+        let interpreter = Interpreter::for_isa("x86", RunOptions::default()).unwrap().with_output(output.clone());
+        interpreter.run_source("mov rax, 2\nprint rax").unwrap();
+        interpreter.run_source("mov rbx, 3\nprint rbx").unwrap();
+        assert_eq!(output.contents(), "rax = 2\nrbx = 3\n");
+    }
+
+    #[test]
+    fn test_interactive_input() {
+        let output = Capture::default();
+        Interpreter::default()
+            .with_input(io::Cursor::new("MOV r0, #4\nADD r0, r0, r0\nPRINT r0\nEXIT\nPRINT r0\n"))
+            .with_output(output.clone())
+            .interactive();
+        assert_eq!(output.contents(), "> > > r0 = 8\n> ");
+    }
+
+    #[test]
+    fn test_output_errors() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let errors = Interpreter::default().with_output(Closed).run_source("MOV r0, #1\nPRINT r0").unwrap_err();
+        assert_eq!(errors[0].code, diagnostics::Code::OutputFailed);
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_run_file() {
+        let path = std::env::temp_dir().join(format!("asm-run-file-{}.s", std::process::id()));
+        fs::write(&path, "MOV r2, #3\nEXIT").unwrap();
+        let state = Interpreter::default().run_file(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(state.register("r2"), Some(3));
+        assert!(Interpreter::default().run_file(&path).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Dead stores, for `--lint`.
+//!
+//! A register written and then written again before anything reads it held a
+//! value nobody needed, which usually means a leftover instruction or one that
+//! writes the wrong register. The check follows the run, and once it ends warns
+//! about each instruction whose value was overwritten every time without ever
+//! being read, so a value only some paths use, such as a flag a loop may set, is
+//! not reported.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::interpreter::SourceLine;
+use crate::taint::DataFlow;
+
+/// The registers written and not read since, and what became of the values each instruction wrote.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeadStores {
+    // Register index to the instruction that wrote it
+    unread: BTreeMap<usize, usize>,
+    // Instructions a value of which was read at least once
+    read: BTreeSet<usize>,
+    // Instruction to the register it wrote and the first instruction that overwrote it unread
+    overwritten: BTreeMap<usize, (usize, usize)>,
+}
+
+impl DeadStores {
+    /// Follows the registers the instruction at `pc` reads and writes.
+    pub fn step(&mut self, pc: usize, flow: &DataFlow) {
+        // An instruction with no data flow may be an SVC whose registers are not
+        // followed, so it counts as reading them all
+        if *flow == DataFlow::default() {
+            self.read.extend(self.unread.values());
+            self.unread.clear();
+            return;
+        }
+        for reg in flow.reads.iter().chain(&flow.address) {
+            if let Some(written) = self.unread.remove(reg) {
+                self.read.insert(written);
+            }
+        }
+        for &reg in &flow.writes {
+            if let Some(written) = self.unread.insert(reg, pc) {
+                self.overwritten.entry(written).or_insert((reg, pc));
+            }
+        }
+    }
+
+    /// A warning for each instruction whose values were all overwritten unread, in program order.
+    pub fn report(&self, lines: &[SourceLine], name: &dyn Fn(usize) -> String) -> Vec<Diagnostic> {
+        self.overwritten.iter().filter(|(written, _)| !self.read.contains(written)).map(|(&written, &(reg, by))| {
+            let (line, again) = (&lines[written], &lines[by]);
+            Diagnostic::warning(Code::DeadStore, format!("{} sets {}, which is written again before it is read", line.text(), name(reg)))
+                .with_note(format!("{} on line {} overwrites it; remove the first write or check which register it should set", again.text(), again.line_no))
+                .with_span(Reporter::new(&RefCell::new(Vec::new()), &line.file, line.line_no, &line.source).span(line.text()))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    // The lines and messages of the warnings --lint gives for `program`
+    fn lint(program: &str) -> Vec<(usize, String)> {
+        let options = RunOptions { lint: true, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        let cpu = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink())).unwrap();
+        cpu.warnings.iter().map(|warning| (warning.span.as_ref().unwrap().line, warning.message.clone())).collect()
+    }
+
+    #[test]
+    fn test_no_effect() {
+        assert_eq!(lint("MOV r1, r1\nADD r0, r0, #0\nPRINT r0\nPRINT r1\nEXIT"), [
+            (1, "MOV r1, r1 has no effect".to_string()),
+            (2, "ADD r0, r0, #0 has no effect".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        // The loop overwrites r2 each time round, but warns once; r4 is read on the last round only
+        let program = "MOV r0, #1\nMOV r0, #2\nMOV r3, #3\nloop: MOV r2, #0\nMOV r2, r3\nPRINT r2\nMOV r4, #1\nSUBS r3, r3, #1\nBNE loop\n\
+            PRINT r4\nPRINT r0\nEXIT";
+        assert_eq!(lint(program), [
+            (1, "MOV r0, #1 sets r0, which is written again before it is read".to_string()),
+            (4, "MOV r2, #0 sets r2, which is written again before it is read".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_values_stores_addresses_and_services_use() {
+        assert!(lint("MOV r0, #600\nSTR r0, [r0]\nMOV r1, #4\nLDR r1, [r1]\nMOV r0, #1\nSVC #2\nMOV r0, #2\nEXIT").is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+//! Little Man Computer front-end, selected with `--isa lmc`.
+//!
+//! The Little Man Computer has one register, the accumulator, a negative flag and
+//! 100 mailboxes, 0 to 99, each holding a number from 0 to 999. A program fills the
+//! mailboxes from 0, one instruction or `DAT` value each, so a label names the
+//! mailbox of its line. Labels are written in front of the mnemonic without a
+//! colon, as in LMC textbooks:
+//!
+//! ```text
+//!         INP
+//!         STA first
+//!         INP
+//!         ADD first
+//!         OUT
+//!         HLT
+//! first   DAT
+//! ```
+//!
+//! Each instruction is placed in its mailbox as its machine code, such as 305 for
+//! `STA 5`, and running into a `DAT` runs the machine code it holds.
+
+use std::collections::HashMap;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::instructions::{self, InstructionInfo, LMC_INSTRUCTIONS};
+use crate::interpreter::{self, parse_immediate, Machine};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+
+/// The number of mailboxes, which is also the most instructions a program can have
+pub const MAILBOXES: usize = 100;
+// Mailboxes and the accumulator hold 0 to 999
+const WORD: i32 = 1000;
+
+// Every mnemonic, including HLT's other name COB, so a word in front of one is known to be a label
+const MNEMONICS: [&str; 12] = ["inp", "out", "add", "sub", "sta", "lda", "bra", "brz", "brp", "hlt", "cob", "dat"];
+
+/// An LMC instruction with its mailbox checked. Branch targets are mailboxes too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Add(usize),
+    Sub(usize),
+    Sta(usize),
+    Lda(usize),
+    Bra(usize),
+    Brz(usize),
+    Brp(usize),
+    Inp,
+    Out,
+    Hlt,
+    Dat(i32),
+}
+
+impl Instruction {
+    /// The machine code placed in the mailbox of the instruction.
+    pub fn code(self) -> i32 {
+        match self {
+            Instruction::Add(mailbox) => 100 + mailbox as i32,
+            Instruction::Sub(mailbox) => 200 + mailbox as i32,
+            Instruction::Sta(mailbox) => 300 + mailbox as i32,
+            Instruction::Lda(mailbox) => 500 + mailbox as i32,
+            Instruction::Bra(mailbox) => 600 + mailbox as i32,
+            Instruction::Brz(mailbox) => 700 + mailbox as i32,
+            Instruction::Brp(mailbox) => 800 + mailbox as i32,
+            Instruction::Inp => 901,
+            Instruction::Out => 902,
+            Instruction::Hlt => 0,
+            Instruction::Dat(value) => value,
+        }
+    }
+
+    /// The instruction with machine code `code`, if it is one.
+    pub fn from_code(code: i32) -> Option<Self> {
+        let mailbox = (code % 100) as usize;
+        let instruction = match code / 100 {
+            0 => Instruction::Hlt,
+            1 => Instruction::Add(mailbox),
+            2 => Instruction::Sub(mailbox),
+            3 => Instruction::Sta(mailbox),
+            5 => Instruction::Lda(mailbox),
+            6 => Instruction::Bra(mailbox),
+            7 => Instruction::Brz(mailbox),
+            8 => Instruction::Brp(mailbox),
+            9 if code == 901 => Instruction::Inp,
+            9 if code == 902 => Instruction::Out,
+            _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+/// The accumulator and negative flag of the Little Man Computer, with its mailboxes in memory.
+pub struct Cpu {
+    pub accumulator: i32,
+    // Set by a SUB whose result is below 0, and cleared by INP, LDA and ADD
+    pub negative: bool,
+    pub machine: Machine,
+}
+
+impl AsRef<Machine> for Cpu {
+    fn as_ref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl AsMut<Machine> for Cpu {
+    fn as_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+/// The Little Man Computer.
+pub struct Lmc;
+
+impl Isa for Lmc {
+    type Instruction = Instruction;
+    type Cpu = Cpu;
+
+    const NAME: &'static str = "lmc";
+    const DESCRIPTION: &'static str = "Little Man Computer: an accumulator, 100 mailboxes and INP, OUT, ADD, SUB, STA, LDA, BRA, BRZ, BRP and DAT";
+    const INSTRUCTIONS: &'static [InstructionInfo] = LMC_INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &RegisterFile { names: &["acc"], bits: 32 };
+    const CORE_REGISTER: usize = 0;
+
+    fn new_cpu(&self) -> Cpu {
+        Cpu { accumulator: 0, negative: false, machine: Machine::new() }
+    }
+
+    fn read_register(&self, cpu: &Cpu, _index: usize) -> i64 {
+        cpu.accumulator as i64
+    }
+
+    fn flags(&self, cpu: &Cpu) -> Vec<(&'static str, bool)> {
+        vec![("N", cpu.negative)]
+    }
+
+    fn seed_registers(&self, cpu: &mut Cpu, registers: &[(usize, i32)]) {
+        for &(_, value) in registers.iter().filter(|&&(reg, _)| reg == 0) {
+            cpu.accumulator = value.rem_euclid(WORD);
+        }
+    }
+
+    // A word in front of a mnemonic is a label, as in "loop LDA count"; "loop: LDA count" works too
+    fn split_label<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        interpreter::split_label(line).or_else(|| {
+            let (label, rest) = line.split_once(char::is_whitespace)?;
+            let rest = rest.trim_start();
+            let mnemonic = rest.split_whitespace().next()?;
+            let known = |word: &str| MNEMONICS.iter().any(|mnemonic| mnemonic.eq_ignore_ascii_case(word));
+            (!known(label) && known(mnemonic)).then_some((label, rest))
+        })
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        decode(tokens, labels, report)
+    }
+
+    fn place(&self, cpu: &mut Cpu, instructions: &[Instruction], code_labels: &HashMap<String, usize>) -> Result<(), Box<Diagnostic>> {
+        if instructions.len() > MAILBOXES {
+            return Err(Box::new(Diagnostic::error(Code::OutOfLabelMemory,
+                format!("The program needs {} mailboxes, but there are {}", instructions.len(), MAILBOXES))
+                .with_note("each instruction and DAT takes one mailbox")));
+        }
+        for (mailbox, instruction) in instructions.iter().enumerate() {
+            cpu.machine.memory[mailbox] = instruction.code();
+        }
+        // Labels name mailboxes, so memory views and snapshots show them
+        cpu.machine.labels = code_labels.clone();
+        Ok(())
+    }
+
+    fn execute(&self, cpu: &mut Cpu, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        cpu.execute(*instruction, pc, console)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Bra(_) | Instruction::Brz(_) | Instruction::Brp(_))
+    }
+}
+
+impl Cpu {
+    // Executes an instruction found in mailbox `pc`
+    fn execute(&mut self, instruction: Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        match instruction {
+            Instruction::Add(mailbox) => {
+                self.accumulator = (self.accumulator + self.machine.load(mailbox)?).rem_euclid(WORD);
+                self.negative = false;
+            },
+            Instruction::Sub(mailbox) => {
+                let difference = self.accumulator - self.machine.load(mailbox)?;
+                self.accumulator = difference.rem_euclid(WORD);
+                self.negative = difference < 0;
+            },
+            Instruction::Sta(mailbox) => self.machine.store(mailbox, self.accumulator)?,
+            Instruction::Lda(mailbox) => {
+                self.accumulator = self.machine.load(mailbox)?;
+                self.negative = false;
+            },
+            Instruction::Bra(mailbox) => return Ok(Flow::Jump(mailbox)),
+            Instruction::Brz(mailbox) if self.accumulator == 0 => return Ok(Flow::Jump(mailbox)),
+            Instruction::Brp(mailbox) if !self.negative => return Ok(Flow::Jump(mailbox)),
+            Instruction::Brz(_) | Instruction::Brp(_) => {},
+            Instruction::Inp => {
+                self.accumulator = read_number(console)?;
+                self.negative = false;
+            },
+            Instruction::Out => console.print(format_args!("{}", self.accumulator))?,
+            Instruction::Hlt => return Ok(Flow::Exit),
+            // Data run as an instruction does what its machine code says, so 0 halts
+            Instruction::Dat(_) => {
+                let code = self.machine.memory[pc];
+                let Some(instruction) = Instruction::from_code(code) else {
+                    return Err(Box::new(Diagnostic::error(Code::UnknownInstruction, format!("Mailbox {} holds {}, which is not an instruction", pc, code))
+                        .with_note("put HLT or a branch before data so the program does not run into it")));
+                };
+                return self.execute(instruction, pc, console);
+            },
+        }
+        Ok(Flow::Next)
+    }
+}
+
+// Reads a number from 0 to 999 for INP
+fn read_number(console: &mut Console) -> Result<i32, Box<Diagnostic>> {
+    let Some(line) = console.read_line()? else {
+        return Err(Box::new(Diagnostic::error(Code::InputFailed, "INP found no more input".to_string())
+            .with_note("each INP reads a number from 0 to 999 on a line of its own")));
+    };
+    match line.trim().parse::<i32>() {
+        Ok(value @ 0..WORD) => Ok(value),
+        _ => Err(Box::new(Diagnostic::error(Code::InputFailed, format!("INP expected a number from 0 to 999, found '{}'", line.trim())))),
+    }
+}
+
+// Parses a mailbox number or the label of a line
+fn mailbox_operand(operand: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    if let Some(&mailbox) = labels.code.get(operand) {
+        return Some(mailbox);
+    }
+    if labels.addresses.contains_key(operand) {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("{} is not a mailbox", operand))
+            .with_note(format!("define it with {} DAT <value>", operand)));
+        return None;
+    }
+    match parse_immediate(operand) {
+        Some(mailbox) if (0..MAILBOXES as i32).contains(&mailbox) => Some(mailbox as usize),
+        Some(mailbox) => {
+            report.report(operand, Diagnostic::error(Code::MemoryOutOfBounds, format!("There is no mailbox {}", mailbox))
+                .with_note(format!("mailboxes are 0 to {}", MAILBOXES - 1)));
+            None
+        },
+        None => {
+            report.error(operand, Code::UndefinedLabel, format!("Undefined label: {}", operand));
+            None
+        },
+    }
+}
+
+// Decodes a single LMC instruction (labels already stripped), reporting any errors.
+fn decode(tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+    let mnemonic = tokens.mnemonic.to_lowercase();
+    let info = instructions::lookup_in(LMC_INSTRUCTIONS, &mnemonic);
+    let mailbox = || {
+        let ops = tokens.expect_instruction(1, info, report)?;
+        mailbox_operand(ops[0], labels, report)
+    };
+    let instruction = match mnemonic.as_str() {
+        "add" => Instruction::Add(mailbox()?),
+        "sub" => Instruction::Sub(mailbox()?),
+        "sta" => Instruction::Sta(mailbox()?),
+        "lda" => Instruction::Lda(mailbox()?),
+        "bra" => Instruction::Bra(mailbox()?),
+        "brz" => Instruction::Brz(mailbox()?),
+        "brp" => Instruction::Brp(mailbox()?),
+        "inp" | "out" | "hlt" | "cob" => {
+            tokens.expect_instruction(0, info, report)?;
+            match mnemonic.as_str() {
+                "inp" => Instruction::Inp,
+                "out" => Instruction::Out,
+                _ => Instruction::Hlt,
+            }
+        },
+        "dat" => {
+            // DAT on its own holds 0
+            let count = tokens.operands.len().min(1);
+            let ops = tokens.expect_instruction(count, info, report)?;
+            let Some(&operand) = ops.first() else {
+                return Some(Instruction::Dat(0));
+            };
+            match parse_immediate(operand) {
+                Some(value @ 0..WORD) => Instruction::Dat(value),
+                _ => {
+                    report.report(operand, Diagnostic::error(Code::InvalidDataValue, format!("Invalid value for DAT: {}", operand))
+                        .with_note("a mailbox holds a number from 0 to 999"));
+                    return None;
+                },
+            }
+        },
+        _ => {
+            report.report(tokens.mnemonic, instructions::unknown(LMC_INSTRUCTIONS, tokens.mnemonic));
+            return None;
+        },
+    };
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{run_sources, RunOptions};
+
+    fn run(program: &str, input: &str) -> Result<(Cpu, String), Vec<Diagnostic>> {
+        let mut out = Vec::new();
+        let sources = [("main.lmc".to_string(), program.to_string())];
+        let cpu = run_sources(&Lmc, &sources, &RunOptions::default(), &mut Console::new(&mut input.as_bytes(), &mut out))?;
+        Ok((cpu, String::from_utf8(out).unwrap()))
+    }
+
+    #[test]
+    fn test_add_inputs() {
+        let (cpu, out) = run("INP\nSTA first\nINP\nADD first\nOUT\nHLT\nfirst DAT", "12\n30\n").unwrap();
+        assert_eq!(out, "42\n");
+        assert_eq!(cpu.machine.memory.to_vec()[..7], [901, 306, 901, 106, 902, 0, 12]);
+        assert_eq!(cpu.machine.labels["first"], 6);
+    }
+
+    #[test]
+    fn test_count_down() {
+        // Counts down from the input; SUB below 0 sets the flag BRP tests, while the accumulator wraps
+        let program = "        INP\nloop    OUT\n        SUB one\n        BRP loop\n        STA left\none     DAT 1\nleft    DAT";
+        let (cpu, out) = run(program, "3\n").unwrap();
+        assert_eq!(out, "3\n2\n1\n0\n");
+        assert!(cpu.negative);
+        assert_eq!(cpu.machine.memory[6], 999);
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let errors = run("INP\nLDA 100\nbad DAT 1000\nBRA nowhere", "").err().unwrap();
+        assert_eq!(errors.iter().map(|error| error.code).collect::<Vec<_>>(), [Code::MemoryOutOfBounds, Code::InvalidDataValue, Code::UndefinedLabel]);
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        assert_eq!(run("INP\nOUT", "").err().unwrap()[0].message, "INP found no more input");
+    }
+}
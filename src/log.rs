@@ -0,0 +1,69 @@
+//! Output levels for interpreter messages that are not program output.
+//!
+//! Program output (`PRINT`) and errors are always shown. Informational messages
+//! such as banners and label definitions are hidden by `-q`, while `-v` and `-vv`
+//! add details about loading, decoding and operand resolution.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Only program output and errors
+    Quiet = 0,
+    /// Banners and label definitions (the default)
+    Normal = 1,
+    /// Program loading and each executed instruction
+    Verbose = 2,
+    /// Instruction decoding and operand resolution
+    Debug = 3,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Picks the level from the `-q` flag and the number of `-v` flags.
+pub fn level_from_flags(quiet: bool, verbose: u8) -> Level {
+    match (quiet, verbose) {
+        (true, _) => Level::Quiet,
+        (false, 0) => Level::Normal,
+        (false, 1) => Level::Verbose,
+        (false, _) => Level::Debug,
+    }
+}
+
+/// Prints an informational message to stdout unless running quietly.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a message to stderr when running with `-v`.
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Verbose) {
+            eprintln!("[verbose] {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// Prints a message to stderr when running with `-vv`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            eprintln!("[debug] {}", format_args!($($arg)*));
+        }
+    };
+}
@@ -0,0 +1,382 @@
+//! A Language Server Protocol server on stdin/stdout (`asm lsp`).
+//!
+//! Editors get diagnostics when a document is opened or saved, go-to-definition
+//! for labels, hover documentation for instructions (from the instruction table)
+//! and completion of mnemonics, registers and labels.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use serde_json::{json, Value};
+use crate::diagnostics::Diagnostic;
+use crate::instructions;
+use crate::interpreter::{split_label, strip_comment};
+use crate::isa::FrontEnd;
+
+// JSON-RPC error code for unsupported requests
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP constants
+const SEVERITY_ERROR: u8 = 1;
+const TEXT_DOCUMENT_SYNC_FULL: u8 = 1;
+const COMPLETION_KIND_VARIABLE: u8 = 6;
+const COMPLETION_KIND_KEYWORD: u8 = 14;
+const COMPLETION_KIND_REFERENCE: u8 = 18;
+
+/// Serves LSP requests from stdin until the client sends `exit`, checking documents
+/// against `front_end`. Returns whether the client shut the server down cleanly first.
+pub fn run(front_end: &'static dyn FrontEnd) -> io::Result<bool> {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+    let mut server = Server::new(front_end);
+
+    while let Some(message) = read_message(&mut reader)? {
+        for outgoing in server.handle(&message) {
+            write_message(&mut writer, &outgoing)?;
+        }
+        if server.exit {
+            break;
+        }
+    }
+    Ok(server.shutdown)
+}
+
+// Reads one message framed with a Content-Length header. Returns None at end of input.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let Some(length) = content_length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header"));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+struct Server {
+    front_end: &'static dyn FrontEnd,
+    // Open documents by URI
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exit: bool,
+}
+
+impl Server {
+    fn new(front_end: &'static dyn FrontEnd) -> Self {
+        Server { front_end, documents: HashMap::new(), shutdown: false, exit: false }
+    }
+
+    /// Handles one incoming message and returns the messages to send back.
+    fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": {
+                        "openClose": true,
+                        "change": TEXT_DOCUMENT_SYNC_FULL,
+                        "save": { "includeText": true },
+                    },
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": crate::APP_NAME, "version": crate::APP_VERSION },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            },
+            "exit" => {
+                self.exit = true;
+                None
+            },
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default().to_string();
+                self.documents.insert(uri.clone(), text);
+                return vec![self.publish_diagnostics(&uri)];
+            },
+            "textDocument/didChange" => {
+                // Full document sync: the last change holds the whole text
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                if let Some(change) = params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    let text = change["text"].as_str().unwrap_or_default().to_string();
+                    self.documents.insert(uri.to_string(), text);
+                }
+                None
+            },
+            "textDocument/didSave" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                if let Some(text) = params["text"].as_str() {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                return vec![self.publish_diagnostics(&uri)];
+            },
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                self.documents.remove(uri);
+                return vec![notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))];
+            },
+            "textDocument/hover" => Some(self.hover(params)),
+            "textDocument/definition" => Some(self.definition(params)),
+            "textDocument/completion" => Some(self.completion(params)),
+            _ => {
+                // Unknown notifications are ignored; unknown requests get an error
+                return match id {
+                    Some(id) => vec![json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": METHOD_NOT_FOUND, "message": format!("Unsupported method: {}", method) },
+                    })],
+                    None => Vec::new(),
+                };
+            },
+        };
+
+        match (id, result) {
+            (Some(id), Some(result)) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            _ => Vec::new(),
+        }
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).cloned().unwrap_or_default();
+        let errors = self.front_end.check_sources(&[(uri.to_string(), text)]);
+        let diagnostics: Vec<Value> = errors.iter().map(to_lsp_diagnostic).collect();
+        notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((uri, word)) = self.word_at(params) else {
+            return Value::Null;
+        };
+
+        let registers = self.front_end.registers();
+        let contents = if let Some(info) = instructions::lookup_in(self.front_end.instructions(), &word) {
+            format!("```asm\n{}\n```\n{}\n\nExample: `{}`", info.syntax, info.summary, info.example)
+        } else if registers.names.iter().any(|name| name.eq_ignore_ascii_case(&word)) {
+            format!("{}-bit register `{}`", registers.bits, word)
+        } else if let Some((def_uri, line, _, _)) = self.find_label(&uri, &word) {
+            let location = if def_uri == uri { String::new() } else { format!(" in {}", def_uri) };
+            format!("Label `{}` defined on line {}{}", word, line + 1, location)
+        } else {
+            return Value::Null;
+        };
+        json!({ "contents": { "kind": "markdown", "value": contents } })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, word)) = self.word_at(params) else {
+            return Value::Null;
+        };
+        match self.find_label(&uri, &word) {
+            Some((def_uri, line, start, end)) => json!({ "uri": def_uri, "range": range(line, start, line, end) }),
+            None => Value::Null,
+        }
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let mut items: Vec<Value> = self.front_end.instructions().iter().map(|info| json!({
+            "label": info.mnemonic,
+            "kind": COMPLETION_KIND_KEYWORD,
+            "detail": info.syntax,
+            "documentation": info.summary,
+        })).collect();
+
+        items.extend(self.front_end.registers().names.iter().map(|name| json!({ "label": name, "kind": COMPLETION_KIND_VARIABLE })));
+
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        if let Some(text) = self.documents.get(uri) {
+            items.extend(label_definitions(text).into_iter().map(|(label, _, _, _)| json!({
+                "label": label,
+                "kind": COMPLETION_KIND_REFERENCE,
+            })));
+        }
+        json!(items)
+    }
+
+    // Finds the identifier under the cursor of a text document position request
+    fn word_at(&self, params: &Value) -> Option<(String, String)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let line_no = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        let line = self.documents.get(uri)?.lines().nth(line_no)?;
+
+        let cursor = byte_offset(line, character);
+        let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        let start = line[..cursor].rfind(|c| !is_word(c)).map_or(0, |i| i + 1);
+        let end = line[cursor..].find(|c| !is_word(c)).map_or(line.len(), |i| cursor + i);
+        if start >= end {
+            return None;
+        }
+        Some((uri.to_string(), line[start..end].to_string()))
+    }
+
+    // Finds where a label is defined, looking in `uri` first and then in the other open documents.
+    // Returns the document, the line and the UTF-16 column range of the label.
+    fn find_label(&self, uri: &str, label: &str) -> Option<(String, usize, usize, usize)> {
+        let current = self.documents.get_key_value(uri);
+        current.into_iter()
+            .chain(self.documents.iter().filter(|(doc_uri, _)| doc_uri.as_str() != uri))
+            .find_map(|(doc_uri, text)| {
+                label_definitions(text).into_iter()
+                    .find(|(name, _, _, _)| name == label)
+                    .map(|(_, line, start, end)| (doc_uri.clone(), line, start, end))
+            })
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn range(start_line: usize, start_character: usize, end_line: usize, end_character: usize) -> Value {
+    json!({
+        "start": { "line": start_line, "character": start_character },
+        "end": { "line": end_line, "character": end_character },
+    })
+}
+
+fn to_lsp_diagnostic(diagnostic: &Diagnostic) -> Value {
+    let range = match &diagnostic.span {
+        Some(span) => {
+            // Span columns count characters from 1; LSP counts UTF-16 code units from 0
+            let utf16_column = |column: usize| -> usize {
+                span.source_line.chars().take(column - 1).map(char::len_utf16).sum()
+            };
+            range(span.line - 1, utf16_column(span.column_start), span.line - 1, utf16_column(span.column_end))
+        },
+        None => range(0, 0, 0, 0),
+    };
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message += &format!("\nnote: {}", note);
+    }
+    json!({
+        "range": range,
+        "severity": SEVERITY_ERROR,
+        "code": diagnostic.code.id(),
+        "source": crate::APP_NAME,
+        "message": message,
+    })
+}
+
+// Every label defined in a document, with its line and UTF-16 column range
+fn label_definitions(text: &str) -> Vec<(String, usize, usize, usize)> {
+    let mut labels = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        if let Some((label, _)) = split_label(strip_comment(line)) {
+            let start = label.as_ptr() as usize - line.as_ptr() as usize;
+            let start_column = line[..start].encode_utf16().count();
+            labels.push((label.to_string(), line_no, start_column, start_column + label.encode_utf16().count()));
+        }
+    }
+    labels
+}
+
+// Converts a UTF-16 column into a byte offset within `line`
+fn byte_offset(line: &str, utf16_column: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= utf16_column {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Arm;
+
+    fn open(server: &mut Server, uri: &str, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "asm", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, uri: &str, line: usize, character: usize) -> Value {
+        let responses = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": { "textDocument": { "uri": uri }, "position": { "line": line, "character": character } },
+        }));
+        responses[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_open_publishes_diagnostics() {
+        let mut server = Server::new(&Arm);
+        let messages = open(&mut server, "file:///main.s", "MOV r0, #1\nADD r1, r99, #2\n");
+        let diagnostics = &messages[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["code"], "E0003");
+        assert_eq!(diagnostics[0]["range"], range(1, 8, 1, 11));
+    }
+
+    #[test]
+    fn test_hover_shows_instruction_docs() {
+        let mut server = Server::new(&Arm);
+        open(&mut server, "file:///main.s", "ADD r0, r1, #5\n");
+        let hover = request(&mut server, "textDocument/hover", "file:///main.s", 0, 1);
+        assert!(hover["contents"]["value"].as_str().unwrap().contains("ADD <dest_register>, <reg_operand>, <operand>"));
+    }
+
+    #[test]
+    fn test_definition_of_label_in_other_document() {
+        let mut server = Server::new(&Arm);
+        open(&mut server, "file:///lib.s", "\n  value: #42\n");
+        open(&mut server, "file:///main.s", "LDR r0, value\n");
+        let location = request(&mut server, "textDocument/definition", "file:///main.s", 0, 10);
+        assert_eq!(location["uri"], "file:///lib.s");
+        assert_eq!(location["range"], range(1, 2, 1, 7));
+    }
+
+    #[test]
+    fn test_completion_lists_instructions_registers_and_labels() {
+        let mut server = Server::new(&Arm);
+        open(&mut server, "file:///main.s", "loop:\nMOV r0, #1\n");
+        let items = request(&mut server, "textDocument/completion", "file:///main.s", 1, 0);
+        let labels: Vec<&str> = items.as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap()).collect();
+        assert!(labels.contains(&"MOV"));
+        assert!(labels.contains(&"r15"));
+        assert!(labels.contains(&"loop"));
+    }
+
+    #[test]
+    fn test_message_framing_round_trip() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &json!({ "jsonrpc": "2.0", "method": "exit" })).unwrap();
+        let message = read_message(&mut buffer.as_slice()).unwrap().unwrap();
+        assert_eq!(message["method"], "exit");
+    }
+}
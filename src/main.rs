@@ -1,32 +1,331 @@
-use std::env;
-use clap::Parser;
-use ctrlc;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs;
+use std::io;
+use std::process::ExitCode;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches};
 
-mod interpreter;
-mod cli;
+use asm::config::{self, Config};
+use asm::console::Console;
+use asm::cores::Schedule;
+use asm::custom::Custom;
+use asm::debugger::DebugScript;
+use asm::grading::{ReportFormat, Rubric};
+use asm::isa::FrontEnd;
+use asm::memory_map::MemoryMap;
+use asm::diagnostics::{Code, Diagnostic};
+use asm::radix::Formats;
+use asm::script::Script;
+use asm::snapshot::{self, Snapshot};
+use asm::{diagnostics, examples, exercises, grading, info, interpreter, isa, jupyter, log, lsp, plugin, scaffold, serve, transcript};
 
-pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
-pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
-pub const APP_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+mod cli;
 
-fn main() -> std::io::Result<()> {
-    ctrlc::set_handler(|| {
-        println!("\nCtrl-C pressed. Exiting...");
-        std::process::exit(0);
+fn main() -> io::Result<ExitCode> {
+    // Ctrl-C stops a running program, and exits when there is none
+    let interrupt = interpreter::Interrupt::default();
+    let handler = interrupt.clone();
+    ctrlc::set_handler(move || {
+        if !handler.request() {
+            println!("\nCtrl-C pressed. Exiting...");
+            std::process::exit(0);
+        }
     }).expect("Error setting Ctrl-C handler");
-    
-    let cli = cli::Cli::parse();
 
-    if let Some(input_file) = cli.input_file {
-        let file = File::open(&input_file)?;
-        let reader = BufReader::new(file);
-        interpreter::run_with_reader(reader, false);
+
+    let matches = cli::Cli::command().get_matches();
+    let mut cli = cli::Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    if let Some(cli::Command::Completions { shell }) = cli.command {
+        clap_complete::generate(shell, &mut cli::Cli::command(), asm::APP_NAME, &mut io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if !cli.no_config {
+        match Config::load(&config::default_paths()) {
+            Ok(config) => cli.apply(config, &matches),
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                return Ok(ExitCode::FAILURE);
+            },
+        }
+    }
+    log::set_level(log::level_from_flags(cli.quiet, cli.verbose));
+    diagnostics::set_format(cli.diagnostics);
+    diagnostics::set_color(cli.color);
+    if matches.value_source("trace_format") == Some(ValueSource::CommandLine) && cli.trace.is_none() {
+        diagnostics::emit(&[Diagnostic::error(Code::UnsupportedOption, "--trace-format needs --trace FILE, or trace in a configuration file")]);
+        return Ok(ExitCode::FAILURE);
+    }
+    if let Some(path) = &cli.log_session
+        && let Err(error) = transcript::open(path) {
+        diagnostics::emit(&[*error]);
+        return Ok(ExitCode::FAILURE);
+    }
+    for path in &cli.plugin {
+        if let Err(error) = plugin::load(path) {
+            diagnostics::emit(&[*error]);
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
+    if let Some(cli::Command::Lsp { isa }) = cli.command {
+        let front_end = isa::front_end(&isa).expect("clap only accepts registered instruction sets");
+        return Ok(if lsp::run(front_end)? { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+
+    if let Some(cli::Command::Jupyter { connection_file, isa, max_steps }) = cli.command {
+        let front_end = isa::front_end(&isa).expect("clap only accepts registered instruction sets");
+        return Ok(match jupyter::run(front_end, &connection_file, max_steps, interrupt) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                ExitCode::FAILURE
+            },
+        });
+    }
+
+    if let Some(cli::Command::Exercise { name, solution }) = &cli.command {
+        let Some(exercise) = name.as_deref().and_then(exercises::find) else {
+            for exercise in exercises::EXERCISES {
+                println!("{}", exercise.name);
+            }
+            return Ok(ExitCode::SUCCESS);
+        };
+        let Some(solution) = solution else {
+            println!("{}\n\nCheck a solution with: {} exercise {} solution.s", exercise.prompt, asm::APP_NAME, exercise.name);
+            return Ok(ExitCode::SUCCESS);
+        };
+        let outcomes = exercise.check(&[(solution.clone(), fs::read_to_string(solution)?)]);
+        for outcome in &outcomes {
+            if let exercises::Outcome::Stopped(errors) = outcome {
+                diagnostics::emit(errors);
+                break;
+            }
+        }
+        print!("{}", exercise.summary(&outcomes));
+        let passed = outcomes.iter().all(|outcome| matches!(outcome, exercises::Outcome::Passed));
+        return Ok(if passed { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+
+    if let Some(cli::Command::Grade { rubric, submissions, format, jobs }) = &cli.command {
+        let rubric = match Rubric::load(rubric) {
+            Ok(rubric) => rubric,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                return Ok(ExitCode::FAILURE);
+            },
+        };
+        let mut sources = Vec::new();
+        for (student, files) in grading::submissions(submissions)? {
+            sources.push((student, files.iter().map(|file| Ok((file.display().to_string(), fs::read_to_string(file)?))).collect::<io::Result<Vec<_>>>()?));
+        }
+        let jobs = jobs.map_or_else(|| std::thread::available_parallelism().map_or(1, |cores| cores.get()), |jobs| jobs as usize);
+        let reports = match grading::grade_all(&rubric, &sources, jobs) {
+            Ok(reports) => reports,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                return Ok(ExitCode::FAILURE);
+            },
+        };
+        print!("{}", match format {
+            ReportFormat::Csv => grading::csv(&rubric, &reports),
+            ReportFormat::Json => grading::json(&reports),
+            ReportFormat::Html => grading::html(&rubric, &reports),
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(cli::Command::Compare { program, reference, cases }) = &cli.command {
+        let cases = match Rubric::load_cases(cases) {
+            Ok(cases) => cases,
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                return Ok(ExitCode::FAILURE);
+            },
+        };
+        let results = cases.compare(&[(program.clone(), fs::read_to_string(program)?)], &[(reference.clone(), fs::read_to_string(reference)?)]);
+        print!("{}", grading::comparison(&results));
+        let same = results.iter().all(|result| result.failures.is_empty());
+        return Ok(if same { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+
+    if let Some(cli::Command::Diff { before, after }) = &cli.command {
+        let differences = Snapshot::load(before).and_then(|snapshot| snapshot.diff(&Snapshot::load(after)?)
+            .map_err(|message| Box::new(Diagnostic::error(Code::InvalidSnapshot, format!("Cannot compare {} with {}: {}", before, after, message)))));
+        return Ok(match differences {
+            Ok(differences) => {
+                println!("{}", snapshot::report(&differences));
+                if differences.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+            },
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                ExitCode::FAILURE
+            },
+        });
+    }
+
+    if let Some(cli::Command::New { directory }) = &cli.command {
+        return Ok(match scaffold::create(directory) {
+            Ok(files) => {
+                for file in files {
+                    info!("Created {}", file.display());
+                }
+                ExitCode::SUCCESS
+            },
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                ExitCode::FAILURE
+            },
+        });
+    }
+
+    let custom;
+    let front_end: &dyn FrontEnd = match &cli.isa_file {
+        Some(path) => match Custom::load(path) {
+            Ok(defined) => {
+                custom = defined;
+                &custom
+            },
+            Err(error) => {
+                diagnostics::emit(&[*error]);
+                return Ok(ExitCode::FAILURE);
+            },
+        },
+        None => isa::front_end(&cli.isa).expect("clap only accepts registered instruction sets"),
+    };
+    let debug_script = match &cli.debug_script {
+        Some(path) => match DebugScript::parse(path, &fs::read_to_string(path)?) {
+            Ok(script) => Some(script),
+            Err(errors) => {
+                diagnostics::emit(&errors);
+                return Ok(ExitCode::FAILURE);
+            },
+        },
+        None => None,
+    };
+    let script = match &cli.script {
+        Some(path) => match Script::parse(path, &fs::read_to_string(path)?) {
+            Ok(script) => Some(script),
+            Err(errors) => {
+                diagnostics::emit(&errors);
+                return Ok(ExitCode::FAILURE);
+            },
+        },
+        None => None,
+    };
+    let replay_schedule = match &cli.replay_schedule {
+        Some(path) => match Schedule::parse(path, &fs::read_to_string(path)?) {
+            Ok(schedule) => Some(schedule),
+            Err(errors) => {
+                diagnostics::emit(&errors);
+                return Ok(ExitCode::FAILURE);
+            },
+        },
+        None => None,
+    };
+    let mut formats = Formats::default();
+    for setting in &cli.radix {
+        if let Some(register) = setting.register.as_deref()
+            && !front_end.registers().names.contains(&register) {
+            diagnostics::emit(&[Diagnostic::error(Code::InvalidRegister, format!("Invalid register in --radix: {}", register))
+                .with_note(format!("the registers of --isa {} are {}", front_end.name(), front_end.registers().names.join(", ")))]);
+            return Ok(ExitCode::FAILURE);
+        }
+        formats.set(setting);
+    }
+    let mut registers = Vec::new();
+    for (name, value) in cli.registers {
+        let Some(register) = front_end.register_index(&name) else {
+            diagnostics::emit(&[Diagnostic::error(Code::InvalidRegister, format!("Invalid register in --set: {}", name))
+                .with_note(format!("the registers of --isa {} are {}", front_end.name(), front_end.registers().names.join(", ")))]);
+            return Ok(ExitCode::FAILURE);
+        };
+        registers.push((register, value));
+    }
+    let memory_map = match MemoryMap::new(cli.memory_map) {
+        Ok(map) if map.regions().is_empty() => None,
+        Ok(map) => Some(map),
+        Err(error) => {
+            diagnostics::emit(&[Diagnostic::error(Code::UnsupportedOption, format!("Invalid --memory-map: {}", error))]);
+            return Ok(ExitCode::FAILURE);
+        },
+    };
+    let options = interpreter::RunOptions {
+        entry: cli.entry,
+        registers,
+        memory: cli.memory,
+        memory_words: cli.memory_bytes.map(|bytes| (bytes / 4) as usize),
+        memory_map,
+        target: cli.target,
+        trap_overflow: cli.trap_overflow,
+        word_size: cli.word_size.filter(|&bits| bits < 32),
+        no_helpers: cli.no_helpers,
+        call_graph: cli.call_graph,
+        call_graph_file: cli.call_graph_file,
+        max_steps: None,
+        allow_only: cli.allow_only,
+        forbid: cli.forbid,
+        cycle_budget: cli.cycle_budget,
+        interrupt,
+        hotkeys: !cli.no_hotkeys,
+        strict_bkpt: cli.strict_bkpt,
+        strict_imm: cli.strict_imm,
+        strict: cli.strict,
+        profile: cli.profile,
+        breakpoints: cli.breakpoints,
+        debug_script,
+        post_mortem: !cli.quiet,
+        heatmap: cli.heatmap,
+        cache: cli.cache,
+        html: cli.html,
+        snapshot: cli.snapshot,
+        checkpoints: cli.checkpoint_every.zip(cli.checkpoint_dir),
+        history_size: cli.history_size,
+        trace: cli.trace,
+        trace_format: cli.trace_format,
+        watch_memory: cli.watch_events.map(|path| (cli.watch_memory, path)),
+        deltas: None,
+        taint: cli.taint,
+        undefined: cli.undefined,
+        lint: cli.lint,
+        cores: cli.cores as usize,
+        interleaving: cli.interleave,
+        record_schedule: cli.record_schedule,
+        replay_schedule,
+        keyboard: cli.keyboard,
+        formats,
+        snippets: (!cli.no_config).then(config::snippets_path).flatten(),
+        prompt: cli.prompt.unwrap_or_default(),
+        script,
+    };
+
+    if let Some(cli::Command::Examples { action }) = cli.command {
+        let (mut stdin, mut stdout) = (io::stdin().lock(), io::stdout());
+        match action {
+            None => print!("{}", examples::list()),
+            Some(cli::ExamplesAction::Show { name }) => print!("{}", examples::find(&name).expect("clap only accepts built-in examples").source),
+            Some(cli::ExamplesAction::Run { name }) => {
+                let example = examples::find(&name).expect("clap only accepts built-in examples");
+                let arm = isa::front_end("arm").expect("the ARM front-end is registered");
+                let sources = [(example.file(), example.source.to_string())];
+                if let Err(errors) = arm.run_sources(&sources, &options, &mut Console::new(&mut stdin, &mut stdout)) {
+                    diagnostics::emit(&errors);
+                    return Ok(ExitCode::FAILURE);
+                }
+            },
+        }
+    } else if let Some(address) = &cli.serve {
+        if let Err(error) = serve::serve(front_end, address) {
+            diagnostics::emit(&[*error]);
+            return Ok(ExitCode::FAILURE);
+        }
+    } else if !cli.input_files.is_empty() {
+        let (mut stdin, mut stdout) = (io::stdin().lock(), io::stdout());
+        if !interpreter::run_files(front_end, &cli.input_files, &options, &mut Console::new(&mut stdin, &mut stdout))? {
+            return Ok(ExitCode::FAILURE);
+        }
     } else {
-        println!("Welcome to the Assembly Interpreter.");
-        interpreter::interactive();
+        info!("Welcome to the Assembly Interpreter.");
+        interpreter::interactive(front_end, &options);
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
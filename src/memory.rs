@@ -0,0 +1,276 @@
+//! Memory as pages of words shared between copies until written.
+//!
+//! Cloning [`Memory`] copies a pointer per page of [`PAGE_WORDS`] words rather than
+//! the words, and a write copies only the page it lands on, if another clone still
+//! shares it. Taking the state of a core for the cycle detector, a trace or a
+//! delta therefore costs the pages changed since, not the whole memory, and so
+//! does comparing two states that share most pages.
+//!
+//! Pages no program has written are not kept at all and read as 0, so a memory of
+//! a billion words, for the addresses of a real board with `--memory-bytes 4G`,
+//! only takes room for the pages in use.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+/// Words in a page, the unit memory is copied in.
+pub const PAGE_WORDS: usize = 256;
+
+type Page = Arc<[i32; PAGE_WORDS]>;
+
+// The page every word of a page not kept reads from
+static ZEROS: [i32; PAGE_WORDS] = [0; PAGE_WORDS];
+
+/// Memory words, addressed from 0.
+#[derive(Clone)]
+pub struct Memory {
+    // The pages written to, by their index
+    pages: BTreeMap<usize, Page>,
+    len: usize,
+}
+
+impl Memory {
+    /// `len` words of 0, taking no room until written.
+    pub fn new(len: usize) -> Self {
+        Memory { pages: BTreeMap::new(), len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The word at `address`, or None past the end.
+    pub fn get(&self, address: usize) -> Option<&i32> {
+        (address < self.len).then(|| &self.page(address / PAGE_WORDS)[address % PAGE_WORDS])
+    }
+
+    /// Every word, including those of pages not kept; see [`Memory::words`] for large memories.
+    pub fn iter(&self) -> impl Iterator<Item = &i32> + '_ {
+        (0..self.len.div_ceil(PAGE_WORDS)).flat_map(|index| self.page(index).iter()).take(self.len)
+    }
+
+    /// The words of the pages kept, as (address, word), which hold every word that is not 0.
+    pub fn words(&self) -> impl Iterator<Item = (usize, i32)> + '_ {
+        self.pages.iter().flat_map(|(&index, page)| page.iter().enumerate().map(move |(offset, &word)| (index * PAGE_WORDS + offset, word)))
+            .filter(|&(address, _)| address < self.len)
+    }
+
+    fn page(&self, index: usize) -> &[i32; PAGE_WORDS] {
+        self.pages.get(&index).map_or(&ZEROS, |page| page)
+    }
+
+    fn page_mut(&mut self, index: usize) -> &mut [i32; PAGE_WORDS] {
+        Arc::make_mut(self.pages.entry(index).or_insert_with(|| Arc::new([0; PAGE_WORDS])))
+    }
+
+    /// Makes the memory `len` words long, dropping the words past the end or adding words of 0.
+    pub fn resize(&mut self, len: usize) {
+        self.pages.retain(|&index, _| index * PAGE_WORDS < len);
+        if let Some(index) = self.pages.keys().next_back().copied().filter(|index| (index + 1) * PAGE_WORDS > len) {
+            let used = len - index * PAGE_WORDS;
+            if self.page(index)[used..].iter().any(|&word| word != 0) {
+                self.page_mut(index)[used..].fill(0);
+            }
+        }
+        self.len = len;
+    }
+
+    pub fn to_vec(&self) -> Vec<i32> {
+        self.iter().copied().collect()
+    }
+
+    /// Sets the words from `words`, which must be as many, copying only the pages where one differs.
+    pub fn copy_from_slice(&mut self, words: &[i32]) {
+        assert_eq!(words.len(), self.len, "the words must fill the memory");
+        for (index, words) in words.chunks(PAGE_WORDS).enumerate() {
+            if self.page(index)[..words.len()] != *words {
+                self.page_mut(index)[..words.len()].copy_from_slice(words);
+            }
+        }
+    }
+
+    /// Replaces each word with `change` of it, copying only the pages where one changes.
+    /// Words of pages not kept stay 0, so `change` must keep 0.
+    pub fn update(&mut self, change: impl Fn(i32) -> i32) {
+        for (&index, page) in &mut self.pages {
+            // The words of the last page past the end stay 0
+            let used = (self.len - index * PAGE_WORDS).min(PAGE_WORDS);
+            if page[..used].iter().any(|&word| change(word) != word) {
+                Arc::make_mut(page)[..used].iter_mut().for_each(|word| *word = change(*word));
+            }
+        }
+    }
+
+    /// The words that differ from those in `before`, a memory of the same size, as
+    /// (address, word). Pages both still share are skipped without comparing them.
+    pub fn changes<'a>(&'a self, before: &'a Memory) -> impl Iterator<Item = (usize, i32)> + 'a {
+        self.differing_pages(before).flat_map(|index| self.page(index).iter().zip(before.page(index).iter()).enumerate()
+                .filter(|(_, (word, old))| word != old).map(move |(offset, (&word, _))| (index * PAGE_WORDS + offset, word)))
+            .filter(|&(address, _)| address < self.len)
+    }
+
+    // The pages either memory keeps that the two do not share
+    fn differing_pages<'a>(&'a self, other: &'a Memory) -> impl Iterator<Item = usize> + 'a {
+        let mut indices: Vec<usize> = self.pages.keys().chain(other.pages.keys()).copied().collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices.into_iter().filter(|index| match (self.pages.get(index), other.pages.get(index)) {
+            (Some(page), Some(other)) => !Arc::ptr_eq(page, other),
+            _ => true,
+        })
+    }
+
+    /// How many pages this memory shares with `other` rather than holding a copy.
+    pub fn shared_pages(&self, other: &Memory) -> usize {
+        self.pages.iter().filter(|(index, page)| other.pages.get(index).is_some_and(|other| Arc::ptr_eq(page, other))).count()
+    }
+}
+
+impl From<Vec<i32>> for Memory {
+    fn from(words: Vec<i32>) -> Self {
+        let mut memory = Memory::new(words.len());
+        memory.copy_from_slice(&words);
+        memory
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = i32;
+
+    fn index(&self, address: usize) -> &i32 {
+        self.get(address).unwrap_or_else(|| panic!("memory address {} out of range for {} words", address, self.len))
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, address: usize) -> &mut i32 {
+        assert!(address < self.len, "memory address {} out of range for {} words", address, self.len);
+        &mut self.page_mut(address / PAGE_WORDS)[address % PAGE_WORDS]
+    }
+}
+
+impl<'a> IntoIterator for &'a Memory {
+    type Item = &'a i32;
+    type IntoIter = Box<dyn Iterator<Item = &'a i32> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl PartialEq for Memory {
+    fn eq(&self, other: &Memory) -> bool {
+        self.len == other.len && self.differing_pages(other).all(|index| self.page(index) == other.page(index))
+    }
+}
+
+impl Eq for Memory {}
+
+impl Hash for Memory {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Pages of zeros hash the same whether they are kept or not
+        self.len.hash(state);
+        for (index, page) in self.pages.iter().filter(|(_, page)| page.iter().any(|&word| word != 0)) {
+            index.hash(state);
+            page.hash(state);
+        }
+    }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A memory of `len` words holding `words`, as (address, word)
+    fn memory(len: usize, words: &[(usize, i32)]) -> Memory {
+        let mut memory = Memory::new(len);
+        for &(address, word) in words {
+            memory[address] = word;
+        }
+        memory
+    }
+
+    #[test]
+    fn test_clones_share_pages() {
+        let memory = memory(1000, &[(5, 7), (999, -1)]);
+        assert_eq!(memory.clone().shared_pages(&memory), 2);
+    }
+
+    #[test]
+    fn test_writes_copy_only_their_page() {
+        let memory = memory(1000, &[(5, 7), (999, -1)]);
+        let mut fork = memory.clone();
+        fork[300] = 9;
+        // Writing a word with its own value still copies the page
+        fork[5] = 7;
+        assert_eq!((fork.shared_pages(&memory), memory[300], fork[300]), (1, 0, 9));
+    }
+
+    #[test]
+    fn test_changes_and_equality() {
+        let memory = memory(1000, &[(5, 7)]);
+        let mut fork = memory.clone();
+        fork[300] = 9;
+        fork[5] = 7;
+        assert_eq!(fork.changes(&memory).collect::<Vec<_>>(), [(300, 9)]);
+        assert_ne!(fork, memory);
+        fork[300] = 0;
+        assert_eq!(fork, memory);
+    }
+
+    #[test]
+    fn test_from_words() {
+        let words: Vec<i32> = (0..1000).collect();
+        let copy = Memory::from(words.clone());
+        assert_eq!((copy.len(), copy.to_vec()), (1000, words));
+    }
+
+    #[test]
+    fn test_update_copies_only_changed_pages() {
+        let mut copy = Memory::from((0..1000).collect::<Vec<_>>());
+        let before = copy.clone();
+        copy.update(|word| if word >= 900 { 0 } else { word });
+        assert_eq!((copy.shared_pages(&before), copy.get(950)), (3, Some(&0)));
+    }
+
+    #[test]
+    fn test_resize_drops_and_adds_words() {
+        let mut copy = Memory::from((0..1000).collect::<Vec<_>>());
+        copy.resize(300);
+        copy.resize(2000);
+        assert_eq!((copy.len(), copy[299], copy[300], copy[1500]), (2000, 299, 0, 0));
+    }
+
+    #[test]
+    fn test_only_written_pages_take_room() {
+        let large = memory(1 << 30, &[(0x2000_0000, 5)]);
+        assert_eq!(large.words().filter(|&(_, word)| word != 0).collect::<Vec<_>>(), [(0x2000_0000, 5)]);
+        assert_eq!(large.words().count(), PAGE_WORDS);
+        assert_eq!(large, memory(1 << 30, &[(0x2000_0000, 5)]));
+    }
+
+    #[test]
+    fn test_reads_past_the_end() {
+        let memory = memory(1000, &[(999, -1)]);
+        assert_eq!((memory.get(999), memory.get(1000), memory.get(usize::MAX)), (Some(&-1), None, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "memory address 1000 out of range for 1000 words")]
+    fn test_writes_past_the_end() {
+        memory(1000, &[(1000, 1)]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Named regions of memory, for the layouts of microcontrollers.
+//!
+//! A memory map, from the `memory-map` setting of a configuration file or
+//! `--memory-map`, lists regions with their start address, what a program may
+//! do there and their size:
+//!
+//! ```text
+//! flash @0x0800_0000 ro 128K
+//! sram @0x2000_0000 rw 20K
+//! peripheral @0x4000_0000 mmio
+//! ```
+//!
+//! Memory is then as large as the highest region, the data labels start in the
+//! first `rw` region, a store into a `ro` region fails, and so does any access
+//! outside every region. `mmio` regions hold device registers, which the data
+//! cache of `--cache` never keeps: a load or store there goes to the [`Device`]
+//! attached at the word, or to memory for a word a `--script` device or
+//! `--keyboard` sets between instructions, and fails for a word no device
+//! answers. START and SIZE are in bytes, as in a
+//! datasheet, and START must be word aligned; a program's addresses count words,
+//! as everywhere else, so a region starts at word START / 4.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::MEMORY_SIZE;
+
+/// The words of the largest memory: one for every address a register holds as a non-negative number.
+pub const MAX_WORDS: usize = 1 << 31;
+
+// The bytes of an `mmio` region given without a size
+const MMIO_BYTES: usize = 4 << 10;
+
+/// What a program may do in a region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+    /// Device registers, read and written past the cache
+    Mmio,
+}
+
+/// A named range of memory words.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    /// The address of the first word, counting words
+    pub start: usize,
+    pub words: usize,
+    pub access: Access,
+}
+
+impl Region {
+    /// The address after the last word.
+    pub fn end(&self) -> usize {
+        self.start + self.words
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start..self.end()).contains(&address)
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parses `NAME @START ro|rw|mmio [SIZE]`, with START a byte address; only `mmio`
+    /// regions may leave out the size.
+    fn from_str(text: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid region '{}', expected NAME @START ro|rw|mmio SIZE, e.g. sram @0x2000_0000 rw 20K", text);
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let (name, start, access, size) = match fields[..] {
+            [name, start, access] => (name, start, access, None),
+            [name, start, access, size] => (name, start, access, Some(size)),
+            _ => return Err(invalid()),
+        };
+        // Byte addresses go past those of an i32, e.g. @0xE000_E000
+        let start = start.strip_prefix('@').map(|start| start.replace('_', ""))
+            .and_then(|start| match start.strip_prefix("0x").or(start.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => start.parse::<u64>().ok(),
+            })
+            .and_then(|start| usize::try_from(start).ok()).ok_or_else(invalid)?;
+        let access = match access {
+            "ro" => Access::ReadOnly,
+            "rw" => Access::ReadWrite,
+            "mmio" => Access::Mmio,
+            _ => return Err(invalid()),
+        };
+        let bytes = match size {
+            Some(size) => parse_size(size)?,
+            None if access == Access::Mmio => MMIO_BYTES as u64,
+            None => return Err(format!("the region '{}' needs a size, e.g. {} 20K", name, text)),
+        };
+        if !start.is_multiple_of(4) {
+            return Err(format!("the region '{}' must start at a multiple of 4 bytes, e.g. @0x{:X}", name, start & !3));
+        }
+        if bytes == 0 || !bytes.is_multiple_of(4) {
+            return Err(format!("the size of region '{}' must be a whole number of 4-byte words", name));
+        }
+        let region = Region { name: name.to_string(), start: start / 4, words: (bytes / 4) as usize, access };
+        if region.end() > MAX_WORDS {
+            return Err(format!("the region '{}' ends past the last address, byte 0x{:X}", name, MAX_WORDS * 4 - 1));
+        }
+        Ok(region)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.access {
+            Access::ReadOnly => "ro",
+            Access::ReadWrite => "rw",
+            Access::Mmio => "mmio",
+        };
+        write!(f, "{} @0x{:X} {} {}", self.name, self.start * 4, access, self.words * 4)
+    }
+}
+
+/// Parses a size in bytes with an optional K, M or G suffix, e.g. "20K".
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (digits, unit) = match text.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&text[..at], 1 << 10),
+        Some((at, 'M' | 'm')) => (&text[..at], 1 << 20),
+        Some((at, 'G' | 'g')) => (&text[..at], 1 << 30),
+        _ => (text, 1),
+    };
+    digits.replace('_', "").parse::<u64>().ok().and_then(|count| count.checked_mul(unit))
+        .ok_or(format!("invalid size '{}', expected bytes with an optional K, M or G, e.g. 64K", text))
+}
+
+/// Parses a size of the whole memory such as "64K": a whole number of words from
+/// the default memory to the largest.
+pub fn parse_memory_bytes(text: &str) -> Result<u64, String> {
+    let bytes = parse_size(text)?;
+    let range = (MEMORY_SIZE as u64 * 4)..=(MAX_WORDS as u64 * 4);
+    if !range.contains(&bytes) || !bytes.is_multiple_of(4) {
+        return Err(format!("invalid size '{}', expected a multiple of 4 bytes from 4K to 8G", text.trim()));
+    }
+    Ok(bytes)
+}
+
+/// The regions of memory, none of which overlap.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    /// The map of `regions`, describing the first two that overlap on failure.
+    pub fn new(mut regions: Vec<Region>) -> Result<Self, String> {
+        regions.sort_by_key(|region| region.start);
+        if let Some(pair) = regions.windows(2).find(|pair| pair[0].end() > pair[1].start) {
+            return Err(format!("the regions '{}' and '{}' overlap", pair[0].name, pair[1].name));
+        }
+        Ok(MemoryMap { regions })
+    }
+
+    /// The regions, by start address.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// The words memory needs to hold every region.
+    pub fn words(&self) -> usize {
+        self.regions.last().map_or(0, Region::end)
+    }
+
+    pub fn region(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    /// Where data labels start: the first `rw` region, else address 0.
+    pub fn data_start(&self) -> usize {
+        self.regions.iter().find(|region| region.access == Access::ReadWrite).map_or(0, |region| region.start)
+    }
+
+    /// Whether the word at `address` is a device register.
+    pub fn is_mmio(&self, address: usize) -> bool {
+        self.region(address).is_some_and(|region| region.access == Access::Mmio)
+    }
+
+    /// Checks that a program may read, or with `write` write, the word at `address`.
+    pub fn check(&self, address: usize, write: bool) -> Result<(), Box<Diagnostic>> {
+        match self.region(address) {
+            None => Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                format!("Memory access outside the memory map: address 0x{:X} (byte 0x{:X})", address, address * 4))
+                .with_note(format!("the regions are {}", self.regions.iter().map(Region::to_string).collect::<Vec<_>>().join(", "))))),
+            Some(region) if write && region.access == Access::ReadOnly => Err(Box::new(Diagnostic::error(Code::ReadOnlyMemory,
+                format!("Cannot write to read-only memory: address 0x{:X} (byte 0x{:X}) is in {}", address, address * 4, region.name))
+                .with_note(format!("the region is {}", region)))),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// A device whose registers are words of an `mmio` region, answering a program's
+/// loads and stores there in place of memory.
+pub trait Device {
+    /// The word at `offset` words into the device's registers.
+    fn read(&mut self, offset: usize) -> Result<i32, Box<Diagnostic>>;
+    /// Takes the word a program stores at `offset` words into the registers.
+    fn write(&mut self, offset: usize, value: i32) -> Result<(), Box<Diagnostic>>;
+}
+
+/// The devices that answer the words of `mmio` regions.
+#[derive(Default)]
+pub struct Devices {
+    // Words a --script device or the keyboard sets between instructions, which stay in memory
+    polled: BTreeSet<usize>,
+    attached: Vec<(String, Range<usize>, Box<dyn Device>)>,
+}
+
+impl Devices {
+    /// Lets a program load and store the word at `address` in memory, where a
+    /// device outside the program sets or watches it between instructions.
+    pub fn poll(&mut self, address: usize) {
+        self.polled.insert(address);
+    }
+
+    /// Attaches `device`, named `name` for messages, at the words `range`, which
+    /// no other device may have.
+    pub fn attach(&mut self, name: &str, range: Range<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        if let Some((other, ..)) = self.attached.iter().find(|(_, other, _)| other.start < range.end && range.start < other.end) {
+            return Err(format!("the devices '{}' and '{}' overlap", other, name));
+        }
+        self.attached.push((name.to_string(), range, device));
+        Ok(())
+    }
+
+    /// The word a device gives for a load at `address`, or None for a word kept in memory.
+    pub fn read(&mut self, address: usize) -> Result<Option<i32>, Box<Diagnostic>> {
+        match self.answering(address)? {
+            Some((device, offset)) => device.read(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Hands a store of `value` at `address` to its device, giving whether one took
+    /// it, or false for a word kept in memory.
+    pub fn write(&mut self, address: usize, value: i32) -> Result<bool, Box<Diagnostic>> {
+        match self.answering(address)? {
+            Some((device, offset)) => device.write(offset, value).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    // The device attached at `address` with the offset into its registers, None for a polled
+    // word, or an error for a word that nothing answers
+    fn answering(&mut self, address: usize) -> Result<Option<(&mut dyn Device, usize)>, Box<Diagnostic>> {
+        if let Some(index) = self.attached.iter().position(|(_, range, _)| range.contains(&address)) {
+            let (_, range, device) = &mut self.attached[index];
+            return Ok(Some((device.as_mut(), address - range.start)));
+        }
+        if self.polled.contains(&address) {
+            return Ok(None);
+        }
+        let attached = self.attached.iter().map(|(name, range, _)| format!("'{}' at 0x{:X}..0x{:X}", name, range.start, range.end));
+        let polled = self.polled.iter().map(|address| format!("0x{:X}", address));
+        let known: Vec<_> = attached.chain(polled).collect();
+        Err(Box::new(Diagnostic::error(Code::NoDevice, format!("No device at address 0x{:X} (byte 0x{:X})", address, address * 4))
+            .with_note(if known.is_empty() {
+                "the address is in an mmio region, but no device is attached; --script devices, --keyboard and plugins attach them".to_string()
+            } else {
+                format!("the devices are at {}", known.join(", "))
+            })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The map of a board with flash, RAM and device registers
+    fn board() -> MemoryMap {
+        MemoryMap::new(regions(&["flash @0x0800_0000 ro 128K", "sram @0x2000_0000 rw 20K", "peripheral @0x4000_0000 mmio"])).unwrap()
+    }
+
+    fn regions(texts: &[&str]) -> Vec<Region> {
+        texts.iter().map(|text| text.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        let regions = regions(&["sram @0x2000_0000 rw 20K", "peripheral @0x4000_0000 mmio"]);
+        // The start is given in bytes and kept in words
+        assert_eq!(regions[0], Region { name: "sram".to_string(), start: 0x0800_0000, words: 5 << 10, access: Access::ReadWrite });
+        // An mmio region without a size has 4K
+        assert_eq!(regions[1].words, 1 << 10);
+        assert_eq!("scs @0xE000_E000 mmio 4K".parse::<Region>().unwrap().start, 0x3800_3800);
+    }
+
+    #[test]
+    fn test_display_regions() {
+        assert_eq!(regions(&["flash @0x0800_0000 ro 128K"])[0].to_string(), "flash @0x8000000 ro 131072");
+    }
+
+    #[test]
+    fn test_layout() {
+        let map = board();
+        assert_eq!((map.words(), map.data_start()), (0x1000_0400, 0x0800_0000));
+        assert!(map.is_mmio(0x1000_0010) && !map.is_mmio(0x0800_0010));
+    }
+
+    #[test]
+    fn test_accesses_inside_regions() {
+        let map = board();
+        assert!(map.check(0x0800_0000 + 5119, true).is_ok() && map.check(0x0200_0000, false).is_ok());
+    }
+
+    #[test]
+    fn test_writes_to_read_only_regions() {
+        assert_eq!(board().check(0x0200_0001, true).unwrap_err().code, Code::ReadOnlyMemory);
+    }
+
+    #[test]
+    fn test_accesses_outside_every_region() {
+        let error = board().check(0x0800_1400, false).unwrap_err();
+        assert_eq!((error.code, error.message.as_str()), (Code::MemoryOutOfBounds, "Memory access outside the memory map: address 0x8001400 (byte 0x20005000)"));
+    }
+
+    #[test]
+    fn test_invalid_regions() {
+        assert!("sram @0x2000_0000 rw".parse::<Region>().unwrap_err().contains("needs a size"));
+        assert!("sram 0x2000_0000 rw 1K".parse::<Region>().unwrap_err().starts_with("invalid region"));
+        assert!("sram @0x2000_0000 rwx 1K".parse::<Region>().unwrap_err().starts_with("invalid region"));
+        assert!("sram @-4 rw 1K".parse::<Region>().unwrap_err().starts_with("invalid region"));
+        assert!("sram @0 rw 6".parse::<Region>().unwrap_err().contains("whole number of 4-byte words"));
+        assert!("sram @0 rw 0".parse::<Region>().unwrap_err().contains("whole number of 4-byte words"));
+        assert!("sram @0 rw lots".parse::<Region>().unwrap_err().starts_with("invalid size"));
+        assert!("top @0x1_FFFF_FFFC rw 8".parse::<Region>().unwrap_err().contains("past the last address"));
+        assert_eq!("sram @0x2000_0002 rw 1K".parse::<Region>().unwrap_err(), "the region 'sram' must start at a multiple of 4 bytes, e.g. @0x20000000");
+    }
+
+    #[test]
+    fn test_overlapping_regions() {
+        assert_eq!(MemoryMap::new(regions(&["a @0 rw 8", "b @4 ro 4"])), Err("the regions 'a' and 'b' overlap".to_string()));
+        // Regions that only touch do not overlap
+        assert!(MemoryMap::new(regions(&["a @0 rw 8", "b @8 ro 4"])).is_ok());
+    }
+
+    // Registers that count the loads, and where a store to the first fails
+    #[derive(Default)]
+    struct Timer {
+        reads: i32,
+    }
+
+    impl Device for Timer {
+        fn read(&mut self, offset: usize) -> Result<i32, Box<Diagnostic>> {
+            self.reads += 1;
+            Ok(self.reads * 100 + offset as i32)
+        }
+
+        fn write(&mut self, offset: usize, _value: i32) -> Result<(), Box<Diagnostic>> {
+            match offset {
+                0 => Err(Box::new(Diagnostic::error(Code::ReadOnlyMemory, "the count is read-only"))),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_attached_devices() {
+        let mut devices = Devices::default();
+        devices.attach("timer", 0x1000_0000..0x1000_0004, Box::new(Timer::default())).unwrap();
+        assert_eq!((devices.read(0x1000_0002).unwrap(), devices.read(0x1000_0000).unwrap()), (Some(102), Some(200)));
+        assert!(devices.write(0x1000_0003, 7).unwrap());
+        assert_eq!(devices.write(0x1000_0000, 7).unwrap_err().message, "the count is read-only");
+        assert_eq!(devices.attach("uart", 0x1000_0003..0x1000_0005, Box::new(Timer::default())), Err("the devices 'timer' and 'uart' overlap".to_string()));
+    }
+
+    #[test]
+    fn test_polled_words() {
+        let mut devices = Devices::default();
+        devices.poll(0x1000_0010);
+        assert_eq!((devices.read(0x1000_0010).unwrap(), devices.write(0x1000_0010, 1).unwrap()), (None, false));
+        let error = devices.read(0x1000_0011).unwrap_err();
+        assert_eq!((error.code, error.notes[0].as_str()), (Code::NoDevice, "the devices are at 0x10000010"));
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!((parse_size("64K"), parse_size("1_024"), parse_size("4g")), (Ok(64 << 10), Ok(1024), Ok(4 << 30)));
+        assert!(parse_size("99999999999999999999G").is_err());
+    }
+}
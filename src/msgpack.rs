@@ -0,0 +1,309 @@
+//! A compact binary form of JSON values, in [MessagePack](https://msgpack.org),
+//! for states sent or saved often, such as the answers of the web playground to
+//! each step and snapshots with much memory.
+//!
+//! A memory word takes 1 to 5 bytes instead of up to 11 characters, and the
+//! brackets, commas and quotes of JSON go away. Numbers take the smallest form
+//! that holds them, so any MessagePack library reads the result.
+
+use serde_json::{Map, Number, Value};
+
+/// The MIME type of MessagePack, for `Accept` and `Content-Type`.
+pub const MIME_TYPE: &str = "application/msgpack";
+
+// Arrays and maps nested deeper are refused, as serde_json does, rather than overflowing the stack
+const MAX_DEPTH: usize = 128;
+
+/// `value` in MessagePack.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write(&mut bytes, value);
+    bytes
+}
+
+/// The value in `bytes`, describing the first problem on failure.
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, at: 0, depth: 0 };
+    let value = reader.value()?;
+    if reader.at != bytes.len() {
+        return Err(format!("unexpected bytes after the value at byte {}", reader.at));
+    }
+    Ok(value)
+}
+
+fn write(bytes: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => bytes.push(0xC0),
+        Value::Bool(value) => bytes.push(if *value { 0xC3 } else { 0xC2 }),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => write_unsigned(bytes, value),
+            (None, Some(value)) => write_signed(bytes, value),
+            _ => {
+                bytes.push(0xCB);
+                bytes.extend_from_slice(&number.as_f64().unwrap_or(0.0).to_be_bytes());
+            },
+        },
+        Value::String(text) => {
+            write_length(bytes, text.len(), 0xA0, 32, [0xD9, 0xDA, 0xDB]);
+            bytes.extend_from_slice(text.as_bytes());
+        },
+        Value::Array(items) => {
+            write_length(bytes, items.len(), 0x90, 16, [0, 0xDC, 0xDD]);
+            for item in items {
+                write(bytes, item);
+            }
+        },
+        Value::Object(entries) => {
+            write_length(bytes, entries.len(), 0x80, 16, [0, 0xDE, 0xDF]);
+            for (key, item) in entries {
+                write(bytes, &Value::String(key.clone()));
+                write(bytes, item);
+            }
+        },
+    }
+}
+
+fn write_unsigned(bytes: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x7F => bytes.push(value as u8),
+        0x80..=0xFF => bytes.extend_from_slice(&[0xCC, value as u8]),
+        0x100..=0xFFFF => {
+            bytes.push(0xCD);
+            bytes.extend_from_slice(&(value as u16).to_be_bytes());
+        },
+        0x1_0000..=0xFFFF_FFFF => {
+            bytes.push(0xCE);
+            bytes.extend_from_slice(&(value as u32).to_be_bytes());
+        },
+        _ => {
+            bytes.push(0xCF);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        },
+    }
+}
+
+// A negative value, in the smallest form that holds it
+fn write_signed(bytes: &mut Vec<u8>, value: i64) {
+    if value >= -32 {
+        bytes.push(value as u8);
+    } else if value >= i8::MIN as i64 {
+        bytes.extend_from_slice(&[0xD0, value as u8]);
+    } else if value >= i16::MIN as i64 {
+        bytes.push(0xD1);
+        bytes.extend_from_slice(&(value as i16).to_be_bytes());
+    } else if value >= i32::MIN as i64 {
+        bytes.push(0xD2);
+        bytes.extend_from_slice(&(value as i32).to_be_bytes());
+    } else {
+        bytes.push(0xD3);
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+// The header of a string, array or map of `length`: a fix form below `fix_limit`,
+// else the markers of the 8-bit (0 for none), 16-bit and 32-bit forms
+fn write_length(bytes: &mut Vec<u8>, length: usize, fix: u8, fix_limit: usize, markers: [u8; 3]) {
+    if length < fix_limit {
+        bytes.push(fix | length as u8);
+    } else if markers[0] != 0 && length <= 0xFF {
+        bytes.extend_from_slice(&[markers[0], length as u8]);
+    } else if length <= 0xFFFF {
+        bytes.push(markers[1]);
+        bytes.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        bytes.push(markers[2]);
+        bytes.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    // Arrays and maps around the value being read
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        let bytes = self.bytes.get(self.at..self.at.saturating_add(count)).ok_or("the value ends early")?;
+        self.at += count;
+        Ok(bytes)
+    }
+
+    // The next `N` bytes as a big-endian number
+    fn number<const N: usize>(&mut self) -> Result<u64, String> {
+        Ok(self.take(N)?.iter().fold(0, |number, &byte| number << 8 | byte as u64))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let marker = self.take(1)?[0];
+        Ok(match marker {
+            0x00..=0x7F => Value::from(marker),
+            0x80..=0x8F => self.map(marker as usize & 0x0F)?,
+            0x90..=0x9F => self.array(marker as usize & 0x0F)?,
+            0xA0..=0xBF => self.string(marker as usize & 0x1F)?,
+            0xC0 => Value::Null,
+            0xC2 => Value::Bool(false),
+            0xC3 => Value::Bool(true),
+            0xCA => Value::from(f32::from_bits(self.number::<4>()? as u32) as f64),
+            0xCB => Number::from_f64(f64::from_bits(self.number::<8>()?)).map_or(Value::Null, Value::Number),
+            0xCC => Value::from(self.number::<1>()?),
+            0xCD => Value::from(self.number::<2>()?),
+            0xCE => Value::from(self.number::<4>()?),
+            0xCF => Value::from(self.number::<8>()?),
+            0xD0 => Value::from(self.number::<1>()? as u8 as i8),
+            0xD1 => Value::from(self.number::<2>()? as u16 as i16),
+            0xD2 => Value::from(self.number::<4>()? as u32 as i32),
+            0xD3 => Value::from(self.number::<8>()? as i64),
+            0xD9 => {
+                let length = self.number::<1>()? as usize;
+                self.string(length)?
+            },
+            0xDA => {
+                let length = self.number::<2>()? as usize;
+                self.string(length)?
+            },
+            0xDB => {
+                let length = self.number::<4>()? as usize;
+                self.string(length)?
+            },
+            0xDC => {
+                let length = self.number::<2>()? as usize;
+                self.array(length)?
+            },
+            0xDD => {
+                let length = self.number::<4>()? as usize;
+                self.array(length)?
+            },
+            0xDE => {
+                let length = self.number::<2>()? as usize;
+                self.map(length)?
+            },
+            0xDF => {
+                let length = self.number::<4>()? as usize;
+                self.map(length)?
+            },
+            0xE0..=0xFF => Value::from(marker as i8),
+            _ => return Err(format!("unsupported marker 0x{:02X} at byte {}", marker, self.at - 1)),
+        })
+    }
+
+    fn string(&mut self, length: usize) -> Result<Value, String> {
+        let at = self.at;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map(Value::String).map_err(|_| format!("invalid UTF-8 in the string at byte {}", at))
+    }
+
+    fn array(&mut self, length: usize) -> Result<Value, String> {
+        self.enter()?;
+        let array = (0..length).map(|_| self.value()).collect::<Result<_, _>>().map(Value::Array);
+        self.depth -= 1;
+        array
+    }
+
+    fn map(&mut self, length: usize) -> Result<Value, String> {
+        self.enter()?;
+        let map = self.entries(length);
+        self.depth -= 1;
+        map
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("more than {} nested arrays and maps at byte {}", MAX_DEPTH, self.at - 1));
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self, length: usize) -> Result<Value, String> {
+        let mut entries = Map::new();
+        for _ in 0..length {
+            let at = self.at;
+            let Value::String(key) = self.value()? else {
+                return Err(format!("expected a string key at byte {}", at));
+            };
+            entries.insert(key, self.value()?);
+        }
+        Ok(Value::Object(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip(value: Value) {
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(json!({
+            "registers": [["r0", 5], ["sp", -1], ["pc", 70000]],
+            "flags": [["Z", true], ["C", false]],
+            "next_line": null,
+            "ratio": 0.5,
+            "name": "x".repeat(40),
+        }));
+    }
+
+    #[test]
+    fn test_round_trip_of_extreme_numbers() {
+        round_trip(json!([0, -1, -32, -33, 127, 128, i32::MIN, i32::MAX, i64::MIN, u64::MAX]));
+    }
+
+    #[test]
+    fn test_round_trip_of_long_collections() {
+        round_trip(json!((0..70_000).collect::<Vec<_>>()));
+        round_trip(Value::Object((0..20).map(|key| (key.to_string(), json!(key))).collect()));
+        round_trip(json!("y".repeat(300)));
+    }
+
+    #[test]
+    fn test_numbers_take_the_smallest_form() {
+        assert_eq!(encode(&json!([1, -1, -33, 200, "ab"])), [0x95, 0x01, 0xFF, 0xD0, 0xDF, 0xCC, 0xC8, 0xA2, b'a', b'b']);
+    }
+
+    #[test]
+    fn test_memory_is_smaller_than_json() {
+        let memory = json!((0..1024).map(|address| [address, address * 1000 - 5000]).collect::<Vec<_>>());
+        assert!(encode(&memory).len() * 3 < memory.to_string().len() * 2);
+    }
+
+    #[test]
+    fn test_values_that_end_early_are_rejected() {
+        assert_eq!(decode(&[0x92, 0x01]), Err("the value ends early".to_string()));
+        assert_eq!(decode(&[0xDD, 0xFF, 0xFF, 0xFF, 0xFF]), Err("the value ends early".to_string()));
+        assert_eq!(decode(&[]), Err("the value ends early".to_string()));
+    }
+
+    #[test]
+    fn test_bytes_after_the_value_are_rejected() {
+        assert_eq!(decode(&[0x01, 0x02]), Err("unexpected bytes after the value at byte 1".to_string()));
+    }
+
+    #[test]
+    fn test_unsupported_markers_are_rejected() {
+        assert_eq!(decode(&[0x91, 0xC1]), Err("unsupported marker 0xC1 at byte 1".to_string()));
+        assert_eq!(decode(&[0xC4, 0x00]), Err("unsupported marker 0xC4 at byte 0".to_string()));
+    }
+
+    #[test]
+    fn test_keys_that_are_not_strings_are_rejected() {
+        assert_eq!(decode(&[0x81, 0x01, 0x01]), Err("expected a string key at byte 1".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_rejected() {
+        assert_eq!(decode(&[0xA2, b'a', 0xFF]), Err("invalid UTF-8 in the string at byte 1".to_string()));
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        round_trip((0..MAX_DEPTH - 1).fold(json!([]), |inner, _| json!([inner])));
+        let nested = vec![0x91; 100_000];
+        assert_eq!(decode(&nested), Err(format!("more than {} nested arrays and maps at byte {}", MAX_DEPTH, MAX_DEPTH)));
+    }
+}
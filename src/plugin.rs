@@ -0,0 +1,536 @@
+//! Plugins for `--plugin`: dynamic libraries that add instructions, services and
+//! devices to ARM mode, so a course can extend the interpreter without forking it.
+//!
+//! A plugin is a crate built as a `cdylib` that exports `asm_plugin`, returning a
+//! [`PluginDef`] that lives as long as the library:
+//!
+//! ```text
+//! use std::ffi::c_char;
+//! use asm::plugin::{Core, InstructionDef, Operand, PluginDef, ServiceDef, ABI_VERSION};
+//!
+//! extern "C" fn rotate(core: *mut Core, operands: *const Operand, _count: usize) -> i32 {
+//!     let core = unsafe { &mut *core };
+//!     let (rd, rm) = unsafe { (*operands, *operands.add(1)) };
+//!     let registers = unsafe { std::slice::from_raw_parts_mut(core.registers, core.register_count) };
+//!     registers[rd.value as usize] = registers[rm.value as usize].rotate_left(8);
+//!     0
+//! }
+//!
+//! static INSTRUCTIONS: [InstructionDef; 1] = [InstructionDef { mnemonic: c"ROT8".as_ptr(), operands: 2, execute: rotate }];
+//! static PLUGIN: PluginDef = PluginDef {
+//!     abi_version: ABI_VERSION,
+//!     name: c"bits".as_ptr(),
+//!     instructions: INSTRUCTIONS.as_ptr(),
+//!     instruction_count: 1,
+//!     services: std::ptr::null(),
+//!     service_count: 0,
+//!     devices: std::ptr::null(),
+//!     device_count: 0,
+//! };
+//!
+//! #[unsafe(no_mangle)]
+//! pub extern "C" fn asm_plugin() -> *const PluginDef {
+//!     &PLUGIN
+//! }
+//! ```
+//!
+//! An instruction takes a fixed number of operands, each a register or an immediate
+//! such as `#5`, and a service runs on `SVC #n` for a number the interpreter does
+//! not use. Both see the sixteen registers and reach memory through the core's
+//! `load` and `store`, which check the memory map and count the access as the
+//! program's own LDR and STR do, and return 0, or a code of their own choosing to
+//! stop the program with an error.
+//!
+//! A device has registers at a byte address in an `mmio` region of the memory map,
+//! and answers the program's loads and stores there. It is attached when the map
+//! has such a region around its registers.
+
+use std::ffi::{c_char, c_void, CStr};
+use std::ops::Range;
+use std::sync::{Mutex, PoisonError};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::instructions;
+use crate::interpreter::{self, Machine};
+use crate::memory_map::{Access, Device};
+use crate::semihosting;
+
+/// The version of the structures below. A plugin built for another version is refused.
+pub const ABI_VERSION: u32 = 2;
+
+/// The registers of the core running a plugin's instruction or service, and its memory.
+#[repr(C)]
+pub struct Core {
+    pub registers: *mut i32,
+    pub register_count: usize,
+    /// Reads the word at `address` into `*word`, returning 0, or 1 where the program may not read
+    pub load: extern "C" fn(core: *mut Core, address: usize, word: *mut i32) -> i32,
+    /// Writes `value` to the word at `address`, returning 0, or 1 where the program may not write
+    pub store: extern "C" fn(core: *mut Core, address: usize, value: i32) -> i32,
+    // The Accessors that load and store go through
+    accessors: *mut c_void,
+}
+
+/// An operand of a plugin's instruction: a register, by its number, or an immediate.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operand {
+    /// [`Operand::REGISTER`] or [`Operand::IMMEDIATE`]
+    pub kind: u32,
+    pub value: i32,
+}
+
+impl Operand {
+    pub const REGISTER: u32 = 0;
+    pub const IMMEDIATE: u32 = 1;
+}
+
+/// An instruction a plugin adds.
+#[repr(C)]
+pub struct InstructionDef {
+    /// The mnemonic, NUL-terminated
+    pub mnemonic: *const c_char,
+    /// How many operands it takes
+    pub operands: u32,
+    /// Runs the instruction on `count` operands, returning 0 or an error code
+    pub execute: extern "C" fn(core: *mut Core, operands: *const Operand, count: usize) -> i32,
+}
+
+/// A service a plugin adds, run by `SVC #number`.
+#[repr(C)]
+pub struct ServiceDef {
+    pub number: u32,
+    /// Runs the service, returning 0 or an error code
+    pub call: extern "C" fn(core: *mut Core) -> i32,
+}
+
+/// A device a plugin adds, whose registers are words of an `mmio` region.
+#[repr(C)]
+pub struct DeviceDef {
+    /// The name of the device for messages, NUL-terminated
+    pub name: *const c_char,
+    /// The byte address of the first register, a multiple of 4
+    pub address: u64,
+    /// How many registers, a word each, it has
+    pub words: usize,
+    /// Reads the register `offset` words in into `*word`, returning 0 or an error code
+    pub read: extern "C" fn(offset: usize, word: *mut i32) -> i32,
+    /// Takes `value`, stored to the register `offset` words in, returning 0 or an error code
+    pub write: extern "C" fn(offset: usize, value: i32) -> i32,
+}
+
+/// What a plugin adds, returned by its `asm_plugin` function.
+#[repr(C)]
+pub struct PluginDef {
+    /// [`ABI_VERSION`] when the plugin was built
+    pub abi_version: u32,
+    /// The name of the plugin for messages, NUL-terminated
+    pub name: *const c_char,
+    pub instructions: *const InstructionDef,
+    pub instruction_count: usize,
+    pub services: *const ServiceDef,
+    pub service_count: usize,
+    pub devices: *const DeviceDef,
+    pub device_count: usize,
+}
+
+// SAFETY: definitions are only read, and a plugin keeps them unchanged for as long as it is loaded
+unsafe impl Sync for PluginDef {}
+unsafe impl Sync for InstructionDef {}
+unsafe impl Sync for ServiceDef {}
+unsafe impl Sync for DeviceDef {}
+
+type Execute = extern "C" fn(*mut Core, *const Operand, usize) -> i32;
+type Call = extern "C" fn(*mut Core) -> i32;
+type Read = extern "C" fn(usize, *mut i32) -> i32;
+type Write = extern "C" fn(usize, i32) -> i32;
+
+// The instructions, services and devices of every plugin loaded, with the name of the plugin
+#[derive(Default)]
+struct Registry {
+    instructions: Vec<(String, String, u32, Execute)>,
+    services: Vec<(String, u32, Call)>,
+    devices: Vec<PluginDevice>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { instructions: Vec::new(), services: Vec::new(), devices: Vec::new() });
+
+/// Loads the plugin at `path` and adds its instructions and services.
+pub fn load(path: &str) -> Result<(), Box<Diagnostic>> {
+    let failed = |message: String| Box::new(Diagnostic::error(Code::PluginFailed, format!("Cannot load plugin {}: {}", path, message)));
+    let plugin = open(path).map_err(failed)?;
+    // SAFETY: asm_plugin returns a definition that lives as long as the library, which is never closed
+    unsafe { register(plugin) }.map_err(failed)
+}
+
+#[cfg(unix)]
+fn open(path: &str) -> Result<*const PluginDef, String> {
+    use std::ffi::CString;
+    let dlerror = || {
+        // SAFETY: dlerror returns NULL or a NUL-terminated message
+        let message = unsafe { libc::dlerror() };
+        if message.is_null() { "unknown error".to_string() } else { unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned() }
+    };
+    let file = CString::new(path).map_err(|_| "the path contains a NUL byte".to_string())?;
+    // SAFETY: both strings are NUL-terminated, and the library stays loaded for the rest of the run
+    let library = unsafe { libc::dlopen(file.as_ptr(), libc::RTLD_NOW) };
+    if library.is_null() {
+        return Err(dlerror());
+    }
+    let entry = unsafe { libc::dlsym(library, c"asm_plugin".as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("it does not export asm_plugin ({})", dlerror()));
+    }
+    // SAFETY: a plugin's asm_plugin has this signature
+    let asm_plugin: extern "C" fn() -> *const PluginDef = unsafe { std::mem::transmute(entry) };
+    Ok(asm_plugin())
+}
+
+#[cfg(not(unix))]
+fn open(_path: &str) -> Result<*const PluginDef, String> {
+    Err("plugins are only supported on Unix".to_string())
+}
+
+/// Adds the instructions, services and devices of a plugin definition.
+///
+/// # Safety
+///
+/// `plugin` must point to a [`PluginDef`] whose strings and arrays stay valid for the rest of the run.
+pub unsafe fn register(plugin: *const PluginDef) -> Result<(), String> {
+    let plugin = unsafe { plugin.as_ref() }.ok_or("asm_plugin returned no definition")?;
+    if plugin.abi_version != ABI_VERSION {
+        return Err(format!("it was built for plugin ABI version {}, but this interpreter has version {}", plugin.abi_version, ABI_VERSION));
+    }
+    let text = |text: *const c_char| if text.is_null() { None } else { Some(unsafe { CStr::from_ptr(text) }.to_string_lossy().into_owned()) };
+    let name = text(plugin.name).ok_or("the plugin has no name")?;
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut instructions = Vec::new();
+    for instruction in unsafe { slice(plugin.instructions, plugin.instruction_count) } {
+        let mnemonic = text(instruction.mnemonic).filter(|mnemonic| !mnemonic.is_empty()).ok_or("an instruction has no mnemonic")?.to_uppercase();
+        if instructions::lookup(&mnemonic).is_some() {
+            return Err(format!("{} is already an instruction", mnemonic));
+        }
+        if let Some((other, ..)) = registry.instructions.iter().chain(&instructions).find(|(_, defined, ..)| *defined == mnemonic) {
+            return Err(format!("{} is already added by plugin {}", mnemonic, other));
+        }
+        instructions.push((name.clone(), mnemonic, instruction.operands, instruction.execute));
+    }
+    let mut services = Vec::new();
+    for service in unsafe { slice(plugin.services, plugin.service_count) } {
+        if interpreter::SERVICES.contains(&service.number) || semihosting::SVC.contains(&service.number) {
+            return Err(format!("SVC #{} is a service of the interpreter", service.number));
+        }
+        if let Some((other, ..)) = registry.services.iter().chain(&services).find(|(_, number, _)| *number == service.number) {
+            return Err(format!("SVC #{} is already added by plugin {}", service.number, other));
+        }
+        services.push((name.clone(), service.number, service.call));
+    }
+    let mut devices = Vec::new();
+    for device in unsafe { slice(plugin.devices, plugin.device_count) } {
+        let device_name = text(device.name).filter(|name| !name.is_empty()).ok_or("a device has no name")?;
+        if !device.address.is_multiple_of(4) || device.words == 0 {
+            return Err(format!("the device {} needs a word-aligned address and at least one register", device_name));
+        }
+        let start = usize::try_from(device.address / 4).map_err(|_| format!("the device {} is past the last address", device_name))?;
+        let words = start.checked_add(device.words).ok_or(format!("the device {} is past the last address", device_name))?;
+        devices.push(PluginDevice { plugin: name.clone(), name: device_name, words: start..words, read: device.read, write: device.write });
+    }
+    registry.instructions.extend(instructions);
+    registry.services.extend(services);
+    registry.devices.extend(devices);
+    Ok(())
+}
+
+// The `count` elements at `data`, which may be null when there are none
+unsafe fn slice<'a, T>(data: *const T, count: usize) -> &'a [T] {
+    if count == 0 { &[] } else { unsafe { std::slice::from_raw_parts(data, count) } }
+}
+
+/// The index and operand count of the instruction a plugin added as `mnemonic`, ignoring case.
+pub fn instruction(mnemonic: &str) -> Option<(usize, u32)> {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    registry.instructions.iter().position(|(_, defined, ..)| defined.eq_ignore_ascii_case(mnemonic))
+        .map(|index| (index, registry.instructions[index].2))
+}
+
+/// Runs the plugin instruction at `index` on a machine.
+pub fn execute(index: usize, operands: &[Operand], machine: &mut Machine) -> Result<(), Box<Diagnostic>> {
+    let (plugin, mnemonic, _, execute) = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner).instructions[index].clone();
+    let comes_from = format!("{} comes from plugin {}", mnemonic, plugin);
+    match with_core(machine, |core| execute(core, operands.as_ptr(), operands.len())) {
+        Err(error) => Err(Box::new((*error).with_note(comes_from))),
+        Ok(0) => Ok(()),
+        Ok(code) => Err(Box::new(Diagnostic::error(Code::PluginFailed, format!("{} failed with code {}", mnemonic, code)).with_note(comes_from))),
+    }
+}
+
+/// Runs the service a plugin added as `SVC #number` on a machine, if one did.
+pub fn call(number: u32, machine: &mut Machine) -> Option<Result<(), Box<Diagnostic>>> {
+    let (plugin, _, call) = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner).services.iter().find(|(_, defined, _)| *defined == number)?.clone();
+    let comes_from = format!("the service comes from plugin {}", plugin);
+    Some(match with_core(machine, |core| call(core)) {
+        Err(error) => Err(Box::new((*error).with_note(comes_from))),
+        Ok(0) => Ok(()),
+        Ok(code) => Err(Box::new(Diagnostic::error(Code::PluginFailed, format!("SVC #{} failed with code {}", number, code)).with_note(comes_from))),
+    })
+}
+
+/// Attaches the devices of every plugin to a machine, each where its memory map has an
+/// `mmio` region around its registers.
+pub fn attach_devices(machine: &mut Machine) -> Result<(), Box<Diagnostic>> {
+    let Some(map) = machine.memory_map.as_ref() else {
+        return Ok(());
+    };
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    let mapped: Vec<_> = registry.devices.iter().filter(|device| map.region(device.words.start)
+        .is_some_and(|region| region.access == Access::Mmio && device.words.end <= region.end())).collect();
+    for device in mapped {
+        machine.devices.attach(&device.name, device.words.clone(), Box::new(device.clone()))
+            .map_err(|message| Box::new(Diagnostic::error(Code::PluginFailed, format!("Cannot attach the device {}: {}", device.name, message))
+                .with_note(format!("{} comes from plugin {}", device.name, device.plugin))))?;
+    }
+    Ok(())
+}
+
+// What the load and store of a Core go through: the machine, and the first access it refused
+struct Accessors<'a> {
+    machine: &'a mut Machine,
+    refused: Option<Box<Diagnostic>>,
+}
+
+// Runs a plugin's function on a copy of the registers, with loads and stores going through the
+// machine's checked accessors. An access they refuse fails the call, whatever the function returns.
+fn with_core(machine: &mut Machine, run: impl FnOnce(&mut Core) -> i32) -> Result<i32, Box<Diagnostic>> {
+    let mut registers = machine.registers;
+    let mut accessors = Accessors { machine, refused: None };
+    let mut core = Core { registers: registers.as_mut_ptr(), register_count: registers.len(), load: load_word, store: store_word,
+        accessors: (&raw mut accessors).cast() };
+    let result = run(&mut core);
+    accessors.machine.registers = registers;
+    match accessors.refused {
+        Some(error) => Err(error),
+        None => Ok(result),
+    }
+}
+
+extern "C" fn load_word(core: *mut Core, address: usize, word: *mut i32) -> i32 {
+    // SAFETY: the core is the one with_core made for the running call, which the plugin hands back
+    let accessors = unsafe { &mut *(*core).accessors.cast::<Accessors>() };
+    match accessors.machine.load(address) {
+        Ok(value) => {
+            // SAFETY: the plugin gives a place for the word
+            unsafe { *word = value };
+            0
+        },
+        Err(error) => {
+            accessors.refused.get_or_insert(error);
+            1
+        },
+    }
+}
+
+extern "C" fn store_word(core: *mut Core, address: usize, value: i32) -> i32 {
+    // SAFETY: as in load_word
+    let accessors = unsafe { &mut *(*core).accessors.cast::<Accessors>() };
+    match accessors.machine.store(address, value) {
+        Ok(()) => 0,
+        Err(error) => {
+            accessors.refused.get_or_insert(error);
+            1
+        },
+    }
+}
+
+// A device of a plugin, with the words of its registers
+#[derive(Clone)]
+struct PluginDevice {
+    plugin: String,
+    name: String,
+    words: Range<usize>,
+    read: Read,
+    write: Write,
+}
+
+impl PluginDevice {
+    fn failed(&self, what: &str, offset: usize, code: i32) -> Box<Diagnostic> {
+        Box::new(Diagnostic::error(Code::PluginFailed, format!("The device {} failed with code {} {} register {}", self.name, code, what, offset))
+            .with_note(format!("{} comes from plugin {}", self.name, self.plugin)))
+    }
+}
+
+impl Device for PluginDevice {
+    fn read(&mut self, offset: usize) -> Result<i32, Box<Diagnostic>> {
+        let mut word = 0;
+        match (self.read)(offset, &mut word) {
+            0 => Ok(word),
+            code => Err(self.failed("reading", offset, code)),
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: i32) -> Result<(), Box<Diagnostic>> {
+        match (self.write)(offset, value) {
+            0 => Ok(()),
+            code => Err(self.failed("writing", offset, code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Once;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+    use crate::memory_map::MemoryMap;
+
+    extern "C" fn swap(core: *mut Core, operands: *const Operand, count: usize) -> i32 {
+        let (core, operands) = unsafe { (&mut *core, std::slice::from_raw_parts(operands, count)) };
+        let registers = unsafe { std::slice::from_raw_parts_mut(core.registers, core.register_count) };
+        registers.swap(operands[0].value as usize, operands[1].value as usize);
+        0
+    }
+
+    // Adds the immediate to the word at r0, or fails with 7 for a negative immediate
+    extern "C" fn add_to_memory(core: *mut Core, operands: *const Operand, _count: usize) -> i32 {
+        let (core, operand) = unsafe { (&mut *core, *operands) };
+        if operand.value < 0 {
+            return 7;
+        }
+        let address = unsafe { *core.registers } as usize;
+        let mut word = 0;
+        if (core.load)(core, address, &mut word) != 0 {
+            return 1;
+        }
+        (core.store)(core, address, word + operand.value)
+    }
+
+    extern "C" fn double_r0(core: *mut Core) -> i32 {
+        unsafe { *(*core).registers *= 2 };
+        0
+    }
+
+    // A serial port: a status register that always reads 1 and cannot be written, then a data register
+    static UART_DATA: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn uart_read(offset: usize, word: *mut i32) -> i32 {
+        unsafe { *word = if offset == 0 { 1 } else { UART_DATA.load(Ordering::Relaxed) } };
+        0
+    }
+
+    extern "C" fn uart_write(offset: usize, value: i32) -> i32 {
+        if offset == 0 {
+            return 5;
+        }
+        UART_DATA.store(value, Ordering::Relaxed);
+        0
+    }
+
+    static INSTRUCTIONS: [InstructionDef; 2] = [
+        InstructionDef { mnemonic: c"XSWAP".as_ptr(), operands: 2, execute: swap },
+        InstructionDef { mnemonic: c"XADDM".as_ptr(), operands: 1, execute: add_to_memory },
+    ];
+    static SERVICES: [ServiceDef; 1] = [ServiceDef { number: 40, call: double_r0 }];
+    static DEVICES: [DeviceDef; 1] = [DeviceDef { name: c"uart".as_ptr(), address: 0x6000_0000, words: 2, read: uart_read, write: uart_write }];
+    static PLUGIN: PluginDef = PluginDef {
+        abi_version: ABI_VERSION,
+        name: c"test".as_ptr(),
+        instructions: INSTRUCTIONS.as_ptr(),
+        instruction_count: 2,
+        services: SERVICES.as_ptr(),
+        service_count: 1,
+        devices: DEVICES.as_ptr(),
+        device_count: 1,
+    };
+
+    // Registers the test plugin once for the tests, which share the registry
+    fn registered() {
+        static REGISTERED: Once = Once::new();
+        REGISTERED.call_once(|| unsafe { register(&PLUGIN) }.unwrap());
+    }
+
+    fn run_with(program: &str, regions: &[&str]) -> Result<Machine, Vec<Diagnostic>> {
+        registered();
+        let memory_map = (!regions.is_empty()).then(|| MemoryMap::new(regions.iter().map(|region| region.parse().unwrap()).collect()).unwrap());
+        let options = RunOptions { memory_map, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink()))
+    }
+
+    fn run(program: &str) -> Result<Machine, Vec<Diagnostic>> {
+        run_with(program, &[])
+    }
+
+    #[test]
+    fn test_instructions() {
+        let cpu = run("MOV r0, #100\nMOV r1, #3\nxswap r0, r1").unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[1]), (3, 100));
+    }
+
+    #[test]
+    fn test_services() {
+        assert_eq!(run("MOV r0, #21\nSVC #40").unwrap().registers[0], 42);
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        let cpu = run("MOV r0, #6\nXADDM #5\nXADDM #6").unwrap();
+        assert_eq!(cpu.memory[6], 11);
+        // They are counted as the program's own
+        assert_eq!((cpu.accesses.reads(6), cpu.accesses.writes(6)), (2, 2));
+    }
+
+    #[test]
+    fn test_failing_instruction() {
+        let errors = run("XADDM #-1").err().unwrap();
+        assert_eq!(errors[0].message, "XADDM failed with code 7");
+        assert_eq!(errors[0].notes, ["XADDM comes from plugin test"]);
+    }
+
+    #[test]
+    fn test_refused_store() {
+        // The store the memory map refuses stops the program, even though the plugin returns 0 after it
+        let errors = run_with("MOV r0, #0\nXADDM #1", &["flash @0 ro 1K", "sram @0x2000_0000 rw 1K"]).err().unwrap();
+        assert_eq!((errors[0].code, errors[0].span.as_ref().map(|span| span.line)), (Code::ReadOnlyMemory, Some(2)));
+        assert!(errors[0].notes.contains(&"XADDM comes from plugin test".to_string()));
+    }
+
+    #[test]
+    fn test_devices() {
+        let board = ["sram @0x2000_0000 rw 1K", "uart @0x6000_0000 mmio 16"];
+        let cpu = run_with("LDR r0, #0x18000000\nMOV r1, #65\nSTR r1, #0x18000001\nLDR r2, #0x18000001\nEXIT", &board).unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[2]), (1, 65));
+        let errors = run_with("MOV r1, #65\nSTR r1, #0x18000000", &board).err().unwrap();
+        assert_eq!(errors[0].message, "The device uart failed with code 5 writing register 0");
+        // The device is only attached where the map has an mmio region for it
+        let errors = run_with("LDR r0, #0x18000001", &["sram @0x2000_0000 rw 1K", "other @0x6000_0010 mmio 16"]).err().unwrap();
+        assert_eq!(errors[0].code, Code::MemoryOutOfBounds);
+    }
+
+    #[test]
+    fn test_operand_count() {
+        assert_eq!(run("XSWAP r0").err().unwrap()[0].code, Code::WrongOperandCount);
+    }
+
+    #[test]
+    fn test_conflicting_plugins() {
+        registered();
+        assert_eq!(unsafe { register(&PLUGIN) }.unwrap_err(), "XSWAP is already added by plugin test");
+    }
+
+    #[test]
+    fn test_misaligned_device() {
+        static DEVICES: [DeviceDef; 1] = [DeviceDef { name: c"timer".as_ptr(), address: 0x6000_0002, words: 1, read: uart_read, write: uart_write }];
+        static PLUGIN: PluginDef = PluginDef {
+            abi_version: ABI_VERSION,
+            name: c"misaligned".as_ptr(),
+            instructions: std::ptr::null(),
+            instruction_count: 0,
+            services: std::ptr::null(),
+            service_count: 0,
+            devices: DEVICES.as_ptr(),
+            device_count: 1,
+        };
+        assert_eq!(unsafe { register(&PLUGIN) }.unwrap_err(), "the device timer needs a word-aligned address and at least one register");
+    }
+}
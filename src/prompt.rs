@@ -0,0 +1,115 @@
+//! The prompt of interactive mode, set with `--prompt` or `prompt` in a
+//! configuration file. It is shown as written except for fields in braces,
+//! which are filled in before each line is read: `{r0}` a register in its
+//! radix, `{Z}` a flag (in capitals) as 0 or 1, `{pc}` the number the next instruction typed
+//! gets and `{count}` the word at a data label, so
+//! `[pc={pc} Z={Z} r0={r0}]> ` shows as `[pc=14 Z=1 r0=5]> `. `{{` and `}}`
+//! stand for braces.
+
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Prompt {
+    parts: Vec<Part>,
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Prompt { parts: vec![Part::Text("> ".to_string())] }
+    }
+}
+
+impl Prompt {
+    /// The prompt with each field replaced by what `value` gives for its name, or `?`
+    /// for a name it does not know, such as a data label not defined yet.
+    pub fn render(&self, value: impl Fn(&str) -> Option<String>) -> String {
+        self.parts.iter().map(|part| match part {
+            Part::Text(text) => text.clone(),
+            Part::Field(name) => value(name).unwrap_or_else(|| "?".to_string()),
+        }).collect()
+    }
+}
+
+impl FromStr for Prompt {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| format!("'{{' without '}}' in prompt \"{}\"", format))?;
+                    let name = rest[..end].trim();
+                    if name.is_empty() || name.contains('{') {
+                        return Err(format!("expected a register, flag, pc or data label in '{{{}}}'", &rest[..end]));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(name.to_string()));
+                    chars = rest[end + 1..].chars();
+                },
+                '}' => return Err(format!("'}}' without '{{' in prompt \"{}\"; write '}}}}' for a brace", format)),
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Prompt { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(name: &str) -> Option<String> {
+        match name {
+            "pc" => Some("14".to_string()),
+            "Z" => Some("1".to_string()),
+            "r0" => Some("5".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_fields() {
+        let prompt: Prompt = "[pc={pc} Z={Z} r0={ r0 }]> ".parse().unwrap();
+        assert_eq!(prompt.render(value), "[pc=14 Z=1 r0=5]> ");
+    }
+
+    #[test]
+    fn test_escaped_braces_and_unknown_fields() {
+        assert_eq!("{{{count}}} ".parse::<Prompt>().unwrap().render(value), "{?} ");
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(Prompt::default().render(value), "> ");
+    }
+
+    #[test]
+    fn test_unbalanced_braces() {
+        assert!("{r0".parse::<Prompt>().unwrap_err().starts_with("'{' without '}'"));
+        assert!("r0}".parse::<Prompt>().is_err());
+        assert!("{}".parse::<Prompt>().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Number conversions for the `HEX`, `BIN`, `DEC` and `TWOS` commands, and the
+//! radix register values are shown in.
+//!
+//! Numbers are read in decimal, `0x` hex, `0b` binary or `0o` octal, optionally
+//! prefixed with `#` and with `_` between digits, and negative numbers are shown as the bit pattern a
+//! register of the instruction set would hold.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A conversion command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conversion {
+    Hex,
+    Bin,
+    Dec,
+    Twos,
+}
+
+impl Conversion {
+    /// The conversion a command name stands for, in any case.
+    pub fn from_command(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "HEX" => Some(Conversion::Hex),
+            "BIN" => Some(Conversion::Bin),
+            "DEC" => Some(Conversion::Dec),
+            "TWOS" => Some(Conversion::Twos),
+            _ => None,
+        }
+    }
+}
+
+/// How a register value is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Radix {
+    #[default]
+    Dec,
+    Hex,
+    Bin,
+}
+
+impl FromStr for Radix {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "dec" | "decimal" => Ok(Radix::Dec),
+            "hex" => Ok(Radix::Hex),
+            "bin" | "binary" => Ok(Radix::Bin),
+            _ => Err(format!("unknown radix '{}', expected dec, hex or bin", name)),
+        }
+    }
+}
+
+impl Radix {
+    /// Shows `value`, a register of `bits` bits: signed in decimal, else its bit pattern.
+    pub fn format(self, value: i64, bits: u32) -> String {
+        let pattern = value as u64 & (u64::MAX >> (64 - bits));
+        match self {
+            Radix::Dec => value.to_string(),
+            Radix::Hex => format!("0x{:X}", pattern),
+            Radix::Bin => format!("0b{:b}", pattern),
+        }
+    }
+}
+
+/// A radix for every register, like `hex`, or for one register, like `r7=bin`.
+/// `r7=default` makes a register follow the radix of every register again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub register: Option<String>,
+    pub radix: Option<Radix>,
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        match spec.split_once('=') {
+            Some((register, radix)) if radix.trim().eq_ignore_ascii_case("default") => {
+                Ok(Setting { register: Some(register.trim().to_lowercase()), radix: None })
+            },
+            Some((register, radix)) => Ok(Setting { register: Some(register.trim().to_lowercase()), radix: Some(radix.trim().parse()?) }),
+            None => Ok(Setting { register: None, radix: Some(spec.trim().parse()?) }),
+        }
+    }
+}
+
+/// The radix registers are shown in, by register name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Formats {
+    pub radix: Radix,
+    registers: HashMap<String, Radix>,
+}
+
+impl Formats {
+    pub fn set(&mut self, setting: &Setting) {
+        match (&setting.register, setting.radix) {
+            (Some(register), Some(radix)) => { self.registers.insert(register.to_lowercase(), radix); },
+            (Some(register), None) => { self.registers.remove(&register.to_lowercase()); },
+            (None, radix) => self.radix = radix.unwrap_or_default(),
+        }
+    }
+
+    /// Shows the value of the register named `register`, `bits` bits wide.
+    pub fn format(&self, register: &str, value: i64, bits: u32) -> String {
+        self.registers.get(&register.to_lowercase()).copied().unwrap_or(self.radix).format(value, bits)
+    }
+}
+
+/// Parses a number in any of the supported notations.
+pub fn parse_number(text: &str) -> Option<i128> {
+    let text = text.strip_prefix('#').unwrap_or(text);
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let magnitude = i128::try_from(parse_unsigned(digits)?.1).ok()?;
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Parses a number without a sign, such as `0b1010_1010`, returning its radix and value.
+pub fn parse_unsigned(text: &str) -> Option<(u32, u128)> {
+    let prefixed = |prefixes: [&str; 2]| prefixes.iter().find_map(|prefix| text.strip_prefix(prefix));
+    let (radix, digits) = if let Some(hex) = prefixed(["0x", "0X"]) {
+        (16, hex)
+    } else if let Some(bin) = prefixed(["0b", "0B"]) {
+        (2, bin)
+    } else if let Some(oct) = prefixed(["0o", "0O"]) {
+        (8, oct)
+    } else {
+        (10, text)
+    };
+    // from_str_radix accepts a sign of its own, and separators only go between digits
+    if digits.starts_with(['+', '-', '_']) || digits.ends_with('_') {
+        return None;
+    }
+    Some((radix, u128::from_str_radix(&digits.replace('_', ""), radix).ok()?))
+}
+
+// Bits in groups of 4, e.g. "0000 0101"
+fn grouped(pattern: u128, bits: u32) -> String {
+    let digits = format!("{:0width$b}", pattern, width = bits as usize);
+    digits.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap()).collect::<Vec<_>>().join(" ")
+}
+
+/// Converts `text` as the command asks, for registers of `bits` bits, or describes why it cannot.
+pub fn convert(conversion: Conversion, text: &str, bits: u32) -> Result<String, String> {
+    let value = parse_number(text).ok_or_else(|| format!("Invalid number: {}", text))?;
+    let (min, max) = (-(1i128 << (bits - 1)), (1i128 << bits) - 1);
+    if value < min || value > max {
+        return Err(format!("{} does not fit in {} bits", text, bits));
+    }
+    // The bit pattern a register would hold, and its signed reading
+    let pattern = (value & max) as u128;
+    let signed = if pattern >> (bits - 1) == 1 { pattern as i128 - (1i128 << bits) } else { pattern as i128 };
+    let text = text.strip_prefix('#').unwrap_or(text);
+    Ok(match conversion {
+        Conversion::Hex => format!("{} = 0x{:X}", text, pattern),
+        Conversion::Bin => format!("{} = 0b{:b}", text, pattern),
+        Conversion::Dec if value != signed && value == pattern as i128 => format!("{} = {} ({} as signed)", text, value, signed),
+        Conversion::Dec => format!("{} = {}", text, value),
+        Conversion::Twos => {
+            let mut shown = format!("{} in {} bits: {} (0x{:X})", text, bits, grouped(pattern, bits), pattern);
+            if value < 0 {
+                let magnitude = (-value) as u128 & max as u128;
+                shown.push_str(&format!("\n  = ~{} + 1: invert {} and add 1", -value, grouped(magnitude, bits)));
+            } else if signed < 0 {
+                shown.push_str(&format!("\n  the top bit is set, so as a signed number it is {}", signed));
+            }
+            shown
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(convert(Conversion::Hex, "255", 32), Ok("255 = 0xFF".to_string()));
+        assert_eq!(convert(Conversion::Hex, "-1", 32), Ok("-1 = 0xFFFFFFFF".to_string()));
+        assert_eq!(convert(Conversion::Bin, "#0xFF", 32), Ok("0xFF = 0b11111111".to_string()));
+        assert_eq!(convert(Conversion::Dec, "0b1010", 32), Ok("0b1010 = 10".to_string()));
+        assert_eq!(convert(Conversion::Dec, "0o17", 32), Ok("0o17 = 15".to_string()));
+        assert_eq!(convert(Conversion::Hex, "0b1010_1010", 32), Ok("0b1010_1010 = 0xAA".to_string()));
+        assert_eq!(convert(Conversion::Dec, "0xFFFFFFFE", 32), Ok("0xFFFFFFFE = 4294967294 (-2 as signed)".to_string()));
+        assert_eq!(convert(Conversion::Twos, "-5", 8), Ok("-5 in 8 bits: 1111 1011 (0xFB)\n  = ~5 + 1: invert 0000 0101 and add 1".to_string()));
+        assert_eq!(convert(Conversion::Twos, "200", 8), Ok("200 in 8 bits: 1100 1000 (0xC8)\n  the top bit is set, so as a signed number it is -56".to_string()));
+        assert_eq!(convert(Conversion::Hex, "-1", 64), Ok("-1 = 0xFFFFFFFFFFFFFFFF".to_string()));
+
+        assert_eq!(convert(Conversion::Hex, "0x100000000", 32), Err("0x100000000 does not fit in 32 bits".to_string()));
+        assert_eq!(convert(Conversion::Hex, "12z", 32), Err("Invalid number: 12z".to_string()));
+        assert_eq!(parse_number("0x-5"), None);
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut formats = Formats::default();
+        assert_eq!(formats.format("r0", -2, 32), "-2");
+        formats.set(&"hex".parse().unwrap());
+        formats.set(&"R7=bin".parse().unwrap());
+        assert_eq!(formats.format("r0", -2, 32), "0xFFFFFFFE");
+        assert_eq!(formats.format("r7", 5, 32), "0b101");
+        assert_eq!(formats.format("x1", -1, 64), "0xFFFFFFFFFFFFFFFF");
+        formats.set(&"r7=default".parse().unwrap());
+        assert_eq!(formats.format("r7", 5, 32), "0x5");
+        assert!("r7=octal".parse::<Setting>().is_err());
+    }
+}
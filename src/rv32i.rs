@@ -0,0 +1,566 @@
+//! RISC-V RV32I front-end, selected with `--isa rv32i`.
+//!
+//! There are 32 registers `x0`-`x31`, also available by their ABI names (`zero`,
+//! `ra`, `sp`, `a0`, `t0`, `s0`/`fp`, ...). `x0` always reads as zero. Immediates
+//! are written without a prefix (`addi t0, t0, 1`), although `#1` is accepted too.
+//! Labels and memory work as in ARM mode: memory is word-addressed, so `lw` and
+//! `sw` offsets count words.
+
+use std::collections::HashMap;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::instructions::{self, InstructionInfo, RV32I_INSTRUCTIONS};
+use crate::interpreter::{parse_immediate, Machine, MEMORY_SIZE};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+
+const NUM_REGISTERS: usize = 32;
+// Written by jal/call and read by ret
+const RETURN_ADDRESS: usize = 1;
+const STACK_POINTER: usize = 2;
+// s0, also called fp
+const FRAME_POINTER: usize = 8;
+
+/// ABI names of x0 through x31.
+const ABI_NAMES: [&str; NUM_REGISTERS] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+const REGISTER_NOTE: &str = "registers are x0 through x31, or ABI names such as zero, ra, sp, a0-a7, t0-t6 and s0-s11";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AluOp { Add, Sub, And, Or, Xor, Sll, Srl, Sra, Slt, Sltu }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchCond { Eq, Ne, Lt, Ge, Ltu, Geu }
+
+/// The memory operand of lw/sw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressOperand {
+    // offset(rs1)
+    RegisterOffset(usize, i32),
+    // A label, known when decoding
+    Absolute(usize),
+}
+
+/// An RV32I instruction with its operands checked and parsed. Pseudo-instructions
+/// such as `li`, `mv`, `j` and `ret` are decoded to the instructions they stand for.
+/// Branch targets are instruction indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Op { op: AluOp, rd: usize, rs1: usize, rs2: usize },
+    OpImm { op: AluOp, rd: usize, rs1: usize, imm: i32 },
+    Lui { rd: usize, imm: i32 },
+    Lw { rd: usize, address: AddressOperand },
+    Sw { rs2: usize, address: AddressOperand },
+    Branch { cond: BranchCond, rs1: usize, rs2: usize, target: usize },
+    Jal { rd: usize, target: usize },
+    Jalr { rd: usize, rs1: usize, offset: i32 },
+    // The register name is printed as written
+    Print { rs: usize, name: String },
+    Exit,
+}
+
+/// Registers of the simulated RV32I core, with its memory and labels.
+pub struct Cpu {
+    pub registers: [i32; NUM_REGISTERS],
+    pub machine: Machine,
+}
+
+impl AsRef<Machine> for Cpu {
+    fn as_ref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl AsMut<Machine> for Cpu {
+    fn as_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+/// The RISC-V RV32I base integer instruction set.
+pub struct Rv32i;
+
+impl Isa for Rv32i {
+    type Instruction = Instruction;
+    type Cpu = Cpu;
+
+    const NAME: &'static str = "rv32i";
+    const DESCRIPTION: &'static str = "RISC-V RV32I with ABI register names and common pseudo-instructions";
+    const INSTRUCTIONS: &'static [InstructionInfo] = RV32I_INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &RegisterFile { names: &ABI_NAMES, bits: 32 };
+    const STACK_POINTER: Option<usize> = Some(STACK_POINTER);
+    // a0
+    const CORE_REGISTER: usize = 10;
+
+    /// A core with the stack pointer at the top of memory and every other register zero.
+    fn new_cpu(&self) -> Cpu {
+        let mut registers = [0; NUM_REGISTERS];
+        registers[STACK_POINTER] = MEMORY_SIZE as i32;
+        Cpu { registers, machine: Machine::new() }
+    }
+
+    fn read_register(&self, cpu: &Cpu, index: usize) -> i64 {
+        cpu.registers[index] as i64
+    }
+
+    fn seed_registers(&self, cpu: &mut Cpu, registers: &[(usize, i32)]) {
+        for &(reg, val) in registers {
+            cpu.write(reg, val);
+        }
+    }
+
+    // x0 to x31 and fp as well as the ABI names
+    fn register_index(&self, name: &str) -> Option<usize> {
+        parse_register(name)
+    }
+
+    fn prepare(&self, cpu: &mut Cpu, len: usize) {
+        // ret from the entry point ends the program
+        cpu.registers[RETURN_ADDRESS] = len as i32;
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        decode(tokens, labels, report)
+    }
+
+    fn execute(&self, cpu: &mut Cpu, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        cpu.execute(instruction, pc, console)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Branch { .. } | Instruction::Jal { .. } | Instruction::Jalr { .. })
+    }
+
+    fn frame_offset(&self, instruction: &Instruction) -> Option<i32> {
+        match *instruction {
+            Instruction::Lw { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. }
+            | Instruction::Sw { address: AddressOperand::RegisterOffset(FRAME_POINTER, offset), .. } => Some(offset),
+            _ => None,
+        }
+    }
+
+    // Jumps that link a return address are calls; "j" and "ret" link to x0
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Jal { rd, .. } | Instruction::Jalr { rd, .. } if *rd != 0)
+    }
+
+    fn call_target(&self, instruction: &Instruction) -> Option<usize> {
+        match instruction {
+            Instruction::Jal { rd, target } if *rd != 0 => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn is_return(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Jalr { rd: 0, rs1: RETURN_ADDRESS, offset: 0 })
+    }
+
+    // Writes to x0 are how nop is written, so they are left alone
+    fn no_effect(&self, instruction: &Instruction) -> Option<&'static str> {
+        match *instruction {
+            Instruction::OpImm { op: AluOp::Add | AluOp::Or | AluOp::Xor | AluOp::Sll | AluOp::Srl | AluOp::Sra, rd, rs1, imm: 0 } if rd == rs1 && rd != 0 =>
+                Some("an immediate of 0 leaves the register as it was"),
+            Instruction::Op { op: AluOp::Add | AluOp::Sub | AluOp::Or | AluOp::Xor, rd, rs1, rs2: 0 } if rd == rs1 && rd != 0 =>
+                Some("x0 is always 0, which leaves the register as it was"),
+            _ => None,
+        }
+    }
+}
+
+impl Cpu {
+    fn write(&mut self, rd: usize, value: i32) {
+        // x0 is hard-wired to zero
+        if rd != 0 {
+            self.registers[rd] = value;
+        }
+    }
+
+    fn effective_address(&self, address: AddressOperand) -> Result<usize, Box<Diagnostic>> {
+        let address = match address {
+            AddressOperand::Absolute(addr) => addr as i64,
+            AddressOperand::RegisterOffset(base, offset) => self.registers[base] as i64 + offset as i64,
+        };
+        let size = self.machine.memory.len();
+        if address < 0 || address >= size as i64 {
+            return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                format!("Memory access out of bounds: address {} (MEMORY_SIZE {})", address, size))));
+        }
+        Ok(address as usize)
+    }
+
+    // Executes a decoded instruction at index `pc`
+    fn execute(&mut self, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        match *instruction {
+            Instruction::Exit => return Ok(Flow::Exit),
+            Instruction::Op { op, rd, rs1, rs2 } => {
+                let value = alu(op, self.registers[rs1], self.registers[rs2]);
+                self.write(rd, value);
+            },
+            Instruction::OpImm { op, rd, rs1, imm } => {
+                let value = alu(op, self.registers[rs1], imm);
+                self.write(rd, value);
+            },
+            Instruction::Lui { rd, imm } => self.write(rd, imm << 12),
+            Instruction::Lw { rd, address } => {
+                let address = self.effective_address(address)?;
+                let value = self.machine.load(address)?;
+                self.write(rd, value);
+            },
+            Instruction::Sw { rs2, address } => {
+                let address = self.effective_address(address)?;
+                self.machine.store(address, self.registers[rs2])?;
+            },
+            Instruction::Branch { cond, rs1, rs2, target } => {
+                let (a, b) = (self.registers[rs1], self.registers[rs2]);
+                let taken = match cond {
+                    BranchCond::Eq => a == b,
+                    BranchCond::Ne => a != b,
+                    BranchCond::Lt => a < b,
+                    BranchCond::Ge => a >= b,
+                    BranchCond::Ltu => (a as u32) < (b as u32),
+                    BranchCond::Geu => (a as u32) >= (b as u32),
+                };
+                if taken {
+                    return Ok(Flow::Jump(target));
+                }
+            },
+            Instruction::Jal { rd, target } => {
+                self.write(rd, pc as i32 + 1);
+                return Ok(Flow::Jump(target));
+            },
+            Instruction::Jalr { rd, rs1, offset } => {
+                // Read the target before writing rd, which may be the same register
+                let target = self.registers[rs1].wrapping_add(offset);
+                self.write(rd, pc as i32 + 1);
+                return Ok(Flow::Jump(target as u32 as usize));
+            },
+            Instruction::Print { rs, ref name } => {
+                let shown = self.machine.formats.format(ABI_NAMES[rs], self.registers[rs] as i64, Rv32i::REGISTERS.bits);
+                console.print(format_args!("{} = {}", name, shown))?;
+            },
+        }
+        Ok(Flow::Next)
+    }
+}
+
+fn alu(op: AluOp, a: i32, b: i32) -> i32 {
+    // Shifts use the low five bits of the amount, as RV32I does
+    let shamt = (b & 0x1F) as u32;
+    match op {
+        AluOp::Add => a.wrapping_add(b),
+        AluOp::Sub => a.wrapping_sub(b),
+        AluOp::And => a & b,
+        AluOp::Or => a | b,
+        AluOp::Xor => a ^ b,
+        AluOp::Sll => a << shamt,
+        AluOp::Srl => ((a as u32) >> shamt) as i32,
+        AluOp::Sra => a >> shamt,
+        AluOp::Slt => (a < b) as i32,
+        AluOp::Sltu => ((a as u32) < (b as u32)) as i32,
+    }
+}
+
+// Parses x0-x31, the ABI names and fp
+pub fn parse_register(reg: &str) -> Option<usize> {
+    let lower = reg.to_lowercase();
+    if lower == "fp" {
+        return Some(8);
+    }
+    if let Some(idx) = ABI_NAMES.iter().position(|name| *name == lower) {
+        return Some(idx);
+    }
+    lower.strip_prefix('x')?.parse::<usize>().ok().filter(|&idx| idx < NUM_REGISTERS)
+}
+
+// Checks the operand count and the commas between operands of an instruction,
+// returning the operands
+fn operands<'a>(tokens: &Tokens<'a>, count: usize, report: &Reporter) -> Option<Vec<&'a str>> {
+    let info = instructions::lookup_in(RV32I_INSTRUCTIONS, &tokens.mnemonic.to_lowercase());
+    tokens.expect_instruction(count, info, report)
+}
+
+fn register_operand(operand: &str, role: &str, report: &Reporter) -> Option<usize> {
+    let idx = parse_register(operand);
+    if idx.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidRegister,
+            format!("Invalid {}: {}", role, operand)).with_note(REGISTER_NOTE));
+    }
+    idx
+}
+
+// Parses an immediate, with or without '#', checking it lies in `min..=max`
+fn immediate_operand(operand: &str, min: i32, max: i32, report: &Reporter) -> Option<i32> {
+    let Some(val) = parse_immediate(operand.strip_prefix('#').unwrap_or(operand)) else {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("Invalid immediate: {}", operand))
+            .with_note("immediates are decimal (-5) or hexadecimal (0x10)"));
+        return None;
+    };
+    if val < min || val > max {
+        report.report(operand, Diagnostic::error(Code::InvalidOperand,
+            format!("Immediate {} is out of range", val))
+            .with_note(format!("the immediate must be {} to {}", min, max)));
+        return None;
+    }
+    Some(val)
+}
+
+// Parses offset(rs1) or a label
+fn address_operand(operand: &str, labels: &HashMap<String, usize>, report: &Reporter) -> Option<AddressOperand> {
+    if let Some(open) = operand.find('(') {
+        let Some(base) = operand[open + 1..].strip_suffix(')') else {
+            report.error(operand, Code::InvalidAddressOperand, format!("Invalid address operand format: {}", operand));
+            return None;
+        };
+        let rs1 = register_operand(base.trim(), "base register", report)?;
+        let offset_str = operand[..open].trim();
+        let offset = if offset_str.is_empty() { 0 } else { immediate_operand(offset_str, -2048, 2047, report)? };
+        Some(AddressOperand::RegisterOffset(rs1, offset))
+    } else if let Some(addr) = labels.get(operand) {
+        Some(AddressOperand::Absolute(*addr))
+    } else {
+        report.error(operand, Code::UndefinedLabel, format!("Undefined label: {}", operand));
+        None
+    }
+}
+
+fn branch_target(label: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    if let Some(index) = labels.code.get(label) {
+        Some(*index)
+    } else if labels.addresses.contains_key(label) {
+        report.error(label, Code::InvalidBranchTarget, format!("Cannot jump to '{}': it is a data label, not code.", label));
+        None
+    } else {
+        report.error(label, Code::UndefinedLabel, format!("Undefined label: {}", label));
+        None
+    }
+}
+
+// Decodes a single RV32I instruction (labels already stripped), reporting any errors.
+fn decode(tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+    let mnemonic = tokens.mnemonic.to_lowercase();
+    debug!("Decoding {} with operands {:?}", mnemonic, tokens.operands);
+    let ops = |count| operands(tokens, count, report);
+
+    let instruction = match mnemonic.as_str() {
+        "exit" => {
+            ops(0)?;
+            Instruction::Exit
+        },
+        "nop" => {
+            ops(0)?;
+            Instruction::OpImm { op: AluOp::Add, rd: 0, rs1: 0, imm: 0 }
+        },
+        "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" | "slt" | "sltu" => {
+            let ops = ops(3)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rs1 = register_operand(ops[1], "first source register", report)?;
+            let rs2 = register_operand(ops[2], "second source register", report)?;
+            let op = match mnemonic.as_str() {
+                "add" => AluOp::Add,
+                "sub" => AluOp::Sub,
+                "and" => AluOp::And,
+                "or" => AluOp::Or,
+                "xor" => AluOp::Xor,
+                "sll" => AluOp::Sll,
+                "srl" => AluOp::Srl,
+                "sra" => AluOp::Sra,
+                "slt" => AluOp::Slt,
+                _ => AluOp::Sltu,
+            };
+            Instruction::Op { op, rd, rs1, rs2 }
+        },
+        "addi" | "andi" | "ori" | "xori" | "slti" | "sltiu" | "slli" | "srli" | "srai" => {
+            let ops = ops(3)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rs1 = register_operand(ops[1], "source register", report)?;
+            // Shift amounts are five bits, other immediates twelve bits signed
+            let (min, max) = if matches!(mnemonic.as_str(), "slli" | "srli" | "srai") { (0, 31) } else { (-2048, 2047) };
+            let imm = immediate_operand(ops[2], min, max, report)?;
+            let op = match mnemonic.as_str() {
+                "addi" => AluOp::Add,
+                "andi" => AluOp::And,
+                "ori" => AluOp::Or,
+                "xori" => AluOp::Xor,
+                "slti" => AluOp::Slt,
+                "sltiu" => AluOp::Sltu,
+                "slli" => AluOp::Sll,
+                "srli" => AluOp::Srl,
+                _ => AluOp::Sra,
+            };
+            Instruction::OpImm { op, rd, rs1, imm }
+        },
+        "li" => {
+            let ops = ops(2)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let imm = immediate_operand(ops[1], i32::MIN, i32::MAX, report)?;
+            Instruction::OpImm { op: AluOp::Add, rd, rs1: 0, imm }
+        },
+        "mv" => {
+            let ops = ops(2)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rs1 = register_operand(ops[1], "source register", report)?;
+            Instruction::OpImm { op: AluOp::Add, rd, rs1, imm: 0 }
+        },
+        "lui" => {
+            let ops = ops(2)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let imm = immediate_operand(ops[1], 0, 0xFFFFF, report)?;
+            Instruction::Lui { rd, imm }
+        },
+        "lw" => {
+            let ops = ops(2)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let address = address_operand(ops[1], labels.addresses, report)?;
+            Instruction::Lw { rd, address }
+        },
+        "sw" => {
+            let ops = ops(2)?;
+            let rs2 = register_operand(ops[0], "source register", report)?;
+            let address = address_operand(ops[1], labels.addresses, report)?;
+            Instruction::Sw { rs2, address }
+        },
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "bgt" | "ble" => {
+            let ops = ops(3)?;
+            let rs1 = register_operand(ops[0], "first source register", report)?;
+            let rs2 = register_operand(ops[1], "second source register", report)?;
+            let target = branch_target(ops[2], labels, report)?;
+            // bgt and ble are blt and bge with the operands swapped
+            let (cond, rs1, rs2) = match mnemonic.as_str() {
+                "beq" => (BranchCond::Eq, rs1, rs2),
+                "bne" => (BranchCond::Ne, rs1, rs2),
+                "blt" => (BranchCond::Lt, rs1, rs2),
+                "bge" => (BranchCond::Ge, rs1, rs2),
+                "bltu" => (BranchCond::Ltu, rs1, rs2),
+                "bgeu" => (BranchCond::Geu, rs1, rs2),
+                "bgt" => (BranchCond::Lt, rs2, rs1),
+                _ => (BranchCond::Ge, rs2, rs1),
+            };
+            Instruction::Branch { cond, rs1, rs2, target }
+        },
+        "beqz" | "bnez" => {
+            let ops = ops(2)?;
+            let rs1 = register_operand(ops[0], "source register", report)?;
+            let target = branch_target(ops[1], labels, report)?;
+            let cond = if mnemonic == "beqz" { BranchCond::Eq } else { BranchCond::Ne };
+            Instruction::Branch { cond, rs1, rs2: 0, target }
+        },
+        "j" => {
+            let ops = ops(1)?;
+            Instruction::Jal { rd: 0, target: branch_target(ops[0], labels, report)? }
+        },
+        "call" => {
+            let ops = ops(1)?;
+            Instruction::Jal { rd: RETURN_ADDRESS, target: branch_target(ops[0], labels, report)? }
+        },
+        "jal" => {
+            // "jal label" links through ra
+            let count = if tokens.operands.len() > 1 { 2 } else { 1 };
+            let ops = ops(count)?;
+            let rd = if count == 2 { register_operand(ops[0], "destination register", report)? } else { RETURN_ADDRESS };
+            Instruction::Jal { rd, target: branch_target(ops[count - 1], labels, report)? }
+        },
+        "jalr" => {
+            let ops = ops(3)?;
+            let rd = register_operand(ops[0], "destination register", report)?;
+            let rs1 = register_operand(ops[1], "source register", report)?;
+            let offset = immediate_operand(ops[2], -2048, 2047, report)?;
+            Instruction::Jalr { rd, rs1, offset }
+        },
+        "ret" => {
+            ops(0)?;
+            Instruction::Jalr { rd: 0, rs1: RETURN_ADDRESS, offset: 0 }
+        },
+        "print" => {
+            let ops = ops(1)?;
+            let rs = register_operand(ops[0], "register", report)?;
+            Instruction::Print { rs, name: ops[0].to_string() }
+        },
+        _ => {
+            report.report(tokens.mnemonic, instructions::unknown(RV32I_INSTRUCTIONS, tokens.mnemonic));
+            return None;
+        }
+    };
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::interpreter::{run_sources, RunOptions};
+
+    fn run(script: &str) -> Cpu {
+        match run_sources(&Rv32i, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(cpu) => cpu,
+            Err(errors) => panic!("{}", errors.iter().map(|e| e.to_human()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    fn errors(script: &str) -> Vec<Diagnostic> {
+        match run_sources(&Rv32i, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(_) => panic!("expected the script to fail"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn test_x0_is_hard_wired_to_zero() {
+        let cpu = run("\
+            addi x0, x0, 5\n\
+            addi t0, zero, -3\n\
+            srli t1, t0, 28\n\
+            lui a0, 0x12345\n\
+            exit");
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.registers[5], -3);
+        assert_eq!(cpu.registers[6], 0xF);
+        assert_eq!(cpu.registers[10], 0x12345000);
+    }
+
+    #[test]
+    fn test_loop_and_call() {
+        let cpu = run("\
+            main: li a0, 0\n\
+            li t0, 1\n\
+            li t1, 11\n\
+            loop: add a0, a0, t0\n\
+            addi t0, t0, 1\n\
+            blt t0, t1, loop\n\
+            call double\n\
+            exit\n\
+            double: add a0, a0, a0\n\
+            ret");
+        assert_eq!(cpu.registers[10], 110);
+    }
+
+    #[test]
+    fn test_load_and_store_use_the_stack() {
+        let cpu = run("\
+            value: #21\n\
+            lw t0, value\n\
+            addi sp, sp, -1\n\
+            sw t0, 0(sp)\n\
+            lw a1, 0(sp)\n\
+            add a1, a1, a1\n\
+            exit");
+        assert_eq!(cpu.registers[11], 42);
+        assert_eq!(cpu.machine.memory[MEMORY_SIZE - 1], 21);
+    }
+
+    #[test]
+    fn test_immediate_range_is_checked() {
+        let errors = errors("addi t0, t0, 4096\n");
+        assert_eq!(errors[0].code, Code::InvalidOperand);
+        assert_eq!(errors[0].span.as_ref().unwrap().column_start, 14);
+    }
+
+    #[test]
+    fn test_register_names_for_set() {
+        assert_eq!((Rv32i.register_index("a0"), Rv32i.register_index("X5"), Rv32i.register_index("fp")), (Some(10), Some(5), Some(8)));
+        assert_eq!(Rv32i.register_index("r5"), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! Starter directories for assignments, created by `asm new lab1`.
+//!
+//! A new directory holds a program to start from, a debug script that checks
+//! its result, the output it is expected to print and an `.asmrc` with the
+//! options labs are usually run with, so the tests work from the first run.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::diagnostics::{Code, Diagnostic};
+
+/// The program, named so that `asm main.s` finds it by habit.
+pub const PROGRAM: &str = "main.s";
+/// Debugger commands checking the program, for `--debug-script`.
+pub const TEST: &str = "test.dbg";
+/// What the program prints, to compare with its output.
+pub const EXPECTED: &str = "expected.txt";
+/// Option defaults for runs in the directory.
+pub const CONFIG: &str = ".asmrc";
+
+const PROGRAM_TEMPLATE: &str = "\
+// {name}: replace this program with your solution.
+//
+// Run it with:   asm main.s
+// Test it with:  asm --debug-script test.dbg main.s
+
+_start:
+    MOV r0, #0          // the sum
+    MOV r1, #1          // the next number to add
+loop:
+    ADD r0, r0, r1
+    ADD r1, r1, #1
+    CMP r1, #10
+    BLE loop
+    PRINT r0
+    EXIT
+";
+
+const TEST_TEMPLATE: &str = "\
+// Checks for {name}, run with: asm --debug-script test.dbg main.s
+// Commands before `run` set breakpoints; the rest run once the program ends.
+run
+assert r0 == 55      // the sum of 1 to 10
+";
+
+const EXPECTED_TEMPLATE: &str = "r0 = 55\n";
+
+const CONFIG_TEMPLATE: &str = "\
+# Options for running {name}; the command line overrides them.
+isa = \"arm\"
+trap-overflow = true
+strict-bkpt = true
+";
+
+/// Creates `directory` with the starter files and returns their paths. The
+/// directory must not exist yet, so no one's work is overwritten.
+pub fn create(directory: &Path) -> Result<Vec<PathBuf>, Box<Diagnostic>> {
+    let failed = |path: &Path, error: std::io::Error| Box::new(Diagnostic::error(Code::OutputFailed,
+        format!("Could not create {}: {}", path.display(), error)));
+    fs::create_dir(directory).map_err(|error| failed(directory, error))?;
+    let name = directory.file_name().map_or_else(|| directory.display().to_string(), |name| name.to_string_lossy().into_owned());
+    let files = [(PROGRAM, PROGRAM_TEMPLATE), (TEST, TEST_TEMPLATE), (EXPECTED, EXPECTED_TEMPLATE), (CONFIG, CONFIG_TEMPLATE)];
+    let mut created = Vec::new();
+    for (file, template) in files {
+        let path = directory.join(file);
+        fs::write(&path, template.replace("{name}", &name)).map_err(|error| failed(&path, error))?;
+        created.push(path);
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::config::Config;
+    use crate::console::Console;
+    use crate::debugger::DebugScript;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    #[test]
+    fn test_create() {
+        let directory = std::env::temp_dir().join(format!("asm-new-{}", std::process::id()));
+        let created = create(&directory).unwrap();
+        let read = |file: &str| fs::read_to_string(directory.join(file)).unwrap();
+        assert_eq!(created.len(), 4);
+        assert!(read(PROGRAM).starts_with(&format!("// asm-new-{}: ", std::process::id())));
+        assert_eq!(Config::parse(&read(CONFIG)).unwrap().isa.as_deref(), Some("arm"));
+
+        // The program passes its own test and prints what is expected
+        let options = RunOptions { debug_script: Some(DebugScript::parse(TEST, &read(TEST)).unwrap()), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let sources = [(PROGRAM.to_string(), read(PROGRAM))];
+        assert!(run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).is_ok());
+        assert_eq!(String::from_utf8(out).unwrap(), read(EXPECTED));
+
+        assert_eq!(create(&directory).unwrap_err().code, Code::OutputFailed);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
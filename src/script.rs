@@ -0,0 +1,330 @@
+//! Scripts of devices, checks and trace filters, for `--script`.
+//!
+//! A script is [Rhai](https://rhai.rs), run once before the program to hand the
+//! interpreter functions to call during the run. Each function takes the core,
+//! whose registers, flags and data labels read as properties such as `core.r0`,
+//! `core.Z` or `core.screen`, with three more: `core.steps`, the instructions run
+//! so far, `core.line`, the source line of the current instruction, and
+//! `core.value`, the word a program wrote. `core.word(address)` reads memory:
+//!
+//! ```text
+//! // A timer: before each instruction, the word at 900 counts tens of instructions
+//! device(900, |core| core.steps / 10);
+//! // A display: each time the program changes the word at screen, what the function gives is printed
+//! on_write("screen", |core| core.value);
+//! // Only the steps of lines 3 to 6 go to --trace
+//! trace(|core| core.line >= 3 && core.line <= 6);
+//! // Checked once the program ends, which fails the run unless it holds
+//! check(|core| core.r0 == 55, "r0 should hold the sum");
+//! ```
+//!
+//! Devices and writes are at a memory address or a data label. A write that
+//! leaves the word as it was is not seen.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, ImmutableString, NativeCallContext, Position, AST};
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::interpreter::parse_immediate;
+use crate::memory::Memory;
+
+// Operations a call of the script may take, so a function that runs away stops the run
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Clone, Debug)]
+enum Rule {
+    // The word at a location, set before each instruction
+    Device { location: String, value: FnPtr },
+    // What to print when the program changes the word at a location
+    Write { location: String, print: FnPtr },
+    Trace(FnPtr),
+    Check { condition: FnPtr, message: Option<String> },
+}
+
+#[derive(Clone, Debug)]
+struct Line {
+    rule: Rule,
+    // Where the script called device, on_write, trace or check
+    position: Position,
+}
+
+/// A parsed script.
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    file: String,
+    text: String,
+    ast: AST,
+    lines: Vec<Line>,
+}
+
+/// What a script sees of the core, the argument of its functions.
+#[derive(Clone)]
+pub struct Core {
+    /// Registers, flags and data labels, by name
+    pub names: Vec<(String, i64)>,
+    pub memory: Memory,
+    pub steps: u64,
+    pub line: usize,
+    /// The word written, for `on_write`
+    pub value: Option<i64>,
+}
+
+impl Core {
+    fn get(&mut self, name: &str) -> Result<i64, Box<EvalAltResult>> {
+        self.names.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)).map(|&(_, value)| value)
+            .ok_or_else(|| format!("unknown name '{}'", name).into())
+    }
+
+    fn word(&mut self, address: i64) -> Result<i64, Box<EvalAltResult>> {
+        usize::try_from(address).ok().and_then(|address| self.memory.get(address)).map(|&word| word as i64)
+            .ok_or_else(|| format!("address {} is outside memory", address).into())
+    }
+}
+
+impl Script {
+    /// Runs the script `text` read from `file`, collecting the functions it hands over.
+    pub fn parse(file: &str, text: &str) -> Result<Self, Vec<Diagnostic>> {
+        let mut script = Script { file: file.to_string(), text: text.to_string(), ..Script::default() };
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = engine();
+        let add = |rule: fn(String, FnPtr) -> Rule| {
+            let lines = lines.clone();
+            move |context: NativeCallContext, location: Dynamic, function: FnPtr| -> Result<(), Box<EvalAltResult>> {
+                if !location.is_int() && !location.is_string() {
+                    return Err(format!("a location is a memory address or a data label, not {}", location.type_name()).into());
+                }
+                lines.borrow_mut().push(Line { rule: rule(location.to_string(), function), position: context.call_position() });
+                Ok(())
+            }
+        };
+        engine.register_fn("device", add(|location, value| Rule::Device { location, value }));
+        engine.register_fn("on_write", add(|location, print| Rule::Write { location, print }));
+        let add = |rule: fn(FnPtr, Option<String>) -> Rule| {
+            let lines = lines.clone();
+            move |context: NativeCallContext, function: FnPtr, message: Option<String>| {
+                lines.borrow_mut().push(Line { rule: rule(function, message), position: context.call_position() });
+            }
+        };
+        let trace = add(|condition, _| Rule::Trace(condition));
+        engine.register_fn("trace", move |context: NativeCallContext, condition: FnPtr| trace(context, condition, None));
+        let check = add(|condition, message| Rule::Check { condition, message });
+        let check_with = check.clone();
+        engine.register_fn("check", move |context: NativeCallContext, condition: FnPtr| check(context, condition, None));
+        engine.register_fn("check", move |context: NativeCallContext, condition: FnPtr, message: ImmutableString| {
+            check_with(context, condition, Some(message.to_string()));
+        });
+
+        script.ast = engine.compile(text).map_err(|error| vec![script.error(error.position(), Code::InvalidScript,
+            format!("Invalid script: {}", error.err_type()))])?;
+        engine.run_ast(&script.ast).map_err(|error| vec![*script.cannot_evaluate(Position::NONE, *error)])?;
+        script.lines = lines.take();
+        Ok(script)
+    }
+
+    /// Whether the script has devices, which change memory between instructions.
+    pub fn has_devices(&self) -> bool {
+        self.lines.iter().any(|line| matches!(line.rule, Rule::Device { .. }))
+    }
+
+    /// The script with the locations of its devices and writes looked up in `labels`,
+    /// in a memory of `memory_words` words.
+    pub fn start(&self, labels: &HashMap<String, usize>, memory_words: usize) -> Result<Running<'_>, Box<Diagnostic>> {
+        let mut addresses = Vec::new();
+        for line in &self.lines {
+            if let Rule::Device { location, .. } | Rule::Write { location, .. } = &line.rule {
+                let address = labels.get(location).copied()
+                    .or_else(|| parse_immediate(location).and_then(|value| usize::try_from(value).ok()))
+                    .filter(|&address| address < memory_words)
+                    .ok_or_else(|| Box::new(self.error(line.position, Code::InvalidScript, format!("Cannot map '{}'", location))
+                        .with_note(format!("expected a memory address below {} or a data label", memory_words))))?;
+                addresses.push(address);
+            }
+        }
+        Ok(Running { script: self, engine: engine(), addresses })
+    }
+
+    /// An error for each check that does not hold at the end of a run.
+    pub fn check(&self, core: &Core) -> Vec<Diagnostic> {
+        let engine = engine();
+        self.lines.iter().filter_map(|line| match &line.rule {
+            Rule::Check { condition, message } => match self.call(&engine, line, condition, core, Dynamic::as_bool, "a bool") {
+                Ok(true) => None,
+                Ok(false) => Some(self.error(line.position, Code::AssertionFailed, format!("Check failed: {}", self.source(line.position).trim()))
+                    .with_note(message.clone().unwrap_or_else(|| "the function gave false".to_string()))),
+                Err(error) => Some(*error),
+            },
+            _ => None,
+        }).collect()
+    }
+
+    // Calls a function the script handed over with the core, for a result `cast` takes as `expected`
+    fn call<T>(&self, engine: &Engine, line: &Line, function: &FnPtr, core: &Core,
+        cast: fn(&Dynamic) -> Result<T, &'static str>, expected: &str) -> Result<T, Box<Diagnostic>> {
+        let value = function.call::<Dynamic>(engine, &self.ast, (core.clone(),)).map_err(|error| self.cannot_evaluate(line.position, *error))?;
+        cast(&value).map_err(|found| Box::new(self.error(line.position, Code::InvalidScript,
+            format!("Cannot evaluate the script: the function gave {}, not {}", found, expected))))
+    }
+
+    // The source line at `position`, or an empty one
+    fn source(&self, position: Position) -> &str {
+        position.line().and_then(|line_no| self.text.lines().nth(line_no - 1)).unwrap_or_default()
+    }
+
+    fn error(&self, position: Position, code: Code, message: String) -> Diagnostic {
+        let Some(line_no) = position.line() else {
+            return Diagnostic::error(code, message).with_note(format!("in {}", self.file));
+        };
+        let errors = RefCell::new(Vec::new());
+        let source = self.source(position);
+        Reporter::new(&errors, &self.file, line_no, source).error(source.trim(), code, message);
+        errors.into_inner().remove(0)
+    }
+
+    // An error at where it happened in the script, or else at `fallback`
+    fn cannot_evaluate(&self, fallback: Position, mut error: EvalAltResult) -> Box<Diagnostic> {
+        while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = error {
+            error = *inner;
+        }
+        let position = error.take_position();
+        let position = if position.is_none() { fallback } else { position };
+        // What the interpreter's own functions fail with needs no "Runtime error" before it
+        let message = match error {
+            EvalAltResult::ErrorRuntime(ref value, _) => value.to_string(),
+            ref error => error.to_string(),
+        };
+        Box::new(self.error(position, Code::InvalidScript, format!("Cannot evaluate the script: {}", message)))
+    }
+}
+
+/// A script during a run, its locations resolved.
+pub struct Running<'a> {
+    script: &'a Script,
+    engine: Engine,
+    // The address of each device and write, in script order
+    addresses: Vec<usize>,
+}
+
+impl Running<'_> {
+    /// The words of the devices before the next instruction, as (address, value).
+    pub fn devices(&self, core: &Core) -> Result<Vec<(usize, i32)>, Box<Diagnostic>> {
+        self.located().filter_map(|(line, address)| match &line.rule {
+            Rule::Device { value, .. } => Some(self.script.call(&self.engine, line, value, core, Dynamic::as_int, "an integer").map(|value| (address, value as i32))),
+            _ => None,
+        }).collect()
+    }
+
+    /// The words the script watches for writes, to compare after the next instruction.
+    pub fn watched(&self, memory: &Memory) -> Vec<i32> {
+        self.located().filter(|(line, _)| matches!(line.rule, Rule::Write { .. })).map(|(_, address)| memory[address]).collect()
+    }
+
+    /// Prints for each watched word that differs from what [`Running::watched`] gave before.
+    pub fn written(&self, before: &[i32], core: &Core, console: &mut Console) -> Result<(), Box<Diagnostic>> {
+        let writes = self.located().filter_map(|(line, address)| match &line.rule {
+            Rule::Write { print, .. } => Some((line, print, address)),
+            _ => None,
+        });
+        for ((line, print, address), _) in writes.zip(before).filter(|&((_, _, address), &old)| core.memory[address] != old) {
+            let core = Core { value: Some(core.memory[address] as i64), ..core.clone() };
+            let value = self.script.call(&self.engine, line, print, &core, |value| Ok(value.clone()), "a value")?;
+            console.print(format_args!("{}", value))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the step just run goes to --trace: every trace filter holds.
+    pub fn traced(&self, core: &Core) -> Result<bool, Box<Diagnostic>> {
+        for line in &self.script.lines {
+            if let Rule::Trace(condition) = &line.rule
+                && !self.script.call(&self.engine, line, condition, core, Dynamic::as_bool, "a bool")? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The words the script's devices set and its writes watch.
+    pub fn addresses(&self) -> &[usize] {
+        &self.addresses
+    }
+
+    // The devices and writes with their addresses
+    fn located(&self) -> impl Iterator<Item = (&Line, usize)> {
+        self.script.lines.iter().filter(|line| matches!(line.rule, Rule::Device { .. } | Rule::Write { .. })).zip(self.addresses.iter().copied())
+    }
+}
+
+// An engine that knows the core
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.register_type_with_name::<Core>("Core")
+        .register_get("steps", |core: &mut Core| core.steps as i64)
+        .register_get("line", |core: &mut Core| core.line as i64)
+        .register_get("value", |core: &mut Core| core.value.ok_or_else(|| Box::<EvalAltResult>::from("value is only known to on_write")))
+        // Any other property is a register, flag or data label
+        .register_indexer_get(|core: &mut Core, name: &str| core.get(name))
+        .register_fn("word", Core::word);
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    // Sums 3 + 2 + 1, showing each term on the screen twice, then loads the word at r3, 900
+    const PROGRAM: &str = "screen: #0\nMOV r0, #0\nMOV r1, #3\nloop: ADD r0, r0, r1\nSTR r1, screen\nSTR r1, screen\nSUBS r1, r1, #1\nBNE loop\nLDR r2, [r3]\nEXIT";
+
+    // Runs PROGRAM with the script `rules`, giving its errors and output
+    fn run(rules: &str) -> (Vec<Diagnostic>, String) {
+        let options = RunOptions { registers: vec![(3, 900)], script: Some(Script::parse("rules.rhai", rules).unwrap()), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), PROGRAM.to_string())];
+        let result = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out));
+        (result.err().unwrap_or_default(), String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_devices() {
+        // The LDR runs after 17 instructions
+        let (errors, _) = run("device(900, |core| core.steps * 10);\ncheck(|core| core.r2 == 170 && core.word(900) == 180);");
+        assert_eq!(errors, Vec::new());
+    }
+
+    #[test]
+    fn test_writes() {
+        // The second store of the same value is not a write
+        let (errors, out) = run("on_write(\"screen\", |core| core.value + core.line);");
+        assert_eq!((errors, out.as_str()), (Vec::new(), "8\n7\n6\n"));
+    }
+
+    #[test]
+    fn test_location_outside_memory() {
+        let (errors, _) = run("on_write(5000, |core| core.value);");
+        assert_eq!(errors[0].message, "Cannot map '5000'");
+    }
+
+    #[test]
+    fn test_checks() {
+        assert_eq!(run("check(|core| core.r0 == 6, \"the sum\");").0, Vec::new());
+        let (errors, _) = run("check(|core| core.r0 == 7, \"r0 should hold the sum\");\ncheck(|core| core.r2 == 0);\ncheck(|core| core.nothing == 0);");
+        assert_eq!(errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>(),
+            ["Check failed: check(|core| core.r0 == 7, \"r0 should hold the sum\");", "Cannot evaluate the script: unknown name 'nothing'"]);
+        assert_eq!(errors[0].notes, ["r0 should hold the sum"]);
+        assert_eq!(errors[1].span.as_ref().unwrap().line, 3);
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        let errors = Script::parse("rules.rhai", "device(900, |core| core.steps\n").unwrap_err();
+        assert_eq!(errors[0].code, Code::InvalidScript);
+        let errors = Script::parse("rules.rhai", "let x = 1;\nwatch(|core| core.r0);").unwrap_err();
+        assert_eq!(errors[0].span.as_ref().unwrap().line, 2);
+        let errors = Script::parse("rules.rhai", "device(true, |core| 0);").unwrap_err();
+        assert_eq!(errors[0].message, "Cannot evaluate the script: a location is a memory address or a data label, not bool");
+    }
+}
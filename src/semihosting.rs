@@ -0,0 +1,139 @@
+//! A subset of ARM semihosting, where a program asks the host to print, read
+//! and exit, as under QEMU with `-semihosting`.
+//!
+//! `BKPT #0xAB`, `SVC #0x123456` and, in Thumb code, `SVC #0xAB` call the host
+//! with the operation in r0 and its parameter in r1:
+//!
+//! - `SYS_WRITEC` (3) prints the character in the word at r1
+//! - `SYS_WRITE0` (4) prints the string at r1, whose characters are words ending with 0
+//! - `SYS_READC` (7) reads a character of input into r0, or -1 at the end of the input
+//! - `SYS_EXIT` (0x18) ends the program, normally when r1 is `ADP_Stopped_ApplicationExit` (0x20026)
+//!
+//! Addresses count words, so strings keep a character to a word as they do for
+//! the helpers of SVC, rather than the bytes of a real board.
+
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::Machine;
+use crate::isa::Flow;
+
+/// The immediate of BKPT that calls the host.
+pub const BKPT: u32 = 0xAB;
+/// The immediates of SVC that call the host, in ARM and in Thumb code.
+pub const SVC: [u32; 2] = [0x12_3456, 0xAB];
+
+const SYS_WRITEC: i32 = 0x03;
+const SYS_WRITE0: i32 = 0x04;
+const SYS_READC: i32 = 0x07;
+const SYS_EXIT: i32 = 0x18;
+// The reason of SYS_EXIT for a program that ran to its end
+const ADP_STOPPED_APPLICATION_EXIT: i32 = 0x2_0026;
+
+const OPERATIONS_NOTE: &str = "the operations in r0 are SYS_WRITEC (3), SYS_WRITE0 (4), SYS_READC (7) and SYS_EXIT (0x18)";
+
+/// Runs the operation in r0 of a call to the host.
+pub fn call(machine: &mut Machine, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+    let parameter = machine.registers[1];
+    match machine.registers[0] {
+        SYS_WRITEC => {
+            let character = machine.load(address(parameter)?)?;
+            console.write(&char::from(character as u8).to_string())?;
+        },
+        SYS_WRITE0 => {
+            let mut text = String::new();
+            let mut at = address(parameter)?;
+            loop {
+                match machine.load(at)? {
+                    0 => break,
+                    character => text.push(char::from(character as u8)),
+                }
+                at += 1;
+            }
+            console.write(&text)?;
+        },
+        SYS_READC => machine.registers[0] = console.read_byte()?.map_or(-1, i32::from),
+        SYS_EXIT if parameter == ADP_STOPPED_APPLICATION_EXIT => return Ok(Flow::Exit),
+        SYS_EXIT => return Err(Box::new(Diagnostic::error(Code::FailedExit,
+            format!("The program exited through semihosting with reason 0x{:X}", parameter))
+            .with_note(format!("SYS_EXIT ends the program normally with r1 = 0x{:X}, ADP_Stopped_ApplicationExit", ADP_STOPPED_APPLICATION_EXIT)))),
+        operation => return Err(Box::new(Diagnostic::error(Code::UnknownService,
+            format!("Unknown semihosting operation 0x{:X}", operation))
+            .with_note(OPERATIONS_NOTE))),
+    }
+    Ok(Flow::Next)
+}
+
+// The word address in r1, which must not be negative
+fn address(parameter: i32) -> Result<usize, Box<Diagnostic>> {
+    usize::try_from(parameter).map_err(|_| Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+        format!("Memory access out of bounds: address {}", parameter))
+        .with_note("the parameter block of a semihosting call is the address in r1")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::interpreter::{run_sources_keeping_state, Arm, RunOptions};
+    use crate::thumb::Thumb;
+    use crate::isa::Isa;
+
+    fn run<I: Isa>(isa: &I, program: &str, input: &str) -> (String, Result<(), Vec<Diagnostic>>) {
+        let sources = [("main.s".to_string(), program.to_string())];
+        let mut output = Vec::new();
+        let (_, result) = run_sources_keeping_state(isa, &sources, &RunOptions::default(), &mut Console::new(&mut io::Cursor::new(input), &mut output)).unwrap();
+        (String::from_utf8(output).unwrap(), result)
+    }
+
+    #[test]
+    fn test_write0_prints_a_string() {
+        let program = "greeting: #72\ng1: #105\ng2: #0\nMOV r0, #4\nLDR r1, =greeting\nSVC #0x123456\n";
+        assert_eq!(run(&Arm, program, ""), ("Hi".to_string(), Ok(())));
+    }
+
+    #[test]
+    fn test_writec_prints_a_character() {
+        let program = "newline: #10\nMOV r0, #3\nLDR r1, =newline\nBKPT #0xAB\n";
+        assert_eq!(run(&Arm, program, ""), ("\n".to_string(), Ok(())));
+    }
+
+    #[test]
+    fn test_readc_reads_until_the_end_of_the_input() {
+        let program = "MOV r0, #7\nBKPT #0xAB\nPRINT r0\nMOV r0, #7\nBKPT #0xAB\nPRINT r0\n";
+        assert_eq!(run(&Arm, program, "A"), ("r0 = 65\nr0 = -1\n".to_string(), Ok(())));
+    }
+
+    #[test]
+    fn test_exit_ends_the_program() {
+        let program = "MOV r0, #0x18\nLDR r1, =0x20026\nBKPT #0xAB\nPRINT r0\n";
+        assert_eq!(run(&Arm, program, ""), (String::new(), Ok(())));
+    }
+
+    #[test]
+    fn test_exit_with_another_reason_fails() {
+        let (_, result) = run(&Thumb, "MOV r0, #0x18\nMOV r1, #1\nSVC #0xAB\n", "");
+        let errors = result.unwrap_err();
+        assert_eq!((errors[0].code, errors[0].message.as_str()), (Code::FailedExit, "The program exited through semihosting with reason 0x1"));
+    }
+
+    #[test]
+    fn test_unsupported_operations_are_errors() {
+        let (_, result) = run(&Arm, "MOV r0, #1\nBKPT #0xAB\n", "");
+        let errors = result.unwrap_err();
+        assert_eq!((errors[0].code, errors[0].message.as_str()), (Code::UnknownService, "Unknown semihosting operation 0x1"));
+        assert_eq!(errors[0].notes[0], OPERATIONS_NOTE);
+    }
+
+    #[test]
+    fn test_negative_parameters_are_errors() {
+        let (_, result) = run(&Arm, "MOV r0, #4\nMOV r1, #-1\nBKPT #0xAB\n", "");
+        assert_eq!(result.unwrap_err()[0].message, "Memory access out of bounds: address -1");
+    }
+
+    #[test]
+    fn test_reading_the_same_character_is_no_infinite_loop() {
+        // Each pass leaves the registers and memory as they were, but reads further into the input
+        let program = "loop: MOV r0, #7\nBKPT #0xAB\nCMP r0, #-1\nBNE loop\n";
+        assert_eq!(run(&Arm, program, &"a".repeat(20_000)), (String::new(), Ok(())));
+    }
+}
@@ -0,0 +1,428 @@
+//! A web playground, for `--serve ADDRESS`.
+//!
+//! The server hands out a single page to write, run and step through programs in
+//! a browser, and the JSON API behind it:
+//!
+//! - `POST /api/run` runs the program in the body, `{"source": "...", "input": "...", "steps": 10}`,
+//!   and answers with its output, errors and final state. With `steps` the run pauses
+//!   after that many instructions, which is how the page steps: it runs again one
+//!   instruction further. With `since`, a step number before `steps`, the answer
+//!   has the `deltas` of the instructions after it instead of the whole state,
+//!   each with the registers, flags and memory words it changed and its output.
+//!   A request with `Accept: application/msgpack` gets the
+//!   same answer in MessagePack, which is much smaller for a UI that steps often.
+//! - `POST /api/stream` runs it the same way, answering with a JSON object per line:
+//!   `{"output": "..."}` for each piece of output as it is printed, then
+//!   `{"result": ...}`, the answer `/api/run` would give without the output.
+//!
+//! Output streams as chunked HTTP rather than over a WebSocket: the page reads the
+//! lines of `/api/stream` with `fetch` as they arrive, which gives the same live
+//! output without a handshake or framing for the server to get right, and works
+//! through any proxy that passes HTTP.
+//!
+//! Every request gets a fresh interpreter, on its own thread, and is stopped after
+//! [`MAX_STEPS`] instructions or [`MAX_OUTPUT`] bytes of output, so a program that
+//! never ends does not hold up the class. At most [`MAX_CONNECTIONS`] are served at
+//! once, a client has [`TIMEOUT`] to send its request, and a request line or header
+//! over [`MAX_LINE`] bytes is refused, so a few bad clients cannot take the server
+//! down either.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::console::Console;
+use crate::delta::StateDelta;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::grading::Limited;
+use crate::interpreter::{FinalState, RunOptions};
+use crate::isa::FrontEnd;
+use crate::msgpack;
+
+/// Instructions a run may take, unless it asks to pause sooner.
+pub const MAX_STEPS: u64 = 10_000_000;
+/// Bytes of output a run may print.
+pub const MAX_OUTPUT: usize = 1 << 20;
+// Bytes of a request body, which holds the program
+const MAX_BODY: usize = 1 << 20;
+/// Instructions whose deltas a run may record.
+pub const MAX_DELTAS: u64 = 10_000;
+/// Connections served at once; more are answered 503 until one ends.
+pub const MAX_CONNECTIONS: usize = 32;
+/// How long a client may wait before sending the next part of its request, or reading the answer.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of the request line, and of each header.
+pub const MAX_LINE: usize = 8 << 10;
+// Headers of a request
+const MAX_HEADERS: usize = 64;
+
+const PAGE: &str = include_str!("playground.html");
+
+/// Serves the playground for `front_end` on `address`, e.g. `0.0.0.0:8080` for every
+/// interface, or `:8080` for this machine only. Only returns if the address cannot be listened on.
+pub fn serve(front_end: &dyn FrontEnd, address: &str) -> Result<(), Box<Diagnostic>> {
+    let address = listen_address(address);
+    let listener = TcpListener::bind(&address).map_err(|error| Box::new(Diagnostic::error(Code::UnsupportedOption,
+        format!("Cannot serve on {}: {}", address, error))
+        .with_note("expected an address and port such as 0.0.0.0:8080, or :8080 for this machine only")))?;
+    info!("Serving the playground for --isa {} on http://{}/", front_end.name(), address);
+    let open = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming().flatten() {
+            // A client that goes away mid-answer is no concern of the server
+            if open.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                let _ = stream.set_write_timeout(Some(TIMEOUT)).and_then(|()| busy(&mut &stream));
+                continue;
+            }
+            open.fetch_add(1, Ordering::SeqCst);
+            let open = &open;
+            scope.spawn(move || {
+                let _ = connection(front_end, stream);
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(())
+}
+
+// The address to listen on: a port alone is on the loopback interface, so a playground
+// is only reachable from elsewhere when asked for
+fn listen_address(address: &str) -> String {
+    if address.starts_with(':') { format!("127.0.0.1{}", address) } else { address.to_string() }
+}
+
+fn connection(front_end: &dyn FrontEnd, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    handle(front_end, &mut reader, &mut writer)?;
+    writer.flush()
+}
+
+// The answer to a client over MAX_CONNECTIONS
+fn busy(writer: &mut dyn Write) -> io::Result<()> {
+    respond(writer, "503 Service Unavailable", "application/json",
+        json!({ "error": format!("the server is busy with {} connections, try again", MAX_CONNECTIONS) }).to_string())
+}
+
+// Reads a line of at most MAX_LINE bytes, or None for a longer one
+fn read_line(reader: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    reader.take(MAX_LINE as u64 + 1).read_line(&mut line)?;
+    Ok((line.len() <= MAX_LINE).then_some(line))
+}
+
+// Answers the one request read from `reader`
+fn handle(front_end: &dyn FrontEnd, reader: &mut dyn BufRead, writer: &mut dyn Write) -> io::Result<()> {
+    let Some(request_line) = read_line(reader)? else {
+        return respond(writer, "400 Bad Request", "application/json", json!({ "error": format!("the request line is over {} bytes", MAX_LINE) }).to_string());
+    };
+    let mut length = 0;
+    let mut binary = false;
+    let mut headers = 0;
+    loop {
+        // The end of the headers, or of the stream, is an empty line
+        let header = read_line(reader)?.filter(|header| header.trim().is_empty() || headers < MAX_HEADERS);
+        let Some(header) = header else {
+            return respond(writer, "431 Request Header Fields Too Large", "application/json",
+                json!({ "error": format!("the request has a header over {} bytes or more than {} headers", MAX_LINE, MAX_HEADERS) }).to_string());
+        };
+        if header.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().unwrap_or(0);
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("accept") {
+            binary = value.contains(msgpack::MIME_TYPE);
+        }
+    }
+    let (method, path) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, path, _] => (method, path),
+        _ => return respond(writer, "400 Bad Request", "application/json", json!({ "error": "not an HTTP request" }).to_string()),
+    };
+    if length > MAX_BODY {
+        return respond(writer, "413 Content Too Large", "application/json", json!({ "error": format!("the program is over {} bytes", MAX_BODY) }).to_string());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    match (method, path) {
+        ("GET", "/") => respond(writer, "200 OK", "text/html; charset=utf-8", PAGE),
+        ("POST", "/api/run" | "/api/stream") => {
+            let request = match serde_json::from_slice::<Value>(&body).ok().and_then(|request| Run::from_json(&request)) {
+                Some(request) => request,
+                None => return respond(writer, "400 Bad Request", "application/json",
+                    json!({ "error": format!("expected {{\"source\": \"...\"}} with an optional input string, steps number, and since number up to {} below steps", MAX_DELTAS) }).to_string()),
+            };
+            if path == "/api/run" {
+                let mut output = Limited { output: Vec::new(), limit: MAX_OUTPUT };
+                let mut result = request.run(front_end, &mut output);
+                result["output"] = json!(String::from_utf8_lossy(&output.output));
+                if binary {
+                    respond(writer, "200 OK", msgpack::MIME_TYPE, msgpack::encode(&result))
+                } else {
+                    respond(writer, "200 OK", "application/json", result.to_string())
+                }
+            } else {
+                write!(writer, "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n")?;
+                let mut events = Events { out: writer, written: 0, pending: Vec::new() };
+                let result = request.run(front_end, &mut events);
+                events.finish(result)
+            }
+        },
+        (_, "/" | "/api/run" | "/api/stream") => respond(writer, "405 Method Not Allowed", "application/json", json!({ "error": format!("{} is not allowed on {}", method, path) }).to_string()),
+        _ => respond(writer, "404 Not Found", "application/json", json!({ "error": format!("no such page: {}", path) }).to_string()),
+    }
+}
+
+fn respond(writer: &mut dyn Write, status: &str, content_type: &str, body: impl AsRef<[u8]>) -> io::Result<()> {
+    let body = body.as_ref();
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len())?;
+    writer.write_all(body)
+}
+
+// A program to run, from the body of a request
+struct Run {
+    source: String,
+    input: String,
+    steps: Option<u64>,
+    // The step after which to give deltas instead of the state
+    since: Option<u64>,
+}
+
+impl Run {
+    fn from_json(request: &Value) -> Option<Self> {
+        let steps = match &request["steps"] {
+            Value::Null => None,
+            steps => Some(steps.as_u64()?),
+        };
+        let input = match &request["input"] {
+            Value::Null => String::new(),
+            input => input.as_str()?.to_string(),
+        };
+        let since = match &request["since"] {
+            Value::Null => None,
+            since => Some(since.as_u64().filter(|&since| steps.is_some_and(|steps| since < steps && steps - since <= MAX_DELTAS))?),
+        };
+        Some(Run { source: request["source"].as_str()?.to_string(), input, steps, since })
+    }
+
+    // Runs the program, printing to `output`, and describes how it ended: paused after
+    // `steps` instructions, finished or stopped with errors, and the state it left
+    fn run(&self, front_end: &dyn FrontEnd, output: &mut dyn Write) -> Value {
+        let options = RunOptions { max_steps: Some(self.steps.unwrap_or(MAX_STEPS).min(MAX_STEPS)), deltas: self.since, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), self.source.clone())];
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            front_end.run_sources_keeping_state(&sources, &options, &mut Console::new(&mut io::Cursor::new(self.input.as_bytes()), output))
+        }));
+        let (state, mut errors) = match run {
+            Ok(Ok((state, result))) => (Some(state), result.err().unwrap_or_default()),
+            Ok(Err(errors)) => (None, errors),
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str));
+                let message = format!("The program crashed the interpreter: {}", message.unwrap_or("unknown panic"));
+                return json!({ "done": true, "errors": [{ "code": null, "message": message, "line": null, "notes": [] }] });
+            },
+        };
+        // Stopping where the client asked to pause is no error
+        let paused = errors.iter().position(|error| self.steps.is_some() && error.code == Code::StepLimit).map(|index| errors.remove(index));
+        let mut result = json!({
+            "done": paused.is_none(),
+            "errors": errors.iter().map(|error| json!({
+                "code": error.code.id(),
+                "message": error.message,
+                "line": error.span.as_ref().map(|span| span.line),
+                "notes": error.notes,
+            })).collect::<Vec<_>>(),
+        });
+        if let Some(span) = paused.as_ref().and_then(|paused| paused.span.as_ref()) {
+            result["next_line"] = json!(span.line);
+        }
+        if let Some(state) = state.as_ref().filter(|_| self.since.is_some()) {
+            result["deltas"] = json!(state.deltas.iter().map(StateDelta::json).collect::<Vec<_>>());
+        } else if let Some(state) = state {
+            result["state"] = state_json(&state);
+        }
+        result
+    }
+}
+
+fn state_json(state: &FinalState) -> Value {
+    json!({
+        "registers": state.registers.iter().map(|(name, value)| json!([name, value])).collect::<Vec<_>>(),
+        "flags": state.flags.iter().map(|(name, set)| json!([name, set])).collect::<Vec<_>>(),
+        "memory": state.memory.words().filter(|&(_, word)| word != 0).map(|(address, word)| json!([address, word])).collect::<Vec<_>>(),
+        "labels": state.labels,
+    })
+}
+
+// Program output sent a line at a time as it is printed, each line a chunk with an event
+struct Events<'a> {
+    out: &'a mut dyn Write,
+    written: usize,
+    // Output after the last complete line
+    pending: Vec<u8>,
+}
+
+impl Events<'_> {
+    // Sends what is left of the output, then the result
+    fn finish(&mut self, result: Value) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let output = String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned();
+            self.send(&json!({ "output": output }))?;
+        }
+        self.send(&json!({ "result": result }))?;
+        write!(self.out, "0\r\n\r\n")
+    }
+
+    fn send(&mut self, event: &Value) -> io::Result<()> {
+        let line = event.to_string() + "\n";
+        write!(self.out, "{:x}\r\n{}\r\n", line.len(), line)?;
+        self.out.flush()
+    }
+}
+
+impl Write for Events<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.written += bytes.len();
+        if self.written > MAX_OUTPUT {
+            return Err(io::Error::other(format!("the output exceeds the limit of {} bytes", MAX_OUTPUT)));
+        }
+        self.pending.extend_from_slice(bytes);
+        if let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') {
+            let lines: Vec<u8> = self.pending.drain(..=end).collect();
+            self.send(&json!({ "output": String::from_utf8_lossy(&lines) }))?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Arm;
+    use crate::lmc::Lmc;
+
+    const PROGRAM: &str = r#""source": "MOV r0, #2\nloop: PRINT r0\nSUBS r0, r0, #1\nBNE loop""#;
+
+    // The answer to the raw `text` of a request
+    fn send_raw(front_end: &dyn FrontEnd, text: &str) -> Vec<u8> {
+        let mut answer = Vec::new();
+        handle(front_end, &mut io::Cursor::new(text.as_bytes().to_vec()), &mut answer).unwrap();
+        answer
+    }
+
+    fn send(front_end: &dyn FrontEnd, method: &str, path: &str, headers: &str, body: &str) -> Vec<u8> {
+        send_raw(front_end, &format!("{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}", method, path, headers, body.len(), body))
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> String {
+        String::from_utf8(send(&Arm, method, path, "", body)).unwrap()
+    }
+
+    fn body(answer: &str) -> Value {
+        serde_json::from_str(answer.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn test_run_pauses_after_steps() {
+        let result = body(&request("POST", "/api/run", &format!("{{{}, \"steps\": 3}}", PROGRAM)));
+        assert_eq!((&result["done"], &result["next_line"], &result["output"]), (&json!(false), &json!(4), &json!("r0 = 2\n")));
+        assert_eq!(result["state"]["registers"][0], json!(["r0", 1]));
+    }
+
+    #[test]
+    fn test_run_in_msgpack() {
+        let result = body(&request("POST", "/api/run", &format!("{{{}, \"steps\": 3}}", PROGRAM)));
+        // The same answer, smaller
+        let answer = send(&Arm, "POST", "/api/run", "Accept: application/msgpack\r\n", &format!("{{{}, \"steps\": 3}}", PROGRAM));
+        let start = answer.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        assert_eq!(msgpack::decode(&answer[start..]), Ok(result.clone()));
+        assert!(answer.len() - start < result.to_string().len());
+    }
+
+    #[test]
+    fn test_run_with_deltas() {
+        // The third step, SUBS, changes r0 and C
+        let result = body(&request("POST", "/api/run", &format!("{{{}, \"steps\": 3, \"since\": 2}}", PROGRAM)));
+        assert_eq!((&result["deltas"][0]["registers"], &result["deltas"][0]["flags"]), (&json!([["r0", 1]]), &json!([["C", true]])));
+        assert!(result.get("state").is_none());
+        assert!(request("POST", "/api/run", &format!("{{{}, \"since\": 3}}", PROGRAM)).starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_stream() {
+        // Output comes a line at a time, then the result
+        let answer = request("POST", "/api/stream", &format!("{{{}}}", PROGRAM));
+        let events: Vec<Value> = answer.lines().filter(|line| line.starts_with('{')).map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events[..2], [json!({ "output": "r0 = 2\n" }), json!({ "output": "r0 = 1\n" })]);
+        assert_eq!(events[2]["result"]["state"]["registers"][0], json!(["r0", 0]));
+        assert!(answer.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_input() {
+        let answer = String::from_utf8(send(&Lmc, "POST", "/api/run", "", r#"{"source": "INP\nADD one\nOUT\nHLT\none DAT 1", "input": "7\n"}"#)).unwrap();
+        assert!(answer.contains(r#""output":"8\n""#));
+    }
+
+    #[test]
+    fn test_page() {
+        assert!(request("GET", "/", "").contains("<title>asm playground</title>"));
+    }
+
+    #[test]
+    fn test_wrong_requests() {
+        assert!(request("POST", "/api/run", "{\"steps\": 3}").starts_with("HTTP/1.1 400"));
+        assert!(request("POST", "/api/run", "not json").starts_with("HTTP/1.1 400"));
+        assert!(request("GET", "/api/run", "").starts_with("HTTP/1.1 405"));
+        assert!(request("GET", "/nothing", "").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        assert!(send_raw(&Arm, "HELLO\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(send_raw(&Arm, "").starts_with(b"HTTP/1.1 400"));
+        // A body shorter than its Content-Length ends the connection without an answer
+        let mut answer = Vec::new();
+        let text = "POST /api/run HTTP/1.1\r\nContent-Length: 100\r\n\r\n{}";
+        assert!(handle(&Arm, &mut io::Cursor::new(text.as_bytes().to_vec()), &mut answer).is_err());
+        assert!(answer.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_requests() {
+        let long = "x".repeat(MAX_LINE);
+        assert!(send_raw(&Arm, &format!("GET /{} HTTP/1.1\r\n\r\n", long)).starts_with(b"HTTP/1.1 400"));
+        assert!(send(&Arm, "GET", "/", &format!("Cookie: {}\r\n", long), "").starts_with(b"HTTP/1.1 431"));
+        let headers = "X-Header: 1\r\n".repeat(MAX_HEADERS);
+        assert!(send(&Arm, "GET", "/", &headers, "").starts_with(b"HTTP/1.1 431"));
+        // Up to the limit is fine, with the Host and Content-Length headers of send
+        assert!(send(&Arm, "GET", "/", &"X-Header: 1\r\n".repeat(MAX_HEADERS - 2), "").starts_with(b"HTTP/1.1 200"));
+        assert!(send_raw(&Arm, &format!("POST /api/run HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1)).starts_with(b"HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn test_busy() {
+        let mut answer = Vec::new();
+        busy(&mut answer).unwrap();
+        assert!(answer.starts_with(b"HTTP/1.1 503"));
+    }
+
+    #[test]
+    fn test_listen_address() {
+        assert_eq!(listen_address(":8080"), "127.0.0.1:8080");
+        assert_eq!(listen_address("0.0.0.0:8080"), "0.0.0.0:8080");
+    }
+}
@@ -0,0 +1,255 @@
+//! Snapshots of the machine state, written after the run with `--snapshot` or
+//! taken by the debugger's `snapshot`, and the differences between two of them,
+//! which `asm diff` and the debugger's `diff` print for before-and-after analysis
+//! of a routine:
+//!
+//! ```text
+//! r0: 5 -> 12
+//! Z: 0 -> 1
+//! [600] result: 0 -> 12
+//! ```
+//!
+//! A snapshot file is JSON with the instruction set, the registers and flags in
+//! the order of the register file, the memory words that are not 0 and the data labels.
+//! A file whose name ends in `.msgpack` holds the same in MessagePack, which is
+//! smaller when there are many memory words.
+
+use std::collections::BTreeMap;
+use std::fs;
+use serde_json::{json, Value};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::FinalState;
+use crate::msgpack;
+
+/// The registers, flags, memory and data labels of a core at one moment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The name of the instruction set, e.g. "arm"
+    pub isa: String,
+    pub registers: Vec<(String, i64)>,
+    pub flags: Vec<(String, bool)>,
+    /// Address and value of each memory word that is not 0
+    pub memory: BTreeMap<usize, i32>,
+    /// Labels and their memory addresses
+    pub labels: BTreeMap<String, usize>,
+}
+
+impl Snapshot {
+    /// The snapshot of the final state of a run with the instruction set named `isa`.
+    pub fn of(isa: &str, state: &FinalState) -> Self {
+        Snapshot {
+            isa: isa.to_string(),
+            registers: state.registers.iter().map(|&(name, value)| (name.to_string(), value)).collect(),
+            flags: state.flags.iter().map(|&(name, set)| (name.to_string(), set)).collect(),
+            memory: state.memory.words().filter(|&(_, word)| word != 0).collect(),
+            labels: state.labels.iter().map(|(name, &address)| (name.clone(), address)).collect(),
+        }
+    }
+
+    /// The snapshot as a JSON value.
+    pub fn value(&self) -> Value {
+        json!({
+            "isa": self.isa,
+            "registers": self.registers.iter().map(|(name, value)| json!([name, value])).collect::<Vec<_>>(),
+            "flags": self.flags.iter().map(|(name, set)| json!([name, set])).collect::<Vec<_>>(),
+            "memory": self.memory.iter().map(|(address, word)| json!([address, word])).collect::<Vec<_>>(),
+            "labels": self.labels,
+        })
+    }
+
+    /// The snapshot as a JSON object.
+    pub fn json(&self) -> String {
+        serde_json::to_string_pretty(&self.value()).expect("snapshots are plain JSON") + "\n"
+    }
+
+    /// The snapshot in MessagePack.
+    pub fn msgpack(&self) -> Vec<u8> {
+        msgpack::encode(&self.value())
+    }
+
+    /// Parses a snapshot written by [`Snapshot::json`], describing the first problem on failure.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Snapshot::from_value(&serde_json::from_str(text).map_err(|error| error.to_string())?)
+    }
+
+    /// Reads a snapshot written by [`Snapshot::json`] or [`Snapshot::msgpack`], telling them apart by the first byte.
+    pub fn parse_bytes(bytes: &[u8]) -> Result<Self, String> {
+        match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') => Snapshot::parse(std::str::from_utf8(bytes).map_err(|error| error.to_string())?),
+            _ => Snapshot::from_value(&msgpack::decode(bytes)?),
+        }
+    }
+
+    /// The snapshot in `value`, as given by [`Snapshot::value`].
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let isa = value["isa"].as_str().ok_or("expected the instruction set in 'isa'")?.to_string();
+        // Each of the lists holds [name or address, value] pairs
+        let pairs = |key: &str| -> Result<Vec<(Value, Value)>, String> {
+            value[key].as_array().ok_or(format!("expected a list in '{}'", key))?.iter().map(|pair| match pair.as_array().map(Vec::as_slice) {
+                Some([first, second]) => Ok((first.clone(), second.clone())),
+                _ => Err(format!("expected pairs in '{}', found {}", key, pair)),
+            }).collect()
+        };
+        let invalid = |key: &str, pair: &(Value, Value)| format!("invalid entry in '{}': [{}, {}]", key, pair.0, pair.1);
+        let registers = pairs("registers")?.iter().map(|pair| match pair {
+            (Value::String(name), value) => value.as_i64().map(|value| (name.clone(), value)).ok_or_else(|| invalid("registers", pair)),
+            _ => Err(invalid("registers", pair)),
+        }).collect::<Result<_, _>>()?;
+        let flags = pairs("flags")?.iter().map(|pair| match pair {
+            (Value::String(name), Value::Bool(set)) => Ok((name.clone(), *set)),
+            _ => Err(invalid("flags", pair)),
+        }).collect::<Result<_, _>>()?;
+        let memory = pairs("memory")?.iter().map(|pair| {
+            let address = pair.0.as_u64().and_then(|address| usize::try_from(address).ok());
+            let word = pair.1.as_i64().and_then(|word| i32::try_from(word).ok());
+            address.zip(word).ok_or_else(|| invalid("memory", pair))
+        }).collect::<Result<_, _>>()?;
+        let labels = value["labels"].as_object().ok_or("expected an object in 'labels'")?.iter().map(|(name, address)| {
+            address.as_u64().and_then(|address| usize::try_from(address).ok()).map(|address| (name.clone(), address))
+                .ok_or_else(|| format!("invalid address of label {}: {}", name, address))
+        }).collect::<Result<_, _>>()?;
+        Ok(Snapshot { isa, registers, flags, memory, labels })
+    }
+
+    /// Writes the snapshot to `path`, in MessagePack if it ends in `.msgpack` and as JSON otherwise.
+    pub fn write(&self, path: &str) -> Result<(), Box<Diagnostic>> {
+        let bytes = if path.ends_with(".msgpack") { self.msgpack() } else { self.json().into_bytes() };
+        fs::write(path, bytes).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not write the snapshot to {}: {}", path, error))))
+    }
+
+    /// Reads the snapshot at `path`.
+    pub fn load(path: &str) -> Result<Self, Box<Diagnostic>> {
+        let bytes = fs::read(path).map_err(|error| Box::new(Diagnostic::error(Code::InputFailed,
+            format!("Cannot read {}: {}", path, error))))?;
+        Snapshot::parse_bytes(&bytes).map_err(|message| Box::new(Diagnostic::error(Code::InvalidSnapshot,
+            format!("Invalid snapshot {}: {}", path, message))))
+    }
+
+    /// The registers, flags and memory words that differ in `after`, each as `name: before -> after`.
+    /// Snapshots of different instruction sets cannot be compared.
+    pub fn diff(&self, after: &Snapshot) -> Result<Vec<String>, String> {
+        if self.isa != after.isa {
+            return Err(format!("a snapshot of --isa {} cannot be compared with one of --isa {}", self.isa, after.isa));
+        }
+        let mut differences = Vec::new();
+        for ((name, before), (_, now)) in self.registers.iter().zip(&after.registers).filter(|(before, now)| before != now) {
+            differences.push(format!("{}: {} -> {}", name, before, now));
+        }
+        for ((name, before), (_, now)) in self.flags.iter().zip(&after.flags).filter(|(before, now)| before != now) {
+            differences.push(format!("{}: {} -> {}", name, u8::from(*before), u8::from(*now)));
+        }
+        let mut addresses: Vec<usize> = self.memory.keys().chain(after.memory.keys()).copied().collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        for address in addresses {
+            let (before, now) = (self.memory.get(&address).unwrap_or(&0), after.memory.get(&address).unwrap_or(&0));
+            if before != now {
+                // The first label at the address, in either snapshot
+                let label = after.labels.iter().chain(&self.labels).find(|&(_, &at)| at == address)
+                    .map(|(name, _)| format!(" {}", name)).unwrap_or_default();
+                differences.push(format!("[{}]{}: {} -> {}", address, label, before, now));
+            }
+        }
+        Ok(differences)
+    }
+}
+
+/// The differences one per line, or a line saying there are none.
+pub fn report(differences: &[String]) -> String {
+    if differences.is_empty() {
+        "No differences".to_string()
+    } else {
+        differences.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::console::Console;
+    use crate::debugger::DebugScript;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    const PROGRAM: &str = "result: #0\nMOV r0, #5\nBKPT\nADD r0, r0, #7\nCMP r0, #12\nSTR r0, result";
+
+    // The final snapshot of PROGRAM run with the debug script `script`, and what the script printed
+    fn run(script: &str) -> (Snapshot, String) {
+        let options = RunOptions { debug_script: Some(DebugScript::parse("walk.dbg", script).unwrap()), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), PROGRAM.to_string())];
+        let cpu = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out)).unwrap();
+        (Snapshot::of("arm", &FinalState::of(&Arm, &cpu)), String::from_utf8(out).unwrap())
+    }
+
+    // A path in the temporary directory, unique to the test run
+    fn path(name: &str) -> String {
+        std::env::temp_dir().join(format!("asm-snapshot-{}-{}", std::process::id(), name)).display().to_string()
+    }
+
+    #[test]
+    fn test_debugger_diff() {
+        let (_, out) = run("run\nsnapshot before\ndiff before\ncontinue\nsnapshot after\ndiff before after\ndiff after");
+        assert_eq!(out, "Paused at main.s:4: ADD r0, r0, #7\nNo differences\nr0: 5 -> 12\nZ: 0 -> 1\nC: 0 -> 1\n[0] result: 0 -> 12\nNo differences\n");
+    }
+
+    #[test]
+    fn test_json_and_msgpack_round_trip() {
+        let (snapshot, _) = run("run");
+        assert_eq!(Snapshot::parse(&snapshot.json()), Ok(snapshot.clone()));
+        assert_eq!(Snapshot::parse_bytes(&snapshot.msgpack()), Ok(snapshot.clone()));
+        assert!(snapshot.msgpack().len() < snapshot.json().len() / 2);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let (snapshot, _) = run("run");
+        for name in ["saved.json", "saved.msgpack"] {
+            let path = path(name);
+            snapshot.write(&path).unwrap();
+            let loaded = Snapshot::load(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded, Ok(snapshot.clone()));
+        }
+    }
+
+    #[test]
+    fn test_other_instruction_sets_cannot_be_compared() {
+        let (snapshot, _) = run("run");
+        let other = Snapshot { isa: "rv32i".to_string(), ..snapshot.clone() };
+        assert_eq!(snapshot.diff(&other).unwrap_err(), "a snapshot of --isa arm cannot be compared with one of --isa rv32i");
+    }
+
+    #[test]
+    fn test_malformed_entries() {
+        assert_eq!(Snapshot::parse("{\"isa\": \"arm\", \"registers\": [[\"r0\"]]}").unwrap_err(), "expected pairs in 'registers', found [\"r0\"]");
+        assert_eq!(Snapshot::parse("[1, 2]").unwrap_err(), "expected the instruction set in 'isa'");
+    }
+
+    #[test]
+    fn test_corrupt_snapshots_fail_clearly() {
+        let (snapshot, _) = run("run");
+        let json = snapshot.json().into_bytes();
+        let msgpack = snapshot.msgpack();
+        let corrupt = [("truncated.json", &json[..json.len() / 2]), ("truncated.msgpack", &msgpack[..msgpack.len() / 2]),
+            ("garbage.msgpack", &[0xC1, 0xFF, 0x00][..]), ("empty.json", &[][..])];
+        for (name, bytes) in corrupt {
+            let path = path(name);
+            fs::write(&path, bytes).unwrap();
+            let error = Snapshot::load(&path).unwrap_err();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(error.code, Code::InvalidSnapshot);
+            assert!(error.message.starts_with(&format!("Invalid snapshot {}: ", path)), "{}", error.message);
+        }
+        let error = Snapshot::load(&path("missing.json")).unwrap_err();
+        assert_eq!(error.code, Code::InputFailed);
+    }
+
+    #[test]
+    fn test_debugger_snapshots_need_names() {
+        let errors = DebugScript::parse("walk.dbg", "diff before\nsnapshot\nsnapshot a b").unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].notes[0].contains("snapshot before"));
+    }
+}
@@ -0,0 +1,134 @@
+//! Named snippets of interactive mode. `DEF name` ... `END` stores the lines in
+//! between and `CALL name` runs them. Snippets are kept in `snippets.s` next to the
+//! user's configuration file, in the same form as they are typed, so they last from
+//! one session to the next:
+//!
+//! ```text
+//! DEF square
+//! MUL r1, r0, r0
+//! END
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::diagnostics::{Code, Diagnostic};
+
+/// The snippets defined so far, and the file they are saved to.
+#[derive(Default)]
+pub struct Snippets {
+    // None keeps them for the session only
+    path: Option<PathBuf>,
+    snippets: BTreeMap<String, Vec<String>>,
+}
+
+impl Snippets {
+    /// Reads the snippets saved in the file at `path`, if it exists, and saves new ones there.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<Diagnostic>> {
+        let snippets = match &path {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(text) => Snippets::parse(&text)
+                    .map_err(|message| Diagnostic::error(Code::InvalidConfig, format!("Invalid snippets file {}: {}", file.display(), message)))?,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(error) => return Err(Box::new(Diagnostic::error(Code::InputFailed, format!("Cannot read {}: {}", file.display(), error)))),
+            },
+            None => BTreeMap::new(),
+        };
+        Ok(Snippets { path, snippets })
+    }
+
+    fn parse(text: &str) -> Result<BTreeMap<String, Vec<String>>, String> {
+        let mut snippets = BTreeMap::new();
+        let mut current: Option<(String, Vec<String>)> = None;
+        for (index, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            match &mut current {
+                Some(_) if trimmed.eq_ignore_ascii_case("END") => {
+                    let (name, lines) = current.take().expect("a snippet is open");
+                    snippets.insert(name, lines);
+                },
+                Some((_, lines)) => if !trimmed.is_empty() {
+                    lines.push(line.trim_end().to_string());
+                },
+                None if trimmed.is_empty() || trimmed.starts_with("//") => {},
+                None => match trimmed.split_whitespace().collect::<Vec<_>>()[..] {
+                    [def, name] if def.eq_ignore_ascii_case("DEF") => current = Some((name.to_string(), Vec::new())),
+                    _ => return Err(format!("line {}: expected DEF <name>", index + 1)),
+                },
+            }
+        }
+        match current {
+            Some((name, _)) => Err(format!("snippet {} has no END", name)),
+            None => Ok(snippets),
+        }
+    }
+
+    /// The lines of the snippet called `name`.
+    pub fn get(&self, name: &str) -> Option<&[String]> {
+        self.snippets.get(name).map(Vec::as_slice)
+    }
+
+    /// The names of the snippets, in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.snippets.keys().map(String::as_str)
+    }
+
+    /// Stores `lines` as the snippet called `name`, replacing any before, and saves all
+    /// of them. The snippet is kept for the session even if saving fails.
+    pub fn define(&mut self, name: &str, lines: Vec<String>) -> Result<(), Box<Diagnostic>> {
+        self.snippets.insert(name.to_string(), lines);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = match path.parent() {
+            Some(dir) => fs::create_dir_all(dir),
+            None => Ok(()),
+        };
+        saved.and_then(|()| fs::write(path, self.to_text()))
+            .map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed, format!("Cannot save snippets to {}: {}", path.display(), error))
+                .with_note("the snippet is kept until the end of the session")))
+    }
+
+    fn to_text(&self) -> String {
+        self.snippets.iter()
+            .map(|(name, lines)| format!("DEF {}\n{}END\n", name, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A snippets file in a directory of its own for `test`, which does not exist yet
+    fn path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("asm-snippets-{}-{}", test, std::process::id())).join("snippets.s")
+    }
+
+    #[test]
+    fn test_missing_file() {
+        assert_eq!(Snippets::load(Some(path("missing"))).unwrap().names().count(), 0);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = path("saved");
+        let mut snippets = Snippets::load(Some(path.clone())).unwrap();
+        snippets.define("square", vec!["MUL r1, r0, r0".to_string()]).unwrap();
+        snippets.define("clear", vec!["MOV r0, #0".to_string(), "MOV r1, #0".to_string()]).unwrap();
+
+        let loaded = Snippets::load(Some(path.clone())).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(loaded.names().collect::<Vec<_>>(), ["clear", "square"]);
+        assert_eq!(loaded.get("clear").unwrap(), ["MOV r0, #0", "MOV r1, #0"]);
+        assert!(loaded.get("cube").is_none());
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert_eq!(Snippets::parse("DEF a\nMOV r0, #1").unwrap_err(), "snippet a has no END");
+        assert_eq!(Snippets::parse("MOV r0, #1").unwrap_err(), "line 1: expected DEF <name>");
+    }
+}
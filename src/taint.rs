@@ -0,0 +1,161 @@
+//! Data-flow taint tracking, for `--taint`.
+//!
+//! Registers and memory words named on the command line start out tainted. An
+//! instruction that reads a tainted register, memory word or flag taints what it
+//! writes, and one that writes only clean values clears the mark, so the taint
+//! follows the data through arithmetic, loads and stores. Branches on tainted
+//! flags and PRINTs of tainted registers count as influenced without writing
+//! anything. Addresses do not carry taint: loading through a tainted pointer
+//! taints the result only if the word loaded is tainted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use crate::interpreter::SourceLine;
+
+/// What an instruction reads and writes, worked out before it runs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DataFlow {
+    /// Registers read, by index
+    pub reads: Vec<usize>,
+    /// Registers written, by index
+    pub writes: Vec<usize>,
+    /// The memory word loaded
+    pub load: Option<usize>,
+    /// The memory word stored
+    pub store: Option<usize>,
+    pub reads_flags: bool,
+    pub writes_flags: bool,
+    /// Registers the memory address is computed from
+    pub address: Vec<usize>,
+    /// Whether what it reads decides where execution continues, as for a conditional branch
+    pub branches: bool,
+    /// Whether it prints what it reads
+    pub prints: bool,
+}
+
+/// Registers, memory words and flags that carry a mark, such as a taint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Marks {
+    pub registers: BTreeSet<usize>,
+    pub memory: BTreeSet<usize>,
+    pub flags: bool,
+}
+
+impl Marks {
+    /// Whether the instruction reads a marked register, memory word or flag.
+    pub fn reads_marked(&self, flow: &DataFlow) -> bool {
+        flow.reads.iter().any(|reg| self.registers.contains(reg))
+            || flow.load.is_some_and(|address| self.memory.contains(&address))
+            || (flow.reads_flags && self.flags)
+    }
+
+    /// Marks what the instruction writes if it reads a marked value and clears
+    /// the marks of what it writes otherwise. Returns whether it read one.
+    pub fn propagate(&mut self, flow: &DataFlow) -> bool {
+        let marked = self.reads_marked(flow);
+        for &reg in &flow.writes {
+            if marked { self.registers.insert(reg) } else { self.registers.remove(&reg) };
+        }
+        if let Some(address) = flow.store {
+            if marked { self.memory.insert(address) } else { self.memory.remove(&address) };
+        }
+        if flow.writes_flags {
+            self.flags = marked;
+        }
+        marked
+    }
+
+    /// The marked locations, naming registers with `name`.
+    pub fn locations(&self, name: &dyn Fn(usize) -> String) -> Vec<String> {
+        let mut locations: Vec<String> = self.registers.iter().map(|&reg| name(reg)).collect();
+        locations.extend(self.memory.iter().map(|address| format!("[{}]", address)));
+        if self.flags {
+            locations.push("flags".to_string());
+        }
+        locations
+    }
+}
+
+/// Which values are tainted, and the instructions they reached.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Taint {
+    // The sources as given, for the report
+    sources: Vec<String>,
+    marks: Marks,
+    // Times each instruction read a tainted value, by instruction index
+    influenced: BTreeMap<usize, u64>,
+}
+
+impl Taint {
+    /// Starts with `registers` and `memory` words tainted, described as `sources`.
+    pub fn new(sources: Vec<String>, registers: BTreeSet<usize>, memory: BTreeSet<usize>) -> Self {
+        Taint { sources, marks: Marks { registers, memory, flags: false }, influenced: BTreeMap::new() }
+    }
+
+    /// Propagates the taint through the instruction at `pc`.
+    pub fn step(&mut self, pc: usize, flow: &DataFlow) {
+        if self.marks.propagate(flow) {
+            *self.influenced.entry(pc).or_default() += 1;
+        }
+    }
+
+    /// Lists the instructions of `lines` the taint reached and where it is at the end,
+    /// naming registers with `name`.
+    pub fn report(&self, lines: &[SourceLine], name: &dyn Fn(usize) -> String) -> String {
+        let mut text = format!("Taint from {}:", self.sources.join(", "));
+        if self.influenced.is_empty() {
+            text.push_str("\n  no instruction read a tainted value");
+        }
+        for (&pc, &count) in &self.influenced {
+            let line = &lines[pc];
+            let times = if count == 1 { "1 time".to_string() } else { format!("{} times", count) };
+            text.push_str(&format!("\n  {}:{}  {} ({})", line.file, line.line_no, line.text(), times));
+        }
+        let locations = self.marks.locations(name);
+        text.push_str(&format!("\nTainted at the end: {}", if locations.is_empty() { "nothing".to_string() } else { locations.join(", ") }));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    // Runs `program` with `taint`, giving the first error and the output
+    fn run(program: &str, taint: &[&str]) -> (Result<(), String>, String) {
+        let options = RunOptions { taint: taint.iter().map(|source| source.to_string()).collect(), ..RunOptions::default() };
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), program.to_string())];
+        let result = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut out));
+        (result.map(|_| ()).map_err(|errors| errors[0].message.clone()), String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_taint_spreads() {
+        // The key reaches r2 through an addition and [600] through a store; r0 is overwritten
+        let program = "key: #0\nMOV r1, #5\nADD r2, r0, r1\nMOV r3, #600\nSTR r2, [r3]\nMOV r0, #0\nLDR r4, [r3]\nCMP r4, #9\n\
+            BEQ done\nMOV r7, #0\nLDR r5, [r7]\ndone: PRINT r1";
+        let (result, out) = run(program, &["r0", "key"]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "r1 = 5\nTaint from r0, key:\
+            \n  main.s:3  ADD r2, r0, r1 (1 time)\
+            \n  main.s:5  STR r2, [r3] (1 time)\
+            \n  main.s:7  LDR r4, [r3] (1 time)\
+            \n  main.s:8  CMP r4, #9 (1 time)\
+            \n  main.s:9  BEQ done (1 time)\
+            \n  main.s:11  LDR r5, [r7] (1 time)\
+            \nTainted at the end: r2, r4, r5, [0], [600], flags\n");
+    }
+
+    #[test]
+    fn test_overwritten_taint() {
+        let (_, out) = run("MOV r0, #1\nPRINT r0", &["r0"]);
+        assert!(out.ends_with("no instruction read a tainted value\nTainted at the end: nothing\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_unknown_source() {
+        assert_eq!(run("MOV r0, #1", &["nowhere"]).0, Err("Cannot taint 'nowhere'".to_string()));
+    }
+}
@@ -0,0 +1,50 @@
+//! Boards a program can be written for, for `--target`.
+//!
+//! A target starts a program the way the board does out of reset: with its
+//! memory map, the stack pointer from the first word of the vector table and
+//! the pc at the reset handler. Embedded-flavored examples then behave like
+//! the boards students use later, without the program setting up a stack.
+
+use crate::memory_map::{MemoryMap, Region};
+
+/// A board whose startup a run follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Target {
+    /// A Cortex-M microcontroller like the STM32F103: 128K of flash at 0x0800_0000, 20K of SRAM at 0x2000_0000
+    /// and 144K of peripherals at 0x4000_0000
+    CortexMLike,
+}
+
+impl Target {
+    /// The regions of the board. Their starts are byte addresses, as in the
+    /// datasheet; the peripheral region reaches the last of the AHB peripherals.
+    pub fn memory_map(self) -> MemoryMap {
+        let regions = match self {
+            Target::CortexMLike => ["flash @0x0800_0000 ro 128K", "sram @0x2000_0000 rw 20K", "peripheral @0x4000_0000 mmio 144K"],
+        };
+        MemoryMap::new(regions.iter().map(|region| region.parse().expect("the regions of a target are valid")).collect())
+            .expect("the regions of a target do not overlap")
+    }
+
+    /// The address of the vector table, counting words as programs do: the initial
+    /// stack pointer, then the reset vector. It is at the start of flash.
+    pub fn vector_table(self) -> usize {
+        self.region("flash").start
+    }
+
+    /// The stack pointer when the vector table leaves it 0, counting words: the top of SRAM.
+    pub fn initial_stack(self) -> usize {
+        self.region("sram").end()
+    }
+
+    fn region(self, name: &str) -> Region {
+        self.memory_map().regions().iter().find(|region| region.name == name).cloned().expect("the target has the region")
+    }
+
+    /// The label of the code the reset vector points at, where programs start.
+    pub fn reset_handler(self) -> &'static str {
+        match self {
+            Target::CortexMLike => "Reset_Handler",
+        }
+    }
+}
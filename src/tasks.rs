@@ -0,0 +1,134 @@
+//! Cooperative tasks, for the task services of `SVC`.
+//!
+//! A program starts as task 0 and creates more with `SVC #1`, each with its own
+//! entry point and stack. A task gives up the core with `SVC #2`: the
+//! interpreter saves its registers, the instruction to resume at and its flags
+//! in its task control block, then loads those of the next task in turn, the
+//! way the context switch of a small RTOS does. The blocks are in memory where
+//! the program can see them (and overwrite them by mistake): task 0's in the
+//! last [`TCB_WORDS`] words, and each following task's just below.
+
+use crate::interpreter::MEMORY_SIZE;
+
+/// The words of a task control block: r0 to r14, the instruction to resume at and the CPSR.
+pub const TCB_WORDS: usize = 17;
+/// Where the instruction to resume at is kept in a task control block.
+pub const TCB_PC: usize = 15;
+/// Where the CPSR is kept in a task control block.
+pub const TCB_CPSR: usize = 16;
+/// The most tasks that can exist at once.
+pub const MAX_TASKS: usize = 8;
+
+/// The address of the task control block of `task`.
+pub fn control_block(task: usize) -> usize {
+    MEMORY_SIZE - TCB_WORDS * (task + 1)
+}
+
+/// Which tasks exist and which one is running.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tasks {
+    // By task number; an ended task's number is given to the next one created
+    alive: Vec<bool>,
+    running: usize,
+    // The instruction a task returns to from its entry point, which ends the program
+    pub end: usize,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks { alive: vec![true], running: 0, end: 0 }
+    }
+}
+
+impl Tasks {
+    pub fn running(&self) -> usize {
+        self.running
+    }
+
+    /// Whether each task exists, by task number.
+    pub fn alive(&self) -> impl Iterator<Item = bool> + '_ {
+        self.alive.iter().copied()
+    }
+
+    /// Adds a task and returns its number, or `None` if [`MAX_TASKS`] already exist.
+    pub fn create(&mut self) -> Option<usize> {
+        let task = self.alive.iter().position(|alive| !alive).unwrap_or(self.alive.len());
+        if task >= MAX_TASKS {
+            return None;
+        }
+        if task == self.alive.len() {
+            self.alive.push(true);
+        }
+        self.alive[task] = true;
+        Some(task)
+    }
+
+    /// Ends the running task.
+    pub fn end(&mut self) {
+        self.alive[self.running] = false;
+    }
+
+    /// Makes the next task in turn the running one and returns it, which is the
+    /// same task if it is the only one, or `None` once every task has ended.
+    pub fn switch(&mut self) -> Option<usize> {
+        let count = self.alive.len();
+        let next = (1..=count).map(|offset| (self.running + offset) % count).find(|&task| self.alive[task])?;
+        self.running = next;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::diagnostics::{Code, Diagnostic};
+    use crate::interpreter::{run_sources, Arm, Machine, RunOptions};
+    use super::*;
+
+    // Two workers take turns printing their argument, then main prints once both ended
+    const WORKERS: &str = "\
+        main: ADR r0, worker\nMOV r1, #700\nMOV r2, #1\nSVC #1\n\
+        ADR r0, worker\nMOV r1, #600\nMOV r2, #2\nSVC #1\n\
+        MOV r4, r0\nSVC #2\nSVC #2\nSVC #2\nMOV r0, #9\nPRINT r0\nPRINT r4\nEXIT\n\
+        worker: MOV r5, #2\nagain: PRINT r0\nSVC #2\nSUBS r5, r5, #1\nBNE again\nSVC #3";
+
+    fn run(program: &str) -> (Result<Machine, Diagnostic>, String) {
+        let mut out = Vec::new();
+        let sources = [("main.s".to_string(), program.to_string())];
+        let result = run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut out));
+        (result.map_err(|errors| errors[0].clone()), String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_tasks_take_turns() {
+        let (result, out) = run(WORKERS);
+        assert!(result.is_ok());
+        assert_eq!(out, "r0 = 1\nr0 = 2\nr0 = 1\nr0 = 2\nr0 = 9\nr4 = 2\n");
+    }
+
+    #[test]
+    fn test_control_blocks() {
+        // Task 2 saved its stack pointer and the instruction after its last yield
+        let cpu = run(WORKERS).0.unwrap();
+        let tcb = control_block(2);
+        assert_eq!(cpu.memory[tcb + 13], 600);
+        assert_eq!(cpu.memory[tcb + TCB_PC], 19);
+    }
+
+    #[test]
+    fn test_ending_the_only_task() {
+        // Ends the program
+        assert_eq!(run("SVC #3\nMOV r0, #1\nPRINT r0").1, "");
+    }
+
+    #[test]
+    fn test_unknown_service() {
+        assert_eq!(run("SVC #9").0.err().unwrap().code, Code::UnknownService);
+    }
+
+    #[test]
+    fn test_task_limit() {
+        assert_eq!(run("loop: SVC #1\nB loop").0.err().unwrap().message, "Cannot create more than 8 tasks");
+    }
+}
@@ -0,0 +1,371 @@
+//! Thumb-1 operand rules, checked with `--isa thumb`.
+//!
+//! Thumb-1 instructions are 16 bits wide, so most of them can only name the low
+//! registers r0-r7, take small immediates and use a two-operand form where the
+//! destination is also the first source. Programs written for a Cortex-M0 must
+//! respect these limits; this module rejects decoded instructions that have no
+//! 16-bit encoding, explaining which constraint was broken.
+
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::instructions::{self, InstructionInfo};
+use crate::interpreter::{self, AddressOperand, AluOp, Arm, Instruction, Machine, Operand, ShiftOp, ARM_REGISTERS};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+use crate::taint::DataFlow;
+
+const LOW_REGISTER_NOTE: &str = "Thumb instructions can only use the low registers r0 through r7 here";
+const TWO_OPERAND_NOTE: &str = "Thumb data-processing instructions use the two-operand form, e.g. AND r0, r0, r1";
+
+/// ARM instructions restricted to their Thumb-1 encodings.
+pub struct Thumb;
+
+impl Isa for Thumb {
+    type Instruction = Instruction;
+    type Cpu = Machine;
+
+    const NAME: &'static str = "thumb";
+    const DESCRIPTION: &'static str = "Thumb-1: low registers, small immediates and two-operand forms, as on Cortex-M0";
+    const INSTRUCTIONS: &'static [InstructionInfo] = instructions::INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &ARM_REGISTERS;
+    const STACK_POINTER: Option<usize> = Arm::STACK_POINTER;
+    const CORE_REGISTER: usize = Arm::CORE_REGISTER;
+
+    fn new_cpu(&self) -> Machine {
+        Arm.new_cpu()
+    }
+
+    fn read_register(&self, machine: &Machine, index: usize) -> i64 {
+        Arm.read_register(machine, index)
+    }
+
+    fn flags(&self, machine: &Machine) -> Vec<(&'static str, bool)> {
+        Arm.flags(machine)
+    }
+
+    fn seed_registers(&self, machine: &mut Machine, registers: &[(usize, i32)]) {
+        Arm.seed_registers(machine, registers);
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        let instruction = interpreter::decode(tokens, labels, report)?;
+        check(&instruction, &tokens.operands, report)?;
+        Some(instruction)
+    }
+
+    fn register_index(&self, name: &str) -> Option<usize> {
+        Isa::register_index(&Arm, name)
+    }
+
+    fn prepare(&self, machine: &mut Machine, len: usize) {
+        Arm.prepare(machine, len);
+    }
+
+    fn execute(&self, machine: &mut Machine, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        Arm.execute(machine, instruction, pc, console)
+    }
+
+    fn narrow(&self, machine: &mut Machine, bits: u32) -> bool {
+        Arm.narrow(machine, bits)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        Arm.is_branch(instruction)
+    }
+
+    fn frame_offset(&self, instruction: &Instruction) -> Option<i32> {
+        Arm.frame_offset(instruction)
+    }
+
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        Arm.is_call(instruction)
+    }
+
+    fn data_flow(&self, machine: &Machine, instruction: &Instruction) -> Option<DataFlow> {
+        Arm.data_flow(machine, instruction)
+    }
+
+    fn is_return(&self, instruction: &Instruction) -> bool {
+        Arm.is_return(instruction)
+    }
+
+    fn remark(&self, instruction: &Instruction) -> Option<&'static str> {
+        Arm.remark(instruction)
+    }
+
+    fn documented(&self, mnemonic: &str) -> Option<&'static InstructionInfo> {
+        Arm.documented(mnemonic)
+    }
+
+    fn no_effect(&self, instruction: &Instruction) -> Option<&'static str> {
+        Arm.no_effect(instruction)
+    }
+
+    fn truncation(&self, instruction: &Instruction, word_bits: u32) -> Option<String> {
+        Arm.truncation(instruction, word_bits)
+    }
+}
+
+/// Checks a decoded instruction against the Thumb-1 encodings. `ops` are the
+/// instruction's operand tokens, used to point at the offending operand.
+/// Returns None after reporting an error.
+pub fn check(instruction: &Instruction, ops: &[&str], report: &Reporter) -> Option<()> {
+    match *instruction {
+        // MOV between registers has an encoding for all sixteen registers
+        Instruction::Mov { src: Operand::Register(_), .. } => {},
+        Instruction::Mov { rd, src: Operand::Immediate(val) } => {
+            low_register(rd, ops[0], report)?;
+            immediate_range(val, 0, 255, ops[1], "MOV", report)?;
+        },
+        Instruction::Alu { op: op @ (AluOp::Add | AluOp::Sub), rd, rn, op2, .. } => {
+            let mnemonic = if op == AluOp::Add { "ADD" } else { "SUB" };
+            low_register(rd, ops[0], report)?;
+            low_register(rn, ops[1], report)?;
+            match op2 {
+                Operand::Register(rm) => low_register(rm, ops[2], report)?,
+                // ADD r0, r0, #imm has an 8-bit immediate, ADD r0, r1, #imm only 3 bits
+                Operand::Immediate(val) if rd == rn => immediate_range(val, 0, 255, ops[2], mnemonic, report)?,
+                Operand::Immediate(val) => {
+                    if !(0..=7).contains(&val) {
+                        report.report(ops[2], Diagnostic::error(Code::ThumbRestriction,
+                            format!("Immediate {} is out of range for {} in Thumb mode", val, mnemonic))
+                            .with_note("with different registers the immediate must be #0 to #7")
+                            .with_note(format!("with the same destination and source, e.g. {} r0, r0, #imm, it may be #0 to #255", mnemonic)));
+                        return None;
+                    }
+                },
+            }
+        },
+        Instruction::Alu { op: AluOp::Mul, rd, rn, op2, .. } => {
+            low_register(rd, ops[0], report)?;
+            low_register(rn, ops[1], report)?;
+            let rm = register_op2(op2, ops[2], "MUL", report)?;
+            low_register(rm, ops[2], report)?;
+            if rd != rn && rd != rm {
+                report.report(ops[0], Diagnostic::error(Code::ThumbRestriction,
+                    "MUL in Thumb mode must write its result to one of its operands")
+                    .with_note("e.g. MUL r0, r0, r1"));
+                return None;
+            }
+        },
+        Instruction::Alu { op, rd, rn, op2, .. } => {
+            let mnemonic = format!("{:?}", op).to_uppercase();
+            low_register(rd, ops[0], report)?;
+            low_register(rn, ops[1], report)?;
+            let rm = register_op2(op2, ops[2], &mnemonic, report)?;
+            low_register(rm, ops[2], report)?;
+            same_register(rd, rn, ops[1], &mnemonic, TWO_OPERAND_NOTE, report)?;
+        },
+        // CMP has an 8-bit immediate form and a register form for all sixteen registers
+        Instruction::Cmp { rn, op2: Operand::Immediate(val) } => {
+            low_register(rn, ops[0], report)?;
+            immediate_range(val, 0, 255, ops[1], "CMP", report)?;
+        },
+        Instruction::Cmp { op2: Operand::Register(_), .. } => {},
+        Instruction::Cmn { rn, op2 } => {
+            low_register(rn, ops[0], report)?;
+            let rm = register_op2(op2, ops[1], "CMN", report)?;
+            low_register(rm, ops[1], report)?;
+        },
+        Instruction::Shift { op, rd, rm, amount, .. } => {
+            let mnemonic = format!("{:?}", op).to_uppercase();
+            low_register(rd, ops[0], report)?;
+            low_register(rm, ops[1], report)?;
+            match (op, amount) {
+                (ShiftOp::Ror, Operand::Immediate(_)) => {
+                    report.report(ops[2], Diagnostic::error(Code::ThumbRestriction,
+                        "ROR in Thumb mode takes its rotate amount from a register")
+                        .with_note("e.g. ROR r0, r0, r1"));
+                    return None;
+                },
+                (ShiftOp::Lsl, Operand::Immediate(val)) => immediate_range(val, 0, 31, ops[2], &mnemonic, report)?,
+                (_, Operand::Immediate(val)) => immediate_range(val, 1, 32, ops[2], &mnemonic, report)?,
+                (_, Operand::Register(rs)) => {
+                    low_register(rs, ops[2], report)?;
+                    same_register(rd, rm, ops[1], &mnemonic,
+                        "shifts by a register use the two-operand form, e.g. LSL r0, r0, r1", report)?;
+                },
+            }
+        },
+        Instruction::Rrx { .. } => {
+            report.report(ops.first().copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "RRX is not available in Thumb mode")
+                .with_note("Thumb-1 has no rotate through carry"));
+            return None;
+        },
+        Instruction::Ldr { rd: rt, address } | Instruction::Str { rs: rt, address } => {
+            let is_store = matches!(instruction, Instruction::Str { .. });
+            let mnemonic = if is_store { "STR" } else { "LDR" };
+            low_register(rt, ops[0], report)?;
+            let address_token = ops.last().copied().unwrap_or("");
+            match address {
+                AddressOperand::RegisterOffset(rn, offset) => {
+                    low_register(rn, address_token, report)?;
+                    if !(0..=31).contains(&offset) {
+                        report.report(address_token, Diagnostic::error(Code::ThumbRestriction,
+                            format!("Offset {} is out of range for {} in Thumb mode", offset, mnemonic))
+                            .with_note("the offset must be #0 to #31 words"));
+                        return None;
+                    }
+                },
+                // Loading from a label is a PC-relative literal load; there is no such store
+                AddressOperand::Absolute(_) if is_store => {
+                    report.report(address_token, Diagnostic::error(Code::ThumbRestriction,
+                        "STR in Thumb mode needs a register base address")
+                        .with_note("load the address into a register first, e.g. STR r0, [r1]"));
+                    return None;
+                },
+                AddressOperand::Absolute(_) => {},
+            }
+        },
+        Instruction::Ldrex { .. } | Instruction::Strex { .. } | Instruction::Clrex => {
+            report.report(ops.first().copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "LDREX, STREX and CLREX are not available in Thumb mode")
+                .with_note("a Cortex-M0 has no exclusive accesses; a lock there turns interrupts off instead"));
+            return None;
+        },
+        Instruction::Vfp { .. } | Instruction::Vmov { .. } | Instruction::VmovToCore { .. }
+            | Instruction::Vldr { .. } | Instruction::Vstr { .. } | Instruction::Vcvt { .. } => {
+            report.report(ops.first().copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "Floating-point instructions are not available in Thumb mode")
+                .with_note("a Cortex-M0 has no floating-point unit"));
+            return None;
+        },
+        Instruction::MultiplyAccumulate { .. } => {
+            report.report(ops.first().copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "MLA and MLS are not available in Thumb mode")
+                .with_note("a Cortex-M0 only has MUL; multiply into a spare register, then ADD or SUB it"));
+            return None;
+        },
+        Instruction::HalfwordMultiply { .. } | Instruction::Parallel { .. } | Instruction::Sel { .. } => {
+            report.report(ops.first().copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "DSP instructions are not available in Thumb mode")
+                .with_note("halfword multiplies and SIMD instructions need the DSP extension, which a Cortex-M0 lacks"));
+            return None;
+        },
+        // MSR and MRS are 32-bit instructions on a Cortex-M0, but MSR only takes a register
+        Instruction::Msr { src: Operand::Immediate(_), .. } => {
+            report.report(ops.get(1).copied().unwrap_or(""), Diagnostic::error(Code::ThumbRestriction,
+                "MSR in Thumb mode takes its value from a register")
+                .with_note("move the value into a register first, e.g. MOV r0, #0 then MSR APSR_nzcvq, r0"));
+            return None;
+        },
+        // A Cortex-M0 has the barriers, for ordering accesses to devices
+        Instruction::Barrier { .. } => {},
+        Instruction::Adr { rd, .. } => low_register(rd, ops[0], report)?,
+        Instruction::Svc { number } => immediate_range(number as i32, 0, 255, ops[0], "SVC", report)?,
+        Instruction::Branch { .. } | Instruction::Bl { .. } | Instruction::Bx { .. } | Instruction::Mrs { .. } | Instruction::Msr { .. } | Instruction::Print { .. } | Instruction::PrintFloat { .. } | Instruction::Bkpt { .. } | Instruction::Exit => {},
+        // A plugin's instructions are its own to check
+        Instruction::Plugin { .. } => {},
+    }
+    Some(())
+}
+
+fn low_register(reg: usize, token: &str, report: &Reporter) -> Option<()> {
+    if reg > 7 {
+        report.report(token, Diagnostic::error(Code::ThumbRestriction,
+            format!("High register r{} is not allowed here in Thumb mode", reg))
+            .with_note(LOW_REGISTER_NOTE));
+        return None;
+    }
+    Some(())
+}
+
+fn immediate_range(val: i32, min: i32, max: i32, token: &str, mnemonic: &str, report: &Reporter) -> Option<()> {
+    if val < min || val > max {
+        report.report(token, Diagnostic::error(Code::ThumbRestriction,
+            format!("Immediate {} is out of range for {} in Thumb mode", val, mnemonic))
+            .with_note(format!("the immediate must be #{} to #{}", min, max)));
+        return None;
+    }
+    Some(())
+}
+
+fn register_op2(op2: Operand, token: &str, mnemonic: &str, report: &Reporter) -> Option<usize> {
+    match op2 {
+        Operand::Register(reg) => Some(reg),
+        Operand::Immediate(_) => {
+            report.report(token, Diagnostic::error(Code::ThumbRestriction,
+                format!("{} in Thumb mode takes a register, not an immediate", mnemonic))
+                .with_note("load the constant into a register with MOV first"));
+            None
+        },
+    }
+}
+
+fn same_register(rd: usize, rn: usize, token: &str, mnemonic: &str, note: &str, report: &Reporter) -> Option<()> {
+    if rd != rn {
+        report.report(token, Diagnostic::error(Code::ThumbRestriction,
+            format!("{} in Thumb mode must use the destination register as its first operand", mnemonic))
+            .with_note(note));
+        return None;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Code;
+    use std::io;
+    use crate::interpreter::{run_sources, RunOptions};
+
+    fn run(script: &str) -> Machine {
+        match run_sources(&Thumb, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(machine) => machine,
+            Err(errors) => panic!("{}", errors.iter().map(|e| e.to_human()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    fn thumb_errors(script: &str) -> Vec<Diagnostic> {
+        match run_sources(&Thumb, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(_) => panic!("expected the script to be rejected in Thumb mode"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn test_thumb_accepts_low_register_program() {
+        let script = "\
+            MOV r0, #200\n\
+            MOV r1, #3\n\
+            ADD r2, r0, #7\n\
+            ADD r0, r0, #255\n\
+            AND r1, r1, r2\n\
+            MOV r9, r0\n\
+            LSR r3, r0, #32\n\
+            LDR r4, [r1,#31]\n\
+            EXIT";
+        let machine = run(script);
+        assert_eq!(machine.registers[2], 207);
+        assert_eq!(machine.registers[9], 455);
+        assert_eq!(machine.registers[3], 0);
+    }
+
+    #[test]
+    fn test_thumb_rejects_high_registers() {
+        let errors = thumb_errors("ADD r8, r0, r1\n");
+        assert_eq!(errors[0].code, Code::ThumbRestriction);
+        assert_eq!(errors[0].span.as_ref().unwrap().column_start, 5);
+    }
+
+    #[test]
+    fn test_thumb_immediate_ranges() {
+        assert_eq!(thumb_errors("MOV r0, #256\n")[0].code, Code::ThumbRestriction);
+        assert_eq!(thumb_errors("ADD r0, r1, #8\n")[0].code, Code::ThumbRestriction);
+        assert_eq!(thumb_errors("LSL r0, r1, #32\n")[0].code, Code::ThumbRestriction);
+        assert_eq!(thumb_errors("STR r0, [r1,#32]\n")[0].code, Code::ThumbRestriction);
+    }
+
+    #[test]
+    fn test_thumb_requires_two_operand_form() {
+        let errors = thumb_errors("EOR r0, r1, r2\n");
+        assert_eq!(errors[0].code, Code::ThumbRestriction);
+        assert_eq!(thumb_errors("AND r0, r0, #1\n")[0].code, Code::ThumbRestriction);
+        assert_eq!(thumb_errors("RRX r0, r0\n")[0].code, Code::ThumbRestriction);
+    }
+
+    #[test]
+    fn test_thumb_rejects_floating_point() {
+        assert_eq!(thumb_errors("VADD.F32 s0, s1, s2\n")[0].code, Code::ThumbRestriction);
+    }
+}
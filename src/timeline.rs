@@ -0,0 +1,167 @@
+//! A step-by-step record of a run, for `--trace FILE`.
+//!
+//! Every instruction executed becomes one event: the step number, the index of
+//! the instruction, its source line and what it changed, which registers,
+//! flags and memory words took which values. As text an event is a line like
+//! those of `RECENT`; as JSON it is an object per line, for tools that animate
+//! the registers and memory of a run.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use serde_json::{json, Map};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::SourceLine;
+use crate::radix::Formats;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// A readable line per step
+    #[default]
+    Text,
+    /// One JSON object per step
+    Json,
+}
+
+/// What one step changed.
+pub struct Event<'a> {
+    pub step: u64,
+    /// The index of the instruction
+    pub pc: usize,
+    pub line: &'a SourceLine,
+    /// Registers written, as (name, value)
+    pub registers: Vec<(&'static str, i64)>,
+    /// Flags that changed, with their new values
+    pub flags: Vec<(&'static str, bool)>,
+    /// Memory words that changed, as (address, value)
+    pub memory: Vec<(usize, i32)>,
+    /// What the instruction did that the changes do not show, such as a barrier
+    pub remark: Option<&'a str>,
+    /// The error the instruction stopped the program with
+    pub error: Option<&'a Diagnostic>,
+}
+
+/// Where the events of a run go.
+pub struct Timeline {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+}
+
+impl Timeline {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Self {
+        Timeline { out, format }
+    }
+
+    /// Creates `path` to write the events to.
+    pub fn create(path: &str, format: TraceFormat) -> Result<Self, Box<Diagnostic>> {
+        let file = File::create(path).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+            format!("Could not create the trace file {}: {}", path, error))))?;
+        Ok(Timeline::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    /// Writes an event, showing register values in the text format with `formats`.
+    pub fn record(&mut self, event: &Event, formats: &Formats, bits: u32) -> Result<(), Box<Diagnostic>> {
+        let entry = match self.format {
+            TraceFormat::Text => text(event, formats, bits),
+            TraceFormat::Json => json(event).to_string(),
+        };
+        writeln!(self.out, "{}", entry).map_err(failed)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<Diagnostic>> {
+        self.out.flush().map_err(failed)
+    }
+}
+
+fn failed(error: std::io::Error) -> Box<Diagnostic> {
+    Box::new(Diagnostic::error(Code::OutputFailed, format!("Could not write the trace: {}", error)))
+}
+
+fn text(event: &Event, formats: &Formats, bits: u32) -> String {
+    let mut changes: Vec<String> = event.registers.iter().map(|&(name, value)| format!("{} = {}", name, formats.format(name, value, bits))).collect();
+    changes.extend(event.flags.iter().map(|&(name, set)| format!("{}={}", name, set as u8)));
+    changes.extend(event.memory.iter().map(|&(address, value)| format!("[{}] = {}", address, value)));
+    let mut entry = format!("step {:>5}  {}:{}  {}", event.step, event.line.file, event.line.line_no, event.line.text());
+    if !changes.is_empty() {
+        entry.push_str(&format!("  -> {}", changes.join(", ")));
+    }
+    if let Some(remark) = event.remark {
+        entry.push_str(&format!("  // {}", remark));
+    }
+    if let Some(error) = event.error {
+        entry.push_str(&format!("  !! {}", error.message));
+    }
+    entry
+}
+
+fn json(event: &Event) -> serde_json::Value {
+    let registers: Map<_, _> = event.registers.iter().map(|&(name, value)| (name.to_string(), json!(value))).collect();
+    let flags: Map<_, _> = event.flags.iter().map(|&(name, set)| (name.to_string(), json!(set))).collect();
+    let memory: Map<_, _> = event.memory.iter().map(|&(address, value)| (address.to_string(), json!(value))).collect();
+    let mut object = json!({
+        "step": event.step,
+        "pc": event.pc,
+        "file": event.line.file,
+        "line": event.line.line_no,
+        "instruction": event.line.text(),
+        "registers": registers,
+        "flags": flags,
+        "memory": memory,
+    });
+    if let Some(remark) = event.remark {
+        object["remark"] = json!(remark);
+    }
+    if let Some(error) = event.error {
+        object["error"] = json!({ "code": error.code.id(), "message": error.message });
+    }
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+    use super::*;
+
+    // Stops at its last instruction, loading past the end of memory
+    const PROGRAM: &str = "MOV r0, #3\nMOV r1, #600\nSUBS r0, r0, #3\nSTR r1, [r1]\nMOV r2, #5000\nLDR r3, [r2]";
+
+    // The trace of `program` in `format`, written to a file of its own for `test`, and whether the run passed
+    fn trace(test: &str, program: &str, format: TraceFormat) -> (bool, String) {
+        let path = std::env::temp_dir().join(format!("asm-trace-{}-{}.jsonl", test, std::process::id()));
+        let options = RunOptions { trace: Some(path.display().to_string()), trace_format: format, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        let result = run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink()));
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (result.is_ok(), trace)
+    }
+
+    #[test]
+    fn test_json_steps() {
+        let (passed, trace) = trace("json", PROGRAM, TraceFormat::Json);
+        assert!(!passed);
+        let events: Vec<serde_json::Value> = trace.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0], json!({"step": 1, "pc": 0, "file": "main.s", "line": 1, "instruction": "MOV r0, #3",
+            "registers": {"r0": 3}, "flags": {}, "memory": {}}));
+        assert_eq!(events[2]["flags"], json!({"Z": true, "C": true}));
+        assert_eq!(events[3]["memory"], json!({"600": 600}));
+        assert_eq!(events[5]["error"]["code"], "E0009");
+    }
+
+    #[test]
+    fn test_text_steps() {
+        let (_, trace) = trace("text", PROGRAM, TraceFormat::Text);
+        assert_eq!(trace.lines().nth(2), Some("step     3  main.s:3  SUBS r0, r0, #3  -> r0 = 0, Z=1, C=1"));
+        assert!(trace.lines().nth(5).unwrap().starts_with("step     6  main.s:6  LDR r3, [r2]  !! "));
+    }
+
+    #[test]
+    fn test_steps_without_changes() {
+        // A barrier changes nothing, so the trace says why it is there
+        let (_, trace) = trace("barrier", "DMB ISH", TraceFormat::Text);
+        assert_eq!(trace, "step     1  main.s:1  DMB ISH  // memory barrier: no effect, every access completes in program order\n");
+    }
+}
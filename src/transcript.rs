@@ -0,0 +1,93 @@
+//! A transcript of the session for `--log-session`. Every line read from the
+//! console, every line of program output and every diagnostic is appended to a
+//! file with the time (UTC), so an instructor can follow afterwards what a
+//! student typed and what the interpreter answered:
+//!
+//! ```text
+//! 2026-10-16T09:12:03Z start   asm 0.1.0
+//! 2026-10-16T09:12:10Z in      MOV r0, #5
+//! 2026-10-16T09:12:14Z in      PRINT r0
+//! 2026-10-16T09:12:14Z out     r0 = 5
+//! 2026-10-16T09:12:20Z error   E0012 <stdin>:3:1: Unknown instruction: FOO
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::diagnostics::{Code, Diagnostic};
+
+static TRANSCRIPT: Mutex<Option<File>> = Mutex::new(None);
+
+/// Starts appending the session to the file at `path`, creating it if needed.
+pub fn open(path: &str) -> Result<(), Box<Diagnostic>> {
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+        format!("Cannot open session log {}: {}", path, error))))?;
+    *TRANSCRIPT.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+    record("start", &format!("{} {}", crate::APP_NAME, crate::APP_VERSION));
+    Ok(())
+}
+
+/// Records a line read from the console.
+pub fn input(line: &str) {
+    record("in", line);
+}
+
+/// Records a line of program output.
+pub fn output(line: &str) {
+    record("out", line);
+}
+
+/// Records a diagnostic on one line, without the source it points at.
+pub fn diagnostic(diagnostic: &Diagnostic) {
+    let location = diagnostic.span.as_ref()
+        .map(|span| format!("{}:{}:{}: ", span.file, span.line, span.column_start))
+        .unwrap_or_default();
+    record(diagnostic.severity.as_str(), &format!("{} {}{}", diagnostic.code.id(), location, diagnostic.message));
+}
+
+fn record(kind: &str, text: &str) {
+    let mut transcript = TRANSCRIPT.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(file) = transcript.as_mut()
+        && let Err(error) = file.write_all(entry(SystemTime::now(), kind, text).as_bytes()) {
+        // A log that cannot be written must not end the session, but is not tried again
+        eprintln!("Could not write the session log, which stops here: {}", error);
+        *transcript = None;
+    }
+}
+
+fn entry(time: SystemTime, kind: &str, text: &str) -> String {
+    format!("{} {:<7} {}\n", timestamp(time), kind, text)
+}
+
+// ISO 8601 in UTC, e.g. 2026-10-16T09:12:03Z, with the days turned into a date by
+// the civil-from-days algorithm of the proleptic Gregorian calendar
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, second_of_day) = ((seconds / 86_400) as i64, seconds % 86_400);
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            second_of_day / 3_600, second_of_day / 60 % 60, second_of_day % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_142_523);
+        assert_eq!(entry(time, "in", "MOV r0, #5"), "2026-10-16T09:22:03Z in      MOV r0, #5\n");
+    }
+}
@@ -0,0 +1,108 @@
+//! Undefined values, for `--undefined`.
+//!
+//! Real registers and memory hold whatever was there before a program starts,
+//! not 0. With `--undefined` every register and memory word the program, its
+//! data labels, `--set` and `--mem` do not set starts out undefined, and an
+//! instruction that reads an undefined value makes what it writes undefined
+//! too. The values still run as 0; what changes is a warning, once per
+//! instruction, when a branch, a memory address or a PRINT depends on one.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::taint::{DataFlow, Marks};
+
+/// Which values are undefined, and the instructions already warned about.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Undefined {
+    marks: Marks,
+    warned: BTreeSet<usize>,
+}
+
+impl Undefined {
+    /// Starts with every register but those in `defined_registers` (of `registers`)
+    /// and every word of `memory`, the words a program can read, but those in
+    /// `defined_memory` undefined.
+    pub fn new(registers: usize, defined_registers: &BTreeSet<usize>, memory: Vec<Range<usize>>, defined_memory: &BTreeSet<usize>) -> Self {
+        Undefined {
+            marks: Marks {
+                registers: (0..registers).filter(|reg| !defined_registers.contains(reg)).collect(),
+                memory: memory.into_iter().flatten().filter(|address| !defined_memory.contains(address)).collect(),
+                flags: true,
+            },
+            warned: BTreeSet::new(),
+        }
+    }
+
+    /// Propagates undefined values through the instruction at `pc`, returning a
+    /// warning the first time it branches, addresses memory or prints with one.
+    pub fn step(&mut self, pc: usize, flow: &DataFlow, name: &dyn Fn(usize) -> String) -> Option<Diagnostic> {
+        let undefined = |regs: &[usize]| regs.iter().find(|reg| self.marks.registers.contains(reg)).map(|&reg| name(reg));
+        let message = if let Some(reg) = undefined(&flow.address) {
+            Some(format!("The memory address depends on {}, which is undefined", reg))
+        } else if flow.branches && self.marks.reads_marked(flow) {
+            Some(match undefined(&flow.reads) {
+                Some(reg) => format!("The branch depends on {}, which is undefined", reg),
+                None => "The branch depends on flags that are undefined".to_string(),
+            })
+        } else if flow.prints {
+            undefined(&flow.reads).map(|reg| format!("Printing {}, which is undefined", reg))
+        } else {
+            None
+        };
+        self.marks.propagate(flow);
+        let message = message.filter(|_| self.warned.insert(pc))?;
+        Some(Diagnostic::warning(Code::UndefinedValue, message)
+            .with_note("registers and memory hold unknown values until the program sets them; it runs as if they were 0"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::diagnostics::{Diagnostic, Severity};
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    // r0 is never set; the loop warns once even though it runs ten times
+    const PROGRAM: &str = "total: #0\nMOV r1, #10\nloop: ADD r0, r0, r1\nSUBS r1, r1, #1\nBNE loop\nCMP r0, r2\nBEQ done\n\
+        LDR r3, [r4]\nLDR r5, total\nPRINT r0\nPRINT r5\ndone: EXIT";
+
+    // The warnings of `program` under --undefined, with `registers` set first
+    fn warnings(program: &str, registers: Vec<(usize, i32)>) -> Vec<Diagnostic> {
+        let options = RunOptions { undefined: true, registers, ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink())).unwrap().warnings
+    }
+
+    fn lines_and_messages(warnings: &[Diagnostic]) -> Vec<(usize, &str)> {
+        warnings.iter().map(|warning| (warning.span.as_ref().unwrap().line, warning.message.as_str())).collect()
+    }
+
+    #[test]
+    fn test_uses_of_undefined_values() {
+        assert_eq!(lines_and_messages(&warnings(PROGRAM, Vec::new())), [
+            (7, "The branch depends on flags that are undefined"),
+            (8, "The memory address depends on r4, which is undefined"),
+            (10, "Printing r0, which is undefined"),
+        ]);
+    }
+
+    #[test]
+    fn test_set_values_are_defined() {
+        // Only r2 is left
+        assert_eq!(lines_and_messages(&warnings(PROGRAM, vec![(0, 0), (4, 600)])), [(7, "The branch depends on flags that are undefined")]);
+    }
+
+    #[test]
+    fn test_branch_to_undefined_register() {
+        assert_eq!(warnings("EXIT\nmain: BX r3", Vec::new())[0].message, "The branch depends on r3, which is undefined");
+    }
+
+    #[test]
+    fn test_reported_as_warnings() {
+        let warnings = warnings("PRINT r7", Vec::new());
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert!(warnings[0].to_human().starts_with("warning[W0001]: Printing r7, which is undefined\n --> main.s:1:1"));
+    }
+}
@@ -0,0 +1,207 @@
+//! Writes to regions of memory as JSON events, for `--watch-memory` and `--watch-events`.
+//!
+//! Each memory word in a watched region that an instruction changes becomes one
+//! line, for tools such as a live view of a memory-mapped screen to follow:
+//!
+//! ```text
+//! {"address":512,"new":7,"old":0,"pc":4,"step":9}
+//! ```
+//!
+//! `pc` is the index of the instruction and `step` how many had run, as in
+//! `--trace`. A store that leaves its word as it was is not an event.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use serde_json::json;
+use crate::diagnostics::{Code, Diagnostic};
+use crate::interpreter::parse_immediate;
+use crate::memory::Memory;
+
+/// A region of memory to watch, `START..END` without END or a single word, each
+/// a memory address or a data label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchRange {
+    start: String,
+    end: Option<String>,
+}
+
+impl FromStr for WatchRange {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let (start, end) = match text.split_once("..") {
+            Some((start, end)) => (start.trim(), Some(end.trim())),
+            None => (text.trim(), None),
+        };
+        if start.is_empty() || end.is_some_and(str::is_empty) {
+            return Err(format!("invalid region '{}', expected START..END or a single address, e.g. 512..576 or grid..end", text));
+        }
+        Ok(WatchRange { start: start.to_string(), end: end.map(str::to_string) })
+    }
+}
+
+impl WatchRange {
+    /// The addresses of the region in a memory of `memory_words` words, with data labels looked up in `labels`.
+    pub fn addresses(&self, labels: &HashMap<String, usize>, memory_words: usize) -> Result<std::ops::Range<usize>, Box<Diagnostic>> {
+        let invalid = |message: String| Box::new(Diagnostic::error(Code::UnsupportedOption, message)
+            .with_note(format!("expected memory addresses up to {} or data labels", memory_words)));
+        let address = |location: &str| labels.get(location).copied()
+            .or_else(|| parse_immediate(location).and_then(|value| usize::try_from(value).ok()))
+            .filter(|&address| address <= memory_words)
+            .ok_or_else(|| invalid(format!("Cannot watch memory at '{}'", location)));
+        let start = address(&self.start)?;
+        let end = match &self.end {
+            Some(end) => address(end)?,
+            None => start + 1,
+        };
+        if start >= end || end > memory_words {
+            let region = self.end.as_ref().map_or(self.start.clone(), |end| format!("{}..{}", self.start, end));
+            return Err(invalid(format!("The region {} to watch holds no memory words", region)));
+        }
+        Ok(start..end)
+    }
+}
+
+/// A change of a watched memory word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: usize,
+    pub old: i32,
+    pub new: i32,
+    /// The index of the instruction that wrote the word
+    pub pc: usize,
+    pub step: u64,
+}
+
+impl MemoryWrite {
+    /// The event as a line of JSON.
+    pub fn json(&self) -> String {
+        json!({ "address": self.address, "old": self.old, "new": self.new, "pc": self.pc, "step": self.step }).to_string()
+    }
+}
+
+/// The watched words and where their changes go.
+pub struct MemoryWatch {
+    // Each watched address once, in order
+    addresses: Vec<usize>,
+    out: Box<dyn Write>,
+}
+
+impl MemoryWatch {
+    pub fn new(regions: &[std::ops::Range<usize>], out: Box<dyn Write>) -> Self {
+        let addresses: BTreeSet<usize> = regions.iter().flat_map(Clone::clone).collect();
+        MemoryWatch { addresses: addresses.into_iter().collect(), out }
+    }
+
+    /// Watches `regions`, writing the events to `path`, or stdout for `-`.
+    pub fn create(regions: &[WatchRange], labels: &HashMap<String, usize>, memory_words: usize, path: &str) -> Result<Self, Box<Diagnostic>> {
+        let regions = regions.iter().map(|region| region.addresses(labels, memory_words)).collect::<Result<Vec<_>, _>>()?;
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = File::create(path).map_err(|error| Box::new(Diagnostic::error(Code::OutputFailed,
+                format!("Could not create the memory events file {}: {}", path, error))))?;
+            Box::new(BufWriter::new(file))
+        };
+        Ok(MemoryWatch::new(&regions, out))
+    }
+
+    /// The watched words before an instruction runs, to compare with afterwards.
+    pub fn before(&self, memory: &Memory) -> Vec<i32> {
+        self.addresses.iter().map(|&address| memory[address]).collect()
+    }
+
+    /// The watched words that differ in `memory` from `before`, as written by the instruction at `pc`.
+    pub fn changes(&self, before: &[i32], memory: &Memory, pc: usize, step: u64) -> Vec<MemoryWrite> {
+        self.addresses.iter().zip(before).filter(|&(&address, &old)| memory[address] != old)
+            .map(|(&address, &old)| MemoryWrite { address, old, new: memory[address], pc, step }).collect()
+    }
+
+    /// Writes an event for each watched word the instruction at `pc` changed.
+    pub fn after(&mut self, before: &[i32], memory: &Memory, pc: usize, step: u64) -> Result<(), Box<Diagnostic>> {
+        for change in self.changes(before, memory, pc, step) {
+            writeln!(self.out, "{}", change.json()).map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<Diagnostic>> {
+        self.out.flush().map_err(failed)
+    }
+}
+
+fn failed(error: io::Error) -> Box<Diagnostic> {
+    Box::new(Diagnostic::error(Code::OutputFailed, format!("Could not write the memory events: {}", error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::console::Console;
+    use crate::interpreter::{run_sources, Arm, RunOptions, MEMORY_SIZE};
+    use super::*;
+
+    // Runs `program` watching `regions`, with the events written to a file named after `test`
+    fn watch(test: &str, regions: &[&str], program: &str) -> Result<Vec<String>, Vec<Diagnostic>> {
+        let path = std::env::temp_dir().join(format!("asm-watch-{}-{}.jsonl", test, std::process::id()));
+        let regions = regions.iter().map(|region| region.parse().unwrap()).collect();
+        let options = RunOptions { watch_memory: Some((regions, path.display().to_string())), ..RunOptions::default() };
+        let sources = [("main.s".to_string(), program.to_string())];
+        run_sources(&Arm, &sources, &options, &mut Console::new(&mut io::empty(), &mut io::sink()))?;
+        let events = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        Ok(events.lines().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn test_changes_are_events() {
+        // The second store to 7 leaves it as it was
+        let events = watch("changes", &["5..8"], "MOV r2, #7\nSTR r2, [r2]\nSTR r2, [r2]").unwrap();
+        assert_eq!(events, ["{\"address\":7,\"new\":7,\"old\":0,\"pc\":1,\"step\":2}"]);
+    }
+
+    #[test]
+    fn test_unwatched_words_are_no_events() {
+        let events = watch("unwatched", &["grid"], "grid: #0\nrow: #0\nMOV r1, #2\nSTR r1, row\nSTR r1, grid").unwrap();
+        assert_eq!(events, ["{\"address\":0,\"new\":2,\"old\":0,\"pc\":2,\"step\":3}"]);
+    }
+
+    #[test]
+    fn test_overlapping_regions_report_a_word_once() {
+        let events = watch("overlapping", &["5..8", "6..7", "6"], "MOV r2, #6\nSTR r2, [r2]").unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_labels_bound_regions() {
+        let labels = HashMap::from([("grid".to_string(), 512)]);
+        assert_eq!("grid..576".parse::<WatchRange>().unwrap().addresses(&labels, MEMORY_SIZE).unwrap(), 512..576);
+        assert_eq!("grid".parse::<WatchRange>().unwrap().addresses(&labels, MEMORY_SIZE).unwrap(), 512..513);
+    }
+
+    #[test]
+    fn test_empty_regions_are_rejected() {
+        let labels = HashMap::new();
+        let error = "8..5".parse::<WatchRange>().unwrap().addresses(&labels, MEMORY_SIZE).unwrap_err();
+        assert_eq!(error.message, "The region 8..5 to watch holds no memory words");
+        let error = "1024".parse::<WatchRange>().unwrap().addresses(&labels, MEMORY_SIZE).unwrap_err();
+        assert_eq!(error.message, "The region 1024 to watch holds no memory words");
+    }
+
+    #[test]
+    fn test_regions_outside_memory_are_rejected() {
+        let errors = watch("outside", &["1000..2000"], "MOV r0, #1").unwrap_err();
+        assert_eq!((errors[0].code, errors[0].message.as_str()), (Code::UnsupportedOption, "Cannot watch memory at '2000'"));
+        let errors = watch("unknown", &["screen"], "MOV r0, #1").unwrap_err();
+        assert_eq!(errors[0].message, "Cannot watch memory at 'screen'");
+    }
+
+    #[test]
+    fn test_regions_need_both_bounds() {
+        assert!("..5".parse::<WatchRange>().is_err());
+        assert!("5..".parse::<WatchRange>().is_err());
+        assert!(" ".parse::<WatchRange>().is_err());
+    }
+}
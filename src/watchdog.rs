@@ -0,0 +1,137 @@
+//! Spotting programs that can never stop.
+//!
+//! A program is deterministic given its input, so if it ever comes back to exactly
+//! the same instruction, registers, flags, memory, tasks, exclusive reservation and
+//! place in its input it will repeat the same steps forever. Every [`SAMPLE_EVERY`] steps the watchdog hashes that state and
+//! looks for a repeat with Brent's cycle detection, which keeps a single saved
+//! hash however long the program runs. A repeated hash is then confirmed by
+//! comparing the full state after every step until it comes back, which also
+//! finds the shortest period and the instructions of the loop body. Memory is
+//! kept copy-on-write, so taking the state and comparing it with the one that
+//! should come back costs the pages the loop writes, not the whole memory.
+
+use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::memory::Memory;
+
+/// Steps between samples of the state.
+pub const SAMPLE_EVERY: u64 = 1024;
+
+/// A loop the program can never leave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    /// Steps after which the state repeats
+    pub period: u64,
+    /// The instructions of the loop, by index
+    pub body: BTreeSet<usize>,
+}
+
+/// The whole state of a core about to run an instruction.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct State {
+    /// The instruction, registers, flags and anything else of the core, as numbers
+    pub core: Vec<i64>,
+    pub memory: Memory,
+}
+
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    // The hash of a saved state and the step it was taken at
+    saved: Option<(u64, u64)>,
+    // Samples compared with the saved state, and how many to compare before saving a new one
+    samples: u64,
+    power: u64,
+    confirming: Option<Confirming>,
+}
+
+// A suspected loop, run once more to check it
+#[derive(Debug)]
+struct Confirming {
+    state: State,
+    start: u64,
+    // The state must come back by this step, or the hashes only collided
+    until: u64,
+    body: BTreeSet<usize>,
+}
+
+impl Watchdog {
+    /// Called before each step, with the steps run so far, the instruction about
+    /// to run and a way to take the state. Returns the loop once one is confirmed.
+    pub fn check(&mut self, step: u64, pc: usize, state: impl FnOnce() -> State) -> Option<Loop> {
+        if let Some(confirming) = self.confirming.as_mut() {
+            if state() == confirming.state {
+                let confirming = self.confirming.take().expect("a loop is being confirmed");
+                return Some(Loop { period: step - confirming.start, body: confirming.body });
+            }
+            confirming.body.insert(pc);
+            if step >= confirming.until {
+                self.confirming = None;
+            }
+            return None;
+        }
+        if !step.is_multiple_of(SAMPLE_EVERY) {
+            return None;
+        }
+        let state = state();
+        let mut hasher = DefaultHasher::new();
+        state.hash(&mut hasher);
+        let hash = hasher.finish();
+        match self.saved {
+            Some((saved, saved_step)) if saved == hash => {
+                self.confirming = Some(Confirming { state, start: step, until: step + (step - saved_step), body: BTreeSet::from([pc]) });
+            },
+            Some(_) if self.samples + 1 < self.power => self.samples += 1,
+            _ => {
+                self.saved = Some((hash, step));
+                self.samples = 0;
+                self.power = (self.power * 2).max(1);
+            },
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::console::Console;
+    use crate::diagnostics::Code;
+    use crate::interpreter::{run_sources, Arm, RunOptions};
+
+    use crate::diagnostics::Diagnostic;
+
+    fn run(program: &str) -> Option<Vec<Diagnostic>> {
+        let sources = [("main.s".to_string(), program.to_string())];
+        run_sources(&Arm, &sources, &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())).err()
+    }
+
+    #[test]
+    fn test_loop_body_and_period() {
+        // r0 goes back and forth between 1 and 2, and r1 never changes
+        let errors = run("MOV r0, #0\nMOV r1, #5\nloop: AND r2, r0, #1\nADD r0, r2, #1\nCMP r1, #5\nBEQ loop\nEXIT").unwrap();
+        assert_eq!(errors[0].code, Code::InfiniteLoop);
+        assert_eq!(errors[0].message, "Infinite loop detected between lines 3 and 6");
+        assert_eq!(errors[0].notes[0], "the program came back to exactly the same registers, flags and memory after 8 instructions, so it can never stop");
+        assert_eq!(errors[0].notes[1], "the loop runs:\n      3 | loop: AND r2, r0, #1\n      4 | ADD r0, r2, #1\n      5 | CMP r1, #5\n      6 | BEQ loop");
+    }
+
+    #[test]
+    fn test_loop_on_one_line() {
+        let errors = run("spin: B spin").unwrap();
+        assert_eq!(errors[0].message, "Infinite loop detected on line 1");
+        assert_eq!(errors[0].notes[0], "the program came back to exactly the same registers, flags and memory after 1 instruction, so it can never stop");
+    }
+
+    #[test]
+    fn test_loop_holding_a_reservation() {
+        let errors = run("MOV r1, #100\nLDREX r0, [r1]\nspin: B spin").unwrap();
+        assert_eq!(errors[0].code, Code::InfiniteLoop);
+    }
+
+    #[test]
+    fn test_loop_that_ends() {
+        // Left alone however long it runs
+        assert!(run("MOV r0, #0\nloop: ADD r0, r0, #1\nCMP r0, #5000\nBNE loop").is_none());
+    }
+}
@@ -0,0 +1,621 @@
+//! Educational x86-64 front-end, selected with `--isa x86`.
+//!
+//! A small subset in Intel syntax (destination first): `mov`, `add`, `sub`, `cmp`,
+//! `jmp`, the conditional jumps, `push`, `pop`, `call` and `ret`, on the 64-bit
+//! registers `rax`-`r15`. Immediates need no prefix, and memory operands are written
+//! `[rbx]`, `[rbx + 2]` or `[label]`, optionally preceded by `qword ptr`.
+//!
+//! Memory is word-addressed as in ARM mode, so a 64-bit value occupies two
+//! consecutive words (low word first), offsets count words, and `push`/`pop`
+//! move `rsp` by two. `rsp` starts at the top of memory.
+
+use std::collections::HashMap;
+use crate::console::Console;
+use crate::diagnostics::{Code, Diagnostic, Reporter};
+use crate::instructions::{self, InstructionInfo, X86_INSTRUCTIONS};
+use crate::interpreter::{parse_immediate, Machine, MEMORY_SIZE};
+use crate::isa::{Flow, Isa, Labels, RegisterFile, Tokens};
+
+const NUM_REGISTERS: usize = 16;
+const STACK_POINTER: usize = 4;
+// rbp, the base of the locals named by .local
+const FRAME_POINTER: usize = 5;
+
+/// Register names in encoding order.
+const REGISTER_NAMES: [&str; NUM_REGISTERS] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+
+const REGISTER_NOTE: &str = "registers are rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp and r8 through r15";
+
+/// The memory operand of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressOperand {
+    // [reg], [reg + offset] or [reg - offset]
+    RegisterOffset(usize, i32),
+    // [address] or [label], known when decoding
+    Absolute(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(usize),
+    Immediate(i64),
+    Memory(AddressOperand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithOp { Add, Sub, Cmp }
+
+/// Conditions of the jump instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition { Always, E, Ne, L, Le, G, Ge, B, Be, A, Ae, S, Ns, O, No }
+
+/// An x86 instruction with its operands checked and parsed. Jump targets are instruction indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Mov { dst: Operand, src: Operand },
+    Arith { op: ArithOp, dst: Operand, src: Operand },
+    Jump { cond: Condition, target: usize },
+    Push { src: Operand },
+    Pop { dst: Operand },
+    Call { target: usize },
+    Ret,
+    // The register name is printed as written
+    Print { reg: usize, name: String },
+    Exit,
+}
+
+/// The status flags set by add, sub and cmp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub zf: bool,
+    pub sf: bool,
+    pub cf: bool,
+    pub of: bool,
+}
+
+/// Registers and flags of the simulated x86-64 core, with its memory and labels.
+pub struct Cpu {
+    pub registers: [i64; NUM_REGISTERS],
+    pub flags: Flags,
+    pub machine: Machine,
+}
+
+impl AsRef<Machine> for Cpu {
+    fn as_ref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl AsMut<Machine> for Cpu {
+    fn as_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+/// An educational subset of x86-64 in Intel syntax.
+pub struct X86;
+
+impl Isa for X86 {
+    type Instruction = Instruction;
+    type Cpu = Cpu;
+
+    const NAME: &'static str = "x86";
+    const DESCRIPTION: &'static str = "x86-64 subset in Intel syntax: mov, add, sub, cmp, jumps, push, pop, call and ret";
+    const INSTRUCTIONS: &'static [InstructionInfo] = X86_INSTRUCTIONS;
+    const REGISTERS: &'static RegisterFile = &RegisterFile { names: &REGISTER_NAMES, bits: 64 };
+    const STACK_POINTER: Option<usize> = Some(STACK_POINTER);
+    // rdi, the first argument
+    const CORE_REGISTER: usize = 7;
+
+    /// A core with the stack pointer at the top of memory and every other register zero.
+    fn new_cpu(&self) -> Cpu {
+        let mut registers = [0; NUM_REGISTERS];
+        registers[STACK_POINTER] = MEMORY_SIZE as i64;
+        Cpu { registers, flags: Flags::default(), machine: Machine::new() }
+    }
+
+    fn read_register(&self, cpu: &Cpu, index: usize) -> i64 {
+        cpu.registers[index]
+    }
+
+    fn flags(&self, cpu: &Cpu) -> Vec<(&'static str, bool)> {
+        vec![("ZF", cpu.flags.zf), ("SF", cpu.flags.sf), ("CF", cpu.flags.cf), ("OF", cpu.flags.of)]
+    }
+
+    /// `--set rax=VALUE` seeds a register by its name.
+    fn seed_registers(&self, cpu: &mut Cpu, registers: &[(usize, i32)]) {
+        for &(reg, val) in registers {
+            cpu.registers[reg] = val as i64;
+        }
+    }
+
+    fn decode(&self, tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+        decode(tokens, labels, report)
+    }
+
+    fn execute(&self, cpu: &mut Cpu, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        cpu.execute(instruction, pc, console)
+    }
+
+    fn is_branch(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Jump { .. } | Instruction::Call { .. } | Instruction::Ret)
+    }
+
+    fn frame_offset(&self, instruction: &Instruction) -> Option<i32> {
+        let operands = match *instruction {
+            Instruction::Mov { dst, src } | Instruction::Arith { dst, src, .. } => [dst, src],
+            Instruction::Push { src: operand } | Instruction::Pop { dst: operand } => [operand, operand],
+            _ => return None,
+        };
+        operands.into_iter().find_map(|operand| match operand {
+            Operand::Memory(AddressOperand::RegisterOffset(FRAME_POINTER, offset)) => Some(offset),
+            _ => None,
+        })
+    }
+
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Call { .. })
+    }
+
+    fn is_return(&self, instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Ret)
+    }
+
+    fn documented(&self, mnemonic: &str) -> Option<&'static InstructionInfo> {
+        documented(mnemonic)
+    }
+
+    fn no_effect(&self, instruction: &Instruction) -> Option<&'static str> {
+        match *instruction {
+            Instruction::Mov { dst: Operand::Register(dst), src: Operand::Register(src) } if dst == src => Some("it copies the register to itself"),
+            _ => None,
+        }
+    }
+}
+
+impl Cpu {
+    // Checks that the two words of a 64-bit access fit in memory
+    fn effective_address(&self, address: AddressOperand) -> Result<usize, Box<Diagnostic>> {
+        let address = match address {
+            AddressOperand::Absolute(addr) => addr as i64,
+            AddressOperand::RegisterOffset(base, offset) => self.registers[base].wrapping_add(offset as i64),
+        };
+        let size = self.machine.memory.len();
+        if address < 0 || address > size as i64 - 2 {
+            return Err(Box::new(Diagnostic::error(Code::MemoryOutOfBounds,
+                format!("Memory access out of bounds: address {} (MEMORY_SIZE {})", address, size))
+                .with_note("64-bit values use two consecutive memory words")));
+        }
+        Ok(address as usize)
+    }
+
+    fn load(&mut self, address: usize) -> Result<i64, Box<Diagnostic>> {
+        let low = self.machine.load(address)? as u32 as u64;
+        let high = self.machine.load(address + 1)? as u32 as u64;
+        Ok((low | high << 32) as i64)
+    }
+
+    fn store(&mut self, address: usize, value: i64) -> Result<(), Box<Diagnostic>> {
+        self.machine.store(address, value as u32 as i32)?;
+        self.machine.store(address + 1, (value >> 32) as u32 as i32)
+    }
+
+    fn read(&mut self, operand: Operand) -> Result<i64, Box<Diagnostic>> {
+        Ok(match operand {
+            Operand::Register(reg) => self.registers[reg],
+            Operand::Immediate(val) => val,
+            Operand::Memory(address) => self.load(self.effective_address(address)?)?,
+        })
+    }
+
+    fn write(&mut self, operand: Operand, value: i64) -> Result<(), Box<Diagnostic>> {
+        match operand {
+            Operand::Register(reg) => self.registers[reg] = value,
+            Operand::Memory(address) => {
+                let address = self.effective_address(address)?;
+                self.store(address, value)?;
+            },
+            // Rejected when decoding
+            Operand::Immediate(_) => {},
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), Box<Diagnostic>> {
+        self.registers[STACK_POINTER] = self.registers[STACK_POINTER].wrapping_sub(2);
+        let address = self.effective_address(AddressOperand::RegisterOffset(STACK_POINTER, 0))?;
+        self.store(address, value)
+    }
+
+    fn pop(&mut self) -> Result<i64, Box<Diagnostic>> {
+        let address = self.effective_address(AddressOperand::RegisterOffset(STACK_POINTER, 0))?;
+        self.registers[STACK_POINTER] = self.registers[STACK_POINTER].wrapping_add(2);
+        self.load(address)
+    }
+
+    fn condition_holds(&self, cond: Condition) -> bool {
+        let Flags { zf, sf, cf, of } = self.flags;
+        match cond {
+            Condition::Always => true,
+            Condition::E => zf,
+            Condition::Ne => !zf,
+            Condition::L => sf != of,
+            Condition::Le => zf || sf != of,
+            Condition::G => !zf && sf == of,
+            Condition::Ge => sf == of,
+            Condition::B => cf,
+            Condition::Be => cf || zf,
+            Condition::A => !cf && !zf,
+            Condition::Ae => !cf,
+            Condition::S => sf,
+            Condition::Ns => !sf,
+            Condition::O => of,
+            Condition::No => !of,
+        }
+    }
+
+    // Executes a decoded instruction at index `pc`
+    fn execute(&mut self, instruction: &Instruction, pc: usize, console: &mut Console) -> Result<Flow, Box<Diagnostic>> {
+        match *instruction {
+            Instruction::Exit => return Ok(Flow::Exit),
+            Instruction::Mov { dst, src } => {
+                let value = self.read(src)?;
+                self.write(dst, value)?;
+            },
+            Instruction::Arith { op, dst, src } => {
+                let a = self.read(dst)?;
+                let b = self.read(src)?;
+                let (result, flags) = match op {
+                    ArithOp::Add => {
+                        let result = a.wrapping_add(b);
+                        // CF is the unsigned carry out, OF the signed overflow
+                        (result, Flags {
+                            zf: result == 0,
+                            sf: result < 0,
+                            cf: (a as u64).overflowing_add(b as u64).1,
+                            of: a.overflowing_add(b).1,
+                        })
+                    },
+                    ArithOp::Sub | ArithOp::Cmp => {
+                        let result = a.wrapping_sub(b);
+                        // Unlike ARM, x86 sets CF when the subtraction borrows
+                        (result, Flags {
+                            zf: result == 0,
+                            sf: result < 0,
+                            cf: (a as u64) < (b as u64),
+                            of: a.overflowing_sub(b).1,
+                        })
+                    },
+                };
+                self.flags = flags;
+                if op != ArithOp::Cmp {
+                    self.write(dst, result)?;
+                }
+            },
+            Instruction::Jump { cond, target } => {
+                if self.condition_holds(cond) {
+                    return Ok(Flow::Jump(target));
+                }
+            },
+            Instruction::Push { src } => {
+                let value = self.read(src)?;
+                self.push(value)?;
+            },
+            Instruction::Pop { dst } => {
+                let value = self.pop()?;
+                self.write(dst, value)?;
+            },
+            Instruction::Call { target } => {
+                self.push(pc as i64 + 1)?;
+                return Ok(Flow::Jump(target));
+            },
+            Instruction::Ret => {
+                // ret with an empty stack returns from the entry point, ending the program
+                if self.registers[STACK_POINTER] >= MEMORY_SIZE as i64 {
+                    return Ok(Flow::Exit);
+                }
+                let target = self.pop()?;
+                return Ok(Flow::Jump(target as u64 as usize));
+            },
+            Instruction::Print { reg, ref name } => {
+                let shown = self.machine.formats.format(REGISTER_NAMES[reg], self.registers[reg], X86::REGISTERS.bits);
+                console.print(format_args!("{} = {}", name, shown))?;
+            },
+        }
+        Ok(Flow::Next)
+    }
+}
+
+fn parse_register(reg: &str) -> Option<usize> {
+    let lower = reg.to_lowercase();
+    REGISTER_NAMES.iter().position(|name| *name == lower)
+}
+
+fn parse_condition(suffix: &str) -> Option<Condition> {
+    Some(match suffix {
+        "e" | "z" => Condition::E,
+        "ne" | "nz" => Condition::Ne,
+        "l" | "nge" => Condition::L,
+        "le" | "ng" => Condition::Le,
+        "g" | "nle" => Condition::G,
+        "ge" | "nl" => Condition::Ge,
+        "b" | "c" | "nae" => Condition::B,
+        "be" | "na" => Condition::Be,
+        "a" | "nbe" => Condition::A,
+        "ae" | "nc" | "nb" => Condition::Ae,
+        "s" => Condition::S,
+        "ns" => Condition::Ns,
+        "o" => Condition::O,
+        "no" => Condition::No,
+        _ => return None,
+    })
+}
+
+// Checks the operand count and the commas between operands of an instruction,
+// returning the operands
+fn operands<'a>(tokens: &Tokens<'a>, count: usize, report: &Reporter) -> Option<Vec<&'a str>> {
+    tokens.expect_instruction(count, documented(tokens.mnemonic), report)
+}
+
+// The conditional jumps are documented together under jcc
+fn documented(mnemonic: &str) -> Option<&'static InstructionInfo> {
+    let mnemonic = mnemonic.to_lowercase();
+    let base = if mnemonic.starts_with('j') && mnemonic != "jmp" { "jcc" } else { &mnemonic };
+    instructions::lookup_in(X86_INSTRUCTIONS, base)
+}
+
+fn register_operand(operand: &str, role: &str, report: &Reporter) -> Option<usize> {
+    let idx = parse_register(operand);
+    if idx.is_none() {
+        report.report(operand, Diagnostic::error(Code::InvalidRegister,
+            format!("Invalid {}: {}", role, operand)).with_note(REGISTER_NOTE));
+    }
+    idx
+}
+
+// Parses the inside of [...]: a register with an optional offset, an address or a label
+fn memory_operand(operand: &str, inner: &str, labels: &HashMap<String, usize>, report: &Reporter) -> Option<AddressOperand> {
+    let inner = inner.trim();
+    if let Some(sign_index) = inner.find(['+', '-']) {
+        let base = inner[..sign_index].trim();
+        let offset_str = inner[sign_index + 1..].trim();
+        let base = register_operand(base, "base register", report)?;
+        let Some(offset) = parse_immediate(offset_str) else {
+            report.error(offset_str, Code::InvalidAddressOperand,
+                format!("Invalid offset in address operand: {}", offset_str));
+            return None;
+        };
+        let offset = if inner.as_bytes()[sign_index] == b'-' { -offset } else { offset };
+        Some(AddressOperand::RegisterOffset(base, offset))
+    } else if let Some(base) = parse_register(inner) {
+        Some(AddressOperand::RegisterOffset(base, 0))
+    } else if let Some(addr) = parse_immediate(inner) {
+        if addr < 0 {
+            report.error(operand, Code::NegativeAddress, format!("Memory address cannot be negative: {}", addr));
+            return None;
+        }
+        Some(AddressOperand::Absolute(addr as usize))
+    } else if let Some(addr) = labels.get(inner) {
+        Some(AddressOperand::Absolute(*addr))
+    } else {
+        report.error(inner, Code::UndefinedLabel, format!("Undefined label: {}", inner));
+        None
+    }
+}
+
+// Parses a register, an immediate or a memory operand
+fn operand(operand: &str, labels: &HashMap<String, usize>, report: &Reporter) -> Option<Operand> {
+    // "qword ptr [rbx]" is the same as "[rbx]"
+    let mut unsized_operand = operand;
+    for keyword in ["qword", "ptr"] {
+        if unsized_operand.get(..keyword.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(keyword)) {
+            unsized_operand = unsized_operand[keyword.len()..].trim_start();
+        }
+    }
+    if let Some(inner) = unsized_operand.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        return memory_operand(operand, inner, labels, report).map(Operand::Memory);
+    }
+    if let Some(reg) = parse_register(operand) {
+        return Some(Operand::Register(reg));
+    }
+    if let Some(val) = parse_immediate(operand) {
+        return Some(Operand::Immediate(val as i64));
+    }
+    report.report(operand, Diagnostic::error(Code::InvalidOperand, format!("Invalid operand: {}", operand))
+        .with_note("use a register (rax), an immediate (15 or 0x10) or a memory operand ([rbx + 2])"));
+    None
+}
+
+// Parses the destination and source of a two-operand instruction. The destination
+// cannot be an immediate, and at most one operand may be in memory.
+fn two_operands(ops: &[&str], labels: &HashMap<String, usize>, report: &Reporter) -> Option<(Operand, Operand)> {
+    let dst = operand(ops[0], labels, report)?;
+    let src = operand(ops[1], labels, report)?;
+    if let Operand::Immediate(_) = dst {
+        report.report(ops[0], Diagnostic::error(Code::RegisterExpected, "The destination cannot be an immediate.")
+            .with_note("Intel syntax writes the destination first, e.g. mov rax, 5"));
+        return None;
+    }
+    if let (Operand::Memory(_), Operand::Memory(_)) = (dst, src) {
+        report.report(ops[1], Diagnostic::error(Code::InvalidOperand, "Only one operand can be a memory operand.")
+            .with_note("load one of the values into a register first"));
+        return None;
+    }
+    Some((dst, src))
+}
+
+fn jump_target(label: &str, labels: &Labels, report: &Reporter) -> Option<usize> {
+    if let Some(index) = labels.code.get(label) {
+        Some(*index)
+    } else if labels.addresses.contains_key(label) {
+        report.error(label, Code::InvalidBranchTarget, format!("Cannot jump to '{}': it is a data label, not code.", label));
+        None
+    } else {
+        report.error(label, Code::UndefinedLabel, format!("Undefined label: {}", label));
+        None
+    }
+}
+
+// Decodes a single x86 instruction (labels already stripped), reporting any errors.
+fn decode(tokens: &Tokens, labels: &Labels, report: &Reporter) -> Option<Instruction> {
+    let mnemonic = tokens.mnemonic.to_lowercase();
+    debug!("Decoding {} with operands {:?}", mnemonic, tokens.operands);
+    let ops = |count| operands(tokens, count, report);
+    let addresses = labels.addresses;
+
+    let instruction = match mnemonic.as_str() {
+        "exit" => {
+            ops(0)?;
+            Instruction::Exit
+        },
+        "ret" => {
+            ops(0)?;
+            Instruction::Ret
+        },
+        "mov" => {
+            let (dst, src) = two_operands(&ops(2)?, addresses, report)?;
+            Instruction::Mov { dst, src }
+        },
+        "add" | "sub" | "cmp" => {
+            let (dst, src) = two_operands(&ops(2)?, addresses, report)?;
+            let op = match mnemonic.as_str() {
+                "add" => ArithOp::Add,
+                "sub" => ArithOp::Sub,
+                _ => ArithOp::Cmp,
+            };
+            Instruction::Arith { op, dst, src }
+        },
+        "push" => {
+            let ops = ops(1)?;
+            Instruction::Push { src: operand(ops[0], addresses, report)? }
+        },
+        "pop" => {
+            let ops = ops(1)?;
+            let dst = operand(ops[0], addresses, report)?;
+            if let Operand::Immediate(_) = dst {
+                report.error(ops[0], Code::RegisterExpected, "pop needs a register or memory operand.");
+                return None;
+            }
+            Instruction::Pop { dst }
+        },
+        "call" => {
+            let ops = ops(1)?;
+            Instruction::Call { target: jump_target(ops[0], labels, report)? }
+        },
+        "print" => {
+            let ops = ops(1)?;
+            let reg = register_operand(ops[0], "register", report)?;
+            Instruction::Print { reg, name: ops[0].to_string() }
+        },
+        "jmp" => {
+            let ops = ops(1)?;
+            Instruction::Jump { cond: Condition::Always, target: jump_target(ops[0], labels, report)? }
+        },
+        _ if mnemonic.starts_with('j') && parse_condition(&mnemonic[1..]).is_some() => {
+            let cond = parse_condition(&mnemonic[1..])?;
+            let ops = ops(1)?;
+            Instruction::Jump { cond, target: jump_target(ops[0], labels, report)? }
+        },
+        _ => {
+            report.report(tokens.mnemonic, instructions::unknown(X86_INSTRUCTIONS, tokens.mnemonic));
+            return None;
+        }
+    };
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::interpreter::{run_sources, RunOptions};
+
+    fn run(script: &str) -> Cpu {
+        match run_sources(&X86, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(cpu) => cpu,
+            Err(errors) => panic!("{}", errors.iter().map(|e| e.to_human()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    fn errors(script: &str) -> Vec<Diagnostic> {
+        match run_sources(&X86, &[("main.s".to_string(), script.to_string())], &RunOptions::default(), &mut Console::new(&mut io::empty(), &mut io::sink())) {
+            Ok(_) => panic!("expected the script to fail"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn test_loop_with_cmp_and_jcc() {
+        let cpu = run("\
+            mov rax, 0\n\
+            mov rcx, 10\n\
+            loop: add rax, rcx\n\
+            sub rcx, 1\n\
+            cmp rcx, 0\n\
+            jg loop\n\
+            exit");
+        assert_eq!(cpu.registers[0], 55);
+        assert_eq!(cpu.registers[1], 0);
+    }
+
+    #[test]
+    fn test_push_pop_and_call() {
+        let cpu = run("\
+            _start: mov rdi, 21\n\
+            push rdi\n\
+            call double\n\
+            pop rbx\n\
+            exit\n\
+            double: add rdi, rdi\n\
+            mov rax, rdi\n\
+            ret");
+        assert_eq!(cpu.registers[0], 42);
+        assert_eq!(cpu.registers[3], 21);
+        assert_eq!(cpu.registers[STACK_POINTER], MEMORY_SIZE as i64);
+    }
+
+    #[test]
+    fn test_memory_operands() {
+        let cpu = run("\
+            value: #0\n\
+            high: #0\n\
+            mov qword ptr [value], -2\n\
+            mov rbx, 0\n\
+            mov rax, [rbx + 0]\n\
+            add [rbx], 3\n\
+            exit");
+        assert_eq!(cpu.registers[0], -2);
+        assert_eq!(cpu.machine.memory[0], 1);
+        assert_eq!(cpu.machine.memory[1], 0);
+    }
+
+    #[test]
+    fn test_carry_is_a_borrow() {
+        let cpu = run("mov rax, 1\ncmp rax, 2\nexit");
+        assert!(cpu.flags.cf);
+        assert!(cpu.flags.sf);
+    }
+
+    #[test]
+    fn test_addresses_near_the_register_limits() {
+        // Doubling 1 sixty-three times gives i64::MIN in rax and i64::MAX in rdx
+        let double = "mov rax, 1\nmov rcx, 63\nloop: add rax, rax\nsub rcx, 1\ncmp rcx, 0\njg loop\nmov rdx, rax\nsub rdx, 1\n";
+        assert_eq!(errors(&format!("{}mov rbx, [rdx]\n", double))[0].code, Code::MemoryOutOfBounds);
+        assert_eq!(errors(&format!("{}mov rsp, rax\npush rcx\n", double))[0].code, Code::MemoryOutOfBounds);
+    }
+
+    #[test]
+    fn test_two_memory_operands_are_rejected() {
+        let errors = errors("data: #1\nmov [data], [data]\n");
+        assert_eq!(errors[0].code, Code::InvalidOperand);
+    }
+
+    #[test]
+    fn test_register_names_for_set() {
+        assert_eq!((X86.register_index("rcx"), X86.register_index("R9")), (Some(1), Some(9)));
+        assert_eq!(X86.register_index("r1"), None);
+    }
+}